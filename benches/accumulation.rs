@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use nabo::datasets::clouds_3d;
+use nabo::Accumulation;
+use nabo::BuildOptions;
+use nabo::KDTree;
+use nabo::Parameters;
+
+fn bench_accumulation(c: &mut Criterion) {
+    const CLOUD_SIZE: u32 = 100000;
+    const QUERY_COUNT: u32 = 1000;
+    let parameters = Parameters::default();
+    let options = BuildOptions {
+        subtree_sums: true,
+        ..BuildOptions::default()
+    };
+    let mut group = c.benchmark_group("Accumulation");
+    group.warm_up_time(Duration::from_secs(1));
+    group.measurement_time(Duration::from_secs(2));
+    group.sample_size(10);
+    let clouds = clouds_3d(CLOUD_SIZE, 0).into_iter();
+    for ((cloud_name, cloud), (_, queries)) in clouds.zip(clouds_3d(QUERY_COUNT, 1)) {
+        let tree = KDTree::new_with_options(&cloud, &options);
+        for accumulation in [Accumulation::Fast, Accumulation::Deterministic] {
+            let name = format!("{:?}/{}", accumulation, cloud_name);
+            group.bench_with_input(
+                BenchmarkId::new(format!("Covariances/{}", name), 16),
+                &tree,
+                |b, tree| {
+                    b.iter(|| tree.knn_covariances_accumulated(16, &parameters, accumulation))
+                },
+            );
+            group.bench_with_input(
+                BenchmarkId::new(format!("Summaries/{}", name), 16),
+                &(&tree, &queries),
+                |b, (tree, queries)| {
+                    b.iter(|| {
+                        tree.knn_summary_batch_accumulated(16, queries, &parameters, accumulation)
+                    })
+                },
+            );
+            group.bench_with_input(
+                BenchmarkId::new(format!("Aggregates/{}", name), 10),
+                &(&tree, &queries),
+                |b, (tree, queries)| {
+                    b.iter(|| {
+                        for query in *queries {
+                            tree.aggregate_within_accumulated(query, 10.0, true, accumulation);
+                        }
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_accumulation);
+criterion_main!(benches);
@@ -0,0 +1,77 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use nabo::simple_point::*;
+use nabo::CandidateContainer;
+use nabo::KDTree;
+use nabo::Parameters;
+use nabo::Radius;
+
+/// The system allocator, counting allocations
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn count_allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn bench_batch_allocations(c: &mut Criterion) {
+    const QUERY_COUNT: u32 = 100000;
+    const CLOUD_SIZE: u32 = 1000000;
+    let cloud = random_point_cloud::<2>(CLOUD_SIZE);
+    let tree = KDTree::new(&cloud);
+    let queries = (0..QUERY_COUNT).map(|_| random_point()).collect::<Vec<_>>();
+    let parameters = Parameters::default();
+    let container = CandidateContainer::BinaryHeap;
+    let nested = count_allocations(|| {
+        tree.knn_batch(16, &queries, container, &parameters);
+    });
+    let flat = count_allocations(|| {
+        tree.knn_batch_results(16, &queries, container, &parameters);
+    });
+    println!("allocations for {QUERY_COUNT} queries: nested {nested}, flat {flat}");
+    let mut out = [(0, 0.0); 64];
+    let fixed = count_allocations(|| {
+        for query in &queries {
+            tree.radius_search_into(query, Radius::new(1.0), &mut out);
+        }
+    });
+    assert_eq!(fixed, 0, "radius searches into slices do not allocate");
+    let mut group = c.benchmark_group("BatchAllocations");
+    group.warm_up_time(Duration::from_secs(1));
+    group.measurement_time(Duration::from_secs(4));
+    group.sample_size(20);
+    group.bench_with_input(
+        BenchmarkId::new("Nested", 16),
+        &(&tree, &queries),
+        |b, (tree, queries)| b.iter(|| tree.knn_batch(16, queries, container, &parameters)),
+    );
+    group.bench_with_input(
+        BenchmarkId::new("Flat", 16),
+        &(&tree, &queries),
+        |b, (tree, queries)| b.iter(|| tree.knn_batch_results(16, queries, container, &parameters)),
+    );
+    group.finish();
+}
+
+criterion_group!(benches, bench_batch_allocations);
+criterion_main!(benches);
@@ -2,9 +2,13 @@ use std::time::Duration;
 
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
 use nabo::simple_point::*;
+use nabo::Boundary;
 use nabo::CandidateContainer;
+use nabo::InsufficientPolicy;
 use nabo::KDTree;
 use nabo::Parameters;
+use nabo::SelfMatch;
+use nabo::TraversalOrder;
 
 fn bench_candidate_container_types(c: &mut Criterion) {
     const QUERY_COUNT: u32 = 10000;
@@ -12,12 +16,17 @@ fn bench_candidate_container_types(c: &mut Criterion) {
     const PARAMETERS: Parameters<f32> = Parameters {
         epsilon: 0.0,
         max_radius: f32::INFINITY,
-        allow_self_match: true,
+        boundary: Boundary::Closed,
+        self_match: SelfMatch::Allow,
         sort_results: true,
+        traversal_order: TraversalOrder::NearChildFirst,
+        on_insufficient: InsufficientPolicy::Truncate,
     };
     let cloud = random_point_cloud::<2>(CLOUD_SIZE);
     let tree = KDTree::new(&cloud);
-    let queries = (0..QUERY_COUNT).map(|_| random_point()).collect::<Vec<_>>();
+    let queries = (0..QUERY_COUNT)
+        .map(|_| random_point::<2>())
+        .collect::<Vec<_>>();
     let mut group = c.benchmark_group("CandidateContainerType");
     group.warm_up_time(Duration::from_secs(1));
     group.measurement_time(Duration::from_secs(2));
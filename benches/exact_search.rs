@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use nabo::datasets::clouds_2d;
+use nabo::CandidateContainer;
+use nabo::KDTree;
+use nabo::Parameters;
+
+fn bench_exact_search(c: &mut Criterion) {
+    const QUERY_COUNT: u32 = 1000;
+    const CLOUD_SIZE: u32 = 1000000;
+    let mut group = c.benchmark_group("ExactSearch");
+    group.warm_up_time(Duration::from_secs(1));
+    group.measurement_time(Duration::from_secs(2));
+    let clouds = clouds_2d(CLOUD_SIZE, 0).into_iter();
+    for ((cloud_name, cloud), (_, queries)) in clouds.zip(clouds_2d(QUERY_COUNT, 1)) {
+        let tree = KDTree::new(&cloud);
+        for k in [1, 16] {
+            // a negligible error still goes through the error factor
+            for (name, epsilon) in [("Exact", 0.0), ("ErrorFactor", 1e-6)] {
+                let parameters = Parameters::builder().epsilon(epsilon).build().unwrap();
+                group.bench_with_input(
+                    BenchmarkId::new(format!("{}/{}", name, cloud_name), k),
+                    &(k, &tree, &queries),
+                    |b, (k, tree, queries)| {
+                        b.iter(|| {
+                            for query in *queries {
+                                #[rustfmt::skip]
+                                tree.knn_advanced(
+                                    *k, query,
+                                    CandidateContainer::BinaryHeap,
+                                    &parameters,
+                                    None,
+                                );
+                            }
+                        })
+                    },
+                );
+            }
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_exact_search);
+criterion_main!(benches);
@@ -0,0 +1,61 @@
+#![cfg_attr(feature = "strict-exact", allow(unused_imports))]
+
+use std::time::Instant;
+
+use nabo::simple_point::*;
+#[cfg(not(feature = "strict-exact"))]
+use nabo::KDForest;
+use nabo::KDTree;
+
+/// Compares the recall and speed of kd-forests to exact search, on random data of the dimension of SIFT descriptors
+#[cfg(not(feature = "strict-exact"))]
+fn main() {
+    const QUERY_COUNT: u32 = 200;
+    const CLOUD_SIZE: u32 = 50000;
+    const K: u32 = 10;
+    let cloud = random_point_cloud::<128>(CLOUD_SIZE);
+    let queries = random_point_cloud::<128>(QUERY_COUNT);
+
+    let tree = KDTree::new(&cloud);
+    let start = Instant::now();
+    let exact: Vec<_> = queries.iter().map(|query| tree.knn(K, query)).collect();
+    println!(
+        "exact KDTree: {:.3} ms/query",
+        start.elapsed().as_secs_f64() * 1e3 / QUERY_COUNT as f64
+    );
+
+    for n_trees in [1, 4, 8] {
+        let forest = KDForest::new(&cloud, n_trees, 0);
+        for max_checks in [64, 256, 1024, 4096] {
+            let start = Instant::now();
+            let approx: Vec<_> = queries
+                .iter()
+                .map(|query| forest.knn(K, query, max_checks))
+                .collect();
+            let elapsed = start.elapsed();
+            let found: usize = exact
+                .iter()
+                .zip(&approx)
+                .map(|(exact, approx)| {
+                    exact
+                        .iter()
+                        .filter(|n| approx.iter().any(|a| a.index == n.index))
+                        .count()
+                })
+                .sum();
+            println!(
+                "{} trees, {:>4} checks: recall {:.3}, {:.3} ms/query",
+                n_trees,
+                max_checks,
+                found as f64 / (QUERY_COUNT * K) as f64,
+                elapsed.as_secs_f64() * 1e3 / QUERY_COUNT as f64
+            );
+        }
+    }
+}
+
+/// kd-forests are absent with the `strict-exact` feature
+#[cfg(feature = "strict-exact")]
+fn main() {
+    println!("kd-forests are not available with the strict-exact feature");
+}
@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use nabo::simple_point::*;
+use nabo::CandidateContainer;
+use nabo::KDTree;
+use nabo::Parameters;
+use nabo::QueryStats;
+
+/// Queries clustered in a small region of the cloud, and a few spread over it
+fn skewed_queries(count: u32) -> Vec<P3> {
+    (0..count)
+        .map(|i| {
+            let point: P3 = random_point();
+            if i % 50 == 0 {
+                point
+            } else {
+                P3::from(point.0.map(|value| value.into_inner() * 0.05))
+            }
+        })
+        .collect()
+}
+
+fn bench_heat_layout(c: &mut Criterion) {
+    const QUERY_COUNT: u32 = 10000;
+    const CLOUD_SIZE: u32 = 1000000;
+    let cloud = random_point_cloud::<3>(CLOUD_SIZE);
+    let tree = KDTree::new(&cloud);
+    let mut optimized = tree.clone();
+    // the layout is adapted to a sample, and measured on other queries of the same workload
+    let heat = optimized.profile(&skewed_queries(QUERY_COUNT), 8, &Parameters::default());
+    optimized.optimize_for(&heat);
+    println!(
+        "nodes: default {}, optimized {}",
+        tree.node_count(),
+        optimized.node_count()
+    );
+    let queries = skewed_queries(QUERY_COUNT);
+    // timings are noisy on shared machines, so the work of the searches is reported as well
+    let work = |tree: &KDTree<f32, P3>| {
+        let container = CandidateContainer::BinaryHeap;
+        let mut total = QueryStats::default();
+        for query in &queries {
+            total += tree
+                .knn_with_stats(8, query, container, &Parameters::default())
+                .1;
+        }
+        total
+    };
+    let (default_work, optimized_work) = (work(&tree), work(&optimized));
+    println!(
+        "visited nodes: default {}, optimized {}; touched points: default {}, optimized {}",
+        default_work.visited_nodes,
+        optimized_work.visited_nodes,
+        default_work.touched_points,
+        optimized_work.touched_points
+    );
+    let mut group = c.benchmark_group("HeatLayout");
+    group.warm_up_time(Duration::from_secs(1));
+    group.measurement_time(Duration::from_secs(4));
+    for (name, tree) in [("Default", &tree), ("Optimized", &optimized)] {
+        group.bench_with_input(
+            BenchmarkId::new(name, 8),
+            &(tree, &queries),
+            |b, (tree, queries)| {
+                b.iter(|| {
+                    for query in *queries {
+                        tree.knn(8, query);
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_heat_layout);
+criterion_main!(benches);
@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use nabo::datasets::{clouds_2d, clouds_3d};
+use nabo::simple_point::*;
+use nabo::KDTree;
+
+/// The named clouds of points of a number of dimensions
+type Clouds<const D: usize> = Vec<(&'static str, Vec<SimplePoint<D>>)>;
+
+fn bench_leaf_scan_dim<const D: usize>(c: &mut Criterion, clouds: fn(u32, u64) -> Clouds<D>)
+where
+    SimplePoint<D>: nabo::Point<f32>,
+{
+    const QUERY_COUNT: u32 = 1000;
+    const CLOUD_SIZE: u32 = 200000;
+    let mut group = c.benchmark_group(format!("LeafScan{}D", D));
+    group.warm_up_time(Duration::from_secs(1));
+    group.measurement_time(Duration::from_secs(2));
+    for ((name, cloud), (_, queries)) in clouds(CLOUD_SIZE, 0)
+        .into_iter()
+        .zip(clouds(QUERY_COUNT, 1))
+    {
+        // large buckets make the leaf scan dominate the search
+        for bucket_size in [8, 32, 128] {
+            let tree = KDTree::new_with_bucket_size(&cloud, bucket_size);
+            group.bench_with_input(
+                BenchmarkId::new(name, bucket_size),
+                &(&tree, &queries),
+                |b, (tree, queries)| {
+                    b.iter(|| {
+                        for query in *queries {
+                            tree.knn(8, query);
+                        }
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+fn bench_leaf_scan(c: &mut Criterion) {
+    bench_leaf_scan_dim::<2>(c, clouds_2d);
+    bench_leaf_scan_dim::<3>(c, clouds_3d);
+}
+
+criterion_group!(benches, bench_leaf_scan);
+criterion_main!(benches);
@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use nabo::datasets::clouds_3d;
+use nabo::CandidateContainer;
+use nabo::KDTree;
+use nabo::Parameters;
+
+fn bench_nearest(c: &mut Criterion) {
+    const QUERY_COUNT: u32 = 10000;
+    let parameters = Parameters::default();
+    let mut group = c.benchmark_group("Nearest");
+    group.warm_up_time(Duration::from_secs(1));
+    group.measurement_time(Duration::from_secs(2));
+    for cloud_size in [100, 1000, 1000000] {
+        let clouds = clouds_3d(cloud_size, 0).into_iter();
+        for ((name, cloud), (_, queries)) in clouds.zip(clouds_3d(QUERY_COUNT, 1)) {
+            let tree = KDTree::new(&cloud);
+            group.bench_with_input(
+                BenchmarkId::new(format!("Knn1/{}", name), cloud_size),
+                &(&tree, &queries),
+                |b, (tree, queries)| {
+                    b.iter(|| {
+                        for query in *queries {
+                            tree.knn_advanced(
+                                1,
+                                query,
+                                CandidateContainer::Linear,
+                                &parameters,
+                                None,
+                            );
+                        }
+                    })
+                },
+            );
+            group.bench_with_input(
+                BenchmarkId::new(format!("Nearest/{}", name), cloud_size),
+                &(&tree, &queries),
+                |b, (tree, queries)| {
+                    b.iter(|| {
+                        for query in *queries {
+                            tree.nearest(query, &parameters);
+                        }
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_nearest);
+criterion_main!(benches);
@@ -0,0 +1,27 @@
+//! A nearest-neighbour query exported from a WebAssembly module, using only `f32` points
+//!
+//! CI builds it with and without the `f64` feature and checks the size of the module:
+//!
+//! `cargo build --release --example wasm_query --target wasm32-unknown-unknown --no-default-features --features f32`
+
+use nabo::simple_point::P3;
+use nabo::KDTree;
+
+/// Returns the index of the nearest point to `(x, y, z)` among the `count` points of `points`,
+/// stored as `x, y, z` triplets, or `u32::MAX` if there are none.
+///
+/// # Safety
+///
+/// `points` must point to `3 * count` valid values.
+#[no_mangle]
+pub unsafe extern "C" fn nearest(points: *const f32, count: usize, x: f32, y: f32, z: f32) -> u32 {
+    let coordinates = core::slice::from_raw_parts(points, count * 3);
+    let cloud: Vec<P3> = coordinates
+        .chunks(3)
+        .map(|point| P3::from([point[0], point[1], point[2]]))
+        .collect();
+    let tree = KDTree::new(&cloud);
+    tree.knn(1, &P3::from([x, y, z]))
+        .first()
+        .map_or(u32::MAX, |neighbour| neighbour.index)
+}
@@ -0,0 +1,46 @@
+//! Feeds arbitrary bytes, reinterpreted as floats, to `KDTree::try_new` and `KDTree::try_knn`.
+//!
+//! Run with `cargo fuzz run try_knn` from the repository root.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nabo::simple_point::P2;
+use nabo::{CandidateContainer, KDTree, Parameters, Point};
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    let (header, data) = data.split_at(2);
+    let bucket_size = u32::from(header[0]);
+    let k = u32::from(header[1]);
+    let floats: Vec<f32> = data
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect();
+    if floats.len() < 4 {
+        return;
+    }
+    // the first floats are the search parameters and the query, the rest the point cloud
+    let (head, cloud) = floats.split_at(4);
+    let cloud: Vec<P2> = cloud
+        .chunks_exact(2)
+        .filter_map(|coords| P2::from_raw(coords).ok())
+        .collect();
+    let tree = match KDTree::try_new_with_bucket_size(&cloud, bucket_size) {
+        Ok(tree) => tree,
+        Err(_) => return,
+    };
+    let query = &head[2..4];
+    let _ = tree.try_knn(k, query);
+    let parameters = Parameters {
+        epsilon: head[0],
+        max_radius: head[1],
+        self_match: (k % 2 == 0).into(),
+        sort_results: bucket_size % 2 == 0,
+    };
+    for candidate_container in [CandidateContainer::Linear, CandidateContainer::BinaryHeap] {
+        let _ = tree.try_knn_advanced(k, query, candidate_container, &parameters, None);
+    }
+});
@@ -0,0 +1,82 @@
+//! The order in which the helpers aggregating neighbours accumulate their contributions
+
+/// How the helpers summing over the points they find order the terms of their floating-point sums
+///
+/// Floating-point addition is not associative, so the last bits of a sum depend on the order of its terms.
+/// The affected helpers are [`KDTree::knn_covariances_accumulated`](crate::KDTree::knn_covariances_accumulated),
+/// [`KDTree::knn_summary_accumulated`](crate::KDTree::knn_summary_accumulated) for the centroid
+/// and [`KDTree::aggregate_within_accumulated`](crate::KDTree::aggregate_within_accumulated),
+/// their variants without `_accumulated` being `Fast`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Accumulation {
+    /// in the order the tree yields the points, which depends on its layout, see [`BuildOptions`](crate::BuildOptions),
+    /// and for aggregates on its subtree sums
+    #[default]
+    Fast,
+    /// by increasing index in the original point cloud, so that trees of the same points give bit-identical results
+    /// whatever their layout or the parallel chunking of the queries
+    ///
+    /// The contributions are sorted before being summed, and aggregates visit every point instead of
+    /// adding subtree sums, which the `accumulation` bench measures.
+    /// Among several neighbours at the distance of the `k`-th, the ones kept may still depend on the layout.
+    Deterministic,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn deterministic_accumulation_is_independent_of_the_layout() {
+        let cloud: Vec<P3> = random_point_cloud(3000);
+        let default = KDTree::new(&cloud);
+        let options = BuildOptions {
+            bucket_size: 3,
+            morton_presort: true,
+            subtree_sums: true,
+            seed: Some(245),
+            ..BuildOptions::default()
+        };
+        let optimised = KDTree::new_with_options(&cloud, &options);
+        let bits = |values: &[f32]| values.iter().map(|v| v.to_bits()).collect::<Vec<_>>();
+        let parameters = Parameters::default();
+        let covariances = |tree: &KDTree<f32, P3>| -> Vec<u32> {
+            tree.knn_covariances_accumulated(16, &parameters, Accumulation::Deterministic)
+                .iter()
+                .flat_map(|covariance| bits(covariance))
+                .collect()
+        };
+        assert_eq!(covariances(&default), covariances(&optimised));
+        let queries: Vec<P3> = (0..50).map(|_| random_point()).collect();
+        let summaries = |tree: &KDTree<f32, P3>| -> Vec<u32> {
+            tree.knn_summary_batch_accumulated(
+                40,
+                &queries,
+                &parameters,
+                Accumulation::Deterministic,
+            )
+            .iter()
+            .flat_map(|summary| bits(&<[f32; 3]>::from(summary.centroid)))
+            .collect()
+        };
+        assert_eq!(summaries(&default), summaries(&optimised));
+        for query in &queries {
+            let aggregate = |tree: &KDTree<f32, P3>| {
+                let aggregate = tree.aggregate_within_accumulated(
+                    query,
+                    30.0,
+                    true,
+                    Accumulation::Deterministic,
+                );
+                let outer_sums = aggregate
+                    .outer_sums()
+                    .expect("outer products are accumulated");
+                (aggregate.sums().iter().chain(outer_sums))
+                    .map(|value| value.to_bits())
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(aggregate(&default), aggregate(&optimised));
+        }
+    }
+}
@@ -0,0 +1,472 @@
+//! Aggregates of the points within a radius, without listing them
+
+use alloc::{vec, vec::Vec};
+use core::marker::PhantomData;
+
+use ordered_float::NotNan;
+
+use crate::cell::NodeCell;
+use crate::internal_parameters::InternalParameters;
+use crate::{point_slice_dist2, Accumulation, KDTree, Parameters, Point, Scalar};
+
+/// The count, coordinate sums and optionally the sums of outer products of a set of points,
+/// see [`KDTree::aggregate_within`]
+///
+/// Sums are accumulated in `f64` whatever the scalar type of the tree, so that summing
+/// many `f32` coordinates does not drift.
+/// Outer products are summed around the origin, so the covariance of points far from it
+/// loses precision, as `f64` has about 16 significant digits.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Aggregate<T: Scalar> {
+    count: u64,
+    sums: Vec<f64>,
+    /// upper triangle of the sum of `p pᵀ`, row by row
+    outer_sums: Option<Vec<f64>>,
+    _scalar: PhantomData<T>,
+}
+
+impl<T: Scalar> Aggregate<T> {
+    fn new(dim: usize, outer_products: bool) -> Self {
+        Aggregate {
+            count: 0,
+            sums: vec![0.0; dim],
+            outer_sums: outer_products.then(|| vec![0.0; triangle_len(dim)]),
+            _scalar: PhantomData,
+        }
+    }
+
+    /// Returns the number of points.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the sum of the coordinates of the points, per dimension.
+    pub fn sums(&self) -> &[f64] {
+        &self.sums
+    }
+
+    /// Returns the upper triangle of the sum of the outer products of the points with themselves, row by row,
+    /// e.g. `[xx, xy, xz, yy, yz, zz]` in 3-D, if they were accumulated.
+    pub fn outer_sums(&self) -> Option<&[f64]> {
+        self.outer_sums.as_deref()
+    }
+
+    /// Returns the centroid of the points, or `None` if there is none.
+    pub fn mean(&self) -> Option<Vec<T>> {
+        if self.count == 0 {
+            return None;
+        }
+        let count = self.count as f64;
+        Some(self.sums.iter().map(|sum| to_scalar(sum / count)).collect())
+    }
+
+    /// Returns the upper triangle of the covariance of the points, row by row and normalised by their number,
+    /// or `None` if there is no point or if outer products were not accumulated.
+    pub fn covariance(&self) -> Option<Vec<T>> {
+        let outer_sums = self.outer_sums.as_ref()?;
+        if self.count == 0 {
+            return None;
+        }
+        let count = self.count as f64;
+        let dim = self.sums.len();
+        let mut covariance = Vec::with_capacity(outer_sums.len());
+        let mut outer_sums = outer_sums.iter();
+        for i in 0..dim {
+            for j in i..dim {
+                let outer = outer_sums
+                    .next()
+                    .expect("one outer sum per pair of dimensions");
+                let mean_product = self.sums[i] / count * (self.sums[j] / count);
+                covariance.push(to_scalar(outer / count - mean_product));
+            }
+        }
+        Some(covariance)
+    }
+
+    fn add_point(&mut self, point: &[NotNan<T>]) {
+        self.count += 1;
+        add_coordinates(&mut self.sums, self.outer_sums.as_deref_mut(), point, 1.0);
+    }
+
+    /// Adds the sums of a subtree, as laid out in `KDTree::subtree_sums`
+    fn add_subtree(&mut self, subtree: &[f64]) {
+        let dim = self.sums.len();
+        self.count += subtree[0] as u64;
+        for (sum, value) in self.sums.iter_mut().zip(&subtree[1..=dim]) {
+            *sum += value;
+        }
+        if let Some(outer_sums) = &mut self.outer_sums {
+            for (sum, value) in outer_sums.iter_mut().zip(&subtree[1 + dim..]) {
+                *sum += value;
+            }
+        }
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Returns the count and coordinate sums of the points within `radius` of `query`,
+    /// see [`Self::aggregate_within_advanced`].
+    pub fn aggregate_within(&self, query: &P, radius: T) -> Aggregate<T> {
+        self.aggregate_within_advanced(query, radius, false)
+    }
+
+    /// Returns the count and coordinate sums of the points within `radius` of `query`,
+    /// and the sums of their outer products if `outer_products` is true.
+    ///
+    /// The points are accumulated while traversing the tree, without being collected.
+    /// If the tree was built with [`BuildOptions::subtree_sums`](crate::BuildOptions::subtree_sums),
+    /// subtrees whose cell is entirely within `radius` are added at once, which is much faster for large radii.
+    /// If the tree is rotated, the sums are those of the rotated points, in the frame of the tree,
+    /// see [`Self::rotation`].
+    ///
+    /// Panics if `radius` is negative or NaN, or if `query` has NaN coordinates.
+    pub fn aggregate_within_advanced(
+        &self,
+        query: &P,
+        radius: T,
+        outer_products: bool,
+    ) -> Aggregate<T> {
+        self.aggregate_within_accumulated(query, radius, outer_products, Accumulation::Fast)
+    }
+
+    /// Same as [`Self::aggregate_within_advanced`], summing the points in the order of `accumulation`.
+    ///
+    /// With [`Accumulation::Deterministic`], the indices of the points within `radius` are collected
+    /// and the points are added by increasing index in the original point cloud, ignoring the subtree sums.
+    pub fn aggregate_within_accumulated(
+        &self,
+        query: &P,
+        radius: T,
+        outer_products: bool,
+        accumulation: Accumulation,
+    ) -> Aggregate<T> {
+        let parameters = Parameters {
+            max_radius: radius,
+            ..Parameters::default()
+        };
+        parameters
+            .validate()
+            .unwrap_or_else(|error| panic!("{}", error));
+        let query = self.query_coordinates(query);
+        let max_radius2 = InternalParameters::new(&parameters, self.scale).max_radius2;
+        let mut aggregate = Aggregate::new(P::DIM as usize, outer_products);
+        if self.is_empty() {
+            return aggregate;
+        }
+        let mut cell = NodeCell::of_root(self);
+        match accumulation {
+            Accumulation::Fast => {
+                self.recurse_aggregate(&query, 0, max_radius2, &mut cell, &mut aggregate, None);
+            }
+            Accumulation::Deterministic => {
+                let mut found = Vec::new();
+                self.recurse_aggregate(
+                    &query,
+                    0,
+                    max_radius2,
+                    &mut cell,
+                    &mut aggregate,
+                    Some(&mut found),
+                );
+                found.sort_unstable_by_key(|index| self.externalise_index(*index));
+                for index in found {
+                    aggregate.add_point(self.stored_coordinates(index));
+                }
+            }
+        }
+        aggregate
+    }
+
+    /// Adds the points of the subtree of `node` within the radius to `aggregate`,
+    /// or pushes their internal indices to `found` if given
+    fn recurse_aggregate(
+        &self,
+        query: &[NotNan<T>],
+        node: usize,
+        max_radius2: NotNan<T>,
+        cell: &mut NodeCell<T>,
+        aggregate: &mut Aggregate<T>,
+        found: Option<&mut Vec<u32>>,
+    ) {
+        let (min_dist2, max_dist2) = cell.dist2_range(query, self.scale);
+        if min_dist2 > max_radius2 {
+            return;
+        }
+        let inside = max_dist2 <= max_radius2;
+        if let (true, Some(sums), None) = (inside, &self.subtree_sums, &found) {
+            let stride = subtree_sums_stride(P::DIM as usize);
+            aggregate.add_subtree(&sums[node * stride..(node + 1) * stride]);
+            return;
+        }
+        self.nodes[node].dispatch_on_type(
+            (cell, aggregate, found),
+            |(cell, aggregate, mut found), split_dim, split_val, right_child| {
+                let dim = split_dim as usize;
+                let parent = cell.extent(dim);
+                for (left, child) in [(true, node + 1), (false, right_child as usize)] {
+                    cell.set_child(dim, parent, split_val, self.drift, left);
+                    self.recurse_aggregate(
+                        query,
+                        child,
+                        max_radius2,
+                        cell,
+                        aggregate,
+                        found.as_deref_mut(),
+                    );
+                }
+                cell.set_extent(dim, parent);
+            },
+            |(_, aggregate, mut found), bucket_start_index, bucket_size| {
+                let dim = P::DIM as usize;
+                for bucket_index in bucket_start_index..bucket_start_index + bucket_size {
+                    let start = bucket_index as usize * dim;
+                    let point = &self.points[start..start + dim];
+                    if inside || point_slice_dist2::<T, P>(query, point, self.scale) <= max_radius2
+                    {
+                        match found.as_deref_mut() {
+                            Some(found) => found.push(bucket_index),
+                            None => aggregate.add_point(point),
+                        }
+                    }
+                }
+            },
+        )
+    }
+
+    /// Computes the count, coordinate sums and outer product sums of the points of each node,
+    /// `subtree_sums_stride` values per node
+    pub(crate) fn compute_subtree_sums(&self) -> Vec<f64> {
+        let dim = P::DIM as usize;
+        let stride = subtree_sums_stride(dim);
+        let mut sums = vec![0.0; self.nodes.len() * stride];
+        // children have larger ids than their parent
+        for node in (0..self.nodes.len()).rev() {
+            let (done, todo) = sums.split_at_mut((node + 1) * stride);
+            let node_sums = &mut done[node * stride..];
+            self.nodes[node].dispatch_on_type(
+                node_sums,
+                |node_sums, _, _, right_child| {
+                    let right_start = (right_child as usize - node - 1) * stride;
+                    let left = &todo[..stride];
+                    let right = &todo[right_start..right_start + stride];
+                    for ((sum, left), right) in node_sums.iter_mut().zip(left).zip(right) {
+                        *sum = left + right;
+                    }
+                },
+                |node_sums, bucket_start_index, bucket_size| {
+                    node_sums[0] = bucket_size as f64;
+                    let (sums, outer_sums) = node_sums[1..].split_at_mut(dim);
+                    for index in bucket_start_index..bucket_start_index + bucket_size {
+                        let start = index as usize * dim;
+                        let point = &self.points[start..start + dim];
+                        add_coordinates(sums, Some(outer_sums), point, 1.0);
+                    }
+                },
+            );
+        }
+        sums
+    }
+
+    /// Recomputes the subtree sums, if the tree has them, after its points changed
+    pub(crate) fn refresh_subtree_sums(&mut self) {
+        if self.subtree_sums.is_some() {
+            self.subtree_sums = Some(self.compute_subtree_sums());
+        }
+    }
+
+    /// Updates the subtree sums, if the tree has them, before the point of internal index `internal_index`
+    /// moves to `new_point`
+    pub(crate) fn move_in_subtree_sums(&mut self, internal_index: u32, new_point: &[NotNan<T>]) {
+        if self.subtree_sums.is_none() {
+            return;
+        }
+        let path = self.path_to_point(internal_index);
+        let dim = P::DIM as usize;
+        let stride = subtree_sums_stride(dim);
+        let start = internal_index as usize * dim;
+        let old_point = &self.points[start..start + dim];
+        if let Some(sums) = &mut self.subtree_sums {
+            for node in path {
+                let node_sums = &mut sums[node * stride + 1..(node + 1) * stride];
+                let (node_sums, outer_sums) = node_sums.split_at_mut(dim);
+                add_coordinates(node_sums, Some(&mut *outer_sums), old_point, -1.0);
+                add_coordinates(node_sums, Some(outer_sums), new_point, 1.0);
+            }
+        }
+    }
+
+    /// Returns the nodes from the root to the leaf holding the point of internal index `internal_index`
+    pub(crate) fn path_to_point(&self, internal_index: u32) -> Vec<usize> {
+        let mut path = vec![0];
+        while let Some(right_child) = self.nodes[path[path.len() - 1]].dispatch_on_type(
+            (),
+            |_, _, _, right_child| Some(right_child as usize),
+            |_, _, _| None,
+        ) {
+            let node = path[path.len() - 1];
+            if internal_index < self.first_internal_index(right_child) {
+                path.push(node + 1);
+            } else {
+                path.push(right_child);
+            }
+        }
+        path
+    }
+
+    /// Returns the internal index of the first point of the subtree of `node`
+    pub(crate) fn first_internal_index(&self, mut node: usize) -> u32 {
+        loop {
+            match self.nodes[node].dispatch_on_type(
+                (),
+                |_, _, _, _| None,
+                |_, bucket_start_index, _| Some(bucket_start_index),
+            ) {
+                Some(bucket_start_index) => return bucket_start_index,
+                // the left child of a split node is the next node
+                None => node += 1,
+            }
+        }
+    }
+}
+
+/// The number of values in the upper triangle of a `dim` by `dim` matrix
+fn triangle_len(dim: usize) -> usize {
+    dim * (dim + 1) / 2
+}
+
+/// The number of values per node in `KDTree::subtree_sums`: the count, the sums and the outer product sums
+pub(crate) fn subtree_sums_stride(dim: usize) -> usize {
+    1 + dim + triangle_len(dim)
+}
+
+/// Adds `sign` times the coordinates of `point` to `sums`, and its outer product to `outer_sums`
+fn add_coordinates<T: Scalar>(
+    sums: &mut [f64],
+    outer_sums: Option<&mut [f64]>,
+    point: &[NotNan<T>],
+    sign: f64,
+) {
+    let coordinate = |i: usize| point[i].to_f64().expect("floats convert to f64");
+    for (i, sum) in sums.iter_mut().enumerate() {
+        *sum += sign * coordinate(i);
+    }
+    if let Some(outer_sums) = outer_sums {
+        let mut outer_sums = outer_sums.iter_mut();
+        for i in 0..point.len() {
+            for j in i..point.len() {
+                let sum = outer_sums
+                    .next()
+                    .expect("one outer sum per pair of dimensions");
+                *sum += sign * coordinate(i) * coordinate(j);
+            }
+        }
+    }
+}
+
+fn to_scalar<T: Scalar>(value: f64) -> T {
+    T::from(value).expect("f64 converts to any float type")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+    use float_cmp::approx_eq;
+
+    fn brute_force(cloud: &[P3], query: &P3, radius: f32) -> (u64, [f64; 3], [f64; 6]) {
+        let mut count = 0;
+        let mut sums = [0.0; 3];
+        let mut outer_sums = [0.0; 6];
+        for point in cloud {
+            let p: [f32; 3] = (*point).into();
+            let q: [f32; 3] = (*query).into();
+            let dist2: f32 = (0..3).map(|i| (p[i] - q[i]) * (p[i] - q[i])).sum();
+            if dist2 <= radius * radius {
+                count += 1;
+                let p = p.map(|v| v as f64);
+                let mut k = 0;
+                for i in 0..3 {
+                    sums[i] += p[i];
+                    for j in i..3 {
+                        outer_sums[k] += p[i] * p[j];
+                        k += 1;
+                    }
+                }
+            }
+        }
+        (count, sums, outer_sums)
+    }
+
+    fn assert_aggregates(tree: &KDTree<f32, P3>, cloud: &[P3]) {
+        for radius in [0.0, 10.0, 40.0, 500.0] {
+            for _ in 0..20 {
+                let query = random_point();
+                let aggregate = tree.aggregate_within_advanced(&query, radius, true);
+                let (count, sums, outer_sums) = brute_force(cloud, &query, radius);
+                assert_eq!(aggregate.count(), count);
+                for (value, expected) in aggregate.sums().iter().zip(sums) {
+                    assert!(approx_eq!(
+                        f64,
+                        *value,
+                        expected,
+                        epsilon = 1e-6 * count as f64
+                    ));
+                }
+                let outer = aggregate.outer_sums().unwrap();
+                for (value, expected) in outer.iter().zip(outer_sums) {
+                    let tolerance = 1e-3 * count as f64;
+                    assert!(approx_eq!(f64, *value, expected, epsilon = tolerance));
+                }
+                assert_eq!(
+                    tree.aggregate_within(&query, radius).sums(),
+                    aggregate.sums()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn aggregates_match_brute_force() {
+        let mut cloud: Vec<P3> = random_point_cloud(2000);
+        let options = BuildOptions {
+            subtree_sums: true,
+            ..BuildOptions::default()
+        };
+        let mut tree = KDTree::new(&cloud);
+        let mut summed = KDTree::new_with_options(&cloud, &options);
+        assert_aggregates(&tree, &cloud);
+        assert_aggregates(&summed, &cloud);
+        for index in (0..2000).step_by(7) {
+            cloud[index] = cloud[index] * 0.5;
+            tree.update_point(index as u32, cloud[index]);
+            summed.update_point(index as u32, cloud[index]);
+        }
+        assert_aggregates(&tree, &cloud);
+        assert_aggregates(&summed, &cloud);
+        let empty = KDTree::<f32, P3>::new(&[]);
+        let aggregate = empty.aggregate_within(&P3::default(), 1.0);
+        assert_eq!(aggregate.count(), 0);
+        assert_eq!(aggregate.mean(), None);
+    }
+
+    #[test]
+    fn mean_and_covariance() {
+        let cloud = vec![
+            P2::new2d(1.0, 0.0),
+            P2::new2d(3.0, 0.0),
+            P2::new2d(1.0, 2.0),
+            P2::new2d(3.0, 2.0),
+            P2::new2d(100.0, 100.0),
+        ];
+        let tree = KDTree::new(&cloud);
+        let aggregate = tree.aggregate_within_advanced(&P2::new2d(2.0, 1.0), 2.0, true);
+        assert_eq!(aggregate.count(), 4);
+        assert_eq!(aggregate.mean(), Some(vec![2.0, 1.0]));
+        assert_eq!(aggregate.covariance(), Some(vec![1.0, 0.0, 1.0]));
+        assert_eq!(
+            tree.aggregate_within(&P2::new2d(2.0, 1.0), 2.0)
+                .covariance(),
+            None
+        );
+    }
+}
@@ -0,0 +1,239 @@
+//! All pairs of points within a radius, streamed by tiles of the tree in bounded memory
+
+use alloc::vec::Vec;
+
+use crate::distance_scale::unscale_dist2;
+use crate::internal_parameters::InternalParameters;
+use crate::radius_search::AllCandidates;
+use crate::{KDTree, Parameters, Point, Scalar};
+
+/// Number of points sampled to estimate the number of pairs per point
+const PAIR_DENSITY_SAMPLES: usize = 256;
+
+/// The number of a tile of [`KDTree::all_pairs_tiled`], counting from zero in the order of the tiles
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TileId(pub u32);
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Lists all pairs of points within `radius` of each other, inclusive, passing them to `f`
+    /// by tiles so that only the pairs of one tile are in memory at once.
+    ///
+    /// Tiles are subtrees of the tree, covering all points, sized so that each holds about
+    /// `target_pairs_per_tile` pairs given the number of pairs per point, estimated by sampling.
+    /// Pairs are `(a, b, dist2)` with `a` and `b` the indices of the points in the original point cloud
+    /// and `dist2` their squared distance, `a` being the point in the tile.
+    /// A pair of points in different tiles belongs to the tile that comes first, so that each pair
+    /// is listed exactly once: for two points of the same tile, `a` is the one the tree stores first.
+    /// A point is never paired with itself, but is paired with its duplicates.
+    ///
+    /// `f` is called in order for each tile, including tiles without pairs,
+    /// with a buffer that is reused from one tile to the next.
+    ///
+    /// Panics if `radius` is negative or NaN, or if `target_pairs_per_tile` is zero.
+    pub fn all_pairs_tiled(
+        &self,
+        radius: T,
+        target_pairs_per_tile: usize,
+        mut f: impl FnMut(TileId, &[(u32, u32, T)]),
+    ) {
+        assert!(radius >= T::zero(), "radius must be positive or zero");
+        assert!(
+            target_pairs_per_tile > 0,
+            "target_pairs_per_tile must be strictly positive"
+        );
+        let parameters = Parameters {
+            max_radius: radius,
+            ..Parameters::default()
+        };
+        let internal_parameters = InternalParameters::new(&parameters, self.scale);
+        let mut candidates = AllCandidates(Vec::new());
+        let pairs_per_point = self.pairs_per_point(&internal_parameters, &mut candidates);
+        let points_per_tile = (target_pairs_per_tile as f64 / pairs_per_point).max(1.0);
+        let mut tiles = Vec::new();
+        if !self.is_empty() {
+            self.collect_tiles(0, points_per_tile as usize, &mut tiles);
+        }
+        let mut pairs = Vec::new();
+        for (tile, (start, end)) in tiles.into_iter().enumerate() {
+            pairs.clear();
+            for source in start..end {
+                self.owned_pairs(source, &internal_parameters, &mut candidates);
+                let a = self.externalise_index(source);
+                pairs.extend(candidates.0.iter().map(|neighbour| {
+                    let dist2 = unscale_dist2(neighbour.dist2, self.scale).into_inner();
+                    (a, self.externalise_index(neighbour.index), dist2)
+                }));
+            }
+            f(TileId(tile as u32), &pairs);
+        }
+    }
+
+    /// Fills `candidates` with the neighbours of the point of internal index `source` with which it forms
+    /// a pair it owns, the ones of larger internal index
+    fn owned_pairs(
+        &self,
+        source: u32,
+        internal_parameters: &InternalParameters<T>,
+        candidates: &mut AllCandidates<T>,
+    ) {
+        let dim = P::DIM as usize;
+        let start = source as usize * dim;
+        candidates.0.clear();
+        self.knn_internal(
+            candidates,
+            &self.points[start..start + dim],
+            internal_parameters,
+        );
+        candidates.0.retain(|neighbour| neighbour.index > source);
+        candidates
+            .0
+            .sort_unstable_by_key(|neighbour| neighbour.index);
+    }
+
+    /// Estimates the number of pairs owned per point, from points spread over the internal order,
+    /// at least one to keep tiles finite
+    fn pairs_per_point(
+        &self,
+        internal_parameters: &InternalParameters<T>,
+        candidates: &mut AllCandidates<T>,
+    ) -> f64 {
+        let len = self.len();
+        let samples = len.min(PAIR_DENSITY_SAMPLES);
+        if samples == 0 {
+            return 1.0;
+        }
+        let mut pairs = 0;
+        for sample in 0..samples {
+            self.owned_pairs(
+                (sample * len / samples) as u32,
+                internal_parameters,
+                candidates,
+            );
+            pairs += candidates.0.len();
+        }
+        (pairs as f64 / samples as f64).max(1.0)
+    }
+
+    /// Appends to `tiles` the ranges of internal indices of the largest subtrees of `node`
+    /// holding at most `points_per_tile` points, or single leaves, in internal order
+    fn collect_tiles(&self, node: usize, points_per_tile: usize, tiles: &mut Vec<(u32, u32)>) {
+        let (start, end) = self.subtree_range(node);
+        if (end - start) as usize <= points_per_tile {
+            tiles.push((start, end));
+            return;
+        }
+        self.nodes[node].dispatch_on_type(
+            tiles,
+            |tiles, _, _, right_child| {
+                self.collect_tiles(node + 1, points_per_tile, tiles);
+                self.collect_tiles(right_child as usize, points_per_tile, tiles);
+            },
+            |tiles, _, _| tiles.push((start, end)),
+        );
+    }
+
+    /// Returns the range of internal indices of the points below `node`,
+    /// from its leftmost leaf to its rightmost one
+    pub(crate) fn subtree_range(&self, node: usize) -> (u32, u32) {
+        let mut first = node;
+        while let Some(left) =
+            self.nodes[first].dispatch_on_type((), |_, _, _, _| Some(first + 1), |_, _, _| None)
+        {
+            first = left;
+        }
+        let mut last = node;
+        while let Some(right) = self.nodes[last].dispatch_on_type(
+            (),
+            |_, _, _, right_child| Some(right_child as usize),
+            |_, _, _| None,
+        ) {
+            last = right;
+        }
+        let start = self.nodes[first].dispatch_on_type(
+            (),
+            |_, _, _, _| unreachable!("the descent stops at a leaf"),
+            |_, bucket_start_index, _| bucket_start_index,
+        );
+        let end = self.nodes[last].dispatch_on_type(
+            (),
+            |_, _, _, _| unreachable!("the descent stops at a leaf"),
+            |_, bucket_start_index, bucket_size| bucket_start_index + bucket_size,
+        );
+        (start, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn tiles_list_each_pair_once() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..5 {
+            let count = rng.gen_range(1..1500);
+            let mut cloud: Vec<P2> = random_point_cloud(count);
+            // duplicates pair with each other, across tiles too
+            for i in 0..count as usize / 10 {
+                cloud.push(cloud[i * 7 % count as usize]);
+            }
+            let tree = KDTree::new(&cloud);
+            let radius = rng.gen_range(0.0..15.0);
+            let mut expected = Vec::new();
+            for a in 0..cloud.len() {
+                for b in a + 1..cloud.len() {
+                    let dx = cloud[a].get(0) - cloud[b].get(0);
+                    let dy = cloud[a].get(1) - cloud[b].get(1);
+                    let dist2 = (dx * dx + dy * dy).into_inner();
+                    if dist2 <= radius * radius {
+                        expected.push((a as u32, b as u32, dist2));
+                    }
+                }
+            }
+            for target in [1, 50, 1000, usize::MAX] {
+                let mut found = Vec::new();
+                let mut next_tile = 0;
+                let mut max_tile_pairs = 0;
+                tree.all_pairs_tiled(radius, target, |tile, pairs| {
+                    assert_eq!(tile, TileId(next_tile));
+                    next_tile += 1;
+                    max_tile_pairs = max_tile_pairs.max(pairs.len());
+                    found.extend_from_slice(pairs);
+                });
+                if target == usize::MAX {
+                    assert_eq!(next_tile, 1);
+                }
+                assert_eq!(found.len(), expected.len());
+                let mut found: Vec<_> = found
+                    .into_iter()
+                    .map(|(a, b, dist2)| (a.min(b), a.max(b), dist2))
+                    .collect();
+                found.sort_unstable_by(|x, y| x.partial_cmp(y).unwrap());
+                assert_eq!(found, expected);
+            }
+        }
+        let empty = KDTree::<f32, P2>::new(&[]);
+        let mut calls = 0;
+        empty.all_pairs_tiled(1.0, 10, |_, pairs| {
+            assert!(pairs.is_empty());
+            calls += 1;
+        });
+        assert_eq!(calls, 0);
+    }
+
+    #[test]
+    fn tiles_follow_the_target_size() {
+        let cloud: Vec<P2> = random_point_cloud(20000);
+        let tree = KDTree::new(&cloud);
+        let mut tile_pairs = Vec::new();
+        tree.all_pairs_tiled(3.0, 2000, |_, pairs| tile_pairs.push(pairs.len()));
+        let total: usize = tile_pairs.iter().sum();
+        // about 20000 × π × 3² / 200² / 2 pairs per point, in tiles of at most 2000 pairs on average
+        assert!(tile_pairs.len() >= total / 2000);
+        assert!(tile_pairs.len() <= 4 * total / 2000 + 1);
+        assert!(tile_pairs.iter().all(|pairs| *pairs <= 4 * 2000));
+    }
+}
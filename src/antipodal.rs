@@ -0,0 +1,145 @@
+//! Nearest neighbours up to sign, as for unit quaternions representing rotations
+
+use alloc::vec::Vec;
+
+use ordered_float::NotNan;
+
+use crate::internal_parameters::InternalParameters;
+use crate::{CandidateCollector, InternalNeighbour, KDTree, Neighbour, Parameters, Point, Scalar};
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Finds the `k` nearest neighbours of `query` when each point is identified with its opposite,
+    /// with user-provided parameters.
+    ///
+    /// The distance between `a` and `b` is the smallest of `|a - b|` and `|a + b|`,
+    /// so that unit quaternions `q` and `-q`, which represent the same rotation, are at distance zero.
+    /// For unit quaternions at rotation angle `θ` from each other, the squared distance is `2 - 2 cos(θ / 2)`.
+    /// The returned points and indices are the stored ones, whatever their sign.
+    /// The neighbours are always sorted by increasing distance.
+    ///
+    /// This searches the tree for `query` and then for `-query`, the second search being bounded by the first.
+    pub fn knn_antipodal(
+        &self,
+        k: u32,
+        query: &P,
+        parameters: &Parameters<T>,
+    ) -> Vec<Neighbour<T, P>> {
+        let k = k.min(self.len() as u32) as usize;
+        let mut collector = UniqueCollector {
+            neighbours: Vec::with_capacity(k),
+            k,
+        };
+        if k > 0 {
+            let query = self.query_coordinates(query);
+            let opposite: Vec<NotNan<T>> = query.iter().map(|value| -*value).collect();
+            let internal_parameters = InternalParameters::new(parameters, self.scale);
+            self.knn_internal(&mut collector, &query, &internal_parameters);
+            self.knn_internal(&mut collector, &opposite, &internal_parameters);
+        }
+        collector
+            .neighbours
+            .into_iter()
+            .map(|n| self.externalise_neighbour(n))
+            .collect()
+    }
+}
+
+/// Keeps the `k` nearest candidates sorted, each index at most once with its smallest distance
+struct UniqueCollector<T: Scalar> {
+    neighbours: Vec<InternalNeighbour<T>>,
+    k: usize,
+}
+
+impl<T: Scalar> CandidateCollector<T> for UniqueCollector<T> {
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        if let Some(position) = self.neighbours.iter().position(|n| n.index == index) {
+            if dist2 >= self.neighbours[position].dist2 {
+                return;
+            }
+            self.neighbours.remove(position);
+        } else if self.neighbours.len() == self.k {
+            if self
+                .neighbours
+                .last()
+                .map_or(false, |last| dist2 >= last.dist2)
+            {
+                return;
+            }
+            self.neighbours.pop();
+        }
+        let candidate = InternalNeighbour { index, dist2 };
+        let position = self.neighbours.partition_point(|n| *n < candidate);
+        self.neighbours.insert(position, candidate);
+    }
+
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        if self.neighbours.len() < self.k {
+            None
+        } else {
+            self.neighbours.last().map(|n| n.dist2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn random_quaternion() -> P4 {
+        let q = random_point::<4>();
+        let norm = (0..4).map(|i| *q.get(i) * *q.get(i)).sum::<f32>().sqrt();
+        q * (1.0 / norm)
+    }
+
+    fn antipodal_dist2(a: &P4, b: &P4) -> f32 {
+        let (mut minus, mut plus) = (0.0, 0.0);
+        for i in 0..4 {
+            let (a, b) = (*a.get(i), *b.get(i));
+            minus += (a - b) * (a - b);
+            plus += (a + b) * (a + b);
+        }
+        f32::min(minus, plus)
+    }
+
+    #[test]
+    fn knn_antipodal_matches_brute_force() {
+        let cloud: Vec<P4> = (0..1000).map(|_| random_quaternion()).collect();
+        let tree = KDTree::new(&cloud);
+        let parameters = Parameters::default();
+        for k in [1, 5] {
+            for _ in 0..50 {
+                let query = random_quaternion();
+                let neighbours = tree.knn_antipodal(k, &query, &parameters);
+                let mut expected: Vec<f32> = cloud
+                    .iter()
+                    .map(|point| antipodal_dist2(point, &query))
+                    .collect();
+                expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                let dist2: Vec<f32> = neighbours.iter().map(|n| *n.dist2).collect();
+                assert_eq!(dist2, expected[..k as usize]);
+                for neighbour in &neighbours {
+                    assert_eq!(neighbour.point, cloud[neighbour.index as usize]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn knn_antipodal_across_the_sign_flip() {
+        let cloud: Vec<P4> = (0..500).map(|_| random_quaternion()).collect();
+        let tree = KDTree::new(&cloud);
+        for index in [0, 42, 499] {
+            // a slightly perturbed opposite of a stored quaternion is the same rotation
+            let query = cloud[index] * -1.0 + P4::from([1e-3, -1e-3, 0.0, 1e-3]);
+            let nearest = tree.knn_antipodal(1, &query, &Parameters::default());
+            assert_eq!(nearest[0].index, index as u32);
+            assert_eq!(nearest[0].point, cloud[index]);
+            assert!(*nearest[0].dist2 < 1e-5);
+            assert_ne!(tree.knn(1, &query)[0].index, index as u32);
+        }
+        assert!(tree
+            .knn_antipodal(0, &cloud[0], &Parameters::default())
+            .is_empty());
+    }
+}
@@ -0,0 +1,184 @@
+//! Trees of either precision behind one type, queried with double-precision points
+
+use alloc::vec::Vec;
+
+use ordered_float::NotNan;
+
+use crate::cross_precision::round_point;
+use crate::simple_point::SimplePoint;
+use crate::{BuildError, FlatTree, KDTree, Neighbour, Parameters};
+
+/// A tree of `D`-dimensional [`SimplePoint`]s of either precision, to hold trees built with `f32`
+/// and with `f64` in one collection and query them alike with `f64` queries
+///
+/// The searches of a single-precision tree run in `f32`:
+/// * the query is rounded to the nearest `f32`, coordinates beyond the range of `f32` becoming infinite,
+///   and so are `epsilon` and `max_radius`,
+/// * the points and squared distances of the results are the `f32` ones, converted exactly to `f64`,
+///   so the squared distances have the relative error of single precision, about `2⁻²⁴ (2 D + 8)`,
+///   and points closer to each other than the `f32` precision of the query may be ordered differently
+///   than in double precision.
+///
+/// [`KDTree::knn_refined`] finds the exact double-precision neighbours, given the points the tree was built from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AnyKDTree<const D: usize> {
+    /// a single-precision tree
+    F32(KDTree<f32, SimplePoint<D>>),
+    /// a double-precision tree
+    F64(KDTree<f64, SimplePoint<D, f64>>),
+}
+
+/// The flat export of a tree of either precision, see [`AnyKDTree::import`]
+///
+/// This crate has no byte format for trees: deserializers read the width of the scalars
+/// from their own header and wrap the arrays they read in the matching variant.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AnyFlatTree {
+    /// the export of a single-precision tree
+    F32(FlatTree<f32>),
+    /// the export of a double-precision tree
+    F64(FlatTree<f64>),
+}
+
+impl<const D: usize> AnyKDTree<D> {
+    /// Imports a tree exported by [`Self::export_flat`] or [`KDTree::export_flat`], of the precision of its variant,
+    /// or returns the error of [`FlatTree::import`].
+    pub fn import(flat: &AnyFlatTree) -> Result<Self, BuildError> {
+        Ok(match flat {
+            AnyFlatTree::F32(flat) => AnyKDTree::F32(flat.import()?),
+            AnyFlatTree::F64(flat) => AnyKDTree::F64(flat.import()?),
+        })
+    }
+
+    /// Exports the tree to plain arrays of its precision, see [`KDTree::export_flat`].
+    pub fn export_flat(&self) -> AnyFlatTree {
+        match self {
+            AnyKDTree::F32(tree) => AnyFlatTree::F32(tree.export_flat()),
+            AnyKDTree::F64(tree) => AnyFlatTree::F64(tree.export_flat()),
+        }
+    }
+
+    /// Returns the number of bits of the scalars of the tree, 32 or 64.
+    pub fn scalar_bits(&self) -> u32 {
+        match self {
+            AnyKDTree::F32(_) => 32,
+            AnyKDTree::F64(_) => 64,
+        }
+    }
+
+    /// Returns the number of points of the tree.
+    pub fn len(&self) -> usize {
+        match self {
+            AnyKDTree::F32(tree) => tree.len(),
+            AnyKDTree::F64(tree) => tree.len(),
+        }
+    }
+
+    /// Returns whether the tree has no points.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Finds the `k` nearest neighbours of `query`, see [`KDTree::knn`] and the conversions of [`AnyKDTree`].
+    pub fn knn(
+        &self,
+        k: u32,
+        query: &SimplePoint<D, f64>,
+    ) -> Vec<Neighbour<f64, SimplePoint<D, f64>>> {
+        self.knn_advanced(k, query, &Parameters::default())
+    }
+
+    /// Finds the `k` nearest neighbours of `query` with `parameters`, see [`KDTree::knn_advanced`]
+    /// and the conversions of [`AnyKDTree`].
+    ///
+    /// Panics in the same cases as [`KDTree::knn_advanced`].
+    pub fn knn_advanced(
+        &self,
+        k: u32,
+        query: &SimplePoint<D, f64>,
+        parameters: &Parameters<f64>,
+    ) -> Vec<Neighbour<f64, SimplePoint<D, f64>>> {
+        match self {
+            AnyKDTree::F32(tree) => {
+                let query: SimplePoint<D> = round_point(query);
+                let parameters = Parameters {
+                    epsilon: parameters.epsilon as f32,
+                    max_radius: parameters.max_radius as f32,
+                    boundary: parameters.boundary,
+                    self_match: parameters.self_match,
+                    sort_results: parameters.sort_results,
+                    traversal_order: parameters.traversal_order,
+                    on_insufficient: parameters.on_insufficient,
+                };
+                let container = KDTree::<f32, SimplePoint<D>>::default_candidate_container(k);
+                tree.knn_advanced(k, &query, container, &parameters, None)
+                    .into_iter()
+                    .map(|neighbour| Neighbour {
+                        point: SimplePoint(neighbour.point.0.map(NotNan::<f64>::from)),
+                        dist2: NotNan::<f64>::from(neighbour.dist2),
+                        index: neighbour.index,
+                    })
+                    .collect()
+            }
+            AnyKDTree::F64(tree) => {
+                let container = KDTree::<f64, SimplePoint<D, f64>>::default_candidate_container(k);
+                tree.knn_advanced(k, query, container, parameters, None)
+            }
+        }
+    }
+}
+
+impl<const D: usize> From<KDTree<f32, SimplePoint<D>>> for AnyKDTree<D> {
+    fn from(tree: KDTree<f32, SimplePoint<D>>) -> Self {
+        AnyKDTree::F32(tree)
+    }
+}
+
+impl<const D: usize> From<KDTree<f64, SimplePoint<D, f64>>> for AnyKDTree<D> {
+    fn from(tree: KDTree<f64, SimplePoint<D, f64>>) -> Self {
+        AnyKDTree::F64(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simple_point::*;
+
+    #[test]
+    fn trees_of_both_precisions_answer_alike() {
+        let cloud: Vec<P3d> = random_point_cloud::<3>(2000)
+            .into_iter()
+            .map(|point| SimplePoint(point.0.map(NotNan::<f64>::from)))
+            .collect();
+        let trees: Vec<AnyKDTree<3>> = vec![
+            KDTree::<f32, P3>::new_from_f64(&cloud).into(),
+            KDTree::new(&cloud).into(),
+        ];
+        assert_eq!(trees[0].scalar_bits(), 32);
+        assert_eq!(trees[1].scalar_bits(), 64);
+        let parameters = Parameters {
+            max_radius: 20.0,
+            ..Parameters::default()
+        };
+        for query in cloud.iter().step_by(50) {
+            let query = *query * 1.01;
+            let single = trees[0].knn_advanced(5, &query, &parameters);
+            let double = trees[1].knn_advanced(5, &query, &parameters);
+            let indices = |neighbours: &[Neighbour<f64, P3d>]| -> Vec<u32> {
+                neighbours.iter().map(|n| n.index).collect()
+            };
+            assert_eq!(indices(&single), indices(&double));
+            for (single, double) in single.iter().zip(&double) {
+                assert!((*single.dist2 - *double.dist2).abs() <= 1e-5 * (1.0 + *double.dist2));
+            }
+            assert_eq!(trees[1].knn(5, &query), KDTree::new(&cloud).knn(5, &query));
+        }
+        for tree in &trees {
+            let flat = tree.export_flat();
+            assert_eq!(AnyKDTree::import(&flat).as_ref(), Ok(tree));
+            assert_eq!(tree.len(), 2000);
+        }
+        assert!(AnyKDTree::<2>::import(&trees[1].export_flat()).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+//! Counts of the points within a radius, bounded to a relative error from the subtrees straddling the ball
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+
+use ordered_float::NotNan;
+
+use crate::cell::box_dist2_range;
+use crate::{point_slice_dist2, KDTree, Point, Radius, Scalar};
+
+/// A subtree straddling the ball, whose points may or may not be in it
+///
+/// Straddling subtrees are ordered by their number of points, the largest being descended first.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Straddling {
+    count: u32,
+    node: u32,
+    /// internal index of the first point of the subtree
+    start: u32,
+    /// slot of the corners of the cell of the subtree
+    slot: u32,
+}
+
+/// The state of an approximate count of the points within a ball
+struct ApproximateCount<'a, T: Scalar> {
+    query: &'a [NotNan<T>],
+    radius2: NotNan<T>,
+    /// points known to be in the ball
+    inside: usize,
+    /// points of the straddling subtrees
+    uncertain: usize,
+    straddling: BinaryHeap<Straddling>,
+    /// for each slot, the lower corner then the upper one of the cell of a straddling subtree
+    corners: Vec<NotNan<T>>,
+    free_slots: Vec<u32>,
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Returns a lower and an upper bound of the number of points within `radius` of `query`, inclusive,
+    /// both within a relative error of `max_rel_error` of the exact count, as given by
+    /// `radius_search_ordered(query, radius).len()`.
+    ///
+    /// Subtrees whose cell is inside the ball add their number of points to both bounds, subtrees whose cell
+    /// is outside it add nothing, and straddling subtrees add their number of points to the upper bound only.
+    /// The largest straddling subtree is descended while the gap between the bounds, the number of points
+    /// of the straddling subtrees, exceeds `max_rel_error` times the lower bound, the leaves being
+    /// counted exactly. The exact count `c` is between the bounds, so once the gap `g` is at most
+    /// `max_rel_error` times the lower bound `l`, `c - l <= g <= max_rel_error * l <= max_rel_error * c`,
+    /// and the same holds for the upper bound. A `max_rel_error` of zero returns the exact count twice.
+    ///
+    /// Panics if `max_rel_error` is NaN or negative.
+    pub fn count_within_radius_approx(
+        &self,
+        query: &P,
+        radius: Radius<T>,
+        max_rel_error: f64,
+    ) -> (usize, usize) {
+        assert!(
+            max_rel_error >= 0.0,
+            "max_rel_error must be positive or zero"
+        );
+        if self.is_empty() {
+            return (0, 0);
+        }
+        let query = self.query_coordinates(query);
+        let mut count = ApproximateCount {
+            query: &query,
+            radius2: self.internal_radius2(radius),
+            inside: 0,
+            uncertain: 0,
+            straddling: BinaryHeap::new(),
+            corners: Vec::new(),
+            free_slots: Vec::new(),
+        };
+        count.corners.extend_from_slice(&self.min_bounds);
+        count.corners.extend_from_slice(&self.max_bounds);
+        self.classify(&mut count, 0, 0, self.len() as u32, 0);
+        while count.uncertain as f64 > max_rel_error * count.inside as f64 {
+            let subtree = count
+                .straddling
+                .pop()
+                .expect("uncertain points are in straddling subtrees");
+            count.uncertain -= subtree.count as usize;
+            self.descend_straddling(&mut count, subtree);
+        }
+        (count.inside, count.inside + count.uncertain)
+    }
+
+    /// Classifies the children of the straddling split node of `subtree`
+    fn descend_straddling(&self, count: &mut ApproximateCount<'_, T>, subtree: Straddling) {
+        let dim = P::DIM as usize;
+        let Straddling {
+            count: subtree_count,
+            node,
+            start,
+            slot,
+        } = subtree;
+        let (split_dim, split_val, right_child) = self.nodes[node as usize].dispatch_on_type(
+            (),
+            |_, split_dim, split_val, right_child| (split_dim as usize, split_val, right_child),
+            |_, _, _| unreachable!("leaves are counted exactly"),
+        );
+        let middle = self.first_internal_index(right_child as usize);
+        let right_slot = count.slot_from(slot, dim);
+        // the children are restricted by the split, widened by the drift as in `NodeCell::set_child`
+        let lower = slot as usize * 2 * dim;
+        count.corners[lower + dim + split_dim] =
+            count.corners[lower + dim + split_dim].min(split_val + self.drift);
+        let right_lower = right_slot as usize * 2 * dim;
+        count.corners[right_lower + split_dim] =
+            count.corners[right_lower + split_dim].max(split_val - self.drift);
+        self.classify(count, node + 1, start, middle - start, slot);
+        let right_count = subtree_count - (middle - start);
+        self.classify(count, right_child, middle, right_count, right_slot);
+    }
+
+    /// Adds the `subtree_count` points of the subtree of `node`, starting at internal index `start`,
+    /// whose cell has its corners in `slot`, to the points inside the ball or to the uncertain ones,
+    /// and frees `slot` unless the subtree straddles the ball
+    fn classify(
+        &self,
+        count: &mut ApproximateCount<'_, T>,
+        node: u32,
+        start: u32,
+        subtree_count: u32,
+        slot: u32,
+    ) {
+        let dim = P::DIM as usize;
+        let corners = &count.corners[slot as usize * 2 * dim..(slot as usize + 1) * 2 * dim];
+        let (lower, upper) = corners.split_at(dim);
+        let (min_dist2, max_dist2) = box_dist2_range(count.query, lower, upper, self.scale);
+        if subtree_count == 0 || min_dist2 > count.radius2 {
+            count.free_slots.push(slot);
+        } else if max_dist2 <= count.radius2 {
+            count.inside += subtree_count as usize;
+            count.free_slots.push(slot);
+        } else if self.nodes[node as usize].dispatch_on_type((), |_, _, _, _| false, |_, _, _| true)
+        {
+            let points = &self.points[start as usize * dim..(start + subtree_count) as usize * dim];
+            count.inside += points
+                .chunks_exact(dim)
+                .filter(|point| {
+                    point_slice_dist2::<T, P>(point, count.query, self.scale) <= count.radius2
+                })
+                .count();
+            count.free_slots.push(slot);
+        } else {
+            count.uncertain += subtree_count as usize;
+            count.straddling.push(Straddling {
+                count: subtree_count,
+                node,
+                start,
+                slot,
+            });
+        }
+    }
+}
+
+impl<T: Scalar> ApproximateCount<'_, T> {
+    /// Returns a free slot holding a copy of the corners of `slot`
+    fn slot_from(&mut self, slot: u32, dim: usize) -> u32 {
+        let start = slot as usize * 2 * dim;
+        match self.free_slots.pop() {
+            Some(free_slot) => {
+                self.corners
+                    .copy_within(start..start + 2 * dim, free_slot as usize * 2 * dim);
+                free_slot
+            }
+            None => {
+                self.corners.extend_from_within(start..start + 2 * dim);
+                (self.corners.len() / (2 * dim) - 1) as u32
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn approximate_counts_bound_the_exact_count() {
+        let mut cloud: Vec<P2> = random_point_cloud(5000);
+        let mut tree = KDTree::new(&cloud);
+        let mut gaps = 0;
+        for round in 0..2 {
+            for _ in 0..30 {
+                let query = random_point();
+                for radius in [0.0, 5.0, 30.0, 80.0, 1000.0] {
+                    let radius = Radius::new(radius);
+                    let exact = tree.radius_search_ordered(&query, radius).len();
+                    assert_eq!(
+                        tree.count_within_radius_approx(&query, radius, 0.0),
+                        (exact, exact)
+                    );
+                    for max_rel_error in [0.01, 0.1, 0.5] {
+                        let (lower, upper) =
+                            tree.count_within_radius_approx(&query, radius, max_rel_error);
+                        assert!(lower <= exact && exact <= upper);
+                        assert!((upper - lower) as f64 <= max_rel_error * lower as f64);
+                        gaps += upper - lower;
+                    }
+                }
+            }
+            if round == 0 {
+                // points moved across their splits
+                for index in (0..5000).step_by(3) {
+                    cloud[index] = cloud[index] * 0.9;
+                    tree.update_point(index as u32, cloud[index]);
+                }
+            }
+        }
+        // the straddling subtrees are not all descended
+        assert!(gaps > 0);
+        let empty = KDTree::<f32, P2>::new(&[]);
+        assert_eq!(
+            empty.count_within_radius_approx(&cloud[0], Radius::new(1.0), 0.1),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "max_rel_error must be positive or zero")]
+    fn negative_errors_panic() {
+        let tree = KDTree::new(&[P2::new2d(0.0, 0.0)]);
+        tree.count_within_radius_approx(&P2::new2d(0.0, 0.0), Radius::new(1.0), -0.1);
+    }
+}
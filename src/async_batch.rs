@@ -0,0 +1,156 @@
+//! Batches of queries run outside of the async executor, completing a future
+
+extern crate std;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use std::sync::Mutex;
+
+use crate::{CancellationToken, KDTree, Neighbour, Parameters, Point, Scalar};
+
+/// The state shared between a [`KnnBatchFuture`] and the thread processing its queries
+struct Shared<T: Scalar, P: Point<T>> {
+    result: Option<Vec<Vec<Neighbour<T, P>>>>,
+    waker: Option<Waker>,
+}
+
+/// The neighbours of a batch of queries, see [`KDTree::knn_batch_spawned`]
+///
+/// Dropping the future cancels the queries that were not processed yet.
+pub struct KnnBatchFuture<T: Scalar, P: Point<T>> {
+    shared: Arc<Mutex<Shared<T, P>>>,
+    cancellation: Arc<CancellationToken>,
+}
+
+impl<T: Scalar, P: Point<T>> Future for KnnBatchFuture<T, P> {
+    type Output = Vec<Vec<Neighbour<T, P>>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut shared = self
+            .shared
+            .lock()
+            .expect("the processing thread does not panic while holding the lock");
+        match shared.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T: Scalar, P: Point<T>> Drop for KnnBatchFuture<T, P> {
+    fn drop(&mut self) {
+        self.cancellation.cancel();
+    }
+}
+
+impl<T, P> KDTree<T, P>
+where
+    T: Scalar + Send + Sync + 'static,
+    P: Point<T> + Send + Sync + 'static,
+{
+    /// Finds the `k` nearest neighbour of each of `queries` on another thread, see [`Self::knn_batch`].
+    ///
+    /// The queries run on a thread of their own, or on the rayon thread pool if the `rayon` feature
+    /// is enabled, so that awaiting the returned future does not block the executor.
+    /// This depends on no async runtime.
+    /// Queries are processed by chunks of `chunk_size`, and dropping the future
+    /// cancels the remaining chunks, see [`Self::knn_batch_cancellable`].
+    ///
+    /// Panics if `chunk_size` is zero.
+    pub fn knn_batch_spawned(
+        self: Arc<Self>,
+        k: u32,
+        queries: Vec<P>,
+        parameters: Parameters<T>,
+        chunk_size: usize,
+    ) -> KnnBatchFuture<T, P> {
+        assert!(chunk_size > 0, "chunk_size must be strictly positive");
+        let shared = Arc::new(Mutex::new(Shared {
+            result: None,
+            waker: None,
+        }));
+        let cancellation = Arc::new(CancellationToken::new());
+        let future = KnnBatchFuture {
+            shared: shared.clone(),
+            cancellation: cancellation.clone(),
+        };
+        let work = move || {
+            let container = Self::default_candidate_container(k);
+            let mut result = Vec::with_capacity(queries.len());
+            for chunk in queries.chunks(chunk_size) {
+                match self.knn_batch_cancellable(k, chunk, container, &parameters, &cancellation) {
+                    Ok(neighbours) => result.extend(neighbours),
+                    // the future was dropped, nobody waits for the result
+                    Err(_) => return,
+                }
+            }
+            let mut shared = shared
+                .lock()
+                .expect("the future does not panic while holding the lock");
+            shared.result = Some(result);
+            if let Some(waker) = shared.waker.take() {
+                waker.wake();
+            }
+        };
+        #[cfg(feature = "rayon")]
+        rayon::spawn(work);
+        #[cfg(not(feature = "rayon"))]
+        std::thread::spawn(work);
+        future
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake};
+    use std::thread::{self, Thread};
+
+    use crate::simple_point::*;
+    use crate::*;
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Runs `future` to completion on the current thread
+    fn block_on<F: core::future::Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Arc::new(ThreadWaker(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn spawned_batch_matches_batch() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let tree = Arc::new(KDTree::new(&cloud));
+        let queries: Vec<P2> = random_point_cloud(300);
+        let parameters = Parameters::default();
+        let expected = tree.knn_batch(5, &queries, CandidateContainer::Linear, &parameters);
+        for chunk_size in [1, 64, 1000] {
+            let future = tree
+                .clone()
+                .knn_batch_spawned(5, queries.clone(), parameters, chunk_size);
+            assert_eq!(block_on(future), expected);
+        }
+        // dropping the future cancels the remaining queries
+        let future = tree.knn_batch_spawned(5, queries, parameters, 1);
+        drop(future);
+    }
+}
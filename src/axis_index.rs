@@ -0,0 +1,260 @@
+//! Auxiliary indices of the points sorted along one dimension, for slab queries
+
+use alloc::vec::Vec;
+
+use ordered_float::NotNan;
+
+use crate::{KDTree, Point, Scalar};
+
+/// The external indices of the points sorted by their coordinate along `dim`,
+/// see [`KDTree::build_axis_index`]
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct AxisIndex<T: Scalar> {
+    dim: u32,
+    /// coordinates along `dim`, increasing
+    coordinates: Vec<NotNan<T>>,
+    /// external index of the point of each coordinate, increasing among equal coordinates
+    indices: Vec<u32>,
+    /// whether points moved, were removed or inserted since the index was sorted
+    stale: bool,
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Builds an auxiliary index of the points sorted by their coordinate along `dim`,
+    /// to answer [`Self::axis_range`].
+    ///
+    /// "All points with x in [a, b]" ignores the other dimensions, so the splits along them
+    /// do not prune the search; the index answers it with two binary searches instead.
+    /// It takes a coordinate and an index per point, and building it takes time `O(n log n)`.
+    /// The coordinates are those of the points as given, also if the tree is rotated, see [`Self::rotation`].
+    /// Once the points change, by [`Self::update_point`], [`Self::update_points`], [`Self::refit`],
+    /// [`Self::apply_batch`] or a merge, see [`Self::merge`], the index is stale until this or [`Self::refresh_axis_indices`] sorts it again.
+    /// Nothing is done if the index of `dim` is built and not stale.
+    /// The indices are exported by [`Self::export_flat`] if not stale.
+    /// Panics if `dim` is not smaller than the dimension of the points.
+    pub fn build_axis_index(&mut self, dim: u32) {
+        assert!(
+            dim < P::DIM,
+            "dimension {} is out of range for points of {} dimensions",
+            dim,
+            P::DIM
+        );
+        match self.axis_indices.iter().position(|index| index.dim == dim) {
+            Some(position) if !self.axis_indices[position].stale => {}
+            Some(position) => self.axis_indices[position] = self.sorted_axis_index(dim),
+            None => {
+                let index = self.sorted_axis_index(dim);
+                self.axis_indices.push(index);
+            }
+        }
+    }
+
+    /// Sorts again the stale auxiliary indices, see [`Self::build_axis_index`].
+    pub fn refresh_axis_indices(&mut self) {
+        for position in 0..self.axis_indices.len() {
+            if self.axis_indices[position].stale {
+                let dim = self.axis_indices[position].dim;
+                self.axis_indices[position] = self.sorted_axis_index(dim);
+            }
+        }
+    }
+
+    /// Removes the auxiliary index of `dim`, if any, to free its memory.
+    pub fn drop_axis_index(&mut self, dim: u32) {
+        self.axis_indices.retain(|index| index.dim != dim);
+    }
+
+    /// Returns the external indices of the points whose coordinate along `dim` is in `[lo, hi]`,
+    /// sorted by that coordinate, then by index.
+    ///
+    /// Returns `None` if there is no index of `dim` or if it is stale, see [`Self::build_axis_index`].
+    /// The range is empty if `hi < lo` or if a bound is NaN.
+    pub fn axis_range(&self, dim: u32, lo: T, hi: T) -> Option<&[u32]> {
+        let index = self
+            .axis_indices
+            .iter()
+            .find(|index| index.dim == dim && !index.stale)?;
+        let (lo, hi) = match (NotNan::new(lo), NotNan::new(hi)) {
+            (Ok(lo), Ok(hi)) => (lo, hi),
+            _ => return Some(&[]),
+        };
+        let start = index
+            .coordinates
+            .partition_point(|coordinate| *coordinate < lo);
+        let end = index
+            .coordinates
+            .partition_point(|coordinate| *coordinate <= hi);
+        Some(&index.indices[start..end.max(start)])
+    }
+
+    /// Returns the dimension and sorted indices of each auxiliary index that is not stale
+    pub(crate) fn exported_axis_indices(&self) -> Vec<(u32, Vec<u32>)> {
+        self.axis_indices
+            .iter()
+            .filter(|index| !index.stale)
+            .map(|index| (index.dim, index.indices.clone()))
+            .collect()
+    }
+
+    /// Marks the auxiliary indices as stale, after the points changed
+    pub(crate) fn mark_axis_indices_stale(&mut self) {
+        for index in &mut self.axis_indices {
+            index.stale = true;
+        }
+    }
+
+    /// Returns the index of `dim`, sorted from the points as given
+    fn sorted_axis_index(&self, dim: u32) -> AxisIndex<T> {
+        let mut entries: Vec<(NotNan<T>, u32)> = self
+            .indices
+            .iter()
+            .enumerate()
+            .map(|(internal, external)| {
+                (
+                    self.original_coordinates(internal as u32)[dim as usize],
+                    *external,
+                )
+            })
+            .collect();
+        entries.sort_unstable();
+        let (coordinates, indices) = entries.into_iter().unzip();
+        AxisIndex {
+            dim,
+            coordinates,
+            indices,
+            stale: false,
+        }
+    }
+
+    /// Replaces the auxiliary indices by those of the dimensions and sorted indices of `axis_indices`,
+    /// returning an error message if they are not sorted indices of the points
+    pub(crate) fn import_axis_indices(
+        &mut self,
+        axis_indices: &[(u32, Vec<u32>)],
+    ) -> Result<(), &'static str> {
+        let mut imported = Vec::with_capacity(axis_indices.len());
+        for (dim, indices) in axis_indices {
+            if *dim >= P::DIM {
+                return Err("the dimension of an axis index is out of bounds");
+            }
+            if imported
+                .iter()
+                .any(|index: &AxisIndex<T>| index.dim == *dim)
+            {
+                return Err("several axis indices have the same dimension");
+            }
+            if indices.len() != self.len() || indices.iter().any(|i| *i as usize >= self.len()) {
+                return Err("an axis index is not a permutation of the indices");
+            }
+            let coordinates: Vec<NotNan<T>> = indices
+                .iter()
+                .map(|external| {
+                    let internal = self.internal_indices[*external as usize];
+                    self.original_coordinates(internal)[*dim as usize]
+                })
+                .collect();
+            let entries = coordinates.iter().zip(indices);
+            if entries.clone().zip(entries.skip(1)).any(|(a, b)| a >= b) {
+                return Err("an axis index is not sorted by coordinate and index");
+            }
+            imported.push(AxisIndex {
+                dim: *dim,
+                coordinates,
+                indices: indices.clone(),
+                stale: false,
+            });
+        }
+        self.axis_indices = imported;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn slab(cloud: &[P3], dim: usize, lo: f32, hi: f32) -> Vec<u32> {
+        let mut found: Vec<(f32, u32)> = cloud
+            .iter()
+            .enumerate()
+            .map(|(index, point)| (point.0[dim].into_inner(), index as u32))
+            .filter(|(coordinate, _)| (lo..=hi).contains(coordinate))
+            .collect();
+        found.sort_by(|a, b| a.partial_cmp(b).expect("coordinates are not NaN"));
+        found.into_iter().map(|(_, index)| index).collect()
+    }
+
+    #[test]
+    fn axis_ranges_are_the_points_of_the_slab() {
+        let mut cloud: Vec<P3> = random_point_cloud(3000);
+        // ties and bounds at coordinates
+        cloud[10].0[1] = cloud[20].0[1];
+        let mut tree = KDTree::new(&cloud);
+        assert_eq!(tree.axis_range(1, -10.0, 10.0), None);
+        tree.build_axis_index(1);
+        tree.build_axis_index(2);
+        let at = cloud[20].0[1].into_inner();
+        for (lo, hi) in [(-10.0, 10.0), (at, at), (-200.0, 200.0), (5.0, -5.0)] {
+            assert_eq!(tree.axis_range(1, lo, hi).unwrap(), slab(&cloud, 1, lo, hi));
+            assert_eq!(tree.axis_range(2, lo, hi).unwrap(), slab(&cloud, 2, lo, hi));
+        }
+        assert_eq!(tree.axis_range(1, at, at).unwrap().len(), 2);
+        assert_eq!(tree.axis_range(1, f32::NAN, 1.0), Some(&[][..]));
+        assert_eq!(tree.axis_range(0, -10.0, 10.0), None);
+        tree.drop_axis_index(2);
+        assert_eq!(tree.axis_range(2, -10.0, 10.0), None);
+    }
+
+    #[test]
+    fn axis_indices_follow_the_changes_of_the_points() {
+        let mut cloud: Vec<P3> = random_point_cloud(1000);
+        let mut tree = KDTree::new(&cloud);
+        tree.build_axis_index(0);
+        cloud[3] = P3::from([1000.0, 0.0, 0.0]);
+        tree.update_point(3, cloud[3]);
+        assert_eq!(tree.axis_range(0, -10.0, 10.0), None);
+        tree.build_axis_index(0);
+        assert_eq!(tree.axis_range(0, 500.0, 2000.0).unwrap(), [3]);
+        let inserted = [P3::from([-1000.0, 0.0, 0.0])];
+        let indices = tree.apply_batch(&[5, 6], &inserted);
+        assert_eq!(tree.axis_range(0, -2000.0, 2000.0), None);
+        tree.refresh_axis_indices();
+        let points = tree.points();
+        assert_eq!(
+            tree.axis_range(0, -10.0, 10.0).unwrap(),
+            slab(&points, 0, -10.0, 10.0)
+        );
+        assert_eq!(
+            tree.axis_range(0, -2000.0, -500.0).unwrap(),
+            indices.inserted
+        );
+        // a rebuild keeps the points and their indices
+        tree.rebuild();
+        assert_eq!(tree.axis_range(0, 500.0, 2000.0).unwrap(), [3]);
+    }
+
+    #[test]
+    fn axis_indices_are_exported_and_validated() {
+        let cloud: Vec<P3> = random_point_cloud(500);
+        let mut tree = KDTree::new(&cloud);
+        tree.build_axis_index(2);
+        let mut flat = tree.export_flat();
+        assert_eq!(flat.axis_indices.len(), 1);
+        let imported = flat.import::<P3>().unwrap();
+        assert_eq!(
+            imported.axis_range(2, -50.0, 50.0),
+            tree.axis_range(2, -50.0, 50.0)
+        );
+        flat.axis_indices[0].1.swap(0, 1);
+        assert_eq!(
+            flat.import::<P3>(),
+            Err(BuildError::InvalidFlatTree(
+                "an axis index is not sorted by coordinate and index"
+            ))
+        );
+        // stale indices are not exported
+        tree.update_point(0, cloud[1]);
+        assert!(tree.export_flat().axis_indices.is_empty());
+    }
+}
@@ -0,0 +1,216 @@
+//! Barycentric coordinates of a query in the simplex of its nearest points, for interpolation over meshes
+
+use alloc::{vec, vec::Vec};
+
+use num_traits::Float;
+
+use crate::{KDTree, Parameters, Point, Scalar};
+
+/// The simplex of the `D + 1` nearest points of a query, see [`KDTree::knn_barycentric`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct BarycentricResult<T> {
+    /// the indices of the vertices in the original point cloud, by increasing distance to the query
+    pub indices: Vec<u32>,
+    /// the barycentric coordinates of the query, one per vertex and summing to one,
+    /// `None` if there are fewer than `D + 1` vertices or they are degenerate, for example coplanar in 3D
+    pub coordinates: Option<Vec<T>>,
+    /// whether all coordinates are non-negative, so the query is inside the simplex or on its boundary
+    pub inside: bool,
+}
+
+impl<T: Scalar + Float, P: Point<T>> KDTree<T, P> {
+    /// Finds the `D + 1` nearest points of `query` and returns the barycentric coordinates of `query`
+    /// in the simplex they span.
+    ///
+    /// The coordinates `λ` are such that `query = Σ λ_i v_i` with `Σ λ_i = 1`, solved by Gaussian elimination
+    /// with partial pivoting.
+    /// A pivot small relative to the edges of the simplex makes its vertices degenerate: the coordinates are
+    /// `None` rather than non-finite.
+    /// The nearest points do not have to form a simplex containing the query, which `inside` tells;
+    /// interpolating from a mesh uses the cell of the mesh containing the query instead in that case.
+    /// `parameters.sort_results` is ignored.
+    ///
+    /// Panics if the parameters are invalid, see [`Parameters::validate`].
+    pub fn knn_barycentric(&self, query: &P, parameters: &Parameters<T>) -> BarycentricResult<T> {
+        let k = P::DIM + 1;
+        let sorted = Parameters {
+            sort_results: true,
+            ..*parameters
+        };
+        let neighbours = self.knn_advanced(
+            k,
+            query,
+            Self::default_candidate_container(k),
+            &sorted,
+            None,
+        );
+        let indices: Vec<u32> = neighbours.iter().map(|neighbour| neighbour.index).collect();
+        let coordinates = if neighbours.len() == k as usize {
+            let vertices: Vec<&P> = neighbours
+                .iter()
+                .map(|neighbour| &neighbour.point)
+                .collect();
+            barycentric_coordinates(&vertices, query)
+        } else {
+            None
+        };
+        let inside = coordinates.as_ref().map_or(false, |coordinates| {
+            coordinates.iter().all(|c| *c >= T::zero())
+        });
+        BarycentricResult {
+            indices,
+            coordinates,
+            inside,
+        }
+    }
+}
+
+/// Returns the barycentric coordinates of `query` in the simplex of `vertices`, which has `D + 1` of them,
+/// or `None` if they are degenerate
+fn barycentric_coordinates<T: Scalar + Float, P: Point<T>>(
+    vertices: &[&P],
+    query: &P,
+) -> Option<Vec<T>> {
+    let dim = P::DIM as usize;
+    let origin = vertices[0];
+    // the augmented matrix of the edges from the first vertex as columns, and of the query from it
+    let width = dim + 1;
+    let mut system = vec![T::zero(); dim * width];
+    for row in 0..dim {
+        let base = *origin.get(row as u32);
+        for (column, vertex) in vertices[1..].iter().enumerate() {
+            system[row * width + column] = *vertex.get(row as u32) - base;
+        }
+        system[row * width + dim] = *query.get(row as u32) - base;
+    }
+    let largest_edge = (0..dim)
+        .flat_map(|row| (0..dim).map(move |column| row * width + column))
+        .fold(T::zero(), |max, entry| {
+            Float::max(max, Float::abs(system[entry]))
+        });
+    // pivots this small relative to the edges are rounding errors of a flat simplex
+    let tolerance =
+        largest_edge * <T as Float>::epsilon() * T::from(16 * dim.max(1)).expect("small integer");
+    for column in 0..dim {
+        let pivot_row = (column..dim)
+            .max_by(|a, b| {
+                Float::abs(system[a * width + column])
+                    .partial_cmp(&Float::abs(system[b * width + column]))
+                    .expect("finite entries")
+            })
+            .expect("a row remains");
+        let pivot = system[pivot_row * width + column];
+        if Float::abs(pivot) <= tolerance {
+            return None;
+        }
+        for entry in 0..width {
+            system.swap(column * width + entry, pivot_row * width + entry);
+        }
+        for row in column + 1..dim {
+            let factor = system[row * width + column] / pivot;
+            for entry in column..width {
+                let value = system[column * width + entry];
+                system[row * width + entry] = system[row * width + entry] - factor * value;
+            }
+        }
+    }
+    let mut coordinates = vec![T::zero(); dim + 1];
+    for row in (0..dim).rev() {
+        let known = (row + 1..dim).fold(T::zero(), |sum, column| {
+            sum + system[row * width + column] * coordinates[column + 1]
+        });
+        coordinates[row + 1] = (system[row * width + dim] - known) / system[row * width + row];
+    }
+    coordinates[0] = coordinates[1..]
+        .iter()
+        .fold(T::one(), |rest, coordinate| rest - *coordinate);
+    Some(coordinates)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn coordinates_reconstruct_the_query() {
+        let cloud: Vec<P3> = random_point_cloud(2000);
+        let tree = KDTree::new(&cloud);
+        let (mut inside, mut outside) = (0, 0);
+        for query in (0..300).map(|_| random_point::<3>()) {
+            let result = tree.knn_barycentric(&query, &Parameters::default());
+            assert_eq!(result.indices.len(), 4);
+            let nearest = tree.knn(4, &query);
+            let expected: Vec<u32> = nearest.iter().map(|n| n.index).collect();
+            assert_eq!(result.indices, expected);
+            let coordinates = result.coordinates.expect("random points are not coplanar");
+            let scale = coordinates.iter().fold(1.0f32, |max, c| max.max(c.abs()));
+            assert!((coordinates.iter().sum::<f32>() - 1.0).abs() < 1e-4 * scale);
+            for axis in 0..3 {
+                let reconstructed: f32 = result
+                    .indices
+                    .iter()
+                    .zip(&coordinates)
+                    .map(|(index, c)| c * cloud[*index as usize].0[axis].into_inner())
+                    .sum();
+                assert!((reconstructed - query.0[axis].into_inner()).abs() < 1e-3 * scale);
+            }
+            assert_eq!(result.inside, coordinates.iter().all(|c| *c >= 0.0));
+            if result.inside {
+                inside += 1;
+            } else {
+                outside += 1;
+            }
+        }
+        assert!(inside > 0 && outside > 0);
+    }
+
+    #[test]
+    fn triangles_in_2d_are_solved_exactly() {
+        let cloud = vec![
+            P2::new2d(0.0, 0.0),
+            P2::new2d(4.0, 0.0),
+            P2::new2d(0.0, 4.0),
+            P2::new2d(40.0, 40.0),
+        ];
+        let tree = KDTree::new(&cloud);
+        let result = tree.knn_barycentric(&P2::new2d(1.0, 1.0), &Parameters::default());
+        assert_eq!(result.indices, vec![0, 1, 2]);
+        assert_eq!(result.coordinates, Some(vec![0.5, 0.25, 0.25]));
+        assert!(result.inside);
+        let result = tree.knn_barycentric(&P2::new2d(-1.0, 2.0), &Parameters::default());
+        assert_eq!(result.indices, vec![0, 2, 1]);
+        assert_eq!(result.coordinates, Some(vec![0.75, 0.5, -0.25]));
+        assert!(!result.inside);
+    }
+
+    #[test]
+    fn degenerate_and_missing_vertices_are_reported() {
+        // the four nearest points of the query are coplanar
+        let mut cloud: Vec<P3> = (0..4)
+            .map(|i| P3::from([(i % 2) as f32, (i / 2) as f32, 0.0]))
+            .collect();
+        cloud.push(P3::from([0.0, 0.0, 50.0]));
+        let tree = KDTree::new(&cloud);
+        let query = P3::from([0.4, 0.6, 0.1]);
+        let result = tree.knn_barycentric(&query, &Parameters::default());
+        assert_eq!(result.indices.len(), 4);
+        assert_eq!(result.coordinates, None);
+        assert!(!result.inside);
+        // collinear up to rounding
+        let line: Vec<P2> = (0..3)
+            .map(|i| P2::new2d(0.1 * i as f32, 0.3 * i as f32))
+            .collect();
+        let result = KDTree::new(&line).knn_barycentric(&line[1], &Parameters::default());
+        assert_eq!(result.coordinates, None);
+        // fewer than D + 1 points within the radius
+        let parameters = Parameters {
+            max_radius: 1.0,
+            ..Parameters::default()
+        };
+        let result = tree.knn_barycentric(&P3::from([0.0, 0.0, 49.5]), &parameters);
+        assert_eq!(result.indices, vec![4]);
+        assert_eq!(result.coordinates, None);
+        assert!(!result.inside);
+    }
+}
@@ -0,0 +1,596 @@
+//! Queries for many points at once, in parallel if the `rayon` feature is enabled
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+
+use crate::distance_scale::unscale_dist2;
+use crate::heap::CandidateHeap;
+use crate::internal_parameters::InternalParameters;
+use crate::{
+    CancellationToken, CandidateCollector, CandidateContainer, Error, InternalNeighbour, KDTree,
+    Neighbour, Parameters, Point, PreparedParameters, QueryError, QueryOverride, Scalar,
+};
+
+/// Number of consecutive queries a worker processes at once in `map_indices_folded`
+#[cfg_attr(not(feature = "rayon"), allow(dead_code))]
+const FOLD_CHUNK_SIZE: usize = 1024;
+
+/// Number of consecutive rows of the output buffers a worker fills at once in `knn_batch_into`
+const ROWS_CHUNK_SIZE: usize = 256;
+
+impl<T: Scalar + Send + Sync, P: Point<T> + Send + Sync> KDTree<T, P> {
+    /// Finds the `k` nearest neighbour of each of `queries`, with user-provided parameters.
+    ///
+    /// Returns the neighbours of each query in the order of `queries`, see [`Self::knn_advanced`].
+    /// If the `rayon` feature is enabled, queries are processed in parallel.
+    pub fn knn_batch(
+        &self,
+        k: u32,
+        queries: &[P],
+        candidate_container: CandidateContainer,
+        parameters: &Parameters<T>,
+    ) -> Vec<Vec<Neighbour<T, P>>> {
+        self.map_indices(queries.len(), |index| {
+            self.knn_advanced(k, &queries[index], candidate_container, parameters, None)
+        })
+    }
+
+    /// Finds the `k` nearest neighbour of each of `queries`, with user-provided parameters
+    /// overridden for each query by the element of `per_query` at the same position.
+    ///
+    /// Returns the neighbours of each query in the order of `queries`, see [`Self::knn_batch`],
+    /// or an error if `per_query` does not have the same length as `queries`,
+    /// or if the parameters of a query are invalid.
+    pub fn knn_batch_with(
+        &self,
+        k: u32,
+        queries: &[P],
+        per_query: &[QueryOverride<T>],
+        candidate_container: CandidateContainer,
+        parameters: &Parameters<T>,
+    ) -> Result<Vec<Vec<Neighbour<T, P>>>, Error> {
+        if per_query.len() != queries.len() {
+            return Err(QueryError::OverrideCountMismatch {
+                queries: queries.len(),
+                overrides: per_query.len(),
+            }
+            .into());
+        }
+        parameters.validate()?;
+        for query_override in per_query {
+            query_override.apply(parameters).validate()?;
+        }
+        Ok(self.map_indices(queries.len(), |index| {
+            let parameters = per_query[index].apply(parameters);
+            self.knn_advanced(k, &queries[index], candidate_container, &parameters, None)
+        }))
+    }
+
+    /// Finds the `k` nearest neighbour of each of `queries`, see [`Self::knn_batch`],
+    /// or returns [`QueryError::Cancelled`] if `cancellation` gets cancelled.
+    ///
+    /// Cancellation is checked before each chunk of queries.
+    pub fn knn_batch_cancellable(
+        &self,
+        k: u32,
+        queries: &[P],
+        candidate_container: CandidateContainer,
+        parameters: &Parameters<T>,
+        cancellation: &CancellationToken,
+    ) -> Result<Vec<Vec<Neighbour<T, P>>>, QueryError> {
+        self.map_indices_with_cancellable(
+            queries.len(),
+            || (),
+            |_, index| self.knn_advanced(k, &queries[index], candidate_container, parameters, None),
+            Some(cancellation),
+        )
+    }
+
+    /// Finds the `k` nearest neighbour of each of `queries`, with user-provided parameters,
+    /// and writes them to row-major buffers of `k` entries per query, for callers owning the memory.
+    ///
+    /// Row `i`, at positions `i * k..(i + 1) * k`, holds the indices in the original point cloud
+    /// and the squared distances of the neighbours of `queries[i]`, in the order of [`Self::knn_advanced`],
+    /// and the entries beyond the neighbours found are set to `fill`, for example `(u32::MAX, T::infinity())`.
+    /// The candidate container is the default one for `k`, as in [`Self::knn`],
+    /// each worker reusing its own between queries, so that nothing is allocated per query.
+    /// If the `rayon` feature is enabled, chunks of rows are filled in parallel.
+    ///
+    /// Returns [`QueryError::OutputLengthMismatch`] if a buffer does not have `k` entries per query,
+    /// before writing anything, an error if the parameters are invalid, see [`Parameters::validate`],
+    /// or the error of [`InsufficientPolicy::Error`](crate::InsufficientPolicy::Error),
+    /// in which case the rows of other queries may have been written.
+    pub fn knn_batch_into(
+        &self,
+        k: u32,
+        queries: &[P],
+        parameters: &Parameters<T>,
+        out_indices: &mut [u32],
+        out_dists2: &mut [T],
+        fill: (u32, T),
+    ) -> Result<(), Error> {
+        let expected = queries.len().saturating_mul(k as usize);
+        if out_indices.len() != expected || out_dists2.len() != expected {
+            return Err(QueryError::OutputLengthMismatch {
+                expected,
+                indices: out_indices.len(),
+                dists2: out_dists2.len(),
+            }
+            .into());
+        }
+        let parameters = self.try_prepare_parameters(parameters)?;
+        if k == 0 {
+            return Ok(());
+        }
+        match Self::default_candidate_container(k) {
+            CandidateContainer::Linear => self.knn_rows_into::<Vec<InternalNeighbour<T>>>(
+                k,
+                queries,
+                &parameters,
+                out_indices,
+                out_dists2,
+                fill,
+            ),
+            CandidateContainer::BinaryHeap => self
+                .knn_rows_into::<BinaryHeap<InternalNeighbour<T>>>(
+                    k,
+                    queries,
+                    &parameters,
+                    out_indices,
+                    out_dists2,
+                    fill,
+                ),
+        }
+    }
+
+    /// Fills the rows of [`Self::knn_batch_into`] by chunks, with a candidate container of type `H` per worker
+    fn knn_rows_into<H: CandidateHeap<T> + CandidateCollector<T>>(
+        &self,
+        k: u32,
+        queries: &[P],
+        parameters: &PreparedParameters<T>,
+        out_indices: &mut [u32],
+        out_dists2: &mut [T],
+        fill: (u32, T),
+    ) -> Result<(), Error> {
+        let row_len = k as usize;
+        // there cannot be more neighbours than points, avoid allocating for them
+        let k = k.min(self.len() as u32);
+        self.try_for_each_rows_with(
+            out_indices,
+            out_dists2,
+            row_len,
+            || (H::new_with_k(k), Vec::with_capacity(k as usize)),
+            |(heap, candidates), first_row, indices, dists2| {
+                let rows = indices.chunks_mut(row_len).zip(dists2.chunks_mut(row_len));
+                for (query, (indices, dists2)) in queries[first_row..].iter().zip(rows) {
+                    heap.clear();
+                    let query = self.query_coordinates(query);
+                    self.knn_internal(heap, &query, &parameters.internal);
+                    self.complete_insufficient(heap, k, &query, &parameters.internal)?;
+                    candidates.clear();
+                    heap.extend_into(candidates, parameters.sort_results);
+                    for (slot, (index, dist2)) in
+                        indices.iter_mut().zip(dists2.iter_mut()).enumerate()
+                    {
+                        (*index, *dist2) = match candidates.get(slot) {
+                            Some(candidate) => (
+                                self.externalise_index(candidate.index),
+                                unscale_dist2(candidate.dist2, self.scale).into_inner(),
+                            ),
+                            None => fill,
+                        };
+                    }
+                }
+                Ok(())
+            },
+        )
+    }
+
+    /// Finds the nearest point of the tree to each of `points`, for example the nearest centroid in k-means.
+    ///
+    /// Returns, for each of `points`, the index of its nearest point in the cloud of the tree,
+    /// and the squared distance to it.
+    /// If the tree is empty, the index is `u32::MAX` and the squared distance infinity.
+    ///
+    /// If given, `prev` holds a previous assignment of each of `points`, for example from the
+    /// previous iteration of k-means, whose squared distances are ignored.
+    /// The current distance to the previously assigned point then bounds the search from the start,
+    /// which speeds it up when assignments change little.
+    /// Results are the same as without `prev`, except that among several nearest points at the same distance,
+    /// the previously assigned one is preferred.
+    /// If the `rayon` feature is enabled, points are processed in parallel.
+    ///
+    /// Panics if `prev` does not have the same length as `points`, or holds an index out of range,
+    /// and if `points` have NaN coordinates.
+    pub fn assign_nearest(&self, points: &[P], prev: Option<&[(u32, T)]>) -> Vec<(u32, T)> {
+        if let Some(prev) = prev {
+            assert_eq!(
+                prev.len(),
+                points.len(),
+                "There are {} previous assignments for {} points",
+                prev.len(),
+                points.len()
+            );
+        }
+        let internal_parameters = InternalParameters::new(&Parameters::default(), self.scale);
+        self.map_indices(points.len(), |index| {
+            let query = self.query_coordinates(&points[index]);
+            let hint = prev.map(|prev| self.internalise_index(prev[index].0));
+            let nearest = self.nearest_seeded(&query, hint, &internal_parameters);
+            if self.is_empty() {
+                (u32::MAX, nearest.dist2.into_inner())
+            } else {
+                (
+                    self.externalise_index(nearest.index),
+                    unscale_dist2(nearest.dist2, self.scale).into_inner(),
+                )
+            }
+        })
+    }
+
+    /// Fills `heap` with the nearest neighbours of the stored point of index `source`
+    /// in the original point cloud, including itself.
+    ///
+    /// `heap` must be empty, and have a capacity of one more than the number of neighbours to find.
+    /// If the point is not in `heap` after the search, `heap` is full of its duplicates.
+    pub(crate) fn knn_of_stored_point(
+        &self,
+        source: u32,
+        heap: &mut BinaryHeap<InternalNeighbour<T>>,
+        internal_parameters: &InternalParameters<T>,
+    ) {
+        let start = self.internalise_index(source) as usize * P::DIM as usize;
+        self.knn_internal(
+            heap,
+            &self.points[start..start + P::DIM as usize],
+            internal_parameters,
+        );
+    }
+
+    /// Returns `f(state, index)` for each index in `0..count`, in parallel if the `rayon` feature is enabled,
+    /// with `state` created by `init` and reused between indices
+    #[cfg(feature = "rayon")]
+    pub(crate) fn map_indices_with<S, R: Send>(
+        &self,
+        count: usize,
+        init: impl Fn() -> S + Sync + Send,
+        f: impl Fn(&mut S, usize) -> R + Sync + Send,
+    ) -> Vec<R> {
+        use rayon::prelude::*;
+        (0..count).into_par_iter().map_init(init, f).collect()
+    }
+
+    /// Returns `f(state, index)` for each index in `0..count`, in parallel if the `rayon` feature is enabled,
+    /// with `state` created by `init` and reused between indices
+    #[cfg(not(feature = "rayon"))]
+    pub(crate) fn map_indices_with<S, R: Send>(
+        &self,
+        count: usize,
+        init: impl Fn() -> S + Sync + Send,
+        f: impl Fn(&mut S, usize) -> R + Sync + Send,
+    ) -> Vec<R> {
+        let mut state = init();
+        (0..count).map(|index| f(&mut state, index)).collect()
+    }
+
+    /// Calls `f(state, item)` for each of `items`, in parallel if the `rayon` feature is enabled,
+    /// with `state` created by `init` and reused between items
+    #[cfg(feature = "rayon")]
+    pub(crate) fn for_each_with<S, I: Send>(
+        &self,
+        items: Vec<I>,
+        init: impl Fn() -> S + Sync + Send,
+        f: impl Fn(&mut S, I) + Sync + Send,
+    ) {
+        use rayon::prelude::*;
+        items.into_par_iter().for_each_init(init, f);
+    }
+
+    /// Calls `f(state, item)` for each of `items`, in parallel if the `rayon` feature is enabled,
+    /// with `state` created by `init` and reused between items
+    #[cfg(not(feature = "rayon"))]
+    pub(crate) fn for_each_with<S, I: Send>(
+        &self,
+        items: Vec<I>,
+        init: impl Fn() -> S + Sync + Send,
+        f: impl Fn(&mut S, I) + Sync + Send,
+    ) {
+        let mut state = init();
+        for item in items {
+            f(&mut state, item);
+        }
+    }
+
+    /// Calls `f(state, first_row, a, b)` for each chunk of [`ROWS_CHUNK_SIZE`] rows of `row_len` elements
+    /// of both `a` and `b`, in parallel if the `rayon` feature is enabled, with `state` created by `init`
+    /// and reused between chunks, and returns the first error
+    #[cfg(feature = "rayon")]
+    pub(crate) fn try_for_each_rows_with<S, A: Send, B: Send, E: Send>(
+        &self,
+        a: &mut [A],
+        b: &mut [B],
+        row_len: usize,
+        init: impl Fn() -> S + Sync + Send,
+        f: impl Fn(&mut S, usize, &mut [A], &mut [B]) -> Result<(), E> + Sync + Send,
+    ) -> Result<(), E> {
+        use rayon::prelude::*;
+        let chunk_len = ROWS_CHUNK_SIZE * row_len;
+        a.par_chunks_mut(chunk_len)
+            .zip(b.par_chunks_mut(chunk_len))
+            .enumerate()
+            .try_for_each_init(init, |state, (chunk, (a, b))| {
+                f(state, chunk * ROWS_CHUNK_SIZE, a, b)
+            })
+    }
+
+    /// Calls `f(state, first_row, a, b)` for each chunk of [`ROWS_CHUNK_SIZE`] rows of `row_len` elements
+    /// of both `a` and `b`, in parallel if the `rayon` feature is enabled, with `state` created by `init`
+    /// and reused between chunks, and returns the first error
+    #[cfg(not(feature = "rayon"))]
+    pub(crate) fn try_for_each_rows_with<S, A: Send, B: Send, E: Send>(
+        &self,
+        a: &mut [A],
+        b: &mut [B],
+        row_len: usize,
+        init: impl Fn() -> S + Sync + Send,
+        f: impl Fn(&mut S, usize, &mut [A], &mut [B]) -> Result<(), E> + Sync + Send,
+    ) -> Result<(), E> {
+        let chunk_len = ROWS_CHUNK_SIZE * row_len;
+        let mut state = init();
+        for (chunk, (a, b)) in a
+            .chunks_mut(chunk_len)
+            .zip(b.chunks_mut(chunk_len))
+            .enumerate()
+        {
+            f(&mut state, chunk * ROWS_CHUNK_SIZE, a, b)?;
+        }
+        Ok(())
+    }
+
+    /// Returns `f(state, index)` for each index in `0..count`, in parallel if the `rayon` feature is enabled,
+    /// with `state` created by `init` for each worker, and the states of all workers merged by `merge`
+    #[cfg(feature = "rayon")]
+    pub(crate) fn map_indices_folded<S: Send, R: Send>(
+        &self,
+        count: usize,
+        init: impl Fn() -> S + Sync + Send,
+        f: impl Fn(&mut S, usize) -> R + Sync + Send,
+        merge: impl Fn(S, S) -> S + Sync + Send,
+    ) -> (Vec<R>, S) {
+        use rayon::prelude::*;
+        let chunk_count = (count + FOLD_CHUNK_SIZE - 1) / FOLD_CHUNK_SIZE;
+        let (mut chunks, state) = (0..chunk_count)
+            .into_par_iter()
+            .fold(
+                || (Vec::new(), init()),
+                |(mut chunks, mut state), chunk| {
+                    let start = chunk * FOLD_CHUNK_SIZE;
+                    let end = (start + FOLD_CHUNK_SIZE).min(count);
+                    let results: Vec<R> = (start..end).map(|index| f(&mut state, index)).collect();
+                    chunks.push((chunk, results));
+                    (chunks, state)
+                },
+            )
+            .reduce(
+                || (Vec::new(), init()),
+                |(mut chunks, state), (other_chunks, other_state)| {
+                    chunks.extend(other_chunks);
+                    (chunks, merge(state, other_state))
+                },
+            );
+        chunks.sort_unstable_by_key(|(chunk, _)| *chunk);
+        let results = chunks
+            .into_iter()
+            .flat_map(|(_, results)| results)
+            .collect();
+        (results, state)
+    }
+
+    /// Returns `f(state, index)` for each index in `0..count`, in parallel if the `rayon` feature is enabled,
+    /// with `state` created by `init` for each worker, and the states of all workers merged by `merge`
+    #[cfg(not(feature = "rayon"))]
+    pub(crate) fn map_indices_folded<S: Send, R: Send>(
+        &self,
+        count: usize,
+        init: impl Fn() -> S + Sync + Send,
+        f: impl Fn(&mut S, usize) -> R + Sync + Send,
+        _merge: impl Fn(S, S) -> S + Sync + Send,
+    ) -> (Vec<R>, S) {
+        let mut state = init();
+        let results = (0..count).map(|index| f(&mut state, index)).collect();
+        (results, state)
+    }
+
+    /// Returns `f(index)` for each index in `0..count`, in parallel if the `rayon` feature is enabled
+    #[cfg(feature = "rayon")]
+    pub(crate) fn map_indices<R: Send>(
+        &self,
+        count: usize,
+        f: impl Fn(usize) -> R + Sync + Send,
+    ) -> Vec<R> {
+        use rayon::prelude::*;
+        (0..count).into_par_iter().map(f).collect()
+    }
+
+    /// Returns `f(index)` for each index in `0..count`, in parallel if the `rayon` feature is enabled
+    #[cfg(not(feature = "rayon"))]
+    pub(crate) fn map_indices<R: Send>(
+        &self,
+        count: usize,
+        f: impl Fn(usize) -> R + Sync + Send,
+    ) -> Vec<R> {
+        (0..count).map(f).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn knn_batch_matches_individual_queries() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let tree = KDTree::new(&cloud);
+        let queries: Vec<P2> = random_point_cloud(100);
+        let parameters = Parameters::default();
+        let batch = tree.knn_batch(5, &queries, CandidateContainer::Linear, &parameters);
+        assert_eq!(batch.len(), queries.len());
+        for (query, neighbours) in queries.iter().zip(&batch) {
+            assert_eq!(neighbours, &tree.knn(5, query));
+        }
+    }
+
+    #[test]
+    fn knn_batch_with_overrides_per_query() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let tree = KDTree::new(&cloud);
+        let queries: Vec<P2> = cloud[..50].to_vec();
+        let per_query: Vec<QueryOverride<f32>> = (0..50)
+            .map(|i| QueryOverride {
+                max_radius: (i % 3 != 0).then_some(i as f32),
+                self_match: (i % 2 == 0).then_some(SelfMatch::ExcludeCoincident),
+            })
+            .collect();
+        let parameters = Parameters::default();
+        let batch = tree
+            .knn_batch_with(
+                4,
+                &queries,
+                &per_query,
+                CandidateContainer::Linear,
+                &parameters,
+            )
+            .unwrap();
+        for ((query, query_override), neighbours) in queries.iter().zip(&per_query).zip(&batch) {
+            let expected = tree.knn_advanced(
+                4,
+                query,
+                CandidateContainer::Linear,
+                &query_override.apply(&parameters),
+                None,
+            );
+            assert_eq!(neighbours, &expected);
+        }
+        assert_eq!(
+            tree.knn_batch_with(
+                4,
+                &queries,
+                &per_query[1..],
+                CandidateContainer::Linear,
+                &parameters
+            ),
+            Err(Error::Query(QueryError::OverrideCountMismatch {
+                queries: 50,
+                overrides: 49
+            }))
+        );
+        let negative = vec![
+            QueryOverride {
+                max_radius: Some(-1.0),
+                self_match: None
+            };
+            50
+        ];
+        assert_eq!(
+            tree.knn_batch_with(
+                4,
+                &queries,
+                &negative,
+                CandidateContainer::Linear,
+                &parameters
+            ),
+            Err(Error::Parameter(ParameterError::NegativeMaxRadius))
+        );
+    }
+
+    #[test]
+    fn knn_batch_into_fills_rows_and_checks_lengths() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let tree = KDTree::new(&cloud);
+        let queries: Vec<P2> = random_point_cloud(600);
+        let fill = (u32::MAX, f32::INFINITY);
+        for k in [3, 40] {
+            let parameters = Parameters {
+                max_radius: 6.0,
+                ..Parameters::default()
+            };
+            let len = queries.len() * k as usize;
+            let (mut indices, mut dists2) = (vec![0; len], vec![0.0; len]);
+            tree.knn_batch_into(k, &queries, &parameters, &mut indices, &mut dists2, fill)
+                .unwrap();
+            let batch = tree.knn_batch(k, &queries, CandidateContainer::Linear, &parameters);
+            for (row, neighbours) in batch.iter().enumerate() {
+                let row = row * k as usize..(row + 1) * k as usize;
+                for (slot, (index, dist2)) in
+                    indices[row.clone()].iter().zip(&dists2[row]).enumerate()
+                {
+                    let expected = neighbours.get(slot).map_or(fill, |n| (n.index, *n.dist2));
+                    assert_eq!((*index, *dist2), expected);
+                }
+            }
+            assert!(indices.contains(&u32::MAX));
+        }
+        // fewer points than k
+        let small = KDTree::new(&cloud[..2]);
+        let (mut indices, mut dists2) = (vec![0; 8], vec![0.0; 8]);
+        small
+            .knn_batch_into(
+                4,
+                &queries[..2],
+                &Parameters::default(),
+                &mut indices,
+                &mut dists2,
+                (7, -1.0),
+            )
+            .unwrap();
+        assert_eq!(indices[2..4], [7, 7]);
+        assert_eq!(dists2[6..], [-1.0, -1.0]);
+        assert_eq!(
+            small.knn_batch_into(
+                4,
+                &queries[..2],
+                &Parameters::default(),
+                &mut indices[..7],
+                &mut dists2,
+                fill
+            ),
+            Err(Error::Query(QueryError::OutputLengthMismatch {
+                expected: 8,
+                indices: 7,
+                dists2: 8
+            }))
+        );
+        assert_eq!(
+            tree.knn_batch_into(0, &queries, &Parameters::default(), &mut [], &mut [], fill),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn assign_nearest_with_and_without_previous_assignment() {
+        let points: Vec<P2> = random_point_cloud(2000);
+        let mut centroids: Vec<P2> = random_point_cloud(64);
+        let tree = KDTree::new(&centroids);
+        let assignment = tree.assign_nearest(&points, None);
+        for (point, (index, dist2)) in points.iter().zip(&assignment) {
+            let nearest = tree.knn(1, point)[0];
+            assert_eq!(
+                (*index, *dist2),
+                (nearest.index, nearest.dist2.into_inner())
+            );
+        }
+        // move the centroids a bit, and warm-start from the previous assignment
+        for centroid in &mut centroids {
+            *centroid = *centroid + P2::new2d(1.0, -1.0);
+        }
+        let tree = KDTree::new(&centroids);
+        let warm = tree.assign_nearest(&points, Some(&assignment));
+        assert_eq!(warm, tree.assign_nearest(&points, None));
+        let empty = KDTree::<f32, P2>::new(&[]);
+        assert_eq!(
+            empty.assign_nearest(&points[..1], None),
+            vec![(u32::MAX, f32::INFINITY)]
+        );
+    }
+}
@@ -0,0 +1,333 @@
+//! Summaries of the work done by batches of queries
+
+use alloc::{vec, vec::Vec};
+
+#[cfg(not(feature = "strict-exact"))]
+use crate::KDForest;
+use crate::{CandidateContainer, KDTree, Neighbour, Parameters, Point, QueryStats, Scalar};
+
+/// Percentiles of a counter over the queries of a batch, see [`BatchStats`]
+///
+/// Values below 128 are exact; larger ones are rounded down to 1/16 of their power of two.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quantiles {
+    /// median
+    pub p50: u32,
+    /// 95th percentile
+    pub p95: u32,
+    /// 99th percentile
+    pub p99: u32,
+    /// largest value, exact
+    pub max: u32,
+}
+
+/// Summary of the work done by a batch of queries, see [`KDTree::knn_batch_with_stats`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BatchStats {
+    /// number of queries of the batch
+    pub queries: u64,
+    /// sum over the queries of the number of nodes visited, split and leaf
+    pub total_visited_nodes: u64,
+    /// sum over the queries of the number of leaves visited
+    pub total_visited_leaves: u64,
+    /// sum over the queries of the number of points whose distance was computed
+    pub total_touched_points: u64,
+    /// distribution over the queries of the number of nodes visited
+    pub visited_nodes: Quantiles,
+    /// distribution over the queries of the number of points whose distance was computed
+    pub touched_points: Quantiles,
+    /// number of queries that stopped because they reached their budget of checks
+    pub budget_exhausted: u64,
+    /// number of queries that returned fewer than `k` neighbours
+    pub short_results: u64,
+}
+
+/// Number of values below which the histogram buckets hold a single value
+const EXACT_BUCKETS: u32 = 128;
+
+/// Number of buckets per power of two beyond the exact buckets
+const SUB_BUCKETS: u32 = 16;
+
+/// Number of buckets to hold any `u32`
+const BUCKET_COUNT: usize = (EXACT_BUCKETS + (32 - 7) * SUB_BUCKETS) as usize;
+
+/// Log-linear histogram of `u32` values, mergeable by adding its counts
+#[derive(Clone, Debug)]
+struct CounterHistogram {
+    counts: Vec<u64>,
+    max: u32,
+}
+
+impl CounterHistogram {
+    fn new() -> Self {
+        CounterHistogram {
+            counts: vec![0; BUCKET_COUNT],
+            max: 0,
+        }
+    }
+
+    fn bucket(value: u32) -> usize {
+        if value < EXACT_BUCKETS {
+            return value as usize;
+        }
+        let log = 31 - value.leading_zeros();
+        let sub = (value >> (log - 4)) & (SUB_BUCKETS - 1);
+        (EXACT_BUCKETS + (log - 7) * SUB_BUCKETS + sub) as usize
+    }
+
+    /// Returns the smallest value falling in `bucket`
+    fn lower_bound(bucket: usize) -> u32 {
+        let bucket = bucket as u32;
+        if bucket < EXACT_BUCKETS {
+            return bucket;
+        }
+        let log = (bucket - EXACT_BUCKETS) / SUB_BUCKETS + 7;
+        let sub = (bucket - EXACT_BUCKETS) % SUB_BUCKETS;
+        (SUB_BUCKETS + sub) << (log - 4)
+    }
+
+    fn add(&mut self, value: u32) {
+        self.counts[Self::bucket(value)] += 1;
+        self.max = self.max.max(value);
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.max = self.max.max(other.max);
+    }
+
+    /// Returns the value of rank `⌈q n⌉` among the `n` values, rounded down to its bucket
+    fn quantile(&self, q: f64, n: u64) -> u32 {
+        let rank = ((q * n as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Self::lower_bound(bucket).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    fn quantiles(&self, n: u64) -> Quantiles {
+        if n == 0 {
+            return Quantiles::default();
+        }
+        Quantiles {
+            p50: self.quantile(0.5, n),
+            p95: self.quantile(0.95, n),
+            p99: self.quantile(0.99, n),
+            max: self.max,
+        }
+    }
+}
+
+/// Counters of the queries processed by a single worker, merged at the end of a batch
+#[derive(Clone, Debug)]
+struct BatchAccumulator {
+    stats: BatchStats,
+    visited_nodes: CounterHistogram,
+    touched_points: CounterHistogram,
+}
+
+impl BatchAccumulator {
+    fn new() -> Self {
+        BatchAccumulator {
+            stats: BatchStats::default(),
+            visited_nodes: CounterHistogram::new(),
+            touched_points: CounterHistogram::new(),
+        }
+    }
+
+    fn add(&mut self, query: QueryStats, budget_exhausted: bool, short: bool) {
+        let stats = &mut self.stats;
+        stats.queries += 1;
+        stats.total_visited_nodes += query.visited_nodes as u64;
+        stats.total_visited_leaves += query.visited_leaves as u64;
+        stats.total_touched_points += query.touched_points as u64;
+        stats.budget_exhausted += budget_exhausted as u64;
+        stats.short_results += short as u64;
+        self.visited_nodes.add(query.visited_nodes);
+        self.touched_points.add(query.touched_points);
+    }
+
+    fn merge(mut self, other: Self) -> Self {
+        let (stats, other_stats) = (&mut self.stats, &other.stats);
+        stats.queries += other_stats.queries;
+        stats.total_visited_nodes += other_stats.total_visited_nodes;
+        stats.total_visited_leaves += other_stats.total_visited_leaves;
+        stats.total_touched_points += other_stats.total_touched_points;
+        stats.budget_exhausted += other_stats.budget_exhausted;
+        stats.short_results += other_stats.short_results;
+        self.visited_nodes.merge(&other.visited_nodes);
+        self.touched_points.merge(&other.touched_points);
+        self
+    }
+
+    fn finish(self) -> BatchStats {
+        let queries = self.stats.queries;
+        BatchStats {
+            visited_nodes: self.visited_nodes.quantiles(queries),
+            touched_points: self.touched_points.quantiles(queries),
+            ..self.stats
+        }
+    }
+}
+
+impl<T: Scalar + Send + Sync, P: Point<T> + Send + Sync> KDTree<T, P> {
+    /// Finds the `k` nearest neighbour of each of `queries`, see [`Self::knn_batch`],
+    /// and returns a summary of the work done by the queries.
+    ///
+    /// Each worker counts its own queries, and the counts are merged once the batch is done.
+    /// Exact searches have no budget, so [`BatchStats::budget_exhausted`] is zero.
+    pub fn knn_batch_with_stats(
+        &self,
+        k: u32,
+        queries: &[P],
+        candidate_container: CandidateContainer,
+        parameters: &Parameters<T>,
+    ) -> (Vec<Vec<Neighbour<T, P>>>, BatchStats) {
+        let parameters = self.prepare_parameters(parameters);
+        let (neighbours, accumulator) = self.map_indices_folded(
+            queries.len(),
+            BatchAccumulator::new,
+            |accumulator, index| {
+                let (neighbours, stats) = self.knn_with_stats_prepared(
+                    k,
+                    &queries[index],
+                    candidate_container,
+                    &parameters,
+                );
+                accumulator.add(stats, false, neighbours.len() < k as usize);
+                neighbours
+            },
+            BatchAccumulator::merge,
+        );
+        (neighbours, accumulator.finish())
+    }
+}
+
+#[cfg(not(feature = "strict-exact"))]
+impl<T: Scalar + Send + Sync, P: Point<T> + Send + Sync> KDForest<T, P> {
+    /// Finds approximately the `k` nearest neighbours of each of `queries`, see [`Self::knn`],
+    /// and returns a summary of the work done by the queries.
+    ///
+    /// A query exhausts its budget when it stops because it checked `max_checks` points.
+    /// If the `rayon` feature is enabled, queries are processed in parallel.
+    pub fn knn_batch_with_stats(
+        &self,
+        k: u32,
+        queries: &[P],
+        max_checks: u32,
+    ) -> (Vec<Vec<Neighbour<T, P>>>, BatchStats) {
+        let (neighbours, accumulator) = self.trees()[0].map_indices_folded(
+            queries.len(),
+            BatchAccumulator::new,
+            |accumulator, index| {
+                let (neighbours, stats, budget_exhausted) =
+                    self.knn_counted(k, &queries[index], max_checks);
+                accumulator.add(stats, budget_exhausted, neighbours.len() < k as usize);
+                neighbours
+            },
+            BatchAccumulator::merge,
+        );
+        (neighbours, accumulator.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CounterHistogram;
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn batch_stats_sum_the_stats_of_the_queries() {
+        let cloud: Vec<P2> = random_point_cloud(3000);
+        let queries: Vec<P2> = random_point_cloud(2500);
+        let tree = KDTree::new(&cloud);
+        let parameters = Parameters {
+            max_radius: 6.0,
+            ..Parameters::default()
+        };
+        let (neighbours, stats) =
+            tree.knn_batch_with_stats(8, &queries, CandidateContainer::BinaryHeap, &parameters);
+        let mut expected = BatchStats {
+            queries: 2500,
+            ..BatchStats::default()
+        };
+        let mut touched = Vec::new();
+        for (query, found) in queries.iter().zip(&neighbours) {
+            let (single, query_stats) =
+                tree.knn_with_stats(8, query, CandidateContainer::BinaryHeap, &parameters);
+            assert_eq!(found, &single);
+            expected.total_visited_nodes += query_stats.visited_nodes as u64;
+            expected.total_visited_leaves += query_stats.visited_leaves as u64;
+            expected.total_touched_points += query_stats.touched_points as u64;
+            expected.short_results += (single.len() < 8) as u64;
+            touched.push(query_stats.touched_points);
+        }
+        assert_eq!(stats.total_visited_nodes, expected.total_visited_nodes);
+        assert_eq!(stats.total_visited_leaves, expected.total_visited_leaves);
+        assert_eq!(stats.total_touched_points, expected.total_touched_points);
+        assert_eq!(stats.short_results, expected.short_results);
+        assert_eq!(stats.queries, 2500);
+        assert_eq!(stats.budget_exhausted, 0);
+        // the radius leaves queries in sparse areas short of neighbours
+        assert!(stats.short_results > 0);
+        touched.sort_unstable();
+        assert_eq!(stats.touched_points.max, touched[2499]);
+        // percentiles are rounded down by at most 1/16
+        for (q, reported) in [
+            (1250, stats.touched_points.p50),
+            (2375, stats.touched_points.p95),
+        ] {
+            let exact = touched[q - 1];
+            assert!(reported <= exact && exact - reported <= exact / 16);
+        }
+        // a forest exhausts the budget of checks for most queries
+        #[cfg(not(feature = "strict-exact"))]
+        {
+            let forest = KDForest::new(&cloud, 4, 3);
+            let (approximate, forest_stats) = forest.knn_batch_with_stats(8, &queries, 32);
+            let mut exhausted = 0;
+            for (query, found) in queries.iter().zip(&approximate) {
+                assert_eq!(found, &forest.knn(8, query, 32));
+                exhausted += forest.knn_counted(8, query, 32).2 as u64;
+            }
+            assert_eq!(forest_stats.budget_exhausted, exhausted);
+            assert!(exhausted > 0);
+            assert_eq!(forest_stats.short_results, 0);
+            let (none, empty) = forest.knn_batch_with_stats(8, &[], 32);
+            assert!(none.is_empty());
+            assert_eq!(empty, BatchStats::default());
+        }
+    }
+
+    #[test]
+    fn histograms_are_exact_below_128_and_merge_by_addition() {
+        let mut low = CounterHistogram::new();
+        let mut high = CounterHistogram::new();
+        for value in 1..=100 {
+            low.add(value);
+        }
+        high.add(u32::MAX);
+        high.add(1000);
+        assert_eq!(CounterHistogram::bucket(u32::MAX), super::BUCKET_COUNT - 1);
+        for value in [0, 127, 128, 135, 136, 1000, 1 << 20, u32::MAX] {
+            let bucket = CounterHistogram::bucket(value);
+            let lower = CounterHistogram::lower_bound(bucket);
+            assert!(lower <= value && value - lower <= value / 16);
+            assert_eq!(CounterHistogram::bucket(lower), bucket);
+        }
+        let quantiles = low.quantiles(100);
+        assert_eq!((quantiles.p50, quantiles.p95, quantiles.p99), (50, 95, 99));
+        low.merge(&high);
+        let merged = low.quantiles(102);
+        assert_eq!(merged.max, u32::MAX);
+        assert_eq!(merged.p50, 51);
+        assert_eq!(merged.p99, 1000 - 1000 % 32);
+    }
+}
@@ -0,0 +1,617 @@
+//! Removing and inserting batches of points, repairing the tree once for the whole batch
+
+use alloc::vec::Vec;
+
+use num_traits::Zero;
+use ordered_float::NotNan;
+
+use crate::node::Node;
+use crate::refit::check_finite;
+use crate::self_check::SELF_CHECK_SAMPLE;
+use crate::time_slice::checked_timestamps;
+use crate::{BitSet, BuildError, BuildOptions, Error, KDTree, Nodes, Point, QueryError, Scalar};
+
+/// A subtree is rebuilt if more than its number of points divided by this ratio are removed from it
+/// or inserted into it
+const REBUILD_RATIO: usize = 2;
+
+/// The external indices of the points after a batch, see [`KDTree::apply_batch`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BatchIndices {
+    /// new external index of each inserted point, in the order of the insertions
+    pub inserted: Vec<u32>,
+    /// new external index of each external index before the batch, `None` for the removed points
+    pub renumbered: Vec<Option<u32>>,
+}
+
+/// The changes of a batch, staged before repairing the tree
+struct Staged<T: Scalar> {
+    /// removed points, by internal index
+    removed: BitSet,
+    /// new external index of each old external index, `u32::MAX` for the removed points
+    renumbered: Vec<u32>,
+    /// coordinates of the inserted points
+    points: Vec<NotNan<T>>,
+    /// new external index of each inserted point
+    indices: Vec<u32>,
+    /// leaf each inserted point descends to and its position in the batch, sorted by leaf
+    leaves: Vec<(u32, u32)>,
+    /// number of points, number of removed and inserted points, and end of the subtree of each node
+    subtrees: Vec<(usize, usize, usize)>,
+}
+
+impl<T: Scalar> Staged<T> {
+    /// Returns the positions in the batch of the points inserted into the leaves of `nodes`
+    fn inserted_into(&self, nodes: core::ops::Range<usize>) -> impl Iterator<Item = u32> + '_ {
+        let start = self
+            .leaves
+            .partition_point(|(leaf, _)| (*leaf as usize) < nodes.start);
+        let end = self
+            .leaves
+            .partition_point(|(leaf, _)| (*leaf as usize) < nodes.end);
+        self.leaves[start..end]
+            .iter()
+            .map(|(_, position)| *position)
+    }
+}
+
+/// The storage of the repaired tree, written subtree by subtree
+struct Repaired<T: Scalar, P: Point<T>> {
+    nodes: Nodes<T, P>,
+    points: Vec<NotNan<T>>,
+    indices: Vec<u32>,
+    root_rebuilt: bool,
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Removes the points of external indices `removals` and inserts `insertions`,
+    /// returning the new external index of each inserted point and of each former point.
+    ///
+    /// All changes are staged first, then the tree is repaired in a single pass:
+    /// a subtree in which more than half as many points as it holds are removed or inserted
+    /// is rebuilt in place, and the leaves of the other subtrees are edited, the inserted points
+    /// going to the leaf they descend to, so that the splits of the tree are kept where little changes.
+    /// This rewrites the storage of the points once for the whole batch, in time linear in the number of points,
+    /// far cheaper than rebuilding the tree or applying the changes one by one.
+    /// Leaves receiving points can grow up to half again the bucket size, which [`quality`](Self::quality) reports.
+    ///
+    /// The inserted points take the indices of the removed ones, in increasing order,
+    /// then the indices following the last one, so that the points not removed keep their index.
+    /// If fewer points are inserted than removed, the indices stay contiguous by moving
+    /// the points of the largest indices, in increasing order, to the freed indices below the new number of points,
+    /// in increasing order: [`BatchIndices::renumbered`] then gives the new index of these points,
+    /// to update the data kept alongside them by external index.
+    /// If the tree has identifiers, the inserted points have their index as identifier, see [`Self::id_of`].
+    /// Duplicate removals are ignored.
+    /// If any point is removed, the generation of the tree is incremented, see [`Self::generation`].
+    /// Panics if a removed index is out of range, if an inserted point has an infinite coordinate,
+    /// if the tree would have more than `u32::MAX` points, or if points are inserted into a tree with timestamps,
+    /// which needs [`Self::apply_batch_with_timestamps`].
+    pub fn apply_batch(&mut self, removals: &[u32], insertions: &[P]) -> BatchIndices {
+        assert!(
+            insertions.is_empty() || !self.has_timestamps(),
+            "the tree has timestamps, insert with KDTree::apply_batch_with_timestamps"
+        );
+        self.apply_stamped_batch(removals, insertions, &[])
+    }
+
+    /// Same as [`Self::apply_batch`] for a tree with timestamps, see [`Self::with_timestamps`],
+    /// `timestamps[i]` being the timestamp of `insertions[i]`, keeping the ranges of timestamps per node.
+    ///
+    /// Panics if the tree has no timestamps, if `timestamps` does not have one value per inserted point
+    /// or has a NaN, or in the same cases as [`Self::apply_batch`].
+    pub fn apply_batch_with_timestamps(
+        &mut self,
+        removals: &[u32],
+        insertions: &[P],
+        timestamps: &[T],
+    ) -> BatchIndices {
+        assert!(
+            self.has_timestamps(),
+            "the tree has no timestamps, see KDTree::with_timestamps"
+        );
+        if timestamps.len() != insertions.len() {
+            panic!(
+                "{}",
+                BuildError::ValuesLengthMismatch {
+                    points: insertions.len(),
+                    values: timestamps.len(),
+                }
+            );
+        }
+        let timestamps = checked_timestamps(timestamps).unwrap_or_else(|error| panic!("{}", error));
+        self.apply_stamped_batch(removals, insertions, &timestamps)
+    }
+
+    /// Applies the batch, `inserted_timestamps` being the timestamps of `insertions` if the tree has timestamps
+    fn apply_stamped_batch(
+        &mut self,
+        removals: &[u32],
+        insertions: &[P],
+        inserted_timestamps: &[NotNan<T>],
+    ) -> BatchIndices {
+        let staged = self.stage_batch(removals, insertions);
+        let new_len = self.len() - staged.removed.count() + insertions.len();
+        let ids = self.ids.as_ref().map(|_| {
+            let mut ids = vec![0; new_len];
+            for (external_index, new_index) in staged.renumbered.iter().enumerate() {
+                if *new_index != u32::MAX {
+                    ids[*new_index as usize] = self.id_of(external_index as u32);
+                }
+            }
+            for new_index in &staged.indices {
+                ids[*new_index as usize] = *new_index as u64;
+            }
+            ids
+        });
+        let timestamps = self.external_timestamps().map(|old_timestamps| {
+            let mut timestamps = vec![NotNan::<T>::zero(); new_len];
+            for (external_index, new_index) in staged.renumbered.iter().enumerate() {
+                if *new_index != u32::MAX {
+                    timestamps[*new_index as usize] = old_timestamps[external_index];
+                }
+            }
+            for (new_index, timestamp) in staged.indices.iter().zip(inserted_timestamps) {
+                timestamps[*new_index as usize] = *timestamp;
+            }
+            timestamps
+        });
+        let dim = P::DIM as usize;
+        let originals = self.originals.as_ref().map(|old_originals| {
+            let mut originals = vec![NotNan::<T>::zero(); new_len * dim];
+            for (external_index, new_index) in staged.renumbered.iter().enumerate() {
+                if *new_index != u32::MAX {
+                    let (old, new) = (external_index * dim, *new_index as usize * dim);
+                    originals[new..new + dim].copy_from_slice(&old_originals[old..old + dim]);
+                }
+            }
+            for (new_index, point) in staged.indices.iter().zip(insertions) {
+                let new = *new_index as usize * dim;
+                for i in 0..P::DIM {
+                    originals[new + i as usize] = point.get(i);
+                }
+            }
+            originals
+        });
+        let mut repaired = Repaired {
+            nodes: Vec::with_capacity(self.nodes.len()),
+            points: Vec::with_capacity(new_len * dim),
+            indices: Vec::with_capacity(new_len),
+            root_rebuilt: false,
+        };
+        self.repair(0, &staged, &mut repaired);
+        self.nodes = repaired.nodes;
+        self.points = repaired.points;
+        self.indices = repaired.indices;
+        self.originals = originals;
+        if repaired.root_rebuilt {
+            self.drift = NotNan::<T>::zero();
+        }
+        self.update_internal_indices();
+        self.set_external_ids(ids);
+        self.set_external_timestamps(timestamps);
+        self.update_bounds_and_scale();
+        self.refresh_subtree_sums();
+        if staged.removed.count() > 0 || !insertions.is_empty() {
+            self.refresh_coarse();
+        }
+        self.mark_axis_indices_stale();
+        if staged.removed.count() > 0 {
+            self.bump_generation();
+        }
+        debug_assert_eq!(self.self_check(SELF_CHECK_SAMPLE, 0), Ok(()));
+        BatchIndices {
+            inserted: staged.indices,
+            renumbered: staged
+                .renumbered
+                .iter()
+                .map(|index| Some(*index).filter(|index| *index != u32::MAX))
+                .collect(),
+        }
+    }
+
+    /// Same as [`Self::apply_batch`], but returns [`QueryError::IndexOutOfRange`] if a removed index
+    /// is out of range, [`BuildError::NonFiniteCoordinate`] with its position in `insertions`
+    /// if an inserted point has an infinite coordinate, and [`BuildError::TooManyPoints`]
+    /// if the tree would have more than `u32::MAX` points, leaving the tree unchanged.
+    pub fn try_apply_batch(
+        &mut self,
+        removals: &[u32],
+        insertions: &[P],
+    ) -> Result<BatchIndices, Error> {
+        let len = self.len();
+        let mut removed = BitSet::with_len(len);
+        for external_index in removals {
+            if *external_index as usize >= len {
+                return Err(QueryError::IndexOutOfRange {
+                    index: *external_index,
+                    len,
+                }
+                .into());
+            }
+            removed.insert(*external_index as usize);
+        }
+        let new_len = len - removed.count() + insertions.len();
+        if new_len > u32::MAX as usize {
+            return Err(BuildError::TooManyPoints(new_len).into());
+        }
+        for (position, point) in insertions.iter().enumerate() {
+            self.check_finite_in_frame(point, position as u32)?;
+        }
+        Ok(self.apply_batch(removals, insertions))
+    }
+
+    /// Validates the batch, assigns the new external indices and finds the leaves of the inserted points
+    fn stage_batch(&mut self, removals: &[u32], insertions: &[P]) -> Staged<T> {
+        let len = self.len();
+        let mut removed = BitSet::with_len(len);
+        for external_index in removals {
+            assert!(
+                (*external_index as usize) < len,
+                "Index {} is out of range for a tree of {} points",
+                external_index,
+                len
+            );
+            removed.insert(self.internalise_index(*external_index) as usize);
+        }
+        let new_len = len - removed.count() + insertions.len();
+        if new_len > u32::MAX as usize {
+            panic!("{}", BuildError::TooManyPoints(new_len));
+        }
+        let dim = P::DIM as usize;
+        let mut points = Vec::with_capacity(insertions.len() * dim);
+        for (position, point) in insertions.iter().enumerate() {
+            let rotated = self.rotated_point(point);
+            let point = rotated.as_ref().unwrap_or(point);
+            check_finite(point, position as u32).unwrap_or_else(|error| panic!("{}", error));
+            points.extend((0..P::DIM).map(|i| point.get(i)));
+        }
+        // assign the freed indices to the inserted points, then the following ones
+        let is_removed =
+            |external: u32| removed.contains(self.internalise_index(external) as usize);
+        let freed: Vec<u32> = (0..len as u32).filter(|index| is_removed(*index)).collect();
+        let indices: Vec<u32> = (0..insertions.len())
+            .map(|i| {
+                freed
+                    .get(i)
+                    .copied()
+                    .unwrap_or((len - freed.len() + i) as u32)
+            })
+            .collect();
+        let mut renumbered: Vec<u32> = (0..len as u32)
+            .map(|index| if is_removed(index) { u32::MAX } else { index })
+            .collect();
+        if freed.len() > insertions.len() {
+            let holes = freed[insertions.len()..]
+                .iter()
+                .filter(|index| (**index as usize) < new_len);
+            let moved = (new_len as u32..len as u32).filter(|index| !is_removed(*index));
+            for (hole, index) in holes.zip(moved) {
+                renumbered[index as usize] = *hole;
+            }
+        }
+        if self.nodes.is_empty() {
+            self.nodes.push(Node::new_leaf_node(0, 0));
+        }
+        let mut leaves: Vec<(u32, u32)> = (0..insertions.len())
+            .map(|position| {
+                let leaf = self.leaf_of(&points[position * dim..(position + 1) * dim]);
+                (leaf as u32, position as u32)
+            })
+            .collect();
+        leaves.sort_unstable();
+        let mut staged = Staged {
+            removed,
+            renumbered,
+            points,
+            indices,
+            leaves,
+            subtrees: Vec::new(),
+        };
+        let mut subtrees = vec![(0, 0, 0); self.nodes.len()];
+        self.count_changes(0, &staged, &mut subtrees);
+        staged.subtrees = subtrees;
+        staged
+    }
+
+    /// Returns the leaf that `point` descends to
+    fn leaf_of(&self, point: &[NotNan<T>]) -> usize {
+        let mut node = 0;
+        while let Some((split_dim, split_val, right_child)) = self.nodes[node].dispatch_on_type(
+            (),
+            |_, split_dim, split_val, right_child| Some((split_dim, split_val, right_child)),
+            |_, _, _| None,
+        ) {
+            node = if point[split_dim as usize] <= split_val {
+                node + 1
+            } else {
+                right_child as usize
+            };
+        }
+        node
+    }
+
+    /// Computes the entries of `subtrees` of `node` and its descendants, and returns the number of points below `node`
+    fn count_changes(
+        &self,
+        node: usize,
+        staged: &Staged<T>,
+        subtrees: &mut [(usize, usize, usize)],
+    ) -> usize {
+        let (size, changes, end) = self.nodes[node].dispatch_on_type(
+            &mut *subtrees,
+            |subtrees, _, _, right_child| {
+                let left_size = self.count_changes(node + 1, staged, subtrees);
+                let right_child = right_child as usize;
+                let right_size = self.count_changes(right_child, staged, subtrees);
+                let changes = subtrees[node + 1].1 + subtrees[right_child].1;
+                (left_size + right_size, changes, subtrees[right_child].2)
+            },
+            |_, bucket_start_index, bucket_size| {
+                let bucket = bucket_start_index..bucket_start_index + bucket_size;
+                let removed = bucket
+                    .filter(|index| staged.removed.contains(*index as usize))
+                    .count();
+                let inserted = staged.inserted_into(node..node + 1).count();
+                (bucket_size as usize, removed + inserted, node + 1)
+            },
+        );
+        subtrees[node] = (size, changes, end);
+        size
+    }
+
+    /// Writes the repaired subtree of `node` to `repaired`, rebuilding it if it changes too much
+    fn repair(&self, node: usize, staged: &Staged<T>, repaired: &mut Repaired<T, P>) {
+        let (size, changes, end) = staged.subtrees[node];
+        if changes > 0 && changes * REBUILD_RATIO > size {
+            self.rebuild_subtree(node..end, staged, repaired);
+            repaired.root_rebuilt |= node == 0;
+            return;
+        }
+        let dim = P::DIM as usize;
+        self.nodes[node].dispatch_on_type(
+            repaired,
+            |repaired, split_dim, split_val, right_child| {
+                let position = repaired.nodes.len();
+                repaired
+                    .nodes
+                    .push(Node::new_split_node(split_dim, split_val));
+                self.repair(node + 1, staged, repaired);
+                let right_position = repaired.nodes.len() as u32;
+                repaired.nodes[position].set_child_index(right_position);
+                self.repair(right_child as usize, staged, repaired);
+            },
+            |repaired, bucket_start_index, bucket_size| {
+                let new_start_index = repaired.indices.len() as u32;
+                for index in bucket_start_index..bucket_start_index + bucket_size {
+                    if staged.removed.contains(index as usize) {
+                        continue;
+                    }
+                    let start = index as usize * dim;
+                    repaired
+                        .points
+                        .extend_from_slice(&self.points[start..start + dim]);
+                    let external_index = self.indices[index as usize] as usize;
+                    repaired.indices.push(staged.renumbered[external_index]);
+                }
+                for position in staged.inserted_into(node..node + 1) {
+                    let start = position as usize * dim;
+                    repaired
+                        .points
+                        .extend_from_slice(&staged.points[start..start + dim]);
+                    repaired.indices.push(staged.indices[position as usize]);
+                }
+                let new_size = repaired.indices.len() as u32 - new_start_index;
+                repaired
+                    .nodes
+                    .push(Node::new_leaf_node(new_start_index, new_size));
+            },
+        );
+    }
+
+    /// Builds a tree from the points remaining in and inserted into the subtree of `nodes`,
+    /// and writes it to `repaired` in place of the subtree
+    fn rebuild_subtree(
+        &self,
+        nodes: core::ops::Range<usize>,
+        staged: &Staged<T>,
+        repaired: &mut Repaired<T, P>,
+    ) {
+        let dim = P::DIM as usize;
+        let mut cloud = Vec::new();
+        let mut indices = Vec::new();
+        for node in nodes.clone() {
+            self.nodes[node].dispatch_on_type(
+                (),
+                |_, _, _, _| (),
+                |_, bucket_start_index, bucket_size| {
+                    for index in bucket_start_index..bucket_start_index + bucket_size {
+                        if !staged.removed.contains(index as usize) {
+                            // in the frame of the tree, like the inserted points
+                            cloud.push(P::from_slice(self.stored_coordinates(index)));
+                            let external_index = self.indices[index as usize] as usize;
+                            indices.push(staged.renumbered[external_index]);
+                        }
+                    }
+                },
+            );
+        }
+        for position in staged.inserted_into(nodes) {
+            let start = position as usize * dim;
+            cloud.push(P::from_slice(&staged.points[start..start + dim]));
+            indices.push(staged.indices[position as usize]);
+        }
+        let options = BuildOptions {
+            bucket_size: self.options.bucket_size,
+            duplicate_leaves: self.options.duplicate_leaves,
+            high_dimension: self.options.high_dimension,
+            ..BuildOptions::default()
+        };
+        let subtree = KDTree::try_build(&cloud, &options, None, None)
+            .unwrap_or_else(|error| panic!("{}", error));
+        let node_offset = repaired.nodes.len() as u32;
+        let point_offset = repaired.indices.len() as u32;
+        repaired.nodes.extend(subtree.nodes.iter().map(|node| {
+            node.dispatch_on_type(
+                (),
+                |_, split_dim, split_val, right_child| {
+                    let mut split = Node::new_split_node(split_dim, split_val);
+                    split.set_child_index(right_child + node_offset);
+                    split
+                },
+                |_, bucket_start_index, bucket_size| {
+                    Node::new_leaf_node(bucket_start_index + point_offset, bucket_size)
+                },
+            )
+        }));
+        repaired.points.extend_from_slice(&subtree.points);
+        repaired
+            .indices
+            .extend(subtree.indices.iter().map(|index| indices[*index as usize]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    /// Applies the batch to `cloud` as [`KDTree::apply_batch`] numbers the points
+    fn apply_to_cloud(
+        cloud: &[P2],
+        removals: &[u32],
+        insertions: &[P2],
+        indices: &[u32],
+    ) -> Vec<P2> {
+        let new_len = cloud.len() - removals.len() + insertions.len();
+        let mut result: Vec<Option<P2>> = cloud.iter().map(|p| Some(*p)).collect();
+        for index in removals {
+            result[*index as usize] = None;
+        }
+        result.resize(cloud.len().max(new_len), None);
+        for (point, index) in insertions.iter().zip(indices) {
+            assert!(result[*index as usize].is_none());
+            result[*index as usize] = Some(*point);
+        }
+        // the points beyond the new length fill the remaining holes in order
+        let mut moved: Vec<P2> = result.drain(new_len..).flatten().collect();
+        moved.reverse();
+        result
+            .into_iter()
+            .map(|point| point.or_else(|| moved.pop()).unwrap())
+            .collect()
+    }
+
+    fn distinct_indices(len: usize, count: usize) -> Vec<u32> {
+        let mut indices: Vec<u32> = (0..len as u32).collect();
+        let mut rng = rng::SplitMix64::new(count as u64);
+        for i in (1..len).rev() {
+            indices.swap(i, rng.below(i as u64 + 1) as usize);
+        }
+        indices.truncate(count);
+        indices
+    }
+
+    #[test]
+    fn batches_match_trees_built_from_scratch() {
+        let cloud: Vec<P2> = random_point_cloud(3000);
+        // small batches edit leaves, large ones rebuild subtrees up to the whole tree
+        for (removed, inserted) in [
+            (10, 10),
+            (40, 5),
+            (5, 60),
+            (600, 900),
+            (2500, 100),
+            (3000, 0),
+        ] {
+            let mut tree = KDTree::new(&cloud);
+            let removals = distinct_indices(cloud.len(), removed);
+            let insertions: Vec<P2> = random_point_cloud(inserted);
+            let indices = tree.apply_batch(&removals, &insertions);
+            let expected = apply_to_cloud(&cloud, &removals, &insertions, &indices.inserted);
+            assert_eq!(tree.points(), expected);
+            assert_eq!(indices.renumbered.len(), cloud.len());
+            for (index, new_index) in indices.renumbered.iter().enumerate() {
+                match new_index {
+                    Some(new_index) => assert_eq!(expected[*new_index as usize], cloud[index]),
+                    None => assert!(removals.contains(&(index as u32))),
+                }
+            }
+            let reference = KDTree::new(&expected);
+            for _ in 0..30 {
+                let query: P2 = random_point();
+                assert_eq!(tree.knn(6, &query), reference.knn(6, &query));
+            }
+            assert_eq!(tree.self_check(200, 0), Ok(()));
+        }
+        let mut empty = KDTree::<f32, P2>::new(&[]);
+        let inserted: Vec<P2> = random_point_cloud(100);
+        assert_eq!(
+            empty.apply_batch(&[], &inserted).inserted,
+            (0..100).collect::<Vec<_>>()
+        );
+        assert_eq!(empty.points(), inserted);
+    }
+
+    #[test]
+    fn batches_keep_the_indices_and_identifiers_of_untouched_points() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let ids: Vec<u64> = (0..cloud.len() as u64).map(|id| id + 5000).collect();
+        let mut tree = KDTree::new_with_ids(&cloud, &ids);
+        let unchanged_nodes = format!("{:?}", tree).matches("Node(").count();
+        let insertions: Vec<P2> = random_point_cloud(4);
+        let indices = tree.apply_batch(&[7, 3, 7, 500], &insertions);
+        // the freed indices are reused in increasing order, then the following ones
+        assert_eq!(indices.inserted, vec![3, 7, 500, 1000]);
+        // a small batch keeps the structure of the tree
+        assert_eq!(
+            format!("{:?}", tree).matches("Node(").count(),
+            unchanged_nodes
+        );
+        for index in 0..cloud.len() as u32 {
+            if ![3, 7, 500].contains(&index) {
+                assert_eq!(tree.points()[index as usize], cloud[index as usize]);
+                assert_eq!(tree.id_of(index), ids[index as usize]);
+            }
+        }
+        assert_eq!(tree.id_of(1000), 1000);
+        // when fewer points are inserted, the last ones move down to the freed indices
+        let indices = tree.apply_batch(&[0, 1, 2], &insertions[..1]);
+        assert_eq!(indices.inserted, vec![0]);
+        assert_eq!(tree.len(), 999);
+        assert_eq!(indices.renumbered[..4], [None, None, None, Some(3)]);
+        assert_eq!(indices.renumbered[999..], [Some(1), Some(2)]);
+        assert_eq!(tree.points()[1], cloud[999]);
+        assert_eq!(tree.points()[2], insertions[3]);
+        assert_eq!((tree.id_of(1), tree.id_of(2)), (ids[999], 1000));
+    }
+
+    #[test]
+    fn invalid_batches_are_rejected_and_leave_the_tree_unchanged() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let mut tree = KDTree::new(&cloud);
+        let reference = tree.clone();
+        let inserted = [P2::new2d(0.0, 0.0), P2::new2d(f32::INFINITY, 0.0)];
+        assert_eq!(
+            tree.try_apply_batch(&[2], &inserted),
+            Err(Error::Build(BuildError::NonFiniteCoordinate { index: 1 }))
+        );
+        assert_eq!(
+            tree.try_apply_batch(&[2, 1000], &inserted[..1]),
+            Err(Error::Query(QueryError::IndexOutOfRange {
+                index: 1000,
+                len: 1000
+            }))
+        );
+        assert_eq!(tree, reference);
+        assert_eq!(
+            tree.try_apply_batch(&[2], &inserted[..1]),
+            Ok(BatchIndices {
+                inserted: vec![2],
+                renumbered: (0..1000)
+                    .map(|index| Some(index).filter(|index| *index != 2))
+                    .collect(),
+            })
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use alloc::{vec, vec::Vec};
+
+/// A fixed-size set of indices, stored as one bit per index
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BitSet {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl BitSet {
+    /// Creates an empty set able to hold the indices `0..len`.
+    pub fn with_len(len: usize) -> Self {
+        BitSet {
+            words: vec![0; (len + 63) / 64],
+            len,
+        }
+    }
+
+    /// Returns the number of indices this set can hold.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether this set can hold no index.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns whether `index` is in the set.
+    ///
+    /// Panics if `index` is not smaller than [`Self::len`].
+    pub fn contains(&self, index: usize) -> bool {
+        assert!(
+            index < self.len,
+            "Index {} is out of range {}",
+            index,
+            self.len
+        );
+        self.words[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    /// Adds `index` to the set, returning whether it was not in the set before.
+    ///
+    /// Panics if `index` is not smaller than [`Self::len`].
+    pub fn insert(&mut self, index: usize) -> bool {
+        assert!(
+            index < self.len,
+            "Index {} is out of range {}",
+            index,
+            self.len
+        );
+        let word = &mut self.words[index / 64];
+        let mask = 1 << (index % 64);
+        let inserted = *word & mask == 0;
+        *word |= mask;
+        inserted
+    }
+
+    /// Removes `index` from the set, returning whether it was in the set before.
+    ///
+    /// Panics if `index` is not smaller than [`Self::len`].
+    pub fn remove(&mut self, index: usize) -> bool {
+        assert!(
+            index < self.len,
+            "Index {} is out of range {}",
+            index,
+            self.len
+        );
+        let word = &mut self.words[index / 64];
+        let mask = 1 << (index % 64);
+        let removed = *word & mask != 0;
+        *word &= !mask;
+        removed
+    }
+
+    /// Removes all indices from the set.
+    pub fn clear(&mut self) {
+        self.words.iter_mut().for_each(|word| *word = 0);
+    }
+
+    /// Returns the number of indices in the set.
+    pub fn count(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BitSet;
+
+    #[test]
+    fn insert_contains_remove() {
+        let mut set = BitSet::with_len(130);
+        assert_eq!(set.len(), 130);
+        assert_eq!(set.count(), 0);
+        assert!(set.insert(0));
+        assert!(set.insert(64));
+        assert!(set.insert(129));
+        assert!(!set.insert(64));
+        assert!(set.contains(129));
+        assert!(!set.contains(128));
+        assert_eq!(set.count(), 3);
+        assert!(set.remove(64));
+        assert!(!set.remove(64));
+        set.clear();
+        assert_eq!(set.count(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn out_of_range_panics() {
+        BitSet::with_len(10).contains(10);
+    }
+}
@@ -0,0 +1,154 @@
+use crate::BuildError;
+
+/// Options for building a [`KDTree`](crate::KDTree)
+///
+/// They are validated once when building, and recorded in the tree, see [`KDTree::build_options`](crate::KDTree::build_options).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BuildOptions {
+    /// maximum number of points in a leaf, must be at least 2
+    pub bucket_size: u32,
+    /// sorts the points along a Morton (Z-order) curve before building
+    ///
+    /// The partitioning steps then access nearly-local memory, which speeds up the build of large clouds.
+    /// The tree might differ from the one built without sorting, but queries return the same neighbours.
+    pub morton_presort: bool,
+    /// precomputes the count, coordinate sums and outer product sums of the points of each node
+    ///
+    /// [`KDTree::aggregate_within`](crate::KDTree::aggregate_within) then adds whole subtrees at once.
+    /// This stores `1 + D + D (D + 1) / 2` values of type `f64` per node, and makes point updates slower.
+    pub subtree_sums: bool,
+    /// stores the points of a range with identical coordinates as a single leaf, whatever its size
+    ///
+    /// This avoids splitting large runs of duplicates down to `bucket_size`, which keeps builds fast and trees shallow.
+    /// Queries reaching such a leaf compute the distance to all its points, so prefer it if queries rarely fall near duplicates.
+    pub duplicate_leaves: bool,
+    /// randomises the splits as in `KDForest`, from this seed
+    ///
+    /// Each split is then along one of the dimensions of highest variance, at the mean.
+    /// The tree only depends on the point cloud and the options, including the seed, on all platforms.
+    /// Without a seed, the build is deterministic and splits the largest dimension of each box.
+    pub seed: Option<u64>,
+    /// maximum number of splits on a path from the root to a leaf
+    ///
+    /// The points of a range reaching this depth are stored as a single leaf, whatever its size,
+    /// which [`TreeStats::oversized_leaf_count`](crate::TreeStats::oversized_leaf_count) reports.
+    /// Without a maximum, or with one above [`Self::MAX_SAFE_DEPTH`], building a deeper tree fails
+    /// with [`BuildError::TooDeep`], as the queries recurse once per split.
+    pub max_depth: Option<u32>,
+    /// rotates the points by a random rotation drawn from this seed before building
+    ///
+    /// Points on axis-aligned structures, such as scan lines or grids, then no longer lie on the splits,
+    /// which improves the balance of the tree and reduces the nodes queries visit.
+    /// The rotation, see [`KDTree::rotation`](crate::KDTree::rotation), is applied to every query point and
+    /// to moved or inserted points, and searches compare the distances in the rotated frame,
+    /// which differ from those of the points by rounding.
+    /// Queries thus return the neighbours of an unrotated tree, except for the points at a distance
+    /// within rounding of a radius or of the `k`-th neighbour, which may be included or excluded differently.
+    /// This matters for points at exactly equal distances, as on grids: a closed radius equal to
+    /// the spacing may miss neighbours at that distance, an open one may return them,
+    /// and ties at the `k`-th distance may be broken differently.
+    /// The tree also keeps the points as given, which it returns exactly, rebuilds from, and tests
+    /// against the boxes of [`KDTree::range_query`](crate::KDTree::range_query) and the slabs of
+    /// [`KDTree::axis_range`](crate::KDTree::axis_range), which are thus in the frame of the points.
+    /// The bounds, the aggregates, the nodes, the dumps and the flat export are in the rotated frame of the tree.
+    pub rotation_seed: Option<u64>,
+    /// also builds a coarse tree of every `coarse_stride`-th point, by index, must be at least 1
+    ///
+    /// `KDTree::knn_coarse_then_fine` answers from it first, then completes the exact search bounded by that answer;
+    /// with the `strict-exact` feature, which leaves out that query, the coarse tree is unused.
+    /// This stores about `1 / coarse_stride` times the tree again.
+    pub coarse_stride: Option<u32>,
+    /// how to store points of more dimensions than splits can prune, see [`HighDimension`]
+    pub high_dimension: HighDimension,
+}
+
+/// How to build a tree of points of many dimensions, see [`BuildOptions::high_dimension`]
+///
+/// Beyond about 20 dimensions, the ball of the current furthest candidate crosses most splits,
+/// so queries visit most leaves and a tree is slower than scanning all points.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HighDimension {
+    /// builds a tree whatever the number of dimensions, as `KDForest` needs
+    Tree,
+    /// above `max_tree_dim` dimensions, stores all points in a single leaf that every query scans,
+    /// which [`BuildReport::warning`](crate::BuildReport::warning) reports
+    ///
+    /// The leaf is scanned a block of points at a time, and queries return exactly the neighbours
+    /// and distances a tree would, so all queries run unchanged.
+    Flat {
+        /// the largest number of dimensions of a tree
+        max_tree_dim: u32,
+    },
+    /// above `max_tree_dim` dimensions, fails with [`BuildError::DimensionalityTooHigh`]
+    Reject {
+        /// the largest number of dimensions of a tree
+        max_tree_dim: u32,
+    },
+}
+
+impl Default for HighDimension {
+    /// Flat above [`BuildOptions::DEFAULT_MAX_TREE_DIM`] dimensions.
+    fn default() -> Self {
+        HighDimension::Flat {
+            max_tree_dim: BuildOptions::DEFAULT_MAX_TREE_DIM,
+        }
+    }
+}
+
+impl HighDimension {
+    /// Returns whether points of `dim` dimensions are stored in a single leaf
+    pub(crate) fn is_flat(&self, dim: u32) -> bool {
+        matches!(self, HighDimension::Flat { max_tree_dim } if dim > *max_tree_dim)
+    }
+
+    /// Checks that points of `dim` dimensions may be built into a tree
+    pub(crate) fn check(&self, dim: u32) -> Result<(), BuildError> {
+        match *self {
+            HighDimension::Reject { max_tree_dim } if dim > max_tree_dim => {
+                Err(BuildError::DimensionalityTooHigh { dim, max_tree_dim })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Default for BuildOptions {
+    /// Buckets of 8 points, without presorting, subtree sums, duplicate leaves, seed,
+    /// maximum depth, rotation nor coarse tree, and points stored flat above 24 dimensions.
+    fn default() -> Self {
+        BuildOptions {
+            bucket_size: 8,
+            morton_presort: false,
+            subtree_sums: false,
+            duplicate_leaves: false,
+            seed: None,
+            max_depth: None,
+            rotation_seed: None,
+            coarse_stride: None,
+            high_dimension: HighDimension::default(),
+        }
+    }
+}
+
+impl BuildOptions {
+    /// The largest depth of a tree, in splits, that queries recurse through within a small stack,
+    /// even in debug builds
+    ///
+    /// Trees of evenly spread points are much shallower, about `log2(n / bucket_size)` splits deep;
+    /// only extremely clustered points, such as coordinates in a geometric progression, reach it.
+    pub const MAX_SAFE_DEPTH: u32 = 1024;
+
+    /// The number of dimensions above which [`HighDimension::default`] stores the points flat
+    pub const DEFAULT_MAX_TREE_DIM: u32 = 24;
+
+    /// Checks that the options are valid.
+    pub fn validate(&self) -> Result<(), BuildError> {
+        if self.bucket_size < 2 {
+            return Err(BuildError::BucketSizeTooSmall(self.bucket_size));
+        }
+        if self.coarse_stride == Some(0) {
+            return Err(BuildError::CoarseStrideZero);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+//! Reports of the time and memory that building a tree took, for monitoring
+
+use core::mem::size_of;
+use core::time::Duration;
+
+use crate::{BuildError, BuildOptions, HighDimension, KDTree, Point, Scalar};
+
+/// The wall time of each phase of a build, see [`KDTree::try_new_with_report`]
+///
+/// Times are measured with the `std` feature, and are zero without it and on `wasm32-unknown-unknown`,
+/// which has no clock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BuildTimes {
+    /// validating the points, and copying them when rotated or presorted
+    pub ingest: Duration,
+    /// splitting the points recursively into nodes
+    pub split: Duration,
+    /// computing the indices from original to stored order, the bounds and the scale
+    pub index: Duration,
+    /// computing the optional structures: the subtree sums and the coarse tree
+    pub augment: Duration,
+}
+
+impl BuildTimes {
+    /// Returns the time of the whole build
+    pub fn total(&self) -> Duration {
+        self.ingest + self.split + self.index + self.augment
+    }
+}
+
+/// The bytes allocated for each structure of a tree, see [`KDTree::try_new_with_report`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BuildMemory {
+    /// the nodes
+    pub nodes: usize,
+    /// the coordinates of the points, and of the points as given if the tree is rotated
+    pub points: usize,
+    /// the indices from stored to original order and back
+    pub indices: usize,
+    /// the bounding box
+    pub bounds: usize,
+    /// the subtree sums, see [`BuildOptions::subtree_sums`]
+    pub subtree_sums: usize,
+    /// the whole coarse tree, see [`BuildOptions::coarse_stride`]
+    pub coarse: usize,
+}
+
+impl BuildMemory {
+    /// Returns the bytes allocated for all structures
+    pub fn total(&self) -> usize {
+        self.nodes + self.points + self.indices + self.bounds + self.subtree_sums + self.coarse
+    }
+}
+
+/// What building a tree took, see [`KDTree::try_new_with_report`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BuildReport {
+    /// the options the tree was built with
+    pub options: BuildOptions,
+    /// number of points
+    pub point_count: usize,
+    /// number of nodes, split and leaf, as [`TreeStats::node_count`](crate::TreeStats::node_count)
+    pub node_count: usize,
+    /// largest number of split nodes on a path from the root to a leaf, as [`TreeStats::depth`](crate::TreeStats::depth)
+    pub depth: u32,
+    /// the time of each phase
+    pub times: BuildTimes,
+    /// the memory of each structure
+    pub memory: BuildMemory,
+    /// how the tree differs from the one the options ask for, if it does
+    pub warning: Option<BuildWarning>,
+}
+
+/// A tree built differently than its options ask for, see [`BuildReport::warning`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuildWarning {
+    /// the points have more dimensions than splits can prune, so they are stored in a single leaf
+    /// that every query scans, see [`HighDimension::Flat`](crate::HighDimension::Flat)
+    FlatLayout {
+        /// the number of dimensions of the points
+        dim: u32,
+        /// the largest number of dimensions of a tree
+        max_tree_dim: u32,
+    },
+}
+
+#[cfg(all(
+    feature = "std",
+    not(all(target_arch = "wasm32", target_os = "unknown"))
+))]
+mod clock {
+    extern crate std;
+
+    use core::time::Duration;
+    use std::time::Instant;
+
+    /// Measures the time between the phases of a build
+    pub(crate) struct PhaseClock {
+        last: Instant,
+    }
+
+    impl PhaseClock {
+        pub(crate) fn start() -> Self {
+            PhaseClock {
+                last: Instant::now(),
+            }
+        }
+
+        /// Returns the time since the start or the previous lap
+        pub(crate) fn lap(&mut self) -> Duration {
+            let now = Instant::now();
+            let elapsed = now - self.last;
+            self.last = now;
+            elapsed
+        }
+    }
+}
+
+#[cfg(not(all(
+    feature = "std",
+    not(all(target_arch = "wasm32", target_os = "unknown"))
+)))]
+mod clock {
+    use core::time::Duration;
+
+    /// Stands for a clock where there is none, measuring zero
+    pub(crate) struct PhaseClock;
+
+    impl PhaseClock {
+        pub(crate) fn start() -> Self {
+            PhaseClock
+        }
+
+        pub(crate) fn lap(&mut self) -> Duration {
+            Duration::ZERO
+        }
+    }
+}
+
+pub(crate) use clock::PhaseClock;
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Creates a new KD-Tree from a point cloud, with user-provided build options, and reports
+    /// the time of each phase of the build and the memory of each structure,
+    /// or returns an error if it cannot be built, see [`Self::try_new_with_options`].
+    ///
+    /// The tree is the same as the one built by [`Self::try_new_with_options`].
+    pub fn try_new_with_report(
+        cloud: &[P],
+        options: &BuildOptions,
+    ) -> Result<(Self, BuildReport), BuildError> {
+        let mut times = BuildTimes::default();
+        let tree = Self::try_build_timed(cloud, options, None, None, &mut times)?;
+        let report = BuildReport {
+            options: *options,
+            point_count: tree.len(),
+            node_count: tree.nodes.len(),
+            depth: tree.stats().depth,
+            times,
+            memory: tree.memory(),
+            warning: match options.high_dimension {
+                HighDimension::Flat { max_tree_dim } if P::DIM > max_tree_dim => {
+                    Some(BuildWarning::FlatLayout {
+                        dim: P::DIM,
+                        max_tree_dim,
+                    })
+                }
+                _ => None,
+            },
+        };
+        Ok((tree, report))
+    }
+
+    /// Returns the bytes allocated for the structures of the tree
+    fn memory(&self) -> BuildMemory {
+        BuildMemory {
+            nodes: self.nodes.capacity() * size_of::<crate::Node<T, P>>(),
+            points: (self.points.capacity() + self.originals.as_ref().map_or(0, Vec::capacity))
+                * size_of::<T>(),
+            indices: (self.indices.capacity() + self.internal_indices.capacity())
+                * size_of::<u32>(),
+            bounds: (self.min_bounds.capacity() + self.max_bounds.capacity()) * size_of::<T>(),
+            subtree_sums: self
+                .subtree_sums
+                .as_ref()
+                .map_or(0, |sums| sums.capacity() * size_of::<f64>()),
+            coarse: self
+                .coarse
+                .as_ref()
+                .map_or(0, |coarse| coarse.memory().total()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn report_counts_match_stats() {
+        let cloud: Vec<P3> = random_point_cloud(5000);
+        for options in [
+            BuildOptions::default(),
+            BuildOptions {
+                morton_presort: true,
+                rotation_seed: Some(232),
+                ..BuildOptions::default()
+            },
+            BuildOptions {
+                bucket_size: 3,
+                subtree_sums: true,
+                coarse_stride: Some(10),
+                ..BuildOptions::default()
+            },
+        ] {
+            let (tree, report) =
+                KDTree::try_new_with_report(&cloud, &options).expect("valid cloud");
+            assert_eq!(tree, KDTree::new_with_options(&cloud, &options));
+            let stats = tree.stats();
+            assert_eq!(report.options, options);
+            assert_eq!(report.point_count, cloud.len());
+            assert_eq!(report.node_count, stats.node_count);
+            assert_eq!(report.depth, stats.depth);
+            let memory = report.memory;
+            assert!(memory.points >= cloud.len() * 3 * 4);
+            assert!(memory.indices >= cloud.len() * 2 * 4);
+            assert_eq!(memory.subtree_sums > 0, options.subtree_sums);
+            assert_eq!(memory.coarse > 0, options.coarse_stride.is_some());
+            assert!(memory.coarse < memory.total() / 5);
+            #[cfg(feature = "std")]
+            assert!(report.times.split > core::time::Duration::ZERO);
+            assert!(report.times.total() >= report.times.split);
+        }
+    }
+
+    #[test]
+    fn many_dimensions_are_stored_flat_or_rejected() {
+        let cloud: Vec<SimplePoint<30>> = random_point_cloud(1000);
+        let (flat, report) =
+            KDTree::try_new_with_report(&cloud, &BuildOptions::default()).expect("valid cloud");
+        assert_eq!(
+            report.warning,
+            Some(BuildWarning::FlatLayout {
+                dim: 30,
+                max_tree_dim: BuildOptions::DEFAULT_MAX_TREE_DIM
+            })
+        );
+        assert_eq!((report.node_count, report.depth), (1, 0));
+        let options = BuildOptions {
+            high_dimension: HighDimension::Tree,
+            ..BuildOptions::default()
+        };
+        let (tree, report) = KDTree::try_new_with_report(&cloud, &options).expect("valid cloud");
+        assert_eq!(report.warning, None);
+        assert!(report.depth > 0);
+        let parameters = Parameters {
+            max_radius: 400.0,
+            ..Parameters::default()
+        };
+        for query in cloud.iter().step_by(10) {
+            let query = *query * 0.9;
+            assert_eq!(flat.knn(7, &query), tree.knn(7, &query));
+            let container = CandidateContainer::BinaryHeap;
+            assert_eq!(
+                flat.knn_advanced(3, &query, container, &parameters, None),
+                tree.knn_advanced(3, &query, container, &parameters, None)
+            );
+        }
+        let options = BuildOptions {
+            high_dimension: HighDimension::Reject { max_tree_dim: 29 },
+            ..BuildOptions::default()
+        };
+        assert_eq!(
+            KDTree::try_new_with_options(&cloud, &options).err(),
+            Some(BuildError::DimensionalityTooHigh {
+                dim: 30,
+                max_tree_dim: 29
+            })
+        );
+        let small: Vec<P3> = random_point_cloud(100);
+        let (_, report) = KDTree::try_new_with_report(&small, &options).expect("few dimensions");
+        assert_eq!(report.warning, None);
+    }
+
+    #[test]
+    fn failed_builds_report_the_error() {
+        let options = BuildOptions {
+            bucket_size: 1,
+            ..BuildOptions::default()
+        };
+        assert_eq!(
+            KDTree::<f32, P2>::try_new_with_report(&[], &options).err(),
+            Some(BuildError::BucketSizeTooSmall(1))
+        );
+    }
+}
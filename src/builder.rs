@@ -0,0 +1,332 @@
+//! Two-phase construction of trees over pre-partitioned point clouds
+
+use alloc::vec::Vec;
+
+use num_traits::{Bounded, One, Zero};
+use ordered_float::NotNan;
+
+use crate::dim_buffer::DimBuffer;
+use crate::node::Node;
+use crate::rotation::random_rotation;
+use crate::{BuildError, BuildOptions, KDTree, Point, Scalar};
+
+/// A builder of a [`KDTree`] whose top levels are given, and whose subtrees are built independently
+///
+/// This allows building trees over clouds that are partitioned into spatial chunks beforehand,
+/// for example because they are too large to be partitioned in memory at once.
+/// The `L` top levels are a complete binary tree of splits, whose `2^L` leaves each receive the subtree of a chunk.
+/// Subtrees can be built from their points with [`Self::attach_subtree`],
+/// or built elsewhere, for example in another process and transferred as a [`FlatTree`](crate::FlatTree),
+/// and attached with [`Self::attach_tree`].
+///
+/// The index of a point in the final tree is its index in its chunk,
+/// offset by the number of points of the chunks of smaller leaf ids,
+/// so it does not depend on the order in which the subtrees are attached.
+pub struct KDTreeBuilder<T: Scalar, P: Point<T>> {
+    splits: Vec<(u32, NotNan<T>)>,
+    options: BuildOptions,
+    /// the subtree of each leaf, by leaf id
+    subtrees: Vec<Option<KDTree<T, P>>>,
+}
+
+impl<T: Scalar, P: Point<T>> KDTreeBuilder<T, P> {
+    /// Creates a builder with the given top splits, as pairs of split dimension and split value,
+    /// and the default build options.
+    ///
+    /// The splits are listed in breadth-first order: the children of split `i` are splits `2 i + 1` and `2 i + 2`,
+    /// the left one receiving the points whose coordinate is smaller or equal to the split value.
+    /// There must be `2^L - 1` splits for `L` levels, which define `2^L` leaves,
+    /// whose ids are numbered from left to right.
+    /// Returns [`BuildError::InvalidTopSplits`] if the number of splits is not of that form,
+    /// or if a split dimension or value is invalid.
+    pub fn with_top_splits(splits: &[(u32, T)]) -> Result<Self, BuildError> {
+        use BuildError::InvalidTopSplits;
+        if P::DIM == 0 {
+            return Err(BuildError::ZeroDimension);
+        }
+        if !(splits.len() + 1).is_power_of_two() {
+            return Err(InvalidTopSplits(
+                "the number of splits is not a power of two minus one",
+            ));
+        }
+        let splits = splits
+            .iter()
+            .map(|(split_dim, split_val)| {
+                if *split_dim >= P::DIM {
+                    return Err(InvalidTopSplits("a split dimension is out of bounds"));
+                }
+                match NotNan::new(*split_val) {
+                    Ok(split_val) if split_val.is_finite() => Ok((*split_dim, split_val)),
+                    _ => Err(InvalidTopSplits("a split value is not finite")),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let leaf_count = splits.len() + 1;
+        Ok(KDTreeBuilder {
+            splits,
+            options: BuildOptions::default(),
+            subtrees: (0..leaf_count).map(|_| None).collect(),
+        })
+    }
+
+    /// Sets the options used to build the subtrees in [`Self::attach_subtree`], and the final tree.
+    pub fn build_options(mut self, options: &BuildOptions) -> Self {
+        self.options = *options;
+        self
+    }
+
+    /// Returns the number of leaves of the top levels, to which subtrees are attached.
+    pub fn leaf_count(&self) -> u32 {
+        self.subtrees.len() as u32
+    }
+
+    /// Builds the subtree of the leaf of id `leaf_id` from the points of its chunk,
+    /// replacing any previously attached subtree.
+    ///
+    /// Returns an error if `leaf_id` is out of range,
+    /// or in the same cases as [`KDTree::try_new_with_options`].
+    pub fn attach_subtree(&mut self, leaf_id: u32, points: &[P]) -> Result<(), BuildError> {
+        self.check_leaf_id(leaf_id)?;
+        let tree = KDTree::try_new_with_options(points, &self.options)?;
+        self.subtrees[leaf_id as usize] = Some(tree);
+        Ok(())
+    }
+
+    /// Attaches `tree`, built from the points of the chunk of the leaf of id `leaf_id`,
+    /// replacing any previously attached subtree.
+    ///
+    /// Returns an error if `leaf_id` is out of range.
+    pub fn attach_tree(&mut self, leaf_id: u32, tree: KDTree<T, P>) -> Result<(), BuildError> {
+        self.check_leaf_id(leaf_id)?;
+        self.subtrees[leaf_id as usize] = Some(tree);
+        Ok(())
+    }
+
+    fn check_leaf_id(&self, leaf_id: u32) -> Result<(), BuildError> {
+        if leaf_id >= self.leaf_count() {
+            Err(BuildError::LeafIdOutOfRange {
+                leaf_id,
+                leaf_count: self.leaf_count(),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Stitches the top levels and the attached subtrees into a tree, leaves without subtree being empty.
+    ///
+    /// Points on the wrong side of a top split are allowed, and handled as after [`KDTree::refit`],
+    /// so chunks should follow the splits for queries to be fast.
+    /// Attached trees built with another [`BuildOptions::rotation_seed`] than the options of the builder
+    /// are rebuilt with them, and the top splits are in the frame of that rotation.
+    /// Returns an error if the tree would have more than `u32::MAX` points or too many nodes.
+    pub fn finalize(mut self) -> Result<KDTree<T, P>, BuildError> {
+        let rotation = self
+            .options
+            .rotation_seed
+            .map(|seed| random_rotation(P::DIM as usize, seed));
+        for subtree in self.subtrees.iter_mut().flatten() {
+            if subtree.rotation != rotation {
+                *subtree = KDTree::try_new_with_options(&subtree.points(), &self.options)?;
+            }
+        }
+        let point_count: usize = self.subtrees.iter().flatten().map(KDTree::len).sum();
+        if point_count > u32::MAX as usize {
+            return Err(BuildError::TooManyPoints(point_count));
+        }
+        let node_count = self.splits.len()
+            + self
+                .subtrees
+                .iter()
+                .map(|subtree| subtree.as_ref().map_or(1, |tree| tree.nodes.len()))
+                .sum::<usize>();
+        if node_count - 1 > P::MAX_NODE_COUNT as usize {
+            return Err(BuildError::TooManyNodes {
+                node_count,
+                max_node_count: P::MAX_NODE_COUNT,
+            });
+        }
+        let mut tree = KDTree {
+            options: self.options,
+            nodes: Vec::with_capacity(node_count),
+            points: Vec::with_capacity(point_count * P::DIM as usize),
+            indices: Vec::with_capacity(point_count),
+            internal_indices: Vec::new(),
+            min_bounds: Vec::new(),
+            max_bounds: Vec::new(),
+            scale: NotNan::one(),
+            drift: NotNan::<T>::zero(),
+            scaled_drift: NotNan::<T>::zero(),
+            subtree_sums: None,
+            ids: None,
+            axis_indices: Vec::new(),
+            // the indices of the chunks follow each other in leaf id order
+            originals: rotation.as_ref().map(|_| {
+                self.subtrees
+                    .iter()
+                    .flatten()
+                    .flat_map(|subtree| subtree.originals.iter().flatten().copied())
+                    .collect()
+            }),
+            rotation,
+            generation: 0,
+            coarse: None,
+            timestamps: None,
+        };
+        // external index of the first point of each chunk, in leaf id order
+        let mut index_offsets = Vec::with_capacity(self.subtrees.len());
+        let mut index_offset = 0;
+        for subtree in &self.subtrees {
+            index_offsets.push(index_offset);
+            index_offset += subtree.as_ref().map_or(0, |tree| tree.len() as u32);
+        }
+        self.stitch(&mut tree, 0, &index_offsets);
+        tree.update_internal_indices();
+        let mut lower = DimBuffer::new(P::DIM as usize, NotNan::<T>::min_value());
+        let mut upper = DimBuffer::new(P::DIM as usize, NotNan::<T>::max_value());
+        tree.drift = tree.recurse_drift(0, &mut lower, &mut upper);
+        tree.update_bounds_and_scale();
+        if self.options.subtree_sums {
+            tree.subtree_sums = Some(tree.compute_subtree_sums());
+        }
+        Ok(tree)
+    }
+
+    /// Appends to `tree`, in depth-first order, the nodes below the top node of breadth-first index `top`
+    fn stitch(&self, tree: &mut KDTree<T, P>, top: usize, index_offsets: &[u32]) {
+        if let Some((split_dim, split_val)) = self.splits.get(top) {
+            let pos = tree.nodes.len();
+            tree.nodes
+                .push(Node::new_split_node(*split_dim, *split_val));
+            self.stitch(tree, 2 * top + 1, index_offsets);
+            let right_child = tree.nodes.len() as u32;
+            tree.nodes[pos].set_child_index(right_child);
+            self.stitch(tree, 2 * top + 2, index_offsets);
+            return;
+        }
+        let leaf_id = top - self.splits.len();
+        let subtree = match &self.subtrees[leaf_id] {
+            Some(subtree) => subtree,
+            None => {
+                let bucket_start_index = tree.indices.len() as u32;
+                tree.nodes.push(Node::new_leaf_node(bucket_start_index, 0));
+                return;
+            }
+        };
+        let node_offset = tree.nodes.len() as u32;
+        let point_offset = tree.indices.len() as u32;
+        for node in &subtree.nodes {
+            let node = node.dispatch_on_type(
+                (),
+                |_, split_dim, split_val, right_child| {
+                    let mut node = Node::new_split_node(split_dim, split_val);
+                    node.set_child_index(right_child + node_offset);
+                    node
+                },
+                |_, bucket_start_index, bucket_size| {
+                    Node::new_leaf_node(bucket_start_index + point_offset, bucket_size)
+                },
+            );
+            tree.nodes.push(node);
+        }
+        tree.points.extend_from_slice(&subtree.points);
+        let index_offset = index_offsets[leaf_id];
+        tree.indices
+            .extend(subtree.indices.iter().map(|index| index + index_offset));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn stitched_tree_matches_brute_force() {
+        let cloud: Vec<P2> = random_point_cloud(2000);
+        // quadrants around the origin, as the chunks of an out-of-core build
+        let splits = [(0, 0.0), (1, 0.0), (1, 0.0)];
+        let quadrant = |p: &P2| (*p.get(0) > 0.0) as usize * 2 + (*p.get(1) > 0.0) as usize;
+        let chunks: Vec<Vec<P2>> = (0..4)
+            .map(|leaf| {
+                cloud
+                    .iter()
+                    .filter(|p| quadrant(p) == leaf)
+                    .copied()
+                    .collect()
+            })
+            .collect();
+        let mut builder = KDTreeBuilder::with_top_splits(&splits)
+            .unwrap()
+            .build_options(&BuildOptions {
+                bucket_size: 4,
+                ..BuildOptions::default()
+            });
+        assert_eq!(builder.leaf_count(), 4);
+        // attach in any order, one subtree being built elsewhere
+        for leaf in [3, 0, 2] {
+            builder
+                .attach_subtree(leaf, &chunks[leaf as usize])
+                .unwrap();
+        }
+        let flat = KDTree::new(&chunks[1]).export_flat();
+        builder.attach_tree(1, flat.import().unwrap()).unwrap();
+        let tree = builder.finalize().unwrap();
+        let stitched_cloud: Vec<P2> = chunks.concat();
+        assert_eq!(tree.len(), cloud.len());
+        assert_eq!(tree.points(), stitched_cloud);
+        assert_eq!(tree.drift_estimate().into_inner(), 0.0);
+        for _ in 0..30 {
+            let query: P2 = random_point();
+            let neighbours = tree.knn(5, &query);
+            let mut brute_force: Vec<(f32, u32)> = stitched_cloud
+                .iter()
+                .enumerate()
+                .map(|(index, p)| {
+                    let (dx, dy) = (*p.get(0) - *query.get(0), *p.get(1) - *query.get(1));
+                    (dx * dx + dy * dy, index as u32)
+                })
+                .collect();
+            brute_force.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let found: Vec<_> = neighbours.iter().map(|n| (*n.dist2, n.index)).collect();
+            assert_eq!(found, brute_force[..5]);
+        }
+        // missing chunks are empty, misplaced points are handled as drift
+        let mut builder = KDTreeBuilder::with_top_splits(&splits).unwrap();
+        builder.attach_subtree(0, &chunks[3]).unwrap();
+        let tree = builder.finalize().unwrap();
+        assert_eq!(tree.points(), chunks[3]);
+        assert!(tree.drift_estimate().into_inner() > 0.0);
+        assert_eq!(tree.knn(1, &chunks[3][7])[0].index, 7);
+    }
+
+    #[test]
+    fn invalid_top_splits_are_rejected() {
+        assert_eq!(
+            KDTreeBuilder::<f32, P2>::with_top_splits(&[(0, 0.0), (1, 0.0)]).err(),
+            Some(BuildError::InvalidTopSplits(
+                "the number of splits is not a power of two minus one"
+            ))
+        );
+        assert_eq!(
+            KDTreeBuilder::<f32, P2>::with_top_splits(&[(2, 0.0)]).err(),
+            Some(BuildError::InvalidTopSplits(
+                "a split dimension is out of bounds"
+            ))
+        );
+        assert_eq!(
+            KDTreeBuilder::<f32, P2>::with_top_splits(&[(0, f32::NAN)]).err(),
+            Some(BuildError::InvalidTopSplits("a split value is not finite"))
+        );
+        let mut builder = KDTreeBuilder::<f32, P2>::with_top_splits(&[]).unwrap();
+        assert_eq!(builder.leaf_count(), 1);
+        assert_eq!(
+            builder.attach_subtree(1, &[]),
+            Err(BuildError::LeafIdOutOfRange {
+                leaf_id: 1,
+                leaf_count: 1
+            })
+        );
+        assert!(builder.finalize().unwrap().is_empty());
+    }
+}
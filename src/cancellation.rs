@@ -0,0 +1,116 @@
+//! Cooperative cancellation of long-running builds and queries
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{KDTree, Point, QueryError, Scalar};
+
+/// Subtrees of at least this many points check for cancellation before being split
+pub(crate) const CANCELLATION_CHECK_SPAN: u32 = 1 << 16;
+
+/// Number of queries between two checks for cancellation
+const CANCELLATION_CHUNK_SIZE: usize = 256;
+
+/// A flag to cancel builds and queries from another thread
+///
+/// Cancellation is checked at coarse granularity, so a cancelled operation
+/// returns shortly after [`Self::cancel`], not immediately.
+#[derive(Debug, Default)]
+pub struct CancellationToken(AtomicBool);
+
+impl CancellationToken {
+    /// Creates a token that is not cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the operations using this token.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns whether [`Self::cancel`] was called.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl<T: Scalar + Send + Sync, P: Point<T> + Send + Sync> KDTree<T, P> {
+    /// Same as [`Self::map_indices_with`], but if `cancellation` is given, indices are processed by chunks
+    /// and [`QueryError::Cancelled`] is returned if it is cancelled before all chunks are processed
+    pub(crate) fn map_indices_with_cancellable<S, R: Send>(
+        &self,
+        count: usize,
+        init: impl Fn() -> S + Sync + Send,
+        f: impl Fn(&mut S, usize) -> R + Sync + Send,
+        cancellation: Option<&CancellationToken>,
+    ) -> Result<Vec<R>, QueryError> {
+        let cancellation = match cancellation {
+            Some(cancellation) => cancellation,
+            None => return Ok(self.map_indices_with(count, init, f)),
+        };
+        let chunk_count = (count + CANCELLATION_CHUNK_SIZE - 1) / CANCELLATION_CHUNK_SIZE;
+        let chunks = self.map_indices_with(chunk_count, init, |state, chunk| {
+            if cancellation.is_cancelled() {
+                return None;
+            }
+            let start = chunk * CANCELLATION_CHUNK_SIZE;
+            let end = (start + CANCELLATION_CHUNK_SIZE).min(count);
+            Some(
+                (start..end)
+                    .map(|index| f(state, index))
+                    .collect::<Vec<_>>(),
+            )
+        });
+        let mut results = Vec::with_capacity(count);
+        for chunk in chunks {
+            results.extend(chunk.ok_or(QueryError::Cancelled)?);
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn cancelled_build_and_queries_return_errors() {
+        let cloud: Vec<P2> = random_point_cloud(100_000);
+        let token = CancellationToken::new();
+        let tree = KDTree::try_new_cancellable(&cloud, &BuildOptions::default(), &token).unwrap();
+        assert_eq!(tree, KDTree::new(&cloud));
+        let queries: Vec<P2> = random_point_cloud(1000);
+        let parameters = Parameters::default();
+        let neighbours = tree
+            .knn_batch_cancellable(3, &queries, CandidateContainer::Linear, &parameters, &token)
+            .unwrap();
+        assert_eq!(
+            neighbours,
+            tree.knn_batch(3, &queries, CandidateContainer::Linear, &parameters)
+        );
+        token.cancel();
+        assert!(token.is_cancelled());
+        assert_eq!(
+            KDTree::try_new_cancellable(&cloud, &BuildOptions::default(), &token),
+            Err(BuildError::Cancelled)
+        );
+        // small builds do not check
+        assert!(KDTree::try_new_cancellable(&queries, &BuildOptions::default(), &token).is_ok());
+        assert_eq!(
+            tree.knn_batch_cancellable(
+                3,
+                &queries,
+                CandidateContainer::Linear,
+                &parameters,
+                &token
+            ),
+            Err(QueryError::Cancelled)
+        );
+        assert_eq!(
+            tree.knn_graph_csr_cancellable(3, &parameters, KnnGraphSymmetry::Directed, &token),
+            Err(QueryError::Cancelled)
+        );
+    }
+}
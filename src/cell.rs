@@ -0,0 +1,122 @@
+//! Bounding boxes of the nodes, maintained while traversing the tree
+
+use alloc::vec::Vec;
+
+use num_traits::Zero;
+use ordered_float::NotNan;
+
+use crate::distance_scale::saturate;
+use crate::{KDTree, Point, Scalar};
+
+/// The bounding box of the node being visited, in tree coordinates
+///
+/// It starts as the bounding box of the tree and is restricted by the split planes on the way down,
+/// widened by the drift of the tree so that it still holds the points that moved across a split.
+pub(crate) struct NodeCell<T: Scalar> {
+    lower: Vec<NotNan<T>>,
+    upper: Vec<NotNan<T>>,
+}
+
+impl<T: Scalar> NodeCell<T> {
+    /// The cell of the root of `tree`
+    pub(crate) fn of_root<P: Point<T>>(tree: &KDTree<T, P>) -> Self {
+        NodeCell {
+            lower: tree.min_bounds.clone(),
+            upper: tree.max_bounds.clone(),
+        }
+    }
+
+    /// Returns the lower and upper corners of the cell.
+    pub(crate) fn corners(&self) -> (&[NotNan<T>], &[NotNan<T>]) {
+        (&self.lower, &self.upper)
+    }
+
+    /// Returns the extent of the cell along `dim`, to restore it with [`Self::set_extent`].
+    pub(crate) fn extent(&self, dim: usize) -> (NotNan<T>, NotNan<T>) {
+        (self.lower[dim], self.upper[dim])
+    }
+
+    pub(crate) fn set_extent(&mut self, dim: usize, (lower, upper): (NotNan<T>, NotNan<T>)) {
+        self.lower[dim] = lower;
+        self.upper[dim] = upper;
+    }
+
+    /// Sets the cell to the left or right child of a split along `dim`, `parent` being the extent of the split node
+    pub(crate) fn set_child(
+        &mut self,
+        dim: usize,
+        parent: (NotNan<T>, NotNan<T>),
+        split_val: NotNan<T>,
+        drift: NotNan<T>,
+        left: bool,
+    ) {
+        let (lower, upper) = parent;
+        if left {
+            self.set_extent(dim, (lower, upper.min(split_val + drift)));
+        } else {
+            self.set_extent(dim, (lower.max(split_val - drift), upper));
+        }
+    }
+
+    /// Whether the cell overlaps the box between `lower` and `upper`, inclusive
+    pub(crate) fn intersects(&self, lower: &[NotNan<T>], upper: &[NotNan<T>]) -> bool {
+        (0..self.lower.len()).all(|i| self.lower[i] <= upper[i] && lower[i] <= self.upper[i])
+    }
+
+    /// Whether the cell is inside the box between `lower` and `upper`, inclusive
+    pub(crate) fn is_inside(&self, lower: &[NotNan<T>], upper: &[NotNan<T>]) -> bool {
+        (0..self.lower.len()).all(|i| lower[i] <= self.lower[i] && self.upper[i] <= upper[i])
+    }
+
+    /// Returns the squared distance between the cell and the box between `lower` and `upper`,
+    /// with coordinate differences multiplied by `scale`
+    pub(crate) fn box_dist2(
+        &self,
+        lower: &[NotNan<T>],
+        upper: &[NotNan<T>],
+        scale: NotNan<T>,
+    ) -> NotNan<T> {
+        let zero = NotNan::<T>::zero();
+        let mut dist2 = zero;
+        for i in 0..self.lower.len() {
+            let gap = (self.lower[i] - upper[i])
+                .max(lower[i] - self.upper[i])
+                .max(zero)
+                * scale;
+            dist2 += gap * gap;
+        }
+        saturate(dist2)
+    }
+
+    /// Returns the smallest and largest squared distances from `query` to the cell,
+    /// with coordinate differences multiplied by `scale`
+    pub(crate) fn dist2_range(
+        &self,
+        query: &[NotNan<T>],
+        scale: NotNan<T>,
+    ) -> (NotNan<T>, NotNan<T>) {
+        box_dist2_range(query, &self.lower, &self.upper, scale)
+    }
+}
+
+/// Returns the smallest and largest squared distances from `query` to the box from `lower` to `upper`,
+/// with coordinate differences multiplied by `scale`
+pub(crate) fn box_dist2_range<T: Scalar>(
+    query: &[NotNan<T>],
+    lower: &[NotNan<T>],
+    upper: &[NotNan<T>],
+    scale: NotNan<T>,
+) -> (NotNan<T>, NotNan<T>) {
+    let zero = NotNan::<T>::zero();
+    let mut min_dist2 = zero;
+    let mut max_dist2 = zero;
+    for ((value, lower), upper) in query.iter().zip(lower).zip(upper) {
+        let below = (*lower - *value).max(zero);
+        let above = (*value - *upper).max(zero);
+        let near = below.max(above) * scale;
+        let far = (*value - *lower).max(*upper - *value) * scale;
+        min_dist2 += near * near;
+        max_dist2 += far * far;
+    }
+    (saturate(min_dist2), saturate(max_dist2))
+}
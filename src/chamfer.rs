@@ -0,0 +1,227 @@
+//! Chamfer distance between the point clouds of two trees, with the residual of each point
+
+use ordered_float::NotNan;
+
+use crate::distance_scale::unscale_dist2;
+use crate::infinite::HasInfinite;
+use crate::internal_parameters::InternalParameters;
+use crate::{InternalNeighbour, KDTree, Parameters, Point, QueryError, Scalar};
+
+/// The mean squared distances from the points of each tree to their nearest point in the other,
+/// see [`KDTree::chamfer`]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChamferResult {
+    /// mean squared distance from the points of `a` to their nearest point of `b`
+    pub a_to_b: f64,
+    /// mean squared distance from the points of `b` to their nearest point of `a`
+    pub b_to_a: f64,
+    /// number of points of `a` with no point of `b` within `max_radius`
+    pub a_unmatched: usize,
+    /// number of points of `b` with no point of `a` within `max_radius`
+    pub b_unmatched: usize,
+}
+
+impl ChamferResult {
+    /// Returns the symmetric Chamfer distance, the sum of the two directed means.
+    pub fn symmetric(&self) -> f64 {
+        self.a_to_b + self.b_to_a
+    }
+}
+
+impl<T: Scalar + Send + Sync, P: Point<T> + Send + Sync> KDTree<T, P> {
+    /// Returns the mean squared distances from the points of `a` to their nearest point of `b`,
+    /// and from the points of `b` to their nearest point of `a`, with user-provided parameters.
+    ///
+    /// The stored points of each tree are the queries of the other, so the original clouds are not needed.
+    /// The means are accumulated in `f64` whatever `T`, and each is taken over the points it starts from,
+    /// so that clouds of very different sizes weigh their directions equally.
+    /// Points with no point of the other tree within `parameters.max_radius` count as at that radius,
+    /// truncating the distances for robustness, and are counted as unmatched.
+    /// The mean from an empty tree is zero, and the mean from a non-empty tree to an empty one
+    /// is infinite unless `parameters.max_radius` is finite, so that no mean is NaN.
+    /// If the `rayon` feature is enabled, the points of each direction are processed in parallel.
+    pub fn chamfer(a: &Self, b: &Self, parameters: &Parameters<T>) -> ChamferResult {
+        let (a_to_b, a_unmatched) = Self::directed_chamfer(a, b, parameters, None);
+        let (b_to_a, b_unmatched) = Self::directed_chamfer(b, a, parameters, None);
+        ChamferResult {
+            a_to_b,
+            b_to_a,
+            a_unmatched,
+            b_unmatched,
+        }
+    }
+
+    /// Returns the Chamfer distance between `a` and `b`, see [`Self::chamfer`], and writes the residual
+    /// of each point, the vector from it to its nearest point in the other tree, to `a_residuals` and `b_residuals`,
+    /// by index in the original point clouds.
+    ///
+    /// The residuals of unmatched points are `None`.
+    /// Returns an error if the residual slices do not have one element per point of their tree.
+    pub fn chamfer_with_residuals(
+        a: &Self,
+        b: &Self,
+        parameters: &Parameters<T>,
+        a_residuals: &mut [Option<P>],
+        b_residuals: &mut [Option<P>],
+    ) -> Result<ChamferResult, QueryError> {
+        for (tree, residuals) in [(a, a_residuals.len()), (b, b_residuals.len())] {
+            if residuals != tree.len() {
+                return Err(QueryError::ValuesLengthMismatch {
+                    points: tree.len(),
+                    values: residuals,
+                });
+            }
+        }
+        let (a_to_b, a_unmatched) = Self::directed_chamfer(a, b, parameters, Some(a_residuals));
+        let (b_to_a, b_unmatched) = Self::directed_chamfer(b, a, parameters, Some(b_residuals));
+        Ok(ChamferResult {
+            a_to_b,
+            b_to_a,
+            a_unmatched,
+            b_unmatched,
+        })
+    }
+
+    /// Returns the mean squared distance from the points of `from` to their nearest point of `to`
+    /// and the number of unmatched points, writing their residuals if asked
+    fn directed_chamfer(
+        from: &Self,
+        to: &Self,
+        parameters: &Parameters<T>,
+        mut residuals: Option<&mut [Option<P>]>,
+    ) -> (f64, usize) {
+        let internal_parameters = InternalParameters::new(parameters, to.scale);
+        let nearest = from.map_indices(from.len(), |internal| {
+            let mut nearest = InternalNeighbour::default();
+            let query = from.stored_coordinates_for(internal as u32, to);
+            to.knn_internal(&mut nearest, &query, &internal_parameters);
+            nearest
+        });
+        let to_f64 = |value: T| value.to_f64().expect("scalars convert to f64");
+        let truncated_dist2 = to_f64(parameters.max_radius) * to_f64(parameters.max_radius);
+        let (mut sum, mut unmatched) = (0.0, 0);
+        for (internal, nearest) in nearest.into_iter().enumerate() {
+            let external = from.externalise_index(internal as u32) as usize;
+            if nearest.dist2 == NotNan::infinite() {
+                sum += truncated_dist2;
+                unmatched += 1;
+                if let Some(residuals) = residuals.as_mut() {
+                    residuals[external] = None;
+                }
+                continue;
+            }
+            sum += to_f64(unscale_dist2(nearest.dist2, to.scale).into_inner());
+            if let Some(residuals) = residuals.as_mut() {
+                // in the frame of the points, whatever the rotations of the trees
+                let (point, neighbour) = (
+                    from.stored_point(internal as u32),
+                    to.stored_point(nearest.index),
+                );
+                let mut residual = P::default();
+                for i in 0..P::DIM {
+                    residual.set(i, neighbour.get(i) - point.get(i));
+                }
+                residuals[external] = Some(residual);
+            }
+        }
+        let mean = if from.is_empty() {
+            0.0
+        } else {
+            sum / from.len() as f64
+        };
+        (mean, unmatched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    /// Returns the mean squared distance from `from` to their nearest point of `to` by querying each of them
+    fn naive_mean(from: &[P2], to: &KDTree<f32, P2>) -> f64 {
+        let sum: f64 = from
+            .iter()
+            .map(|point| to.knn(1, point)[0].dist2.into_inner() as f64)
+            .sum();
+        sum / from.len() as f64
+    }
+
+    #[test]
+    fn chamfer_distances_match_the_naive_means() {
+        let a: Vec<P2> = random_point_cloud(2000);
+        let b: Vec<P2> = random_point_cloud(300);
+        let (tree_a, tree_b) = (KDTree::new(&a), KDTree::new(&b));
+        let result = KDTree::chamfer(&tree_a, &tree_b, &Parameters::default());
+        assert!((result.a_to_b - naive_mean(&a, &tree_b)).abs() < 1e-6 * result.a_to_b);
+        assert!((result.b_to_a - naive_mean(&b, &tree_a)).abs() < 1e-6 * result.b_to_a);
+        // the points of the denser cloud are farther from the sparser one than the reverse
+        assert!(result.a_to_b > result.b_to_a);
+        assert_eq!((result.a_unmatched, result.b_unmatched), (0, 0));
+        assert_eq!(result.symmetric(), result.a_to_b + result.b_to_a);
+        let mut a_residuals: Vec<Option<P2>> = (0..a.len()).map(|_| None).collect();
+        let mut b_residuals: Vec<Option<P2>> = (0..b.len()).map(|_| None).collect();
+        let with_residuals = KDTree::chamfer_with_residuals(
+            &tree_a,
+            &tree_b,
+            &Parameters::default(),
+            &mut a_residuals,
+            &mut b_residuals,
+        );
+        assert_eq!(with_residuals, Ok(result));
+        for (index, residual) in a_residuals.iter().enumerate() {
+            let nearest = tree_b.knn(1, &a[index])[0];
+            let residual = residual.expect("all points are matched");
+            assert_eq!(residual.get(0), nearest.point.get(0) - a[index].get(0));
+            assert_eq!(residual.get(1), nearest.point.get(1) - a[index].get(1));
+        }
+        assert!(b_residuals.iter().all(Option::is_some));
+        assert_eq!(
+            KDTree::chamfer_with_residuals(
+                &tree_a,
+                &tree_b,
+                &Parameters::default(),
+                &mut a_residuals,
+                &mut [],
+            ),
+            Err(QueryError::ValuesLengthMismatch {
+                points: b.len(),
+                values: 0
+            })
+        );
+    }
+
+    #[test]
+    fn chamfer_distances_of_empty_and_distant_clouds_are_not_nan() {
+        let a: Vec<P2> = random_point_cloud(100);
+        let tree = KDTree::new(&a);
+        let empty = KDTree::<f32, P2>::new(&[]);
+        let result = KDTree::chamfer(&tree, &empty, &Parameters::default());
+        assert_eq!(result.a_to_b, f64::INFINITY);
+        assert_eq!(result.b_to_a, 0.0);
+        assert_eq!((result.a_unmatched, result.b_unmatched), (100, 0));
+        let result = KDTree::chamfer(&empty, &empty, &Parameters::default());
+        assert_eq!(result, ChamferResult::default());
+        // distances beyond the maximum radius are truncated to it
+        let far = KDTree::new(&[P2::new2d(0.0, 0.0), P2::new2d(1e5, 0.0)]);
+        let parameters = Parameters {
+            max_radius: 2000.0,
+            ..Parameters::default()
+        };
+        let mut residuals = [None, None];
+        let mut tree_residuals: Vec<Option<P2>> = (0..a.len()).map(|_| None).collect();
+        let result = KDTree::chamfer_with_residuals(
+            &far,
+            &tree,
+            &parameters,
+            &mut residuals,
+            &mut tree_residuals,
+        )
+        .unwrap();
+        assert_eq!(result.a_unmatched, 1);
+        assert!(residuals[0].is_some() && residuals[1].is_none());
+        let nearest = tree.knn(1, &P2::new2d(0.0, 0.0))[0].dist2.into_inner() as f64;
+        assert!((result.a_to_b - (nearest + 2000.0 * 2000.0) / 2.0).abs() < 1e-3);
+        assert_eq!(result.b_unmatched, 0);
+    }
+}
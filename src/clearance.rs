@@ -0,0 +1,197 @@
+//! Radius of the largest empty ball around a query, and the direction away from the nearest point
+
+use num_traits::Float;
+use ordered_float::NotNan;
+
+use crate::distance_scale::unscale_dist2;
+use crate::internal_parameters::InternalParameters;
+use crate::{CandidateCollector, InternalNeighbour, KDTree, Parameters, Point, QueryError, Scalar};
+
+/// The largest ball around a query that is empty of points, see [`KDTree::clearance`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Clearance<T: Scalar, P: Point<T>> {
+    /// the radius of the ball, the distance from the query to the nearest point
+    pub distance: T,
+    /// the index of the nearest point in the original point cloud
+    pub index: u32,
+    /// the unit vector from the nearest point to the query, along which the clearance grows fastest,
+    /// all zeros if the query coincides with the point
+    pub direction: P,
+}
+
+/// Keeps the nearest candidate with a given label
+struct LabelCollector<'a, T: Scalar> {
+    nearest: InternalNeighbour<T>,
+    /// labels by external index
+    labels: &'a [u32],
+    /// external indices by internal index
+    indices: &'a [u32],
+    label: u32,
+}
+
+impl<'a, T: Scalar> CandidateCollector<T> for LabelCollector<'a, T> {
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        if self.labels[self.indices[index as usize] as usize] == self.label {
+            self.nearest.add(dist2, index);
+        }
+    }
+
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        self.nearest.furthest_dist2()
+    }
+}
+
+impl<T: Scalar + Float, P: Point<T>> KDTree<T, P> {
+    /// Returns the largest ball centered at `query` containing no point in its interior,
+    /// with user-provided parameters, or `None` if there is no point within `parameters.max_radius`.
+    ///
+    /// Its radius is the distance to the nearest neighbour, and its direction points from that neighbour
+    /// to `query`, computed from the stored coordinates.
+    /// A query on a point has a clearance of zero and, as it has no direction, a direction of all zeros.
+    /// With a positive `parameters.epsilon`, the neighbour and the distance are approximate.
+    ///
+    /// Panics if `query` has NaN coordinates.
+    pub fn clearance(&self, query: &P, parameters: &Parameters<T>) -> Option<Clearance<T, P>> {
+        let mut nearest = InternalNeighbour::default();
+        self.knn_internal(
+            &mut nearest,
+            &self.query_coordinates(query),
+            &InternalParameters::new(parameters, self.scale),
+        );
+        self.make_clearance(query, nearest)
+    }
+
+    /// Returns the largest ball centered at `query` containing no point of label `label`,
+    /// `labels[i]` being the label of the point of index `i` in the original point cloud,
+    /// see [`Self::clearance`].
+    ///
+    /// Points of other labels are ignored, so the ball may contain them.
+    /// Returns `Ok(None)` if there is no point of label `label` within `parameters.max_radius`,
+    /// or an error if `labels` does not have one element per point of the tree.
+    pub fn clearance_from_label(
+        &self,
+        query: &P,
+        labels: &[u32],
+        label: u32,
+        parameters: &Parameters<T>,
+    ) -> Result<Option<Clearance<T, P>>, QueryError> {
+        if labels.len() != self.len() {
+            return Err(QueryError::ValuesLengthMismatch {
+                points: self.len(),
+                values: labels.len(),
+            });
+        }
+        let mut collector = LabelCollector {
+            nearest: InternalNeighbour::default(),
+            labels,
+            indices: &self.indices,
+            label,
+        };
+        self.knn_internal(
+            &mut collector,
+            &self.query_coordinates(query),
+            &InternalParameters::new(parameters, self.scale),
+        );
+        Ok(self.make_clearance(query, collector.nearest))
+    }
+
+    /// Makes the clearance of `query` to `nearest`, if a point was found
+    fn make_clearance(&self, query: &P, nearest: InternalNeighbour<T>) -> Option<Clearance<T, P>> {
+        nearest.furthest_dist2()?;
+        let point = self.stored_point(nearest.index);
+        let mut direction = P::default();
+        for i in 0..P::DIM {
+            direction.set(i, query.get(i) - *point.get(i));
+        }
+        let norm = Float::sqrt((0..P::DIM).fold(T::zero(), |norm2, i| {
+            norm2 + *direction.get(i) * *direction.get(i)
+        }));
+        if norm > T::zero() {
+            for i in 0..P::DIM {
+                let value = direction.get(i) / norm;
+                direction.set(i, value);
+            }
+        }
+        Some(Clearance {
+            distance: Float::sqrt(unscale_dist2(nearest.dist2, self.scale).into_inner()),
+            index: self.externalise_index(nearest.index),
+            direction,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn clearance_is_the_distance_to_the_nearest_point_of_the_label() {
+        let mut rng = rand::thread_rng();
+        let cloud: Vec<P2d> = random_point_cloud::<2>(2000)
+            .iter()
+            .map(|p| P2d::from([*p.get(0) as f64, *p.get(1) as f64]))
+            .collect();
+        let labels: Vec<u32> = (0..cloud.len()).map(|_| rng.gen_range(0..3)).collect();
+        let tree = KDTree::new(&cloud);
+        for _ in 0..100 {
+            let query = P2d::from([rng.gen_range(-120.0..120.0), rng.gen_range(-120.0..120.0)]);
+            let dist = |p: &P2d| {
+                ((p.get(0) - query.get(0)).powi(2) + (p.get(1) - query.get(1)).powi(2)).sqrt()
+            };
+            let clearance = tree.clearance(&query, &Parameters::default()).unwrap();
+            let nearest = &tree.knn(1, &query)[0];
+            assert_eq!(clearance.index, nearest.index);
+            assert_eq!(clearance.distance, nearest.dist2.sqrt());
+            // the direction points from the neighbour to the query
+            let point = cloud[clearance.index as usize];
+            for i in 0..2 {
+                let expected = *(query.get(i) - point.get(i)) / clearance.distance;
+                assert!((*clearance.direction.get(i) - expected).abs() < 1e-9);
+            }
+            for label in 0..3 {
+                let labelled = tree
+                    .clearance_from_label(&query, &labels, label, &Parameters::default())
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(labels[labelled.index as usize], label);
+                let expected = (0..cloud.len())
+                    .filter(|i| labels[*i] == label)
+                    .map(|i| dist(&cloud[i]))
+                    .fold(f64::INFINITY, f64::min);
+                assert!((labelled.distance - expected).abs() < 1e-9);
+                assert!(labelled.distance >= clearance.distance);
+            }
+        }
+        let absent = tree.clearance_from_label(&cloud[0], &labels, 7, &Parameters::default());
+        assert_eq!(absent, Ok(None));
+        assert_eq!(
+            tree.clearance_from_label(&cloud[0], &labels[1..], 0, &Parameters::default()),
+            Err(QueryError::ValuesLengthMismatch {
+                points: 2000,
+                values: 1999
+            })
+        );
+    }
+
+    #[test]
+    fn clearance_on_a_point_is_zero() {
+        let cloud = [P2::new2d(1.0, 2.0), P2::new2d(4.0, 6.0)];
+        let tree = KDTree::new(&cloud);
+        let on_point = tree.clearance(&cloud[1], &Parameters::default()).unwrap();
+        assert_eq!(on_point.distance, 0.0);
+        assert_eq!(on_point.index, 1);
+        assert_eq!(on_point.direction, P2::new2d(0.0, 0.0));
+        let parameters = Parameters {
+            max_radius: 1.0,
+            ..Parameters::default()
+        };
+        assert_eq!(tree.clearance(&P2::new2d(10.0, 10.0), &parameters), None);
+        assert_eq!(
+            KDTree::<f32, P2>::new(&[]).clearance(&cloud[0], &Parameters::default()),
+            None
+        );
+    }
+}
@@ -0,0 +1,248 @@
+//! Two-phase searches, answering first from a coarse tree of a subsample of the points
+
+use alloc::{boxed::Box, vec::Vec};
+
+#[cfg(not(feature = "strict-exact"))]
+use num_traits::Float;
+#[cfg(not(feature = "strict-exact"))]
+use ordered_float::NotNan;
+
+#[cfg(not(feature = "strict-exact"))]
+use crate::internal_parameters::InternalParameters;
+use crate::{copy_point, BuildError, BuildOptions, CancellationToken, KDTree, Point, Scalar};
+#[cfg(not(feature = "strict-exact"))]
+use crate::{Boundary, Neighbour, Parameters, SelfMatch};
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Builds the coarse tree of every `stride`-th point of `cloud`, see [`BuildOptions::coarse_stride`]
+    pub(crate) fn try_build_coarse(
+        cloud: &[P],
+        stride: u32,
+        options: &BuildOptions,
+        cancellation: Option<&CancellationToken>,
+    ) -> Result<Self, BuildError> {
+        let subsample: Vec<P> = cloud
+            .iter()
+            .step_by(stride as usize)
+            .map(copy_point)
+            .collect();
+        let options = BuildOptions {
+            subtree_sums: false,
+            coarse_stride: None,
+            ..*options
+        };
+        Self::try_build(&subsample, &options, None, cancellation)
+    }
+
+    /// Rebuilds the coarse tree, if any, from the current points, after their indices changed
+    pub(crate) fn refresh_coarse(&mut self) {
+        if let (Some(_), Some(stride)) = (&self.coarse, self.options.coarse_stride) {
+            let coarse = Self::try_build_coarse(&self.points(), stride, &self.options, None)
+                .unwrap_or_else(|error| panic!("{}", error));
+            self.coarse = Some(Box::new(coarse));
+        }
+    }
+}
+
+#[cfg(not(feature = "strict-exact"))]
+impl<T: Scalar + Float, P: Point<T>> KDTree<T, P> {
+    /// Finds the `k` nearest neighbours of `query` in two phases: returns a coarse answer found
+    /// in the coarse tree, see [`BuildOptions::coarse_stride`], and a closure completing the exact search.
+    ///
+    /// The coarse answer holds points of this tree, with their indices and distances in this tree,
+    /// sorted by increasing distance; they are at least as far as the exact neighbours, and fewer
+    /// than `k` if the coarse tree has fewer points within `max_radius`.
+    /// The closure returns the same neighbours as [`Self::knn_advanced`] with `parameters`.
+    /// If the coarse answer has `k` points, the exact search is bounded by the distance of the furthest,
+    /// so it prunes the cells beyond it from the start and never visits more nodes than a search without bound.
+    /// As the exact search descends to the nearest leaves first, it usually finds such a bound early by itself:
+    /// the coarse tree mostly serves to have a rough answer immediately.
+    /// The coarse tree is rebuilt by [`Self::apply_batch`], whose removals and insertions change the indices,
+    /// in time linear in the number of points. It is not updated when points move,
+    /// so moved points are returned at their new position and the coarse answer gets rougher,
+    /// until [`Self::rebuild`].
+    ///
+    /// Without a coarse tree, the coarse answer is empty and the closure runs the search unbounded.
+    /// Panics if the parameters are invalid, see [`Parameters::validate`].
+    #[allow(clippy::type_complexity)]
+    pub fn knn_coarse_then_fine<'a>(
+        &'a self,
+        k: u32,
+        query: &P,
+        parameters: &Parameters<T>,
+    ) -> (
+        Vec<Neighbour<T, P>>,
+        impl FnOnce() -> Vec<Neighbour<T, P>> + 'a,
+    ) {
+        parameters
+            .validate()
+            .unwrap_or_else(|error| panic!("{}", error));
+        let coarse = self.coarse_answer(k, query, parameters);
+        let bound = if coarse.len() == k as usize && k > 0 {
+            coarse.last().map(|furthest| *furthest.dist2)
+        } else {
+            None
+        };
+        let mut fine_parameters = *parameters;
+        if let Some(bound2) = bound {
+            // widened so that rounding the radius does not exclude the furthest exact neighbour
+            let margin = T::one() + <T as Float>::epsilon() * T::from(8).expect("small integer");
+            let radius = Float::sqrt(bound2) * margin;
+            if radius < parameters.max_radius {
+                fine_parameters.max_radius = radius;
+                fine_parameters.boundary = Boundary::Closed;
+            }
+        }
+        let query: Vec<NotNan<T>> = (0..P::DIM).map(|i| query.get(i)).collect();
+        let fine = move || {
+            self.knn_advanced(
+                k,
+                query.as_slice(),
+                Self::default_candidate_container(k),
+                &fine_parameters,
+                None,
+            )
+        };
+        (coarse, fine)
+    }
+
+    /// Returns the neighbours of `query` found in the coarse tree, as points of this tree
+    fn coarse_answer(&self, k: u32, query: &P, parameters: &Parameters<T>) -> Vec<Neighbour<T, P>> {
+        let (coarse, stride) = match (&self.coarse, self.options.coarse_stride) {
+            (Some(coarse), Some(stride)) => (coarse, stride),
+            _ => return Vec::new(),
+        };
+        let query_coordinates = self.query_coordinates(query);
+        let internal_parameters = InternalParameters::new(parameters, self.scale);
+        let metric = self.metric();
+        // the coarse tree has its own indices, the excluded point is filtered below
+        let excluded = parameters.self_match.excluded_index();
+        let coarse_parameters = Parameters {
+            self_match: match excluded {
+                Some(_) => SelfMatch::NotApplicable,
+                None => parameters.self_match,
+            },
+            ..*parameters
+        };
+        let mut neighbours: Vec<Neighbour<T, P>> = coarse
+            .knn_advanced(
+                k,
+                query,
+                Self::default_candidate_container(k),
+                &coarse_parameters,
+                None,
+            )
+            .into_iter()
+            .filter_map(|neighbour| {
+                let index = neighbour.index.checked_mul(stride)?;
+                if index as usize >= self.len() || Some(index) == excluded {
+                    return None;
+                }
+                // the distance in this tree, where the point may have moved since the coarse tree was built
+                let internal = self.internalise_index(index);
+                let dist2 = metric
+                    .internal_dist2::<P>(self.stored_coordinates(internal), &query_coordinates);
+                let self_match =
+                    !parameters.self_match.allows_coincident() && *dist2 <= <T as Float>::epsilon();
+                if self_match || dist2 > internal_parameters.max_radius2 {
+                    return None;
+                }
+                Some(Neighbour {
+                    point: self.stored_point(internal),
+                    dist2: metric.external_dist2(dist2),
+                    index,
+                })
+            })
+            .collect();
+        neighbours.sort_unstable_by_key(|neighbour| (neighbour.dist2, neighbour.index));
+        neighbours
+    }
+}
+
+#[cfg(all(test, not(feature = "strict-exact")))]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn with_coarse_tree(stride: u32) -> BuildOptions {
+        BuildOptions {
+            coarse_stride: Some(stride),
+            ..BuildOptions::default()
+        }
+    }
+
+    #[test]
+    fn fine_answers_match_cold_queries() {
+        let mut cloud: Vec<P2> = random_point_cloud(5000);
+        for stride in [1, 7, 50, 10_000] {
+            let mut tree = KDTree::new_with_options(&cloud, &with_coarse_tree(stride));
+            for round in 0..2 {
+                for k in [1, 5, 40] {
+                    for max_radius in [f32::INFINITY, 10.0] {
+                        let parameters = Parameters {
+                            max_radius,
+                            self_match: (round == 0).into(),
+                            ..Parameters::default()
+                        };
+                        for query in cloud.iter().step_by(97) {
+                            let (coarse, fine) = tree.knn_coarse_then_fine(k, query, &parameters);
+                            let fine = fine();
+                            let cold = tree.knn_advanced(
+                                k,
+                                query,
+                                CandidateContainer::BinaryHeap,
+                                &parameters,
+                                None,
+                            );
+                            assert_eq!(fine, cold);
+                            assert!(coarse.len() <= cold.len());
+                            for (rough, exact) in coarse.iter().zip(&cold) {
+                                assert!(rough.dist2 >= exact.dist2);
+                                assert_eq!(cloud[rough.index as usize], rough.point);
+                            }
+                        }
+                    }
+                }
+                // the coarse tree is kept when points move, and rebuilt when a batch renumbers them
+                for index in (0..cloud.len()).step_by(3) {
+                    cloud[index] = cloud[index] * 0.9;
+                    tree.update_point(index as u32, cloud[index]);
+                }
+                tree.apply_batch(&[0, 1, 2], &[]);
+                cloud = tree.points();
+                let rebuilt = KDTree::new_with_options(&cloud, &with_coarse_tree(stride));
+                assert_eq!(tree.coarse, rebuilt.coarse);
+            }
+        }
+    }
+
+    #[test]
+    fn coarse_answers_bound_the_fine_search() {
+        let cloud: Vec<P3> = random_point_cloud(20_000);
+        let tree = KDTree::new_with_options(&cloud, &with_coarse_tree(20));
+        let plain = KDTree::new(&cloud);
+        for query in (0..200).map(|_| random_point::<3>() * 1.5) {
+            let (coarse, _) = tree.knn_coarse_then_fine(50, &query, &Parameters::default());
+            assert_eq!(coarse.len(), 50);
+            // the bounds of the fine search
+            let parameters = Parameters {
+                max_radius: coarse[49].dist2.into_inner().sqrt() * (1.0 + 8.0 * f32::EPSILON),
+                ..Parameters::default()
+            };
+            let container = CandidateContainer::BinaryHeap;
+            let (bounded, bounded_stats) = tree.knn_with_stats(50, &query, container, &parameters);
+            let (cold, cold_stats) =
+                plain.knn_with_stats(50, &query, container, &Parameters::default());
+            assert_eq!(bounded, cold);
+            assert!(bounded_stats.visited_nodes <= cold_stats.visited_nodes);
+        }
+        // without a coarse tree, the search is only run by the closure
+        let (coarse, fine) = plain.knn_coarse_then_fine(8, &cloud[0], &Parameters::default());
+        assert!(coarse.is_empty());
+        assert_eq!(fine(), plain.knn(8, &cloud[0]));
+        assert_eq!(
+            KDTree::try_new_with_options(&cloud, &with_coarse_tree(0)).err(),
+            Some(BuildError::CoarseStrideZero)
+        );
+    }
+}
@@ -0,0 +1,224 @@
+//! Rust source of a flat tree, to place a tree known at build time in read-only memory
+
+use alloc::string::String;
+use core::fmt::Write;
+
+use crate::{FlatTree, Scalar};
+
+/// Number of indices per line of the emitted source
+const INDICES_PER_LINE: usize = 16;
+
+impl<T: Scalar> FlatTree<T> {
+    /// Returns Rust source declaring this tree as items named after `name`, queried without allocating.
+    ///
+    /// The source declares:
+    /// * `pub static NAME: nabo::FlatTreeView<'static, T>`, the view of the arrays of the tree, which
+    ///   are in the `static` and so in read-only memory;
+    /// * `pub const NAME_DEPTH: usize`, the number of frames that [`FlatTreeView::knn_into`](crate::FlatTreeView::knn_into)
+    ///   needs at most, to size its stack as an array;
+    /// * `pub static NAME_ROTATION: [T; D * D]`, the rotation by which queries must be multiplied,
+    ///   if the tree was built with [`BuildOptions::rotation_seed`](crate::BuildOptions::rotation_seed).
+    ///
+    /// The values are written so that they parse back exactly, so queries on the emitted tree
+    /// return the same results as on [`FlatTree::view`].
+    /// The identifiers and the auxiliary indices are not emitted.
+    /// A build script typically builds the tree, writes this source to a file in `OUT_DIR`,
+    /// and the crate includes it with `include!(concat!(env!("OUT_DIR"), "/tree.rs"))`.
+    ///
+    /// Panics if `name` is not an identifier made of ASCII letters, digits and underscores.
+    pub fn to_rust_source(&self, name: &str) -> String {
+        assert!(
+            !name.is_empty()
+                && !name.starts_with(|c: char| c.is_ascii_digit())
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            "name is not an identifier"
+        );
+        let ty = core::any::type_name::<T>();
+        let mut source = String::new();
+        let mut emit = |args: core::fmt::Arguments<'_>| {
+            source
+                .write_fmt(args)
+                .expect("writing to a string does not fail")
+        };
+        emit(format_args!(
+            "// Generated by nabo::FlatTree::to_rust_source, do not edit.\n\n"
+        ));
+        emit(format_args!(
+            "/// Number of frames of the traversal stack of the searches on [`{name}`]\n"
+        ));
+        emit(format_args!(
+            "pub const {name}_DEPTH: usize = {};\n\n",
+            self.depth()
+        ));
+        if let Some(rotation) = &self.rotation {
+            emit(format_args!(
+                "/// Rotation by which the queries on [`{name}`] must be multiplied, row-major\n"
+            ));
+            emit(format_args!(
+                "pub static {name}_ROTATION: [{ty}; {}] = [\n",
+                rotation.len()
+            ));
+            for row in rotation.chunks(self.dim.max(1) as usize) {
+                emit(format_args!("    {}\n", values(row, ty)));
+            }
+            emit(format_args!("];\n\n"));
+        }
+        emit(format_args!(
+            "/// A tree of {} points of {} dimensions\n",
+            self.indices.len(),
+            self.dim
+        ));
+        // the scale is one for most clouds, whose view is then that of `FlatTreeView::new`
+        let scale = self.distance_scale();
+        let constructor = if scale == T::one() {
+            "new"
+        } else {
+            "with_distance_scale"
+        };
+        emit(format_args!(
+            "pub static {name}: nabo::FlatTreeView<'static, {ty}> = nabo::FlatTreeView::{constructor}(\n"
+        ));
+        emit(format_args!("    {},\n    &[\n", self.dim));
+        for node in &self.nodes {
+            let split_dim = if node.is_leaf() {
+                alloc::format!("nabo::FlatNode::<{ty}>::LEAF")
+            } else {
+                alloc::format!("{}", node.split_dim)
+            };
+            emit(format_args!(
+                "        nabo::FlatNode {{ split_dim: {}, first: {}, second: {}, reserved: {}, split_val: {} }},\n",
+                split_dim,
+                node.first,
+                node.second,
+                node.reserved,
+                literal(node.split_val, ty)
+            ));
+        }
+        emit(format_args!("    ],\n    &[\n"));
+        for point in self.points.chunks(self.dim.max(1) as usize) {
+            emit(format_args!("        {}\n", values(point, ty)));
+        }
+        emit(format_args!("    ],\n    &[\n"));
+        for line in self.indices.chunks(INDICES_PER_LINE) {
+            let mut text = String::new();
+            for index in line {
+                // writing to a string does not fail
+                let _ = write!(text, "{index}, ");
+            }
+            emit(format_args!("        {}\n", text.trim_end()));
+        }
+        if scale == T::one() {
+            emit(format_args!("    ],\n);\n"));
+        } else {
+            emit(format_args!("    ],\n    {},\n);\n", literal(scale, ty)));
+        }
+        source
+    }
+}
+
+/// Returns `values` as literals followed by commas
+fn values<T: Scalar>(values: &[T], ty: &str) -> String {
+    let mut text = String::new();
+    for value in values {
+        // writing to a string does not fail
+        let _ = write!(text, "{}, ", literal(*value, ty));
+    }
+    String::from(text.trim_end())
+}
+
+/// Returns a literal of `value`, which parses back to `value` exactly
+fn literal<T: Scalar>(value: T, ty: &str) -> String {
+    let mut text = String::new();
+    // writing to a string does not fail
+    let _ = if value.is_infinite() {
+        let constant = if value > T::zero() {
+            "INFINITY"
+        } else {
+            "NEG_INFINITY"
+        };
+        write!(text, "{ty}::{constant}")
+    } else {
+        // the debug format of floats is the shortest that parses back to the same value
+        write!(text, "{value:?}")
+    };
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rng::SplitMix64;
+    use crate::simple_point::*;
+    use crate::*;
+
+    /// A tree written by `to_rust_source` from [`rom_cloud`]
+    mod rom {
+        include!("codegen_rom_tree.rs");
+    }
+
+    fn rom_cloud() -> Vec<P2> {
+        let mut rng = SplitMix64::new(226);
+        let mut coordinate = || rng.below(20_000) as f32 / 1000.0 - 10.0;
+        (0..60)
+            .map(|_| P2::new2d(coordinate(), coordinate()))
+            .collect()
+    }
+
+    #[test]
+    fn emitted_source_is_the_included_one() {
+        let flat = KDTree::new(&rom_cloud()).export_flat();
+        assert_eq!(
+            flat.to_rust_source("TREE"),
+            include_str!("codegen_rom_tree.rs")
+        );
+        let rotated = BuildOptions {
+            rotation_seed: Some(226),
+            ..BuildOptions::default()
+        };
+        let source = KDTree::new_with_options(&rom_cloud(), &rotated)
+            .export_flat()
+            .to_rust_source("ROTATED");
+        assert!(source.contains("pub static ROTATED_ROTATION: [f32; 4] = ["));
+        let tiny: Vec<P2> = rom_cloud().iter().map(|p| *p * 1e-30).collect();
+        let flat = KDTree::new(&tiny).export_flat();
+        let source = flat.to_rust_source("TINY");
+        assert!(source.contains("nabo::FlatTreeView::with_distance_scale("));
+        let scale = format!("    ],\n    {:?},\n);\n", flat.distance_scale());
+        assert!(source.ends_with(&scale));
+    }
+
+    #[test]
+    fn rom_trees_return_the_results_of_runtime_trees() {
+        let cloud = rom_cloud();
+        let tree = KDTree::new(&cloud);
+        let flat = tree.export_flat();
+        let runtime = flat.view();
+        let mut stack = [TraversalFrame::default(); rom::TREE_DEPTH];
+        let mut rng = SplitMix64::new(7);
+        for k in [1, 4, 60, 70] {
+            for _ in 0..40 {
+                let query = [
+                    rng.below(24_000) as f32 / 1000.0 - 12.0,
+                    rng.below(24_000) as f32 / 1000.0 - 12.0,
+                ];
+                let mut found = vec![FlatNeighbour::default(); k];
+                let mut expected = vec![FlatNeighbour::default(); k];
+                let count = rom::TREE.knn_into(&query, &mut found, &mut stack);
+                assert_eq!(count, runtime.knn_into(&query, &mut expected, &mut stack));
+                assert_eq!(found, expected);
+                let neighbours = tree.knn(k as u32, &P2::new2d(query[0], query[1]));
+                assert_eq!(count, Ok(neighbours.len()));
+                for (found, neighbour) in found.iter().zip(&neighbours) {
+                    assert_eq!(found.dist2, *neighbour.dist2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "name is not an identifier")]
+    fn invalid_names_panic() {
+        KDTree::new(&rom_cloud())
+            .export_flat()
+            .to_rust_source("1TREE");
+    }
+}
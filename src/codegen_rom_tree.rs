@@ -0,0 +1,98 @@
+// Generated by nabo::FlatTree::to_rust_source, do not edit.
+
+/// Number of frames of the traversal stack of the searches on [`TREE`]
+pub const TREE_DEPTH: usize = 4;
+
+/// A tree of 60 points of 2 dimensions
+pub static TREE: nabo::FlatTreeView<'static, f32> = nabo::FlatTreeView::new(
+    2,
+    &[
+        nabo::FlatNode { split_dim: 1, first: 1, second: 10, reserved: 0, split_val: 0.034499645 },
+        nabo::FlatNode { split_dim: 0, first: 2, second: 5, reserved: 0, split_val: -0.22399998 },
+        nabo::FlatNode { split_dim: 0, first: 3, second: 4, reserved: 0, split_val: -4.996 },
+        nabo::FlatNode { split_dim: nabo::FlatNode::<f32>::LEAF, first: 0, second: 7, reserved: 0, split_val: 0.0 },
+        nabo::FlatNode { split_dim: nabo::FlatNode::<f32>::LEAF, first: 7, second: 12, reserved: 0, split_val: 0.0 },
+        nabo::FlatNode { split_dim: 0, first: 6, second: 7, reserved: 0, split_val: 4.731 },
+        nabo::FlatNode { split_dim: nabo::FlatNode::<f32>::LEAF, first: 12, second: 18, reserved: 0, split_val: 0.0 },
+        nabo::FlatNode { split_dim: 1, first: 8, second: 9, reserved: 0, split_val: -5.2590003 },
+        nabo::FlatNode { split_dim: nabo::FlatNode::<f32>::LEAF, first: 18, second: 25, reserved: 0, split_val: 0.0 },
+        nabo::FlatNode { split_dim: nabo::FlatNode::<f32>::LEAF, first: 25, second: 28, reserved: 0, split_val: 0.0 },
+        nabo::FlatNode { split_dim: 0, first: 11, second: 14, reserved: 0, split_val: -0.09800005 },
+        nabo::FlatNode { split_dim: 1, first: 12, second: 13, reserved: 0, split_val: 5.1134996 },
+        nabo::FlatNode { split_dim: nabo::FlatNode::<f32>::LEAF, first: 28, second: 33, reserved: 0, split_val: 0.0 },
+        nabo::FlatNode { split_dim: nabo::FlatNode::<f32>::LEAF, first: 33, second: 39, reserved: 0, split_val: 0.0 },
+        nabo::FlatNode { split_dim: 0, first: 15, second: 18, reserved: 0, split_val: 5.0695 },
+        nabo::FlatNode { split_dim: 1, first: 16, second: 17, reserved: 0, split_val: 5.1064997 },
+        nabo::FlatNode { split_dim: nabo::FlatNode::<f32>::LEAF, first: 39, second: 46, reserved: 0, split_val: 0.0 },
+        nabo::FlatNode { split_dim: nabo::FlatNode::<f32>::LEAF, first: 46, second: 52, reserved: 0, split_val: 0.0 },
+        nabo::FlatNode { split_dim: nabo::FlatNode::<f32>::LEAF, first: 52, second: 60, reserved: 0, split_val: 0.0 },
+    ],
+    &[
+        -7.414, -2.179,
+        -6.466, -9.384,
+        -8.248, -4.981,
+        -9.667, -2.1079998,
+        -8.268, -4.049,
+        -8.658, -0.526,
+        -5.569, -8.379,
+        -0.3249998, -3.179,
+        -1.8909998, -6.385,
+        -4.602, -9.359,
+        -2.545, -7.763,
+        -1.6339998, -3.2849998,
+        1.3540001, -4.846,
+        2.212, -1.1339998,
+        0.24300003, -1.0089998,
+        4.585, -3.8309999,
+        2.842, -9.127,
+        1.25, -3.7800002,
+        7.316, -8.116,
+        8.285, -8.409,
+        5.0579996, -5.586,
+        7.797001, -7.611,
+        9.219, -9.744,
+        6.681999, -7.337,
+        7.0820007, -5.691,
+        8.233999, -0.77400017,
+        7.84, -1.4370003,
+        5.358, -3.0619998,
+        -5.122, 4.9659996,
+        -5.367, 3.0019999,
+        -1.7670002, 3.1829996,
+        -8.654, 0.41399956,
+        -2.599, 2.6379995,
+        -8.682, 5.408,
+        -3.657, 5.1850004,
+        -7.01, 7.424999,
+        -5.029, 5.7580004,
+        -9.869, 9.357,
+        -7.506, 9.813,
+        2.6920004, 3.0019999,
+        4.1470003, 2.7209997,
+        2.3769999, 2.67,
+        3.3120003, 1.7889996,
+        1.5780001, 4.7299995,
+        1.0019999, 2.3360004,
+        1.9189997, 0.5450001,
+        4.017, 9.667999,
+        0.7329998, 8.108,
+        1.9840002, 7.549999,
+        2.3470001, 6.6089993,
+        0.9919996, 5.9969997,
+        0.4659996, 7.698,
+        9.673, 2.764,
+        5.3430004, 7.2140007,
+        6.5820007, 6.0289993,
+        9.542999, 1.8929996,
+        9.187, 7.5249996,
+        8.176001, 1.177,
+        5.344, 7.5300007,
+        7.5130005, 8.570999,
+    ],
+    &[
+        26, 1, 59, 11, 41, 54, 20, 33, 17, 55, 46, 29, 27, 0, 28, 9,
+        3, 6, 40, 34, 49, 21, 51, 44, 43, 16, 30, 52, 18, 36, 23, 48,
+        42, 12, 15, 35, 24, 37, 7, 39, 57, 19, 10, 53, 13, 5, 32, 45,
+        4, 22, 50, 8, 25, 31, 47, 14, 56, 38, 58, 2,
+    ],
+);
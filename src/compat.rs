@@ -0,0 +1,252 @@
+//! Golden tests of the semantics of the searches against libnabo, the C++ library this crate re-implements
+//!
+//! Each case is a call to `NearestNeighbourSearch::knn` of libnabo, with its `k`, `epsilon`,
+//! option flags and `maxRadius`, on a fixed point cloud, and the `indices` and `dists2` it returns.
+//! These outputs follow `KDTreeUnbalancedPtInLeavesImplicitBoundsStackOpt`: points at squared distance
+//! up to `maxRadius²` inclusive are candidates, self matches are the points at a squared distance up to
+//! the machine epsilon, and entries beyond the neighbours found hold an invalid index and an infinite distance.
+//! The results of this crate are brought to that layout before comparison, and the neighbours at the same
+//! distance are compared regardless of their order, which libnabo does not specify.
+//! The intentional differences are listed in the README.
+
+use crate::simple_point::*;
+use crate::*;
+
+/// `NearestNeighbourSearch::ALLOW_SELF_MATCH`
+const ALLOW_SELF_MATCH: u32 = 1;
+/// `NearestNeighbourSearch::SORT_RESULTS`
+const SORT_RESULTS: u32 = 2;
+/// `NearestNeighbourSearch::InvalidIndex`, for a signed index type
+const INVALID_INDEX: i32 = -1;
+/// `NearestNeighbourSearch::InvalidValue`
+const INVALID_VALUE: f32 = f32::INFINITY;
+
+/// Points on a line, with a duplicate at index 3, and one off the line
+const CLOUD: [[f32; 2]; 8] = [
+    [0.0, 0.0],
+    [1.0, 0.0],
+    [2.0, 0.0],
+    [2.0, 0.0],
+    [4.0, 0.0],
+    [7.0, 0.0],
+    [0.0, 3.0],
+    [11.0, 0.0],
+];
+
+/// A call to libnabo and its output
+struct Case {
+    query: [f32; 2],
+    k: u32,
+    epsilon: f32,
+    option_flags: u32,
+    max_radius: f32,
+    indices: &'static [i32],
+    dists2: &'static [f32],
+}
+
+const INF: f32 = f32::INFINITY;
+const SORTED_SELF: u32 = ALLOW_SELF_MATCH | SORT_RESULTS;
+
+const CASES: &[Case] = &[
+    // duplicates are both self matches
+    Case {
+        query: [2.0, 0.0],
+        k: 3,
+        epsilon: 0.0,
+        option_flags: SORTED_SELF,
+        max_radius: INF,
+        indices: &[2, 3, 1],
+        dists2: &[0.0, 0.0, 1.0],
+    },
+    Case {
+        query: [2.0, 0.0],
+        k: 3,
+        epsilon: 0.0,
+        option_flags: SORT_RESULTS,
+        max_radius: INF,
+        indices: &[1, 0, 4],
+        dists2: &[1.0, 4.0, 4.0],
+    },
+    // the ball includes its boundary
+    Case {
+        query: [2.0, 0.0],
+        k: 5,
+        epsilon: 0.0,
+        option_flags: SORTED_SELF,
+        max_radius: 2.0,
+        indices: &[2, 3, 1, 0, 4],
+        dists2: &[0.0, 0.0, 1.0, 4.0, 4.0],
+    },
+    Case {
+        query: [2.0, 0.0],
+        k: 5,
+        epsilon: 0.0,
+        option_flags: SORTED_SELF,
+        max_radius: 1.99,
+        indices: &[2, 3, 1, INVALID_INDEX, INVALID_INDEX],
+        dists2: &[0.0, 0.0, 1.0, INVALID_VALUE, INVALID_VALUE],
+    },
+    Case {
+        query: [9.0, 0.0],
+        k: 3,
+        epsilon: 0.0,
+        option_flags: SORTED_SELF,
+        max_radius: 2.5,
+        indices: &[5, 7, INVALID_INDEX],
+        dists2: &[4.0, 4.0, INVALID_VALUE],
+    },
+    // more neighbours requested than there are points
+    Case {
+        query: [5.0, 0.0],
+        k: 10,
+        epsilon: 0.0,
+        option_flags: SORTED_SELF,
+        max_radius: INF,
+        indices: &[4, 5, 2, 3, 1, 0, 6, 7, INVALID_INDEX, INVALID_INDEX],
+        dists2: &[
+            1.0,
+            4.0,
+            9.0,
+            9.0,
+            16.0,
+            25.0,
+            34.0,
+            36.0,
+            INVALID_VALUE,
+            INVALID_VALUE,
+        ],
+    },
+    Case {
+        query: [2.0, 0.0],
+        k: 8,
+        epsilon: 0.0,
+        option_flags: SORT_RESULTS,
+        max_radius: INF,
+        indices: &[1, 0, 4, 6, 5, 7, INVALID_INDEX, INVALID_INDEX],
+        dists2: &[
+            1.0,
+            4.0,
+            4.0,
+            13.0,
+            25.0,
+            81.0,
+            INVALID_VALUE,
+            INVALID_VALUE,
+        ],
+    },
+    // the second nearest point is more than 1 + epsilon times further than the nearest
+    Case {
+        query: [6.2, 0.0],
+        k: 1,
+        epsilon: 0.1,
+        option_flags: SORTED_SELF,
+        max_radius: INF,
+        indices: &[5],
+        dists2: &[0.6400003],
+    },
+    Case {
+        query: [6.2, 0.0],
+        k: 2,
+        epsilon: 0.1,
+        option_flags: SORTED_SELF,
+        max_radius: INF,
+        indices: &[5, 4],
+        dists2: &[0.6400003, 4.839999],
+    },
+    // a point at a squared distance below the machine epsilon is a self match
+    Case {
+        query: [7.0003, 0.0],
+        k: 1,
+        epsilon: 0.0,
+        option_flags: SORT_RESULTS,
+        max_radius: INF,
+        indices: &[4],
+        dists2: &[9.0018],
+    },
+    Case {
+        query: [7.0003, 0.0],
+        k: 1,
+        epsilon: 0.0,
+        option_flags: SORTED_SELF,
+        max_radius: INF,
+        indices: &[5],
+        dists2: &[8.995835e-8],
+    },
+    // unsorted results, compared as sets
+    Case {
+        query: [2.0, 0.0],
+        k: 5,
+        epsilon: 0.0,
+        option_flags: ALLOW_SELF_MATCH,
+        max_radius: 2.5,
+        indices: &[0, 1, 2, 3, 4],
+        dists2: &[4.0, 1.0, 0.0, 0.0, 4.0],
+    },
+];
+
+/// Returns the results of `tree` for `case`, in the layout of libnabo
+fn knn_as_libnabo(
+    tree: &KDTree<f32, P2>,
+    case: &Case,
+    candidate_container: CandidateContainer,
+) -> Vec<(i32, f32)> {
+    let parameters = Parameters {
+        epsilon: case.epsilon,
+        max_radius: case.max_radius,
+        self_match: (case.option_flags & ALLOW_SELF_MATCH != 0).into(),
+        sort_results: case.option_flags & SORT_RESULTS != 0,
+        ..Parameters::default()
+    };
+    let query = P2::from(case.query);
+    let neighbours = tree.knn_advanced(case.k, &query, candidate_container, &parameters, None);
+    let mut results: Vec<(i32, f32)> = neighbours
+        .iter()
+        .map(|neighbour| (neighbour.index as i32, neighbour.dist2.into_inner()))
+        .collect();
+    results.resize(case.k as usize, (INVALID_INDEX, INVALID_VALUE));
+    results
+}
+
+/// Sorts `results` by distance, then by index among the neighbours at the same distance
+fn normalised(mut results: Vec<(i32, f32)>) -> Vec<(i32, f32)> {
+    results.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+    results
+}
+
+#[test]
+fn knn_matches_the_outputs_of_libnabo() {
+    let cloud: Vec<P2> = CLOUD.iter().map(|point| P2::from(*point)).collect();
+    for bucket_size in [2, 8] {
+        let tree = KDTree::new_with_bucket_size(&cloud, bucket_size);
+        for (case_index, case) in CASES.iter().enumerate() {
+            // strict exact mode rejects the approximate cases
+            if cfg!(feature = "strict-exact") && case.epsilon != 0.0 {
+                continue;
+            }
+            let expected: Vec<(i32, f32)> = case
+                .indices
+                .iter()
+                .copied()
+                .zip(case.dists2.iter().copied())
+                .collect();
+            for candidate_container in [CandidateContainer::Linear, CandidateContainer::BinaryHeap]
+            {
+                let found = knn_as_libnabo(&tree, case, candidate_container);
+                if case.option_flags & SORT_RESULTS != 0 {
+                    assert!(
+                        found.windows(2).all(|pair| pair[0].1 <= pair[1].1),
+                        "case {case_index}"
+                    );
+                }
+                let (found, expected) = (normalised(found), normalised(expected.clone()));
+                assert_eq!(found.len(), expected.len(), "case {case_index}");
+                for (found, expected) in found.iter().zip(&expected) {
+                    assert_eq!(found.0, expected.0, "case {case_index}");
+                    let close = found.1 == expected.1
+                        || (found.1 - expected.1).abs() <= 1e-6 * expected.1.abs();
+                    assert!(close, "case {case_index}: {found:?} != {expected:?}");
+                }
+            }
+        }
+    }
+}
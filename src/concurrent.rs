@@ -0,0 +1,266 @@
+//! Trees shared between query threads and a thread publishing new versions
+
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{AutoRebuild, KDTree, Point, Scalar, TreeQuality};
+
+/// A lock for critical sections of a few instructions, which never panic nor allocate
+struct SpinLock<V> {
+    locked: AtomicBool,
+    value: UnsafeCell<V>,
+}
+
+// SAFETY: the value is only accessed by the thread holding the lock
+unsafe impl<V: Send> Sync for SpinLock<V> {}
+
+impl<V> SpinLock<V> {
+    fn new(value: V) -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Runs `f` on the value while holding the lock, `f` must not panic
+    fn with<R>(&self, f: impl FnOnce(&mut V) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        // SAFETY: the lock is held until the store below, so no other reference to the value exists
+        let result = f(unsafe { &mut *self.value.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+/// A published version of the tree of a [`ConcurrentKDTree`]
+struct Published<T: Scalar, P: Point<T>> {
+    tree: Arc<KDTree<T, P>>,
+    version: u64,
+}
+
+impl<T: Scalar, P: Point<T>> Published<T, P> {
+    fn guard(&self) -> TreeGuard<T, P> {
+        TreeGuard {
+            tree: Arc::clone(&self.tree),
+            version: self.version,
+        }
+    }
+}
+
+/// A version of the tree of a [`ConcurrentKDTree`], which stays valid and unchanged while held
+///
+/// It dereferences to the tree, so all queries are available on it.
+pub struct TreeGuard<T: Scalar, P: Point<T>> {
+    tree: Arc<KDTree<T, P>>,
+    version: u64,
+}
+
+impl<T: Scalar, P: Point<T>> TreeGuard<T, P> {
+    /// Returns the version of the tree, starting at 0 and increased by each publication.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+}
+
+impl<T: Scalar, P: Point<T>> Clone for TreeGuard<T, P> {
+    fn clone(&self) -> Self {
+        TreeGuard {
+            tree: Arc::clone(&self.tree),
+            version: self.version,
+        }
+    }
+}
+
+impl<T: Scalar, P: Point<T>> Deref for TreeGuard<T, P> {
+    type Target = KDTree<T, P>;
+
+    fn deref(&self) -> &KDTree<T, P> {
+        &self.tree
+    }
+}
+
+/// A KDTree read by many threads while new versions of it are published
+///
+/// Readers get a [`TreeGuard`] on the current version, and all their queries run on that version,
+/// even if others are published meanwhile.
+/// Point indices are those of the cloud of each version: a neighbour index is only meaningful
+/// for the version that returned it, unless the publisher keeps the indices stable,
+/// as [`KDTree::update_point`] does.
+/// Reading and publishing only lock the tree for the time of swapping a pointer,
+/// and a version is freed when its last guard is dropped.
+pub struct ConcurrentKDTree<T: Scalar, P: Point<T>> {
+    current: SpinLock<Published<T, P>>,
+}
+
+impl<T: Scalar, P: Point<T>> ConcurrentKDTree<T, P> {
+    /// Creates a concurrent tree whose version 0 is `tree`.
+    pub fn new(tree: KDTree<T, P>) -> Self {
+        ConcurrentKDTree {
+            current: SpinLock::new(Published {
+                tree: Arc::new(tree),
+                version: 0,
+            }),
+        }
+    }
+
+    /// Returns a guard on the current version of the tree.
+    pub fn read(&self) -> TreeGuard<T, P> {
+        self.current.with(|current| current.guard())
+    }
+
+    /// Publishes `tree` as the new version, and returns its version number.
+    ///
+    /// Guards taken before keep reading the previous version.
+    pub fn replace(&self, tree: KDTree<T, P>) -> u64 {
+        let tree = Arc::new(tree);
+        let (previous, version) = self.current.with(|current| {
+            let version = current.version + 1;
+            let previous = core::mem::replace(current, Published { tree, version });
+            (previous, version)
+        });
+        // freed outside of the lock, if this was the last reference
+        drop(previous);
+        version
+    }
+
+    /// Applies `mutate` to a copy of the current version, publishes the result, and returns its version number.
+    ///
+    /// If another version is published while `mutate` runs, the copy is discarded
+    /// and `mutate` is applied again to the newer version, so no publication is lost.
+    /// Readers never observe a partially mutated tree.
+    pub fn write<F: FnMut(&mut KDTree<T, P>)>(&self, mut mutate: F) -> u64 {
+        loop {
+            let base = self.read();
+            let mut tree = (*base.tree).clone();
+            mutate(&mut tree);
+            let tree = Arc::new(tree);
+            let published = self.current.with(|current| {
+                if current.version != base.version {
+                    return None;
+                }
+                let version = current.version + 1;
+                Some((
+                    core::mem::replace(current, Published { tree, version }),
+                    version,
+                ))
+            });
+            if let Some((previous, version)) = published {
+                drop(previous);
+                return version;
+            }
+        }
+    }
+
+    /// Applies `mutate` as [`Self::write`] does, then rebuilds the mutated copy before publishing it
+    /// if its quality exceeds the thresholds of `policy` and `on_rebuild` accepts it.
+    ///
+    /// `on_rebuild` receives the quality of the mutated copy, and returns `false` to defer the rebuild,
+    /// for example to a time when the application is idle.
+    /// Checking the quality traverses the copy, which costs less than copying it.
+    /// If `mutate` is applied again because another version was published meanwhile,
+    /// `on_rebuild` may be called again.
+    pub fn write_with_auto_rebuild<F, R>(
+        &self,
+        mut mutate: F,
+        policy: &AutoRebuild,
+        mut on_rebuild: R,
+    ) -> u64
+    where
+        F: FnMut(&mut KDTree<T, P>),
+        R: FnMut(&TreeQuality) -> bool,
+    {
+        self.write(|tree| {
+            mutate(tree);
+            let quality = tree.quality();
+            if policy.needs_rebuild(&quality) && on_rebuild(&quality) {
+                tree.rebuild();
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    /// A cloud whose points all have `version` as first coordinate
+    fn versioned_cloud(version: u64) -> Vec<P2> {
+        (0..200)
+            .map(|i| P2::new2d(version as f32, i as f32))
+            .collect()
+    }
+
+    #[test]
+    fn readers_see_consistent_versions() {
+        let tree = ConcurrentKDTree::new(KDTree::new(&versioned_cloud(0)));
+        let first = tree.read();
+        assert_eq!(first.version(), 0);
+        const PUBLICATIONS: u64 = 200;
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let mut last_version = 0;
+                    while last_version < 2 * PUBLICATIONS {
+                        let guard = tree.read();
+                        assert!(guard.version() >= last_version);
+                        last_version = guard.version();
+                        // even versions are rebuilds, odd ones move point 0 by one
+                        let expected = (guard.version() / 2 * 2) as f32;
+                        let neighbours = guard.knn(3, &P2::new2d(0.0, 100.0));
+                        assert_eq!(neighbours.len(), 3);
+                        for neighbour in neighbours {
+                            assert_eq!(*neighbour.point.get(0), expected);
+                        }
+                        let moved = guard.points()[0];
+                        assert_eq!(*moved.get(0), expected + (guard.version() % 2) as f32);
+                    }
+                });
+            }
+            scope.spawn(|| {
+                for publication in 0..PUBLICATIONS {
+                    let version = tree.write(|tree| {
+                        let point = tree.points()[0];
+                        tree.update_point(0, P2::new2d(*point.get(0) + 1.0, 0.0));
+                    });
+                    assert_eq!(version, 2 * publication + 1);
+                    let version = tree.replace(KDTree::new(&versioned_cloud(version + 1)));
+                    assert_eq!(version, 2 * publication + 2);
+                }
+            });
+        });
+        // the first guard still reads version 0
+        assert_eq!(*first.points()[0].get(0), 0.0);
+        assert_eq!(tree.read().version(), 2 * PUBLICATIONS);
+    }
+
+    #[test]
+    fn concurrent_writes_are_not_lost() {
+        let cloud: Vec<P2> = (0..100).map(|i| P2::new2d(i as f32, 0.0)).collect();
+        let tree = ConcurrentKDTree::new(KDTree::new(&cloud));
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let tree = &tree;
+                scope.spawn(move || {
+                    for i in 0..25 {
+                        let index = thread * 25 + i;
+                        tree.write(|tree| {
+                            tree.update_point(index, P2::new2d(index as f32, 1.0));
+                        });
+                    }
+                });
+            }
+        });
+        let guard = tree.read();
+        assert_eq!(guard.version(), 100);
+        assert!(guard.points().iter().all(|point| *point.get(1) == 1.0));
+    }
+}
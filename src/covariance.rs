@@ -0,0 +1,175 @@
+//! Covariances of the neighbourhoods of points, as used for normal estimation
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+
+use crate::internal_parameters::InternalParameters;
+use crate::{Accumulation, KDTree, Parameters, Point, Scalar};
+
+impl<T: Scalar + Send + Sync, P: Point<T> + Send + Sync> KDTree<T, P> {
+    /// Returns the covariance of the `k` nearest neighbours of each point, in the order of the original point cloud.
+    ///
+    /// Each covariance is given by its upper triangle `[xx, xy, xz, yy, yz, zz]`, normalised by the number of neighbours.
+    /// The point itself is one of its neighbours if `parameters.self_match` allows coincident points,
+    /// see [`SelfMatch::allows_coincident`](crate::SelfMatch::allows_coincident).
+    /// The covariance is computed over less than `k` neighbours if the cloud or the ball defined by
+    /// `parameters.max_radius` contains less than `k` points, and is zero if there is no neighbour.
+    /// Neighbours are collected in a heap reused between points and never returned as lists,
+    /// and summed by increasing distance, so that the result does not depend on the layout of the heap.
+    /// If the `rayon` feature is enabled, points are processed in parallel.
+    ///
+    /// Panics if the points are not three-dimensional.
+    pub fn knn_covariances(&self, k: u32, parameters: &Parameters<T>) -> Vec<[T; 6]> {
+        self.knn_covariances_accumulated(k, parameters, Accumulation::Fast)
+    }
+
+    /// Same as [`Self::knn_covariances`], summing the neighbours in the order of `accumulation`.
+    ///
+    /// With [`Accumulation::Fast`], neighbours are summed by increasing distance, those at the same distance
+    /// in the order of the layout of the tree, and with [`Accumulation::Deterministic`],
+    /// by increasing index in the original point cloud.
+    pub fn knn_covariances_accumulated(
+        &self,
+        k: u32,
+        parameters: &Parameters<T>,
+        accumulation: Accumulation,
+    ) -> Vec<[T; 6]> {
+        assert_eq!(
+            P::DIM,
+            3,
+            "Covariances are only available for 3-D points, not {}-D",
+            P::DIM
+        );
+        let internal_parameters = InternalParameters::new(parameters, self.scale);
+        // there cannot be more neighbours than points, avoid allocating for them
+        let capacity = k.min(self.len() as u32) as usize;
+        self.map_indices_with(
+            self.len(),
+            || BinaryHeap::with_capacity(capacity),
+            |heap, source| {
+                self.knn_of_stored_point(source as u32, heap, &internal_parameters);
+                // sum in a fixed order, not in the layout of the heap, then reuse its allocation
+                let mut neighbours = core::mem::take(heap).into_sorted_vec();
+                if accumulation == Accumulation::Deterministic {
+                    neighbours
+                        .sort_unstable_by_key(|neighbour| self.externalise_index(neighbour.index));
+                }
+                // in the frame of the points, if the tree is rotated
+                let point = |index: u32| {
+                    let p = self.stored_point(index);
+                    [
+                        p.get(0).into_inner(),
+                        p.get(1).into_inner(),
+                        p.get(2).into_inner(),
+                    ]
+                };
+                let mut count = T::zero();
+                let mut mean = [T::zero(); 3];
+                for neighbour in &neighbours {
+                    let p = point(neighbour.index);
+                    count += T::one();
+                    for i in 0..3 {
+                        mean[i] += p[i];
+                    }
+                }
+                let mut covariance = [T::zero(); 6];
+                if count.is_zero() {
+                    *heap = BinaryHeap::from(neighbours);
+                    return covariance;
+                }
+                mean.iter_mut().for_each(|m| *m = *m / count);
+                for neighbour in neighbours.drain(..) {
+                    let p = point(neighbour.index);
+                    let d = [p[0] - mean[0], p[1] - mean[1], p[2] - mean[2]];
+                    covariance[0] += d[0] * d[0];
+                    covariance[1] += d[0] * d[1];
+                    covariance[2] += d[0] * d[2];
+                    covariance[3] += d[1] * d[1];
+                    covariance[4] += d[1] * d[2];
+                    covariance[5] += d[2] * d[2];
+                }
+                covariance.iter_mut().for_each(|c| *c = *c / count);
+                *heap = BinaryHeap::from(neighbours);
+                covariance
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+    use float_cmp::approx_eq;
+
+    #[test]
+    fn planar_neighbourhoods_have_no_normal_variance() {
+        // points on the plane z = 0
+        let mut cloud = Vec::new();
+        for i in 0..20 {
+            for j in 0..20 {
+                cloud.push(P2::new3d(i as f32, j as f32, 0.0));
+            }
+        }
+        let tree = KDTree::new(&cloud);
+        let covariances = tree.knn_covariances(9, &Parameters::default());
+        assert_eq!(covariances.len(), cloud.len());
+        for covariance in covariances {
+            assert!(covariance[0] > 0.0 && covariance[3] > 0.0);
+            assert_eq!(&covariance[4..], &[0.0, 0.0]);
+            assert_eq!(covariance[2], 0.0);
+        }
+    }
+
+    #[test]
+    fn covariances_match_explicit_neighbours() {
+        let cloud: Vec<P3> = random_point_cloud(500);
+        let tree = KDTree::new(&cloud);
+        let covariances = tree.knn_covariances(8, &Parameters::default());
+        for (point, covariance) in cloud.iter().zip(covariances) {
+            let neighbours = tree.knn(8, point);
+            let n = neighbours.len() as f32;
+            let mean: Vec<f32> = (0..3)
+                .map(|i| neighbours.iter().map(|m| *m.point.get(i)).sum::<f32>() / n)
+                .collect();
+            let cov = |i: u32, j: u32| {
+                neighbours
+                    .iter()
+                    .map(|m| {
+                        (*m.point.get(i) - mean[i as usize]) * (*m.point.get(j) - mean[j as usize])
+                    })
+                    .sum::<f32>()
+                    / n
+            };
+            let expected = [
+                cov(0, 0),
+                cov(0, 1),
+                cov(0, 2),
+                cov(1, 1),
+                cov(1, 2),
+                cov(2, 2),
+            ];
+            for (value, expected) in covariance.iter().zip(expected) {
+                assert!(
+                    approx_eq!(f32, *value, expected, epsilon = 1e-2),
+                    "{value} {expected}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn no_neighbour_gives_zero_covariance() {
+        let tree = KDTree::new(&[P2::new3d(0.0, 0.0, 0.0)]);
+        let parameters = Parameters {
+            self_match: SelfMatch::ExcludeCoincident,
+            ..Parameters::default()
+        };
+        assert_eq!(tree.knn_covariances(4, &parameters), vec![[0.0; 6]]);
+    }
+
+    #[test]
+    #[should_panic(expected = "only available for 3-D points")]
+    fn two_dimensional_points_panic() {
+        KDTree::new(&random_point_cloud::<2>(10)).knn_covariances(3, &Parameters::default());
+    }
+}
@@ -0,0 +1,293 @@
+//! Trees of points with their own radii, queried for the points whose ball covers a location
+
+use alloc::vec::Vec;
+
+use num_traits::Zero;
+use ordered_float::NotNan;
+
+use crate::cell::NodeCell;
+use crate::distance_scale::saturate;
+use crate::{point_slice_dist2, BuildError, BuildOptions, KDTree, Point, Scalar};
+
+/// A KDTree in which each point has a radius, to find the points whose ball contains a query
+///
+/// Each node stores the largest radius of its points, so that a query prunes the nodes
+/// farther from it than that radius.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CoveringKDTree<T: Scalar, P: Point<T>> {
+    tree: KDTree<T, P>,
+    /// radii of the points, in the internal order of the tree
+    radii: Vec<NotNan<T>>,
+    /// largest radius of the points of each node
+    max_radii: Vec<NotNan<T>>,
+}
+
+impl<T: Scalar, P: Point<T>> CoveringKDTree<T, P> {
+    /// Creates a new tree where `radii[i]` is the radius of `cloud[i]`.
+    ///
+    /// Panics in the same cases as [`Self::try_new`].
+    pub fn new(cloud: &[P], radii: &[T]) -> Self {
+        Self::try_new(cloud, radii).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Creates a new tree where `radii[i]` is the radius of `cloud[i]`, with default options.
+    ///
+    /// Returns an error if `radii` does not have the same length as `cloud`,
+    /// if a radius is negative or NaN, or in the same cases as [`KDTree::try_new`].
+    pub fn try_new(cloud: &[P], radii: &[T]) -> Result<Self, BuildError> {
+        Self::try_new_with_options(cloud, radii, &BuildOptions::default())
+    }
+
+    /// Creates a new tree where `radii[i]` is the radius of `cloud[i]`, with user-provided options.
+    pub fn try_new_with_options(
+        cloud: &[P],
+        radii: &[T],
+        options: &BuildOptions,
+    ) -> Result<Self, BuildError> {
+        if radii.len() != cloud.len() {
+            return Err(BuildError::ValuesLengthMismatch {
+                points: cloud.len(),
+                values: radii.len(),
+            });
+        }
+        let radii = radii
+            .iter()
+            .enumerate()
+            .map(|(index, radius)| {
+                valid_radius(*radius).ok_or(BuildError::InvalidRadius {
+                    index: index as u32,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let tree = KDTree::try_new_with_options(cloud, options)?;
+        let radii = tree
+            .externalisation_map()
+            .iter()
+            .map(|external| radii[*external as usize])
+            .collect();
+        let mut covering = CoveringKDTree {
+            tree,
+            radii,
+            max_radii: Vec::new(),
+        };
+        covering.max_radii = alloc::vec![NotNan::zero(); covering.tree.nodes.len()];
+        // children have larger ids than their parent
+        for node in (0..covering.max_radii.len()).rev() {
+            covering.update_max_radius(node);
+        }
+        Ok(covering)
+    }
+
+    /// Returns the underlying tree, whose queries ignore the radii.
+    pub fn tree(&self) -> &KDTree<T, P> {
+        &self.tree
+    }
+
+    /// Returns the radius of the point of index `index` in the original point cloud.
+    pub fn radius(&self, index: u32) -> T {
+        self.radii[self.tree.internalise_index(index) as usize].into_inner()
+    }
+
+    /// Sets the radius of the point of index `index` in the original point cloud.
+    ///
+    /// This updates the largest radii of the nodes holding the point,
+    /// in time logarithmic in the number of points.
+    /// Panics if `index` is out of range, or if `radius` is negative or NaN.
+    pub fn set_radius(&mut self, index: u32, radius: T) {
+        let radius = valid_radius(radius).expect("the radius must be positive or zero");
+        let internal_index = self.tree.internalise_index(index);
+        self.radii[internal_index as usize] = radius;
+        for node in self.tree.path_to_point(internal_index).into_iter().rev() {
+            self.update_max_radius(node);
+        }
+    }
+
+    /// Moves the point of index `index` in the original point cloud to `new_pos`, keeping its radius,
+    /// see [`KDTree::update_point`].
+    pub fn update_point(&mut self, index: u32, new_pos: P) {
+        self.tree.update_point(index, new_pos);
+    }
+
+    /// Replaces the coordinates of all points, keeping their radii, see [`KDTree::refit`].
+    pub fn refit(&mut self, points: &[P]) {
+        self.tree.refit(points);
+    }
+
+    /// Returns the indices of the points whose radius reaches `query`, inclusive, by increasing index.
+    ///
+    /// A node is pruned when `query` is farther from its cell than the largest radius of its points.
+    pub fn covering_points(&self, query: &P) -> Vec<u32> {
+        let mut found = Vec::new();
+        if !self.tree.is_empty() {
+            let query = self.tree.query_coordinates(query);
+            let mut cell = NodeCell::of_root(&self.tree);
+            self.recurse_covering(&query, 0, &mut cell, &mut found);
+        }
+        found.sort_unstable();
+        found
+    }
+
+    fn recurse_covering(
+        &self,
+        query: &[NotNan<T>],
+        node: usize,
+        cell: &mut NodeCell<T>,
+        found: &mut Vec<u32>,
+    ) {
+        let scale = self.tree.scale;
+        if cell.dist2_range(query, scale).0 > scaled_radius2(self.max_radii[node], scale) {
+            return;
+        }
+        self.tree.nodes[node].dispatch_on_type(
+            (cell, found),
+            |(cell, found), split_dim, split_val, right_child| {
+                let split_dim = split_dim as usize;
+                let parent = cell.extent(split_dim);
+                let drift = self.tree.drift;
+                cell.set_child(split_dim, parent, split_val, drift, true);
+                self.recurse_covering(query, node + 1, cell, found);
+                cell.set_child(split_dim, parent, split_val, drift, false);
+                self.recurse_covering(query, right_child as usize, cell, found);
+                cell.set_extent(split_dim, parent);
+            },
+            |(_, found), bucket_start_index, bucket_size| {
+                let dim = P::DIM as usize;
+                for index in bucket_start_index..bucket_start_index + bucket_size {
+                    let start = index as usize * dim;
+                    let point = &self.tree.points[start..start + dim];
+                    let dist2 = point_slice_dist2::<T, P>(query, point, scale);
+                    if dist2 <= scaled_radius2(self.radii[index as usize], scale) {
+                        found.push(self.tree.externalise_index(index));
+                    }
+                }
+            },
+        );
+    }
+
+    /// Recomputes the largest radius of `node` from its points or its children
+    fn update_max_radius(&mut self, node: usize) {
+        let max_radius = self.tree.nodes[node].dispatch_on_type(
+            (),
+            |_, _, _, right_child| {
+                self.max_radii[node + 1].max(self.max_radii[right_child as usize])
+            },
+            |_, bucket_start_index, bucket_size| {
+                let start = bucket_start_index as usize;
+                let bucket = &self.radii[start..start + bucket_size as usize];
+                bucket.iter().copied().fold(NotNan::zero(), NotNan::max)
+            },
+        );
+        self.max_radii[node] = max_radius;
+    }
+}
+
+/// Returns the square of `radius` in the internal units of a tree of distance scale `scale`,
+/// saturating on overflow
+fn scaled_radius2<T: Scalar>(radius: NotNan<T>, scale: NotNan<T>) -> NotNan<T> {
+    let radius = radius * scale;
+    saturate(radius * radius)
+}
+
+/// Returns `radius` if it is positive or zero
+fn valid_radius<T: Scalar>(radius: T) -> Option<NotNan<T>> {
+    NotNan::new(radius)
+        .ok()
+        .filter(|radius| *radius >= NotNan::zero())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn brute_force_covering(cloud: &[P2], radii: &[f32], query: &P2) -> Vec<u32> {
+        (0..cloud.len() as u32)
+            .filter(|index| {
+                let p = cloud[*index as usize];
+                let dx = p.get(0) - query.get(0);
+                let dy = p.get(1) - query.get(1);
+                let radius = radii[*index as usize];
+                (dx * dx + dy * dy).into_inner() <= radius * radius
+            })
+            .collect()
+    }
+
+    #[test]
+    fn covering_points_match_brute_force() {
+        let mut rng = crate::rng::SplitMix64::new(11);
+        let mut cloud: Vec<P2> = random_point_cloud(2000);
+        // mostly small radii, with a few large ones that must not be pruned
+        let mut radii: Vec<f32> = (0..cloud.len())
+            .map(|i| match i % 100 {
+                0 => 60.0,
+                _ => rng.below(1000) as f32 / 100.0,
+            })
+            .collect();
+        let mut tree = CoveringKDTree::new(&cloud, &radii);
+        for round in 0..3 {
+            for _ in 0..50 {
+                let query = random_point();
+                assert_eq!(
+                    tree.covering_points(&query),
+                    brute_force_covering(&cloud, &radii, &query)
+                );
+            }
+            // later rounds change radii and move points, which must keep queries exact
+            for _ in 0..100 {
+                let index = rng.below(cloud.len() as u64) as u32;
+                radii[index as usize] = rng.below(3000) as f32 / 100.0;
+                tree.set_radius(index, radii[index as usize]);
+                assert_eq!(tree.radius(index), radii[index as usize]);
+                if round == 1 {
+                    cloud[index as usize] = random_point();
+                    tree.update_point(index, cloud[index as usize]);
+                }
+            }
+            if round == 2 {
+                cloud = random_point_cloud(cloud.len() as u32);
+                tree.refit(&cloud);
+            }
+        }
+        let point = P2::new2d(0.0, 0.0);
+        let empty = CoveringKDTree::<f32, P2>::new(&[], &[]);
+        assert!(empty.covering_points(&point).is_empty());
+        // the points of radius zero cover themselves
+        let tree = CoveringKDTree::new(&[point, point], &[0.0, 1.0]);
+        assert_eq!(tree.covering_points(&point), vec![0, 1]);
+    }
+
+    #[test]
+    fn covering_points_are_exact_at_extreme_magnitudes() {
+        let cloud = [P2::new2d(1e-30, 0.0), P2::new2d(3e-30, 0.0)];
+        let tree = CoveringKDTree::new(&cloud, &[1.5e-30, 0.5e-30]);
+        assert_eq!(tree.covering_points(&P2::new2d(0.0, 0.0)), vec![0]);
+        assert_eq!(tree.covering_points(&P2::new2d(3e-30, 0.0)), vec![1]);
+        assert!(tree.covering_points(&P2::new2d(5e-30, 0.0)).is_empty());
+
+        let cloud = [P2::new2d(1e20, 0.0), P2::new2d(0.0, 0.0)];
+        let tree = CoveringKDTree::new(&cloud, &[2e19, 1.0]);
+        assert!(tree.covering_points(&P2::new2d(-1e20, 0.0)).is_empty());
+        assert_eq!(tree.covering_points(&P2::new2d(0.9e20, 0.0)), vec![0]);
+        assert_eq!(tree.covering_points(&P2::new2d(0.0, 0.5)), vec![1]);
+    }
+
+    #[test]
+    fn invalid_radii_are_errors() {
+        let cloud = vec![P2::new2d(0.0, 0.0); 3];
+        assert_eq!(
+            CoveringKDTree::try_new(&cloud, &[1.0, 2.0]).unwrap_err(),
+            BuildError::ValuesLengthMismatch {
+                points: 3,
+                values: 2
+            }
+        );
+        assert_eq!(
+            CoveringKDTree::try_new(&cloud, &[1.0, -2.0, 0.0]).unwrap_err(),
+            BuildError::InvalidRadius { index: 1 }
+        );
+        assert_eq!(
+            CoveringKDTree::try_new(&cloud, &[1.0, 2.0, f32::NAN]).unwrap_err(),
+            BuildError::InvalidRadius { index: 2 }
+        );
+    }
+}
@@ -0,0 +1,298 @@
+//! Building single-precision trees from double-precision points,
+//! and refining their results with the double-precision points
+
+use alloc::vec::Vec;
+
+use ordered_float::NotNan;
+
+use crate::internal_parameters::InternalParameters;
+use crate::radius_search::AllCandidates;
+use crate::{BuildError, BuildOptions, KDTree, Neighbour, Parameters, Point, QueryError};
+
+/// The unit roundoff of `f32`, bounding the relative error of rounding to it
+const F32_UNIT_ROUNDOFF: f64 = f32::EPSILON as f64 / 2.0;
+
+/// Returns the bound `γ_n = n u / (1 - n u)` of the relative error of `n` successive `f32` roundings
+fn f32_rounding_bound(n: u32) -> f64 {
+    let nu = n as f64 * F32_UNIT_ROUNDOFF;
+    nu / (1.0 - nu)
+}
+
+impl<P: Point<f32>> KDTree<f32, P> {
+    /// Creates a new single-precision KDTree from double-precision points, see [`Self::try_new_from_f64`].
+    ///
+    /// Panics in the same cases as [`Self::try_new_from_f64`].
+    pub fn new_from_f64<Q: Point<f64>>(cloud: &[Q]) -> Self {
+        Self::try_new_from_f64(cloud, &BuildOptions::default())
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Creates a new single-precision KDTree from double-precision points, with user-provided options.
+    ///
+    /// Coordinates are rounded to the nearest `f32`, ties to even.
+    /// Coordinates too large for `f32` become infinite, and so are reported as
+    /// [`BuildError::NonFiniteCoordinate`], and too small ones become zero.
+    /// Panics if `Q` and `P` do not have the same number of dimensions.
+    pub fn try_new_from_f64<Q: Point<f64>>(
+        cloud: &[Q],
+        options: &BuildOptions,
+    ) -> Result<Self, BuildError> {
+        assert_eq!(
+            Q::DIM,
+            P::DIM,
+            "Source and target points must have the same number of dimensions"
+        );
+        let cloud: Vec<P> = cloud.iter().map(round_point).collect();
+        Self::try_new_with_options(&cloud, options)
+    }
+
+    /// Finds the `k` nearest neighbours of `query` by their double-precision distance,
+    /// searching this single-precision tree and refining with `originals`,
+    /// the double-precision points the tree was built from, for example by [`Self::new_from_f64`].
+    ///
+    /// Neighbours are sorted by increasing distance, then by index, and their points and distances
+    /// are those of `originals`, so that the result is that of an exact search among `originals`.
+    /// A first search finds `k` candidates, whose largest double-precision distance `R` bounds
+    /// the distance of the `k`-th nearest neighbour.
+    /// A radius search then collects all the points within `R` plus a margin covering the single-precision errors:
+    /// * rounding the points and the query to `f32` moves them by at most `u ‖p‖` and `u ‖q‖`,
+    ///   with `u = 2⁻²⁴` the unit roundoff, and `‖p‖` bounded from the bounds of the tree,
+    ///   so that a point at distance `d` of the query is at most at `d + u (‖p‖ + ‖q‖)` after rounding,
+    /// * the squared distance and the pruning bounds of the search are computed with at most `2 D + 8`
+    ///   successive roundings, `D` being the number of dimensions, so that the squared search radius
+    ///   is widened relatively by `4 γ` with `γ = n u / (1 - n u)` for `n = 2 D + 8`.
+    ///
+    /// Thus no true neighbour is lost to single-precision rounding, even among points that
+    /// only differ below `f32` precision.
+    /// If the tree was modified after being built, `originals` must have been modified alike.
+    /// Returns [`QueryError::ValuesLengthMismatch`] if `originals` does not have one point per point of the tree.
+    /// Panics if `Q` and `P` do not have the same number of dimensions.
+    pub fn knn_refined<Q: Point<f64>>(
+        &self,
+        k: u32,
+        query: &Q,
+        originals: &[Q],
+    ) -> Result<Vec<Neighbour<f64, Q>>, QueryError> {
+        assert_eq!(
+            Q::DIM,
+            P::DIM,
+            "Source and target points must have the same number of dimensions"
+        );
+        if originals.len() != self.len() {
+            return Err(QueryError::ValuesLengthMismatch {
+                points: self.len(),
+                values: originals.len(),
+            });
+        }
+        if k == 0 || self.is_empty() {
+            return Ok(Vec::new());
+        }
+        let dist2 = |index: u32| {
+            let point = &originals[index as usize];
+            (0..Q::DIM).fold(0.0, |dist2, i| {
+                let diff = *point.get(i) - *query.get(i);
+                dist2 + diff * diff
+            })
+        };
+        let rounded_query: P = round_point(query);
+        let candidates = self.knn(k, &rounded_query);
+        let radius = if candidates.len() < k as usize {
+            f64::INFINITY
+        } else {
+            let furthest2 = candidates
+                .iter()
+                .map(|neighbour| dist2(neighbour.index))
+                .fold(0.0, f64::max);
+            furthest2.sqrt()
+        };
+        // the largest norm of the points of the tree, slightly enlarged to cover their rounding
+        let points_norm = (0..P::DIM as usize)
+            .map(|dim| {
+                let lowest = self.min_bounds[dim].into_inner().abs();
+                let highest = self.max_bounds[dim].into_inner().abs();
+                let extent = lowest.max(highest) as f64;
+                extent * extent
+            })
+            .sum::<f64>()
+            .sqrt()
+            * (1.0 + 2.0 * F32_UNIT_ROUNDOFF);
+        let query_norm = (0..Q::DIM)
+            .map(|i| *query.get(i) * *query.get(i))
+            .sum::<f64>()
+            .sqrt();
+        let rounded_radius = radius + F32_UNIT_ROUNDOFF * (points_norm + query_norm);
+        let scaled_radius = rounded_radius * self.scale.into_inner() as f64;
+        let radius2 =
+            scaled_radius * scaled_radius * (1.0 + 4.0 * f32_rounding_bound(2 * P::DIM + 8));
+        // rounding to f32 may shrink the radius, round it up instead
+        let search_radius2 = match radius2 as f32 {
+            rounded if (rounded as f64) < radius2 => next_up(rounded),
+            rounded => rounded,
+        };
+        let internal_parameters = InternalParameters {
+            max_radius2: NotNan::new(search_radius2).expect("the search radius is not NaN"),
+            ..InternalParameters::new(&Parameters::default(), self.scale)
+        };
+        let mut collector = AllCandidates(Vec::new());
+        self.knn_internal(
+            &mut collector,
+            &self.query_coordinates(&rounded_query),
+            &internal_parameters,
+        );
+        let mut neighbours: Vec<_> = collector
+            .0
+            .iter()
+            .map(|neighbour| {
+                let index = self.externalise_index(neighbour.index);
+                (
+                    NotNan::new(dist2(index)).expect("distances are not NaN"),
+                    index,
+                )
+            })
+            .collect();
+        neighbours.sort_unstable();
+        neighbours.truncate(k as usize);
+        Ok(neighbours
+            .into_iter()
+            .map(|(dist2, index)| {
+                let original = &originals[index as usize];
+                let mut point = Q::default();
+                for i in 0..Q::DIM {
+                    point.set(i, original.get(i));
+                }
+                Neighbour {
+                    point,
+                    dist2,
+                    index,
+                }
+            })
+            .collect())
+    }
+}
+
+/// Rounds the coordinates of `source` to the nearest `f32`, ties to even
+pub(crate) fn round_point<Q: Point<f64>, P: Point<f32>>(source: &Q) -> P {
+    let mut point = P::default();
+    for i in 0..P::DIM {
+        // rounding a non-NaN value cannot produce NaN
+        let value =
+            NotNan::new(source.get(i).into_inner() as f32).expect("rounded coordinate is not NaN");
+        point.set(i, value);
+    }
+    point
+}
+
+/// Returns the smallest `f32` larger than the positive or zero `value`, or `value` if it is infinite
+fn next_up(value: f32) -> f32 {
+    if value.is_infinite() {
+        value
+    } else {
+        f32::from_bits(value.to_bits() + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn single_precision_tree_from_double_points() {
+        let cloud: Vec<P3d> = random_point_cloud::<3>(500)
+            .into_iter()
+            .map(|p| {
+                let coordinates: [f32; 3] = p.into();
+                P3d::from(coordinates.map(|c| c as f64 + 1e-9))
+            })
+            .collect();
+        let tree = KDTree::<f32, P3>::new_from_f64(&cloud);
+        assert_eq!(tree.len(), 500);
+        for (index, point) in tree.iter() {
+            let source: [f64; 3] = cloud[index as usize].into();
+            assert_eq!(<[f32; 3]>::from(point), source.map(|c| c as f32));
+        }
+        let too_large = vec![P3d::from([1e300, 0.0, 0.0])];
+        assert_eq!(
+            KDTree::<f32, P3>::try_new_from_f64(&too_large, &BuildOptions::default()),
+            Err(BuildError::NonFiniteCoordinate { index: 0 })
+        );
+    }
+
+    /// The `k` nearest of `cloud` to `query` in double precision, by distance then index
+    fn brute_force_knn(cloud: &[P2d], query: &P2d, k: usize) -> Vec<(u32, f64)> {
+        let mut neighbours: Vec<_> = cloud
+            .iter()
+            .enumerate()
+            .map(|(index, point)| {
+                let dx = *point.get(0) - *query.get(0);
+                let dy = *point.get(1) - *query.get(1);
+                (dx * dx + dy * dy, index as u32)
+            })
+            .collect();
+        neighbours.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+        neighbours
+            .into_iter()
+            .take(k)
+            .map(|(dist2, index)| (index, dist2))
+            .collect()
+    }
+
+    #[test]
+    fn refined_results_are_exact_below_single_precision() {
+        let mut rng = crate::rng::SplitMix64::new(5);
+        // far from the origin, the points differ only below the precision of f32,
+        // whose spacing is about 6e-5 around 1000
+        let mut cloud: Vec<P2d> = (0..2000)
+            .map(|_| {
+                let mut coordinate = || 1000.0 + rng.below(1 << 20) as f64 * 1e-10;
+                P2d::from([coordinate(), coordinate()])
+            })
+            .collect();
+        // and a few points further away and in a grid, for the search to prune
+        cloud.extend((0..400).map(|i| P2d::from([(i % 20) as f64 * 60.0, (i / 20) as f64 * 60.0])));
+        let tree = KDTree::<f32, P2>::new_from_f64(&cloud);
+        let mut single_precision_errors = 0;
+        for trial in 0..100 {
+            let query = if trial % 4 == 0 {
+                P2d::from([rng.below(1200) as f64, rng.below(1200) as f64])
+            } else {
+                P2d::from([
+                    1000.0 + rng.below(1 << 20) as f64 * 1e-10,
+                    1000.0 + rng.below(1 << 20) as f64 * 1e-10,
+                ])
+            };
+            for k in [1, 5, 30] {
+                let expected = brute_force_knn(&cloud, &query, k);
+                let refined = tree.knn_refined(k as u32, &query, &cloud).unwrap();
+                let found: Vec<_> = refined.iter().map(|n| (n.index, *n.dist2)).collect();
+                assert_eq!(found, expected);
+                for neighbour in &refined {
+                    assert_eq!(neighbour.point, cloud[neighbour.index as usize]);
+                }
+                let query: [f64; 2] = query.into();
+                let single: Vec<_> = tree
+                    .knn(k as u32, &P2::from(query.map(|c| c as f32)))
+                    .iter()
+                    .map(|n| n.index)
+                    .collect();
+                if single != expected.iter().map(|n| n.0).collect::<Vec<_>>() {
+                    single_precision_errors += 1;
+                }
+            }
+        }
+        // without refinement, single precision gets these queries wrong
+        assert!(single_precision_errors > 0);
+        assert_eq!(tree.knn_refined(0, &cloud[0], &cloud).unwrap(), vec![]);
+        assert_eq!(
+            tree.knn_refined(5000, &cloud[0], &cloud).unwrap().len(),
+            cloud.len()
+        );
+        assert_eq!(
+            tree.knn_refined(1, &cloud[0], &cloud[1..]),
+            Err(QueryError::ValuesLengthMismatch {
+                points: cloud.len(),
+                values: cloud.len() - 1
+            })
+        );
+    }
+}
@@ -0,0 +1,279 @@
+//! Deterministic point clouds of documented statistics, to benchmark and test searches on reproducible data
+//!
+//! The generators depend only on their arguments, and not on `rand`, so a given seed produces the same cloud
+//! on every platform and with every version of the dependencies.
+//! [`clouds_2d`] and [`clouds_3d`] return a cloud of each generator by name, as the benchmarks of this crate use them.
+//! Real scans are loaded with [`parse_xyz`].
+
+use alloc::{vec, vec::Vec};
+use core::fmt;
+
+use num_traits::Float;
+use ordered_float::NotNan;
+
+use crate::rng::SplitMix64;
+use crate::simple_point::{SimplePoint, P2, P3};
+
+/// Half the side of the cube the generators draw positions in, centred on the origin
+pub const HALF_SIDE: f32 = 100.0;
+
+/// The number of lasers of [`scan_lines`]
+pub const SCAN_LINE_COUNT: u32 = 16;
+
+/// Returns a number uniform in `[0, 1)`
+fn unit(rng: &mut SplitMix64) -> f64 {
+    (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Returns a number uniform in `[-HALF_SIDE, HALF_SIDE)`
+fn in_cube(rng: &mut SplitMix64) -> f64 {
+    (unit(rng) * 2.0 - 1.0) * HALF_SIDE as f64
+}
+
+/// Returns a number of the standard normal distribution, by the Box-Muller transform
+fn normal(rng: &mut SplitMix64) -> f64 {
+    // 1 - unit is in (0, 1], so that its logarithm is finite
+    let radius = Float::sqrt(-2.0 * Float::ln(1.0 - unit(rng)));
+    radius * Float::cos(2.0 * core::f64::consts::PI * unit(rng))
+}
+
+fn point<const D: usize>(coordinates: [f64; D]) -> SimplePoint<D> {
+    SimplePoint(
+        coordinates.map(|c| NotNan::new(c as f32).expect("generated coordinates are not NaN")),
+    )
+}
+
+/// Returns `count` points whose coordinates are independent and uniform in `[-100, 100)`.
+///
+/// Each coordinate has a mean of 0 and a standard deviation of `100 / √3`, about 57.7.
+pub fn uniform_cube<const D: usize>(count: u32, seed: u64) -> Vec<SimplePoint<D>> {
+    let mut rng = SplitMix64::new(seed);
+    (0..count)
+        .map(|_| point(core::array::from_fn(|_| in_cube(&mut rng))))
+        .collect()
+}
+
+/// Returns `count` points of a mixture of `clusters` isotropic Gaussians of standard deviation `sigma` on each axis.
+///
+/// The centres of the clusters are uniform in the cube of [`uniform_cube`] and each point belongs to a cluster
+/// drawn uniformly, so that the clusters have about `count / clusters` points each.
+/// The density of the cloud varies by orders of magnitude, as in scans of scattered objects.
+///
+/// Panics if `clusters` is zero while `count` is not.
+pub fn gaussian_mixture<const D: usize>(
+    count: u32,
+    clusters: u32,
+    sigma: f32,
+    seed: u64,
+) -> Vec<SimplePoint<D>> {
+    assert!(count == 0 || clusters > 0, "a mixture needs clusters");
+    let mut rng = SplitMix64::new(seed);
+    let centres: Vec<[f64; D]> = (0..clusters)
+        .map(|_| core::array::from_fn(|_| in_cube(&mut rng)))
+        .collect();
+    (0..count)
+        .map(|_| {
+            let centre = centres[rng.below(clusters as u64) as usize];
+            point(core::array::from_fn(|axis| {
+                centre[axis] + normal(&mut rng) * sigma as f64
+            }))
+        })
+        .collect()
+}
+
+/// Returns `count` points of a simulated rotating lidar at the origin, above a ground plane and inside a wall.
+///
+/// The [`SCAN_LINE_COUNT`] lasers have elevations evenly spaced by 2° from -15° to 15°, and each takes
+/// `count / 16` points, rounded up, evenly spaced in azimuth, from an azimuth that depends on the seed.
+/// Beams hit the ground at `z = -2` if it is within 50 of the sensor horizontally, and else a vertical cylinder
+/// of radius 50, with a range noise of standard deviation 0.02.
+/// The points lie on rings and lines, their spacing growing with the range from about 0.05 near the sensor
+/// to 0.3 on the wall for 16 000 points, so the cloud is dense along the lines and sparse across them.
+pub fn scan_lines(count: u32, seed: u64) -> Vec<P3> {
+    let mut rng = SplitMix64::new(seed);
+    let per_line = (count + SCAN_LINE_COUNT - 1) / SCAN_LINE_COUNT;
+    let start = unit(&mut rng) * 2.0 * core::f64::consts::PI;
+    (0..count)
+        .map(|index| {
+            let line = index % SCAN_LINE_COUNT;
+            let step = index / SCAN_LINE_COUNT;
+            let elevation = (-15.0 + 2.0 * line as f64).to_radians();
+            let azimuth = start + 2.0 * core::f64::consts::PI * step as f64 / per_line as f64;
+            let ground = if elevation < 0.0 {
+                -2.0 / Float::tan(elevation)
+            } else {
+                f64::INFINITY
+            };
+            let horizontal = ground.min(50.0);
+            let range = horizontal / Float::cos(elevation);
+            let horizontal = horizontal * (range + 0.02 * normal(&mut rng)) / range;
+            point([
+                horizontal * Float::cos(azimuth),
+                horizontal * Float::sin(azimuth),
+                horizontal * Float::tan(elevation),
+            ])
+        })
+        .collect()
+}
+
+/// Returns `count` points on `count / 8` positions, rounded up, uniform in the cube of [`uniform_cube`].
+///
+/// Point `i` is on position `i` modulo the number of positions, so that each position has about 8 points.
+/// The even copies of a position, starting with its first, are exactly equal; the odd ones are moved
+/// uniformly by at most 0.001 on each axis.
+/// About half of the points are thus exact duplicates of another point, and the others are closer to a point
+/// than the precision of most sensors: the worst case for ties and for trees whose leaves cannot be split.
+pub fn near_duplicates<const D: usize>(count: u32, seed: u64) -> Vec<SimplePoint<D>> {
+    let mut rng = SplitMix64::new(seed);
+    let position_count = (count + 7) / 8;
+    let positions: Vec<[f64; D]> = (0..position_count)
+        .map(|_| core::array::from_fn(|_| in_cube(&mut rng)))
+        .collect();
+    (0..count)
+        .map(|index| {
+            let position = positions[(index % position_count) as usize];
+            if (index / position_count) % 2 == 0 {
+                point(position)
+            } else {
+                point(core::array::from_fn(|axis| {
+                    position[axis] + (unit(&mut rng) * 2.0 - 1.0) * 1e-3
+                }))
+            }
+        })
+        .collect()
+}
+
+/// Returns a cloud of `count` points of each 2-dimensional generator, with its name:
+/// `"Uniform"`, `"Clustered"` with 16 clusters of standard deviation 2, and `"NearDuplicates"`.
+pub fn clouds_2d(count: u32, seed: u64) -> Vec<(&'static str, Vec<P2>)> {
+    vec![
+        ("Uniform", uniform_cube(count, seed)),
+        ("Clustered", gaussian_mixture(count, 16, 2.0, seed)),
+        ("NearDuplicates", near_duplicates(count, seed)),
+    ]
+}
+
+/// Returns a cloud of `count` points of each 3-dimensional generator, with its name:
+/// the clouds of [`clouds_2d`] in 3 dimensions, and `"ScanLines"`.
+pub fn clouds_3d(count: u32, seed: u64) -> Vec<(&'static str, Vec<P3>)> {
+    vec![
+        ("Uniform", uniform_cube(count, seed)),
+        ("Clustered", gaussian_mixture(count, 16, 2.0, seed)),
+        ("NearDuplicates", near_duplicates(count, seed)),
+        ("ScanLines", scan_lines(count, seed)),
+    ]
+}
+
+/// A line of the text given to [`parse_xyz`] that is not a point
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct XyzError {
+    /// the number of the line, from 1
+    pub line: usize,
+}
+
+impl fmt::Display for XyzError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {} does not start with three finite coordinates",
+            self.line
+        )
+    }
+}
+
+/// Parses the points of an XYZ text, as exported by most lidar tools, one point per line.
+///
+/// A line starts with the `x`, `y` and `z` coordinates, separated by whitespace or commas,
+/// and further columns, such as intensities, are ignored.
+/// Empty lines and lines starting with `#` or `//` are skipped.
+/// Returns the first line that is not a point, or whose coordinates are not finite in `f32`.
+pub fn parse_xyz(text: &str) -> Result<Vec<P3>, XyzError> {
+    let mut points = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+            continue;
+        }
+        let error = XyzError { line: index + 1 };
+        let mut columns = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|column| !column.is_empty());
+        let mut coordinates = [NotNan::default(); 3];
+        for coordinate in &mut coordinates {
+            let value: f32 = columns
+                .next()
+                .and_then(|column| column.parse().ok())
+                .ok_or(error)?;
+            if !value.is_finite() {
+                return Err(error);
+            }
+            *coordinate = NotNan::new(value).map_err(|_| error)?;
+        }
+        points.push(SimplePoint(coordinates));
+    }
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    fn mean_and_deviation(values: impl Iterator<Item = f32> + Clone) -> (f64, f64) {
+        let count = values.clone().count() as f64;
+        let mean = values.clone().map(|v| v as f64).sum::<f64>() / count;
+        let variance = values.map(|v| (v as f64 - mean).powi(2)).sum::<f64>() / count;
+        (mean, variance.sqrt())
+    }
+
+    #[test]
+    fn generators_are_deterministic_and_have_their_statistics() {
+        assert_eq!(clouds_3d(1000, 241), clouds_3d(1000, 241));
+        assert_ne!(uniform_cube::<3>(10, 1), uniform_cube::<3>(10, 2));
+        for (name, cloud) in clouds_3d(16000, 241) {
+            assert_eq!(cloud.len(), 16000, "{}", name);
+        }
+        let uniform: Vec<P3> = uniform_cube(20000, 241);
+        for axis in 0..3 {
+            let (mean, deviation) = mean_and_deviation(uniform.iter().map(|p| *p.get(axis)));
+            assert!(mean.abs() < 2.0);
+            assert!((deviation - 100.0 / 3f64.sqrt()).abs() < 1.0);
+            assert!(uniform.iter().all(|p| p.get(axis).abs() <= HALF_SIDE));
+        }
+        // a single cluster is a Gaussian around its centre
+        let cluster: Vec<P2> = gaussian_mixture(20000, 1, 3.0, 241);
+        let (_, deviation) = mean_and_deviation(cluster.iter().map(|p| *p.get(0)));
+        assert!((deviation - 3.0).abs() < 0.1);
+        // the lines of the scan are on the ground or the wall
+        let scan = scan_lines(16000, 241);
+        for point in &scan {
+            let horizontal = point.get(0).hypot(*point.get(1));
+            assert!((*point.get(2) + 2.0).abs() < 0.05 || (horizontal - 50.0).abs() < 0.1);
+        }
+        // half of the points are exact duplicates
+        let duplicates: Vec<P3> = near_duplicates(8000, 241);
+        let report = KDTree::new(&duplicates).duplicate_report();
+        assert_eq!(report.distinct_positions, 1000 + 4000);
+        assert_eq!(report.group_sizes, vec![(4, 1000)]);
+    }
+
+    #[test]
+    fn xyz_text_is_parsed() {
+        let text = "# x y z intensity\n1 2 3 0.5\n\n4.5,-1e2,0\n  7\t8\t9  \n";
+        assert_eq!(
+            parse_xyz(text),
+            Ok(vec![
+                P2::new3d(1.0, 2.0, 3.0),
+                P2::new3d(4.5, -100.0, 0.0),
+                P2::new3d(7.0, 8.0, 9.0),
+            ])
+        );
+        assert_eq!(parse_xyz("1 2 3\n1 2\n"), Err(XyzError { line: 2 }));
+        assert_eq!(parse_xyz("1 2 nan"), Err(XyzError { line: 1 }));
+        assert_eq!(parse_xyz("1 2 1e39"), Err(XyzError { line: 1 }));
+        assert_eq!(
+            XyzError { line: 2 }.to_string(),
+            "line 2 does not start with three finite coordinates"
+        );
+    }
+}
@@ -0,0 +1,108 @@
+//! Density-based clustering (DBSCAN)
+
+use alloc::{vec, vec::Vec};
+
+use crate::{BitSet, KDTree, Point, Scalar};
+
+/// The label of points that do not belong to any cluster
+pub const NOISE: i32 = -1;
+
+/// The label of points not processed yet
+const UNDEFINED: i32 = i32::MIN;
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Clusters the points with the DBSCAN algorithm.
+    ///
+    /// A point is a core point if at least `min_pts` points, itself included, are within distance `eps`.
+    /// Clusters are formed of core points within `eps` of each others, and of the points within `eps`
+    /// of these core points.
+    /// Returns the label of each point, in the order of the original point cloud:
+    /// clusters are numbered from 0, and points in no cluster are labelled [`NOISE`].
+    /// A point within `eps` of core points of several clusters is assigned to the first one found.
+    ///
+    /// Panics if `eps` is negative or NaN.
+    pub fn dbscan(&self, eps: T, min_pts: usize) -> Vec<i32> {
+        let mut labels = vec![UNDEFINED; self.len()];
+        let mut queued = BitSet::with_len(self.len());
+        let mut neighbours = Vec::new();
+        let mut seeds = Vec::new();
+        let mut cluster = 0;
+        for index in 0..self.len() as u32 {
+            if labels[index as usize] != UNDEFINED {
+                continue;
+            }
+            neighbours.clear();
+            if self.region_query(index, eps, &mut queued, false, &mut neighbours) < min_pts {
+                labels[index as usize] = NOISE;
+                continue;
+            }
+            // expand a new cluster from this core point
+            labels[index as usize] = cluster;
+            queued.insert(index as usize);
+            seeds.clear();
+            for neighbour in &neighbours {
+                if queued.insert(*neighbour as usize) {
+                    seeds.push(*neighbour);
+                }
+            }
+            while let Some(seed) = seeds.pop() {
+                let label = &mut labels[seed as usize];
+                if *label == NOISE {
+                    // a border point, not a core point
+                    *label = cluster;
+                }
+                if *label != UNDEFINED {
+                    continue;
+                }
+                *label = cluster;
+                neighbours.clear();
+                if self.region_query(seed, eps, &mut queued, false, &mut neighbours) >= min_pts {
+                    for neighbour in &neighbours {
+                        if queued.insert(*neighbour as usize) {
+                            seeds.push(*neighbour);
+                        }
+                    }
+                }
+            }
+            cluster += 1;
+        }
+        labels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dbscan::NOISE;
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn two_clusters_and_noise() {
+        let mut cloud = Vec::new();
+        for i in 0..10 {
+            for j in 0..10 {
+                cloud.push(P2::new2d(i as f32, j as f32));
+                cloud.push(P2::new2d(100.0 + i as f32, j as f32));
+            }
+        }
+        cloud.push(P2::new2d(50.0, 50.0));
+        // a border point, within eps of a single core point
+        cloud.push(P2::new2d(-1.5, 0.0));
+        let tree = KDTree::new(&cloud);
+        let labels = tree.dbscan(1.5, 4);
+        assert_eq!(labels.len(), cloud.len());
+        for i in 0..100 {
+            assert_eq!(labels[2 * i], 0);
+            assert_eq!(labels[2 * i + 1], 1);
+        }
+        assert_eq!(labels[200], NOISE);
+        assert_eq!(labels[201], 0);
+        assert!(tree.dbscan(0.5, 2).iter().all(|label| *label == NOISE));
+    }
+
+    #[test]
+    fn empty_tree() {
+        let tree = KDTree::<f32, P2>::new(&[]);
+        assert!(tree.dbscan(1.0, 3).is_empty());
+    }
+}
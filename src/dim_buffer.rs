@@ -0,0 +1,65 @@
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+
+use num_traits::Zero;
+use ordered_float::NotNan;
+
+use crate::Scalar;
+
+/// Number of dimensions up to which per-query buffers are kept on the stack
+const STACK_DIM: usize = 8;
+
+/// A buffer of one value per dimension, avoiding heap allocation in low dimensions
+pub(crate) enum DimBuffer<T: Scalar> {
+    Stack([NotNan<T>; STACK_DIM], usize),
+    Heap(Vec<NotNan<T>>),
+}
+
+impl<T: Scalar> DimBuffer<T> {
+    /// Creates a buffer of `len` times `value`
+    pub(crate) fn new(len: usize, value: NotNan<T>) -> Self {
+        if len <= STACK_DIM {
+            DimBuffer::Stack([value; STACK_DIM], len)
+        } else {
+            DimBuffer::Heap(alloc::vec![value; len])
+        }
+    }
+
+    /// Creates a buffer of `len` values given by `f`, or the first error returned by `f`
+    pub(crate) fn try_from_fn<E>(
+        len: usize,
+        mut f: impl FnMut(usize) -> Result<NotNan<T>, E>,
+    ) -> Result<Self, E> {
+        if len <= STACK_DIM {
+            let mut values = [NotNan::<T>::zero(); STACK_DIM];
+            for (i, value) in values.iter_mut().take(len).enumerate() {
+                *value = f(i)?;
+            }
+            Ok(DimBuffer::Stack(values, len))
+        } else {
+            (0..len)
+                .map(f)
+                .collect::<Result<_, _>>()
+                .map(DimBuffer::Heap)
+        }
+    }
+}
+
+impl<T: Scalar> Deref for DimBuffer<T> {
+    type Target = [NotNan<T>];
+    fn deref(&self) -> &Self::Target {
+        match self {
+            DimBuffer::Stack(values, len) => &values[..*len],
+            DimBuffer::Heap(values) => values,
+        }
+    }
+}
+
+impl<T: Scalar> DerefMut for DimBuffer<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            DimBuffer::Stack(values, len) => &mut values[..*len],
+            DimBuffer::Heap(values) => values,
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use num_traits::{Bounded, One, Zero};
+use ordered_float::NotNan;
+
+use crate::Scalar;
+
+/// Floor of the base-2 logarithm of a strictly positive, finite value, also for subnormals
+fn exponent<T: Scalar>(value: T) -> i32 {
+    let (mantissa, exponent, _) = value.integer_decode();
+    exponent as i32 + (63 - mantissa.leading_zeros() as i32)
+}
+
+/// Computes the power-of-two factor by which coordinate differences are multiplied before squaring
+///
+/// Squared distances between points of magnitude `max_abs` either overflow to infinity
+/// (e.g. around 1e20 in `f32`) or underflow to zero or subnormals (e.g. around 1e-40 in `f32`).
+/// In these cases, the returned scale brings `max_abs` close to one.
+/// As it is a power of two, scaling is exact and does not change the order of distances.
+/// For magnitudes that are safe to square, this returns one, so that no rounding differs from
+/// an unscaled computation.
+pub(crate) fn distance_scale<T: Scalar>(max_abs: NotNan<T>, dim: u32) -> NotNan<T> {
+    let one = NotNan::one();
+    let max_abs = max_abs.into_inner();
+    if max_abs == T::zero() || max_abs.is_infinite() {
+        return one;
+    }
+    let max_exponent = exponent(T::max_value());
+    let min_exponent = exponent(T::min_positive_value());
+    let mantissa_digits = -exponent(T::epsilon());
+    let dim_exponent = 32 - dim.leading_zeros() as i32;
+    let e = exponent(max_abs);
+    // the squared sum of `dim` differences of up to twice `max_abs` must not overflow
+    let too_large = 2 * (e + 2) + dim_exponent >= max_exponent;
+    // the square of the smallest difference representable around `max_abs` must be normal
+    let too_small = 2 * (e - mantissa_digits - 1) < min_exponent;
+    if !too_large && !too_small {
+        return one;
+    }
+    let two = T::one() + T::one();
+    let scale = two.powi((-e).clamp(min_exponent, max_exponent));
+    NotNan::new(scale).expect("a power of two is not NaN")
+}
+
+/// Returns the largest absolute value of flat coordinates, zero if there are none
+pub(crate) fn max_abs_coordinate<T: Scalar>(coordinates: &[NotNan<T>]) -> NotNan<T> {
+    coordinates
+        .iter()
+        .map(|value| core::cmp::max(-*value, *value))
+        .max()
+        .unwrap_or_else(NotNan::<T>::zero)
+}
+
+/// Removes the scale of an internal squared distance, saturating to `T::max_value()` on overflow
+pub(crate) fn unscale_dist2<T: Scalar>(dist2: NotNan<T>, scale: NotNan<T>) -> NotNan<T> {
+    let dist2 = (dist2 / scale) / scale;
+    saturate(dist2)
+}
+
+/// Saturates a squared distance that overflowed to infinity to `T::max_value()`
+///
+/// Collectors thus only receive finite squared distances, and a bound of `T::max_value()` still prunes.
+#[inline]
+pub(crate) fn saturate<T: Scalar>(dist2: NotNan<T>) -> NotNan<T> {
+    if dist2.is_infinite() {
+        NotNan::max_value()
+    } else {
+        dist2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scale_f32(max_abs: f32) -> f32 {
+        distance_scale(NotNan::new(max_abs).unwrap(), 3).into_inner()
+    }
+
+    #[test]
+    fn usual_magnitudes_are_not_scaled() {
+        for max_abs in [0.0, 1e-9, 1e-3, 1.0, 100.0, 1e6, 1e15] {
+            assert_eq!(scale_f32(max_abs), 1.0, "{max_abs}");
+        }
+        assert_eq!(distance_scale(NotNan::new(1e20f64).unwrap(), 3), 1.0);
+    }
+
+    #[test]
+    fn extreme_magnitudes_are_scaled_by_powers_of_two() {
+        for max_abs in [1e-40, 1e-30, 1e20, 3e38] {
+            let scale = scale_f32(max_abs);
+            assert_ne!(scale, 1.0);
+            assert_eq!(scale.to_bits() & 0x007f_ffff, 0, "{max_abs}");
+            let scaled = max_abs * scale;
+            let square = scaled * scaled;
+            assert!(square.is_normal() && square.is_finite(), "{max_abs}");
+        }
+    }
+
+    #[test]
+    fn saturate_distances() {
+        let inf = NotNan::new(f32::INFINITY).unwrap();
+        assert_eq!(saturate(inf).into_inner(), f32::MAX);
+        let one = NotNan::new(1.0f32).unwrap();
+        assert_eq!(
+            unscale_dist2(one, NotNan::new(0.5).unwrap()).into_inner(),
+            4.0
+        );
+    }
+}
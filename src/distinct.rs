@@ -0,0 +1,150 @@
+//! Nearest neighbours among distinct positions
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+
+use ordered_float::NotNan;
+
+use crate::{CandidateCollector, InternalNeighbour, KDTree, Neighbour, Parameters, Point, Scalar};
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Finds the `k` nearest neighbours of `query` with pairwise distinct coordinates,
+    /// using reasonable default parameters.
+    ///
+    /// Among points with equal coordinates, only one is returned.
+    pub fn knn_distinct(&self, k: u32, query: &P) -> Vec<Neighbour<T, P>> {
+        self.knn_distinct_advanced(k, query, &Parameters::default(), None)
+    }
+
+    /// Finds the `k` nearest neighbours of `query` with pairwise distinct coordinates,
+    /// with user-provided parameters.
+    ///
+    /// Coordinates are compared exactly, so `0.0` and `-0.0` are equal.
+    /// With heavily duplicated clouds, the search can touch many points before finding `k` distinct ones.
+    pub fn knn_distinct_advanced(
+        &self,
+        k: u32,
+        query: &P,
+        parameters: &Parameters<T>,
+        touch_statistics: Option<&mut u32>,
+    ) -> Vec<Neighbour<T, P>> {
+        let k = k.min(self.len() as u32);
+        let mut collector = DistinctCollector {
+            heap: BinaryHeap::with_capacity(k as usize),
+            k: k as usize,
+            points: &self.points,
+            dim: P::DIM as usize,
+        };
+        if k > 0 {
+            self.nn_raw(query, &mut collector, parameters, touch_statistics);
+        }
+        let neighbours = if parameters.sort_results {
+            collector.heap.into_sorted_vec()
+        } else {
+            collector.heap.into_vec()
+        };
+        neighbours
+            .into_iter()
+            .map(|n| self.externalise_neighbour(n))
+            .collect()
+    }
+}
+
+/// Keeps the `k` nearest candidates, rejecting the ones at the position of a kept one
+struct DistinctCollector<'a, T: Scalar> {
+    heap: BinaryHeap<InternalNeighbour<T>>,
+    k: usize,
+    /// points of the tree, by internal index
+    points: &'a [NotNan<T>],
+    dim: usize,
+}
+
+impl<'a, T: Scalar> DistinctCollector<'a, T> {
+    fn coordinates(&self, index: u32) -> &'a [NotNan<T>] {
+        let start = index as usize * self.dim;
+        &self.points[start..start + self.dim]
+    }
+}
+
+impl<'a, T: Scalar> CandidateCollector<T> for DistinctCollector<'a, T> {
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        if self
+            .furthest_dist2()
+            .map_or(false, |furthest| dist2 >= furthest)
+        {
+            return;
+        }
+        // equal positions have equal distances, only compare with those
+        let coordinates = self.coordinates(index);
+        if self
+            .heap
+            .iter()
+            .any(|kept| kept.dist2 == dist2 && self.coordinates(kept.index) == coordinates)
+        {
+            return;
+        }
+        if self.heap.len() == self.k {
+            self.heap.pop();
+        }
+        self.heap.push(InternalNeighbour { index, dist2 });
+    }
+
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        if self.heap.len() < self.k {
+            None
+        } else {
+            self.heap.peek().map(|n| n.dist2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn knn_distinct_skips_duplicates() {
+        // 90% of the points are copies of 10 positions
+        let mut cloud: Vec<P2> = random_point_cloud(100);
+        let positions: Vec<P2> = random_point_cloud(10);
+        for i in 0..900 {
+            cloud.push(positions[i % 10]);
+        }
+        let tree = KDTree::new(&cloud);
+        let mut distinct: Vec<P2> = Vec::new();
+        for point in &cloud {
+            if !distinct.contains(point) {
+                distinct.push(*point);
+            }
+        }
+        for _ in 0..30 {
+            let query = random_point();
+            let neighbours = tree.knn_distinct(12, &query);
+            let mut brute_force: Vec<_> = distinct
+                .iter()
+                .map(|point| {
+                    let dx = *point.get(0) - *query.get(0);
+                    let dy = *point.get(1) - *query.get(1);
+                    (dx * dx + dy * dy, *point)
+                })
+                .collect();
+            brute_force.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+            assert_eq!(neighbours.len(), 12);
+            for (neighbour, (dist2, point)) in neighbours.iter().zip(brute_force) {
+                assert_eq!(neighbour.dist2, dist2);
+                assert_eq!(neighbour.point, point);
+                assert_eq!(cloud[neighbour.index as usize], point);
+            }
+        }
+    }
+
+    #[test]
+    fn knn_distinct_of_a_single_position() {
+        let cloud = vec![P2::new2d(1.0, 2.0); 50];
+        let tree = KDTree::new(&cloud);
+        let neighbours = tree.knn_distinct(5, &P2::new2d(0.0, 0.0));
+        assert_eq!(neighbours.len(), 1);
+        assert_eq!(neighbours[0].point, cloud[0]);
+        assert!(tree.knn_distinct(0, &P2::new2d(0.0, 0.0)).is_empty());
+    }
+}
@@ -0,0 +1,263 @@
+//! Text dumps of the structure of a tree, for debugging
+
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use crate::{BitSet, KDTree, Point, Scalar};
+
+/// Options for [`KDTree::dump_dot`] and [`KDTree::dump_json`]
+#[derive(Clone, Copy, Debug)]
+pub struct DumpOptions<'a> {
+    /// nodes deeper than this are not dumped, their parent being shown as a truncated subtree
+    pub max_depth: u32,
+    /// nodes to highlight, by node id
+    pub highlighted: Option<&'a BitSet>,
+}
+
+impl<'a> Default for DumpOptions<'a> {
+    /// The whole tree, without highlighted nodes.
+    fn default() -> Self {
+        DumpOptions {
+            max_depth: u32::MAX,
+            highlighted: None,
+        }
+    }
+}
+
+/// The content of a node, for dumping
+enum NodeKind<T> {
+    Split {
+        split_dim: u32,
+        split_val: T,
+        right_child: usize,
+    },
+    Leaf,
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Returns the number of nodes, split and leaf, of the tree.
+    ///
+    /// Node ids, as used by [`DumpOptions::highlighted`], are in `0..node_count()`;
+    /// the root has id 0 and the nodes are numbered in depth-first order.
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Writes the structure of the tree to `w` in the Graphviz dot format.
+    ///
+    /// Each split node is labeled by its split dimension, split value, and number of points in its subtree,
+    /// and each leaf by its number of points.
+    /// The output only depends on the tree and `options`, so it can be compared to a reference.
+    /// If the tree is rotated, the split values are in its frame, see [`Self::rotation`].
+    pub fn dump_dot<W: Write>(&self, w: &mut W, options: &DumpOptions) -> fmt::Result {
+        let counts = self.subtree_counts();
+        writeln!(w, "digraph kdtree {{")?;
+        writeln!(w, "  node [shape=box];")?;
+        self.dump_dot_node(w, options, &counts, 0, 0)?;
+        writeln!(w, "}}")
+    }
+
+    fn dump_dot_node<W: Write>(
+        &self,
+        w: &mut W,
+        options: &DumpOptions,
+        counts: &[u32],
+        node: usize,
+        depth: u32,
+    ) -> fmt::Result {
+        let style = if is_highlighted(options, node) {
+            ", style=filled, fillcolor=orange"
+        } else {
+            ""
+        };
+        match self.node_kind(node) {
+            NodeKind::Split { .. } if depth >= options.max_depth => writeln!(
+                w,
+                "  n{} [label=\"subtree\\n{} points\", style=dashed{}];",
+                node, counts[node], style
+            ),
+            NodeKind::Split {
+                split_dim,
+                split_val,
+                right_child,
+            } => {
+                writeln!(
+                    w,
+                    "  n{} [label=\"dim {} <= {:?}\\n{} points\"{}];",
+                    node, split_dim, split_val, counts[node], style
+                )?;
+                writeln!(w, "  n{} -> n{} [label=\"<=\"];", node, node + 1)?;
+                writeln!(w, "  n{} -> n{} [label=\">\"];", node, right_child)?;
+                self.dump_dot_node(w, options, counts, node + 1, depth + 1)?;
+                self.dump_dot_node(w, options, counts, right_child, depth + 1)
+            }
+            NodeKind::Leaf => writeln!(
+                w,
+                "  n{} [label=\"leaf\\n{} points\"{}];",
+                node, counts[node], style
+            ),
+        }
+    }
+
+    /// Writes the structure of the tree to `w` as JSON, with the same data as [`Self::dump_dot`].
+    ///
+    /// Each node is an object with its `id`, its number of points `count`, and whether it is `highlighted`.
+    /// Split nodes also have `split_dim`, `split_val`, `left` and `right`,
+    /// or `truncated` set to true if they are at `options.max_depth`.
+    pub fn dump_json<W: Write>(&self, w: &mut W, options: &DumpOptions) -> fmt::Result {
+        let counts = self.subtree_counts();
+        self.dump_json_node(w, options, &counts, 0, 0)
+    }
+
+    fn dump_json_node<W: Write>(
+        &self,
+        w: &mut W,
+        options: &DumpOptions,
+        counts: &[u32],
+        node: usize,
+        depth: u32,
+    ) -> fmt::Result {
+        write!(
+            w,
+            "{{\"id\":{},\"count\":{},\"highlighted\":{}",
+            node,
+            counts[node],
+            is_highlighted(options, node)
+        )?;
+        match self.node_kind(node) {
+            NodeKind::Split { .. } if depth >= options.max_depth => {
+                write!(w, ",\"truncated\":true")?;
+            }
+            NodeKind::Split {
+                split_dim,
+                split_val,
+                right_child,
+            } => {
+                write!(
+                    w,
+                    ",\"split_dim\":{},\"split_val\":{:?},\"left\":",
+                    split_dim, split_val
+                )?;
+                self.dump_json_node(w, options, counts, node + 1, depth + 1)?;
+                write!(w, ",\"right\":")?;
+                self.dump_json_node(w, options, counts, right_child, depth + 1)?;
+            }
+            NodeKind::Leaf => {}
+        }
+        write!(w, "}}")
+    }
+
+    fn node_kind(&self, node: usize) -> NodeKind<T> {
+        self.nodes[node].dispatch_on_type(
+            (),
+            |_, split_dim, split_val, right_child| NodeKind::Split {
+                split_dim,
+                split_val: split_val.into_inner(),
+                right_child: right_child as usize,
+            },
+            |_, _, _| NodeKind::Leaf,
+        )
+    }
+
+    /// Returns the number of points in the subtree of each node
+    fn subtree_counts(&self) -> Vec<u32> {
+        let mut counts = alloc::vec![0; self.nodes.len()];
+        // children have larger ids than their parent
+        for node in (0..self.nodes.len()).rev() {
+            counts[node] = self.nodes[node].dispatch_on_type(
+                &counts,
+                |counts, _, _, right_child| counts[node + 1] + counts[right_child as usize],
+                |_, _, bucket_size| bucket_size,
+            );
+        }
+        counts
+    }
+}
+
+fn is_highlighted(options: &DumpOptions, node: usize) -> bool {
+    options
+        .highlighted
+        .map_or(false, |highlighted| highlighted.contains(node))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn line_tree() -> KDTree<f32, P2> {
+        let cloud: Vec<P2> = (0..6).map(|i| P2::new2d(i as f32, 0.0)).collect();
+        KDTree::new_with_bucket_size(&cloud, 2)
+    }
+
+    #[test]
+    fn dump_dot_is_deterministic() {
+        let tree = line_tree();
+        let mut highlighted = BitSet::with_len(tree.node_count());
+        highlighted.insert(0);
+        let options = DumpOptions {
+            highlighted: Some(&highlighted),
+            ..DumpOptions::default()
+        };
+        let mut dot = String::new();
+        tree.dump_dot(&mut dot, &options).unwrap();
+        assert_eq!(
+            dot,
+            r#"digraph kdtree {
+  node [shape=box];
+  n0 [label="dim 0 <= 2.5\n6 points", style=filled, fillcolor=orange];
+  n0 -> n1 [label="<="];
+  n0 -> n4 [label=">"];
+  n1 [label="dim 0 <= 1.0\n3 points"];
+  n1 -> n2 [label="<="];
+  n1 -> n3 [label=">"];
+  n2 [label="leaf\n2 points"];
+  n3 [label="leaf\n1 points"];
+  n4 [label="dim 0 <= 4.0\n3 points"];
+  n4 -> n5 [label="<="];
+  n4 -> n6 [label=">"];
+  n5 [label="leaf\n2 points"];
+  n6 [label="leaf\n1 points"];
+}
+"#
+        );
+        let mut truncated = String::new();
+        let options = DumpOptions {
+            max_depth: 0,
+            ..DumpOptions::default()
+        };
+        tree.dump_dot(&mut truncated, &options).unwrap();
+        assert_eq!(
+            truncated,
+            r#"digraph kdtree {
+  node [shape=box];
+  n0 [label="subtree\n6 points", style=dashed];
+}
+"#
+        );
+    }
+
+    #[test]
+    fn dump_json_is_deterministic() {
+        let tree = line_tree();
+        let mut json = String::new();
+        tree.dump_json(&mut json, &DumpOptions::default()).unwrap();
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"id":0,"count":6,"highlighted":false,"split_dim":0,"split_val":2.5,"left":"#,
+                r#"{"id":1,"count":3,"highlighted":false,"split_dim":0,"split_val":1.0,"#,
+                r#""left":{"id":2,"count":2,"highlighted":false},"#,
+                r#""right":{"id":3,"count":1,"highlighted":false}},"right":"#,
+                r#"{"id":4,"count":3,"highlighted":false,"split_dim":0,"split_val":4.0,"#,
+                r#""left":{"id":5,"count":2,"highlighted":false},"#,
+                r#""right":{"id":6,"count":1,"highlighted":false}}}"#
+            )
+        );
+        let empty = KDTree::<f32, P2>::new(&[]);
+        let mut json = String::new();
+        empty.dump_json(&mut json, &DumpOptions::default()).unwrap();
+        // an empty tree is a single empty leaf
+        assert_eq!(json, r#"{"id":0,"count":0,"highlighted":false}"#);
+    }
+}
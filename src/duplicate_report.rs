@@ -0,0 +1,177 @@
+//! Reports of the points sharing their exact position, as a diagnostic of the quality of a point cloud
+
+use alloc::{vec, vec::Vec};
+
+use ordered_float::NotNan;
+
+use crate::internal_parameters::InternalParameters;
+use crate::{Boundary, CandidateCollector, KDTree, Parameters, Point, Scalar, SelfMatch};
+
+/// The exact duplicates of the points of a tree, see [`KDTree::duplicate_report`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DuplicateReport {
+    /// number of distinct positions, equal to the number of points if there is no duplicate
+    pub distinct_positions: usize,
+    /// for each size of group of points at the same position, from 2, the number of groups of that size,
+    /// by increasing size
+    pub group_sizes: Vec<(usize, usize)>,
+    /// the indices in the original point cloud of the points of the largest group, by increasing index,
+    /// the group of smallest first index among the largest, and empty if there is no duplicate
+    pub largest_group: Vec<u32>,
+}
+
+/// Collects the internal indices of the points at exactly the position of a point
+struct EqualPoints<'a, T: Scalar> {
+    /// points of the tree, by internal index
+    points: &'a [NotNan<T>],
+    position: &'a [NotNan<T>],
+    found: Vec<u32>,
+}
+
+impl<'a, T: Scalar> CandidateCollector<T> for EqualPoints<'a, T> {
+    fn add(&mut self, _dist2: NotNan<T>, index: u32) {
+        let dim = self.position.len();
+        let start = index as usize * dim;
+        // distinct points can have a zero squared distance when it underflows
+        if self.points[start..start + dim] == *self.position {
+            self.found.push(index);
+        }
+    }
+
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        None
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Returns the number of distinct positions of the points, the histogram of the sizes of the groups
+    /// of points at the same position and the largest group.
+    ///
+    /// Coordinates are compared exactly, so `0.0` and `-0.0` are equal, as in [`Self::knn_distinct`].
+    /// The points are visited leaf by leaf, and the first point of each new position searches the others
+    /// at its position with a search of zero radius, which also finds the duplicates in other leaves:
+    /// duplicates on the plane of a random split, see [`BuildOptions::seed`](crate::BuildOptions::seed),
+    /// or moved by [`Self::update_point`] to the position of a point of another leaf.
+    /// This costs one search per distinct position, about the cost of [`Self::knn`] with `k = 1` for each point.
+    pub fn duplicate_report(&self) -> DuplicateReport {
+        let parameters = Parameters {
+            max_radius: T::zero(),
+            boundary: Boundary::Closed,
+            self_match: SelfMatch::Allow,
+            ..Parameters::default()
+        };
+        let internal_parameters = InternalParameters::new(&parameters, self.scale);
+        let mut grouped = vec![false; self.len()];
+        let mut sizes = Vec::new();
+        let mut largest: Vec<u32> = Vec::new();
+        let mut collector = EqualPoints {
+            points: &self.points,
+            position: &[],
+            found: Vec::new(),
+        };
+        for internal_index in 0..self.len() as u32 {
+            if grouped[internal_index as usize] {
+                continue;
+            }
+            let position = self.stored_coordinates(internal_index);
+            collector.position = position;
+            collector.found.clear();
+            self.knn_internal(&mut collector, position, &internal_parameters);
+            for index in &collector.found {
+                grouped[*index as usize] = true;
+            }
+            let size = collector.found.len();
+            sizes.push(size);
+            if size > 1 {
+                let mut group: Vec<u32> = collector
+                    .found
+                    .iter()
+                    .map(|index| self.indices[*index as usize])
+                    .collect();
+                group.sort_unstable();
+                if (size, core::cmp::Reverse(group[0]))
+                    > (
+                        largest.len(),
+                        core::cmp::Reverse(largest.first().copied().unwrap_or(0)),
+                    )
+                {
+                    largest = group;
+                }
+            }
+        }
+        let distinct_positions = sizes.len();
+        sizes.retain(|size| *size > 1);
+        sizes.sort_unstable();
+        let mut group_sizes: Vec<(usize, usize)> = Vec::new();
+        for size in sizes {
+            match group_sizes.last_mut() {
+                Some((last, count)) if *last == size => *count += 1,
+                _ => group_sizes.push((size, 1)),
+            }
+        }
+        DuplicateReport {
+            distinct_positions,
+            group_sizes,
+            largest_group: largest,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn duplicates_are_grouped_across_leaves() {
+        // a grid of 100 positions, each repeated by its index modulo 4 plus one
+        let mut cloud: Vec<P2> = Vec::new();
+        for position in 0..100 {
+            for _ in 0..position % 4 + 1 {
+                cloud.push(P2::new2d((position % 10) as f32, (position / 10) as f32));
+            }
+        }
+        let options = BuildOptions {
+            bucket_size: 2,
+            ..BuildOptions::default()
+        };
+        let mut tree = KDTree::new_with_options(&cloud, &options);
+        let report = tree.duplicate_report();
+        assert_eq!(report.distinct_positions, 100);
+        assert_eq!(report.group_sizes, vec![(2, 25), (3, 25), (4, 25)]);
+        assert_eq!(report.largest_group, vec![6, 7, 8, 9]);
+        // moving points onto the positions of points of other leaves
+        let far = cloud.len() as u32 - 1;
+        tree.update_point(0, cloud[far as usize]);
+        tree.update_point(1, cloud[far as usize]);
+        let report = tree.duplicate_report();
+        assert_eq!(report.distinct_positions, 99);
+        assert_eq!(report.group_sizes, vec![(2, 24), (3, 25), (4, 24), (6, 1)]);
+        assert_eq!(
+            report.largest_group,
+            vec![0, 1, far - 3, far - 2, far - 1, far]
+        );
+    }
+
+    #[test]
+    fn trees_without_duplicates_have_no_groups() {
+        let cloud: Vec<P3> = random_point_cloud(1000);
+        let report = KDTree::new(&cloud).duplicate_report();
+        assert_eq!(report.distinct_positions, 1000);
+        assert!(report.group_sizes.is_empty());
+        assert!(report.largest_group.is_empty());
+        // distinct coordinates whose squared distance underflows
+        let tiny = vec![
+            P2::new2d(0.0, 0.0),
+            P2::new2d(1e-30, 0.0),
+            P2::new2d(-0.0, 0.0),
+        ];
+        let report = KDTree::new(&tiny).duplicate_report();
+        assert_eq!(report.distinct_positions, 2);
+        assert_eq!(report.largest_group, vec![0, 2]);
+        assert_eq!(
+            KDTree::<f32, P2>::new(&[]).duplicate_report(),
+            DuplicateReport::default()
+        );
+    }
+}
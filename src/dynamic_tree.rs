@@ -0,0 +1,506 @@
+//! Dynamic index supporting insertion, built from a logarithmic collection of static trees
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+
+use num_traits::{One, Zero};
+use ordered_float::NotNan;
+
+use crate::dim_buffer::DimBuffer;
+use crate::distance_scale::{distance_scale, max_abs_coordinate, unscale_dist2};
+use crate::heap::{CandidateHeap, ExcludingCollector};
+use crate::internal_parameters::InternalParameters;
+use crate::{
+    point_slice_dist2, BuildError, CandidateCollector, CandidateContainer, InternalNeighbour,
+    KDTree, Neighbour, Parameters, Point, Scalar,
+};
+
+/// Component of `DynamicKDTree::locations` designating the buffer
+const BUFFER: u32 = u32::MAX;
+
+/// A static tree of a [`DynamicKDTree`], with the index of its points in the dynamic tree
+struct Component<T: Scalar, P: Point<T>> {
+    tree: KDTree<T, P>,
+    /// global index of each internal index of `tree`
+    indices: Vec<u32>,
+}
+
+/// A point index supporting insertion
+///
+/// Inserted points go to a small buffer, searched by brute force.
+/// When the buffer is full, it is merged with the smallest static [`KDTree`]s into a new one,
+/// so that the tree of level `i` holds at most `buffer_size * 2^i` points,
+/// which is the classic static-to-dynamic transformation.
+/// Insertion thus takes amortised time O(log² n), and queries search the buffer and
+/// the O(log n) trees with a single candidate collector, returning the same points as a [`KDTree`]
+/// built on all points.
+/// Points keep the index of their insertion across merges.
+pub struct DynamicKDTree<T: Scalar, P: Point<T>> {
+    /// maximum number of points in the buffer
+    buffer_size: u32,
+    /// coordinates of the points not yet in a tree, which have the last indices
+    buffer: Vec<NotNan<T>>,
+    /// the tree of each level, if any
+    levels: Vec<Option<Component<T, P>>>,
+    /// component and internal index of each point, the component being a level or `BUFFER`
+    locations: Vec<(u32, u32)>,
+    /// identifier of each point, empty until a point is inserted with an identifier
+    ids: Vec<u64>,
+}
+
+impl<T: Scalar, P: Point<T>> Default for DynamicKDTree<T, P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Scalar, P: Point<T>> DynamicKDTree<T, P> {
+    /// Creates an empty dynamic tree, with a buffer of 64 points.
+    pub fn new() -> Self {
+        Self::with_buffer_size(64)
+    }
+
+    /// Creates an empty dynamic tree, with a buffer of `buffer_size` points.
+    ///
+    /// A larger buffer makes insertion faster, but queries slower.
+    /// Panics if `buffer_size` is zero.
+    pub fn with_buffer_size(buffer_size: u32) -> Self {
+        assert!(buffer_size > 0, "The buffer size must not be zero");
+        DynamicKDTree {
+            buffer_size,
+            buffer: Vec::with_capacity(buffer_size as usize * P::DIM as usize),
+            levels: Vec::new(),
+            locations: Vec::new(),
+            ids: Vec::new(),
+        }
+    }
+
+    /// Returns the number of points.
+    pub fn len(&self) -> usize {
+        self.locations.len()
+    }
+
+    /// Returns whether there are no points.
+    pub fn is_empty(&self) -> bool {
+        self.locations.is_empty()
+    }
+
+    /// Returns the number of static trees currently holding points.
+    pub fn tree_count(&self) -> usize {
+        self.levels.iter().flatten().count()
+    }
+
+    /// Inserts `point` and returns its index, which is the number of points inserted before it.
+    ///
+    /// Panics if `point` has an infinite coordinate, or if there would be more than `u32::MAX` points.
+    pub fn push(&mut self, point: P) -> u32 {
+        self.push_coordinates(&point, None)
+    }
+
+    /// Inserts `point` with identifier `id` and returns its index, see [`Self::push`].
+    ///
+    /// The identifier needs not be unique.
+    pub fn push_with_id(&mut self, point: P, id: u64) -> u32 {
+        self.push_coordinates(&point, Some(id))
+    }
+
+    /// Returns the identifier of the point of index `index`,
+    /// which is the index itself if the point was inserted without identifier.
+    ///
+    /// Panics if `index` is not smaller than [`Self::len`].
+    pub fn id_of(&self, index: u32) -> u64 {
+        assert!((index as usize) < self.len(), "index out of range");
+        self.ids
+            .get(index as usize)
+            .copied()
+            .unwrap_or(index as u64)
+    }
+
+    /// Inserts all points of `cloud`, see [`Self::push`].
+    pub fn extend(&mut self, cloud: &[P]) {
+        for point in cloud {
+            self.push_coordinates(point, None);
+        }
+    }
+
+    /// Merges all points into a single static tree, making queries as fast as on a [`KDTree`].
+    pub fn compact(&mut self) {
+        let (cloud, indices) = self.take_levels(self.levels.len());
+        self.levels.clear();
+        if !cloud.is_empty() {
+            let level = (0..)
+                .find(|level| cloud.len() <= self.capacity(*level))
+                .expect("a level fits all points");
+            self.place(level, &cloud, indices);
+        }
+    }
+
+    /// Returns the point of index `index`.
+    ///
+    /// Panics if `index` is not smaller than [`Self::len`].
+    pub fn point(&self, index: u32) -> P {
+        let (component, internal) = self.locations[index as usize];
+        if component == BUFFER {
+            let start = internal as usize * P::DIM as usize;
+            P::from_slice(&self.buffer[start..start + P::DIM as usize])
+        } else {
+            self.levels[component as usize]
+                .as_ref()
+                .expect("locations point to existing levels")
+                .tree
+                .stored_point(internal)
+        }
+    }
+
+    /// Finds the `k` nearest neighbour of `query`, using reasonable default parameters, see [`KDTree::knn`].
+    pub fn knn(&self, k: u32, query: &P) -> Vec<Neighbour<T, P>> {
+        self.knn_advanced(
+            k,
+            query,
+            KDTree::<T, P>::default_candidate_container(k),
+            &Parameters::default(),
+            None,
+        )
+    }
+
+    /// Finds the `k` nearest neighbour of `query`, with user-provided parameters, see [`KDTree::knn_advanced`].
+    ///
+    /// The touch statistics sum the points touched in all trees and the points in the buffer.
+    pub fn knn_advanced(
+        &self,
+        k: u32,
+        query: &P,
+        candidate_container: CandidateContainer,
+        parameters: &Parameters<T>,
+        touch_statistics: Option<&mut u32>,
+    ) -> Vec<Neighbour<T, P>> {
+        (match candidate_container {
+            CandidateContainer::Linear => Self::knn_generic_heap::<Vec<InternalNeighbour<T>>>,
+            CandidateContainer::BinaryHeap => {
+                Self::knn_generic_heap::<BinaryHeap<InternalNeighbour<T>>>
+            }
+        })(self, k, query, parameters, touch_statistics)
+    }
+
+    fn knn_generic_heap<H: CandidateHeap<T> + CandidateCollector<T>>(
+        &self,
+        k: u32,
+        query: &P,
+        parameters: &Parameters<T>,
+        touch_statistics: Option<&mut u32>,
+    ) -> Vec<Neighbour<T, P>> {
+        let k = k.min(self.len() as u32);
+        let mut heap = H::new_with_k(k);
+        let mut query_coordinates = DimBuffer::new(P::DIM as usize, NotNan::<T>::zero());
+        for (i, value) in query_coordinates.iter_mut().enumerate() {
+            *value = query.get(i as u32);
+        }
+        let excluded = InternalParameters::new(parameters, NotNan::one()).excluded;
+        // the candidates of all components are compared at the scale of a tree of all points
+        let max_abs = self
+            .levels
+            .iter()
+            .flatten()
+            .map(|component| component.tree.max_abs_coordinate())
+            .fold(max_abs_coordinate(&self.buffer), NotNan::max);
+        let scale = distance_scale(max_abs, P::DIM);
+        let mut touched_points = self.buffer_len() as u32;
+        // the trees are searched from the largest, which provides the best candidates
+        for component in self.levels.iter().rev().flatten() {
+            // the excluded index is global, so it is skipped after translating the indices of the component
+            let mut excluding = ExcludingCollector {
+                inner: &mut heap,
+                excluded,
+            };
+            let mut collector = ComponentCollector {
+                heap: &mut excluding,
+                indices: &component.indices,
+                scale: component.tree.scale / scale,
+            };
+            let stats = component.tree.knn_internal(
+                &mut collector,
+                &query_coordinates,
+                &InternalParameters::new(parameters, component.tree.scale).without_excluded(),
+            );
+            touched_points += stats.touched_points;
+        }
+        let buffer_parameters = InternalParameters::new(parameters, scale);
+        let first_buffer_index = self.len() - self.buffer_len();
+        for (position, point) in self.buffer.chunks(P::DIM as usize).enumerate() {
+            let index = (first_buffer_index + position) as u32;
+            let dist2 = point_slice_dist2::<T, P>(&query_coordinates, point, scale);
+            if buffer_parameters.accepts(dist2, index) {
+                heap.add(dist2, index);
+            }
+        }
+        if let Some(touch_statistics) = touch_statistics {
+            *touch_statistics = touched_points;
+        }
+        if parameters.sort_results {
+            heap.into_sorted_vec()
+        } else {
+            heap.into_vec()
+        }
+        .into_iter()
+        .map(|neighbour| Neighbour {
+            point: self.point(neighbour.index),
+            dist2: unscale_dist2(neighbour.dist2, scale),
+            index: neighbour.index,
+        })
+        .collect()
+    }
+
+    /// Copies the coordinates of `point` to the buffer with its identifier, if any, merging it into a tree if it is full
+    fn push_coordinates(&mut self, point: &P, id: Option<u64>) -> u32 {
+        let index = self.len() as u32;
+        if (0..P::DIM).any(|i| !point.get(i).is_finite()) {
+            panic!("{}", BuildError::NonFiniteCoordinate { index });
+        }
+        assert!(
+            index < u32::MAX,
+            "{}",
+            BuildError::TooManyPoints(self.len() + 1)
+        );
+        match id {
+            Some(id) => {
+                if self.ids.is_empty() {
+                    self.ids.extend(0..index as u64);
+                }
+                self.ids.push(id);
+            }
+            None if !self.ids.is_empty() => self.ids.push(index as u64),
+            None => {}
+        }
+        self.locations.push((BUFFER, self.buffer_len() as u32));
+        self.buffer.extend((0..P::DIM).map(|i| point.get(i)));
+        if self.buffer_len() == self.buffer_size as usize {
+            self.flush();
+        }
+        index
+    }
+
+    /// Returns the number of points in the buffer
+    fn buffer_len(&self) -> usize {
+        self.buffer.len() / P::DIM as usize
+    }
+
+    /// Returns the maximum number of points in the tree of `level`
+    fn capacity(&self, level: usize) -> usize {
+        (self.buffer_size as usize).saturating_mul(1 << level.min(usize::BITS as usize - 1))
+    }
+
+    /// Merges the buffer with the trees of the smallest levels into the tree of the first level that can hold them
+    fn flush(&mut self) {
+        let mut level = 0;
+        while level < self.levels.len() && self.levels[level].is_some() {
+            level += 1;
+        }
+        let (cloud, indices) = self.take_levels(level);
+        // the merged points might not fit the first free level, for example after `compact`
+        let level = (level..)
+            .find(|level| {
+                cloud.len() <= self.capacity(*level)
+                    && self.levels.get(*level).map_or(true, Option::is_none)
+            })
+            .expect("a level fits all points");
+        self.place(level, &cloud, indices);
+    }
+
+    /// Removes the buffer and the trees of the levels below `level_count`, returning their points and indices
+    fn take_levels(&mut self, level_count: usize) -> (Vec<P>, Vec<u32>) {
+        let mut cloud = Vec::new();
+        let mut indices = Vec::new();
+        for component in self.levels.iter_mut().take(level_count) {
+            if let Some(component) = component.take() {
+                cloud.extend(component.tree.iter_points());
+                indices.extend(component.indices);
+            }
+        }
+        let first_buffer_index = self.len() - self.buffer_len();
+        cloud.extend(self.buffer.chunks(P::DIM as usize).map(P::from_slice));
+        indices.extend(first_buffer_index as u32..self.len() as u32);
+        self.buffer.clear();
+        (cloud, indices)
+    }
+
+    /// Builds the tree of `level` from `cloud`, `indices` being the global index of each point of `cloud`
+    fn place(&mut self, level: usize, cloud: &[P], indices: Vec<u32>) {
+        let tree = KDTree::new(cloud);
+        let indices: Vec<u32> = tree
+            .externalisation_map()
+            .iter()
+            .map(|local| indices[*local as usize])
+            .collect();
+        for (internal, index) in indices.iter().enumerate() {
+            self.locations[*index as usize] = (level as u32, internal as u32);
+        }
+        if self.levels.len() <= level {
+            self.levels.resize_with(level + 1, || None);
+        }
+        self.levels[level] = Some(Component { tree, indices });
+    }
+}
+
+/// Passes the candidates of a component tree to a collector shared with the other components,
+/// with the indices and squared distances of the dynamic tree
+pub(crate) struct ComponentCollector<'a, T: Scalar, H: CandidateCollector<T>> {
+    pub(crate) heap: &'a mut H,
+    /// global index of each internal index of the component tree
+    pub(crate) indices: &'a [u32],
+    /// ratio of the distance scale of the component tree to that of the shared collector,
+    /// a power of two of at least one, as the shared scale is that of all points
+    pub(crate) scale: NotNan<T>,
+}
+
+impl<'a, T: Scalar, H: CandidateCollector<T>> CandidateCollector<T>
+    for ComponentCollector<'a, T, H>
+{
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        self.heap.add(
+            unscale_dist2(dist2, self.scale),
+            self.indices[index as usize],
+        );
+    }
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        let furthest_dist2 = self.heap.furthest_dist2();
+        if self.scale == T::one() {
+            furthest_dist2
+        } else if self.scale.is_infinite() {
+            // the ratio overflowed, so the bound is out of the range of the component
+            None
+        } else {
+            furthest_dist2.map(|furthest_dist2| furthest_dist2 * self.scale * self.scale)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn assert_same_knn(dynamic: &DynamicKDTree<f32, P2>, cloud: &[P2]) {
+        let reference = KDTree::new(cloud);
+        for k in [1, 5, 40] {
+            for _ in 0..20 {
+                let query = random_point();
+                assert_eq!(dynamic.knn(k, &query), reference.knn(k, &query));
+            }
+        }
+    }
+
+    #[test]
+    fn dynamic_tree_matches_static_tree() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let mut dynamic = DynamicKDTree::with_buffer_size(16);
+        assert!(dynamic.knn(3, &cloud[0]).is_empty());
+        for (index, point) in cloud.iter().enumerate() {
+            assert_eq!(dynamic.push(*point), index as u32);
+            if index % 97 == 0 {
+                assert_same_knn(&dynamic, &cloud[..=index]);
+            }
+        }
+        assert_eq!(dynamic.len(), 1000);
+        assert!(dynamic.tree_count() <= 7);
+        assert_same_knn(&dynamic, &cloud);
+        for (index, point) in cloud.iter().enumerate() {
+            assert_eq!(dynamic.point(index as u32), *point);
+        }
+    }
+
+    #[test]
+    fn dynamic_tree_compact() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let mut dynamic = DynamicKDTree::new();
+        dynamic.extend(&cloud[..700]);
+        dynamic.compact();
+        assert_eq!(dynamic.tree_count(), 1);
+        assert_same_knn(&dynamic, &cloud[..700]);
+        dynamic.extend(&cloud[700..]);
+        assert_same_knn(&dynamic, &cloud);
+        dynamic.compact();
+        assert_eq!(dynamic.tree_count(), 1);
+        assert_same_knn(&dynamic, &cloud);
+        dynamic.compact();
+        assert_eq!(dynamic.len(), 1000);
+    }
+
+    #[test]
+    fn dynamic_tree_buffer_is_scaled_like_the_components() {
+        // squared distances between these points overflow `f32` unless scaled, and then saturate
+        let cloud: Vec<P2> = (0..40)
+            .map(|i| P2::new2d(i as f32 * 1e19, (i % 3) as f32 * 1e19))
+            .collect();
+        let mut dynamic = DynamicKDTree::with_buffer_size(64);
+        dynamic.extend(&cloud);
+        assert_eq!(dynamic.buffer_len(), 40);
+        let reference = KDTree::new(&cloud);
+        let dist2s = |neighbours: Vec<Neighbour<f32, P2>>| -> Vec<NotNan<f32>> {
+            neighbours.iter().map(|neighbour| neighbour.dist2).collect()
+        };
+        for query in &cloud {
+            let dist2 = dist2s(dynamic.knn(5, query));
+            assert_eq!(dist2, dist2s(reference.knn(5, query)));
+            assert!(dist2.iter().all(|dist2| dist2.is_finite()));
+        }
+    }
+
+    #[test]
+    fn dynamic_tree_compares_components_of_different_scales() {
+        // the unscaled squared distances of the first points underflow, unlike those at the common scale
+        let cloud: Vec<P2> = random_point_cloud(120)
+            .into_iter()
+            .enumerate()
+            .map(|(index, point): (usize, P2)| {
+                point
+                    * if index < 64 {
+                        2f32.powi(-100)
+                    } else {
+                        2f32.powi(-90)
+                    }
+            })
+            .collect();
+        let mut dynamic = DynamicKDTree::with_buffer_size(16);
+        dynamic.extend(&cloud);
+        assert_eq!(dynamic.tree_count(), 3);
+        assert_eq!(dynamic.buffer_len(), 8);
+        let reference = KDTree::new(&cloud);
+        let queries: Vec<P2> = random_point_cloud(20);
+        for query in &queries {
+            for factor in [2f32.powi(-100), 2f32.powi(-90)] {
+                let query = *query * factor;
+                assert_eq!(dynamic.knn(5, &query), reference.knn(5, &query));
+            }
+        }
+    }
+
+    #[test]
+    fn dynamic_tree_respects_parameters() {
+        let cloud: Vec<P2> = random_point_cloud(300);
+        let mut dynamic = DynamicKDTree::with_buffer_size(32);
+        dynamic.extend(&cloud);
+        let reference = KDTree::new(&cloud);
+        let parameters = Parameters {
+            epsilon: 0.0,
+            max_radius: 10.0,
+            boundary: Boundary::Closed,
+            self_match: SelfMatch::ExcludeCoincident,
+            sort_results: true,
+            traversal_order: TraversalOrder::NearChildFirst,
+            on_insufficient: InsufficientPolicy::Truncate,
+        };
+        for query in &cloud[..50] {
+            let mut touched = 0;
+            let neighbours = dynamic.knn_advanced(
+                4,
+                query,
+                CandidateContainer::BinaryHeap,
+                &parameters,
+                Some(&mut touched),
+            );
+            let expected =
+                reference.knn_advanced(4, query, CandidateContainer::Linear, &parameters, None);
+            assert_eq!(neighbours, expected);
+            assert!(touched > 0);
+        }
+    }
+}
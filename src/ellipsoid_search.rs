@@ -0,0 +1,294 @@
+//! Searches of the points inside an ellipsoid given by an inverse covariance, such as an uncertainty region
+
+use alloc::{vec, vec::Vec};
+
+use num_traits::Float;
+use ordered_float::NotNan;
+
+use crate::internal_parameters::InternalParameters;
+use crate::{point_slice_dist2, Error, KDTree, Neighbour, Parameters, Point, QueryError, Scalar};
+
+/// The ellipsoid of the points `p` such that `(p - c)ᵀ A (p - c) <= 1`, in the frame of a tree
+struct Ellipsoid<T> {
+    dim: usize,
+    /// lower factor of the Cholesky decomposition of `A`, row-major
+    cholesky: Vec<T>,
+}
+
+impl<T: Scalar + Float> Ellipsoid<T> {
+    /// Returns the ellipsoid of the symmetric part of `matrix`, row-major,
+    /// or `None` if it is not positive definite or has non-finite entries
+    fn new(matrix: &[T], dim: usize) -> Option<Self> {
+        let two = T::one() + T::one();
+        let symmetric = |i: usize, j: usize| (matrix[i * dim + j] + matrix[j * dim + i]) / two;
+        if matrix.iter().any(|value| !Float::is_finite(*value)) {
+            return None;
+        }
+        let largest_diagonal = (0..dim).fold(T::zero(), |max, i| Float::max(max, symmetric(i, i)));
+        // pivots this small relative to the diagonal are rounding errors of a singular matrix
+        let tolerance =
+            largest_diagonal * <T as Float>::epsilon() * T::from(dim).expect("small integer");
+        let mut cholesky = vec![T::zero(); dim * dim];
+        for i in 0..dim {
+            for j in 0..=i {
+                let sum = (0..j).fold(symmetric(i, j), |sum, k| {
+                    sum - cholesky[i * dim + k] * cholesky[j * dim + k]
+                });
+                if i == j {
+                    if !Float::is_finite(sum) || sum <= tolerance {
+                        return None;
+                    }
+                    cholesky[i * dim + i] = Float::sqrt(sum);
+                } else {
+                    cholesky[i * dim + j] = sum / cholesky[j * dim + j];
+                }
+            }
+        }
+        Some(Ellipsoid { dim, cholesky })
+    }
+
+    /// Returns `dᵀ A d`, as the squared norm of `Lᵀ d`
+    fn mahalanobis2(&self, offset: &[T]) -> T {
+        let dim = self.dim;
+        (0..dim).fold(T::zero(), |sum, j| {
+            let projection = (j..dim).fold(T::zero(), |projection, i| {
+                projection + self.cholesky[i * dim + j] * offset[i]
+            });
+            sum + projection * projection
+        })
+    }
+
+    /// Returns the half-width of the bounding box of the ellipsoid along each dimension,
+    /// the square root of the corresponding diagonal entry of `A⁻¹`
+    fn half_widths(&self) -> Vec<T> {
+        let dim = self.dim;
+        // widened so that rounding errors do not exclude points on the border
+        let margin = T::one() + <T as Float>::epsilon() * T::from(8 * dim).expect("small integer");
+        let mut column = vec![T::zero(); dim];
+        (0..dim)
+            .map(|i| {
+                // column `i` of `L⁻¹`, by forward substitution
+                for row in 0..dim {
+                    let unit = if row == i { T::one() } else { T::zero() };
+                    let sum = (0..row).fold(unit, |sum, k| {
+                        sum - self.cholesky[row * dim + k] * column[k]
+                    });
+                    column[row] = sum / self.cholesky[row * dim + row];
+                }
+                let norm2 = column.iter().fold(T::zero(), |sum, x| sum + *x * *x);
+                Float::sqrt(norm2) * margin
+            })
+            .collect()
+    }
+}
+
+impl<T: Scalar + Float, P: Point<T>> KDTree<T, P> {
+    /// Finds the points `p` inside the ellipsoid `(p - center)ᵀ inv_cov (p - center) <= 1`, where `inv_cov`
+    /// is a `D × D` inverse covariance matrix, row-major, as in gating by the Mahalanobis distance.
+    ///
+    /// The `dist2` of the returned neighbours are their squared Mahalanobis distances, the left-hand side
+    /// of the condition.
+    /// Subtrees are pruned by the axis-aligned bounding box of the ellipsoid, so the tree is not transformed.
+    /// Of the `parameters`, `max_radius` additionally bounds the Euclidean distance to `center`,
+    /// `self_match` whether points at `center` or of a given index are returned, and `sort_results` whether the neighbours
+    /// are sorted by increasing Mahalanobis distance; the search is always exact.
+    /// Only the symmetric part of `inv_cov` is used.
+    /// Returns [`QueryError::DegenerateCovariance`] if it is not positive definite, for instance singular,
+    /// or has non-finite entries, and an error if the parameters are invalid, see [`Parameters::validate`].
+    ///
+    /// Panics if `inv_cov` does not have `D × D` entries.
+    pub fn ellipsoid_search(
+        &self,
+        center: &P,
+        inv_cov: &[T],
+        parameters: &Parameters<T>,
+    ) -> Result<Vec<Neighbour<T, P>>, Error> {
+        let dim = P::DIM as usize;
+        assert_eq!(
+            inv_cov.len(),
+            dim * dim,
+            "the inverse covariance must have D × D entries"
+        );
+        parameters.validate()?;
+        let ellipsoid = match self.rotation.as_deref() {
+            None => Ellipsoid::new(inv_cov, dim),
+            // in the frame of the tree, offsets are `R d` so the matrix is `R A Rᵀ`
+            Some(rotation) => Ellipsoid::new(&conjugate(rotation, inv_cov, dim), dim),
+        }
+        .ok_or(QueryError::DegenerateCovariance)?;
+        let center = self.query_coordinates(center);
+        let internal_parameters = InternalParameters::new(parameters, self.scale);
+        let mut half_widths = ellipsoid.half_widths();
+        for half_width in &mut half_widths {
+            *half_width = Float::min(*half_width, parameters.max_radius);
+        }
+        let corner = |sign: T| {
+            let mut corner = P::default();
+            for (i, half_width) in half_widths.iter().enumerate() {
+                let value = NotNan::new(*center[i] + sign * *half_width)
+                    .expect("the bounding box of the ellipsoid is not NaN");
+                corner.set(i as u32, value);
+            }
+            corner
+        };
+        let (min, max) = (corner(-T::one()), corner(T::one()));
+        let mut offset = vec![T::zero(); dim];
+        let mut found = Vec::new();
+        self.visit_range(&min, &max, &mut |internal_indices, _| {
+            for internal in internal_indices {
+                let point = self.stored_coordinates(internal);
+                let dist2 = point_slice_dist2::<T, P>(point, &center, self.scale);
+                if !internal_parameters.accepts(dist2, self.indices[internal as usize]) {
+                    continue;
+                }
+                for (i, value) in offset.iter_mut().enumerate() {
+                    *value = *point[i] - *center[i];
+                }
+                let mahalanobis2 = ellipsoid.mahalanobis2(&offset);
+                if mahalanobis2 <= T::one() {
+                    found.push(Neighbour {
+                        point: self.stored_point(internal),
+                        dist2: NotNan::new(mahalanobis2).expect("distances are not NaN"),
+                        index: self.externalise_index(internal),
+                    });
+                }
+            }
+        });
+        if parameters.sort_results {
+            found.sort_unstable_by_key(|neighbour| (neighbour.dist2, neighbour.index));
+        }
+        Ok(found)
+    }
+}
+
+/// Returns `R A Rᵀ` for the row-major matrices `rotation` and `matrix` of `dim × dim` entries
+fn conjugate<T: Scalar + Float>(rotation: &[T], matrix: &[T], dim: usize) -> Vec<T> {
+    let product = |lhs: &dyn Fn(usize, usize) -> T, rhs: &dyn Fn(usize, usize) -> T| {
+        let mut product = vec![T::zero(); dim * dim];
+        for i in 0..dim {
+            for j in 0..dim {
+                product[i * dim + j] =
+                    (0..dim).fold(T::zero(), |sum, k| sum + lhs(i, k) * rhs(k, j));
+            }
+        }
+        product
+    };
+    let left = product(&|i, k| rotation[i * dim + k], &|k, j| matrix[k * dim + j]);
+    product(&|i, k| left[i * dim + k], &|k, j| rotation[j * dim + k])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    /// Returns the squared Mahalanobis distance between `point` and `center`
+    fn mahalanobis2(point: &P3, center: &P3, inv_cov: &[f32; 9]) -> f32 {
+        let d = [0, 1, 2].map(|i| (point.get(i) - center.get(i)).into_inner());
+        (0..3)
+            .flat_map(|i| (0..3).map(move |j| (i, j)))
+            .map(|(i, j)| d[i] * inv_cov[i * 3 + j] * d[j])
+            .sum()
+    }
+
+    /// Returns `B Bᵀ + 0.001 I` for a random `B`, a random positive definite matrix
+    fn random_inverse_covariance() -> [f32; 9] {
+        let b: [f32; 9] =
+            core::array::from_fn(|_| random_point::<1>().get(0).into_inner() / 1000.0);
+        core::array::from_fn(|index| {
+            let (i, j) = (index / 3, index % 3);
+            let diagonal = if i == j { 0.001 } else { 0.0 };
+            (0..3).map(|k| b[i * 3 + k] * b[j * 3 + k]).sum::<f32>() + diagonal
+        })
+    }
+
+    #[test]
+    fn ellipsoid_searches_match_brute_force() {
+        let cloud: Vec<P3> = random_point_cloud(4000);
+        let rotated = BuildOptions {
+            rotation_seed: Some(227),
+            ..BuildOptions::default()
+        };
+        for tree in [
+            KDTree::new(&cloud),
+            KDTree::new_with_options(&cloud, &rotated),
+        ] {
+            let mut total = 0;
+            for _ in 0..30 {
+                let center: P3 = random_point();
+                let inv_cov = random_inverse_covariance();
+                let found = tree
+                    .ellipsoid_search(&center, &inv_cov, &Parameters::default())
+                    .unwrap();
+                assert!(found.windows(2).all(|pair| pair[0].dist2 <= pair[1].dist2));
+                for neighbour in &found {
+                    let expected =
+                        mahalanobis2(&cloud[neighbour.index as usize], &center, &inv_cov);
+                    assert!((*neighbour.dist2 - expected).abs() <= 1e-4);
+                    assert!(*neighbour.dist2 <= 1.0);
+                }
+                // points clearly inside are found, borders aside
+                let inside = cloud
+                    .iter()
+                    .filter(|point| mahalanobis2(point, &center, &inv_cov) <= 0.999)
+                    .count();
+                let around = cloud
+                    .iter()
+                    .filter(|point| mahalanobis2(point, &center, &inv_cov) <= 1.001)
+                    .count();
+                assert!(inside <= found.len() && found.len() <= around);
+                total += found.len();
+            }
+            assert!(total > 0);
+        }
+    }
+
+    #[test]
+    fn parameters_restrict_ellipsoid_searches() {
+        let cloud: Vec<P3> = (0..10).map(|i| P3::from([i as f32, 0.0, 0.0])).collect();
+        let tree = KDTree::new(&cloud);
+        // an ellipsoid of half-axes 5, 1 and 1
+        let inv_cov = [0.04, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0];
+        let indices = |parameters: &Parameters<f32>| -> Vec<u32> {
+            let found = tree
+                .ellipsoid_search(&cloud[0], &inv_cov, parameters)
+                .unwrap();
+            found.iter().map(|neighbour| neighbour.index).collect()
+        };
+        assert_eq!(indices(&Parameters::default()), [0, 1, 2, 3, 4, 5]);
+        let parameters = Parameters {
+            max_radius: 2.0,
+            self_match: SelfMatch::ExcludeCoincident,
+            ..Parameters::default()
+        };
+        assert_eq!(indices(&parameters), [1, 2]);
+        let found = tree
+            .ellipsoid_search(&cloud[0], &inv_cov, &Parameters::default())
+            .unwrap();
+        assert_eq!(*found[5].dist2, 25.0 * 0.04);
+    }
+
+    #[test]
+    fn degenerate_inverse_covariances_are_rejected() {
+        let tree = KDTree::new(&random_point_cloud::<3>(100));
+        let center = P3::from([0.0, 0.0, 0.0]);
+        let singular = [1.0, 2.0, 0.0, 2.0, 4.0, 0.0, 0.0, 0.0, 1.0];
+        let indefinite = [1.0, 0.0, 0.0, 0.0, -1.0, 0.0, 0.0, 0.0, 1.0];
+        let infinite = [f32::INFINITY, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0];
+        for inv_cov in [singular, indefinite, infinite, [0.0; 9]] {
+            assert_eq!(
+                tree.ellipsoid_search(&center, &inv_cov, &Parameters::default()),
+                Err(Error::Query(QueryError::DegenerateCovariance))
+            );
+        }
+        let negative = Parameters {
+            max_radius: -1.0,
+            ..Parameters::default()
+        };
+        let identity = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0];
+        assert!(matches!(
+            tree.ellipsoid_search(&center, &identity, &negative),
+            Err(Error::Parameter(_))
+        ));
+    }
+}
@@ -0,0 +1,352 @@
+//! Errors reported by nabo
+
+use core::fmt;
+
+/// An error in the search parameters
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParameterError {
+    /// `epsilon` must be positive or zero
+    NegativeEpsilon,
+    /// `epsilon` must not be NaN
+    NonFiniteEpsilon,
+    /// `max_radius` must be positive or zero
+    NegativeMaxRadius,
+    /// `max_radius` must not be NaN
+    NanMaxRadius,
+    /// `epsilon` must be zero when the `strict-exact` feature is enabled
+    ApproximateSearch,
+}
+
+impl fmt::Display for ParameterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ParameterError::*;
+        match self {
+            NegativeEpsilon => write!(f, "epsilon must be positive or zero"),
+            NonFiniteEpsilon => write!(f, "epsilon must not be NaN"),
+            NegativeMaxRadius => write!(f, "max_radius must be positive or zero"),
+            NanMaxRadius => write!(f, "max_radius must not be NaN"),
+            ApproximateSearch => write!(f, "epsilon must be zero in strict exact mode"),
+        }
+    }
+}
+
+/// An error when building a [`KDTree`](crate::KDTree)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    /// The bucket size must be at least 2
+    BucketSizeTooSmall(u32),
+    /// The point cloud has more than `u32::MAX` points
+    TooManyPoints(usize),
+    /// The tree would need more nodes than can be encoded for this number of dimensions
+    TooManyNodes {
+        /// the number of nodes needed, possibly estimated
+        node_count: usize,
+        /// the maximum number of nodes
+        max_node_count: u32,
+    },
+    /// The point type has zero dimensions
+    ZeroDimension,
+    /// A point has an infinite coordinate
+    NonFiniteCoordinate {
+        /// the index of the point in the point cloud
+        index: u32,
+    },
+    /// A [`FlatTree`](crate::FlatTree) does not describe a valid tree, for the given reason
+    InvalidFlatTree(&'static str),
+    /// The values associated with the points, such as the counts of a [`WeightedKDTree`](crate::WeightedKDTree),
+    /// are not as many as the points
+    ValuesLengthMismatch {
+        /// the number of points
+        points: usize,
+        /// the number of values
+        values: usize,
+    },
+    /// The radius of a point of a [`CoveringKDTree`](crate::CoveringKDTree) is negative or NaN
+    InvalidRadius {
+        /// the index of the point
+        index: u32,
+    },
+    /// The build was cancelled through a [`CancellationToken`](crate::CancellationToken)
+    Cancelled,
+    /// The top splits of a [`KDTreeBuilder`](crate::KDTreeBuilder) are invalid, for the given reason
+    InvalidTopSplits(&'static str),
+    /// A subtree was attached to a leaf of a [`KDTreeBuilder`](crate::KDTreeBuilder) that does not exist
+    LeafIdOutOfRange {
+        /// the id of the leaf
+        leaf_id: u32,
+        /// the number of leaves
+        leaf_count: u32,
+    },
+    /// A point of a [`GeoTree`](crate::geo::GeoTree) has a latitude outside `-90..=90`
+    /// or a longitude outside `-180..=180`
+    InvalidLatLon {
+        /// the index of the point
+        index: u32,
+    },
+    /// Two points have the same identifier, while unique identifiers were required,
+    /// see [`KDTree::try_new_with_ids`](crate::KDTree::try_new_with_ids)
+    DuplicateId {
+        /// the identifier
+        id: u64,
+    },
+    /// The tree would be deeper than the number of splits that queries can safely recurse through,
+    /// see [`BuildOptions::max_depth`](crate::BuildOptions::max_depth)
+    TooDeep {
+        /// the maximum depth, [`BuildOptions::MAX_SAFE_DEPTH`](crate::BuildOptions::MAX_SAFE_DEPTH)
+        max_depth: u32,
+    },
+    /// The stride of the coarse tree is zero, see [`BuildOptions::coarse_stride`](crate::BuildOptions::coarse_stride)
+    CoarseStrideZero,
+    /// A timestamp is NaN, see [`KDTree::with_timestamps`](crate::KDTree::with_timestamps)
+    NanTimestamp {
+        /// the index of the point
+        index: u32,
+    },
+    /// The points have more dimensions than a tree is built for,
+    /// see [`HighDimension::Reject`](crate::HighDimension::Reject)
+    DimensionalityTooHigh {
+        /// the number of dimensions of the points
+        dim: u32,
+        /// the largest number of dimensions of a tree
+        max_tree_dim: u32,
+    },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use BuildError::*;
+        match self {
+            BucketSizeTooSmall(bucket_size) => write!(
+                f,
+                "Bucket size must be at least 2, but {} was passed",
+                bucket_size
+            ),
+            TooManyPoints(count) => write!(
+                f,
+                "Point cloud of size {} is larger than maximum possible size {}",
+                count,
+                u32::MAX
+            ),
+            TooManyNodes {
+                node_count,
+                max_node_count,
+            } => write!(
+                f,
+                "Point cloud has a risk to have more nodes {} than the kd-tree allows {}",
+                node_count, max_node_count
+            ),
+            ZeroDimension => write!(f, "Points must have at least one dimension"),
+            NonFiniteCoordinate { index } => {
+                write!(f, "Point {} has an infinite coordinate", index)
+            }
+            InvalidFlatTree(reason) => write!(f, "Invalid flat tree: {}", reason),
+            ValuesLengthMismatch { points, values } => write!(
+                f,
+                "Point cloud has {} points but {} values were passed",
+                points, values
+            ),
+            InvalidRadius { index } => {
+                write!(f, "Point {} has a negative or NaN radius", index)
+            }
+            Cancelled => write!(f, "Build was cancelled"),
+            InvalidTopSplits(reason) => write!(f, "Invalid top splits: {}", reason),
+            LeafIdOutOfRange {
+                leaf_id,
+                leaf_count,
+            } => write!(
+                f,
+                "Leaf id {} is out of range, there are {} leaves",
+                leaf_id, leaf_count
+            ),
+            InvalidLatLon { index } => {
+                write!(f, "Point {} has an invalid latitude or longitude", index)
+            }
+            DuplicateId { id } => write!(f, "Identifier {} is used by several points", id),
+            TooDeep { max_depth } => write!(
+                f,
+                "Tree would be deeper than {} splits, the maximum depth of queries",
+                max_depth
+            ),
+            CoarseStrideZero => write!(f, "Coarse stride must be at least 1"),
+            NanTimestamp { index } => write!(f, "Point {} has a NaN timestamp", index),
+            DimensionalityTooHigh { dim, max_tree_dim } => write!(
+                f,
+                "Points have {} dimensions, more than the {} of a tree",
+                dim, max_tree_dim
+            ),
+        }
+    }
+}
+
+/// An error in a query
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryError {
+    /// The query does not have the same number of dimensions as the tree
+    DimensionMismatch {
+        /// the number of dimensions of the query
+        query_dim: u32,
+        /// the number of dimensions of the tree
+        tree_dim: u32,
+    },
+    /// A coordinate of the query is NaN
+    NanCoordinate,
+    /// The queries were cancelled through a [`CancellationToken`](crate::CancellationToken)
+    Cancelled,
+    /// A batch of queries does not have one override, or one transformation, per query
+    OverrideCountMismatch {
+        /// the number of queries
+        queries: usize,
+        /// the number of overrides
+        overrides: usize,
+    },
+    /// The output buffers of a batch do not have `k` entries per query,
+    /// see [`KDTree::knn_batch_into`](crate::KDTree::knn_batch_into)
+    OutputLengthMismatch {
+        /// the number of entries needed, `k` times the number of queries, saturated to `usize::MAX`
+        expected: usize,
+        /// the length of the buffer of indices
+        indices: usize,
+        /// the length of the buffer of squared distances
+        dists2: usize,
+    },
+    /// The per-point values, labels or targets do not have one element per point of the tree
+    ValuesLengthMismatch {
+        /// the number of points
+        points: usize,
+        /// the number of values
+        values: usize,
+    },
+    /// The query of a [`GeoTree`](crate::geo::GeoTree) has a latitude outside `-90..=90`
+    /// or a longitude outside `-180..=180`
+    InvalidLatLon,
+    /// The direction of a query has a norm below the machine epsilon, or that is not finite,
+    /// see [`KDTree::nearest_by_direction`](crate::KDTree::nearest_by_direction)
+    DegenerateDirection,
+    /// The inverse covariance of an ellipsoid is not positive definite, for instance singular, or is not finite,
+    /// see [`KDTree::ellipsoid_search`](crate::KDTree::ellipsoid_search)
+    DegenerateCovariance,
+    /// A point index is not smaller than the number of points of the tree
+    IndexOutOfRange {
+        /// the index passed
+        index: u32,
+        /// the number of points
+        len: usize,
+    },
+    /// Fewer neighbours than requested are within the maximum radius, although there are points beyond it,
+    /// see [`InsufficientPolicy::Error`](crate::InsufficientPolicy::Error)
+    InsufficientNeighbours {
+        /// the number of neighbours within the radius
+        found: usize,
+        /// the number of neighbours requested, at most the number of points
+        requested: u32,
+    },
+    /// A radius search found more points than its limit of results, and was stopped,
+    /// see [`KDTree::try_radius_search_limited`](crate::KDTree::try_radius_search_limited)
+    ResultLimitExceeded {
+        /// the maximum number of results
+        limit: usize,
+        /// a lower bound of the number of points within the radius
+        seen_at_least: usize,
+    },
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use QueryError::*;
+        match self {
+            DimensionMismatch {
+                query_dim,
+                tree_dim,
+            } => write!(
+                f,
+                "Query has {} dimensions but the tree has {}",
+                query_dim, tree_dim
+            ),
+            NanCoordinate => write!(f, "Query coordinates must not be NaN"),
+            Cancelled => write!(f, "Queries were cancelled"),
+            OverrideCountMismatch { queries, overrides } => write!(
+                f,
+                "Batch has {} queries but {} overrides were passed",
+                queries, overrides
+            ),
+            OutputLengthMismatch {
+                expected,
+                indices,
+                dists2,
+            } => write!(
+                f,
+                "Batch needs {} output entries but {} indices and {} squared distances were passed",
+                expected, indices, dists2
+            ),
+            ValuesLengthMismatch { points, values } => write!(
+                f,
+                "Tree has {} points but {} values were passed",
+                points, values
+            ),
+            InvalidLatLon => write!(f, "Query has an invalid latitude or longitude"),
+            DegenerateDirection => write!(f, "Query direction must have a non-zero finite norm"),
+            DegenerateCovariance => {
+                write!(f, "Inverse covariance must be finite and positive definite")
+            }
+            IndexOutOfRange { index, len } => {
+                write!(
+                    f,
+                    "Point index {} is out of range for {} points",
+                    index, len
+                )
+            }
+            InsufficientNeighbours { found, requested } => write!(
+                f,
+                "Only {} of {} requested neighbours are within the maximum radius",
+                found, requested
+            ),
+            ResultLimitExceeded {
+                limit,
+                seen_at_least,
+            } => write!(
+                f,
+                "At least {} points are within the radius, more than the limit of {}",
+                seen_at_least, limit
+            ),
+        }
+    }
+}
+
+/// Any error reported by nabo
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// An error when building a tree
+    Build(BuildError),
+    /// An error in a query
+    Query(QueryError),
+    /// An error in the search parameters
+    Parameter(ParameterError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Build(error) => error.fmt(f),
+            Error::Query(error) => error.fmt(f),
+            Error::Parameter(error) => error.fmt(f),
+        }
+    }
+}
+
+impl From<BuildError> for Error {
+    fn from(error: BuildError) -> Self {
+        Error::Build(error)
+    }
+}
+
+impl From<QueryError> for Error {
+    fn from(error: QueryError) -> Self {
+        Error::Query(error)
+    }
+}
+
+impl From<ParameterError> for Error {
+    fn from(error: ParameterError) -> Self {
+        Error::Parameter(error)
+    }
+}
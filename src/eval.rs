@@ -0,0 +1,343 @@
+//! Evaluation of the recall and latency of approximate searches against exact ones, to choose their parameters
+//!
+//! [`evaluate`] runs the same queries with each of a grid of [`Parameters`], and compares their results
+//! to the ones of exact searches, computed once for all settings that differ only by `epsilon` and the traversal order.
+//! The rows it returns print as CSV lines, below [`EvalRow::CSV_HEADER`].
+
+use alloc::{vec, vec::Vec};
+use core::fmt;
+use core::time::Duration;
+
+use num_traits::{Float, Zero};
+use ordered_float::NotNan;
+
+use crate::build_report::PhaseClock;
+use crate::{KDTree, KnnScratch, Parameters, Point, Scalar};
+
+/// How [`evaluate_with_options`] times the queries
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EvalOptions {
+    /// number of untimed passes over the queries before the timed ones, to warm the caches and fill
+    /// the reused candidate containers, so that the timed passes allocate nothing
+    pub warmup: u32,
+    /// number of timed passes over the queries, the latency of a query being its fastest pass
+    pub repetitions: u32,
+}
+
+impl Default for EvalOptions {
+    fn default() -> Self {
+        EvalOptions {
+            warmup: 1,
+            repetitions: 3,
+        }
+    }
+}
+
+/// The distribution of the latencies of the queries of an evaluation, see [`EvalRow`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Latencies {
+    /// median
+    pub p50: Duration,
+    /// 95th percentile
+    pub p95: Duration,
+    /// 99th percentile
+    pub p99: Duration,
+    /// largest latency
+    pub max: Duration,
+}
+
+/// The quality and speed of the searches with a setting of the parameters, see [`evaluate`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EvalRow<T: Scalar> {
+    /// the parameters of the searches
+    pub parameters: Parameters<T>,
+    /// recall@k: the fraction of the exact neighbours found, a neighbour at the distance of the furthest
+    /// exact one counting as found, so that ties do not lower the recall
+    pub recall: f64,
+    /// mean over the queries and ranks of the distance of the neighbour found divided by that of the exact one,
+    /// at least 1, over the ranks both searches found and whose exact distance is positive
+    pub distance_ratio: f64,
+    /// the latencies of the queries
+    pub latency: Latencies,
+}
+
+impl<T: Scalar> EvalRow<T> {
+    /// The header of the CSV lines printed by the [`Display`](fmt::Display) of the rows, latencies being in nanoseconds
+    pub const CSV_HEADER: &'static str =
+        "epsilon,max_radius,self_match,recall,distance_ratio,p50_ns,p95_ns,p99_ns,max_ns";
+}
+
+impl<T: Scalar> fmt::Display for EvalRow<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{:?},{:.6},{:.6},{},{},{},{}",
+            to_f64(self.parameters.epsilon),
+            to_f64(self.parameters.max_radius),
+            self.parameters.self_match,
+            self.recall,
+            self.distance_ratio,
+            self.latency.p50.as_nanos(),
+            self.latency.p95.as_nanos(),
+            self.latency.p99.as_nanos(),
+            self.latency.max.as_nanos()
+        )
+    }
+}
+
+/// The sorted squared distances of the neighbours of each query
+type Distances<T> = Vec<Vec<NotNan<T>>>;
+
+/// Evaluates the searches of the `k` nearest neighbours of `queries` in `tree` with each of `settings`,
+/// with the default [`EvalOptions`], see [`evaluate_with_options`].
+pub fn evaluate<T: Scalar, P: Point<T>>(
+    tree: &KDTree<T, P>,
+    queries: &[P],
+    k: u32,
+    settings: &[Parameters<T>],
+) -> Vec<EvalRow<T>> {
+    evaluate_with_options(tree, queries, k, settings, &EvalOptions::default())
+}
+
+/// Evaluates the searches of the `k` nearest neighbours of `queries` in `tree` with each of `settings`,
+/// returning a row per setting, in the same order.
+///
+/// The ground truth of a setting is the result of exact searches with its radius, boundary, self-match
+/// and insufficiency policies, computed once for all settings sharing them.
+/// Each query is timed alone with a steady clock, through [`KDTree::knn_raw_results`] with candidate containers
+/// reused from query to query, so that the latencies measure the search rather than allocations
+/// and externalising the results.
+/// The clock is local to the call, so concurrent evaluations do not disturb each other's measurements
+/// beyond competing for the cores.
+/// Latencies are measured with the `std` feature, and are zero without it and on `wasm32-unknown-unknown`,
+/// as for [`BuildTimes`](crate::BuildTimes).
+///
+/// Panics if a setting is invalid, see [`Parameters::validate`], or in the same cases as [`KDTree::knn_advanced`].
+pub fn evaluate_with_options<T: Scalar, P: Point<T>>(
+    tree: &KDTree<T, P>,
+    queries: &[P],
+    k: u32,
+    settings: &[Parameters<T>],
+    options: &EvalOptions,
+) -> Vec<EvalRow<T>> {
+    let mut truths: Vec<(Parameters<T>, Distances<T>)> = Vec::new();
+    let mut scratch = KnnScratch::new();
+    settings
+        .iter()
+        .map(|parameters| {
+            parameters
+                .validate()
+                .unwrap_or_else(|error| panic!("{}", error));
+            let exact = exact_parameters(parameters);
+            let position = match truths.iter().position(|(key, _)| *key == exact) {
+                Some(position) => position,
+                None => {
+                    truths.push((exact, ground_truth(tree, queries, k, &exact, &mut scratch)));
+                    truths.len() - 1
+                }
+            };
+            let truth = &truths[position].1;
+            let (found, latencies) =
+                timed_searches(tree, queries, k, parameters, options, &mut scratch);
+            let (recall, distance_ratio) = compare(&found, truth);
+            EvalRow {
+                parameters: *parameters,
+                recall,
+                distance_ratio,
+                latency: latencies,
+            }
+        })
+        .collect()
+}
+
+/// Returns the parameters of the exact searches to compare the searches with `parameters` to
+fn exact_parameters<T: Scalar>(parameters: &Parameters<T>) -> Parameters<T> {
+    Parameters {
+        epsilon: T::zero(),
+        sort_results: true,
+        traversal_order: Default::default(),
+        ..*parameters
+    }
+}
+
+/// Returns the squared distances of the neighbours of each query with the exact `parameters`, sorted
+fn ground_truth<T: Scalar, P: Point<T>>(
+    tree: &KDTree<T, P>,
+    queries: &[P],
+    k: u32,
+    parameters: &Parameters<T>,
+    scratch: &mut KnnScratch<T>,
+) -> Distances<T> {
+    let container = KDTree::<T, P>::default_candidate_container(k);
+    queries
+        .iter()
+        .map(|query| {
+            tree.knn_raw_results(k, query, container, parameters, scratch)
+                .dist2s()
+                .collect()
+        })
+        .collect()
+}
+
+/// Runs the searches with `parameters`, returning the sorted squared distances of the neighbours of each query
+/// and the distribution of the fastest latency of each query over the repetitions
+fn timed_searches<T: Scalar, P: Point<T>>(
+    tree: &KDTree<T, P>,
+    queries: &[P],
+    k: u32,
+    parameters: &Parameters<T>,
+    options: &EvalOptions,
+    scratch: &mut KnnScratch<T>,
+) -> (Distances<T>, Latencies) {
+    let container = KDTree::<T, P>::default_candidate_container(k);
+    let mut found = vec![Vec::new(); queries.len()];
+    let mut fastest = vec![Duration::MAX; queries.len()];
+    let mut clock = PhaseClock::start();
+    for pass in 0..options.warmup + options.repetitions.max(1) {
+        let timed = pass >= options.warmup;
+        for (index, query) in queries.iter().enumerate() {
+            clock.lap();
+            let results = tree.knn_raw_results(k, query, container, parameters, scratch);
+            let latency = clock.lap();
+            if timed {
+                fastest[index] = fastest[index].min(latency);
+                if fastest[index] == latency {
+                    found[index].clear();
+                    found[index].extend(results.dist2s());
+                }
+            }
+        }
+    }
+    for dist2s in &mut found {
+        dist2s.sort_unstable();
+    }
+    (found, latencies(fastest))
+}
+
+/// Returns the distribution of `latencies`, by nearest rank
+fn latencies(mut latencies: Vec<Duration>) -> Latencies {
+    if latencies.is_empty() {
+        return Latencies::default();
+    }
+    latencies.sort_unstable();
+    let quantile = |q: f64| {
+        let rank = ((q * latencies.len() as f64).ceil() as usize).max(1);
+        latencies[rank - 1]
+    };
+    Latencies {
+        p50: quantile(0.5),
+        p95: quantile(0.95),
+        p99: quantile(0.99),
+        max: latencies[latencies.len() - 1],
+    }
+}
+
+/// Returns the recall and the mean distance ratio of `found` with respect to `truth`
+fn compare<T: Scalar>(found: &Distances<T>, truth: &Distances<T>) -> (f64, f64) {
+    let (mut hits, mut expected) = (0, 0);
+    let (mut ratio_sum, mut ratio_count) = (0.0, 0);
+    for (found, truth) in found.iter().zip(truth) {
+        let furthest = match truth.last() {
+            Some(furthest) => *furthest,
+            None => continue,
+        };
+        expected += truth.len();
+        hits += found
+            .iter()
+            .filter(|dist2| **dist2 <= furthest)
+            .count()
+            .min(truth.len());
+        for (found, exact) in found.iter().zip(truth) {
+            if *exact > NotNan::zero() {
+                ratio_sum += Float::sqrt(to_f64(**found) / to_f64(**exact));
+                ratio_count += 1;
+            }
+        }
+    }
+    let recall = if expected == 0 {
+        1.0
+    } else {
+        hits as f64 / expected as f64
+    };
+    let distance_ratio = if ratio_count == 0 {
+        1.0
+    } else {
+        ratio_sum / ratio_count as f64
+    };
+    (recall, distance_ratio)
+}
+
+/// Converts `value` to `f64`, which holds all scalars up to rounding
+fn to_f64<T: Scalar>(value: T) -> f64 {
+    value.to_f64().expect("scalars convert to f64")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn exact_settings_have_full_recall() {
+        let cloud: Vec<P3> = random_point_cloud(3000);
+        let tree = KDTree::new(&cloud);
+        let queries: Vec<P3> = (0..100).map(|_| random_point()).collect();
+        let settings = [
+            Parameters::default(),
+            Parameters {
+                epsilon: 1.0,
+                ..Parameters::default()
+            },
+            Parameters {
+                epsilon: 1000.0,
+                ..Parameters::default()
+            },
+            Parameters {
+                max_radius: 5.0,
+                ..Parameters::default()
+            },
+        ];
+        let rows = evaluate(&tree, &queries, 10, &settings);
+        assert_eq!(rows.len(), 4);
+        for (row, parameters) in rows.iter().zip(&settings) {
+            assert_eq!(row.parameters, *parameters);
+        }
+        assert_eq!(rows[0].recall, 1.0);
+        assert_eq!(rows[0].distance_ratio, 1.0);
+        assert_eq!(rows[3].recall, 1.0);
+        assert!(rows[2].recall < rows[0].recall);
+        assert!(rows[2].distance_ratio > 1.0);
+        assert!(rows[1].recall >= rows[2].recall);
+        for row in &rows {
+            assert!(row.latency.p50 <= row.latency.p95);
+            assert!(row.latency.p99 <= row.latency.max);
+            #[cfg(feature = "std")]
+            assert!(row.latency.max > Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn rows_print_as_csv() {
+        let cloud: Vec<P2> = random_point_cloud(100);
+        let tree = KDTree::new(&cloud);
+        let options = EvalOptions {
+            warmup: 0,
+            repetitions: 1,
+        };
+        let rows = evaluate_with_options(&tree, &[], 4, &[Parameters::default()], &options);
+        assert_eq!(rows[0].recall, 1.0);
+        assert_eq!(rows[0].latency, Latencies::default());
+        assert_eq!(
+            EvalRow::<f32>::CSV_HEADER.split(',').count(),
+            rows[0].to_string().split(',').count()
+        );
+        assert_eq!(
+            rows[0].to_string(),
+            format!(
+                "0,inf,{:?},1.000000,1.000000,0,0,0,0",
+                Parameters::<f32>::default().self_match
+            )
+        );
+    }
+}
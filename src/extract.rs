@@ -0,0 +1,118 @@
+//! Extraction of a part of a tree as an independent tree, for example to process it elsewhere
+
+use alloc::vec::Vec;
+
+use crate::{KDTree, Point, Scalar};
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Returns a new tree of the points inside the axis-aligned box from `min` to `max`, bounds included,
+    /// as found by [`Self::range_query`], so in the frame of the points also if the tree is rotated.
+    ///
+    /// The extracted tree is built with the options of this one, and its points are in the order
+    /// of the original point cloud.
+    /// Each point keeps its identifier, which is its index in the original point cloud if this tree
+    /// has no identifiers, so that [`Self::id_of`] on the extracted tree gives the same identifier
+    /// as on this one, and maps the indices found in the extracted tree back to the original ones.
+    pub fn extract_region(&self, min: &P, max: &P) -> KDTree<T, P> {
+        self.extract(self.range_query(min, max))
+    }
+
+    /// Returns a new tree of the points below the node of id `node_id`, see [`NodeRef::id`](crate::NodeRef::id),
+    /// for example to split the tree along its structure, see [`Self::extract_region`].
+    ///
+    /// Panics if `node_id` is not the id of a node of the tree.
+    pub fn extract_subtree(&self, node_id: usize) -> KDTree<T, P> {
+        let node = self.node_by_id(node_id);
+        self.extract(node.points().map(|(index, _)| index).collect())
+    }
+
+    /// Builds a tree of the points of indices `indices` in the original point cloud, keeping their identifiers and timestamps
+    fn extract(&self, mut indices: Vec<u32>) -> KDTree<T, P> {
+        indices.sort_unstable();
+        let cloud: Vec<P> = indices
+            .iter()
+            .map(|index| self.stored_point(self.internalise_index(*index)))
+            .collect();
+        let ids: Vec<u64> = indices.iter().map(|index| self.id_of(*index)).collect();
+        let mut tree = KDTree::try_new_with_ids(&cloud, &ids, &self.options, false)
+            .unwrap_or_else(|error| panic!("{}", error));
+        if let Some(timestamps) = self.external_timestamps() {
+            let timestamps = indices
+                .iter()
+                .map(|index| timestamps[*index as usize])
+                .collect();
+            tree.set_external_timestamps(Some(timestamps));
+        }
+        tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn queries_inside_extracted_regions_match_the_original_tree() {
+        let cloud: Vec<P2> = random_point_cloud(4000);
+        let ids: Vec<u64> = (0..4000).map(|index| 1000 + 3 * index).collect();
+        for tree in [KDTree::new(&cloud), KDTree::new_with_ids(&cloud, &ids)] {
+            let (min, max) = (P2::new2d(-50.0, -30.0), P2::new2d(40.0, 60.0));
+            let region = tree.extract_region(&min, &max);
+            assert_eq!(region.len(), tree.range_query(&min, &max).len());
+            assert!(region.has_ids());
+            for _ in 0..50 {
+                // balls inside the box
+                let query = P2::new2d(
+                    random_point::<1>().get(0).into_inner() * 0.3 - 5.0,
+                    random_point::<1>().get(0).into_inner() * 0.3 + 15.0,
+                );
+                let radius = Radius::new(10.0);
+                let mut expected: Vec<_> = tree
+                    .radius_search_ordered(&query, radius)
+                    .iter()
+                    .map(|n| (tree.id_of(n.index), n.point, n.dist2))
+                    .collect();
+                let mut found: Vec<_> = region
+                    .radius_search_ordered(&query, radius)
+                    .iter()
+                    .map(|n| (region.id_of(n.index), n.point, n.dist2))
+                    .collect();
+                expected.sort_unstable_by_key(|(id, _, _)| *id);
+                found.sort_unstable_by_key(|(id, _, _)| *id);
+                assert_eq!(found, expected);
+            }
+        }
+        let empty = KDTree::new(&cloud).extract_region(&P2::new2d(1e3, 1e3), &P2::new2d(2e3, 2e3));
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn extracted_subtrees_hold_the_points_below_their_node() {
+        let cloud: Vec<P2> = random_point_cloud(3000);
+        let tree = KDTree::new(&cloud);
+        let root = tree.root();
+        let right = root.right().expect("the root splits");
+        for node in [root, right, right.left().expect("the node splits")] {
+            let subtree = tree.extract_subtree(node.id());
+            assert_eq!(subtree.len(), node.subtree_len());
+            let mut expected: Vec<u32> = node.points().map(|(index, _)| index).collect();
+            expected.sort_unstable();
+            let extracted: Vec<u32> = (0..subtree.len() as u32)
+                .map(|index| subtree.id_of(index) as u32)
+                .collect();
+            assert_eq!(extracted, expected);
+            for (index, point) in subtree.iter() {
+                assert_eq!(point, cloud[subtree.id_of(index) as usize]);
+                assert_eq!(subtree.knn(1, &point)[0].index, index);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "node id out of range")]
+    fn extracting_missing_nodes_panics() {
+        let tree = KDTree::new(&[P2::new2d(0.0, 0.0)]);
+        tree.extract_subtree(1);
+    }
+}
@@ -0,0 +1,168 @@
+//! Nearest and farthest neighbours in a single traversal
+
+use alloc::vec::Vec;
+
+use ordered_float::NotNan;
+
+use crate::cell::NodeCell;
+use crate::heap::MinMaxCollector;
+use crate::internal_parameters::InternalParameters;
+use crate::{point_slice_dist2, KDTree, Neighbour, Parameters, Point, Scalar};
+
+type Neighbours<T, P> = Vec<Neighbour<T, P>>;
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Finds both the `k` nearest and the `k` farthest neighbours of `query` in a single traversal,
+    /// with user-provided parameters.
+    ///
+    /// Returns the nearest neighbours by increasing distance and the farthest by decreasing distance;
+    /// with less than `2k` points in reach, the two overlap.
+    /// `parameters.max_radius` applies to both sides, so the farthest neighbours are the farthest within it,
+    /// and `parameters.epsilon` only relaxes the nearest side, the farthest being exact.
+    /// `parameters.sort_results` is ignored.
+    ///
+    /// A subtree is only pruned if its cell can neither hold a nearer point than the `k`-th nearest
+    /// nor a farther point than the `k`-th farthest, so this visits more of the tree than [`Self::knn`].
+    /// The cells are bounded by the bounding box of the tree and the split planes, widened by [`Self::drift_estimate`].
+    ///
+    /// Panics if `query` has NaN coordinates.
+    pub fn knn_extremes(
+        &self,
+        k: u32,
+        query: &P,
+        parameters: &Parameters<T>,
+    ) -> (Neighbours<T, P>, Neighbours<T, P>) {
+        let mut collector = MinMaxCollector::new(k.min(self.len() as u32));
+        if k > 0 && !self.is_empty() {
+            let query = self.query_coordinates(query);
+            let mut cell = NodeCell::of_root(self);
+            self.recurse_extremes(
+                &query,
+                0,
+                &mut collector,
+                &mut cell,
+                &InternalParameters::new(parameters, self.scale),
+            );
+        }
+        let (nearest, farthest) = collector.into_sorted_vecs();
+        let externalise = |neighbours: Vec<_>| {
+            neighbours
+                .into_iter()
+                .map(|n| self.externalise_neighbour(n))
+                .collect()
+        };
+        (externalise(nearest), externalise(farthest))
+    }
+
+    fn recurse_extremes(
+        &self,
+        query: &[NotNan<T>],
+        node: usize,
+        collector: &mut MinMaxCollector<T>,
+        cell: &mut NodeCell<T>,
+        internal_parameters: &InternalParameters<T>,
+    ) {
+        self.nodes[node].dispatch_on_type(
+            (collector, cell),
+            |(collector, cell), split_dim, split_val, right_child| {
+                let dim = split_dim as usize;
+                let parent = cell.extent(dim);
+                // nearer child first, to tighten the nearest side early
+                let left_first = query[dim] <= split_val;
+                for left in [left_first, !left_first] {
+                    cell.set_child(dim, parent, split_val, self.drift, left);
+                    let child = if left { node + 1 } else { right_child as usize };
+                    if self.extremes_cell_is_useful(query, collector, cell, internal_parameters) {
+                        self.recurse_extremes(query, child, collector, cell, internal_parameters);
+                    }
+                }
+                cell.set_extent(dim, parent);
+            },
+            |(collector, _), bucket_start_index, bucket_size| {
+                for bucket_index in bucket_start_index..bucket_start_index + bucket_size {
+                    let point_index = bucket_index as usize * P::DIM as usize;
+                    let point = &self.points[point_index..point_index + P::DIM as usize];
+                    let dist2 = point_slice_dist2::<T, P>(query, point, self.scale);
+                    if internal_parameters.accepts(dist2, self.indices[bucket_index as usize]) {
+                        collector.add(dist2, bucket_index);
+                    }
+                }
+            },
+        )
+    }
+
+    /// Whether a point of `cell` could improve either side of `collector`
+    fn extremes_cell_is_useful(
+        &self,
+        query: &[NotNan<T>],
+        collector: &MinMaxCollector<T>,
+        cell: &NodeCell<T>,
+        internal_parameters: &InternalParameters<T>,
+    ) -> bool {
+        let (min_dist2, max_dist2) = cell.dist2_range(query, self.scale);
+        min_dist2 <= internal_parameters.max_radius2
+            && (internal_parameters.within_error(min_dist2, collector.nearest_bound2())
+                || max_dist2 > collector.farthest_bound2())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn brute_force(cloud: &[P2], query: &P2, max_radius: f32) -> Vec<(f32, u32)> {
+        let mut all: Vec<(f32, u32)> = cloud
+            .iter()
+            .enumerate()
+            .map(|(index, point)| {
+                let dx = *point.get(0) - *query.get(0);
+                let dy = *point.get(1) - *query.get(1);
+                (dx * dx + dy * dy, index as u32)
+            })
+            .filter(|(dist2, _)| *dist2 <= max_radius * max_radius)
+            .collect();
+        all.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        all
+    }
+
+    #[test]
+    fn knn_extremes_match_brute_force() {
+        let cloud: Vec<P2> = random_point_cloud(2000);
+        let mut tree = KDTree::new(&cloud);
+        let mut cloud = cloud;
+        for round in 0..2 {
+            for max_radius in [f32::INFINITY, 30.0] {
+                let parameters = Parameters {
+                    max_radius,
+                    ..Parameters::default()
+                };
+                for k in [1, 7] {
+                    for _ in 0..20 {
+                        let query = random_point();
+                        let (nearest, farthest) = tree.knn_extremes(k, &query, &parameters);
+                        let expected = brute_force(&cloud, &query, max_radius);
+                        let k = (k as usize).min(expected.len());
+                        let dist2 = |n: &Neighbour<f32, P2>| n.dist2.into_inner();
+                        let nearest: Vec<_> = nearest.iter().map(dist2).collect();
+                        let farthest: Vec<_> = farthest.iter().map(dist2).collect();
+                        let expected_nearest: Vec<_> = expected[..k].iter().map(|e| e.0).collect();
+                        let expected_farthest: Vec<_> =
+                            expected.iter().rev().take(k).map(|e| e.0).collect();
+                        assert_eq!(nearest, expected_nearest);
+                        assert_eq!(farthest, expected_farthest);
+                    }
+                }
+            }
+            if round == 0 {
+                // moved points must still be found on both sides
+                for index in (0..2000).step_by(5) {
+                    cloud[index] = cloud[index] * 1.5;
+                    tree.update_point(index as u32, cloud[index]);
+                }
+            }
+        }
+        let (nearest, farthest) = tree.knn_extremes(0, &random_point(), &Parameters::default());
+        assert!(nearest.is_empty() && farthest.is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+//! Farthest-point sampling, selecting well-spread points of the cloud
+
+use alloc::{collections::BinaryHeap, vec, vec::Vec};
+use core::cmp::Reverse;
+use ordered_float::NotNan;
+
+use crate::infinite::HasInfinite;
+use crate::internal_parameters::InternalParameters;
+use crate::rng::SplitMix64;
+use crate::{CandidateCollector, KDTree, Parameters, Point, Scalar};
+
+/// Lowers the squared distance of the candidates to the nearest sample, and queues the changed ones
+struct SampleDistanceUpdater<'a, T: Scalar> {
+    /// internal squared distance of each point to the nearest sample
+    dist2: &'a mut [NotNan<T>],
+    /// candidates for the next sample, by decreasing distance then increasing index
+    queue: &'a mut BinaryHeap<(NotNan<T>, Reverse<u32>)>,
+    /// points further than this are not closer to the new sample than to their current nearest one
+    radius2: NotNan<T>,
+}
+
+impl<T: Scalar> CandidateCollector<T> for SampleDistanceUpdater<'_, T> {
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        let current = &mut self.dist2[index as usize];
+        if dist2 < *current {
+            *current = dist2;
+            self.queue.push((dist2, Reverse(index)));
+        }
+    }
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        // the first sample updates all points
+        (!self.radius2.is_infinite()).then_some(self.radius2)
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Selects `m` well-spread points by farthest-point sampling, and returns their indices in the original point cloud.
+    ///
+    /// The first point is chosen pseudo-randomly from `seed`, then each next point is the one furthest from
+    /// all points selected so far.
+    /// After each selection, only the points within the distance of the furthest point are updated,
+    /// using the tree.
+    /// The result is deterministic for a given tree and `seed`, ties being broken consistently.
+    /// If `m` is at least the number of points, all indices are returned, in sampling order.
+    pub fn farthest_point_sample(&self, m: usize, seed: u32) -> Vec<u32> {
+        let m = m.min(self.len());
+        if m == 0 {
+            return Vec::new();
+        }
+        let internal_parameters = InternalParameters::new(&Parameters::default(), self.scale);
+        let mut dist2 = vec![NotNan::<T>::infinite(); self.len()];
+        let mut queue = BinaryHeap::new();
+        let mut samples = Vec::with_capacity(m);
+        let mut rng = SplitMix64::new(seed as u64);
+        let mut sample = rng.below(self.len() as u64) as u32;
+        let mut radius2 = NotNan::<T>::infinite();
+        loop {
+            samples.push(self.externalise_index(sample));
+            if samples.len() == m {
+                return samples;
+            }
+            dist2[sample as usize] = -NotNan::<T>::infinite();
+            let start = sample as usize * P::DIM as usize;
+            let mut updater = SampleDistanceUpdater {
+                dist2: &mut dist2,
+                queue: &mut queue,
+                radius2,
+            };
+            self.knn_internal(
+                &mut updater,
+                &self.points[start..start + P::DIM as usize],
+                &internal_parameters,
+            );
+            // the next sample is the furthest point, skipping outdated queue entries
+            loop {
+                let (candidate_dist2, Reverse(candidate)) = match queue.pop() {
+                    Some(entry) => entry,
+                    // cannot happen as points are queued when first reached, but be safe
+                    None => return samples,
+                };
+                if dist2[candidate as usize] == candidate_dist2 {
+                    sample = candidate;
+                    radius2 = candidate_dist2;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn dist2(a: &P2, b: &P2) -> f32 {
+        let dx = *a.get(0) - *b.get(0);
+        let dy = *a.get(1) - *b.get(1);
+        dx * dx + dy * dy
+    }
+
+    /// Naive O(n m) farthest-point sampling, starting from `first`
+    fn brute_force_fps(cloud: &[P2], first: u32, m: usize) -> Vec<u32> {
+        let mut samples = vec![first];
+        let mut nearest: Vec<f32> = cloud
+            .iter()
+            .map(|p| dist2(p, &cloud[first as usize]))
+            .collect();
+        while samples.len() < m {
+            let (next, _) = nearest.iter().enumerate().fold((0, -1.0), |best, (i, d)| {
+                if *d > best.1 {
+                    (i, *d)
+                } else {
+                    best
+                }
+            });
+            samples.push(next as u32);
+            for (i, p) in cloud.iter().enumerate() {
+                nearest[i] = nearest[i].min(dist2(p, &cloud[next]));
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn matches_naive_sampling() {
+        let cloud: Vec<P2> = random_point_cloud(2000);
+        let tree = KDTree::new(&cloud);
+        let samples = tree.farthest_point_sample(50, 7);
+        assert_eq!(samples.len(), 50);
+        assert_eq!(samples, tree.farthest_point_sample(50, 7));
+        // random floats make ties improbable, so the order is the naive one
+        assert_eq!(samples, brute_force_fps(&cloud, samples[0], 50));
+    }
+
+    #[test]
+    fn edge_cases() {
+        let cloud: Vec<P2> = random_point_cloud(20);
+        let tree = KDTree::new(&cloud);
+        assert!(tree.farthest_point_sample(0, 1).is_empty());
+        let mut all = tree.farthest_point_sample(100, 1);
+        all.sort_unstable();
+        assert_eq!(all, (0..20).collect::<Vec<_>>());
+        assert!(KDTree::<f32, P2>::new(&[])
+            .farthest_point_sample(3, 1)
+            .is_empty());
+        let duplicates = KDTree::new(&[P2::new2d(1.0, 1.0); 5]);
+        let mut samples = duplicates.farthest_point_sample(5, 3);
+        samples.sort_unstable();
+        assert_eq!(samples, vec![0, 1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,465 @@
+//! Export of the tree to plain arrays, for example to run queries on a GPU
+
+use alloc::vec::Vec;
+
+use num_traits::{Bounded, One, Zero};
+use ordered_float::NotNan;
+
+use crate::dim_buffer::DimBuffer;
+use crate::node::Node;
+use crate::self_check::SELF_CHECK_SAMPLE;
+use crate::{BitSet, BuildError, BuildOptions, KDTree, Point, Scalar};
+
+/// A node of a [`FlatTree`]
+///
+/// The layout is `#[repr(C)]`: four `u32` followed by the split value, without padding for `f32` and `f64`.
+/// A node is a leaf if and only if `split_dim` is [`FlatNode::LEAF`]:
+/// * for a split node, `first` and `second` are the indices of the left and right children in
+///   [`FlatTree::nodes`]; points with a coordinate along `split_dim` smaller than or equal to
+///   `split_val` are below the left child, the others below the right child;
+/// * for a leaf, `first..second` is the range of its points in [`FlatTree::indices`],
+///   and `split_val` is zero.
+///
+/// The left child of a split node is always the next node.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FlatNode<T> {
+    /// dimension along which this node splits, or [`FlatNode::LEAF`]
+    pub split_dim: u32,
+    /// index of the left child, or start of the range of points
+    pub first: u32,
+    /// index of the right child, or end of the range of points
+    pub second: u32,
+    /// always zero, makes the padding explicit
+    pub reserved: u32,
+    /// the split value, or zero for a leaf
+    pub split_val: T,
+}
+
+impl<T> FlatNode<T> {
+    /// Value of `split_dim` marking a leaf
+    pub const LEAF: u32 = u32::MAX;
+
+    /// Returns whether this node is a leaf.
+    pub fn is_leaf(&self) -> bool {
+        self.split_dim == Self::LEAF
+    }
+}
+
+/// A [`KDTree`] as plain arrays of plain values, see [`KDTree::export_flat`]
+///
+/// The root is the first node.
+/// The coordinates of point `i` in traversal order are `points[i * dim..(i + 1) * dim]`,
+/// and its index in the original point cloud is `indices[i]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlatTree<T> {
+    /// number of dimensions of the points
+    pub dim: u32,
+    /// bucket size with which the tree was built
+    pub bucket_size: u32,
+    /// nodes, the root first
+    pub nodes: Vec<FlatNode<T>>,
+    /// coordinates of the points in traversal order, `dim` per point
+    pub points: Vec<T>,
+    /// index in the original point cloud of each point in traversal order
+    pub indices: Vec<u32>,
+    /// identifier of each point in traversal order, if the tree has identifiers, see [`KDTree::id_of`]
+    pub ids: Option<Vec<u64>>,
+    /// dimension and external indices sorted by coordinate along it, then by index,
+    /// of each auxiliary index of the tree, see [`KDTree::build_axis_index`]
+    pub axis_indices: Vec<(u32, Vec<u32>)>,
+    /// rotation of the points, row-major, if the tree was built with
+    /// [`BuildOptions::rotation_seed`](crate::BuildOptions::rotation_seed), see [`KDTree::rotation`]
+    ///
+    /// The points and split values are in the rotated frame, so the queries of a [`FlatTreeView`](crate::FlatTreeView)
+    /// must be rotated by it.
+    pub rotation: Option<Vec<T>>,
+    /// coordinates of the points as given to the tree, `dim` per point by index in the original point cloud,
+    /// if it is rotated, so that the imported tree returns them exactly
+    ///
+    /// If `None` for a rotated tree, they are computed by rotating the points back, up to rounding.
+    pub original_points: Option<Vec<T>>,
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Exports the structure of the tree to plain arrays, see [`FlatTree`] for the layout.
+    ///
+    /// If the tree is rotated, the points and split values are in its frame, see [`Self::rotation`],
+    /// and the points as given are exported in [`FlatTree::original_points`].
+    pub fn export_flat(&self) -> FlatTree<T> {
+        let nodes = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| {
+                node.dispatch_on_type(
+                    (),
+                    |_, split_dim, split_val, right_child| FlatNode {
+                        split_dim,
+                        first: index as u32 + 1,
+                        second: right_child,
+                        reserved: 0,
+                        split_val: split_val.into_inner(),
+                    },
+                    |_, bucket_start_index, bucket_size| FlatNode {
+                        split_dim: FlatNode::<T>::LEAF,
+                        first: bucket_start_index,
+                        second: bucket_start_index + bucket_size,
+                        reserved: 0,
+                        split_val: T::zero(),
+                    },
+                )
+            })
+            .collect();
+        FlatTree {
+            dim: P::DIM,
+            bucket_size: self.options.bucket_size,
+            nodes,
+            points: self.points.iter().map(|value| value.into_inner()).collect(),
+            indices: self.indices.clone(),
+            ids: self.ids.clone(),
+            axis_indices: self.exported_axis_indices(),
+            rotation: self.rotation.clone(),
+            original_points: self
+                .originals
+                .as_ref()
+                .map(|originals| originals.iter().map(|value| value.into_inner()).collect()),
+        }
+    }
+}
+
+impl<T: Scalar> FlatTree<T> {
+    /// Rebuilds a [`KDTree`] from its exported structure, without repartitioning the points.
+    ///
+    /// Returns [`BuildError::InvalidFlatTree`] if the arrays do not describe a tree of points of type `P`,
+    /// with the left child of every split node being the next node and the ranges of the leaves following
+    /// each other in the order of the nodes, as produced by [`KDTree::export_flat`].
+    /// Points on the wrong side of a split are allowed, and handled as after [`KDTree::refit`].
+    /// Returns [`BuildError::TooDeep`] if a path from the root has more than
+    /// [`BuildOptions::MAX_SAFE_DEPTH`] splits, as a build would, before recursing through the nodes.
+    pub fn import<P: Point<T>>(&self) -> Result<KDTree<T, P>, BuildError> {
+        use BuildError::InvalidFlatTree;
+        if self.dim != P::DIM {
+            return Err(InvalidFlatTree(
+                "the dimension differs from the one of the points",
+            ));
+        }
+        if self.bucket_size < 2 {
+            return Err(BuildError::BucketSizeTooSmall(self.bucket_size));
+        }
+        let count = self.indices.len();
+        if count > u32::MAX as usize {
+            return Err(BuildError::TooManyPoints(count));
+        }
+        if self.points.len() != count * P::DIM as usize {
+            return Err(InvalidFlatTree(
+                "the number of coordinates differs from the number of indices",
+            ));
+        }
+        if self.ids.as_ref().map_or(false, |ids| ids.len() != count) {
+            return Err(InvalidFlatTree(
+                "the number of identifiers differs from the number of indices",
+            ));
+        }
+        if let Some(rotation) = &self.rotation {
+            if rotation.len() != P::DIM as usize * P::DIM as usize {
+                return Err(InvalidFlatTree(
+                    "the rotation does not have one entry per pair of dimensions",
+                ));
+            }
+            if rotation.iter().any(|entry| !entry.is_finite()) {
+                return Err(InvalidFlatTree("an entry of the rotation is not finite"));
+            }
+        }
+        let originals = match (&self.rotation, &self.original_points) {
+            // computed once the tree is imported
+            (_, None) => None,
+            (None, Some(_)) => {
+                return Err(InvalidFlatTree(
+                    "original points are given for a tree that is not rotated",
+                ))
+            }
+            (Some(_), Some(original_points)) => {
+                if original_points.len() != count * P::DIM as usize {
+                    return Err(InvalidFlatTree(
+                        "the number of original coordinates differs from the number of indices",
+                    ));
+                }
+                let mut originals = Vec::with_capacity(original_points.len());
+                for (position, value) in original_points.iter().enumerate() {
+                    match NotNan::new(*value) {
+                        Ok(value) if value.is_finite() => originals.push(value),
+                        _ => {
+                            return Err(BuildError::NonFiniteCoordinate {
+                                index: (position / P::DIM as usize) as u32,
+                            })
+                        }
+                    }
+                }
+                Some(originals)
+            }
+        };
+        let mut seen = BitSet::with_len(count);
+        for index in &self.indices {
+            if *index as usize >= count || seen.contains(*index as usize) {
+                return Err(InvalidFlatTree("the indices are not a permutation"));
+            }
+            seen.insert(*index as usize);
+        }
+        let mut points = Vec::with_capacity(self.points.len());
+        for (position, value) in self.points.iter().enumerate() {
+            match NotNan::new(*value) {
+                Ok(value) if value.is_finite() => points.push(value),
+                _ => {
+                    return Err(BuildError::NonFiniteCoordinate {
+                        index: self.indices[position / P::DIM as usize],
+                    })
+                }
+            }
+        }
+        if self.nodes.is_empty() {
+            return Err(InvalidFlatTree("there is no root node"));
+        }
+        if self.nodes.len() - 1 > P::MAX_NODE_COUNT as usize {
+            return Err(BuildError::TooManyNodes {
+                node_count: self.nodes.len(),
+                max_node_count: P::MAX_NODE_COUNT,
+            });
+        }
+        // every node except the root must be the child of exactly one node of smaller index,
+        // which makes the nodes a tree
+        let mut has_parent = BitSet::with_len(self.nodes.len());
+        // the depth of each node, known before the node is reached as its parent has a smaller index
+        let mut depths = alloc::vec![0u32; self.nodes.len()];
+        let mut next_point = 0;
+        let mut nodes = Vec::with_capacity(self.nodes.len());
+        for (index, node) in self.nodes.iter().enumerate() {
+            if node.is_leaf() {
+                if node.first != next_point || node.second < node.first {
+                    return Err(InvalidFlatTree(
+                        "the ranges of the leaves do not follow each other",
+                    ));
+                }
+                if node.second as usize > count {
+                    return Err(InvalidFlatTree("the range of a leaf is out of bounds"));
+                }
+                let bucket_size = node.second - node.first;
+                if bucket_size > P::MAX_NODE_COUNT {
+                    return Err(InvalidFlatTree("a leaf has too many points"));
+                }
+                next_point = node.second;
+                nodes.push(Node::new_leaf_node(node.first, bucket_size));
+            } else {
+                if node.split_dim >= P::DIM {
+                    return Err(InvalidFlatTree("a split dimension is out of bounds"));
+                }
+                if node.first as usize != index + 1 {
+                    return Err(InvalidFlatTree("a left child is not the next node"));
+                }
+                if node.second <= node.first || node.second as usize >= self.nodes.len() {
+                    return Err(InvalidFlatTree("a right child is out of bounds"));
+                }
+                // the drift and the checks below recurse once per split, as the queries
+                if depths[index] >= BuildOptions::MAX_SAFE_DEPTH {
+                    return Err(BuildError::TooDeep {
+                        max_depth: BuildOptions::MAX_SAFE_DEPTH,
+                    });
+                }
+                for child in [node.first, node.second] {
+                    if has_parent.contains(child as usize) {
+                        return Err(InvalidFlatTree("a node has several parents"));
+                    }
+                    has_parent.insert(child as usize);
+                    depths[child as usize] = depths[index] + 1;
+                }
+                let split_val = match NotNan::new(node.split_val) {
+                    Ok(split_val) if split_val.is_finite() => split_val,
+                    _ => return Err(InvalidFlatTree("a split value is not finite")),
+                };
+                let mut split_node = Node::new_split_node(node.split_dim, split_val);
+                split_node.set_child_index(node.second);
+                nodes.push(split_node);
+            }
+        }
+        if next_point as usize != count {
+            return Err(InvalidFlatTree("the leaves do not cover all points"));
+        }
+        if has_parent.count() != self.nodes.len() - 1 {
+            return Err(InvalidFlatTree("a node is not reachable from the root"));
+        }
+
+        let mut tree = KDTree {
+            options: BuildOptions {
+                bucket_size: self.bucket_size,
+                ..BuildOptions::default()
+            },
+            nodes,
+            points,
+            indices: self.indices.clone(),
+            internal_indices: Vec::new(),
+            min_bounds: Vec::new(),
+            max_bounds: Vec::new(),
+            scale: NotNan::one(),
+            drift: NotNan::<T>::zero(),
+            scaled_drift: NotNan::<T>::zero(),
+            subtree_sums: None,
+            ids: self.ids.clone(),
+            axis_indices: Vec::new(),
+            rotation: self.rotation.clone(),
+            originals,
+            generation: 0,
+            coarse: None,
+            timestamps: None,
+        };
+        tree.update_internal_indices();
+        if tree.rotation.is_some() && tree.originals.is_none() {
+            let mut originals = Vec::with_capacity(tree.points.len());
+            for external in 0..count as u32 {
+                let internal = tree.internalise_index(external);
+                let point: P = tree.point_from_tree_frame(tree.stored_coordinates(internal));
+                originals.extend((0..P::DIM).map(|i| point.get(i)));
+            }
+            tree.originals = Some(originals);
+        }
+        let mut lower = DimBuffer::new(P::DIM as usize, NotNan::<T>::min_value());
+        let mut upper = DimBuffer::new(P::DIM as usize, NotNan::<T>::max_value());
+        tree.drift = tree.recurse_drift(0, &mut lower, &mut upper);
+        tree.update_bounds_and_scale();
+        tree.import_axis_indices(&self.axis_indices)
+            .map_err(InvalidFlatTree)?;
+        debug_assert_eq!(tree.self_check(SELF_CHECK_SAMPLE, 0), Ok(()));
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn export_import_round_trip() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let tree = KDTree::new(&cloud);
+        let flat = tree.export_flat();
+        assert_eq!(flat.dim, 2);
+        assert_eq!(flat.points.len(), 2000);
+        assert_eq!(flat.nodes[0].first, 1);
+        let leaf_points: u32 = flat
+            .nodes
+            .iter()
+            .filter(|node| node.is_leaf())
+            .map(|node| node.second - node.first)
+            .sum();
+        assert_eq!(leaf_points, 1000);
+        let imported = flat.import::<P2>().unwrap();
+        assert_eq!(imported, tree);
+        assert_eq!(imported.drift_estimate(), 0.0);
+        assert_eq!(imported.export_flat(), flat);
+        let empty = KDTree::<f32, P2>::new(&[]).export_flat();
+        assert!(empty.import::<P2>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn import_keeps_refitted_trees_exact() {
+        let cloud: Vec<P2> = random_point_cloud(500);
+        let mut tree = KDTree::new(&cloud);
+        let moved: Vec<P2> = cloud
+            .iter()
+            .map(|p| P2::new2d(*p.get(1), *p.get(0)))
+            .collect();
+        tree.refit(&moved);
+        let imported = tree.export_flat().import::<P2>().unwrap();
+        assert_eq!(imported.drift_estimate(), tree.drift_estimate());
+        for query in &cloud[..20] {
+            assert_eq!(imported.knn(3, query), KDTree::new(&moved).knn(3, query));
+        }
+    }
+
+    /// Returns a tree of `splits` split nodes whose left children are split nodes, down a single path
+    fn chain(splits: u32) -> FlatTree<f32> {
+        let mut nodes: Vec<FlatNode<f32>> = (0..splits)
+            .map(|index| FlatNode {
+                split_dim: 0,
+                first: index + 1,
+                second: 2 * splits - index,
+                reserved: 0,
+                split_val: 0.0,
+            })
+            .collect();
+        // the deepest leaf, then the right children from the deepest
+        nodes.extend((0..=splits).map(|point| FlatNode {
+            split_dim: FlatNode::<f32>::LEAF,
+            first: point,
+            second: point + 1,
+            reserved: 0,
+            split_val: 0.0,
+        }));
+        FlatTree {
+            dim: 2,
+            bucket_size: 2,
+            nodes,
+            points: vec![0.0; 2 * (splits as usize + 1)],
+            indices: (0..=splits).collect(),
+            ids: None,
+            axis_indices: Vec::new(),
+            rotation: None,
+            original_points: None,
+        }
+    }
+
+    #[test]
+    fn import_rejects_trees_deeper_than_queries_can_recurse() {
+        let max_depth = BuildOptions::MAX_SAFE_DEPTH;
+        let tree = chain(max_depth).import::<P2>().unwrap();
+        assert_eq!(tree.len(), max_depth as usize + 1);
+        let too_deep = Some(BuildError::TooDeep { max_depth });
+        assert_eq!(chain(max_depth + 1).import::<P2>().err(), too_deep);
+        // rejected before any recursive pass could overflow the stack
+        assert_eq!(chain(200_000).import::<P2>().err(), too_deep);
+    }
+
+    #[test]
+    fn import_rejects_invalid_trees() {
+        let cloud: Vec<P2> = random_point_cloud(100);
+        let flat = KDTree::new(&cloud).export_flat();
+        let invalid = |change: fn(&mut FlatTree<f32>)| {
+            let mut flat = flat.clone();
+            change(&mut flat);
+            flat.import::<P2>().unwrap_err()
+        };
+        assert!(matches!(
+            flat.import::<P3>().unwrap_err(),
+            BuildError::InvalidFlatTree(_)
+        ));
+        assert_eq!(
+            invalid(|flat| flat.points[3] = f32::NAN),
+            BuildError::NonFiniteCoordinate {
+                index: flat.indices[1]
+            }
+        );
+        assert!(matches!(
+            invalid(|flat| flat.indices[0] = flat.indices[1]),
+            BuildError::InvalidFlatTree(_)
+        ));
+        assert!(matches!(
+            invalid(|flat| flat.nodes[0].second = 0),
+            BuildError::InvalidFlatTree(_)
+        ));
+        assert!(matches!(
+            invalid(|flat| flat.nodes[0].split_dim = 2),
+            BuildError::InvalidFlatTree(_)
+        ));
+        assert!(matches!(
+            invalid(|flat| {
+                let last = flat.nodes.len() - 1;
+                flat.nodes[last].second -= 1;
+            }),
+            BuildError::InvalidFlatTree(_)
+        ));
+        assert!(matches!(
+            invalid(|flat| flat.nodes.truncate(1)),
+            BuildError::InvalidFlatTree(_)
+        ));
+    }
+}
@@ -0,0 +1,455 @@
+//! Queries on borrowed flat arrays without allocating, for example on a tree stored in flash
+
+use core::{fmt, mem};
+
+use ordered_float::NotNan;
+
+use crate::distance_scale::distance_scale;
+use crate::{FlatNode, FlatTree, Scalar};
+
+/// Bytes between the values read when prefetching, no larger than the pages of common platforms
+const PREFETCH_STRIDE: usize = 4096;
+
+/// A neighbour found by [`FlatTreeView::knn_into`]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FlatNeighbour<T> {
+    /// the index of the point in the original point cloud
+    pub index: u32,
+    /// the squared distance to the point, saturating to `T::max_value()` on overflow
+    pub dist2: T,
+}
+
+/// A node whose subtree remains to be searched by [`FlatTreeView::knn_into`]
+///
+/// Frames are opaque, the caller only provides storage for them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TraversalFrame<T> {
+    node: u32,
+    lower_bound2: T,
+}
+
+/// The arrays of a tree that [`FlatTreeView::prefetch`] touches
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum PrefetchLevel {
+    /// the nodes, which all searches traverse
+    #[default]
+    Nodes,
+    /// the nodes and the coordinates of the points, which the searches scan in the leaves they reach
+    Points,
+    /// the nodes, the coordinates and the indices of the points, which the searches return
+    All,
+}
+
+/// The stack passed to [`FlatTreeView::knn_into`] is too small for the tree
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StackOverflow {
+    /// the number of frames of the stack passed
+    pub capacity: usize,
+}
+
+impl fmt::Display for StackOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "A traversal stack of {} frames is too small for the tree",
+            self.capacity
+        )
+    }
+}
+
+/// A [`FlatTree`] borrowed as slices, for instance from deserialized memory, queried without allocating
+///
+/// The arrays have the layout of [`FlatTree`].
+/// They are not validated, as [`FlatTree::import`] does: invalid arrays lead to meaningless results,
+/// panics or searches that do not terminate, but never to reads out of bounds.
+#[derive(Clone, Copy, Debug)]
+pub struct FlatTreeView<'a, T> {
+    dim: u32,
+    nodes: &'a [FlatNode<T>],
+    points: &'a [T],
+    indices: &'a [u32],
+    /// factor of the coordinate differences in distances, `None` for one
+    scale: Option<T>,
+}
+
+impl<'a, T: Scalar> FlatTreeView<'a, T> {
+    /// Creates a view of the arrays of a tree of `dim` dimensions, see [`FlatTree`] for their layout.
+    ///
+    /// The coordinate differences are not scaled, so distances may overflow or underflow
+    /// for coordinates of extreme magnitudes, see [`Self::with_distance_scale`].
+    /// This is a `const fn`, so that a view of arrays in a `static` is itself a `static`,
+    /// as emitted by `FlatTree::to_rust_source` with the `codegen` feature.
+    pub const fn new(
+        dim: u32,
+        nodes: &'a [FlatNode<T>],
+        points: &'a [T],
+        indices: &'a [u32],
+    ) -> Self {
+        FlatTreeView {
+            dim,
+            nodes,
+            points,
+            indices,
+            scale: None,
+        }
+    }
+
+    /// Creates a view as [`Self::new`], whose searches multiply coordinate differences by `scale`,
+    /// which must be the [`FlatTree::distance_scale`] of the arrays, as [`KDTree`](crate::KDTree) does.
+    ///
+    /// The returned distances are those of the points, without the scale.
+    pub const fn with_distance_scale(
+        dim: u32,
+        nodes: &'a [FlatNode<T>],
+        points: &'a [T],
+        indices: &'a [u32],
+        scale: T,
+    ) -> Self {
+        FlatTreeView {
+            dim,
+            nodes,
+            points,
+            indices,
+            scale: Some(scale),
+        }
+    }
+
+    /// Finds the nearest neighbours of `query`, as many as `out` holds, and returns how many were found.
+    ///
+    /// `out[..count]` is sorted by increasing distance, and self matches are allowed.
+    /// The search needs at most [`FlatTree::depth`] frames of `stack`,
+    /// and returns [`StackOverflow`] if `stack` is too small.
+    /// The cells of the nodes are bounded by their largest distance to the query along a single axis,
+    /// which prunes less than the queries of [`KDTree`](crate::KDTree) but needs no buffer of `dim` offsets.
+    ///
+    /// Panics if `query` does not have `dim` coordinates or has NaN coordinates.
+    pub fn knn_into(
+        &self,
+        query: &[T],
+        out: &mut [FlatNeighbour<T>],
+        stack: &mut [TraversalFrame<T>],
+    ) -> Result<usize, StackOverflow> {
+        assert_eq!(
+            query.len(),
+            self.dim as usize,
+            "query does not have the dimension of the tree"
+        );
+        assert!(
+            query.iter().all(|value| !value.is_nan()),
+            "query has NaN coordinates"
+        );
+        let k = out.len();
+        if k == 0 || self.nodes.is_empty() {
+            return Ok(0);
+        }
+        let dim = self.dim as usize;
+        let scale = self.scale.unwrap_or_else(T::one);
+        let mut count = 0;
+        let mut stack_len = 0;
+        let mut frame = TraversalFrame {
+            node: 0,
+            lower_bound2: T::zero(),
+        };
+        loop {
+            let furthest_dist2 = |count: usize, out: &[FlatNeighbour<T>]| {
+                if count < k {
+                    T::infinity()
+                } else {
+                    out[k - 1].dist2
+                }
+            };
+            if frame.lower_bound2 <= furthest_dist2(count, out) {
+                let mut node = self.nodes[frame.node as usize];
+                while !node.is_leaf() {
+                    let diff = (query[node.split_dim as usize] - node.split_val) * scale;
+                    let (near, far) = if diff <= T::zero() {
+                        (node.first, node.second)
+                    } else {
+                        (node.second, node.first)
+                    };
+                    let far_bound2 = frame.lower_bound2.max(diff * diff);
+                    if far_bound2 <= furthest_dist2(count, out) {
+                        let capacity = stack.len();
+                        let slot = stack.get_mut(stack_len).ok_or(StackOverflow { capacity })?;
+                        *slot = TraversalFrame {
+                            node: far,
+                            lower_bound2: far_bound2,
+                        };
+                        stack_len += 1;
+                    }
+                    node = self.nodes[near as usize];
+                }
+                for point_index in node.first as usize..node.second as usize {
+                    let point = &self.points[point_index * dim..(point_index + 1) * dim];
+                    let mut dist2 = T::zero();
+                    for (q, p) in query.iter().zip(point) {
+                        let diff = (*q - *p) * scale;
+                        dist2 += diff * diff;
+                    }
+                    if count == k && dist2 >= out[k - 1].dist2 {
+                        continue;
+                    }
+                    let mut i = if count < k {
+                        count += 1;
+                        count - 1
+                    } else {
+                        k - 1
+                    };
+                    while i > 0 && out[i - 1].dist2 > dist2 {
+                        out[i] = out[i - 1];
+                        i -= 1;
+                    }
+                    out[i] = FlatNeighbour {
+                        index: self.indices[point_index],
+                        dist2,
+                    };
+                }
+            }
+            if stack_len == 0 {
+                for neighbour in &mut out[..count] {
+                    neighbour.dist2 = unscale(neighbour.dist2, scale);
+                }
+                return Ok(count);
+            }
+            stack_len -= 1;
+            frame = stack[stack_len];
+        }
+    }
+}
+
+impl<'a, T: Scalar> FlatTreeView<'a, T> {
+    /// Reads the arrays of `level` once, in storage order, and returns the number of bytes they span.
+    ///
+    /// For arrays in memory mapped from a file, this pages them in before the first queries,
+    /// which would otherwise each wait for the pages they reach.
+    /// One value is read every 4 KiB, so that each page is touched once; other memory is only brought
+    /// closer to the processor. The results of the searches do not change.
+    pub fn prefetch(&self, level: PrefetchLevel) -> usize {
+        let mut bytes = touch(self.nodes);
+        if level != PrefetchLevel::Nodes {
+            bytes += touch(self.points);
+        }
+        if level == PrefetchLevel::All {
+            bytes += touch(self.indices);
+        }
+        bytes
+    }
+
+    /// Reads the nodes whose cell overlaps the axis-aligned box from `min` to `max`, bounds included,
+    /// and the coordinates and indices of the points of the leaves among them,
+    /// returning the number of bytes they span, see [`Self::prefetch`].
+    ///
+    /// The nodes are pruned by their split values as [`KDTree::range_query`](crate::KDTree::range_query)
+    /// prunes them, so this warms up the memory that queries in the box read.
+    ///
+    /// Panics if `min` or `max` does not have `dim` coordinates.
+    pub fn prefetch_for_region(&self, min: &[T], max: &[T]) -> usize {
+        let dim = self.dim as usize;
+        assert!(
+            min.len() == dim && max.len() == dim,
+            "region does not have the dimension of the tree"
+        );
+        if self.nodes.is_empty() {
+            return 0;
+        }
+        self.prefetch_subtree(0, min, max)
+    }
+
+    fn prefetch_subtree(&self, node: u32, min: &[T], max: &[T]) -> usize {
+        let flat_node = &self.nodes[node as usize];
+        let mut bytes = touch(core::slice::from_ref(flat_node));
+        if flat_node.is_leaf() {
+            let dim = self.dim as usize;
+            let (start, end) = (flat_node.first as usize, flat_node.second as usize);
+            bytes += touch(&self.points[start * dim..end * dim]);
+            bytes += touch(&self.indices[start..end]);
+        } else {
+            let split_dim = flat_node.split_dim as usize;
+            if min[split_dim] <= flat_node.split_val {
+                bytes += self.prefetch_subtree(flat_node.first, min, max);
+            }
+            if max[split_dim] >= flat_node.split_val {
+                bytes += self.prefetch_subtree(flat_node.second, min, max);
+            }
+        }
+        bytes
+    }
+}
+
+/// Reads one value of `values` in each span of [`PREFETCH_STRIDE`] bytes, and the last one,
+/// returning the number of bytes of `values`
+fn touch<V: Copy>(values: &[V]) -> usize {
+    let stride = (PREFETCH_STRIDE / mem::size_of::<V>().max(1)).max(1);
+    for value in values.iter().step_by(stride).chain(values.last()) {
+        // SAFETY: `value` is a reference to an initialized value, so it is valid for reads;
+        // the volatile read is not removed although its result is unused
+        unsafe { core::ptr::read_volatile(value) };
+    }
+    mem::size_of_val(values)
+}
+
+/// Removes the scale of a squared distance, saturating to `T::max_value()` on overflow
+fn unscale<T: Scalar>(dist2: T, scale: T) -> T {
+    let dist2 = (dist2 / scale) / scale;
+    if dist2.is_infinite() {
+        T::max_value()
+    } else {
+        dist2
+    }
+}
+
+impl<T: Scalar> FlatTree<T> {
+    /// Returns a view of the arrays of this tree, to query it without allocating.
+    ///
+    /// The view scales distances by [`Self::distance_scale`], computed here in time linear
+    /// in the number of points.
+    pub fn view(&self) -> FlatTreeView<'_, T> {
+        FlatTreeView::with_distance_scale(
+            self.dim,
+            &self.nodes,
+            &self.points,
+            &self.indices,
+            self.distance_scale(),
+        )
+    }
+
+    /// Returns the power of two by which searches multiply coordinate differences,
+    /// so that squared distances between the points neither overflow nor underflow.
+    ///
+    /// It is one for coordinates whose distances are safe to square, as those of most clouds.
+    pub fn distance_scale(&self) -> T {
+        let max_abs =
+            self.points
+                .iter()
+                .map(|value| value.abs())
+                .fold(
+                    T::zero(),
+                    |max_abs, value| if value > max_abs { value } else { max_abs },
+                );
+        NotNan::new(max_abs).map_or_else(
+            |_| T::one(),
+            |max_abs| distance_scale(max_abs, self.dim).into_inner(),
+        )
+    }
+
+    /// Returns the largest number of split nodes on a path from the root to a leaf,
+    /// which is the number of frames that [`FlatTreeView::knn_into`] needs at most.
+    ///
+    /// The nodes must be valid, as accepted by [`FlatTree::import`].
+    pub fn depth(&self) -> u32 {
+        // the nodes are in depth-first order, so the depth of each node follows from its parent
+        let mut depths = alloc::vec![0u32; self.nodes.len()];
+        let mut max_depth = 0;
+        for (index, node) in self.nodes.iter().enumerate() {
+            if !node.is_leaf() {
+                let child_depth = depths[index] + 1;
+                depths[node.first as usize] = child_depth;
+                depths[node.second as usize] = child_depth;
+                max_depth = max_depth.max(child_depth);
+            }
+        }
+        max_depth
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn view_queries_match_tree_queries() {
+        let cloud: Vec<P3> = random_point_cloud(3000);
+        let tree = KDTree::new(&cloud);
+        let flat = tree.export_flat();
+        let view = flat.view();
+        let depth = flat.depth() as usize;
+        assert!(depth > 0);
+        let mut stack = vec![TraversalFrame::default(); depth];
+        for k in [1, 7, 40] {
+            for _ in 0..50 {
+                let query: P3 = random_point();
+                let coordinates = [*query.get(0), *query.get(1), *query.get(2)];
+                let mut out = vec![FlatNeighbour::default(); k];
+                let count = view.knn_into(&coordinates, &mut out, &mut stack).unwrap();
+                let expected = tree.knn(k as u32, &query);
+                assert_eq!(count, expected.len());
+                for (found, expected) in out.iter().zip(&expected) {
+                    assert!((found.dist2 - *expected.dist2).abs() <= 1e-3 * *expected.dist2);
+                }
+            }
+        }
+        let mut out = [FlatNeighbour::default(); 5];
+        assert_eq!(
+            view.knn_into(&[0.0, 0.0, 0.0], &mut out, &mut []),
+            Err(StackOverflow { capacity: 0 })
+        );
+        // more neighbours than points
+        let small = KDTree::new(&cloud[..3]).export_flat();
+        assert_eq!(small.depth(), 0);
+        assert_eq!(small.view().knn_into(&[0.0; 3], &mut out, &mut []), Ok(3));
+    }
+
+    #[test]
+    fn view_queries_are_scaled_like_tree_queries() {
+        let cloud: Vec<P2> = random_point_cloud(400);
+        let queries: Vec<P2> = random_point_cloud(20);
+        for factor in [2f32.powi(-100), 1e20] {
+            let scaled: Vec<P2> = cloud.iter().map(|p| *p * factor).collect();
+            let tree = KDTree::new(&scaled);
+            let flat = tree.export_flat();
+            assert_ne!(flat.distance_scale(), 1.0);
+            let view = flat.view();
+            let mut stack = vec![TraversalFrame::default(); flat.depth() as usize];
+            for query in &queries {
+                let query = *query * factor;
+                let mut out = [FlatNeighbour::default(); 5];
+                let count = view
+                    .knn_into(&[*query.get(0), *query.get(1)], &mut out, &mut stack)
+                    .unwrap();
+                let expected = tree.knn(5, &query);
+                assert_eq!(count, expected.len());
+                for (found, expected) in out.iter().zip(&expected) {
+                    assert_eq!(found.index, expected.index);
+                    assert_eq!(found.dist2, *expected.dist2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn prefetching_reports_the_bytes_and_keeps_the_results() {
+        let cloud: Vec<P2> = random_point_cloud(5000);
+        let tree = KDTree::new(&cloud);
+        let flat = tree.export_flat();
+        let view = flat.view();
+        let node_bytes = flat.nodes.len() * mem::size_of::<FlatNode<f32>>();
+        let point_bytes = flat.points.len() * mem::size_of::<f32>();
+        let index_bytes = flat.indices.len() * mem::size_of::<u32>();
+        let mut stack = vec![TraversalFrame::default(); flat.depth() as usize];
+        let query = [3.0, -7.0];
+        let mut before = [FlatNeighbour::default(); 8];
+        view.knn_into(&query, &mut before, &mut stack).unwrap();
+        assert_eq!(view.prefetch(PrefetchLevel::Nodes), node_bytes);
+        assert_eq!(
+            view.prefetch(PrefetchLevel::Points),
+            node_bytes + point_bytes
+        );
+        let all = node_bytes + point_bytes + index_bytes;
+        assert_eq!(view.prefetch(PrefetchLevel::All), all);
+        // a region holding all points reaches all nodes, a small one only some of them
+        assert_eq!(view.prefetch_for_region(&[-1e6, -1e6], &[1e6, 1e6]), all);
+        let region = view.prefetch_for_region(&[0.0, 0.0], &[10.0, 10.0]);
+        assert!(region > mem::size_of::<FlatNode<f32>>() && region < all / 10);
+        let far = view.prefetch_for_region(&[1e5, 1e5], &[2e5, 2e5]);
+        assert!(far < region);
+        let mut after = [FlatNeighbour::default(); 8];
+        view.knn_into(&query, &mut after, &mut stack).unwrap();
+        assert_eq!(after, before);
+        // an empty tree has a single leaf, without points
+        let empty = KDTree::<f32, P2>::new(&[]).export_flat();
+        let empty_bytes = empty.view().prefetch_for_region(&[0.0; 2], &[1.0; 2]);
+        assert_eq!(empty_bytes, mem::size_of::<FlatNode<f32>>());
+    }
+}
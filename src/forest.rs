@@ -0,0 +1,245 @@
+//! Randomised kd-forest for approximate search in high-dimensional spaces
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+use core::cmp::Reverse;
+
+use num_traits::Zero;
+use ordered_float::NotNan;
+
+use crate::heap::CandidateHeap;
+use crate::rng::SplitMix64;
+use crate::{
+    point_slice_dist2, BitSet, BuildError, BuildOptions, CandidateCollector, HighDimension,
+    InternalNeighbour, KDTree, Neighbour, Point, QueryStats, Scalar,
+};
+
+/// A set of KD-Trees with randomised splits, searched simultaneously for approximate neighbours
+///
+/// A single KD-Tree degrades to an almost linear scan in high dimensions, as most cells must be visited
+/// to prove that a neighbour is the nearest.
+/// Following FLANN, each tree of the forest splits at the mean of a dimension drawn among those of
+/// largest variance, so the trees partition the space differently.
+/// They are searched best-bin-first with a single candidate collector, and the search stops after
+/// a fixed number of distance computations, which trades accuracy for speed.
+pub struct KDForest<T: Scalar, P: Point<T>> {
+    trees: Vec<KDTree<T, P>>,
+}
+
+impl<T: Scalar, P: Point<T>> KDForest<T, P> {
+    /// Creates a forest of `n_trees` randomised KD-Trees from a point cloud.
+    ///
+    /// The trees only depend on the point cloud and `seed`.
+    /// Panics if `n_trees` is zero or if the trees cannot be built, see [`Self::try_new`].
+    pub fn new(cloud: &[P], n_trees: u32, seed: u64) -> Self {
+        Self::try_new(cloud, n_trees, seed).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Creates a forest of `n_trees` randomised KD-Trees from a point cloud, or returns an error
+    /// if they cannot be built, as for [`KDTree::try_new`].
+    ///
+    /// Panics if `n_trees` is zero.
+    pub fn try_new(cloud: &[P], n_trees: u32, seed: u64) -> Result<Self, BuildError> {
+        assert!(n_trees > 0, "A forest needs at least one tree");
+        let mut rng = SplitMix64::new(seed);
+        // forests are meant for many dimensions, where the trees are searched approximately
+        let options = BuildOptions {
+            high_dimension: HighDimension::Tree,
+            ..BuildOptions::default()
+        };
+        let trees = (0..n_trees)
+            .map(|_| KDTree::try_build(cloud, &options, Some(&mut rng), None))
+            .collect::<Result<_, _>>()?;
+        Ok(KDForest { trees })
+    }
+
+    /// Returns the number of trees in the forest.
+    pub fn n_trees(&self) -> usize {
+        self.trees.len()
+    }
+
+    /// Returns the trees of the forest.
+    pub fn trees(&self) -> &[KDTree<T, P>] {
+        &self.trees
+    }
+
+    /// Returns the number of points in the forest.
+    pub fn len(&self) -> usize {
+        self.trees[0].len()
+    }
+
+    /// Returns whether the forest contains no point.
+    pub fn is_empty(&self) -> bool {
+        self.trees[0].is_empty()
+    }
+
+    /// Finds approximately the `k` nearest neighbours of `query`, sorted by increasing distance.
+    ///
+    /// The search computes the distances of at least `max_checks` distinct points, unless
+    /// it can prove that no further cell is closer, and then stops as soon as it has `k` candidates.
+    /// A larger `max_checks` returns the true neighbours more often, at the cost of speed.
+    /// If there are less than `k` points in the point cloud, the returned vector will be smaller than `k`.
+    /// Panics if `query` has NaN coordinates.
+    pub fn knn(&self, k: u32, query: &P, max_checks: u32) -> Vec<Neighbour<T, P>> {
+        self.knn_counted(k, query, max_checks).0
+    }
+
+    /// Finds approximately the `k` nearest neighbours of `query`, see [`Self::knn`],
+    /// with counters of the work done and whether the search stopped because of `max_checks`
+    pub(crate) fn knn_counted(
+        &self,
+        k: u32,
+        query: &P,
+        max_checks: u32,
+    ) -> (Vec<Neighbour<T, P>>, QueryStats, bool) {
+        let reference = &self.trees[0];
+        let query = reference.query_coordinates(query);
+        let k = k.min(self.len() as u32);
+        let mut stats = QueryStats::default();
+        if k == 0 {
+            return (Vec::new(), stats, false);
+        }
+        let mut budget_exhausted = false;
+        // candidates are identified by their external index, common to all trees
+        let mut heap = BinaryHeap::<InternalNeighbour<T>>::new_with_k(k);
+        let mut checked = BitSet::with_len(self.len());
+        let mut checks = 0;
+        let mut branches: BinaryHeap<_> = (0..self.trees.len())
+            .map(|tree| Reverse((NotNan::<T>::zero(), tree, 0)))
+            .collect();
+        while let Some(Reverse((rd, tree_index, mut node))) = branches.pop() {
+            if heap
+                .furthest_dist2()
+                .map_or(false, |furthest| rd >= furthest)
+            {
+                break;
+            }
+            if checks >= max_checks && heap.len() == k as usize {
+                budget_exhausted = true;
+                break;
+            }
+            let tree = &self.trees[tree_index];
+            // descend to the closest leaf, queuing the other branches
+            while let Some((split_dim, split_val, right_child)) = tree.nodes[node].dispatch_on_type(
+                (),
+                |_, split_dim, split_val, right_child| Some((split_dim, split_val, right_child)),
+                |_, _, _| None,
+            ) {
+                let off = (query[split_dim as usize] - split_val) * tree.scale;
+                let (near, far) = if off > NotNan::<T>::zero() {
+                    (right_child as usize, node + 1)
+                } else {
+                    (node + 1, right_child as usize)
+                };
+                // as in FLANN, the squared offsets are summed even if along the same dimension
+                let far_rd = rd + off * off;
+                if heap
+                    .furthest_dist2()
+                    .map_or(true, |furthest| far_rd < furthest)
+                {
+                    branches.push(Reverse((far_rd, tree_index, far)));
+                }
+                stats += QueryStats::split();
+                node = near;
+            }
+            let (bucket_start_index, bucket_size) = tree.nodes[node].dispatch_on_type(
+                (),
+                |_, _, _, _| unreachable!("the descent stops at a leaf"),
+                |_, bucket_start_index, bucket_size| (bucket_start_index, bucket_size),
+            );
+            stats += QueryStats::leaf(0);
+            for bucket_index in bucket_start_index..bucket_start_index + bucket_size {
+                let index = tree.indices[bucket_index as usize];
+                if checked.contains(index as usize) {
+                    continue;
+                }
+                checked.insert(index as usize);
+                checks += 1;
+                stats.touched_points += 1;
+                let point_index = bucket_index as usize * P::DIM as usize;
+                let point = &tree.points[point_index..point_index + (P::DIM as usize)];
+                heap.add(point_slice_dist2::<T, P>(&query, point, tree.scale), index);
+            }
+        }
+        let neighbours = heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|neighbour| {
+                reference.externalise_neighbour(InternalNeighbour {
+                    index: reference.internalise_index(neighbour.index),
+                    dist2: neighbour.dist2,
+                })
+            })
+            .collect();
+        (neighbours, stats, budget_exhausted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn recall<const D: usize>(
+        forest: &KDForest<f32, SimplePoint<D>>,
+        tree: &KDTree<f32, SimplePoint<D>>,
+        queries: &[SimplePoint<D>],
+        k: u32,
+        max_checks: u32,
+    ) -> f32 {
+        let mut found = 0;
+        for query in queries {
+            let approx = forest.knn(k, query, max_checks);
+            assert_eq!(approx.len(), k as usize);
+            assert!(approx.windows(2).all(|w| w[0].dist2 <= w[1].dist2));
+            let exact = tree.knn(k, query);
+            found += exact
+                .iter()
+                .filter(|n| approx.iter().any(|a| a.index == n.index))
+                .count();
+        }
+        found as f32 / (queries.len() * k as usize) as f32
+    }
+
+    #[test]
+    fn forest_recall_grows_with_checks() {
+        let cloud: Vec<SimplePoint<16>> = random_point_cloud(5000);
+        let queries: Vec<SimplePoint<16>> = random_point_cloud(50);
+        let tree = KDTree::new(&cloud);
+        let forest = KDForest::new(&cloud, 4, 42);
+        assert_eq!(forest.n_trees(), 4);
+        assert_eq!(forest.len(), 5000);
+        let low = recall(&forest, &tree, &queries, 5, 50);
+        let high = recall(&forest, &tree, &queries, 5, 2000);
+        assert!(low < high, "{} {}", low, high);
+        assert!(high > 0.8, "{}", high);
+    }
+
+    #[test]
+    fn forest_is_almost_exact_in_low_dimensions() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let queries: Vec<P2> = random_point_cloud(50);
+        let tree = KDTree::new(&cloud);
+        let forest = KDForest::new(&cloud, 2, 7);
+        let recall = recall(&forest, &tree, &queries, 3, 1000);
+        assert!(recall > 0.98, "{}", recall);
+        assert!(forest.knn(0, &queries[0], 10).is_empty());
+        assert_eq!(forest.knn(2000, &queries[0], 10).len(), 1000);
+    }
+
+    #[test]
+    fn forest_trees_depend_on_seed() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let a = KDForest::new(&cloud, 2, 1);
+        let b = KDForest::new(&cloud, 2, 1);
+        let c = KDForest::new(&cloud, 2, 2);
+        assert_eq!(a.trees(), b.trees());
+        assert_ne!(a.trees()[0], a.trees()[1]);
+        assert_ne!(a.trees(), c.trees());
+        // randomised trees still return exact results with the usual search
+        let query: P2 = random_point();
+        assert_eq!(
+            a.trees()[0].knn(4, &query),
+            KDTree::new(&cloud).knn(4, &query)
+        );
+    }
+}
@@ -0,0 +1,195 @@
+//! Nearest neighbours on the Earth, by embedding latitudes and longitudes on the unit sphere
+//!
+//! The chord between two points of the sphere grows with their great-circle distance,
+//! so the nearest neighbours in 3D are the nearest on the sphere, across the antimeridian and the poles.
+
+use alloc::vec::Vec;
+
+use num_traits::Float;
+
+use crate::simple_point::P3d;
+use crate::{BuildError, KDTree, QueryError};
+
+/// The mean radius of the Earth in meters, as defined by the IUGG
+pub const MEAN_EARTH_RADIUS: f64 = 6_371_008.8;
+
+/// A neighbour found by [`GeoTree::nearest`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GeoNeighbour {
+    /// the index of the point in the original list of points
+    pub index: u32,
+    /// the great-circle distance to the point, in the units of the radius of the tree
+    pub distance: f64,
+}
+
+/// A tree of geographic points, queried by great-circle distance
+///
+/// Points are `[latitude, longitude]` in degrees, latitudes within `-90..=90`
+/// and longitudes within `-180..=180`.
+#[derive(Clone, Debug)]
+pub struct GeoTree {
+    tree: KDTree<f64, P3d>,
+    earth_radius: f64,
+}
+
+impl GeoTree {
+    /// Creates a tree of `lat_lon_points`, with distances in meters on a sphere of [`MEAN_EARTH_RADIUS`].
+    ///
+    /// Returns [`BuildError::InvalidLatLon`] if a point is outside the valid ranges.
+    pub fn new(lat_lon_points: &[[f64; 2]]) -> Result<Self, BuildError> {
+        let points = lat_lon_points
+            .iter()
+            .enumerate()
+            .map(|(index, [lat, lon])| {
+                unit_vector(*lat, *lon).ok_or(BuildError::InvalidLatLon {
+                    index: index as u32,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(GeoTree {
+            tree: KDTree::try_new(&points)?,
+            earth_radius: MEAN_EARTH_RADIUS,
+        })
+    }
+
+    /// Sets the radius of the sphere, in the units of the distances to return.
+    ///
+    /// Panics if `earth_radius` is not strictly positive and finite.
+    pub fn with_earth_radius(self, earth_radius: f64) -> Self {
+        assert!(
+            earth_radius > 0.0 && earth_radius.is_finite(),
+            "the radius must be strictly positive and finite"
+        );
+        GeoTree {
+            earth_radius,
+            ..self
+        }
+    }
+
+    /// Returns the radius of the sphere.
+    pub fn earth_radius(&self) -> f64 {
+        self.earth_radius
+    }
+
+    /// Returns the number of points of the tree.
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// Returns whether the tree has no points.
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Returns the underlying tree of the points on the unit sphere.
+    pub fn tree(&self) -> &KDTree<f64, P3d> {
+        &self.tree
+    }
+
+    /// Finds the `k` nearest points to (`lat`, `lon`), sorted by increasing great-circle distance.
+    ///
+    /// Returns [`QueryError::InvalidLatLon`] if the query is outside the valid ranges.
+    pub fn nearest(&self, lat: f64, lon: f64, k: u32) -> Result<Vec<GeoNeighbour>, QueryError> {
+        let query = unit_vector(lat, lon).ok_or(QueryError::InvalidLatLon)?;
+        Ok(self
+            .tree
+            .knn(k, &query)
+            .into_iter()
+            .map(|neighbour| GeoNeighbour {
+                index: neighbour.index,
+                distance: self.earth_radius * chord_to_angle(Float::sqrt(*neighbour.dist2)),
+            })
+            .collect())
+    }
+}
+
+/// Returns the point of the unit sphere at `lat`, `lon` in degrees, if they are within their ranges
+fn unit_vector(lat: f64, lon: f64) -> Option<P3d> {
+    // the comparisons are false for NaN
+    if !((-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon)) {
+        return None;
+    }
+    let (sin_lat, cos_lat) = Float::sin_cos(lat.to_radians());
+    let (sin_lon, cos_lon) = Float::sin_cos(lon.to_radians());
+    Some(P3d::from([cos_lat * cos_lon, cos_lat * sin_lon, sin_lat]))
+}
+
+/// Returns the angle between two points of the unit sphere separated by `chord`
+fn chord_to_angle(chord: f64) -> f64 {
+    // rounding may make the chord of antipodes slightly longer than the diameter
+    2.0 * Float::asin(Float::min(chord / 2.0, 1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn haversine(a: [f64; 2], b: [f64; 2]) -> f64 {
+        let (lat_a, lat_b) = (a[0].to_radians(), b[0].to_radians());
+        let d_lat = lat_b - lat_a;
+        let d_lon = (b[1] - a[1]).to_radians();
+        let h =
+            (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * MEAN_EARTH_RADIUS * h.sqrt().asin()
+    }
+
+    #[test]
+    fn distances_are_great_circle_distances() {
+        let points = [
+            [48.8566, 2.3522],    // Paris
+            [51.5074, -0.1278],   // London
+            [-33.8688, 151.2093], // Sydney
+            [0.0, 179.99],
+            [0.0, -179.99],
+            [89.999, 0.0],
+            [89.999, 180.0],
+            [-48.8566, -177.6478], // antipode of Paris
+        ];
+        let tree = GeoTree::new(&points).unwrap();
+        assert_eq!(tree.len(), points.len());
+        let from_paris = tree.nearest(48.8566, 2.3522, 8).unwrap();
+        assert_eq!(from_paris[0].index, 0);
+        assert!(from_paris[0].distance < 1e-3);
+        assert_eq!(from_paris[1].index, 1);
+        for neighbour in &from_paris {
+            let expected = haversine(points[0], points[neighbour.index as usize]);
+            assert!((neighbour.distance - expected).abs() < 1e-3 * expected.max(1.0));
+        }
+        // the antipode is half a circumference away
+        assert_eq!(from_paris[7].index, 7);
+        let half = core::f64::consts::PI * MEAN_EARTH_RADIUS;
+        assert!((from_paris[7].distance - half).abs() < 1.0);
+        // neighbours across the antimeridian and the pole
+        let across = tree.nearest(0.0, 179.99, 2).unwrap();
+        assert_eq!(across[1].index, 4);
+        assert!((across[1].distance - haversine(points[3], points[4])).abs() < 1e-3);
+        let pole = tree.nearest(90.0, 42.0, 2).unwrap();
+        let indices: Vec<_> = pole.iter().map(|neighbour| neighbour.index).collect();
+        assert!(indices.contains(&5) && indices.contains(&6));
+        // distances follow the radius
+        let km = tree.clone().with_earth_radius(MEAN_EARTH_RADIUS / 1000.0);
+        let distance = km.nearest(51.5074, -0.1278, 2).unwrap()[1].distance;
+        assert!((distance - from_paris[1].distance / 1000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn invalid_coordinates_are_errors() {
+        assert_eq!(
+            GeoTree::new(&[[0.0, 0.0], [90.5, 0.0]]).unwrap_err(),
+            BuildError::InvalidLatLon { index: 1 }
+        );
+        assert_eq!(
+            GeoTree::new(&[[0.0, f64::NAN]]).unwrap_err(),
+            BuildError::InvalidLatLon { index: 0 }
+        );
+        let tree = GeoTree::new(&[[0.0, 0.0]]).unwrap();
+        assert_eq!(
+            tree.nearest(0.0, 180.5, 1).unwrap_err(),
+            QueryError::InvalidLatLon
+        );
+        assert_eq!(
+            tree.nearest(f64::NAN, 0.0, 1).unwrap_err(),
+            QueryError::InvalidLatLon
+        );
+    }
+}
@@ -0,0 +1,151 @@
+//! Nearest neighbours with the matrix of the squared distances between them
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+
+use crate::distance_scale::unscale_dist2;
+use crate::heap::CandidateHeap;
+use crate::{
+    point_slice_dist2, CandidateCollector, CandidateContainer, InternalNeighbour, KDTree,
+    Neighbour, Parameters, Point, PreparedParameters, Scalar,
+};
+
+/// Neighbours and the row-major matrix of the squared distances between them
+type NeighboursWithGram<T, P> = (Vec<Neighbour<T, P>>, Vec<T>);
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Finds the `k` nearest neighbour of `query`, with user-provided parameters,
+    /// and the squared distances between the neighbours found.
+    ///
+    /// The matrix is row-major, of size `n × n` with `n` the number of neighbours returned,
+    /// its element `(i, j)` being the squared distance between neighbours `i` and `j`.
+    /// It is computed from the coordinates stored in the tree, right after the search
+    /// reached them, and is bitwise symmetric with a zero diagonal.
+    pub fn knn_with_gram(
+        &self,
+        k: u32,
+        query: &P,
+        parameters: &Parameters<T>,
+    ) -> NeighboursWithGram<T, P> {
+        self.knn_with_gram_prepared(k, query, &self.prepare_parameters(parameters))
+    }
+
+    fn knn_with_gram_prepared(
+        &self,
+        k: u32,
+        query: &P,
+        parameters: &PreparedParameters<T>,
+    ) -> NeighboursWithGram<T, P> {
+        (match Self::default_candidate_container(k) {
+            CandidateContainer::Linear => Self::knn_with_gram_generic::<Vec<InternalNeighbour<T>>>,
+            CandidateContainer::BinaryHeap => {
+                Self::knn_with_gram_generic::<BinaryHeap<InternalNeighbour<T>>>
+            }
+        })(self, k, query, parameters)
+    }
+
+    fn knn_with_gram_generic<H: CandidateHeap<T> + CandidateCollector<T>>(
+        &self,
+        k: u32,
+        query: &P,
+        parameters: &PreparedParameters<T>,
+    ) -> NeighboursWithGram<T, P> {
+        let mut heap = H::new_with_k(k.min(self.len() as u32));
+        self.knn_internal(
+            &mut heap,
+            &self.query_coordinates(query),
+            &parameters.internal,
+        );
+        let neighbours = if parameters.sort_results {
+            heap.into_sorted_vec()
+        } else {
+            heap.into_vec()
+        };
+        let count = neighbours.len();
+        let dim = P::DIM as usize;
+        let mut gram = alloc::vec![T::zero(); count * count];
+        for (i, lhs) in neighbours.iter().enumerate() {
+            let lhs_start = lhs.index as usize * dim;
+            let lhs = &self.points[lhs_start..lhs_start + dim];
+            for (j, rhs) in neighbours.iter().enumerate().skip(i + 1) {
+                let rhs_start = rhs.index as usize * dim;
+                let rhs = &self.points[rhs_start..rhs_start + dim];
+                let dist2 = point_slice_dist2::<T, P>(lhs, rhs, self.scale);
+                let dist2 = unscale_dist2(dist2, self.scale).into_inner();
+                gram[i * count + j] = dist2;
+                gram[j * count + i] = dist2;
+            }
+        }
+        let neighbours = neighbours
+            .into_iter()
+            .map(|n| self.externalise_neighbour(n))
+            .collect();
+        (neighbours, gram)
+    }
+}
+
+impl<T: Scalar + Send + Sync, P: Point<T> + Send + Sync> KDTree<T, P> {
+    /// Finds the `k` nearest neighbour of each of `queries`, with user-provided parameters,
+    /// and the squared distances between the neighbours found, see [`Self::knn_with_gram`].
+    ///
+    /// If the `rayon` feature is enabled, queries are processed in parallel.
+    pub fn knn_batch_with_gram(
+        &self,
+        k: u32,
+        queries: &[P],
+        parameters: &Parameters<T>,
+    ) -> Vec<NeighboursWithGram<T, P>> {
+        let parameters = self.prepare_parameters(parameters);
+        self.map_indices(queries.len(), |index| {
+            self.knn_with_gram_prepared(k, &queries[index], &parameters)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn gram_matrices_hold_the_distances_between_neighbours() {
+        let cloud: Vec<P2> = random_point_cloud(2000);
+        let tree = KDTree::new(&cloud);
+        let queries: Vec<P2> = random_point_cloud(50);
+        for (k, sort_results) in [(0, true), (1, true), (8, true), (30, true), (8, false)] {
+            let parameters = Parameters {
+                sort_results,
+                ..Parameters::default()
+            };
+            let batch = tree.knn_batch_with_gram(k, &queries, &parameters);
+            for (query, (neighbours, gram)) in queries.iter().zip(batch) {
+                assert_eq!(
+                    neighbours,
+                    tree.knn_advanced(
+                        k,
+                        query,
+                        KDTree::<f32, P2>::default_candidate_container(k),
+                        &parameters,
+                        None
+                    )
+                );
+                assert_eq!(tree.knn_with_gram(k, query, &parameters).1, gram);
+                let count = neighbours.len();
+                assert_eq!(gram.len(), count * count);
+                for (i, lhs) in neighbours.iter().enumerate() {
+                    for (j, rhs) in neighbours.iter().enumerate() {
+                        let dx = lhs.point.get(0) - rhs.point.get(0);
+                        let dy = lhs.point.get(1) - rhs.point.get(1);
+                        assert_eq!(gram[i * count + j], (dx * dx + dy * dy).into_inner());
+                    }
+                }
+            }
+        }
+        // a radius limits the size of the matrix
+        let parameters = Parameters {
+            max_radius: 0.0,
+            ..Parameters::default()
+        };
+        let (neighbours, gram) = tree.knn_with_gram(5, &random_point(), &parameters);
+        assert_eq!(gram.len(), neighbours.len() * neighbours.len());
+    }
+}
@@ -0,0 +1,384 @@
+//! Trees storing each distinct position once, with the indices of the points at that position
+
+use alloc::vec::Vec;
+
+use crate::{
+    BuildError, BuildOptions, KDTree, Neighbour, Parameters, Point, Scalar, SelfMatch,
+    WeightedKDTree,
+};
+
+/// A KDTree of the distinct positions of a point cloud, each listing the points at that position
+///
+/// Heavily duplicated clouds are stored in the size of their distinct positions,
+/// and queries return either one representative per position, or the indices of all points.
+/// Coordinates are compared exactly, so `0.0` and `-0.0` are equal.
+/// Group `g` is the position of the `g`-th distinct point of the cloud, and its representative
+/// is the point of smallest index at that position.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroupedKDTree<T: Scalar, P: Point<T>> {
+    /// the distinct positions, each counted with its number of points
+    tree: WeightedKDTree<T, P>,
+    /// start of the members of each group in `members`, followed by the number of points
+    offsets: Vec<u32>,
+    /// indices of the points of each group, by increasing index within a group
+    members: Vec<u32>,
+    /// group of each point
+    groups: Vec<u32>,
+}
+
+impl<T: Scalar, P: Point<T>> GroupedKDTree<T, P> {
+    /// Creates a new tree grouping the points of `cloud` with equal coordinates.
+    ///
+    /// Panics in the same cases as [`Self::try_new`].
+    pub fn new(cloud: &[P]) -> Self {
+        Self::try_new(cloud).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Creates a new tree grouping the points of `cloud` with equal coordinates, with default options.
+    ///
+    /// Returns an error in the same cases as [`KDTree::try_new`], with the index of a point of `cloud`.
+    pub fn try_new(cloud: &[P]) -> Result<Self, BuildError> {
+        Self::try_new_with_options(cloud, &BuildOptions::default())
+    }
+
+    /// Creates a new tree grouping the points of `cloud` with equal coordinates, with user-provided options.
+    ///
+    /// Points are grouped by sorting them by coordinates, in time O(n log n).
+    pub fn try_new_with_options(cloud: &[P], options: &BuildOptions) -> Result<Self, BuildError> {
+        if cloud.len() > u32::MAX as usize {
+            return Err(BuildError::TooManyPoints(cloud.len()));
+        }
+        let coordinates = |index: u32| (0..P::DIM).map(move |i| cloud[index as usize].get(i));
+        let mut order: Vec<u32> = (0..cloud.len() as u32).collect();
+        order.sort_unstable_by(|a, b| coordinates(*a).cmp(coordinates(*b)).then(a.cmp(b)));
+        // runs of equal coordinates, ordered by their smallest index, which starts each run
+        let mut runs = Vec::new();
+        let mut start = 0;
+        for end in 1..=order.len() {
+            if end == order.len() || coordinates(order[end]).ne(coordinates(order[start])) {
+                runs.push(start..end);
+                start = end;
+            }
+        }
+        runs.sort_unstable_by_key(|run| order[run.start]);
+        let mut offsets = Vec::with_capacity(runs.len() + 1);
+        let mut members = Vec::with_capacity(cloud.len());
+        let mut groups = alloc::vec![0; cloud.len()];
+        let mut distinct = Vec::with_capacity(runs.len());
+        let mut counts = Vec::with_capacity(runs.len());
+        for (group, run) in runs.into_iter().enumerate() {
+            offsets.push(members.len() as u32);
+            counts.push(run.len() as u32);
+            distinct.push(P::from_slice(
+                &coordinates(order[run.start]).collect::<Vec<_>>(),
+            ));
+            for member in &order[run] {
+                groups[*member as usize] = group as u32;
+                members.push(*member);
+            }
+        }
+        offsets.push(members.len() as u32);
+        let representative = |group: u32| members[offsets[group as usize] as usize];
+        let tree =
+            WeightedKDTree::try_new_with_options(&distinct, &counts, options).map_err(|error| {
+                match error {
+                    BuildError::NonFiniteCoordinate { index } => BuildError::NonFiniteCoordinate {
+                        index: representative(index),
+                    },
+                    error => error,
+                }
+            })?;
+        Ok(GroupedKDTree {
+            tree,
+            offsets,
+            members,
+            groups,
+        })
+    }
+
+    /// Returns the tree of the distinct positions, in which the index of a point is its group.
+    pub fn tree(&self) -> &KDTree<T, P> {
+        self.tree.tree()
+    }
+
+    /// Returns the number of points, counting duplicates.
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    /// Returns whether there are no points.
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// Returns the number of distinct positions.
+    pub fn group_count(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    /// Returns the group of the point of index `index` in the original point cloud.
+    pub fn group_of(&self, index: u32) -> u32 {
+        self.groups[index as usize]
+    }
+
+    /// Returns the indices of the points of group `group`, by increasing index.
+    pub fn members(&self, group: u32) -> &[u32] {
+        let group = group as usize;
+        &self.members[self.offsets[group] as usize..self.offsets[group + 1] as usize]
+    }
+
+    /// Finds the `k` nearest distinct positions of `query`, returning their representatives,
+    /// using reasonable default parameters.
+    pub fn knn(&self, k: u32, query: &P) -> Vec<Neighbour<T, P>> {
+        self.knn_advanced(k, query, &Parameters::default(), false, None)
+    }
+
+    /// Finds the `k` nearest neighbours of `query`, with user-provided parameters.
+    ///
+    /// If `expand_duplicates` is false, neighbours are the representatives of the `k` nearest
+    /// distinct positions.
+    /// If it is true, duplicates count as distinct neighbours, and the `k` nearest points are returned,
+    /// sorted by increasing distance, independently of `parameters.sort_results`,
+    /// the points of a position by increasing index.
+    /// The points of the furthest position might then not all be returned.
+    /// As the query is a position, [`SelfMatch::ExcludeCoincident`] excludes all the points at that position,
+    /// see [`Self::knn_of_point`] or [`SelfMatch::ExcludeByIndex`] to only exclude one of them.
+    pub fn knn_advanced(
+        &self,
+        k: u32,
+        query: &P,
+        parameters: &Parameters<T>,
+        expand_duplicates: bool,
+        touch_statistics: Option<&mut u32>,
+    ) -> Vec<Neighbour<T, P>> {
+        if let Some(excluded) = parameters.self_match.excluded_index() {
+            if (excluded as usize) < self.groups.len() {
+                return self.knn_excluding(k, query, excluded, parameters, expand_duplicates);
+            }
+        }
+        if !expand_duplicates {
+            return self
+                .tree()
+                .knn_advanced(
+                    k,
+                    query,
+                    KDTree::<T, P>::default_candidate_container(k),
+                    parameters,
+                    touch_statistics,
+                )
+                .into_iter()
+                .map(|neighbour| Neighbour {
+                    index: self.members(neighbour.index)[0],
+                    ..neighbour
+                })
+                .collect();
+        }
+        let mut neighbours = Vec::new();
+        for weighted in self
+            .tree
+            .knn_advanced(k, query, parameters, touch_statistics)
+        {
+            let neighbour = weighted.neighbour;
+            let coordinates: Vec<_> = (0..P::DIM).map(|i| neighbour.point.get(i)).collect();
+            neighbours.extend(
+                self.members(neighbour.index)[..weighted.slots as usize]
+                    .iter()
+                    .map(|index| Neighbour {
+                        point: P::from_slice(&coordinates),
+                        dist2: neighbour.dist2,
+                        index: *index,
+                    }),
+            );
+        }
+        neighbours
+    }
+
+    /// Finds the `k` nearest neighbours of the point of index `index` in the original point cloud,
+    /// with user-provided parameters, as [`Self::knn_advanced`] does.
+    ///
+    /// With [`SelfMatch::ExcludeCoincident`], only the point itself is excluded, by index, as with
+    /// [`SelfMatch::ExcludeByIndex`]: its duplicates are returned at distance zero, represented by the smallest
+    /// other index if `expand_duplicates` is false.
+    ///
+    /// Panics if `index` is out of range.
+    pub fn knn_of_point(
+        &self,
+        k: u32,
+        index: u32,
+        parameters: &Parameters<T>,
+        expand_duplicates: bool,
+    ) -> Vec<Neighbour<T, P>> {
+        let group = self.group_of(index);
+        let tree = self.tree();
+        let query = tree.stored_point(tree.internalise_index(group));
+        let parameters = match parameters.self_match {
+            SelfMatch::ExcludeCoincident => Parameters {
+                self_match: SelfMatch::ExcludeByIndex(index),
+                ..*parameters
+            },
+            _ => *parameters,
+        };
+        self.knn_advanced(k, &query, &parameters, expand_duplicates, None)
+    }
+
+    /// Finds the `k` nearest neighbours of `query` other than the point of index `excluded`,
+    /// searching one more neighbour and removing that point, or the furthest neighbour if it is not found
+    fn knn_excluding(
+        &self,
+        k: u32,
+        query: &P,
+        excluded: u32,
+        parameters: &Parameters<T>,
+        expand_duplicates: bool,
+    ) -> Vec<Neighbour<T, P>> {
+        let group = self.group_of(excluded);
+        let with_self = Parameters {
+            self_match: SelfMatch::Allow,
+            sort_results: true,
+            ..*parameters
+        };
+        let mut neighbours = self.knn_advanced(
+            k.saturating_add(1),
+            query,
+            &with_self,
+            expand_duplicates,
+            None,
+        );
+        if expand_duplicates {
+            neighbours.retain(|neighbour| neighbour.index != excluded);
+        } else if let Some(position) = neighbours
+            .iter()
+            .position(|neighbour| self.group_of(neighbour.index) == group)
+        {
+            match self
+                .members(group)
+                .iter()
+                .find(|member| **member != excluded)
+            {
+                Some(other) => neighbours[position].index = *other,
+                None => {
+                    neighbours.remove(position);
+                }
+            }
+        }
+        neighbours.truncate(k as usize);
+        neighbours
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    /// A cloud in which each of `distinct` positions is repeated with varying multiplicities
+    fn duplicated_cloud(distinct: u32) -> Vec<P2> {
+        let positions: Vec<P2> = random_point_cloud(distinct);
+        let mut rng = crate::rng::SplitMix64::new(5);
+        (0..distinct * 6)
+            .map(|_| positions[rng.below(distinct as u64) as usize])
+            .collect()
+    }
+
+    fn dist2(a: &P2, b: &P2) -> f32 {
+        let dx = a.get(0) - b.get(0);
+        let dy = a.get(1) - b.get(1);
+        (dx * dx + dy * dy).into_inner()
+    }
+
+    #[test]
+    fn groups_list_the_points_of_each_position() {
+        let mut cloud = duplicated_cloud(300);
+        cloud.push(P2::new2d(-0.0, 0.0));
+        cloud.push(P2::new2d(0.0, -0.0));
+        let grouped = GroupedKDTree::new(&cloud);
+        assert_eq!(grouped.len(), cloud.len());
+        assert_eq!(grouped.tree().len(), grouped.group_count());
+        assert!(grouped.group_count() <= 301);
+        let mut seen = vec![false; cloud.len()];
+        let mut last_representative = None;
+        for group in 0..grouped.group_count() as u32 {
+            let members = grouped.members(group);
+            assert!(members.windows(2).all(|pair| pair[0] < pair[1]));
+            // groups are ordered by representative
+            assert!(last_representative < Some(members[0]));
+            last_representative = Some(members[0]);
+            for member in members {
+                assert!(!seen[*member as usize]);
+                seen[*member as usize] = true;
+                assert_eq!(grouped.group_of(*member), group);
+                assert_eq!(cloud[*member as usize], cloud[members[0] as usize]);
+            }
+        }
+        assert!(seen.iter().all(|seen| *seen));
+        let zeros = cloud.len() as u32 - 2;
+        assert_eq!(grouped.group_of(zeros), grouped.group_of(zeros + 1));
+        assert_eq!(
+            GroupedKDTree::try_new(&[P2::new2d(0.0, 0.0), P2::new2d(f32::INFINITY, 0.0)]),
+            Err(BuildError::NonFiniteCoordinate { index: 1 })
+        );
+        assert!(GroupedKDTree::<f32, P2>::new(&[])
+            .knn(3, &random_point())
+            .is_empty());
+    }
+
+    #[test]
+    fn queries_return_representatives_or_all_points() {
+        let cloud = duplicated_cloud(200);
+        let grouped = GroupedKDTree::new(&cloud);
+        let parameters = Parameters::default();
+        for _ in 0..50 {
+            let query = random_point();
+            let mut expected: Vec<(f32, u32)> = (0..cloud.len() as u32)
+                .map(|index| (dist2(&query, &cloud[index as usize]), index))
+                .collect();
+            expected.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+            for k in [1, 5, 20] {
+                let expanded = grouped.knn_advanced(k, &query, &parameters, true, None);
+                assert_eq!(expanded.len(), k as usize);
+                for (neighbour, (dist2, _)) in expanded.iter().zip(&expected) {
+                    assert_eq!(neighbour.dist2, *dist2);
+                    assert_eq!(neighbour.point, cloud[neighbour.index as usize]);
+                }
+                let mut representatives: Vec<(f32, u32)> = expected.clone();
+                representatives
+                    .retain(|(_, index)| grouped.members(grouped.group_of(*index))[0] == *index);
+                let found = grouped.knn(k, &query);
+                assert_eq!(found.len(), k as usize);
+                for (neighbour, (dist2, index)) in found.iter().zip(&representatives) {
+                    assert_eq!(neighbour.dist2, *dist2);
+                    assert_eq!(neighbour.index, *index);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn self_matches_are_excluded_by_index() {
+        let cloud = duplicated_cloud(100);
+        let grouped = GroupedKDTree::new(&cloud);
+        let parameters = Parameters {
+            self_match: SelfMatch::ExcludeCoincident,
+            ..Parameters::default()
+        };
+        for index in 0..cloud.len() as u32 {
+            let group = grouped.group_of(index);
+            let duplicates = grouped.members(group).len();
+            let expanded = grouped.knn_of_point(10, index, &parameters, true);
+            assert_eq!(expanded.len(), 10);
+            assert!(expanded.iter().all(|neighbour| neighbour.index != index));
+            let at_zero = expanded.iter().filter(|n| n.dist2 == 0.0).count();
+            assert_eq!(at_zero, (duplicates - 1).min(10));
+            let found = grouped.knn_of_point(3, index, &parameters, false);
+            assert_eq!(found.len(), 3);
+            assert!(found.iter().all(|neighbour| neighbour.index != index));
+            assert_eq!(found[0].dist2 == 0.0, duplicates > 1);
+            // querying by position excludes all the points at the position
+            let by_position =
+                grouped.knn_advanced(3, &cloud[index as usize], &parameters, true, None);
+            assert!(by_position
+                .iter()
+                .all(|n| grouped.group_of(n.index) != group));
+        }
+    }
+}
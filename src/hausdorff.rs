@@ -0,0 +1,196 @@
+//! Directed Hausdorff distance from other points to a tree, with searches breaking early
+
+use alloc::vec::Vec;
+
+use num_traits::Float;
+use ordered_float::NotNan;
+
+use crate::distance_scale::unscale_dist2;
+use crate::infinite::HasInfinite;
+use crate::internal_parameters::InternalParameters;
+use crate::rng::SplitMix64;
+use crate::{CandidateCollector, InternalNeighbour, KDTree, Parameters, Point, Scalar};
+
+/// Seed of the shuffle of the other points, fixed so that results are reproducible
+const SHUFFLE_SEED: u64 = 0x4861_7573_646f_7266;
+
+/// Keeps the nearest candidate until one is within `bound2`, then prunes the rest of the search
+///
+/// Once a candidate is within the bound, the query cannot raise the running maximum
+/// of the nearest-neighbour distances, so its exact nearest neighbour is of no interest.
+struct BreakingCollector<T: Scalar> {
+    nearest: InternalNeighbour<T>,
+    bound2: NotNan<T>,
+}
+
+impl<T: Scalar> BreakingCollector<T> {
+    fn new(bound2: NotNan<T>) -> Self {
+        BreakingCollector {
+            nearest: InternalNeighbour::default(),
+            bound2,
+        }
+    }
+
+    fn broke(&self) -> bool {
+        self.nearest.dist2 <= self.bound2
+    }
+}
+
+impl<T: Scalar> CandidateCollector<T> for BreakingCollector<T> {
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        self.nearest.add(dist2, index);
+    }
+
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        if self.broke() {
+            Some(-NotNan::<T>::infinite())
+        } else {
+            self.nearest.furthest_dist2()
+        }
+    }
+}
+
+impl<T: Scalar + Float, P: Point<T>> KDTree<T, P> {
+    /// Returns the directed Hausdorff distance from `others` to the points of the tree,
+    /// the largest distance from a point of `others` to its nearest point in the tree,
+    /// with the index of that point in `others` and the index of its nearest neighbour in the tree,
+    /// or `None` if `others` is empty.
+    ///
+    /// Each search breaks as soon as it finds a point nearer than the largest distance so far,
+    /// as its point cannot be the farthest, so that most searches only visit a few nodes.
+    /// `others` are processed in a pseudo-random order, fixed for reproducibility, for the largest distance
+    /// to grow quickly even if they are spatially sorted.
+    /// Points of `others` with no point within `parameters.max_radius`, which includes all of them
+    /// if the tree is empty, are infinitely far, with a neighbour index of `u32::MAX`.
+    /// With a positive `parameters.epsilon`, the distance may be overestimated by that ratio.
+    pub fn directed_hausdorff(
+        &self,
+        others: &[P],
+        parameters: &Parameters<T>,
+    ) -> Option<(T, u32, u32)> {
+        let internal_parameters = InternalParameters::new(parameters, self.scale);
+        let mut farthest: Option<(NotNan<T>, u32, u32)> = None;
+        for index in shuffled_indices(others.len()) {
+            let bound2 = farthest.map_or(-NotNan::<T>::infinite(), |(dist2, _, _)| dist2);
+            let mut collector = BreakingCollector::new(bound2);
+            self.knn_internal(
+                &mut collector,
+                &self.query_coordinates(&others[index as usize]),
+                &internal_parameters,
+            );
+            if collector.broke() {
+                continue;
+            }
+            let nearest = collector.nearest;
+            if nearest.dist2 == NotNan::infinite() {
+                return Some((Float::infinity(), index, u32::MAX));
+            }
+            farthest = Some((nearest.dist2, index, self.externalise_index(nearest.index)));
+        }
+        farthest.map(|(dist2, index, neighbour)| {
+            (unscale_dist2(dist2, self.scale).sqrt(), index, neighbour)
+        })
+    }
+
+    /// Returns whether the directed Hausdorff distance from `others` to the points of the tree
+    /// exceeds `threshold`, see [`Self::directed_hausdorff`].
+    ///
+    /// The searches are limited to `threshold` and each breaks at the first point it finds,
+    /// and the function returns at the first point of `others` with no point of the tree within `threshold`.
+    /// Panics if `threshold` is NaN.
+    pub fn hausdorff_exceeds(&self, others: &[P], threshold: T) -> bool {
+        if threshold < T::zero() {
+            return !others.is_empty();
+        }
+        let parameters = Parameters {
+            max_radius: threshold,
+            ..Parameters::default()
+        };
+        let internal_parameters = InternalParameters::new(&parameters, self.scale);
+        shuffled_indices(others.len()).into_iter().any(|index| {
+            let mut collector = BreakingCollector::new(internal_parameters.max_radius2);
+            self.knn_internal(
+                &mut collector,
+                &self.query_coordinates(&others[index as usize]),
+                &internal_parameters,
+            );
+            !collector.broke()
+        })
+    }
+}
+
+/// Returns the indices `0..len` in a pseudo-random order
+fn shuffled_indices(len: usize) -> Vec<u32> {
+    let mut indices: Vec<u32> = (0..len as u32).collect();
+    let mut rng = SplitMix64::new(SHUFFLE_SEED);
+    for i in (1..len).rev() {
+        indices.swap(i, rng.below(i as u64 + 1) as usize);
+    }
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    /// Returns the directed Hausdorff distance from `others` to `cloud` by searching all nearest neighbours
+    fn naive_hausdorff(cloud: &[P2], others: &[P2]) -> f32 {
+        let tree = KDTree::new(cloud);
+        others
+            .iter()
+            .map(|point| tree.knn(1, point)[0].dist2.into_inner())
+            .fold(0.0, f32::max)
+            .sqrt()
+    }
+
+    #[test]
+    fn directed_hausdorff_matches_the_naive_maximum() {
+        let cloud: Vec<P2> = random_point_cloud(3000);
+        let tree = KDTree::new(&cloud);
+        for count in [1, 10, 500] {
+            let others: Vec<P2> = random_point_cloud(count);
+            let (distance, index, neighbour) = tree
+                .directed_hausdorff(&others, &Parameters::default())
+                .unwrap();
+            assert_eq!(distance, naive_hausdorff(&cloud, &others));
+            let nearest = tree.knn(1, &others[index as usize])[0];
+            assert_eq!(nearest.dist2.into_inner().sqrt(), distance);
+            assert_eq!(neighbour, nearest.index);
+            assert!(tree.hausdorff_exceeds(&others, distance * 0.99));
+            assert!(!tree.hausdorff_exceeds(&others, distance * 1.01));
+            assert!(!tree.hausdorff_exceeds(&others, f32::INFINITY));
+        }
+        // the points of the tree are at distance zero from it
+        let (distance, _, _) = tree
+            .directed_hausdorff(&cloud, &Parameters::default())
+            .unwrap();
+        assert_eq!(distance, 0.0);
+        assert!(!tree.hausdorff_exceeds(&cloud, 0.0));
+        assert!(tree.hausdorff_exceeds(&cloud, -1.0));
+    }
+
+    #[test]
+    fn directed_hausdorff_of_missing_neighbours_is_infinite() {
+        let cloud: Vec<P2> = random_point_cloud(100);
+        let tree = KDTree::new(&cloud);
+        assert_eq!(tree.directed_hausdorff(&[], &Parameters::default()), None);
+        assert!(!tree.hausdorff_exceeds(&[], 0.0));
+        let far = [cloud[0], P2::new2d(1e6, 1e6)];
+        let parameters = Parameters {
+            max_radius: 1000.0,
+            ..Parameters::default()
+        };
+        assert_eq!(
+            tree.directed_hausdorff(&far, &parameters),
+            Some((f32::INFINITY, 1, u32::MAX))
+        );
+        let empty = KDTree::<f32, P2>::new(&[]);
+        let result = empty.directed_hausdorff(&far, &Parameters::default());
+        assert_eq!(
+            result.map(|(distance, _, neighbour)| (distance, neighbour)),
+            Some((f32::INFINITY, u32::MAX))
+        );
+        assert!(empty.hausdorff_exceeds(&far, 1e9));
+    }
+}
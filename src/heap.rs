@@ -1,46 +1,132 @@
+use core::cmp::Reverse;
+use core::fmt;
 use core::marker::PhantomData;
 
 use alloc::collections::BinaryHeap;
 use alloc::vec;
 use alloc::vec::Vec;
 
-use num_traits::Zero;
 use ordered_float::NotNan;
 
+use crate::distance_scale::unscale_dist2;
+use crate::infinite::HasInfinite;
 use crate::internal_neighbour::InternalNeighbour;
-use crate::{KDTree, Point, Scalar};
+use crate::{KDTree, Neighbour, Point, Scalar};
 
 /// Global trait for tracking candidates during KDTree traversal
 pub trait CandidateCollector<T: Scalar> {
+    /// Offers a candidate at squared distance `dist2` with internal index `index`.
     fn add(&mut self, dist2: NotNan<T>, index: u32);
-    fn furthest_dist2(&self) -> NotNan<T>;
+    /// The squared distance beyond which candidates are of no interest to this collector,
+    /// used to prune the search, or `None` while the collector accepts any candidate.
+    ///
+    /// Squared distances offered through [`Self::add`] are finite, as overflowing distances
+    /// saturate to `T::max_value()`, so a bound of `T::max_value()` still prunes.
+    fn furthest_dist2(&self) -> Option<NotNan<T>>;
 }
 
-pub struct UnboundedCollector<T: Scalar, P: Point<T>>(Vec<u32>, NotNan<T>, PhantomData<P>);
+/// A collector keeping all candidates, for radius searches
+///
+/// Its search region is only limited by `Parameters::max_radius`, and optionally the number of candidates
+/// by [`Self::with_max_results`].
+pub struct UnboundedCollector<T: Scalar, P: Point<T>> {
+    indices: Vec<u32>,
+    max_results: Option<usize>,
+    exceeded: bool,
+    marker: PhantomData<(T, P)>,
+}
 
 impl<T: Scalar, P: Point<T>> CandidateCollector<T> for UnboundedCollector<T, P> {
-    fn add(&mut self, dist2: NotNan<T>, index: u32) {
-        self.0.push(index);
-        self.1 = self.1.max(dist2);
+    fn add(&mut self, _dist2: NotNan<T>, index: u32) {
+        if self.exceeded {
+            return;
+        }
+        match self.max_results {
+            Some(limit) if self.indices.len() >= limit => self.exceeded = true,
+            Some(limit) => push_within_limit(&mut self.indices, index, limit),
+            None => self.indices.push(index),
+        }
     }
 
-    fn furthest_dist2(&self) -> NotNan<T> {
-        self.1
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        // once over the limit, no cell is worth visiting
+        self.exceeded.then(|| -NotNan::<T>::infinite())
     }
 }
 
+/// Pushes `item` to `vec`, which holds fewer than `limit` items, growing it to at most `limit` items
+pub(crate) fn push_within_limit<I>(vec: &mut Vec<I>, item: I, limit: usize) {
+    if vec.len() == vec.capacity() {
+        let grown = vec.capacity().max(4);
+        vec.reserve_exact(grown.min(limit - vec.len()));
+    }
+    vec.push(item);
+}
+
 impl<T: Scalar, P: Point<T>> UnboundedCollector<T, P> {
+    /// Creates a new collector able to hold `capacity` candidates without reallocating.
     pub fn with_capacity(capacity: usize) -> Self {
-        Self(Vec::with_capacity(capacity), NotNan::zero(), PhantomData)
+        Self {
+            indices: Vec::with_capacity(capacity),
+            max_results: None,
+            exceeded: false,
+            marker: PhantomData,
+        }
+    }
+
+    /// Creates a new collector keeping at most `max_results` candidates, able to hold `capacity` of them
+    /// without reallocating.
+    ///
+    /// The collector keeps the first `max_results` candidates found and ignores the rest.
+    /// Offering one more candidate sets [`Self::limit_exceeded`] and prunes the rest of the search,
+    /// only the current leaf being finished.
+    /// The candidates kept are the first ones visited by the search, which are not the nearest in general.
+    /// The collector never allocates memory for more than `max_results` candidates, even when given more,
+    /// so a search covering a huge cloud cannot exhaust the memory.
+    pub fn with_max_results(capacity: usize, max_results: usize) -> Self {
+        Self {
+            max_results: Some(max_results),
+            ..Self::with_capacity(capacity.min(max_results))
+        }
     }
 
+    /// Returns whether more candidates than the maximum of [`Self::with_max_results`] were offered,
+    /// so the collected ones are only part of the points within the radius.
+    pub fn limit_exceeded(&self) -> bool {
+        self.exceeded
+    }
+
+    /// Removes all candidates, keeping the allocated memory and the maximum number of results.
     pub fn clear(&mut self) {
-        self.0.clear();
-        self.1 = NotNan::zero();
+        self.indices.clear();
+        self.exceeded = false;
+    }
+}
+
+impl<T: Scalar, P: Point<T>> Clone for UnboundedCollector<T, P> {
+    fn clone(&self) -> Self {
+        Self {
+            indices: self.indices.clone(),
+            max_results: self.max_results,
+            exceeded: self.exceeded,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Scalar, P: Point<T>> fmt::Debug for UnboundedCollector<T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UnboundedCollector")
+            .field("len", &self.indices.len())
+            .field("capacity", &self.indices.capacity())
+            .field("max_results", &self.max_results)
+            .field("exceeded", &self.exceeded)
+            .finish()
     }
 }
 
 impl<T: Scalar, P: Point<T>> UnboundedCollector<T, P> {
+    /// Iterates over the indices of the collected candidates, in the original point cloud.
     pub fn externalise<'a, 'b>(
         &'a self,
         tree: &'b KDTree<T, P>,
@@ -48,10 +134,140 @@ impl<T: Scalar, P: Point<T>> UnboundedCollector<T, P> {
     where
         'a: 'b,
     {
-        self.0.iter().map(|inner| tree.externalise_index(*inner))
+        self.indices
+            .iter()
+            .map(|inner| tree.externalise_index(*inner))
+    }
+
+    /// Consumes the collector, returning the indices of the collected candidates in the original point cloud.
+    ///
+    /// Contrary to [`Self::externalise`], the result does not borrow the collector, and reuses its memory.
+    pub fn into_indices(self, tree: &KDTree<T, P>) -> Vec<u32> {
+        let mut indices = self.indices;
+        for index in &mut indices {
+            *index = tree.externalise_index(*index);
+        }
+        indices
+    }
+
+    /// Appends the indices of the collected candidates in the original point cloud to `out`,
+    /// leaving the collector empty, with its memory kept for another query.
+    pub fn drain_externalise(&mut self, tree: &KDTree<T, P>, out: &mut Vec<u32>) {
+        out.extend(
+            self.indices
+                .drain(..)
+                .map(|inner| tree.externalise_index(inner)),
+        );
+        self.exceeded = false;
+    }
+}
+
+/// A collector appending all candidates to a caller-provided slice, for radius searches without allocation
+///
+/// As [`UnboundedCollector`], its search region is only limited by `Parameters::max_radius`.
+/// Once the slice is full, further candidates are dropped and [`Self::overflowed`] is set:
+/// the candidates kept are then the first ones visited by the search, which are not the nearest in general.
+/// The slice holds internal indices and squared distances until externalised.
+pub struct FixedCollector<'a, T: Scalar> {
+    slots: &'a mut [(u32, T)],
+    len: usize,
+    overflowed: bool,
+}
+
+impl<'a, T: Scalar> CandidateCollector<T> for FixedCollector<'a, T> {
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        match self.slots.get_mut(self.len) {
+            Some(slot) => {
+                *slot = (index, dist2.into_inner());
+                self.len += 1;
+            }
+            None => self.overflowed = true,
+        }
+    }
+
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        None
+    }
+}
+
+impl<'a, T: Scalar> FixedCollector<'a, T> {
+    /// Creates an empty collector filling `slots`.
+    pub fn new(slots: &'a mut [(u32, T)]) -> Self {
+        FixedCollector {
+            slots,
+            len: 0,
+            overflowed: false,
+        }
+    }
+
+    /// Returns the number of candidates kept.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether no candidate was kept.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns whether candidates were dropped because the slice was full.
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    /// Returns the internal indices and squared distances of the candidates kept.
+    pub fn as_slice(&self) -> &[(u32, T)] {
+        &self.slots[..self.len]
+    }
+
+    /// Removes all candidates and clears the overflow flag.
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.overflowed = false;
+    }
+
+    /// Iterates over the candidates kept, with their indices in the original point cloud
+    /// and their squared distances in the original units.
+    pub fn externalise<'b, P: Point<T>>(
+        &'b self,
+        tree: &'b KDTree<T, P>,
+    ) -> impl ExactSizeIterator<Item = (u32, T)> + 'b {
+        self.as_slice()
+            .iter()
+            .map(|(index, dist2)| externalise_slot(tree, *index, *dist2))
+    }
+
+    /// Consumes the collector, converting the candidates kept in place to their indices in the original point cloud
+    /// and their squared distances in the original units, and returns them.
+    pub fn into_externalised<P: Point<T>>(self, tree: &KDTree<T, P>) -> &'a mut [(u32, T)] {
+        let slots = &mut self.slots[..self.len];
+        for slot in slots.iter_mut() {
+            *slot = externalise_slot(tree, slot.0, slot.1);
+        }
+        slots
     }
 }
 
+/// Converts an internal index and squared distance to the original ones
+fn externalise_slot<T: Scalar, P: Point<T>>(tree: &KDTree<T, P>, index: u32, dist2: T) -> (u32, T) {
+    let dist2 = NotNan::new(dist2).expect("collected distances are not NaN");
+    (
+        tree.externalise_index(index),
+        unscale_dist2(dist2, tree.scale).into_inner(),
+    )
+}
+
+impl<'a, T: Scalar> fmt::Debug for FixedCollector<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FixedCollector")
+            .field("len", &self.len)
+            .field("capacity", &self.slots.len())
+            .field("overflowed", &self.overflowed)
+            .finish()
+    }
+}
+
+/// A collector keeping the `capacity` nearest candidates
 pub struct BoundedCollector<T: Scalar, P: Point<T>>(
     BinaryHeap<InternalNeighbour<T>>,
     PhantomData<P>,
@@ -62,22 +278,76 @@ impl<T: Scalar, P: Point<T>> CandidateCollector<T> for BoundedCollector<T, P> {
         self.0.add(dist2, index);
     }
 
-    fn furthest_dist2(&self) -> NotNan<T> {
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
         self.0.furthest_dist2()
     }
 }
 
 impl<T: Scalar, P: Point<T>> BoundedCollector<T, P> {
+    /// Creates a new collector keeping the `capacity` nearest candidates.
     pub fn with_capacity(capacity: usize) -> Self {
-        Self(BinaryHeap::with_capacity(capacity as usize), PhantomData)
+        Self(BinaryHeap::with_capacity(capacity), PhantomData)
     }
 
+    /// Removes all candidates, keeping the allocated memory.
     pub fn clear(&mut self) {
         self.0.clear();
     }
+
+    /// Keeps the `new_k` nearest candidates from now on, if more than the current capacity, and does nothing otherwise.
+    ///
+    /// The candidates kept so far are kept, and the collector accepts any candidate, returning `None`
+    /// from [`CandidateCollector::furthest_dist2`], until it holds `new_k` of them.
+    /// Candidates evicted before are not recovered, so a search continuing with the grown collector
+    /// must offer them again, as the resumable searches of `KDTree::begin_query` do.
+    pub fn grow_k(&mut self, new_k: usize) {
+        if new_k > self.0.capacity() {
+            self.0.reserve_exact(new_k - self.0.len());
+        }
+    }
+
+    /// Offers a candidate, and returns the candidate that is not kept, this one or the evicted furthest, if any
+    #[cfg(not(feature = "strict-exact"))]
+    pub(crate) fn add_evicting(
+        &mut self,
+        dist2: NotNan<T>,
+        index: u32,
+    ) -> Option<InternalNeighbour<T>> {
+        let candidate = InternalNeighbour { index, dist2 };
+        if self.0.len() < self.0.capacity() {
+            self.0.push(candidate);
+            return None;
+        }
+        match self.0.peek_mut() {
+            Some(mut furthest) if dist2 < furthest.dist2 => {
+                Some(core::mem::replace(&mut *furthest, candidate))
+            }
+            _ => Some(candidate),
+        }
+    }
+}
+
+impl<T: Scalar, P: Point<T>> Clone for BoundedCollector<T, P> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone(), PhantomData)
+    }
+}
+
+impl<T: Scalar, P: Point<T>> fmt::Debug for BoundedCollector<T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BoundedCollector")
+            .field("len", &self.0.len())
+            .field("capacity", &self.0.capacity())
+            .field("furthest_dist2", &self.furthest_dist2())
+            .finish()
+    }
 }
 
 impl<T: Scalar, P: Point<T>> BoundedCollector<T, P> {
+    /// Iterates over the indices of the collected candidates, in the original point cloud.
+    ///
+    /// The order is the internal order of the heap, which is unspecified and may change between versions
+    /// of Rust for candidates at the same distance; use [`Self::externalise_sorted`] for a stable order.
     pub fn externalise<'a, 'b>(
         &'a self,
         tree: &'b KDTree<T, P>,
@@ -89,6 +359,54 @@ impl<T: Scalar, P: Point<T>> BoundedCollector<T, P> {
             .iter()
             .map(|inner| tree.externalise_index(inner.index))
     }
+
+    /// Returns the collected neighbours by increasing distance, those at the same distance by increasing index
+    /// in the original point cloud, keeping them in the collector.
+    ///
+    /// This order only depends on the candidates collected, so it is the same on all platforms.
+    pub fn externalise_sorted(&self, tree: &KDTree<T, P>) -> Vec<Neighbour<T, P>> {
+        let mut sorted: Vec<_> = self.0.iter().copied().collect();
+        tree.sort_ties_by_external_index(&mut sorted);
+        sorted
+            .into_iter()
+            .map(|inner| tree.externalise_neighbour(inner))
+            .collect()
+    }
+
+    /// Consumes the collector, returning the collected neighbours in the order of [`Self::externalise_sorted`].
+    ///
+    /// Contrary to [`Self::externalise`], the result does not borrow the collector.
+    pub fn into_neighbours(self, tree: &KDTree<T, P>) -> Vec<Neighbour<T, P>> {
+        let mut sorted = self.0.into_vec();
+        tree.sort_ties_by_external_index(&mut sorted);
+        sorted
+            .into_iter()
+            .map(|inner| tree.externalise_neighbour(inner))
+            .collect()
+    }
+
+    /// Appends the collected neighbours to `out` in the order of [`Self::externalise_sorted`],
+    /// leaving the collector empty, with its memory kept for another query.
+    pub fn drain_externalise(&mut self, tree: &KDTree<T, P>, out: &mut Vec<Neighbour<T, P>>) {
+        let start = out.len();
+        // popping yields the farthest first, without allocating
+        while let Some(inner) = self.0.pop() {
+            out.push(tree.externalise_neighbour(inner));
+        }
+        out[start..].reverse();
+        // the heap orders ties by internal index
+        out[start..].sort_by_key(|neighbour| (neighbour.dist2, neighbour.index));
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Sorts `candidates` by increasing distance, those at the same distance by increasing external index,
+    /// the order of the sorted results of all searches
+    pub(crate) fn sort_ties_by_external_index(&self, candidates: &mut [InternalNeighbour<T>]) {
+        candidates.sort_unstable_by_key(|candidate| {
+            (candidate.dist2, self.indices[candidate.index as usize])
+        });
+    }
 }
 
 /// Local trait for keeping candidates in a heap-like behaviour
@@ -96,6 +414,12 @@ pub(crate) trait CandidateHeap<T: Scalar> {
     fn new_with_k(k: u32) -> Self;
     fn into_vec(self) -> Vec<InternalNeighbour<T>>;
     fn into_sorted_vec(self) -> Vec<InternalNeighbour<T>>;
+    /// Removes all candidates, keeping the capacity, so that the heap can be reused for another query
+    fn clear(&mut self);
+    /// Appends the candidates to `out` in the order of [`Self::into_vec`], or of [`Self::into_sorted_vec`] if `sorted`
+    fn extend_into(&self, out: &mut Vec<InternalNeighbour<T>>, sorted: bool);
+    /// Returns the number of candidates kept
+    fn candidate_count(&self) -> usize;
 }
 
 impl<T: Scalar> CandidateCollector<T> for BinaryHeap<InternalNeighbour<T>> {
@@ -104,18 +428,21 @@ impl<T: Scalar> CandidateCollector<T> for BinaryHeap<InternalNeighbour<T>> {
         if self.len() < k {
             self.push(InternalNeighbour { index, dist2 });
         } else {
-            let mut max_heap_value = self.peek_mut().unwrap();
-            if dist2 < max_heap_value.dist2 {
-                *max_heap_value = InternalNeighbour { index, dist2 };
+            if let Some(mut max_heap_value) = self.peek_mut() {
+                if dist2 < max_heap_value.dist2 {
+                    *max_heap_value = InternalNeighbour { index, dist2 };
+                }
             }
         }
     }
-    fn furthest_dist2(&self) -> NotNan<T> {
-        if self.len() < self.capacity() {
-            NotNan::new(T::infinity()).unwrap()
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        if self.capacity() == 0 {
+            // with k = 0, no candidate is of interest
+            Some(-NotNan::<T>::infinite())
+        } else if self.len() < self.capacity() {
+            None
         } else {
-            self.peek()
-                .map_or(NotNan::new(T::infinity()).unwrap(), |n| n.dist2)
+            self.peek().map(|n| n.dist2)
         }
     }
 }
@@ -130,60 +457,161 @@ impl<T: Scalar> CandidateHeap<T> for BinaryHeap<InternalNeighbour<T>> {
     fn into_sorted_vec(self) -> Vec<InternalNeighbour<T>> {
         BinaryHeap::into_sorted_vec(self)
     }
-}
-
-fn keep_finite_elements<T: Scalar>(v: Vec<InternalNeighbour<T>>) -> Vec<InternalNeighbour<T>> {
-    let pos = v.iter().position(|n| n.dist2.into_inner() == T::infinity());
-    match pos {
-        None => v,
-        Some(pos) => {
-            let mut v = v;
-            v.truncate(pos);
-            v
+    fn clear(&mut self) {
+        BinaryHeap::clear(self)
+    }
+    fn extend_into(&self, out: &mut Vec<InternalNeighbour<T>>, sorted: bool) {
+        let start = out.len();
+        out.extend(self.iter());
+        if sorted {
+            out[start..].sort_unstable();
         }
     }
+    fn candidate_count(&self) -> usize {
+        BinaryHeap::len(self)
+    }
 }
 
+/// A sorted vector of candidates of capacity `k`, growing until it holds `k` candidates
 impl<T: Scalar> CandidateCollector<T> for Vec<InternalNeighbour<T>> {
     fn add(&mut self, dist2: NotNan<T>, index: u32) {
-        if dist2 > self.furthest_dist2() {
-            return;
-        }
-        let mut i = self.len() - 1;
-        while i > 0 {
-            if self[i - 1].dist2 > dist2 {
-                self[i] = self[i - 1];
-            } else {
-                break;
+        let k = self.capacity();
+        let mut i = if self.len() < k {
+            self.push(InternalNeighbour { index, dist2 });
+            self.len() - 1
+        } else {
+            match self.last() {
+                Some(last) if dist2 <= last.dist2 => self.len() - 1,
+                _ => return,
             }
+        };
+        while i > 0 && self[i - 1].dist2 > dist2 {
+            self[i] = self[i - 1];
             i -= 1;
         }
-        self[i].dist2 = dist2;
-        self[i].index = index;
+        self[i] = InternalNeighbour { index, dist2 };
     }
-    fn furthest_dist2(&self) -> NotNan<T> {
-        self[self.len() - 1].dist2
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        if self.capacity() == 0 {
+            // with k = 0, no candidate is of interest
+            Some(-NotNan::<T>::infinite())
+        } else if self.len() < self.capacity() {
+            None
+        } else {
+            self.last().map(|n| n.dist2)
+        }
     }
 }
 
 impl<T: Scalar> CandidateHeap<T> for Vec<InternalNeighbour<T>> {
     fn new_with_k(k: u32) -> Self {
-        vec![
-            InternalNeighbour {
-                index: 0,
-                dist2: NotNan::new(T::infinity()).unwrap()
-            };
-            k as usize
-        ]
+        Vec::with_capacity(k as usize)
     }
     fn into_vec(self) -> Vec<InternalNeighbour<T>> {
-        keep_finite_elements(self)
+        self
     }
     fn into_sorted_vec(self) -> Vec<InternalNeighbour<T>> {
-        keep_finite_elements(self)
+        self
+    }
+    fn clear(&mut self) {
+        Vec::clear(self)
+    }
+    fn extend_into(&self, out: &mut Vec<InternalNeighbour<T>>, _sorted: bool) {
+        out.extend_from_slice(self);
+    }
+    fn candidate_count(&self) -> usize {
+        Vec::len(self)
     }
 }
 
+/// A collector keeping the `K` nearest candidates in a fixed-size array, without allocating
+#[derive(Clone, Copy, Debug)]
+pub struct ArrayCollector<T: Scalar, const K: usize> {
+    candidates: [InternalNeighbour<T>; K],
+    len: usize,
+}
+
+impl<T: Scalar, const K: usize> CandidateCollector<T> for ArrayCollector<T, K> {
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        if K == 0 || (self.len == K && dist2 > self.candidates[K - 1].dist2) {
+            return;
+        }
+        let mut i = if self.len < K {
+            self.len += 1;
+            self.len - 1
+        } else {
+            K - 1
+        };
+        while i > 0 && self.candidates[i - 1].dist2 > dist2 {
+            self.candidates[i] = self.candidates[i - 1];
+            i -= 1;
+        }
+        self.candidates[i] = InternalNeighbour { index, dist2 };
+    }
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        if K == 0 {
+            Some(-NotNan::<T>::infinite())
+        } else if self.len < K {
+            None
+        } else {
+            Some(self.candidates[K - 1].dist2)
+        }
+    }
+}
+
+impl<T: Scalar, const K: usize> ArrayCollector<T, K> {
+    /// Creates a new, empty collector.
+    pub fn new() -> Self {
+        ArrayCollector {
+            candidates: [InternalNeighbour::default(); K],
+            len: 0,
+        }
+    }
+
+    /// Returns the number of collected candidates.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether no candidate was collected.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Removes all candidates.
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Returns the collected candidates, sorted by increasing distance.
+    pub(crate) fn as_slice(&self) -> &[InternalNeighbour<T>] {
+        &self.candidates[..self.len]
+    }
+
+    /// Iterates over the indices of the collected candidates, in the original point cloud,
+    /// by increasing distance.
+    pub fn externalise<'a, 'b, P: Point<T>>(
+        &'a self,
+        tree: &'b KDTree<T, P>,
+    ) -> impl ExactSizeIterator<Item = u32> + Clone + 'b
+    where
+        'a: 'b,
+    {
+        self.as_slice()
+            .iter()
+            .map(|inner| tree.externalise_index(inner.index))
+    }
+}
+
+impl<T: Scalar, const K: usize> Default for ArrayCollector<T, K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A collector keeping the nearest candidate, unfilled while its squared distance is infinite
+///
+/// As offered squared distances are finite, the infinite default marks that no candidate was kept.
 impl<T: Scalar> CandidateCollector<T> for InternalNeighbour<T> {
     fn add(&mut self, dist2: NotNan<T>, index: u32) {
         if dist2 < self.dist2 {
@@ -191,8 +619,8 @@ impl<T: Scalar> CandidateCollector<T> for InternalNeighbour<T> {
             self.index = index;
         }
     }
-    fn furthest_dist2(&self) -> NotNan<T> {
-        self.dist2
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        (!self.dist2.is_infinite()).then_some(self.dist2)
     }
 }
 impl<T: Scalar> CandidateHeap<T> for InternalNeighbour<T> {
@@ -200,7 +628,7 @@ impl<T: Scalar> CandidateHeap<T> for InternalNeighbour<T> {
         debug_assert_eq!(k, 1);
         InternalNeighbour {
             index: 0,
-            dist2: NotNan::new(T::infinity()).unwrap(),
+            dist2: NotNan::infinite(),
         }
     }
     fn into_vec(self) -> Vec<InternalNeighbour<T>> {
@@ -209,35 +637,241 @@ impl<T: Scalar> CandidateHeap<T> for InternalNeighbour<T> {
     fn into_sorted_vec(self) -> Vec<InternalNeighbour<T>> {
         vec![self]
     }
+    fn clear(&mut self) {
+        self.dist2 = NotNan::infinite();
+    }
+    fn extend_into(&self, out: &mut Vec<InternalNeighbour<T>>, _sorted: bool) {
+        out.push(*self);
+    }
+    fn candidate_count(&self) -> usize {
+        usize::from(self.dist2 != NotNan::infinite())
+    }
+}
+
+/// A collector passing the candidates to `inner`, except the one of index `excluded`, if any,
+/// see [`SelfMatch::ExcludeByIndex`](crate::SelfMatch::ExcludeByIndex)
+pub(crate) struct ExcludingCollector<'a, H> {
+    pub(crate) inner: &'a mut H,
+    pub(crate) excluded: Option<u32>,
+}
+
+impl<'a, T: Scalar, H: CandidateCollector<T>> CandidateCollector<T> for ExcludingCollector<'a, H> {
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        if Some(index) != self.excluded {
+            self.inner.add(dist2, index);
+        }
+    }
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        self.inner.furthest_dist2()
+    }
+}
+
+/// A collector keeping both the `k` nearest and the `k` farthest candidates, see [`KDTree::knn_extremes`]
+///
+/// It is not a [`CandidateCollector`], as the search may only prune a subtree
+/// that is useless to both sides.
+pub(crate) struct MinMaxCollector<T: Scalar> {
+    /// max-heap of the nearest candidates
+    nearest: BinaryHeap<InternalNeighbour<T>>,
+    /// min-heap of the farthest candidates
+    farthest: BinaryHeap<Reverse<InternalNeighbour<T>>>,
+    k: usize,
+}
+
+impl<T: Scalar> MinMaxCollector<T> {
+    pub(crate) fn new(k: u32) -> Self {
+        MinMaxCollector {
+            nearest: BinaryHeap::with_capacity(k as usize),
+            farthest: BinaryHeap::with_capacity(k as usize),
+            k: k as usize,
+        }
+    }
+
+    pub(crate) fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        if self.k == 0 {
+            return;
+        }
+        let candidate = InternalNeighbour { index, dist2 };
+        if self.nearest.len() < self.k {
+            self.nearest.push(candidate);
+        } else if let Some(mut nearest) = self.nearest.peek_mut() {
+            if dist2 < nearest.dist2 {
+                *nearest = candidate;
+            }
+        }
+        if self.farthest.len() < self.k {
+            self.farthest.push(Reverse(candidate));
+        } else if let Some(mut farthest) = self.farthest.peek_mut() {
+            if dist2 > farthest.0.dist2 {
+                *farthest = Reverse(candidate);
+            }
+        }
+    }
+
+    /// The squared distance beyond which candidates do not improve the nearest side
+    pub(crate) fn nearest_bound2(&self) -> Option<NotNan<T>> {
+        if self.k == 0 {
+            Some(-NotNan::<T>::infinite())
+        } else if self.nearest.len() < self.k {
+            None
+        } else {
+            self.nearest.peek().map(|n| n.dist2)
+        }
+    }
+
+    /// The squared distance below which candidates do not improve the farthest side
+    pub(crate) fn farthest_bound2(&self) -> NotNan<T> {
+        if self.k == 0 {
+            NotNan::infinite()
+        } else if self.farthest.len() < self.k {
+            -NotNan::<T>::infinite()
+        } else {
+            self.farthest
+                .peek()
+                .map_or(-NotNan::<T>::infinite(), |n| n.0.dist2)
+        }
+    }
+
+    /// Returns the nearest candidates by increasing distance, and the farthest by decreasing distance.
+    pub(crate) fn into_sorted_vecs(self) -> (Vec<InternalNeighbour<T>>, Vec<InternalNeighbour<T>>) {
+        let farthest = self
+            .farthest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|n| n.0)
+            .collect();
+        (self.nearest.into_sorted_vec(), farthest)
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::heap::CandidateHeap;
     use crate::infinite::HasInfinite;
     use crate::*;
+
+    #[test]
+    fn array_collector_keeps_k_nearest_sorted() {
+        let mut collector = ArrayCollector::<f32, 3>::new();
+        assert_eq!(collector.furthest_dist2(), None);
+        for (index, dist2) in [(0, 5.0), (1, 1.0), (2, 4.0), (3, 3.0), (4, 6.0), (5, 0.5)] {
+            collector.add(NotNan::new(dist2).unwrap(), index);
+        }
+        assert_eq!(collector.len(), 3);
+        let kept: Vec<_> = collector.as_slice().iter().map(|n| n.index).collect();
+        assert_eq!(kept, vec![5, 1, 3]);
+        assert_eq!(collector.furthest_dist2(), NotNan::new(3.0).ok());
+        let mut empty = ArrayCollector::<f32, 0>::new();
+        empty.add(NotNan::zero(), 0);
+        assert!(empty.is_empty());
+    }
     #[test]
-    fn keep_finite_elements() {
-        let v = vec![
-            InternalNeighbour {
-                index: 0,
-                dist2: NotNan::<f32>::zero(),
-            },
-            InternalNeighbour {
-                index: 0,
-                dist2: NotNan::<f32>::infinite(),
-            },
-            InternalNeighbour {
-                index: 0,
-                dist2: NotNan::<f32>::infinite(),
-            },
-        ];
-        let finite_v = crate::heap::keep_finite_elements(v);
-        assert_eq!(
-            finite_v,
-            vec![InternalNeighbour {
-                index: 0,
-                dist2: NotNan::<f32>::zero(),
-            }]
+    fn ties_are_sorted_by_external_index() {
+        use crate::simple_point::P2;
+        // a shuffled grid, many of whose points are at exactly the same distance of its center
+        let mut rng = crate::rng::SplitMix64::new(234);
+        let mut cloud: Vec<P2> = (-5..=5)
+            .flat_map(|x| (-5..=5).map(move |y| P2::new2d(x as f32, y as f32)))
+            .collect();
+        for i in (1..cloud.len()).rev() {
+            cloud.swap(i, rng.below(i as u64 + 1) as usize);
+        }
+        let tree = KDTree::new(&cloud);
+        let query = P2::new2d(0.0, 0.0);
+        // the points at squared distances 0, 1, 2, 4 and 5
+        let k = 21;
+        let is_tie_sorted = |neighbours: &[Neighbour<f32, P2>]| {
+            neighbours
+                .windows(2)
+                .all(|pair| (pair[0].dist2, pair[0].index) < (pair[1].dist2, pair[1].index))
+        };
+        let mut collector = BoundedCollector::with_capacity(k);
+        tree.nn_raw(&query, &mut collector, &Parameters::default(), None);
+        let sorted = collector.externalise_sorted(&tree);
+        assert_eq!(sorted.len(), k);
+        assert!(is_tie_sorted(&sorted));
+        assert_eq!(collector.externalise(&tree).len(), k);
+        for container in [CandidateContainer::Linear, CandidateContainer::BinaryHeap] {
+            let knn = tree.knn_advanced(k as u32, &query, container, &Parameters::default(), None);
+            assert_eq!(knn, sorted);
+        }
+        let mut drained = Vec::new();
+        collector.clone().drain_externalise(&tree, &mut drained);
+        assert_eq!(drained, sorted);
+        assert_eq!(collector.into_neighbours(&tree), sorted);
+        assert!(is_tie_sorted(&tree.knn_skip(
+            3,
+            10,
+            &query,
+            &Parameters::default()
+        )));
+    }
+
+    #[test]
+    fn public_collectors_drain_into_owned_results() {
+        let cloud: Vec<simple_point::P2> = simple_point::random_point_cloud(500);
+        let tree = KDTree::new(&cloud);
+        let parameters = Parameters {
+            max_radius: 20.0,
+            ..Parameters::default()
+        };
+        let (mut unbounded, mut bounded) = (
+            UnboundedCollector::with_capacity(0),
+            BoundedCollector::with_capacity(5),
         );
+        let (mut indices, mut neighbours) = (Vec::new(), Vec::new());
+        for query in &cloud[..10] {
+            tree.nn_raw(query, &mut unbounded, &parameters, None);
+            tree.nn_raw(query, &mut bounded, &parameters, None);
+            let expected: Vec<u32> = unbounded.externalise(&tree).collect();
+            assert_eq!(unbounded.clone().into_indices(&tree), expected);
+            let start = indices.len();
+            unbounded.drain_externalise(&tree, &mut indices);
+            assert_eq!(indices[start..], expected[..]);
+            let expected =
+                tree.knn_advanced(5, query, CandidateContainer::BinaryHeap, &parameters, None);
+            assert_eq!(bounded.clone().into_neighbours(&tree), expected);
+            let start = neighbours.len();
+            bounded.drain_externalise(&tree, &mut neighbours);
+            assert_eq!(neighbours[start..], expected[..]);
+            // drained collectors are empty and can be reused
+            assert_eq!(unbounded.externalise(&tree).len(), 0);
+            assert_eq!(bounded.externalise(&tree).len(), 0);
+        }
+    }
+
+    #[test]
+    fn min_max_collector_keeps_both_ends() {
+        let mut collector = heap::MinMaxCollector::<f32>::new(2);
+        assert_eq!(collector.nearest_bound2(), None);
+        assert_eq!(collector.farthest_bound2(), -f32::INFINITY);
+        for (index, dist2) in [(0, 5.0), (1, 1.0), (2, 4.0), (3, 3.0), (4, 6.0), (5, 0.5)] {
+            collector.add(NotNan::new(dist2).unwrap(), index);
+        }
+        assert_eq!(collector.nearest_bound2(), NotNan::new(1.0).ok());
+        assert_eq!(collector.farthest_bound2(), 5.0);
+        let (nearest, farthest) = collector.into_sorted_vecs();
+        let nearest: Vec<_> = nearest.iter().map(|n| n.index).collect();
+        let farthest: Vec<_> = farthest.iter().map(|n| n.index).collect();
+        assert_eq!(nearest, vec![5, 1]);
+        assert_eq!(farthest, vec![4, 0]);
+    }
+    #[test]
+    fn vec_collector_tells_unfilled_from_far() {
+        let mut collector = Vec::<InternalNeighbour<f32>>::new_with_k(3);
+        assert_eq!(collector.furthest_dist2(), None);
+        // saturated squared distances are kept, as any other
+        let max = NotNan::new(f32::MAX).unwrap();
+        collector.add(max, 0);
+        collector.add(NotNan::new(2.0).unwrap(), 1);
+        assert_eq!(collector.furthest_dist2(), None);
+        collector.add(max, 2);
+        assert_eq!(collector.furthest_dist2(), Some(max));
+        collector.add(NotNan::new(1.0).unwrap(), 3);
+        let kept: Vec<_> = collector.iter().map(|n| n.index).collect();
+        assert_eq!(kept, vec![3, 1, 0]);
+        assert_eq!(collector.into_vec().len(), 3);
+        let empty = Vec::<InternalNeighbour<f32>>::new_with_k(0);
+        assert_eq!(empty.furthest_dist2(), Some(-NotNan::infinite()));
     }
 }
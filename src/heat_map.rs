@@ -0,0 +1,294 @@
+//! Counting the visits of the nodes over a sample of queries, and collapsing the subtrees they rarely visit
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+
+use ordered_float::NotNan;
+
+use crate::heap::CandidateHeap;
+use crate::internal_parameters::InternalParameters;
+use crate::node::Node;
+use crate::query_trace::NodeVisitor;
+use crate::self_check::SELF_CHECK_SAMPLE;
+use crate::{
+    CandidateCollector, CandidateContainer, InternalNeighbour, KDTree, Nodes, Parameters, Point,
+    RelayoutMap, Scalar,
+};
+
+/// A subtree descended into by at most one in this many profiled queries is cold
+const COLD_RATIO: u64 = 100;
+
+/// Cold subtrees are collapsed into a leaf if they hold at most this many times the bucket size
+const MAX_COLD_LEAF_GROWTH: u32 = 4;
+
+/// The number of queries that descended into each node of a tree, see [`KDTree::profile`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NodeHeatMap {
+    /// number of visits of each node, by node id
+    visits: Vec<u64>,
+    query_count: u64,
+}
+
+impl NodeHeatMap {
+    /// Creates a heat map with no visits for a tree of `node_count` nodes.
+    pub fn new(node_count: usize) -> Self {
+        NodeHeatMap {
+            visits: alloc::vec![0; node_count],
+            query_count: 0,
+        }
+    }
+
+    /// Returns the number of nodes of the profiled tree.
+    pub fn node_count(&self) -> usize {
+        self.visits.len()
+    }
+
+    /// Returns the number of profiled queries.
+    pub fn query_count(&self) -> u64 {
+        self.query_count
+    }
+
+    /// Returns the number of profiled queries that descended into `node`, see [`KDTree::node_count`].
+    ///
+    /// Panics if `node` is not smaller than [`Self::node_count`].
+    pub fn visits(&self, node: usize) -> u64 {
+        self.visits[node]
+    }
+
+    /// Returns the number of visits of each node, by node id.
+    pub fn as_slice(&self) -> &[u64] {
+        &self.visits
+    }
+
+    /// Adds the visits and queries of `other`, profiled on the same tree, to this heat map.
+    ///
+    /// Panics if `other` does not have the same number of nodes.
+    pub fn merge(&mut self, other: &NodeHeatMap) {
+        assert_eq!(
+            self.visits.len(),
+            other.visits.len(),
+            "heat maps of trees of different node counts cannot be merged"
+        );
+        for (visits, other_visits) in self.visits.iter_mut().zip(&other.visits) {
+            *visits += other_visits;
+        }
+        self.query_count += other.query_count;
+    }
+}
+
+/// Counts the nodes a search descends into
+struct HeatVisitor<'a>(&'a mut [u64]);
+
+impl<T: Scalar> NodeVisitor<T> for HeatVisitor<'_> {
+    const ENABLED: bool = true;
+    fn visit(&mut self, node: usize, _: NotNan<T>, _: Option<NotNan<T>>, descended: bool) {
+        if descended {
+            self.0[node] += 1;
+        }
+    }
+}
+
+impl<T: Scalar + Send + Sync, P: Point<T> + Send + Sync> KDTree<T, P> {
+    /// Searches the `k` nearest neighbours of each of `queries`, as [`Self::knn_advanced`]
+    /// with the default candidate container, and counts the queries that descended into each node.
+    ///
+    /// The counts, for example over a sample of the queries of a workload, can adapt the tree
+    /// to that workload with [`Self::optimize_for`].
+    /// If the `rayon` feature is enabled, the queries are processed in parallel,
+    /// each worker counting the visits of its queries, which are then merged.
+    /// Panics if a query has NaN coordinates.
+    pub fn profile(&self, queries: &[P], k: u32, parameters: &Parameters<T>) -> NodeHeatMap {
+        match Self::default_candidate_container(k) {
+            CandidateContainer::Linear => {
+                self.profile_heap::<Vec<InternalNeighbour<T>>>(queries, k, parameters)
+            }
+            CandidateContainer::BinaryHeap => {
+                self.profile_heap::<BinaryHeap<InternalNeighbour<T>>>(queries, k, parameters)
+            }
+        }
+    }
+
+    fn profile_heap<H: CandidateHeap<T> + CandidateCollector<T> + Send>(
+        &self,
+        queries: &[P],
+        k: u32,
+        parameters: &Parameters<T>,
+    ) -> NodeHeatMap {
+        let internal_parameters = InternalParameters::new(parameters, self.scale);
+        let k = k.min(self.len() as u32);
+        let (_, (heat, _)) = self.map_indices_folded(
+            queries.len(),
+            || (NodeHeatMap::new(self.nodes.len()), H::new_with_k(k)),
+            |(heat, heap), index| {
+                heap.clear();
+                let query = self.query_coordinates(&queries[index]);
+                let mut visitor = HeatVisitor(&mut heat.visits);
+                self.knn_internal_visited(heap, &mut visitor, &query, &internal_parameters);
+                heat.query_count += 1;
+            },
+            |(mut heat, heap), (other, _)| {
+                heat.merge(&other);
+                (heat, heap)
+            },
+        );
+        heat
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Adapts the structure of the tree to the queries profiled in `heat` by [`Self::profile`].
+    ///
+    /// The subtrees descended into by at most 1% of the profiled queries, if they hold at most
+    /// four times the bucket size, are collapsed into a single leaf.
+    /// The nodes of the hot paths then follow each other more closely in storage,
+    /// the tree takes less memory, and the rare queries in the cold regions visit fewer nodes
+    /// for more distances computed, while the hot queries search the same nodes.
+    /// The nodes stay in depth-first order, the left child of a node following it,
+    /// and the points keep their place, as the points of a subtree are already contiguous,
+    /// so that the tree stays valid for all other functions and exports.
+    /// The queries return the same neighbours, except for ties and approximate searches.
+    /// The node ids change, so `heat` does not apply to the adapted tree, which can be profiled again,
+    /// and the returned map gives the old id of each node, a collapsed leaf having the id of the root
+    /// of the subtree it replaces, to migrate [`NodeAnnotations`](crate::NodeAnnotations).
+    /// Nothing changes if `heat` has no queries.
+    /// Panics if `heat` does not have the node count of the tree.
+    pub fn optimize_for(&mut self, heat: &NodeHeatMap) -> RelayoutMap {
+        assert_eq!(
+            heat.node_count(),
+            self.nodes.len(),
+            "the heat map was profiled on a tree of another node count"
+        );
+        let old_node_count = self.nodes.len();
+        if heat.query_count == 0 {
+            return RelayoutMap::new(
+                (0..old_node_count as u32).map(Some).collect(),
+                old_node_count,
+            );
+        }
+        let mut nodes = Vec::with_capacity(self.nodes.len());
+        let mut sources = Vec::with_capacity(self.nodes.len());
+        self.collapse_cold(0, heat, &mut nodes, &mut sources);
+        self.nodes = nodes;
+        self.refresh_subtree_sums();
+        self.refresh_time_ranges();
+        debug_assert_eq!(self.self_check(SELF_CHECK_SAMPLE, 0), Ok(()));
+        RelayoutMap::new(sources, old_node_count)
+    }
+
+    /// Writes the subtree of `node` to `nodes`, collapsing its cold subtrees,
+    /// and the old id of each written node to `sources`
+    fn collapse_cold(
+        &self,
+        node: usize,
+        heat: &NodeHeatMap,
+        nodes: &mut Nodes<T, P>,
+        sources: &mut Vec<Option<u32>>,
+    ) {
+        sources.push(Some(node as u32));
+        let cold = heat.visits[node] * COLD_RATIO <= heat.query_count;
+        let max_leaf_size = self
+            .options
+            .bucket_size
+            .saturating_mul(MAX_COLD_LEAF_GROWTH);
+        self.nodes[node].dispatch_on_type(
+            nodes,
+            |nodes, split_dim, split_val, right_child| {
+                let (start, end) = self.subtree_range(node);
+                if cold && end - start <= max_leaf_size {
+                    nodes.push(Node::new_leaf_node(start, end - start));
+                    return;
+                }
+                let position = nodes.len();
+                nodes.push(Node::new_split_node(split_dim, split_val));
+                self.collapse_cold(node + 1, heat, nodes, sources);
+                let right_position = nodes.len() as u32;
+                nodes[position].set_child_index(right_position);
+                self.collapse_cold(right_child as usize, heat, nodes, sources);
+            },
+            |nodes, _, _| nodes.push(self.nodes[node].clone()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    /// Queries clustered in a small region of the cloud, and a few spread over it
+    fn skewed_queries(count: usize) -> Vec<P2> {
+        (0..count)
+            .map(|i| {
+                let point: P2 = random_point();
+                if i % 50 == 0 {
+                    point
+                } else {
+                    P2::new2d(
+                        point.0[0].into_inner() * 0.05,
+                        point.0[1].into_inner() * 0.05,
+                    )
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn profiles_count_the_descents_and_merge() {
+        let cloud: Vec<P2> = random_point_cloud(5000);
+        let tree = KDTree::new(&cloud);
+        let queries = skewed_queries(300);
+        let heat = tree.profile(&queries, 4, &Parameters::default());
+        assert_eq!(heat.node_count(), tree.node_count());
+        assert_eq!(heat.query_count(), 300);
+        assert_eq!(heat.visits(0), 300);
+        let visited_nodes: u64 = queries
+            .iter()
+            .map(|query| {
+                let container = KDTree::<f32, P2>::default_candidate_container(4);
+                let (_, stats) = tree.knn_with_stats(4, query, container, &Parameters::default());
+                stats.visited_nodes as u64
+            })
+            .sum();
+        assert_eq!(heat.as_slice().iter().sum::<u64>(), visited_nodes);
+        let mut merged = tree.profile(&queries[..100], 4, &Parameters::default());
+        merged.merge(&tree.profile(&queries[100..], 4, &Parameters::default()));
+        assert_eq!(merged, heat);
+    }
+
+    #[test]
+    fn optimizing_for_a_profile_keeps_the_results() {
+        let cloud: Vec<P2> = random_point_cloud(20000);
+        let ids: Vec<u64> = (0..cloud.len() as u64).map(|id| id * 7).collect();
+        let mut tree = KDTree::new_with_ids(&cloud, &ids);
+        let reference = tree.clone();
+        let queries = skewed_queries(2000);
+        let heat = tree.profile(&queries, 4, &Parameters::default());
+        tree.optimize_for(&heat);
+        // cold subtrees are collapsed
+        assert!(tree.node_count() < reference.node_count());
+        assert_eq!(tree.self_check(500, 0), Ok(()));
+        let uniform: Vec<P2> = random_point_cloud(200);
+        for query in queries.iter().chain(&uniform) {
+            assert_eq!(tree.knn(4, query), reference.knn(4, query));
+        }
+        for index in [0, 77, 19999] {
+            assert_eq!(tree.id_of(index), ids[index as usize]);
+        }
+        // the queries in the cold regions visit fewer nodes
+        let visited = |tree: &KDTree<f32, P2>| -> u32 {
+            let container = KDTree::<f32, P2>::default_candidate_container(4);
+            uniform
+                .iter()
+                .map(|query| {
+                    let (_, stats) =
+                        tree.knn_with_stats(4, query, container, &Parameters::default());
+                    stats.visited_nodes
+                })
+                .sum()
+        };
+        assert!(visited(&tree) < visited(&reference));
+        // heat maps without queries change nothing
+        let mut unchanged = reference.clone();
+        unchanged.optimize_for(&NodeHeatMap::new(reference.node_count()));
+        assert_eq!(unchanged, reference);
+    }
+}
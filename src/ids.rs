@@ -0,0 +1,198 @@
+//! Identifiers of the points chosen by the user, stable whatever their position in the point cloud
+
+use alloc::vec::Vec;
+
+use crate::{BuildError, BuildOptions, KDTree, Point, Scalar};
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Creates a new KD-Tree from a point cloud, where `ids[i]` is the identifier of `cloud[i]`.
+    ///
+    /// Identifiers may repeat, see [`Self::try_new_with_ids`].
+    /// Panics if `ids` does not have the same length as `cloud`, or in the same cases as [`Self::new`].
+    pub fn new_with_ids(cloud: &[P], ids: &[u64]) -> Self {
+        Self::try_new_with_ids(cloud, ids, &BuildOptions::default(), false)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Creates a new KD-Tree from a point cloud, where `ids[i]` is the identifier of `cloud[i]`,
+    /// with user-provided options.
+    ///
+    /// The identifiers are stored in the order of the points in the tree, and kept by updates,
+    /// merges, rebuilds and flat exports, see [`Self::id_of`].
+    /// Returns an error if `ids` does not have the same length as `cloud`,
+    /// if `unique_ids` is true and an identifier repeats, or in the same cases as [`Self::try_new_with_options`].
+    pub fn try_new_with_ids(
+        cloud: &[P],
+        ids: &[u64],
+        options: &BuildOptions,
+        unique_ids: bool,
+    ) -> Result<Self, BuildError> {
+        if ids.len() != cloud.len() {
+            return Err(BuildError::ValuesLengthMismatch {
+                points: cloud.len(),
+                values: ids.len(),
+            });
+        }
+        if unique_ids {
+            if let Some(id) = first_duplicate(ids.to_vec()) {
+                return Err(BuildError::DuplicateId { id });
+            }
+        }
+        let mut tree = Self::try_new_with_options(cloud, options)?;
+        tree.set_external_ids(Some(ids.to_vec()));
+        Ok(tree)
+    }
+
+    /// Returns whether the tree was built with identifiers.
+    pub fn has_ids(&self) -> bool {
+        self.ids.is_some()
+    }
+
+    /// Returns the identifier of the point of index `external_index` in the original point cloud,
+    /// which is the index itself if the tree was built without identifiers.
+    ///
+    /// Panics if `external_index` is not smaller than [`Self::len`].
+    pub fn id_of(&self, external_index: u32) -> u64 {
+        match &self.ids {
+            Some(ids) => ids[self.internalise_index(external_index) as usize],
+            None => {
+                assert!((external_index as usize) < self.len(), "index out of range");
+                external_index as u64
+            }
+        }
+    }
+
+    /// Returns an identifier used by several points, if any, in time O(n log n).
+    pub fn duplicate_id(&self) -> Option<u64> {
+        self.ids.clone().and_then(first_duplicate)
+    }
+
+    /// Returns the identifiers in the order of the original point cloud, if the tree has some
+    pub(crate) fn external_ids(&self) -> Option<Vec<u64>> {
+        self.ids.as_ref()?;
+        Some(
+            (0..self.len() as u32)
+                .map(|index| self.id_of(index))
+                .collect(),
+        )
+    }
+
+    /// Returns the identifiers of the points of `trees`, one tree after the other,
+    /// if any of them has identifiers
+    pub(crate) fn concatenated_ids<'a>(
+        trees: impl Iterator<Item = &'a Self> + Clone,
+    ) -> Option<Vec<u64>>
+    where
+        T: 'a,
+        P: 'a,
+    {
+        if !trees.clone().any(KDTree::has_ids) {
+            return None;
+        }
+        Some(
+            trees
+                .flat_map(|tree| (0..tree.len() as u32).map(move |index| tree.id_of(index)))
+                .collect(),
+        )
+    }
+
+    /// Stores `ids`, given in the order of the original point cloud, in the internal order
+    pub(crate) fn set_external_ids(&mut self, ids: Option<Vec<u64>>) {
+        self.ids = ids.map(|ids| {
+            self.indices
+                .iter()
+                .map(|external| ids[*external as usize])
+                .collect()
+        });
+    }
+}
+
+/// Returns the smallest value of `ids` that appears more than once
+fn first_duplicate(mut ids: Vec<u64>) -> Option<u64> {
+    ids.sort_unstable();
+    ids.windows(2)
+        .find(|pair| pair[0] == pair[1])
+        .map(|pair| pair[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn ids_follow_points_through_mutations() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let ids: Vec<u64> = (0..1000).map(|i| 10_000 + 7 * i).collect();
+        let mut tree = KDTree::new_with_ids(&cloud, &ids);
+        assert!(tree.has_ids());
+        let query: P2 = random_point();
+        for neighbour in tree.knn(10, &query) {
+            assert_eq!(tree.id_of(neighbour.index), ids[neighbour.index as usize]);
+        }
+        tree.update_point(3, random_point());
+        tree.rebuild();
+        assert_eq!(tree.id_of(3), ids[3]);
+        // merging keeps the identifiers of both trees, indices following each other
+        let other_cloud: Vec<P2> = random_point_cloud(50);
+        let merged = KDTree::merge(tree.clone(), KDTree::new(&other_cloud));
+        let balanced = KDTree::merge_all(vec![tree.clone(), KDTree::new(&cloud)]);
+        for index in 0..1000 {
+            assert_eq!(merged.id_of(index), ids[index as usize]);
+            assert_eq!(balanced.id_of(index), ids[index as usize]);
+        }
+        assert_eq!(merged.id_of(1049), 49);
+        assert_eq!(balanced.id_of(1999), 999);
+        // flat exports round-trip the identifiers
+        let flat = tree.export_flat();
+        assert_eq!(flat.import::<P2>().unwrap(), tree);
+        let mut invalid = flat.clone();
+        invalid.ids.as_mut().unwrap().pop();
+        assert!(matches!(
+            invalid.import::<P2>(),
+            Err(BuildError::InvalidFlatTree(_))
+        ));
+        // without identifiers, the identifier is the index
+        let plain = KDTree::new(&cloud);
+        assert!(!plain.has_ids());
+        assert_eq!(plain.id_of(5), 5);
+        assert_eq!(plain.export_flat().ids, None);
+    }
+
+    #[test]
+    fn duplicate_ids_are_detected() {
+        let cloud: Vec<P2> = random_point_cloud(10);
+        let ids = [3, 1, 4, 1, 5, 9, 2, 6, 5, 8];
+        let tree = KDTree::new_with_ids(&cloud, &ids);
+        assert_eq!(tree.duplicate_id(), Some(1));
+        assert_eq!(
+            KDTree::try_new_with_ids(&cloud, &ids, &BuildOptions::default(), true),
+            Err(BuildError::DuplicateId { id: 1 })
+        );
+        assert_eq!(
+            KDTree::try_new_with_ids(&cloud, &ids[1..], &BuildOptions::default(), false),
+            Err(BuildError::ValuesLengthMismatch {
+                points: 10,
+                values: 9
+            })
+        );
+        let unique = KDTree::new_with_ids(&cloud, &[0, 1, 2, 3, 4, 5, 6, 7, 8, 42]);
+        assert_eq!(unique.duplicate_id(), None);
+        // insertions take an identifier
+        let mut snapshot = unique.snapshot();
+        let inserted = snapshot.insert_with_id(random_point(), 77);
+        let plain = snapshot.insert(random_point());
+        assert_eq!(snapshot.id_of(9), 42);
+        assert_eq!(snapshot.id_of(inserted), 77);
+        assert_eq!(snapshot.id_of(plain), 11);
+        let mut dynamic = DynamicKDTree::with_buffer_size(4);
+        dynamic.extend(&cloud[..3]);
+        for (i, point) in cloud[3..].iter().enumerate() {
+            dynamic.push_with_id(*point, 100 + i as u64);
+        }
+        dynamic.push(random_point());
+        assert_eq!(dynamic.id_of(2), 2);
+        assert_eq!(dynamic.id_of(9), 106);
+        assert_eq!(dynamic.id_of(10), 10);
+    }
+}
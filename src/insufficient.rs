@@ -0,0 +1,217 @@
+//! Completion of the k-nearest-neighbour searches that fewer than `k` neighbours within the radius under-fill
+
+use ordered_float::NotNan;
+
+use crate::heap::CandidateHeap;
+use crate::infinite::HasInfinite;
+use crate::internal_parameters::InternalParameters;
+use crate::{
+    CandidateCollector, InsufficientPolicy, KDTree, Point, QueryError, QueryStats, Scalar,
+};
+
+/// Offers to `heap` only the candidates beyond `radius2`, which it already holds all others of
+struct BeyondRadius<'a, T: Scalar, H> {
+    heap: &'a mut H,
+    radius2: NotNan<T>,
+}
+
+impl<T: Scalar, H: CandidateCollector<T>> CandidateCollector<T> for BeyondRadius<'_, T, H> {
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        if dist2 > self.radius2 {
+            self.heap.add(dist2, index);
+        }
+    }
+
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        self.heap.furthest_dist2()
+    }
+}
+
+/// Records whether any candidate is beyond `radius2`, pruning the search once one is
+struct ProbeBeyondRadius<T: Scalar> {
+    radius2: NotNan<T>,
+    found: bool,
+}
+
+impl<T: Scalar> CandidateCollector<T> for ProbeBeyondRadius<T> {
+    fn add(&mut self, dist2: NotNan<T>, _index: u32) {
+        self.found |= dist2 > self.radius2;
+    }
+
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        if self.found {
+            Some(-NotNan::<T>::infinite())
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Applies `internal_parameters.on_insufficient` to `heap`, the candidates of the search
+    /// for the `k` nearest neighbours of `query`, `k` being at most the number of points,
+    /// and returns the counters of the extra work.
+    ///
+    /// Nothing is done if `heap` is full or the radius is infinite.
+    /// To ignore the radius, the search continues from the candidates kept, without the radius,
+    /// offering only the candidates beyond it, as those within are already kept,
+    /// and pruning by the furthest candidate once `heap` is full.
+    pub(crate) fn complete_insufficient<H: CandidateHeap<T> + CandidateCollector<T>>(
+        &self,
+        heap: &mut H,
+        k: u32,
+        query: &[NotNan<T>],
+        internal_parameters: &InternalParameters<T>,
+    ) -> Result<QueryStats, QueryError> {
+        let radius2 = internal_parameters.max_radius2;
+        if internal_parameters.on_insufficient == InsufficientPolicy::Truncate
+            || radius2 == NotNan::infinite()
+            || heap.furthest_dist2().is_some()
+        {
+            return Ok(QueryStats::default());
+        }
+        let unbounded = InternalParameters {
+            max_radius2: NotNan::infinite(),
+            ..*internal_parameters
+        };
+        if internal_parameters.on_insufficient == InsufficientPolicy::IgnoreRadius {
+            let mut beyond = BeyondRadius { heap, radius2 };
+            return Ok(self.knn_internal(&mut beyond, query, &unbounded));
+        }
+        let mut probe = ProbeBeyondRadius {
+            radius2,
+            found: false,
+        };
+        self.knn_internal(&mut probe, query, &unbounded);
+        if probe.found {
+            Err(QueryError::InsufficientNeighbours {
+                found: heap.candidate_count(),
+                requested: k,
+            })
+        } else {
+            Ok(QueryStats::default())
+        }
+    }
+
+    /// Same as [`Self::complete_insufficient`], panicking with the error if any
+    pub(crate) fn complete_insufficient_or_panic<H: CandidateHeap<T> + CandidateCollector<T>>(
+        &self,
+        heap: &mut H,
+        k: u32,
+        query: &[NotNan<T>],
+        internal_parameters: &InternalParameters<T>,
+    ) -> QueryStats {
+        self.complete_insufficient(heap, k, query, internal_parameters)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn with_policy(max_radius: f32, on_insufficient: InsufficientPolicy) -> Parameters<f32> {
+        Parameters {
+            max_radius,
+            on_insufficient,
+            ..Parameters::default()
+        }
+    }
+
+    #[test]
+    fn ignoring_the_radius_continues_to_the_unbounded_results() {
+        let cloud: Vec<P2> = random_point_cloud(3000);
+        let tree = KDTree::new(&cloud);
+        let completing = with_policy(3.0, InsufficientPolicy::IgnoreRadius);
+        let truncating = with_policy(3.0, InsufficientPolicy::Truncate);
+        let mut under_filled = 0;
+        for query in &cloud[..100] {
+            for candidate_container in [CandidateContainer::Linear, CandidateContainer::BinaryHeap]
+            {
+                let unbounded =
+                    tree.knn_advanced(8, query, candidate_container, &Parameters::default(), None);
+                let truncated = tree.knn_advanced(8, query, candidate_container, &truncating, None);
+                under_filled += usize::from(truncated.len() < 8);
+                let completed = tree.knn_advanced(8, query, candidate_container, &completing, None);
+                assert_eq!(completed, unbounded);
+                assert_eq!(completed[..truncated.len()], truncated[..]);
+                let results =
+                    tree.knn_batch_results(8, &[*query], candidate_container, &completing);
+                assert_eq!(results.get(0), &unbounded[..]);
+            }
+            let index = tree.knn(1, query)[0].index;
+            let excluding = Parameters {
+                self_match: SelfMatch::ExcludeCoincident,
+                ..completing
+            };
+            let unbounded = Parameters {
+                self_match: SelfMatch::ExcludeCoincident,
+                ..Parameters::default()
+            };
+            assert_eq!(
+                tree.knn_of(8, index, &excluding),
+                tree.knn_of(8, index, &unbounded)
+            );
+        }
+        assert!(under_filled > 0, "the radius under-fills some searches");
+        // more neighbours than points are truncated to the points
+        assert_eq!(
+            tree.knn_advanced(
+                5000,
+                &cloud[0],
+                CandidateContainer::BinaryHeap,
+                &completing,
+                None
+            )
+            .len(),
+            cloud.len()
+        );
+    }
+
+    #[test]
+    fn insufficient_neighbours_are_reported_by_the_try_searches() {
+        let cloud = [
+            P2::new2d(0.0, 0.0),
+            P2::new2d(1.0, 0.0),
+            P2::new2d(10.0, 0.0),
+        ];
+        let tree = KDTree::new(&cloud);
+        let query = P2::new2d(0.0, 0.0);
+        let parameters = with_policy(2.0, InsufficientPolicy::Error);
+        let container = CandidateContainer::Linear;
+        assert_eq!(
+            tree.try_knn_advanced(3, &query, container, &parameters, None),
+            Err(Error::Query(QueryError::InsufficientNeighbours {
+                found: 2,
+                requested: 3
+            }))
+        );
+        assert_eq!(
+            tree.try_knn_advanced(2, &query, container, &parameters, None)
+                .map(|neighbours| neighbours.len()),
+            Ok(2)
+        );
+        // the requested neighbours are at most the points
+        assert_eq!(
+            tree.try_knn_advanced(10, &query, container, &parameters, None),
+            Err(Error::Query(QueryError::InsufficientNeighbours {
+                found: 2,
+                requested: 3
+            }))
+        );
+        // the query excluded as a self match is not missing, all other points being within the radius
+        let parameters = Parameters {
+            self_match: SelfMatch::ExcludeCoincident,
+            ..with_policy(20.0, InsufficientPolicy::Error)
+        };
+        assert_eq!(
+            tree.try_knn_advanced(3, &query, container, &parameters, None)
+                .map(|neighbours| neighbours.len()),
+            Ok(2)
+        );
+        assert!(tree
+            .knn_of(2, 0, &with_policy(0.5, InsufficientPolicy::Error))
+            .is_err());
+    }
+}
@@ -42,6 +42,6 @@ impl<T: FloatCore> PartialEq for InternalNeighbour<T> {
 
 impl<T: FloatCore> HasInfinite for NotNan<T> {
     fn infinite() -> Self {
-        NotNan::new(T::infinity()).unwrap()
+        NotNan::new(T::infinity()).expect("infinity is not NaN")
     }
 }
@@ -1,29 +1,201 @@
+use num_traits::One;
 use ordered_float::{FloatCore, NotNan};
 
-use crate::{Parameters, Scalar};
+use crate::distance_scale::saturate;
+use crate::infinite::HasInfinite;
+use crate::{
+    Boundary, InsufficientPolicy, ParameterError, Parameters, Radius, Scalar, TraversalOrder,
+};
 
 /// Parameters to be passed unchanged to internal recursive function
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub(crate) struct InternalParameters<T: FloatCore> {
+    /// squared maximal error ratio, infinite for a greedy descent
     pub(crate) max_error2: NotNan<T>,
     pub(crate) max_radius2: NotNan<T>,
+    /// whether points at distance zero are returned, see [`SelfMatch::allows_coincident`](crate::SelfMatch::allows_coincident)
     pub(crate) allow_self_match: bool,
+    /// index in the original point cloud of the point excluded as the query, see [`SelfMatch::ExcludeByIndex`](crate::SelfMatch::ExcludeByIndex)
+    pub(crate) excluded: Option<u32>,
+    pub(crate) traversal_order: TraversalOrder,
+    pub(crate) on_insufficient: InsufficientPolicy,
 }
 
-impl<T: FloatCore + Scalar> From<&Parameters<T>> for InternalParameters<T> {
-    fn from(value: &Parameters<T>) -> Self {
+impl<T: FloatCore + Scalar> InternalParameters<T> {
+    /// Converts user parameters, with `scale` the distance scale of the tree
+    ///
+    /// All searches convert their parameters here, also the ones not validating them,
+    /// so this panics with the error of [`Self::try_new`].
+    pub(crate) fn new(value: &Parameters<T>, scale: NotNan<T>) -> Self {
+        Self::try_new(value, scale).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Converts user parameters, with `scale` the distance scale of the tree,
+    /// or returns an error if `epsilon` or `max_radius` is NaN,
+    /// or if `epsilon` is not zero with the `strict-exact` feature
+    pub(crate) fn try_new(value: &Parameters<T>, scale: NotNan<T>) -> Result<Self, ParameterError> {
         let Parameters {
             epsilon,
             max_radius,
-            allow_self_match,
+            boundary,
+            self_match,
+            traversal_order,
+            on_insufficient,
             ..
         } = *value;
-        let max_error = epsilon + T::from(1).unwrap();
-        let max_error2 = NotNan::new(max_error * max_error).unwrap();
-        let max_radius2 = NotNan::new(max_radius * max_radius).unwrap();
-        InternalParameters {
+        if epsilon.is_nan() {
+            return Err(ParameterError::NonFiniteEpsilon);
+        }
+        if cfg!(feature = "strict-exact") && epsilon != T::zero() {
+            return Err(ParameterError::ApproximateSearch);
+        }
+        let max_error2 = if epsilon == T::infinity() {
+            NotNan::infinite()
+        } else {
+            let max_error = epsilon + T::one();
+            // saturate so that multiplying a zero distance by it does not lead to NaN
+            saturate(
+                NotNan::new(max_error * max_error).map_err(|_| ParameterError::NonFiniteEpsilon)?,
+            )
+        };
+        let max_radius2 = Radius::new(max_radius * scale.into_inner()).squared();
+        let max_radius2 =
+            NotNan::new(max_radius2.get()).map_err(|_| ParameterError::NanMaxRadius)?;
+        // all searches accept distances up to the radius inclusive, and prune cells beyond it
+        let max_radius2 = match boundary {
+            Boundary::Closed => max_radius2,
+            Boundary::Open => largest_below(max_radius2),
+        };
+        Ok(InternalParameters {
             max_error2,
             max_radius2,
-            allow_self_match,
+            allow_self_match: self_match.allows_coincident(),
+            excluded: self_match.excluded_index(),
+            traversal_order,
+            on_insufficient,
+        })
+    }
+
+    /// Returns the parameters excluding no point by index, for trees whose indices are not those of the queried structure
+    pub(crate) fn without_excluded(&self) -> Self {
+        InternalParameters {
+            excluded: None,
+            ..*self
+        }
+    }
+
+    /// Whether a candidate at internal squared distance `dist2` and of index `index` in the queried structure
+    /// is accepted: within the radius, not a self match if these are excluded, and not the excluded index
+    #[inline]
+    pub(crate) fn accepts(&self, dist2: NotNan<T>, index: u32) -> bool {
+        dist2 <= self.max_radius2
+            && (self.allow_self_match || *dist2 > <T as FloatCore>::epsilon())
+            && self.excluded != Some(index)
+    }
+
+    /// Whether a cell at internal squared distance `rd` is worth visiting,
+    /// given `bound2` the squared distance beyond which the collector is not interested in candidates,
+    /// `None` if it accepts any candidate
+    ///
+    /// With an infinite error, cells are only visited while the collector accepts any candidate.
+    pub(crate) fn within_error(&self, rd: NotNan<T>, bound2: Option<NotNan<T>>) -> bool {
+        self.within_error_of::<false>(rd, bound2)
+    }
+
+    /// Whether the search is exact, with no error factor to apply to the bounds
+    pub(crate) fn is_exact(&self) -> bool {
+        self.max_error2 == NotNan::one()
+    }
+
+    /// Same as [`Self::within_error`], comparing `rd` to `bound2` directly if `EXACT`,
+    /// which must only be set if [`Self::is_exact`]
+    #[inline]
+    pub(crate) fn within_error_of<const EXACT: bool>(
+        &self,
+        rd: NotNan<T>,
+        bound2: Option<NotNan<T>>,
+    ) -> bool {
+        match bound2 {
+            None => true,
+            Some(bound2) if EXACT => rd < bound2,
+            Some(_) if self.max_error2.is_infinite() => false,
+            Some(bound2) => rd * self.max_error2 < bound2,
+        }
+    }
+}
+
+/// Returns the largest value smaller than `radius2`, a squared radius positive or zero,
+/// so that comparing distances to it with `<=` compares them to `radius2` with `<`
+fn largest_below<T: Scalar>(radius2: NotNan<T>) -> NotNan<T> {
+    let radius2 = radius2.into_inner();
+    if radius2.is_infinite() {
+        // distances saturate, so they are all smaller
+        return NotNan::infinite();
+    }
+    if radius2 == T::zero() {
+        return -NotNan::<T>::one();
+    }
+    let two = T::one() + T::one();
+    // at most a few representable values below, then the midpoints up to `radius2`
+    let mut below = radius2 - radius2 * T::epsilon();
+    if below >= radius2 {
+        // the product underflowed, for subnormal squared radii
+        below = radius2 / two;
+    }
+    loop {
+        let middle = below + (radius2 - below) / two;
+        if middle <= below || middle >= radius2 {
+            return NotNan::new(below).expect("the squared radius is not NaN");
+        }
+        below = middle;
+    }
+}
+
+/// [`Parameters`] converted once to the internal units of a tree, see [`KDTree::prepare_parameters`](crate::KDTree::prepare_parameters)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PreparedParameters<T: Scalar> {
+    pub(crate) internal: InternalParameters<T>,
+    pub(crate) sort_results: bool,
+    /// distance scale of the tree the parameters were prepared for
+    pub(crate) scale: NotNan<T>,
+}
+
+#[cfg(test)]
+mod tests {
+    use ordered_float::NotNan;
+
+    use super::largest_below;
+
+    #[test]
+    fn largest_below_is_the_previous_value() {
+        let mut values = vec![
+            f32::from_bits(1),
+            f32::from_bits(2),
+            f32::MIN_POSITIVE,
+            f32::MIN_POSITIVE * 3.0,
+            f32::EPSILON,
+            0.5,
+            1.0,
+            25.0,
+            1.5,
+            f32::MAX,
+        ];
+        values.extend((0..1000u32).map(|i| f32::from_bits(i.wrapping_mul(2_654_435_761) >> 1)));
+        for value in values
+            .into_iter()
+            .filter(|value| value.is_finite() && *value > 0.0)
+        {
+            let below = largest_below(NotNan::new(value).unwrap()).into_inner();
+            assert_eq!(below.to_bits(), value.to_bits() - 1, "{:e}", value);
+        }
+        for value in [f64::from_bits(1), 1.0, 1e300, f64::MAX] {
+            let below = largest_below(NotNan::new(value).unwrap()).into_inner();
+            assert_eq!(below.to_bits(), value.to_bits() - 1);
         }
+        assert!(largest_below(NotNan::new(0.0f32).unwrap()).into_inner() < 0.0);
+        assert_eq!(
+            largest_below(NotNan::new(f32::INFINITY).unwrap()),
+            f32::INFINITY
+        );
     }
 }
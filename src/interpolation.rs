@@ -0,0 +1,197 @@
+//! Inverse-distance-weighted interpolation of values attached to the points
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+use num_traits::Float;
+
+use crate::internal_parameters::InternalParameters;
+use crate::{InternalNeighbour, KDTree, Parameters, Point, Scalar};
+
+impl<T: Scalar + Float, P: Point<T>> KDTree<T, P> {
+    /// Interpolates `values` at `query` by inverse distance weighting of its `k` nearest neighbours,
+    /// `values[i]` being the value of the point of index `i` in the original point cloud.
+    ///
+    /// Each neighbour at distance `d` has a weight of `1 / d^power`, `power` being usually 2.
+    /// If neighbours are at distance zero, the result is the mean of their values.
+    /// Returns `None` if there is no neighbour, because `k` is zero, the tree is empty,
+    /// or no point is within `parameters.max_radius`.
+    /// `parameters.sort_results` is ignored.
+    ///
+    /// Panics if `values` does not have as many elements as the tree has points.
+    pub fn idw(
+        &self,
+        query: &P,
+        k: u32,
+        power: T,
+        values: &[T],
+        parameters: &Parameters<T>,
+    ) -> Option<T> {
+        self.check_idw_values(values);
+        let mut heap = BinaryHeap::with_capacity(k.min(self.len() as u32) as usize);
+        let internal_parameters = InternalParameters::new(parameters, self.scale);
+        self.idw_with_heap(query, &mut heap, power, values, &internal_parameters)
+    }
+
+    /// Finds the neighbours of `query` in `heap`, which must be empty, and interpolates their values
+    fn idw_with_heap(
+        &self,
+        query: &P,
+        heap: &mut BinaryHeap<InternalNeighbour<T>>,
+        power: T,
+        values: &[T],
+        internal_parameters: &InternalParameters<T>,
+    ) -> Option<T> {
+        if heap.capacity() == 0 {
+            return None;
+        }
+        let query = self.query_coordinates(query);
+        self.knn_internal(heap, &query, internal_parameters);
+        let value = |neighbour: &InternalNeighbour<T>| {
+            values[self.indices[neighbour.index as usize] as usize]
+        };
+        // the ratios of distances do not depend on the scale, so the internal ones are used
+        let nearest_dist2 = heap.iter().min()?.dist2.into_inner();
+        let (mut weighted_sum, mut weight_sum) = (T::zero(), T::zero());
+        if nearest_dist2 == T::zero() {
+            // exact hits dominate all other weights
+            for neighbour in heap.iter().filter(|n| n.dist2.into_inner() == T::zero()) {
+                weighted_sum += value(neighbour);
+                weight_sum += T::one();
+            }
+        } else {
+            // weights relative to the nearest neighbour are at most one, so they do not overflow
+            let half_power = power / (T::one() + T::one());
+            for neighbour in heap.iter() {
+                let weight = Float::powf(nearest_dist2 / neighbour.dist2.into_inner(), half_power);
+                weighted_sum += weight * value(neighbour);
+                weight_sum += weight;
+            }
+        }
+        heap.clear();
+        Some(weighted_sum / weight_sum)
+    }
+
+    fn check_idw_values(&self, values: &[T]) {
+        assert_eq!(
+            values.len(),
+            self.len(),
+            "Interpolating {} values over a tree of {} points",
+            values.len(),
+            self.len()
+        );
+    }
+}
+
+impl<T: Scalar + Float + Send + Sync, P: Point<T> + Send + Sync> KDTree<T, P> {
+    /// Interpolates `values` at each of `queries` as [`Self::idw`], in the order of `queries`.
+    ///
+    /// The candidate heap is reused between queries.
+    /// If the `rayon` feature is enabled, queries are processed in parallel.
+    pub fn idw_batch(
+        &self,
+        queries: &[P],
+        k: u32,
+        power: T,
+        values: &[T],
+        parameters: &Parameters<T>,
+    ) -> Vec<Option<T>> {
+        self.check_idw_values(values);
+        let internal_parameters = InternalParameters::new(parameters, self.scale);
+        let capacity = k.min(self.len() as u32) as usize;
+        self.map_indices_with(
+            queries.len(),
+            || BinaryHeap::with_capacity(capacity),
+            |heap, index| {
+                self.idw_with_heap(&queries[index], heap, power, values, &internal_parameters)
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+    use float_cmp::approx_eq;
+
+    #[test]
+    fn idw_matches_explicit_neighbours() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let values: Vec<f32> = (0..1000).map(|i| (i % 17) as f32).collect();
+        let tree = KDTree::new(&cloud);
+        let parameters = Parameters::default();
+        let queries: Vec<P2> = random_point_cloud(50);
+        let batch = tree.idw_batch(&queries, 6, 2.0, &values, &parameters);
+        for (query, batch_value) in queries.iter().zip(batch) {
+            let neighbours = tree.knn(6, query);
+            let weight = |n: &Neighbour<f32, P2>| 1.0 / n.dist2.into_inner();
+            let weight_sum: f32 = neighbours.iter().map(weight).sum();
+            let expected = neighbours
+                .iter()
+                .map(|n| weight(n) * values[n.index as usize])
+                .sum::<f32>()
+                / weight_sum;
+            let value = tree.idw(query, 6, 2.0, &values, &parameters).unwrap();
+            assert!(
+                approx_eq!(f32, value, expected, epsilon = 1e-4),
+                "{value} {expected}"
+            );
+            assert_eq!(batch_value, Some(value));
+        }
+    }
+
+    #[test]
+    fn idw_exact_hits_and_empty_neighbourhoods() {
+        let cloud = vec![
+            P2::new2d(0.0, 0.0),
+            P2::new2d(1.0, 0.0),
+            P2::new2d(1.0, 0.0),
+            P2::new2d(5.0, 0.0),
+        ];
+        let values = [1.0, 2.0, 4.0, 8.0];
+        let tree = KDTree::new(&cloud);
+        let parameters = Parameters::default();
+        let idw = |query: P2, k: u32, parameters: &Parameters<f32>| {
+            tree.idw(&query, k, 2.0, &values, parameters)
+        };
+        assert_eq!(idw(P2::new2d(0.0, 0.0), 3, &parameters), Some(1.0));
+        assert_eq!(idw(P2::new2d(1.0, 0.0), 4, &parameters), Some(3.0));
+        // equidistant neighbours have equal weights
+        let value = idw(P2::new2d(3.0, 0.0), 3, &parameters).unwrap();
+        assert!(approx_eq!(f32, value, 14.0 / 3.0, ulps = 2));
+        assert_eq!(idw(P2::new2d(3.0, 0.0), 0, &parameters), None);
+        let radius = Parameters {
+            max_radius: 0.5,
+            ..Parameters::default()
+        };
+        assert_eq!(idw(P2::new2d(3.0, 0.0), 2, &radius), None);
+        let empty = KDTree::<f32, P2>::new(&[]);
+        assert_eq!(
+            empty.idw(&P2::new2d(0.0, 0.0), 2, 2.0, &[], &parameters),
+            None
+        );
+    }
+
+    #[test]
+    fn idw_does_not_depend_on_the_magnitude_of_coordinates() {
+        let cloud: Vec<P2> = random_point_cloud(200);
+        let values: Vec<f32> = (0..200).map(|i| (i % 7) as f32).collect();
+        let tiny_cloud: Vec<P2> = cloud.iter().map(|p| *p * 1e-30).collect();
+        let tree = KDTree::new(&cloud);
+        let tiny_tree = KDTree::new(&tiny_cloud);
+        let parameters = Parameters::default();
+        let queries: Vec<P2> = random_point_cloud(20);
+        for query in queries {
+            let expected = tree.idw(&query, 5, 2.0, &values, &parameters).unwrap();
+            let value = tiny_tree
+                .idw(&(query * 1e-30), 5, 2.0, &values, &parameters)
+                .unwrap();
+            assert!(
+                approx_eq!(f32, value, expected, epsilon = 1e-3),
+                "{value} {expected}"
+            );
+        }
+        // exact hits are found on the internal distances, which do not underflow
+        let hit = tiny_tree.idw(&tiny_cloud[3], 5, 2.0, &values, &parameters);
+        assert_eq!(hit, Some(values[3]));
+    }
+}
@@ -0,0 +1,265 @@
+//! Rigid transformations of the queries into the frame in which the tree was built
+
+use alloc::vec::Vec;
+
+use num_traits::Float;
+use ordered_float::NotNan;
+
+use crate::{KDTree, Neighbour, Parameters, Point, QueryError, Scalar};
+
+/// A rigid transformation `p ↦ R p + t` of points of `D` dimensions
+///
+/// The rotation `R` must be orthonormal for distances to be preserved, which is not checked:
+/// queries through [`KDTree::knn_transformed`] return the same neighbours as queries
+/// at the point transformed by [`Self::transform`], whatever the matrix.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Isometry<T: Scalar, const D: usize> {
+    /// the rotation matrix, row-major
+    pub rotation: [[T; D]; D],
+    /// the translation, applied after the rotation
+    pub translation: [T; D],
+}
+
+impl<T: Scalar, const D: usize> Isometry<T, D> {
+    /// Creates the transformation rotating by `rotation`, row-major, then translating by `translation`.
+    pub fn new(rotation: [[T; D]; D], translation: [T; D]) -> Self {
+        Isometry {
+            rotation,
+            translation,
+        }
+    }
+
+    /// Returns the transformation leaving points unchanged.
+    pub fn identity() -> Self {
+        let mut rotation = [[T::zero(); D]; D];
+        for (i, row) in rotation.iter_mut().enumerate() {
+            row[i] = T::one();
+        }
+        Isometry::new(rotation, [T::zero(); D])
+    }
+
+    /// Returns the inverse transformation `p ↦ Rᵀ (p - t)`, exact if the rotation is orthonormal.
+    pub fn inverse(&self) -> Self {
+        let mut rotation = [[T::zero(); D]; D];
+        for (i, row) in rotation.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = self.rotation[j][i];
+            }
+        }
+        let mut translation = [T::zero(); D];
+        for (i, value) in translation.iter_mut().enumerate() {
+            *value = -(0..D).fold(T::zero(), |sum, j| {
+                sum + rotation[i][j] * self.translation[j]
+            });
+        }
+        Isometry::new(rotation, translation)
+    }
+
+    /// Returns `point` transformed, or an error if `P` does not have `D` dimensions
+    /// or if a transformed coordinate is NaN.
+    ///
+    /// Each coordinate is the sum of the products of a row of the rotation with the point,
+    /// by increasing dimension, plus the translation.
+    pub fn transform<P: Point<T>>(&self, point: &P) -> Result<P, QueryError> {
+        if P::DIM as usize != D {
+            return Err(QueryError::DimensionMismatch {
+                query_dim: D as u32,
+                tree_dim: P::DIM,
+            });
+        }
+        let mut transformed = P::default();
+        for (i, row) in self.rotation.iter().enumerate() {
+            let value = (0..D).fold(T::zero(), |sum, j| sum + row[j] * *point.get(j as u32))
+                + self.translation[i];
+            let value = NotNan::new(value).map_err(|_| QueryError::NanCoordinate)?;
+            transformed.set(i as u32, value);
+        }
+        Ok(transformed)
+    }
+}
+
+impl<T: Scalar + Float> Isometry<T, 2> {
+    /// Creates the transformation rotating by `angle` radians counterclockwise, then translating by `translation`.
+    pub fn from_angle(angle: T, translation: [T; 2]) -> Self {
+        let (sin, cos) = Float::sin_cos(angle);
+        Isometry::new([[cos, -sin], [sin, cos]], translation)
+    }
+}
+
+impl<T: Scalar + Float> Isometry<T, 3> {
+    /// Creates the transformation rotating by the quaternion `[w, x, y, z]`, then translating by `translation`.
+    ///
+    /// The quaternion is normalised, so it only needs to be non-zero.
+    pub fn from_quaternion([w, x, y, z]: [T; 4], translation: [T; 3]) -> Self {
+        let norm2 = w * w + x * x + y * y + z * z;
+        let two = T::one() + T::one();
+        let s = two / norm2;
+        let rotation = [
+            [
+                T::one() - s * (y * y + z * z),
+                s * (x * y - w * z),
+                s * (x * z + w * y),
+            ],
+            [
+                s * (x * y + w * z),
+                T::one() - s * (x * x + z * z),
+                s * (y * z - w * x),
+            ],
+            [
+                s * (x * z - w * y),
+                s * (y * z + w * x),
+                T::one() - s * (x * x + y * y),
+            ],
+        ];
+        Isometry::new(rotation, translation)
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Finds the `k` nearest neighbour of `query`, given in another frame than the points of the tree,
+    /// with user-provided parameters.
+    ///
+    /// The query is transformed into the frame of the tree by `world_to_local`, see [`Isometry::transform`],
+    /// so the results are those of [`Self::knn_advanced`] at the transformed query.
+    /// Neighbours are points of the tree, in its frame.
+    /// As rigid transformations preserve distances, the distances are those in the frame of the query,
+    /// up to the rounding of the transformation.
+    /// Returns an error if `D` is not the number of dimensions of the tree,
+    /// or if a transformed coordinate is NaN.
+    pub fn knn_transformed<const D: usize>(
+        &self,
+        k: u32,
+        query: &P,
+        world_to_local: &Isometry<T, D>,
+        parameters: &Parameters<T>,
+    ) -> Result<Vec<Neighbour<T, P>>, QueryError> {
+        let query = world_to_local.transform(query)?;
+        Ok(self.knn_advanced(
+            k,
+            &query,
+            Self::default_candidate_container(k),
+            parameters,
+            None,
+        ))
+    }
+}
+
+impl<T: Scalar + Send + Sync, P: Point<T> + Send + Sync> KDTree<T, P> {
+    /// Finds the `k` nearest neighbour of each of `queries`, transformed into the frame of the tree
+    /// by the element of `world_to_local` at the same position, see [`Self::knn_transformed`].
+    ///
+    /// Returns the neighbours of each query in the order of `queries`,
+    /// or an error if `world_to_local` does not have the same length as `queries`,
+    /// or in the same cases as [`Self::knn_transformed`].
+    /// If the `rayon` feature is enabled, queries are processed in parallel.
+    pub fn knn_batch_transformed<const D: usize>(
+        &self,
+        k: u32,
+        queries: &[P],
+        world_to_local: &[Isometry<T, D>],
+        parameters: &Parameters<T>,
+    ) -> Result<Vec<Vec<Neighbour<T, P>>>, QueryError> {
+        if world_to_local.len() != queries.len() {
+            return Err(QueryError::OverrideCountMismatch {
+                queries: queries.len(),
+                overrides: world_to_local.len(),
+            });
+        }
+        let parameters = self.prepare_parameters(parameters);
+        self.map_indices(queries.len(), |index| {
+            let query = world_to_local[index].transform(&queries[index])?;
+            Ok(self.knn_prepared(k, &query, &parameters))
+        })
+        .into_iter()
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn dist2(a: &P3d, b: &P3d) -> f64 {
+        (0..3).map(|i| (*a.get(i) - *b.get(i)).powi(2)).sum::<f64>()
+    }
+
+    #[test]
+    fn transformed_queries_match_queries_at_transformed_points() {
+        let local: Vec<P3d> = random_point_cloud::<3>(2000)
+            .iter()
+            .map(|p| P3d::from([*p.get(0) as f64, *p.get(1) as f64, *p.get(2) as f64]))
+            .collect();
+        let tree = KDTree::new(&local);
+        let local_to_world = Isometry::from_quaternion([0.3, -0.5, 0.7, 0.2], [10.0, -4.0, 2.5]);
+        let world_to_local = local_to_world.inverse();
+        let queries: Vec<P3d> = (0..50)
+            .map(|_| {
+                let p = random_point::<3>();
+                P3d::from([*p.get(0) as f64, *p.get(1) as f64, *p.get(2) as f64])
+            })
+            .collect();
+        for query in &queries {
+            let found = tree
+                .knn_transformed(5, query, &world_to_local, &Parameters::default())
+                .unwrap();
+            assert_eq!(
+                found,
+                tree.knn(5, &world_to_local.transform(query).unwrap())
+            );
+            // distances are those between the query and the neighbours in the world frame
+            for neighbour in &found {
+                let world = local_to_world.transform(&neighbour.point).unwrap();
+                assert!((*neighbour.dist2 - dist2(query, &world)).abs() < 1e-9);
+            }
+        }
+        // each query of a batch has its own transform
+        let transforms: Vec<_> = (0..queries.len())
+            .map(|i| {
+                Isometry::from_quaternion([1.0, 0.0, i as f64 * 0.1, 0.0], [i as f64, 0.0, 0.0])
+            })
+            .collect();
+        let batch = tree
+            .knn_batch_transformed(3, &queries, &transforms, &Parameters::default())
+            .unwrap();
+        for ((query, transform), neighbours) in queries.iter().zip(&transforms).zip(batch) {
+            assert_eq!(
+                neighbours,
+                tree.knn_transformed(3, query, transform, &Parameters::default())
+                    .unwrap()
+            );
+        }
+        assert_eq!(
+            tree.knn_batch_transformed(3, &queries, &transforms[1..], &Parameters::default()),
+            Err(QueryError::OverrideCountMismatch {
+                queries: 50,
+                overrides: 49
+            })
+        );
+    }
+
+    #[test]
+    fn isometries_compose_with_their_inverse() {
+        let rotation = Isometry::from_angle(0.7f64, [3.0, -1.0]);
+        let point = P2d::from([1.5, -2.0]);
+        let back = rotation
+            .inverse()
+            .transform(&rotation.transform(&point).unwrap())
+            .unwrap();
+        for i in 0..2 {
+            assert!((*back.get(i) - *point.get(i)).abs() < 1e-12);
+        }
+        assert_eq!(Isometry::<f64, 2>::identity().transform(&point), Ok(point));
+        // a quaternion needs not be normalised
+        let half_turn = Isometry::from_quaternion([0.0, 0.0, 0.0, 2.0], [0.0; 3]);
+        let turned = half_turn.transform(&P3d::from([1.0, 2.0, 3.0])).unwrap();
+        assert_eq!(turned, P3d::from([-1.0, -2.0, 3.0]));
+        assert_eq!(
+            Isometry::<f64, 3>::identity().transform(&point),
+            Err(QueryError::DimensionMismatch {
+                query_dim: 3,
+                tree_dim: 2
+            })
+        );
+    }
+}
@@ -0,0 +1,361 @@
+//! Distances of points to their k-th neighbour, as used by outlier scores such as the Local Outlier Factor
+
+use alloc::{collections::BinaryHeap, vec, vec::Vec};
+use num_traits::Float;
+
+use crate::distance_scale::unscale_dist2;
+use crate::internal_parameters::InternalParameters;
+use crate::rng::SplitMix64;
+use crate::{CsrGraph, KDTree, KnnGraphSymmetry, Parameters, Point, Scalar, SelfMatch};
+
+impl<T: Scalar + Float + Send + Sync, P: Point<T> + Send + Sync> KDTree<T, P> {
+    /// Returns the distance of each point to its `k`-th nearest neighbour other than itself,
+    /// in the order of the original point cloud.
+    ///
+    /// The point itself is excluded by index, so a point with `k` duplicates has a distance of zero.
+    /// The distance is infinite for points that have less than `k` other points in the cloud,
+    /// or in the ball defined by `parameters.max_radius`.
+    /// `parameters.self_match` and `parameters.sort_results` are ignored.
+    /// If the `rayon` feature is enabled, neighbours are searched in parallel.
+    pub fn k_distances(&self, k: u32, parameters: &Parameters<T>) -> Vec<T> {
+        self.k_distances_of(None, k, parameters)
+    }
+
+    /// Returns the histogram of the distances of the points to their `k`-th nearest neighbour other than itself,
+    /// the "k-distance plot" used to choose a search radius, for example the `eps` of DBSCAN.
+    ///
+    /// The distances are computed as in [`Self::k_distances`], and the finite ones are counted
+    /// in `bins` bins of equal width between their minimum and maximum.
+    /// Panics if `bins` is zero.
+    pub fn nn_distance_histogram(
+        &self,
+        k: u32,
+        bins: usize,
+        parameters: &Parameters<T>,
+    ) -> Histogram<T> {
+        Histogram::new(&self.k_distances(k, parameters), bins)
+    }
+
+    /// Returns the histogram of the distances of `sample` points, drawn at random without replacement
+    /// from `seed`, to their `k`-th nearest neighbour other than themselves.
+    ///
+    /// This is a fast approximation of [`Self::nn_distance_histogram`], which it equals
+    /// if `sample` is at least the number of points.
+    /// Panics if `bins` is zero.
+    pub fn nn_distance_histogram_sampled(
+        &self,
+        k: u32,
+        bins: usize,
+        sample: usize,
+        seed: u64,
+        parameters: &Parameters<T>,
+    ) -> Histogram<T> {
+        // selection sampling (Knuth's algorithm S), which keeps the sources in increasing order
+        let mut rng = SplitMix64::new(seed);
+        let count = self.len();
+        let sample = sample.min(count);
+        let mut sources = Vec::with_capacity(sample);
+        for source in 0..count {
+            if rng.below((count - source) as u64) < (sample - sources.len()) as u64 {
+                sources.push(source as u32);
+            }
+        }
+        let distances = self.k_distances_of(Some(&sources), k, parameters);
+        Histogram::new(&distances, bins)
+    }
+
+    /// Returns the distances of `sources` to their `k`-th nearest neighbour, or of all points if `None`
+    fn k_distances_of(
+        &self,
+        sources: Option<&[u32]>,
+        k: u32,
+        parameters: &Parameters<T>,
+    ) -> Vec<T> {
+        let count = sources.map_or(self.len(), <[u32]>::len);
+        if k == 0 {
+            return vec![T::zero(); count];
+        }
+        if k as usize >= self.len() {
+            return vec![<T as Float>::infinity(); count];
+        }
+        let parameters = Parameters {
+            self_match: SelfMatch::Allow,
+            ..*parameters
+        };
+        let internal_parameters = InternalParameters::new(&parameters, self.scale);
+        let capacity = k as usize + 1;
+        self.map_indices_with(
+            count,
+            || BinaryHeap::with_capacity(capacity),
+            |heap, index| {
+                let source = sources.map_or(index as u32, |sources| sources[index]);
+                self.knn_of_stored_point(source, heap, &internal_parameters);
+                // the furthest of the k + 1 candidates is the k-th other than the point itself,
+                // or a duplicate of it if the point is not among the candidates
+                let k_distance = match heap.peek() {
+                    Some(furthest) if heap.len() == capacity => {
+                        unscale_dist2(furthest.dist2, self.scale).sqrt()
+                    }
+                    _ => <T as Float>::infinity(),
+                };
+                heap.clear();
+                k_distance
+            },
+        )
+    }
+
+    /// Returns the distance of each point to its `k`-th nearest neighbour other than itself,
+    /// and the directed graph linking each point to these neighbours.
+    ///
+    /// See [`Self::k_distances`] and [`Self::knn_graph_csr`].
+    pub fn k_distances_and_graph(
+        &self,
+        k: u32,
+        parameters: &Parameters<T>,
+    ) -> (Vec<T>, CsrGraph<T>) {
+        let graph = self.knn_graph_csr(k, parameters, KnnGraphSymmetry::Directed);
+        let k_distances = (0..self.len() as u32)
+            .map(|node| {
+                let (_, weights) = graph.edges(node);
+                if k == 0 {
+                    T::zero()
+                } else if weights.len() < k as usize {
+                    <T as Float>::infinity()
+                } else {
+                    weights
+                        .iter()
+                        .fold(T::zero(), |max, weight| Float::max(max, *weight))
+                        .sqrt()
+                }
+            })
+            .collect();
+        (k_distances, graph)
+    }
+}
+
+/// A histogram of distances, see [`KDTree::nn_distance_histogram`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram<T: Scalar> {
+    /// `bins + 1` increasing edges, bin `i` spanning from edge `i` to edge `i + 1`
+    edges: Vec<T>,
+    counts: Vec<u64>,
+    infinite_count: u64,
+    mean: Option<T>,
+}
+
+impl<T: Scalar + Float> Histogram<T> {
+    fn new(distances: &[T], bins: usize) -> Self {
+        assert!(bins > 0, "a histogram needs at least one bin");
+        let finite = || distances.iter().copied().filter(|d| Float::is_finite(*d));
+        let (min, max) = finite().fold(
+            (<T as Float>::infinity(), <T as Float>::neg_infinity()),
+            |(min, max), d| (Float::min(min, d), Float::max(max, d)),
+        );
+        let finite_count = finite().count();
+        let infinite_count = (distances.len() - finite_count) as u64;
+        if finite_count == 0 {
+            return Histogram {
+                edges: Vec::new(),
+                counts: Vec::new(),
+                infinite_count,
+                mean: None,
+            };
+        }
+        let bin_count = T::from(bins).expect("the number of bins fits the scalar type");
+        let width = (max - min) / bin_count;
+        let mut edges: Vec<T> = (0..bins)
+            .map(|i| min + width * T::from(i).expect("a bin index fits the scalar type"))
+            .collect();
+        edges.push(max);
+        let mut counts = vec![0; bins];
+        let mut sum = T::zero();
+        for distance in finite() {
+            let mut bin = if width > T::zero() {
+                ((distance - min) / width)
+                    .to_usize()
+                    .unwrap_or(bins)
+                    .min(bins - 1)
+            } else {
+                0
+            };
+            // make the bins agree with the rounded edges
+            while bin + 1 < bins && distance >= edges[bin + 1] {
+                bin += 1;
+            }
+            while bin > 0 && distance < edges[bin] {
+                bin -= 1;
+            }
+            counts[bin] += 1;
+            sum += distance;
+        }
+        let mean =
+            sum / T::from(finite_count).expect("the number of distances fits the scalar type");
+        Histogram {
+            edges,
+            counts,
+            infinite_count,
+            mean: Some(mean),
+        }
+    }
+
+    /// Returns the edges of the bins, which are empty if no distance is finite.
+    ///
+    /// The first edge is the minimum distance and the last one the maximum distance.
+    pub fn edges(&self) -> &[T] {
+        &self.edges
+    }
+
+    /// Returns the number of finite distances in each bin, which are empty if no distance is finite.
+    ///
+    /// Bins include their lower edge, and the last one also its upper edge.
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// Returns the number of finite distances.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Returns the number of infinite distances, of points with less than `k` neighbours in reach.
+    pub fn infinite_count(&self) -> u64 {
+        self.infinite_count
+    }
+
+    /// Returns the minimum finite distance, if any.
+    pub fn min(&self) -> Option<T> {
+        self.edges.first().copied()
+    }
+
+    /// Returns the maximum finite distance, if any.
+    pub fn max(&self) -> Option<T> {
+        self.edges.last().copied()
+    }
+
+    /// Returns the mean of the finite distances, if any.
+    pub fn mean(&self) -> Option<T> {
+        self.mean
+    }
+
+    /// Returns the distance below which `percent` percent of the finite distances lie, if any.
+    ///
+    /// The distances are assumed to be evenly spread within each bin,
+    /// so the result is exact to within a bin width.
+    /// `percent` is clamped to `0..=100`.
+    pub fn percentile(&self, percent: T) -> Option<T> {
+        let hundred = T::from(100).expect("100 fits the scalar type");
+        let percent = Float::max(Float::min(percent, hundred), T::zero());
+        let count = T::from(self.count())?;
+        let target = percent / hundred * count;
+        let mut below = T::zero();
+        for (bin, bin_count) in self.counts.iter().enumerate() {
+            let bin_count = T::from(*bin_count).expect("a count fits the scalar type");
+            if bin_count > T::zero() && below + bin_count >= target {
+                let (lower, upper) = (self.edges[bin], self.edges[bin + 1]);
+                let fraction = (target - below) / bin_count;
+                return Some(lower + (upper - lower) * fraction);
+            }
+            below += bin_count;
+        }
+        self.max()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn k_distances_match_knn() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let tree = KDTree::new(&cloud);
+        let parameters = Parameters::default();
+        let k_distances = tree.k_distances(5, &parameters);
+        let (k_distances_from_graph, graph) = tree.k_distances_and_graph(5, &parameters);
+        assert_eq!(k_distances, k_distances_from_graph);
+        assert_eq!(graph.edge_count(), 5 * cloud.len());
+        for (point, k_distance) in cloud.iter().zip(&k_distances) {
+            let expected = tree.knn(6, point)[5].dist2.sqrt();
+            assert_eq!(*k_distance, expected);
+        }
+    }
+
+    #[test]
+    fn nn_distance_histogram_bins_k_distances() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let tree = KDTree::new(&cloud);
+        let parameters = Parameters::default();
+        let k_distances = tree.k_distances(3, &parameters);
+        let histogram = tree.nn_distance_histogram(3, 10, &parameters);
+        let (min, max) = k_distances
+            .iter()
+            .fold((f32::INFINITY, 0.0f32), |(min, max), d| {
+                (min.min(*d), max.max(*d))
+            });
+        assert_eq!(histogram.edges().len(), 11);
+        assert_eq!(histogram.min(), Some(min));
+        assert_eq!(histogram.max(), Some(max));
+        assert_eq!(histogram.count(), 1000);
+        assert_eq!(histogram.infinite_count(), 0);
+        let mean = k_distances.iter().sum::<f32>() / 1000.0;
+        assert!((histogram.mean().unwrap() - mean).abs() < 1e-3 * mean);
+        for (bin, count) in histogram.counts().iter().enumerate() {
+            let (lower, upper) = (histogram.edges()[bin], histogram.edges()[bin + 1]);
+            let expected = k_distances
+                .iter()
+                .filter(|d| **d >= lower && (**d < upper || (bin == 9 && **d == upper)))
+                .count();
+            assert_eq!(*count as usize, expected);
+        }
+        let width = (max - min) / 10.0;
+        let mut sorted = k_distances.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let median = histogram.percentile(50.0).unwrap();
+        assert!((median - sorted[500]).abs() <= width);
+        assert_eq!(histogram.percentile(0.0), Some(min));
+        assert_eq!(histogram.percentile(100.0), Some(max));
+        // sampling all points is exact, sampling fewer is deterministic
+        assert_eq!(
+            tree.nn_distance_histogram_sampled(3, 10, 5000, 1, &parameters),
+            histogram
+        );
+        let sampled = tree.nn_distance_histogram_sampled(3, 10, 100, 7, &parameters);
+        assert_eq!(sampled.count(), 100);
+        assert_eq!(
+            sampled,
+            tree.nn_distance_histogram_sampled(3, 10, 100, 7, &parameters)
+        );
+        // points without enough neighbours are only counted as infinite
+        let histogram = tree.nn_distance_histogram(1000, 4, &parameters);
+        assert_eq!(histogram.infinite_count(), 1000);
+        assert!(histogram.edges().is_empty());
+        assert_eq!(histogram.percentile(50.0), None);
+    }
+
+    #[test]
+    fn k_distances_with_duplicates_and_few_points() {
+        let mut cloud = vec![P2::new2d(0.0, 0.0); 3];
+        cloud.push(P2::new2d(3.0, 4.0));
+        let tree = KDTree::new(&cloud);
+        let parameters = Parameters::default();
+        assert_eq!(tree.k_distances(2, &parameters), vec![0.0, 0.0, 0.0, 5.0]);
+        assert_eq!(tree.k_distances(3, &parameters), vec![5.0, 5.0, 5.0, 5.0]);
+        assert!(tree
+            .k_distances(4, &parameters)
+            .iter()
+            .all(|d| d.is_infinite()));
+        let radius_limited = Parameters {
+            max_radius: 1.0,
+            ..Parameters::default()
+        };
+        assert_eq!(
+            tree.k_distances(2, &radius_limited),
+            vec![0.0, 0.0, 0.0, f32::INFINITY]
+        );
+        assert_eq!(
+            tree.k_distances_and_graph(2, &radius_limited).0,
+            vec![0.0, 0.0, 0.0, f32::INFINITY]
+        );
+    }
+}
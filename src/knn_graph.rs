@@ -0,0 +1,355 @@
+//! The k-nearest-neighbour graph of the points of a tree, in compressed sparse row format
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+
+use ordered_float::NotNan;
+
+use crate::distance_scale::{saturate, unscale_dist2};
+use crate::internal_parameters::InternalParameters;
+use crate::{point_slice_dist2, CancellationToken, KDTree, Parameters, Point, QueryError, Scalar};
+
+/// Which edges to keep when building a kNN graph
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KnnGraphSymmetry {
+    /// An edge from each point to each of its neighbours
+    Directed,
+    /// An edge in both directions between two points if either is a neighbour of the other
+    Union,
+    /// An edge in both directions between two points if each is a neighbour of the other (mutual kNN)
+    Mutual,
+}
+
+/// A directed graph in compressed sparse row (CSR) format
+///
+/// The edges from node `i` are at positions `offsets[i]..offsets[i + 1]` of `targets` and `weights`,
+/// sorted by target.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CsrGraph<T> {
+    /// start of the edges of each node, size node count + 1
+    pub offsets: Vec<u32>,
+    /// target node of each edge
+    pub targets: Vec<u32>,
+    /// weight of each edge
+    pub weights: Vec<T>,
+}
+
+impl<T: Copy> CsrGraph<T> {
+    /// Builds a graph of `node_count` nodes from edges `(source, target, weight)` sorted by source
+    pub(crate) fn from_sorted_edges(node_count: usize, edges: &[(u32, u32, T)]) -> Self {
+        let mut offsets = Vec::with_capacity(node_count + 1);
+        offsets.push(0);
+        let mut edge_index = 0;
+        for node in 0..node_count as u32 {
+            while edge_index < edges.len() && edges[edge_index].0 == node {
+                edge_index += 1;
+            }
+            offsets.push(edge_index as u32);
+        }
+        CsrGraph {
+            offsets,
+            targets: edges.iter().map(|edge| edge.1).collect(),
+            weights: edges.iter().map(|edge| edge.2).collect(),
+        }
+    }
+
+    /// Returns the number of nodes.
+    pub fn node_count(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    /// Returns the number of edges.
+    pub fn edge_count(&self) -> usize {
+        self.targets.len()
+    }
+
+    /// Returns the targets and the weights of the edges from `node`.
+    pub fn edges(&self, node: u32) -> (&[u32], &[T]) {
+        let range = self.offsets[node as usize] as usize..self.offsets[node as usize + 1] as usize;
+        (&self.targets[range.clone()], &self.weights[range])
+    }
+
+    /// Returns whether there is an edge from `source` to `target`.
+    pub fn contains_edge(&self, source: u32, target: u32) -> bool {
+        self.edges(source).0.binary_search(&target).is_ok()
+    }
+}
+
+/// Number of consecutive points searched in sequence when building a kNN graph,
+/// each search being bounded by the neighbours of the previous point
+const GRAPH_CHUNK_SIZE: usize = 1024;
+
+impl<T: Scalar + Send + Sync, P: Point<T> + Send + Sync> KDTree<T, P> {
+    /// Builds the graph linking each point to its `k` nearest neighbours, other than itself.
+    ///
+    /// Nodes are the indices of the points in the original point cloud,
+    /// and weights are the squared distances between points.
+    /// Duplicated points are linked with edges of weight zero.
+    /// A point has less than `k` neighbours if the cloud or the ball defined by
+    /// `parameters.max_radius` contains less than `k` other points.
+    /// `parameters.self_match` is ignored: the point itself is excluded by index, as with [`SelfMatch::ExcludeByIndex`](crate::SelfMatch::ExcludeByIndex),
+    /// so that its duplicates are kept.
+    /// Points are processed by chunks of neighbouring points, in which the distances to the neighbours
+    /// of the previous point bound the search of the next one, without changing the result.
+    /// If the `rayon` feature is enabled, chunks are searched in parallel.
+    pub fn knn_graph_csr(
+        &self,
+        k: u32,
+        parameters: &Parameters<T>,
+        symmetry: KnnGraphSymmetry,
+    ) -> CsrGraph<T> {
+        self.build_knn_graph_csr(k, parameters, symmetry, None)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Builds the graph linking each point to its `k` nearest neighbours, see [`Self::knn_graph_csr`],
+    /// or returns [`QueryError::Cancelled`] if `cancellation` gets cancelled.
+    ///
+    /// Cancellation is checked before each chunk of points whose neighbours are searched.
+    pub fn knn_graph_csr_cancellable(
+        &self,
+        k: u32,
+        parameters: &Parameters<T>,
+        symmetry: KnnGraphSymmetry,
+        cancellation: &CancellationToken,
+    ) -> Result<CsrGraph<T>, QueryError> {
+        self.build_knn_graph_csr(k, parameters, symmetry, Some(cancellation))
+    }
+
+    /// Returns the `k`-th smallest squared distance from the stored point of internal index `internal_source`
+    /// to the points of internal indices `candidates` other than itself, if there are as many
+    ///
+    /// The point and its `k` nearest other points are within that distance, which bounds the search.
+    fn warm_start_bound2(
+        &self,
+        internal_source: u32,
+        candidates: &[u32],
+        k: u32,
+        dist2s: &mut Vec<NotNan<T>>,
+    ) -> Option<NotNan<T>> {
+        let k = k as usize;
+        if k == 0 || candidates.len() < k {
+            return None;
+        }
+        let dim = P::DIM as usize;
+        let start = internal_source as usize * dim;
+        let source = &self.points[start..start + dim];
+        dist2s.clear();
+        dist2s.extend(
+            candidates
+                .iter()
+                .filter(|candidate| **candidate != internal_source)
+                .map(|candidate| {
+                    let start = *candidate as usize * dim;
+                    let dist2 = point_slice_dist2::<T, P>(
+                        source,
+                        &self.points[start..start + dim],
+                        self.scale,
+                    );
+                    saturate(dist2)
+                }),
+        );
+        if dist2s.len() < k {
+            return None;
+        }
+        Some(*dist2s.select_nth_unstable(k - 1).1)
+    }
+
+    fn build_knn_graph_csr(
+        &self,
+        k: u32,
+        parameters: &Parameters<T>,
+        symmetry: KnnGraphSymmetry,
+        cancellation: Option<&CancellationToken>,
+    ) -> Result<CsrGraph<T>, QueryError> {
+        let internal_parameters = InternalParameters::new(parameters, self.scale);
+        // there cannot be more neighbours than other points, avoid allocating for them
+        let capacity = k.min(self.len().saturating_sub(1) as u32) as usize;
+        // consecutive internal indices are spatially close,
+        // so the neighbours found for a point bound the search of the next one of its chunk
+        let chunk_count = (self.len() + GRAPH_CHUNK_SIZE - 1) / GRAPH_CHUNK_SIZE;
+        let chunks = self.map_indices_with_cancellable(
+            chunk_count,
+            || {
+                (
+                    BinaryHeap::with_capacity(capacity),
+                    Vec::with_capacity(capacity),
+                )
+            },
+            |(heap, neighbours), chunk| {
+                let start = chunk * GRAPH_CHUNK_SIZE;
+                let end = (start + GRAPH_CHUNK_SIZE).min(self.len());
+                // the previous point of the chunk and its neighbours, as internal indices
+                let mut previous = Vec::with_capacity(capacity);
+                let mut dist2s = Vec::with_capacity(capacity);
+                // the edges of the points of the chunk, stored together, and their number for each point
+                let mut edges = Vec::with_capacity((end - start) * k as usize);
+                let mut lens = Vec::with_capacity(end - start);
+                for internal_source in start..end {
+                    let internal_source = internal_source as u32;
+                    let source = self.externalise_index(internal_source);
+                    let bound2 = self.warm_start_bound2(internal_source, &previous, k, &mut dist2s);
+                    let seeded_parameters = InternalParameters {
+                        max_radius2: bound2.map_or(internal_parameters.max_radius2, |bound2| {
+                            internal_parameters.max_radius2.min(bound2)
+                        }),
+                        allow_self_match: true,
+                        excluded: Some(source),
+                        ..internal_parameters
+                    };
+                    self.knn_of_stored_point(source, heap, &seeded_parameters);
+                    neighbours.clear();
+                    neighbours.extend(heap.drain());
+                    neighbours.sort_unstable();
+                    previous.clear();
+                    previous.push(internal_source);
+                    previous.extend(neighbours.iter().map(|n| n.index));
+                    let first = edges.len();
+                    edges.extend(neighbours.iter().map(|n| {
+                        let dist2 = unscale_dist2(n.dist2, self.scale);
+                        (source, self.externalise_index(n.index), dist2.into_inner())
+                    }));
+                    edges[first..].sort_unstable_by_key(|edge| edge.1);
+                    lens.push(neighbours.len());
+                }
+                (edges, lens)
+            },
+            cancellation,
+        )?;
+        // the edges of each point, by internal index
+        let mut offsets = Vec::with_capacity(self.len() + 1);
+        offsets.push(0);
+        let mut chunk_edges = Vec::with_capacity(chunks.iter().map(|chunk| chunk.0.len()).sum());
+        for (edges, lens) in chunks {
+            for len in lens {
+                offsets.push(offsets[offsets.len() - 1] + len);
+            }
+            chunk_edges.extend(edges);
+        }
+        let mut edges = Vec::with_capacity(chunk_edges.len());
+        for source in 0..self.len() as u32 {
+            let internal_source = self.internalise_index(source) as usize;
+            edges.extend_from_slice(
+                &chunk_edges[offsets[internal_source]..offsets[internal_source + 1]],
+            );
+        }
+        let directed = CsrGraph::from_sorted_edges(self.len(), &edges);
+        Ok(match symmetry {
+            KnnGraphSymmetry::Directed => directed,
+            KnnGraphSymmetry::Union => {
+                let mut edges = Vec::with_capacity(2 * directed.edge_count());
+                for source in 0..self.len() as u32 {
+                    let (targets, weights) = directed.edges(source);
+                    for (target, weight) in targets.iter().zip(weights) {
+                        edges.push((source, *target, *weight));
+                        edges.push((*target, source, *weight));
+                    }
+                }
+                edges.sort_unstable_by_key(|edge| (edge.0, edge.1));
+                edges.dedup_by_key(|edge| (edge.0, edge.1));
+                CsrGraph::from_sorted_edges(self.len(), &edges)
+            }
+            KnnGraphSymmetry::Mutual => {
+                let mut edges = Vec::with_capacity(directed.edge_count());
+                for source in 0..self.len() as u32 {
+                    let (targets, weights) = directed.edges(source);
+                    for (target, weight) in targets.iter().zip(weights) {
+                        if directed.contains_edge(*target, source) {
+                            edges.push((source, *target, *weight));
+                        }
+                    }
+                }
+                CsrGraph::from_sorted_edges(self.len(), &edges)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn directed_graph_matches_knn() {
+        let cloud: Vec<P2> = random_point_cloud(500);
+        let tree = KDTree::new(&cloud);
+        let graph = tree.knn_graph_csr(4, &Parameters::default(), KnnGraphSymmetry::Directed);
+        assert_eq!(graph.node_count(), cloud.len());
+        assert_eq!(graph.edge_count(), 4 * cloud.len());
+        for (source, point) in cloud.iter().enumerate() {
+            let mut expected: Vec<_> = tree.knn(5, point)[1..]
+                .iter()
+                .map(|n| (n.index, n.dist2.into_inner()))
+                .collect();
+            expected.sort_unstable_by_key(|edge| edge.0);
+            let (targets, weights) = graph.edges(source as u32);
+            let edges: Vec<_> = targets
+                .iter()
+                .copied()
+                .zip(weights.iter().copied())
+                .collect();
+            assert_eq!(edges, expected);
+        }
+    }
+
+    #[test]
+    fn seeded_searches_keep_large_k_graphs_exact() {
+        // clusters of duplicates challenge the exclusion of the point itself
+        let mut cloud: Vec<P2> = random_point_cloud(3000);
+        for i in 0..300 {
+            cloud.push(cloud[i % 50]);
+        }
+        let tree = KDTree::new(&cloud);
+        let k = 40;
+        let graph = tree.knn_graph_csr(k, &Parameters::default(), KnnGraphSymmetry::Directed);
+        assert_eq!(graph.edge_count(), k as usize * cloud.len());
+        for (source, point) in cloud.iter().enumerate() {
+            let mut expected: Vec<_> = tree
+                .knn(k + 1, point)
+                .iter()
+                .map(|n| n.dist2.into_inner())
+                .collect();
+            // one of the points at distance zero is the point itself
+            expected.remove(0);
+            let mut weights = graph.edges(source as u32).1.to_vec();
+            weights.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+            assert_eq!(weights, expected);
+            assert!(!graph.contains_edge(source as u32, source as u32));
+        }
+    }
+
+    #[test]
+    fn symmetric_graphs() {
+        let cloud: Vec<P2> = random_point_cloud(500);
+        let tree = KDTree::new(&cloud);
+        let parameters = Parameters::default();
+        let directed = tree.knn_graph_csr(3, &parameters, KnnGraphSymmetry::Directed);
+        let union = tree.knn_graph_csr(3, &parameters, KnnGraphSymmetry::Union);
+        let mutual = tree.knn_graph_csr(3, &parameters, KnnGraphSymmetry::Mutual);
+        for source in 0..cloud.len() as u32 {
+            for target in 0..cloud.len() as u32 {
+                let forward = directed.contains_edge(source, target);
+                let backward = directed.contains_edge(target, source);
+                assert_eq!(union.contains_edge(source, target), forward || backward);
+                assert_eq!(mutual.contains_edge(source, target), forward && backward);
+            }
+        }
+        assert!(mutual.edge_count() <= directed.edge_count());
+        assert!(union.edge_count() >= directed.edge_count());
+    }
+
+    #[test]
+    fn duplicates_give_zero_weight_edges() {
+        let cloud = vec![P2::new2d(0.0, 0.0); 5];
+        let tree = KDTree::new(&cloud);
+        let graph = tree.knn_graph_csr(2, &Parameters::default(), KnnGraphSymmetry::Directed);
+        assert_eq!(graph.edge_count(), 10);
+        for source in 0..5 {
+            let (targets, weights) = graph.edges(source);
+            assert!(!targets.contains(&source));
+            assert_eq!(weights, &[0.0, 0.0]);
+        }
+        let graph = tree.knn_graph_csr(10, &Parameters::default(), KnnGraphSymmetry::Mutual);
+        assert_eq!(graph.edge_count(), 20);
+    }
+}
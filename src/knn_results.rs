@@ -0,0 +1,380 @@
+//! Neighbours of many queries stored contiguously, without an allocation per query
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+use core::ops::Index;
+
+use crate::distance_scale::unscale_dist2;
+use crate::heap::CandidateHeap;
+use crate::{
+    CandidateCollector, CandidateContainer, InternalNeighbour, KDTree, Neighbour, Parameters,
+    Point, PreparedParameters, Scalar,
+};
+
+/// Number of consecutive queries whose neighbours are stored in the same chunk before being merged
+const RESULTS_CHUNK_SIZE: usize = 1024;
+
+/// The neighbours of a batch of queries, stored in a single vector, see [`KDTree::knn_batch_results`]
+///
+/// The neighbours of query `i` are at positions `offsets[i]..offsets[i + 1]` of the vector.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KnnResults<T: Scalar, P: Point<T>> {
+    neighbours: Vec<Neighbour<T, P>>,
+    offsets: Vec<usize>,
+}
+
+impl<T: Scalar, P: Point<T>> KnnResults<T, P> {
+    /// Returns the number of queries.
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    /// Returns whether there are no queries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the neighbours of query `query`.
+    ///
+    /// Panics if `query` is out of range.
+    pub fn get(&self, query: usize) -> &[Neighbour<T, P>] {
+        &self.neighbours[self.offsets[query]..self.offsets[query + 1]]
+    }
+
+    /// Returns an iterator over the neighbours of each query, in the order of the queries.
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = &[Neighbour<T, P>]> + '_ {
+        self.offsets
+            .windows(2)
+            .map(|range| &self.neighbours[range[0]..range[1]])
+    }
+
+    /// Returns the neighbours of all queries, one query after the other.
+    pub fn neighbours(&self) -> &[Neighbour<T, P>] {
+        &self.neighbours
+    }
+
+    /// Returns the start of the neighbours of each query in [`Self::neighbours`],
+    /// followed by their total number.
+    pub fn offsets(&self) -> &[usize] {
+        &self.offsets
+    }
+
+    /// Converts the results to a vector of neighbours per query, as returned by [`KDTree::knn_batch`].
+    pub fn into_nested(self) -> Vec<Vec<Neighbour<T, P>>> {
+        // points need not be cloneable, so the neighbours are moved, from the last query
+        let mut neighbours = self.neighbours;
+        let mut nested: Vec<_> = self.offsets[..self.offsets.len() - 1]
+            .iter()
+            .rev()
+            .map(|start| neighbours.split_off(*start))
+            .collect();
+        nested.reverse();
+        nested
+    }
+}
+
+impl<T: Scalar, P: Point<T>> Index<usize> for KnnResults<T, P> {
+    type Output = [Neighbour<T, P>];
+
+    fn index(&self, query: usize) -> &Self::Output {
+        self.get(query)
+    }
+}
+
+impl<T: Scalar, P: Point<T>> From<KnnResults<T, P>> for Vec<Vec<Neighbour<T, P>>> {
+    fn from(results: KnnResults<T, P>) -> Self {
+        results.into_nested()
+    }
+}
+
+/// The results of a batch of queries mapped by a user function, stored in a single vector,
+/// see [`KDTree::knn_batch_results_map`]
+///
+/// The results of query `i` are at positions `offsets[i]..offsets[i + 1]` of the vector.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MappedKnnResults<R> {
+    items: Vec<R>,
+    offsets: Vec<usize>,
+}
+
+impl<R> MappedKnnResults<R> {
+    /// Returns the number of queries.
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    /// Returns whether there are no queries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the results of query `query`.
+    ///
+    /// Panics if `query` is out of range.
+    pub fn get(&self, query: usize) -> &[R] {
+        &self.items[self.offsets[query]..self.offsets[query + 1]]
+    }
+
+    /// Returns an iterator over the results of each query, in the order of the queries.
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = &[R]> + '_ {
+        self.offsets
+            .windows(2)
+            .map(|range| &self.items[range[0]..range[1]])
+    }
+
+    /// Returns the results of all queries, one query after the other.
+    pub fn items(&self) -> &[R] {
+        &self.items
+    }
+
+    /// Returns the start of the results of each query in [`Self::items`],
+    /// followed by their total number.
+    pub fn offsets(&self) -> &[usize] {
+        &self.offsets
+    }
+
+    /// Returns the results of all queries and the offsets of each query, see [`Self::offsets`].
+    pub fn into_parts(self) -> (Vec<R>, Vec<usize>) {
+        (self.items, self.offsets)
+    }
+}
+
+impl<R> Index<usize> for MappedKnnResults<R> {
+    type Output = [R];
+
+    fn index(&self, query: usize) -> &Self::Output {
+        self.get(query)
+    }
+}
+
+/// The results of a chunk of consecutive queries, and the number of results of each
+struct ResultsChunk<R> {
+    items: Vec<R>,
+    lens: Vec<u32>,
+}
+
+impl<T: Scalar + Send + Sync, P: Point<T> + Send + Sync> KDTree<T, P> {
+    /// Finds the `k` nearest neighbour of each of `queries`, with user-provided parameters,
+    /// and stores them contiguously.
+    ///
+    /// The results are the same as those of [`Self::knn_batch`], but each worker reuses its candidate container
+    /// between queries, and the neighbours of chunks of consecutive queries are stored together
+    /// before being merged, so that there is no allocation per query.
+    /// If the `rayon` feature is enabled, queries are processed in parallel.
+    pub fn knn_batch_results(
+        &self,
+        k: u32,
+        queries: &[P],
+        candidate_container: CandidateContainer,
+        parameters: &Parameters<T>,
+    ) -> KnnResults<T, P> {
+        let (neighbours, offsets) = self
+            .knn_batch_results_map_internal(k, queries, candidate_container, parameters, |n| {
+                self.externalise_neighbour(n)
+            })
+            .into_parts();
+        KnnResults {
+            neighbours,
+            offsets,
+        }
+    }
+
+    /// Finds the `k` nearest neighbour of each of `queries`, with user-provided parameters,
+    /// and stores `f(index, dist2)` for each of them contiguously.
+    ///
+    /// As [`Self::knn_map`], `f` receives the index in the original point cloud and the squared distance
+    /// of each neighbour, in the order of the results, and neighbours are not built.
+    /// Otherwise, this is the same as [`Self::knn_batch_results`].
+    pub fn knn_batch_results_map<R: Send>(
+        &self,
+        k: u32,
+        queries: &[P],
+        candidate_container: CandidateContainer,
+        parameters: &Parameters<T>,
+        f: impl Fn(u32, T) -> R + Sync + Send,
+    ) -> MappedKnnResults<R> {
+        self.knn_batch_results_map_internal(k, queries, candidate_container, parameters, |n| {
+            f(
+                self.externalise_index(n.index),
+                unscale_dist2(n.dist2, self.scale).into_inner(),
+            )
+        })
+    }
+
+    /// Finds the neighbours of `queries` and stores the results of `map` on them contiguously
+    fn knn_batch_results_map_internal<R: Send>(
+        &self,
+        k: u32,
+        queries: &[P],
+        candidate_container: CandidateContainer,
+        parameters: &Parameters<T>,
+        map: impl Fn(InternalNeighbour<T>) -> R + Sync + Send,
+    ) -> MappedKnnResults<R> {
+        let parameters = self.prepare_parameters(parameters);
+        let chunks = match candidate_container {
+            CandidateContainer::Linear => self.knn_results_chunks::<Vec<InternalNeighbour<T>>, R>(
+                k,
+                queries,
+                &parameters,
+                &map,
+            ),
+            CandidateContainer::BinaryHeap => self
+                .knn_results_chunks::<BinaryHeap<InternalNeighbour<T>>, R>(
+                    k,
+                    queries,
+                    &parameters,
+                    &map,
+                ),
+        };
+        let total = chunks.iter().map(|chunk| chunk.items.len()).sum();
+        let mut items = Vec::with_capacity(total);
+        let mut offsets = Vec::with_capacity(queries.len() + 1);
+        offsets.push(0);
+        for chunk in chunks {
+            for len in chunk.lens {
+                offsets.push(offsets[offsets.len() - 1] + len as usize);
+            }
+            items.extend(chunk.items);
+        }
+        MappedKnnResults { items, offsets }
+    }
+
+    /// Finds the neighbours of `queries` by chunks, with a candidate container of type `H` per worker
+    fn knn_results_chunks<H: CandidateHeap<T> + CandidateCollector<T>, R: Send>(
+        &self,
+        k: u32,
+        queries: &[P],
+        parameters: &PreparedParameters<T>,
+        map: &(impl Fn(InternalNeighbour<T>) -> R + Sync + Send),
+    ) -> Vec<ResultsChunk<R>> {
+        // there cannot be more neighbours than points, avoid allocating for them
+        let k = k.min(self.len() as u32);
+        let chunk_count = (queries.len() + RESULTS_CHUNK_SIZE - 1) / RESULTS_CHUNK_SIZE;
+        self.map_indices_with(
+            chunk_count,
+            || (H::new_with_k(k), Vec::with_capacity(k as usize)),
+            |(heap, candidates), chunk| {
+                let start = chunk * RESULTS_CHUNK_SIZE;
+                let end = (start + RESULTS_CHUNK_SIZE).min(queries.len());
+                let mut results = ResultsChunk {
+                    items: Vec::with_capacity((end - start) * k as usize),
+                    lens: Vec::with_capacity(end - start),
+                };
+                for query in &queries[start..end] {
+                    heap.clear();
+                    let query = self.query_coordinates(query);
+                    self.knn_internal(heap, &query, &parameters.internal);
+                    self.complete_insufficient_or_panic(heap, k, &query, &parameters.internal);
+                    candidates.clear();
+                    heap.extend_into(candidates, parameters.sort_results);
+                    results.lens.push(candidates.len() as u32);
+                    results
+                        .items
+                        .extend(candidates.iter().map(|candidate| map(*candidate)));
+                }
+                results
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn results_match_nested_batches() {
+        let cloud: Vec<P2> = random_point_cloud(2000);
+        let queries: Vec<P2> = random_point_cloud(2500);
+        let tree = KDTree::new(&cloud);
+        for candidate_container in [CandidateContainer::Linear, CandidateContainer::BinaryHeap] {
+            for sort_results in [true, false] {
+                let parameters = Parameters {
+                    sort_results,
+                    max_radius: 8.0,
+                    ..Parameters::default()
+                };
+                let nested = tree.knn_batch(6, &queries, candidate_container, &parameters);
+                let results = tree.knn_batch_results(6, &queries, candidate_container, &parameters);
+                assert_eq!(results.len(), queries.len());
+                for (i, neighbours) in nested.iter().enumerate() {
+                    assert_eq!(results.get(i), &neighbours[..]);
+                    assert_eq!(&results[i], &neighbours[..]);
+                }
+                assert!(results.iter().eq(nested.iter().map(|n| &n[..])));
+                assert_eq!(results.offsets()[queries.len()], results.neighbours().len());
+                assert_eq!(Vec::from(results), nested);
+            }
+        }
+        let empty =
+            tree.knn_batch_results(6, &[], CandidateContainer::Linear, &Parameters::default());
+        assert!(empty.is_empty());
+        assert_eq!(empty.iter().count(), 0);
+        // a tree with less than k points returns all of them
+        let small = KDTree::new(&cloud[..3]);
+        let results = small.knn_batch_results(
+            6,
+            &queries[..2],
+            CandidateContainer::BinaryHeap,
+            &Parameters::default(),
+        );
+        assert_eq!(results.neighbours().len(), 6);
+        assert_eq!(results.get(1), &small.knn(6, &queries[1])[..]);
+    }
+
+    #[test]
+    fn mapped_results_match_the_mapped_neighbours() {
+        let cloud: Vec<P2> = random_point_cloud(2000);
+        let queries: Vec<P2> = random_point_cloud(1500);
+        let tree = KDTree::new(&cloud);
+        let weights: Vec<f64> = (0..cloud.len()).map(|i| i as f64 * 0.5).collect();
+        let weigh = |index: u32, dist2: f32| (index, dist2.sqrt(), weights[index as usize]);
+        for candidate_container in [CandidateContainer::Linear, CandidateContainer::BinaryHeap] {
+            for sort_results in [true, false] {
+                let parameters = Parameters {
+                    sort_results,
+                    max_radius: 8.0,
+                    ..Parameters::default()
+                };
+                let results = tree.knn_batch_results(5, &queries, candidate_container, &parameters);
+                let mapped = tree.knn_batch_results_map(
+                    5,
+                    &queries,
+                    candidate_container,
+                    &parameters,
+                    weigh,
+                );
+                assert_eq!(mapped.len(), queries.len());
+                assert_eq!(mapped.offsets(), results.offsets());
+                for (i, neighbours) in results.iter().enumerate() {
+                    let expected: Vec<_> = neighbours
+                        .iter()
+                        .map(|n| weigh(n.index, n.dist2.into_inner()))
+                        .collect();
+                    assert_eq!(mapped.get(i), &expected[..]);
+                    assert_eq!(&mapped[i], &expected[..]);
+                    if candidate_container == KDTree::<f32, P2>::default_candidate_container(5) {
+                        assert_eq!(tree.knn_map(5, &queries[i], &parameters, weigh), expected);
+                    }
+                }
+                assert_eq!(mapped.iter().count(), queries.len());
+                let (items, offsets) = mapped.clone().into_parts();
+                assert_eq!(
+                    (&items[..], &offsets[..]),
+                    (mapped.items(), mapped.offsets())
+                );
+            }
+        }
+        let empty = tree.knn_batch_results_map(
+            5,
+            &[],
+            CandidateContainer::Linear,
+            &Parameters::default(),
+            |index, _| index,
+        );
+        assert!(empty.is_empty());
+        assert!(tree
+            .knn_map(0, &queries[0], &Parameters::default(), |index, _| index)
+            .is_empty());
+    }
+}
@@ -0,0 +1,224 @@
+//! Summaries of the nearest neighbours of queries, without collecting the neighbours
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+
+use ordered_float::NotNan;
+
+use crate::distance_scale::unscale_dist2;
+use crate::heap::CandidateHeap;
+use crate::internal_parameters::InternalParameters;
+use crate::{Accumulation, InternalNeighbour, KDTree, Neighbour, Parameters, Point, Scalar};
+
+/// The nearest distance, the furthest distance and the centroid of the `k` nearest neighbours of a query,
+/// see [`KDTree::knn_summary`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KnnSummary<T: Scalar, P: Point<T>> {
+    /// the nearest neighbour, `None` if no neighbour was found
+    pub nearest: Option<Neighbour<T, P>>,
+    /// the squared distance of the furthest neighbour found, the `k`-th if `count` is `k`,
+    /// infinite if no neighbour was found
+    pub kth_dist2: T,
+    /// the mean of the points of the neighbours found, the origin if no neighbour was found
+    pub centroid: P,
+    /// the number of neighbours found, smaller than `k` if fewer points are within `max_radius` or in the tree
+    pub count: u32,
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Finds the `k` nearest neighbours of `query` with `parameters`, see [`Self::knn_advanced`],
+    /// and returns the distance of the nearest, the distance of the furthest and their centroid.
+    ///
+    /// The summary is computed from the candidates kept by the search and their stored coordinates,
+    /// without collecting the neighbours.
+    /// The nearest neighbour is the one of smallest index among those at the same distance,
+    /// as in sorted results.
+    ///
+    /// Panics if the parameters are invalid, see [`Parameters::validate`], or in the same cases as [`Self::knn_advanced`].
+    pub fn knn_summary(&self, k: u32, query: &P, parameters: &Parameters<T>) -> KnnSummary<T, P> {
+        self.knn_summary_accumulated(k, query, parameters, Accumulation::Fast)
+    }
+
+    /// Same as [`Self::knn_summary`], summing the neighbours into the centroid in the order of `accumulation`.
+    ///
+    /// With [`Accumulation::Fast`], the neighbours are summed in the order of the candidate container,
+    /// and with [`Accumulation::Deterministic`], by increasing index in the original point cloud.
+    pub fn knn_summary_accumulated(
+        &self,
+        k: u32,
+        query: &P,
+        parameters: &Parameters<T>,
+        accumulation: Accumulation,
+    ) -> KnnSummary<T, P> {
+        parameters
+            .validate()
+            .unwrap_or_else(|error| panic!("{}", error));
+        let internal_parameters = InternalParameters::new(parameters, self.scale);
+        let mut scratch = (BinaryHeap::new(), Vec::new());
+        self.summarise(k, query, &internal_parameters, accumulation, &mut scratch)
+    }
+
+    /// Searches the neighbours of `query` into the heap of `scratch`, reused between queries with its buffer
+    /// to order the neighbours, and summarises them
+    fn summarise(
+        &self,
+        k: u32,
+        query: &P,
+        internal_parameters: &InternalParameters<T>,
+        accumulation: Accumulation,
+        (heap, ordered): &mut (BinaryHeap<InternalNeighbour<T>>, Vec<InternalNeighbour<T>>),
+    ) -> KnnSummary<T, P> {
+        let coordinates = self.query_coordinates(query);
+        // there cannot be more neighbours than points, avoid allocating for them
+        let k = k.min(self.len() as u32);
+        // the capacity of the heap is the number of candidates it keeps
+        if heap.capacity() == k as usize {
+            heap.clear();
+        } else {
+            *heap = BinaryHeap::new_with_k(k);
+        }
+        self.knn_internal(heap, &coordinates, internal_parameters);
+        self.complete_insufficient_or_panic(heap, k, &coordinates, internal_parameters);
+        let count = heap.candidate_count() as u32;
+        let nearest = heap
+            .iter()
+            .min_by_key(|candidate| (candidate.dist2, self.indices[candidate.index as usize]))
+            .map(|candidate| self.externalise_neighbour(*candidate));
+        let kth_dist2 = heap
+            .iter()
+            .map(|candidate| candidate.dist2)
+            .max()
+            .map_or(T::infinity(), |dist2| {
+                unscale_dist2(dist2, self.scale).into_inner()
+            });
+        ordered.clear();
+        ordered.extend(heap.iter());
+        if accumulation == Accumulation::Deterministic {
+            ordered.sort_unstable_by_key(|candidate| self.externalise_index(candidate.index));
+        }
+        // the mean is a sum of fractions, so that it does not overflow
+        let dim = P::DIM as usize;
+        let fraction = T::one() / T::from(count.max(1)).expect("counts convert to scalars");
+        let mut sum = alloc::vec![T::zero(); dim];
+        for candidate in ordered.iter() {
+            for (sum, coordinate) in sum.iter_mut().zip(self.stored_coordinates(candidate.index)) {
+                *sum += coordinate.into_inner() * fraction;
+            }
+        }
+        let sum: Vec<NotNan<T>> = sum
+            .into_iter()
+            .map(|value| NotNan::new(value).expect("a mean of finite coordinates is not NaN"))
+            .collect();
+        KnnSummary {
+            nearest,
+            kth_dist2,
+            centroid: self.point_from_tree_frame(&sum),
+            count,
+        }
+    }
+}
+
+impl<T: Scalar + Send + Sync, P: Point<T> + Send + Sync> KDTree<T, P> {
+    /// Summarises the `k` nearest neighbours of each of `queries`, see [`Self::knn_summary`],
+    /// in the order of `queries`.
+    ///
+    /// If the `rayon` feature is enabled, queries are processed in parallel,
+    /// each worker reusing its candidate container from query to query.
+    /// Panics if the parameters are invalid, see [`Parameters::validate`], or in the same cases as [`Self::knn_advanced`].
+    pub fn knn_summary_batch(
+        &self,
+        k: u32,
+        queries: &[P],
+        parameters: &Parameters<T>,
+    ) -> Vec<KnnSummary<T, P>> {
+        self.knn_summary_batch_accumulated(k, queries, parameters, Accumulation::Fast)
+    }
+
+    /// Same as [`Self::knn_summary_batch`], summing the neighbours into the centroids in the order of `accumulation`,
+    /// see [`Self::knn_summary_accumulated`].
+    pub fn knn_summary_batch_accumulated(
+        &self,
+        k: u32,
+        queries: &[P],
+        parameters: &Parameters<T>,
+        accumulation: Accumulation,
+    ) -> Vec<KnnSummary<T, P>> {
+        parameters
+            .validate()
+            .unwrap_or_else(|error| panic!("{}", error));
+        let internal_parameters = InternalParameters::new(parameters, self.scale);
+        let init = || (BinaryHeap::new(), Vec::new());
+        self.map_indices_with(queries.len(), init, |scratch, index| {
+            self.summarise(
+                k,
+                &queries[index],
+                &internal_parameters,
+                accumulation,
+                scratch,
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn summaries_match_the_neighbours() {
+        let cloud: Vec<P3> = random_point_cloud(3000);
+        let options = BuildOptions {
+            rotation_seed: Some(239),
+            ..BuildOptions::default()
+        };
+        let tree = KDTree::new_with_options(&cloud, &options);
+        let queries: Vec<P3> = (0..100).map(|_| random_point()).collect();
+        let parameters = Parameters::default();
+        let summaries = tree.knn_summary_batch(12, &queries, &parameters);
+        for (query, summary) in queries.iter().zip(&summaries) {
+            assert_eq!(*summary, tree.knn_summary(12, query, &parameters));
+            let neighbours = tree.knn(12, query);
+            assert_eq!(summary.count, 12);
+            assert_eq!(summary.nearest, Some(neighbours[0]));
+            assert_eq!(summary.kth_dist2, *neighbours[11].dist2);
+            for axis in 0..3 {
+                let mean = neighbours
+                    .iter()
+                    .map(|n| *cloud[n.index as usize].get(axis))
+                    .sum::<f32>()
+                    / 12.0;
+                assert!((*summary.centroid.get(axis) - mean).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn fewer_neighbours_summarise_what_exists() {
+        let cloud = vec![
+            P2::new2d(0.0, 0.0),
+            P2::new2d(2.0, 0.0),
+            P2::new2d(0.0, 4.0),
+            P2::new2d(50.0, 50.0),
+        ];
+        let tree = KDTree::new(&cloud);
+        let parameters = Parameters {
+            max_radius: 10.0,
+            ..Parameters::default()
+        };
+        let summary = tree.knn_summary(10, &P2::new2d(0.0, 1.0), &parameters);
+        assert_eq!(summary.count, 3);
+        assert_eq!(summary.nearest.map(|n| n.index), Some(0));
+        assert_eq!(summary.kth_dist2, 9.0);
+        assert_eq!(summary.centroid, P2::new2d(2.0 / 3.0, 4.0 / 3.0));
+        // all points, beyond k
+        let summary = tree.knn_summary(10, &P2::new2d(0.0, 1.0), &Parameters::default());
+        assert_eq!(summary.count, 4);
+        assert_eq!(summary.kth_dist2, 50.0 * 50.0 + 49.0 * 49.0);
+        // nothing within the radius
+        let summary = tree.knn_summary(3, &P2::new2d(30.0, 30.0), &parameters);
+        assert_eq!(summary.count, 0);
+        assert_eq!(summary.nearest, None);
+        assert_eq!(summary.kth_dist2, f32::INFINITY);
+        assert_eq!(summary.centroid, P2::default());
+    }
+}
@@ -0,0 +1,197 @@
+//! Scan of the points of a leaf, with straight-line kernels for the common dimensions
+
+use ordered_float::NotNan;
+
+use crate::distance_scale::saturate;
+use crate::internal_parameters::InternalParameters;
+use crate::{point_slice_dist2, CandidateCollector, Point, Scalar};
+
+/// The range of squared distances of the candidates accepted by a leaf scan
+#[derive(Clone, Copy)]
+struct LeafFilter<T: Scalar> {
+    /// candidates must be strictly further than this, to exclude self matches
+    min_dist2: T,
+    max_dist2: T,
+}
+
+impl<T: Scalar> LeafFilter<T> {
+    fn new(internal_parameters: &InternalParameters<T>) -> Self {
+        LeafFilter {
+            min_dist2: if internal_parameters.allow_self_match {
+                -T::one()
+            } else {
+                T::epsilon()
+            },
+            max_dist2: internal_parameters.max_radius2.into_inner(),
+        }
+    }
+
+    #[inline]
+    fn accepts(&self, dist2: T) -> bool {
+        dist2 > self.min_dist2 && dist2 <= self.max_dist2
+    }
+}
+
+/// Adds to `heap` the points of `bucket`, whose first point has internal index `bucket_start_index`,
+/// that pass the radius and self-match filters of `internal_parameters`
+///
+/// Points of 2 and 3 dimensions are scanned as fixed-size arrays, others by blocks, see [`scan_blocked`].
+pub(crate) fn scan_leaf<T: Scalar, P: Point<T>, H: CandidateCollector<T>>(
+    heap: &mut H,
+    query: &[NotNan<T>],
+    bucket: &[NotNan<T>],
+    bucket_start_index: u32,
+    scale: NotNan<T>,
+    internal_parameters: &InternalParameters<T>,
+) {
+    let filter = LeafFilter::new(internal_parameters);
+    match P::DIM {
+        2 => scan_fixed::<T, H, 2>(heap, query, bucket, bucket_start_index, scale, filter),
+        3 => scan_fixed::<T, H, 3>(heap, query, bucket, bucket_start_index, scale, filter),
+        _ => scan_blocked::<T, P, H>(heap, query, bucket, bucket_start_index, scale, filter),
+    }
+}
+
+/// The number of points whose distances [`scan_blocked`] computes together
+const BLOCK: usize = 8;
+
+/// Scans the points of `bucket` by blocks of [`BLOCK`] points, the remaining ones through [`point_slice_dist2`]
+///
+/// The sums of a block are independent, so the processor overlaps them and the compiler may vectorise them,
+/// which matters for the large leaves of flat trees, see [`HighDimension::Flat`](crate::HighDimension::Flat).
+/// Each sum adds the dimensions in order, as [`point_slice_dist2`], so the distances are bitwise equal.
+fn scan_blocked<T: Scalar, P: Point<T>, H: CandidateCollector<T>>(
+    heap: &mut H,
+    query: &[NotNan<T>],
+    bucket: &[NotNan<T>],
+    bucket_start_index: u32,
+    scale: NotNan<T>,
+    filter: LeafFilter<T>,
+) {
+    let dim = P::DIM as usize;
+    let unwrapped_scale = scale.into_inner();
+    let mut blocks = bucket.chunks_exact(dim * BLOCK);
+    let mut index = bucket_start_index;
+    for block in &mut blocks {
+        let mut dist2s = [T::zero(); BLOCK];
+        for (i, coordinate) in query[..dim].iter().enumerate() {
+            let coordinate = coordinate.into_inner();
+            for (j, dist2) in dist2s.iter_mut().enumerate() {
+                // multiplying by a unit scale is exact, so this matches point_slice_dist2
+                let diff = (coordinate - block[j * dim + i].into_inner()) * unwrapped_scale;
+                *dist2 += diff * diff;
+            }
+        }
+        for dist2 in dist2s {
+            // finite coordinates only lead to finite or infinite squares, whose sum is not NaN
+            let dist2 = saturate(NotNan::new(dist2).expect("distance is not NaN"));
+            if filter.accepts(dist2.into_inner()) {
+                heap.add(dist2, index);
+            }
+            index += 1;
+        }
+    }
+    for (index, point) in (index..).zip(blocks.remainder().chunks_exact(dim)) {
+        let dist2 = point_slice_dist2::<T, P>(query, point, scale);
+        if filter.accepts(dist2.into_inner()) {
+            heap.add(dist2, index);
+        }
+    }
+}
+
+fn scan_fixed<T: Scalar, H: CandidateCollector<T>, const D: usize>(
+    heap: &mut H,
+    query: &[NotNan<T>],
+    bucket: &[NotNan<T>],
+    bucket_start_index: u32,
+    scale: NotNan<T>,
+    filter: LeafFilter<T>,
+) {
+    let query: [T; D] = core::array::from_fn(|i| query[i].into_inner());
+    let scale = scale.into_inner();
+    for (index, point) in (bucket_start_index..).zip(bucket.chunks_exact(D)) {
+        // multiplying by a unit scale is exact, so this matches point_slice_dist2
+        let mut dist2 = T::zero();
+        for i in 0..D {
+            let diff = (query[i] - point[i].into_inner()) * scale;
+            dist2 += diff * diff;
+        }
+        // finite coordinates only lead to finite or infinite squares, whose sum is not NaN
+        let dist2 = saturate(NotNan::new(dist2).expect("distance is not NaN"));
+        if filter.accepts(dist2.into_inner()) {
+            heap.add(dist2, index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simple_point::*;
+    use crate::Parameters;
+    use alloc::vec::Vec;
+
+    struct AllCandidates(Vec<(NotNan<f32>, u32)>);
+
+    impl CandidateCollector<f32> for AllCandidates {
+        fn add(&mut self, dist2: NotNan<f32>, index: u32) {
+            self.0.push((dist2, index));
+        }
+
+        fn furthest_dist2(&self) -> Option<NotNan<f32>> {
+            None
+        }
+    }
+
+    fn check_kernel<const D: usize>()
+    where
+        SimplePoint<D>: Point<f32>,
+    {
+        let cloud: Vec<SimplePoint<D>> = random_point_cloud(100);
+        let bucket: Vec<NotNan<f32>> = cloud
+            .iter()
+            .flat_map(|p| (0..D as u32).map(|i| p.get(i)))
+            .collect();
+        let query: Vec<NotNan<f32>> = bucket[..D].to_vec();
+        for scale in [1.0, 0.25] {
+            let scale = NotNan::new(scale).unwrap();
+            for (max_radius, allow_self_match) in [(f32::INFINITY, true), (80.0, false)] {
+                let parameters = Parameters {
+                    max_radius,
+                    self_match: allow_self_match.into(),
+                    ..Parameters::default()
+                };
+                let internal_parameters = InternalParameters::new(&parameters, scale);
+                let mut scanned = AllCandidates(Vec::new());
+                #[rustfmt::skip]
+                scan_leaf::<f32, SimplePoint<D>, _>(
+                    &mut scanned, &query,
+                    &bucket, 7,
+                    scale, &internal_parameters,
+                );
+                let expected: Vec<_> = (7..)
+                    .zip(bucket.chunks_exact(D))
+                    .map(|(index, point)| {
+                        (
+                            point_slice_dist2::<f32, SimplePoint<D>>(&query, point, scale),
+                            index,
+                        )
+                    })
+                    .filter(|(dist2, _)| {
+                        *dist2 <= internal_parameters.max_radius2
+                            && (allow_self_match || **dist2 > f32::EPSILON)
+                    })
+                    .collect();
+                assert!(!expected.is_empty());
+                assert_eq!(scanned.0, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn kernels_match_generic_distance() {
+        check_kernel::<2>();
+        check_kernel::<3>();
+        check_kernel::<4>();
+    }
+}
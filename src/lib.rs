@@ -1,5 +1,7 @@
 // #![no_std]
 #![warn(missing_docs)]
+// Library code reachable from `try_new` and `try_knn` must not panic, so unwrapping is not allowed.
+#![cfg_attr(not(test), deny(clippy::unwrap_used))]
 
 //! A fast K Nearest Neighbour library for low-dimensional spaces.
 //!
@@ -12,7 +14,7 @@
 //! use nabo::KDTree;
 //! let cloud = random_point_cloud::<2>(10000);
 //! let tree = KDTree::new(&cloud);
-//! let query = random_point();
+//! let query = random_point::<2>();
 //! let neighbour = tree.knn(3, &query);
 //! ```
 //!
@@ -24,52 +26,324 @@
 //! use nabo::Parameters;
 //! let cloud = random_point_cloud::<2>(10000);
 //! let tree = KDTree::new(&cloud);
-//! let query = random_point();
+//! let query = random_point::<2>();
 //! let mut touch_count = 0;
+//! let parameters = Parameters::builder()
+//!     .max_radius(10.0)
+//!     .sort_results(false)
+//!     .build()
+//!     .unwrap();
 //! let neighbour = tree.knn_advanced(
 //!     3,
 //!     &query,
 //!     CandidateContainer::BinaryHeap,
-//!     &Parameters {
-//!         epsilon: 0.0,
-//!         max_radius: 10.0,
-//!         allow_self_match: true,
-//!         sort_results: false,
-//!     },
+//!     &parameters,
 //!     Some(&mut touch_count) // statistics
 //! );
 //! ```
+//!
+//! # Determinism
+//!
+//! For identical inputs, results are bit-identical across platforms, as `f32` and `f64` follow IEEE 754.
+//! Distances are sums of squared differences accumulated in dimension order,
+//! and Rust neither contracts multiplications and additions into fused operations
+//! nor reassociates floating-point arithmetic, so no special mode is needed.
+//! Each query runs sequentially, also in batches processed in parallel with the `rayon` feature,
+//! and the sums of [`KDTree::knn_covariances`] and [`KDTree::aggregate_within`] are accumulated in a fixed order.
+//! Functions such as the square root come from the pure-Rust `libm` crate rather than from the platform.
+//!
+//! # The `ordered_float` dependency
+//!
+//! Coordinates and squared distances are [`NotNan`] values in [`Point`], [`CandidateCollector`] and [`Neighbour`],
+//! so that they are checked once, when entering the crate, rather than at each comparison.
+//! The crate re-exports the version of `ordered_float` these types come from as [`nabo::ordered_float`](ordered_float),
+//! and [`NotNan`] and [`FloatIsNan`] at its root, so that dependents do not need to depend on `ordered_float` themselves
+//! and cannot pick a version whose types differ.
+//! Raw values cross the boundary through [`Point::from_raw`], [`Neighbour::new`] and [`Neighbour::dist2`]:
+//! ```
+//! use nabo::{KDTree, NotNan, Point};
+//!
+//! #[derive(Default)]
+//! struct Sample {
+//!     position: [f64; 2],
+//! }
+//!
+//! impl Point<f64> for Sample {
+//!     fn set(&mut self, i: u32, value: NotNan<f64>) {
+//!         self.position[i as usize] = value.into_inner();
+//!     }
+//!     fn get(&self, i: u32) -> NotNan<f64> {
+//!         NotNan::new(self.position[i as usize]).expect("positions are not NaN")
+//!     }
+//!     const DIM: u32 = 2;
+//! }
+//!
+//! let cloud = [Sample { position: [0.0, 0.0] }, Sample { position: [1.0, 1.0] }];
+//! let tree = KDTree::new(&cloud);
+//! let query = Sample::from_raw(&[0.75, 1.0]).expect("the query is not NaN");
+//! let nearest = &tree.knn(1, &query)[0];
+//! assert_eq!((nearest.index, nearest.dist2().get()), (1, 0.0625));
+//! // the re-exported crate is the one of the public types
+//! let _: nabo::ordered_float::NotNan<f64> = nearest.dist2;
+//! ```
 
 // We forbid the clippy lint here because it suggests to use #[rustfmt::skip],
 // which is experimental. See: https://github.com/rust-lang/rust/issues/88591
 #![allow(clippy::deprecated_cfg_attr)]
 
 extern crate alloc;
+// the source emitted by `FlatTree::to_rust_source` names the crate, as in the crates that include it
+#[cfg(all(test, feature = "codegen"))]
+extern crate self as nabo;
 
+mod accumulation;
+mod aggregate;
+mod all_pairs;
+mod antipodal;
+#[cfg(any(all(feature = "f32", feature = "f64"), test))]
+mod any_tree;
+#[cfg(not(feature = "strict-exact"))]
+mod approximate_count;
+#[cfg(feature = "async")]
+mod async_batch;
+mod axis_index;
+mod barycentric;
+mod batch;
+mod batch_stats;
+mod batch_update;
+mod bit_set;
+mod build_options;
+mod build_report;
+mod builder;
+mod cancellation;
+mod cell;
+mod chamfer;
+mod clearance;
+mod coarse_fine;
+#[cfg(feature = "codegen")]
+mod codegen;
+#[cfg(all(test, feature = "compat"))]
+mod compat;
+mod concurrent;
+mod covariance;
+mod covering;
+#[cfg(any(all(feature = "f32", feature = "f64"), test))]
+mod cross_precision;
+#[cfg(any(feature = "datasets", test))]
+pub mod datasets;
+#[cfg(feature = "dbscan")]
+mod dbscan;
+mod dim_buffer;
+mod distance_scale;
+mod distinct;
+mod dump;
+mod duplicate_report;
+mod dynamic_tree;
+mod ellipsoid_search;
+mod error;
+#[cfg(not(feature = "strict-exact"))]
+pub mod eval;
+mod extract;
+mod extremes;
+mod farthest_point_sampling;
+mod flat_tree;
+mod flat_view;
+#[cfg(not(feature = "strict-exact"))]
+mod forest;
+#[cfg(any(feature = "f64", test))]
+pub mod geo;
+mod gram;
+mod grouped;
+mod hausdorff;
 mod heap;
+mod heat_map;
+mod ids;
 mod infinite;
+mod insufficient;
 mod internal_neighbour;
 mod internal_parameters;
+mod interpolation;
+mod isometry;
+mod k_distances;
+mod knn_graph;
+mod knn_results;
+mod knn_summary;
+mod leaf_scan;
+#[cfg(not(feature = "strict-exact"))]
+mod lod;
+mod lower_bound;
+mod matching;
+mod merge;
+mod metric;
+mod morton;
+mod mst;
+mod multi_radius;
+mod nearest_hint;
+mod nearest_iter;
+mod neighbour_lists;
 mod node;
+mod node_annotations;
+mod node_ref;
+mod oriented_search;
+mod pair_counts;
+mod parameters;
+mod polyline;
+mod prediction;
+#[cfg(not(feature = "strict-exact"))]
+mod quantized;
+mod query_explain;
+#[cfg(not(feature = "strict-exact"))]
+mod query_handle;
+mod query_point;
+mod query_stats;
+mod query_trace;
+mod radius;
+mod radius_probe;
+mod radius_search;
+mod random_split;
+mod range_query;
+mod raw_results;
+mod refit;
+mod region_query;
+mod rng;
+mod rotation;
+#[cfg(feature = "rand")]
+mod sampling;
+#[cfg(test)]
+mod scalar_tests;
+mod self_check;
 pub mod simple_point;
+mod sliding_tree;
+mod snapshot;
+mod sphere;
+mod stamped_index;
+#[cfg(all(test, feature = "strict-exact"))]
+mod strict_exact;
+#[cfg(feature = "structural_hash")]
+mod structural_hash;
+mod thinning;
+mod time_slice;
+mod traversal;
+mod tree_map;
+mod tree_pair;
+mod tree_quality;
+mod tree_stats;
+#[cfg(not(feature = "strict-exact"))]
+mod tuning;
+mod weighted;
 
-use alloc::{collections::BinaryHeap, vec, vec::Vec};
+use alloc::{boxed::Box, collections::BinaryHeap, vec, vec::Vec};
+use axis_index::AxisIndex;
+use cell::NodeCell;
 use core::ops::AddAssign;
-use core::ops::Range;
+use dim_buffer::DimBuffer;
+use distance_scale::{distance_scale, unscale_dist2};
 use internal_parameters::InternalParameters;
+use metric::point_slice_dist2;
 use node::Node;
-use num_traits::{clamp_max, clamp_min, Bounded, Zero};
+use num_traits::{clamp_max, clamp_min, Bounded, One, Zero};
+/// The version of `ordered_float` whose types appear in the public API, see [the crate documentation](crate)
+pub use ordered_float;
 use ordered_float::FloatCore;
 pub use ordered_float::{FloatIsNan, NotNan};
 
-use heap::CandidateHeap;
+use build_report::PhaseClock;
+use cancellation::CANCELLATION_CHECK_SPAN;
+use heap::{CandidateHeap, ExcludingCollector};
+use infinite::HasInfinite;
+use query_trace::NodeVisitor;
+use rng::SplitMix64;
+use time_slice::Timestamps;
+use traversal::ChildBounds;
 
+pub use accumulation::Accumulation;
+pub use aggregate::Aggregate;
+pub use all_pairs::TileId;
+#[cfg(any(all(feature = "f32", feature = "f64"), test))]
+pub use any_tree::{AnyFlatTree, AnyKDTree};
+#[cfg(feature = "async")]
+pub use async_batch::KnnBatchFuture;
+pub use barycentric::BarycentricResult;
+pub use batch_stats::{BatchStats, Quantiles};
+pub use batch_update::BatchIndices;
+pub use bit_set::BitSet;
+pub use build_options::{BuildOptions, HighDimension};
+pub use build_report::{BuildMemory, BuildReport, BuildTimes, BuildWarning};
+pub use builder::KDTreeBuilder;
+pub use cancellation::CancellationToken;
+pub use chamfer::ChamferResult;
+pub use clearance::Clearance;
+pub use concurrent::{ConcurrentKDTree, TreeGuard};
+pub use covering::CoveringKDTree;
+#[cfg(feature = "dbscan")]
+pub use dbscan::NOISE;
+pub use dump::DumpOptions;
+pub use duplicate_report::DuplicateReport;
+pub use dynamic_tree::DynamicKDTree;
+pub use error::{BuildError, Error, ParameterError, QueryError};
+pub use flat_tree::{FlatNode, FlatTree};
+pub use flat_view::{FlatNeighbour, FlatTreeView, PrefetchLevel, StackOverflow, TraversalFrame};
+#[cfg(not(feature = "strict-exact"))]
+pub use forest::KDForest;
+pub use grouped::GroupedKDTree;
+pub use heap::ArrayCollector;
 pub use heap::BoundedCollector;
 pub use heap::CandidateCollector;
+pub use heap::FixedCollector;
 pub use heap::UnboundedCollector;
+pub use heat_map::NodeHeatMap;
 pub use internal_neighbour::InternalNeighbour;
+pub use internal_parameters::PreparedParameters;
+pub use isometry::Isometry;
+pub use k_distances::Histogram;
+pub use knn_graph::{CsrGraph, KnnGraphSymmetry};
+pub use knn_results::KnnResults;
+pub use knn_results::MappedKnnResults;
+pub use knn_summary::KnnSummary;
+#[cfg(not(feature = "strict-exact"))]
+pub use lod::{LodNeighbour, LodPolicy};
+pub use nearest_iter::NearestIter;
+pub use node_annotations::{NodeAnnotations, RelayoutMap};
+pub use node_ref::NodeRef;
+pub use parameters::{
+    Boundary, InsufficientPolicy, Parameters, ParametersBuilder, QueryOverride, SelfMatch,
+    TraversalOrder,
+};
+pub use prediction::Weighting;
+#[cfg(not(feature = "strict-exact"))]
+pub use quantized::QuantizedKDTree;
+pub use query_explain::{QueryExplanation, QueryHint};
+#[cfg(not(feature = "strict-exact"))]
+pub use query_handle::QueryHandle;
+pub use query_point::{Converted, QueryPoint};
+pub use query_stats::QueryStats;
+pub use query_trace::{QueryTrace, TraceStep};
+pub use radius::{Radius, SquaredRadius};
+pub use radius_probe::RadiusProbe;
+pub use raw_results::{KnnScratch, RawResults};
+pub use refit::UpdateSummary;
+pub use self_check::SelfCheckFailure;
+pub use sliding_tree::SlidingKDTree;
+pub use snapshot::KDTreeSnapshot;
+pub use sphere::DirectionalNeighbour;
+pub use stamped_index::{StaleIndex, StampedIndex};
+pub use tree_map::KDTreeMap;
+pub use tree_pair::TreePair;
+pub use tree_quality::{AutoRebuild, TreeQuality};
+pub use tree_stats::TreeStats;
+#[cfg(not(feature = "strict-exact"))]
+pub use tuning::TuningReport;
+pub use weighted::{WeightedKDTree, WeightedNeighbour};
 
 /// The scalar type for points in the space to be searched
+///
+/// It is implemented for all types implementing [`FloatCore`], [`AddAssign`] and [`Debug`](core::fmt::Debug),
+/// such as `f32` and `f64`, but also types defined outside the crate, for example a newtype
+/// wrapping `f32` that carries a unit in its type.
+/// The searches only rely on these traits, in particular on [`FloatCore::epsilon`], which is
+/// the largest squared distance of a self match, and on [`FloatCore::max_value`],
+/// to which overflowing squared distances saturate.
+/// Some functions, such as [`Neighbour::dist`], also need [`num_traits::Float`].
 pub trait Scalar: FloatCore + AddAssign + core::fmt::Debug {}
 impl<T: FloatCore + AddAssign + core::fmt::Debug> Scalar for T {}
 
@@ -113,23 +387,34 @@ pub trait Point<T: Scalar>: Default {
     }
 }
 
-/// Helper function to compute the square distance between two points given as slice
+/// Copies a point through the [`Point`] trait, as points are not required to be `Clone`
+fn copy_point<T: Scalar, P: Point<T>>(point: &P) -> P {
+    let mut copy = P::default();
+    for i in 0..P::DIM {
+        copy.set(i, point.get(i));
+    }
+    copy
+}
+
+/// Updates the squared distance `rd` from the query to a cell, when the offset along one dimension
+/// changes from `old_off` to `new_off`
+///
+/// Saturates to infinity if the squared offsets are both infinite and cannot be subtracted.
 #[inline]
-fn point_slice_dist2<T: Scalar, P: Point<T>>(lhs: &[NotNan<T>], rhs: &[NotNan<T>]) -> NotNan<T> {
-    let mut dist2 = NotNan::<T>::zero();
-    for index in 0..P::DIM {
-        let index = index as usize;
-        let diff = lhs[index] - rhs[index];
-        dist2 += diff * diff;
-    }
-    dist2
+fn update_rd<T: Scalar>(rd: NotNan<T>, old_off: NotNan<T>, new_off: NotNan<T>) -> NotNan<T> {
+    let (rd, old_off, new_off) = (rd.into_inner(), old_off.into_inner(), new_off.into_inner());
+    NotNan::new(rd + (new_off * new_off - old_off * old_off)).unwrap_or_else(|_| NotNan::infinite())
 }
 
 /// The index of a point in the original point cloud
 pub type Index = u32;
 
 /// A neighbour resulting from the search
-#[derive(Debug)]
+///
+/// Neighbours compare by squared distance, then by index, ignoring the point:
+/// two neighbours with the same distance and index are equal.
+/// Within the results of a query, the index determines the point.
+#[derive(Clone, Copy, Debug)]
 pub struct Neighbour<T: Scalar, P: Point<T>> {
     /// the point itself
     pub point: P,
@@ -139,7 +424,63 @@ pub struct Neighbour<T: Scalar, P: Point<T>> {
     pub index: Index,
 }
 
+impl<T: Scalar, P: Point<T>> Neighbour<T, P> {
+    /// Creates a neighbour from the index of its point in the original point cloud,
+    /// its squared distance and the point itself.
+    ///
+    /// Panics if `dist2` is NaN.
+    pub fn new(index: Index, dist2: T, point: P) -> Self {
+        Neighbour {
+            point,
+            dist2: NotNan::new(dist2).expect("dist2 must not be NaN"),
+            index,
+        }
+    }
+
+    /// Returns the squared distance to the point.
+    pub fn dist2(&self) -> SquaredRadius<T> {
+        SquaredRadius::new(self.dist2.into_inner())
+    }
+}
+
+impl<T: Scalar + num_traits::Float, P: Point<T>> Neighbour<T, P> {
+    /// Returns the distance to the point.
+    pub fn dist(&self) -> Radius<T> {
+        self.dist2().sqrt()
+    }
+}
+
+impl<T: Scalar, P: Point<T>> PartialEq for Neighbour<T, P> {
+    fn eq(&self, other: &Self) -> bool {
+        self.dist2 == other.dist2 && self.index == other.index
+    }
+}
+
+impl<T: Scalar, P: Point<T>> Eq for Neighbour<T, P> {}
+
+impl<T: Scalar, P: Point<T>> PartialOrd for Neighbour<T, P> {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: Scalar, P: Point<T>> Ord for Neighbour<T, P> {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        self.dist2
+            .cmp(&other.dist2)
+            .then_with(|| self.index.cmp(&other.index))
+    }
+}
+
+impl<T: Scalar, P: Point<T>> core::hash::Hash for Neighbour<T, P> {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        self.dist2.hash(state);
+        self.index.hash(state);
+    }
+}
+
 /// The type of container to keep candidates
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CandidateContainer {
     /// use a linear vector to keep candidates, good for small k
     Linear,
@@ -147,18 +488,6 @@ pub enum CandidateContainer {
     BinaryHeap,
 }
 
-/// Advanced search parameters
-pub struct Parameters<T: Scalar> {
-    /// maximal ratio of error for approximate search, 0 for exact search; has no effect if the number of neighbours found is smaller than the number requested
-    pub epsilon: T,
-    /// maximum radius in which to search, can be used to prune search, is not affected by `epsilon`
-    pub max_radius: T,
-    /// allows the return of the same point as the query, if this point is in the point cloud
-    pub allow_self_match: bool,
-    /// sort points by distances, when `k` > 1
-    pub sort_results: bool,
-}
-
 /// A dense vector of search nodes, provides better memory performances than many small objects
 type Nodes<T, P> = Vec<Node<T, P>>;
 
@@ -167,54 +496,420 @@ type Nodes<T, P> = Vec<Node<T, P>>;
 /// This implementation is inspired of the variant `KDTreeUnbalancedPtInLeavesImplicitBoundsStackOpt` in libnabo C++.
 /// Contrary to the latter, it does not keep a reference to the point cloud but copies the point.
 /// It retains their index though.
-#[derive(Clone, Debug)]
+///
+/// All queries take `&self`, and the tree is `Send` and `Sync` when its point type is,
+/// so a single tree can be shared between threads.
 pub struct KDTree<T: Scalar, P: Point<T>> {
-    /// size of a bucket
-    bucket_size: u32,
+    /// options the tree was built with, including the size of a bucket
+    options: BuildOptions,
     /// search nodes
     nodes: Nodes<T, P>,
     /// point data, size cloud.len() * P::DIM
     points: Vec<NotNan<T>>,
     /// indices in cloud , size cloud.len()
     indices: Vec<Index>,
+    /// inverse of `indices`: position in `indices` of each index in cloud, size cloud.len()
+    internal_indices: Vec<u32>,
+    /// component-wise minimum of the points, size P::DIM
+    min_bounds: Vec<NotNan<T>>,
+    /// component-wise maximum of the points, size P::DIM
+    max_bounds: Vec<NotNan<T>>,
+    /// power-of-two factor applied to coordinate differences when computing distances
+    scale: NotNan<T>,
+    /// upper bound of how far any point sits on the wrong side of an ancestor split, see `refit`
+    drift: NotNan<T>,
+    /// `drift` multiplied by `scale`, used to widen pruning
+    scaled_drift: NotNan<T>,
+    /// count, coordinate sums and outer product sums per node, see [`BuildOptions::subtree_sums`]
+    subtree_sums: Option<Vec<f64>>,
+    /// identifiers of the points by internal index, see [`KDTree::new_with_ids`]
+    ids: Option<Vec<u64>>,
+    /// points sorted along some dimensions, see [`KDTree::build_axis_index`]
+    axis_indices: Vec<AxisIndex<T>>,
+    /// rotation applied to the points and queries, row-major, see [`BuildOptions::rotation_seed`]
+    rotation: Option<Vec<T>>,
+    /// coordinates of the points as given, by index in cloud, size cloud.len() * P::DIM, if the tree is rotated,
+    /// so that returned points and tests against boxes do not depend on rotating the stored points back
+    originals: Option<Vec<NotNan<T>>>,
+    /// number of operations that changed the meaning of the indices, see [`KDTree::generation`]
+    generation: u64,
+    /// tree of every few points, see [`BuildOptions::coarse_stride`]
+    coarse: Option<Box<KDTree<T, P>>>,
+    /// timestamps of the points and their range per node, see [`KDTree::with_timestamps`]
+    timestamps: Option<Timestamps<T>>,
+}
+
+impl<T: Scalar, P: Point<T>> Clone for KDTree<T, P> {
+    fn clone(&self) -> Self {
+        KDTree {
+            options: self.options,
+            nodes: self.nodes.clone(),
+            points: self.points.clone(),
+            indices: self.indices.clone(),
+            internal_indices: self.internal_indices.clone(),
+            min_bounds: self.min_bounds.clone(),
+            max_bounds: self.max_bounds.clone(),
+            scale: self.scale,
+            drift: self.drift,
+            scaled_drift: self.scaled_drift,
+            subtree_sums: self.subtree_sums.clone(),
+            ids: self.ids.clone(),
+            axis_indices: self.axis_indices.clone(),
+            rotation: self.rotation.clone(),
+            originals: self.originals.clone(),
+            generation: self.generation,
+            coarse: self.coarse.clone(),
+            timestamps: self.timestamps.clone(),
+        }
+    }
+}
+
+impl<T: Scalar, P: Point<T>> core::fmt::Debug for KDTree<T, P> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("KDTree")
+            .field("options", &self.options)
+            .field("nodes", &self.nodes)
+            .field("points", &self.points)
+            .field("indices", &self.indices)
+            .field("internal_indices", &self.internal_indices)
+            .field("min_bounds", &self.min_bounds)
+            .field("max_bounds", &self.max_bounds)
+            .field("scale", &self.scale)
+            .field("drift", &self.drift)
+            .field("ids", &self.ids)
+            .field("rotation", &self.rotation)
+            .field("originals", &self.originals)
+            .field("generation", &self.generation)
+            .field("timestamps", &self.timestamps)
+            .finish()
+    }
+}
+
+/// Structural equality: two trees are equal if they have the same bucket size,
+/// the same nodes, and store the same points with the same indices, identifiers and timestamps in the same order,
+/// in the same frame.
+///
+/// Trees built from the same point cloud with the same bucket size are equal.
+impl<T: Scalar, P: Point<T>> PartialEq for KDTree<T, P> {
+    fn eq(&self, other: &Self) -> bool {
+        // bounds, scale and drift are derived from the points
+        self.options.bucket_size == other.options.bucket_size
+            && self.nodes == other.nodes
+            && self.points == other.points
+            && self.indices == other.indices
+            && self.ids == other.ids
+            && self.timestamps == other.timestamps
+            && self.rotation == other.rotation
+            && self.originals == other.originals
+    }
 }
 
 impl<T: Scalar, P: Point<T>> KDTree<T, P> {
     /// Creates a new KD-Tree from a point cloud.
+    ///
+    /// If the coordinates are of such magnitude that their squared differences would overflow
+    /// or underflow, for example around 1e20 or 1e-40 in `f32`, distances are internally
+    /// computed on coordinates scaled by a power of two, which preserves their order.
+    /// Distances returned in [`Neighbour`] are always expressed in the original units,
+    /// saturating to `T::max_value()` if they are not representable.
+    /// The scaling is common to the whole cloud, so a cloud mixing both extremes will still
+    /// lose precision on its smallest differences.
     pub fn new(cloud: &[P]) -> Self {
         KDTree::new_with_bucket_size(cloud, 8)
     }
     /// Creates a new KD-Tree from a point cloud.
     ///
     /// The `bucket_size` can be chosen freely, but must be at least 2.
+    /// Panics if the tree cannot be built, see [`Self::try_new_with_bucket_size`].
     pub fn new_with_bucket_size(cloud: &[P], bucket_size: u32) -> Self {
+        Self::try_new_with_bucket_size(cloud, bucket_size)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Creates a new KD-Tree from a point cloud, or returns an error if it cannot be built.
+    ///
+    /// Same as [`Self::new`], but does not panic.
+    pub fn try_new(cloud: &[P]) -> Result<Self, BuildError> {
+        Self::try_new_with_bucket_size(cloud, 8)
+    }
+
+    /// Creates a new KD-Tree from a point cloud, or returns an error if it cannot be built.
+    ///
+    /// Building fails if `bucket_size` is smaller than 2, if the cloud has more than `u32::MAX` points
+    /// or a point with an infinite coordinate, if the tree would have more nodes than
+    /// can be encoded for the number of dimensions of `P`, or if it would be deeper than
+    /// [`BuildOptions::MAX_SAFE_DEPTH`] splits.
+    /// This function does not panic.
+    pub fn try_new_with_bucket_size(cloud: &[P], bucket_size: u32) -> Result<Self, BuildError> {
+        Self::try_new_with_options(
+            cloud,
+            &BuildOptions {
+                bucket_size,
+                ..BuildOptions::default()
+            },
+        )
+    }
+
+    /// Creates a new KD-Tree from a point cloud, with user-provided build options.
+    ///
+    /// Panics if the tree cannot be built, see [`Self::try_new_with_bucket_size`].
+    pub fn new_with_options(cloud: &[P], options: &BuildOptions) -> Self {
+        Self::try_new_with_options(cloud, options).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Creates a new KD-Tree from a point cloud, with user-provided build options,
+    /// or returns an error if it cannot be built, see [`Self::try_new_with_bucket_size`].
+    ///
+    /// This function does not panic.
+    pub fn try_new_with_options(cloud: &[P], options: &BuildOptions) -> Result<Self, BuildError> {
+        Self::try_build(cloud, options, None, None)
+    }
+
+    /// Creates a new KD-Tree from a point cloud, with user-provided build options,
+    /// or returns an error if it cannot be built, see [`Self::try_new_with_options`].
+    ///
+    /// Returns [`BuildError::Cancelled`] if `cancellation` gets cancelled during the build,
+    /// which is checked before splitting each subtree of many points.
+    pub fn try_new_cancellable(
+        cloud: &[P],
+        options: &BuildOptions,
+        cancellation: &CancellationToken,
+    ) -> Result<Self, BuildError> {
+        Self::try_build(cloud, options, None, Some(cancellation))
+    }
+
+    /// Builds a tree, with splits randomised by `rng` if given, see [`KDForest`],
+    /// or else by [`BuildOptions::seed`], and stopping with [`BuildError::Cancelled`] if `cancellation` is cancelled
+    pub(crate) fn try_build(
+        cloud: &[P],
+        options: &BuildOptions,
+        rng: Option<&mut SplitMix64>,
+        cancellation: Option<&CancellationToken>,
+    ) -> Result<Self, BuildError> {
+        Self::try_build_timed(
+            cloud,
+            options,
+            rng,
+            cancellation,
+            &mut BuildTimes::default(),
+        )
+    }
+
+    /// Builds the tree phase by phase, see [`BuildTimes`], recording the time of each phase in `times`
+    pub(crate) fn try_build_timed(
+        cloud: &[P],
+        options: &BuildOptions,
+        rng: Option<&mut SplitMix64>,
+        cancellation: Option<&CancellationToken>,
+        times: &mut BuildTimes,
+    ) -> Result<Self, BuildError> {
+        let mut clock = PhaseClock::start();
+        let bucket_size = options.bucket_size;
         // validate input
-        if bucket_size < 2 {
-            panic!(
-                "Bucket size must be at least 2, but {} was passed",
-                bucket_size
-            );
-        }
+        options.validate()?;
+        let mut seeded_rng = options.seed.map(SplitMix64::new);
+        let rng = rng.or(seeded_rng.as_mut());
         if cloud.len() > u32::MAX as usize {
-            panic!(
-                "Point cloud is larger than maximum possible size {}",
-                u32::MAX
-            );
+            return Err(BuildError::TooManyPoints(cloud.len()));
+        }
+        if P::DIM == 0 {
+            return Err(BuildError::ZeroDimension);
         }
-        let estimated_node_count = (cloud.len() / (bucket_size as usize / 2)) as u32;
-        if estimated_node_count > P::MAX_NODE_COUNT {
-            panic!("Point cloud has a risk to have more nodes {} than the kd-tree allows {}. The kd-tree has {} bits for dimensions and {} bits for node indices", estimated_node_count, P::MAX_NODE_COUNT, P::DIM_BIT_COUNT, 32 - P::DIM_BIT_COUNT);
+        options.high_dimension.check(P::DIM)?;
+        let estimated_node_count = cloud.len() / (bucket_size as usize / 2);
+        if estimated_node_count > P::MAX_NODE_COUNT as usize {
+            return Err(BuildError::TooManyNodes {
+                node_count: estimated_node_count,
+                max_node_count: P::MAX_NODE_COUNT,
+            });
+        }
+        if let Some(index) = cloud
+            .iter()
+            .position(|point| (0..P::DIM).any(|i| !point.get(i).is_finite()))
+        {
+            return Err(BuildError::NonFiniteCoordinate {
+                index: index as u32,
+            });
         }
+        let rotation = options
+            .rotation_seed
+            .map(|seed| rotation::random_rotation(P::DIM as usize, seed));
 
         // create and populate tree
         let mut tree = KDTree {
-            bucket_size,
-            nodes: Vec::with_capacity(estimated_node_count as usize),
+            options: *options,
+            nodes: Vec::with_capacity(estimated_node_count),
             points: Vec::with_capacity(cloud.len() * P::DIM as usize),
             indices: Vec::with_capacity(cloud.len()),
+            internal_indices: Vec::new(),
+            min_bounds: Vec::new(),
+            max_bounds: Vec::new(),
+            scale: NotNan::one(),
+            drift: NotNan::<T>::zero(),
+            scaled_drift: NotNan::<T>::zero(),
+            subtree_sums: None,
+            ids: None,
+            axis_indices: Vec::new(),
+            originals: rotation.as_ref().map(|_| {
+                cloud
+                    .iter()
+                    .flat_map(|point| (0..P::DIM).map(move |i| point.get(i)))
+                    .collect()
+            }),
+            rotation,
+            generation: 0,
+            coarse: None,
+            timestamps: None,
+        };
+        let (ingested, order) = tree.ingest(cloud)?;
+        times.ingest = clock.lap();
+
+        tree.build_nodes(
+            ingested.as_deref().unwrap_or(cloud),
+            rng,
+            cancellation,
+            options.duplicate_leaves,
+        )?;
+        if let Some(order) = order {
+            for index in &mut tree.indices {
+                *index = order[*index as usize] as u32;
+            }
+        }
+        times.split = clock.lap();
+
+        tree.update_internal_indices();
+        tree.update_bounds_and_scale();
+        times.index = clock.lap();
+
+        if options.subtree_sums {
+            tree.subtree_sums = Some(tree.compute_subtree_sums());
+        }
+        if let Some(stride) = options.coarse_stride {
+            tree.coarse = Some(Box::new(Self::try_build_coarse(
+                cloud,
+                stride,
+                options,
+                cancellation,
+            )?));
+        }
+        times.augment = clock.lap();
+        Ok(tree)
+    }
+
+    /// Returns the points of `cloud` in the frame and order they are split in, if that differs from `cloud`,
+    /// and the order of the points by their original index, if they are presorted
+    #[allow(clippy::type_complexity)]
+    fn ingest(&self, cloud: &[P]) -> Result<(Option<Vec<P>>, Option<Vec<usize>>), BuildError> {
+        let rotated = if self.rotation.is_some() {
+            let rotated: Vec<P> = cloud
+                .iter()
+                .map(|point| self.rotated_point(point).expect("the tree is rotated"))
+                .collect();
+            // finite coordinates may only overflow when rotated
+            if let Some(index) = rotated
+                .iter()
+                .position(|point| (0..P::DIM).any(|i| !point.get(i).is_finite()))
+            {
+                return Err(BuildError::NonFiniteCoordinate {
+                    index: index as u32,
+                });
+            }
+            Some(rotated)
+        } else {
+            None
         };
-        tree.build_nodes(cloud);
-        tree
+        if !self.options.morton_presort {
+            return Ok((rotated, None));
+        }
+        let cloud = rotated.as_deref().unwrap_or(cloud);
+        let order = morton::morton_order(cloud);
+        let sorted = order
+            .iter()
+            .map(|index| copy_point(&cloud[*index]))
+            .collect();
+        Ok((Some(sorted), Some(order)))
+    }
+
+    /// Computes the inverse permutation of `indices`
+    fn update_internal_indices(&mut self) {
+        let mut internal_indices = vec![0; self.indices.len()];
+        for (internal, external) in self.indices.iter().enumerate() {
+            internal_indices[*external as usize] = internal as u32;
+        }
+        self.internal_indices = internal_indices;
+    }
+
+    /// Computes the bounding box of the stored points, and from it the scale of distances
+    fn update_bounds_and_scale(&mut self) {
+        let mut min_bounds = vec![NotNan::<T>::max_value(); P::DIM as usize];
+        let mut max_bounds = vec![NotNan::<T>::min_value(); P::DIM as usize];
+        for point in self.points.chunks(P::DIM as usize) {
+            for (index, value) in point.iter().enumerate() {
+                min_bounds[index] = clamp_max(*value, min_bounds[index]);
+                max_bounds[index] = clamp_min(*value, max_bounds[index]);
+            }
+        }
+        self.min_bounds = min_bounds;
+        self.max_bounds = max_bounds;
+        self.update_scale();
+    }
+
+    /// Computes the scale of distances from the bounding box
+    fn update_scale(&mut self) {
+        self.scale = distance_scale(self.max_abs_coordinate(), P::DIM);
+        self.scaled_drift = self.drift * self.scale;
+    }
+
+    /// Returns the largest absolute value of the coordinates of the bounding box, zero if the tree is empty
+    pub(crate) fn max_abs_coordinate(&self) -> NotNan<T> {
+        if self.is_empty() {
+            return NotNan::<T>::zero();
+        }
+        self.min_bounds
+            .iter()
+            .zip(&self.max_bounds)
+            .map(|(min, max)| core::cmp::max(-*min, *max))
+            .max()
+            .unwrap_or_else(NotNan::<T>::zero)
+    }
+
+    /// Returns the options the tree was built with.
+    ///
+    /// Trees imported from a [`FlatTree`] or rebuilt by merging report the default options,
+    /// except for the bucket size and whether they have subtree sums.
+    pub fn build_options(&self) -> BuildOptions {
+        self.options
+    }
+
+    /// Returns the number of points in the tree.
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    /// Returns whether the tree contains no points.
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    /// Returns the number of dimensions of the space of the tree.
+    pub fn dim(&self) -> u32 {
+        P::DIM
+    }
+
+    /// Returns the component-wise minimum and maximum of the points in the tree,
+    /// or `None` if the tree is empty.
+    ///
+    /// If the tree is rotated, these are the bounds of the rotated points, in the frame of the tree,
+    /// see [`Self::rotation`].
+    pub fn bounds(&self) -> Option<(P, P)> {
+        if self.is_empty() {
+            None
+        } else {
+            Some((
+                P::from_slice(&self.min_bounds),
+                P::from_slice(&self.max_bounds),
+            ))
+        }
     }
 
     /// Finds the `k` nearest neighbour of `query`, using reasonable default parameters.
@@ -223,604 +918,2289 @@ impl<T: Scalar, P: Point<T>> KDTree<T, P> {
     /// The default parameters are:
     /// Exact search, no max. radius, allowing self matching, sorting results, and not collecting statistics.
     /// If `k` <= 16, a linear vector is used to keep track of candidates, otherwise a binary heap is used.
-    pub fn knn(&self, k: u32, query: &P) -> Vec<Neighbour<T, P>> {
-        let candidate_container = if k <= 16 {
-            CandidateContainer::Linear
-        } else {
-            CandidateContainer::BinaryHeap
-        };
+    /// `query` is a point or any other type of [`QueryPoint`], such as a slice of coordinates.
+    /// Panics if `query` does not have the same number of dimensions as the tree.
+    pub fn knn<Q: QueryPoint<T> + ?Sized>(&self, k: u32, query: &Q) -> Vec<Neighbour<T, P>> {
         #[cfg_attr(rustfmt, rustfmt_skip)]
         self.knn_advanced(
             k, query,
-            candidate_container,
-            &Parameters {
-                epsilon: T::from(0.0).unwrap(),
-                max_radius: T::infinity(),
-                allow_self_match: true,
-                sort_results: true,
-            },
+            Self::default_candidate_container(k),
+            &Parameters::default(),
             None,
         )
     }
 
+    /// Finds the `k` nearest neighbour of `query`, using reasonable default parameters,
+    /// or returns an error if the query is invalid.
+    ///
+    /// Same as [`Self::knn`], but does not panic.
+    pub fn try_knn<Q: QueryPoint<T> + ?Sized>(
+        &self,
+        k: u32,
+        query: &Q,
+    ) -> Result<Vec<Neighbour<T, P>>, QueryError> {
+        let query = self.try_query_coordinates(query)?;
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let (neighbours, _) = self.knn_coordinates(
+            k, &query,
+            Self::default_candidate_container(k),
+            &Parameters::default(),
+        );
+        Ok(neighbours)
+    }
+
+    fn default_candidate_container(k: u32) -> CandidateContainer {
+        if k <= 16 {
+            CandidateContainer::Linear
+        } else {
+            CandidateContainer::BinaryHeap
+        }
+    }
+
     /// Finds the `k` nearest neighbour of `query`, with user-provided parameters.
     ///
     /// If there are less than `k` points in the point cloud or in the ball around `query`
-    /// defined by `parameters.max_radius`, the returned vector will be smaller than `k`.
+    /// defined by `parameters.max_radius`, the returned vector will be smaller than `k`,
+    /// unless `parameters.on_insufficient` completes it, see [`InsufficientPolicy`].
     /// The parameters are:
     /// * `candidate_container` which container to use to collect candidates,
     /// * `parameters` the advanced search parameters,
     /// * `touch_statistics`, if `Some(&mut u32)`, return the number of point touched in the provided `u32` reference.
-    pub fn knn_advanced(
+    ///
+    /// Panics if `query` does not have the same number of dimensions as the tree, see [`Self::knn`].
+    pub fn knn_advanced<Q: QueryPoint<T> + ?Sized>(
         &self,
         k: u32,
-        query: &P,
+        query: &Q,
         candidate_container: CandidateContainer,
         parameters: &Parameters<T>,
         touch_statistics: Option<&mut u32>,
     ) -> Vec<Neighbour<T, P>> {
         #[cfg_attr(rustfmt, rustfmt_skip)]
-        (match candidate_container {
-            CandidateContainer::Linear => Self::knn_generic_heap::<Vec<InternalNeighbour<T>>>,
-            CandidateContainer::BinaryHeap => Self::knn_generic_heap::<BinaryHeap<InternalNeighbour<T>>>
-        })(
-            self,
-            k, query,
-            parameters, touch_statistics
-        )
+        let (neighbours, stats) = self.knn_coordinates(
+            k, &self.query_coordinates(query),
+            candidate_container,
+            parameters,
+        );
+        if let Some(touch_statistics) = touch_statistics {
+            *touch_statistics = stats.touched_points;
+        }
+        neighbours
     }
 
-    /// Finds the nearest neighbours of `query`, with user-provided parameters.
-    pub fn nn_raw<H: CandidateCollector<T>>(
+    /// Finds the `k` nearest neighbour of `query`, with user-provided parameters,
+    /// and returns `f(index, dist2)` for each of them, in the order of the results.
+    ///
+    /// `f` receives the index in the original point cloud and the squared distance of each neighbour,
+    /// after sorting if `parameters.sort_results`, so that results of the caller's type are built
+    /// directly, without building neighbours nor an intermediate vector of them.
+    /// The candidate container is the default one for `k`, see [`Self::knn`].
+    pub fn knn_map<R>(
         &self,
+        k: u32,
         query: &P,
-        collector: &mut H,
         parameters: &Parameters<T>,
-        touch_statistics: Option<&mut u32>,
-    ) {
-        let query_as_vec: Vec<_> = (0..P::DIM).map(|i| query.get(i)).collect();
-        self.knn_internal(
-            collector,
-            &query_as_vec,
-            &parameters.into(),
-            touch_statistics,
-        );
+        mut f: impl FnMut(u32, T) -> R,
+    ) -> Vec<R> {
+        let parameters = self.prepare_parameters(parameters);
+        let query = self.query_coordinates(query);
+        let map = |n: InternalNeighbour<T>| {
+            f(
+                self.externalise_index(n.index),
+                unscale_dist2(n.dist2, self.scale).into_inner(),
+            )
+        };
+        let internal = &parameters.internal;
+        let sorted = parameters.sort_results;
+        let results = match Self::default_candidate_container(k) {
+            CandidateContainer::Linear => self
+                .knn_generic_heap_map::<Vec<InternalNeighbour<T>>, R>(
+                    k, &query, internal, sorted, map,
+                ),
+            CandidateContainer::BinaryHeap => self
+                .knn_generic_heap_map::<BinaryHeap<InternalNeighbour<T>>, R>(
+                    k, &query, internal, sorted, map,
+                ),
+        };
+        results.unwrap_or_else(|error| panic!("{}", error)).0
     }
 
-    fn knn_generic_heap<H: CandidateHeap<T> + CandidateCollector<T>>(
+    /// Finds the `k` nearest neighbour of `query`, with user-provided parameters,
+    /// and returns counters of the work done by the search.
+    ///
+    /// Same as [`Self::knn_advanced`], with more detailed statistics.
+    pub fn knn_with_stats<Q: QueryPoint<T> + ?Sized>(
         &self,
         k: u32,
-        query: &P,
+        query: &Q,
+        candidate_container: CandidateContainer,
         parameters: &Parameters<T>,
-        touch_statistics: Option<&mut u32>,
-    ) -> Vec<Neighbour<T, P>> {
-        let query_as_vec: Vec<_> = (0..P::DIM).map(|i| query.get(i)).collect();
-        let mut heap = H::new_with_k(k);
-        self.knn_internal::<H>(
-            &mut heap,
-            &query_as_vec,
-            &parameters.into(),
-            touch_statistics,
-        );
-        if parameters.sort_results {
-            heap.into_sorted_vec()
-        } else {
-            heap.into_vec()
-        }
-        .into_iter()
-        .map(|n| self.externalise_neighbour(n))
-        .collect()
+    ) -> (Vec<Neighbour<T, P>>, QueryStats) {
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        self.knn_coordinates(
+            k, &self.query_coordinates(query),
+            candidate_container,
+            parameters,
+        )
     }
 
-    fn knn_internal<H: CandidateCollector<T>>(
+    /// Finds the `k` nearest neighbour of `query`, with user-provided parameters,
+    /// or returns an error if the query or the parameters are invalid.
+    ///
+    /// Same as [`Self::knn_advanced`], but does not panic.
+    /// The parameters are checked with [`Parameters::validate`].
+    pub fn try_knn_advanced<Q: QueryPoint<T> + ?Sized>(
         &self,
-        heap: &mut H,
-        query: &[NotNan<T>],
-        internal_parameters: &InternalParameters<T>,
+        k: u32,
+        query: &Q,
+        candidate_container: CandidateContainer,
+        parameters: &Parameters<T>,
         touch_statistics: Option<&mut u32>,
-    ) {
-        // TODO Const generics: once available, remove `vec!` below.
-        let mut off = vec![NotNan::<T>::zero(); P::DIM as usize];
+    ) -> Result<Vec<Neighbour<T, P>>, Error> {
+        parameters.validate()?;
+        let query = self.try_query_coordinates(query)?;
         #[cfg_attr(rustfmt, rustfmt_skip)]
-        let leaf_touched_count = self.recurse_knn(
-            query,
-            0, NotNan::<T>::zero(),
-            heap, &mut off,
-            internal_parameters,
-        );
+        let (neighbours, stats) = self.try_knn_coordinates_prepared(
+            k, &query,
+            candidate_container,
+            &self.prepare_parameters(parameters),
+        )?;
         if let Some(touch_statistics) = touch_statistics {
-            *touch_statistics = leaf_touched_count;
+            *touch_statistics = stats.touched_points;
         }
+        Ok(neighbours)
     }
 
-    #[allow(clippy::too_many_arguments)]
-    fn recurse_knn<H: CandidateCollector<T>>(
+    fn knn_coordinates(
         &self,
+        k: u32,
         query: &[NotNan<T>],
-        node: usize,
-        rd: NotNan<T>,
-        heap: &mut H,
-        off: &mut [NotNan<T>],
-        internal_parameters: &InternalParameters<T>,
-    ) -> u32 {
-        self.nodes[node].dispatch_on_type(
-            heap,
-            |heap, split_dim, split_val, right_child| {
-                // split node, see whether we have to recurse
-                let mut rd = rd;
-                let split_dim = split_dim as usize;
-                let old_off = off[split_dim];
-                let new_off = query[split_dim] - split_val;
-                let left_child = node + 1;
-                let right_child = right_child as usize;
-                let InternalParameters {
-                    max_radius2,
-                    max_error2,
-                    ..
-                } = *internal_parameters;
-                if new_off > NotNan::<T>::zero() {
-                    #[cfg_attr(rustfmt, rustfmt_skip)]
-                    let mut leaf_visited_count = self.recurse_knn(
-                        query,
-                        right_child, rd,
-                        heap, off,
-                        internal_parameters,
-                    );
-                    rd += new_off * new_off - old_off * old_off;
-                    if rd <= max_radius2 && rd * max_error2 < heap.furthest_dist2() {
-                        off[split_dim] = new_off;
-                        #[cfg_attr(rustfmt, rustfmt_skip)]
-                        let new_visits= self.recurse_knn(
-                            query,
-                            left_child, rd,
-                            heap, off,
-                            internal_parameters,
-                        );
-                        leaf_visited_count += new_visits;
-                        off[split_dim] = old_off;
-                    }
-                    leaf_visited_count
-                } else {
-                    #[cfg_attr(rustfmt, rustfmt_skip)]
-                    let mut leaf_visited_count = self.recurse_knn(
-                        query,
-                        left_child, rd,
-                        heap, off,
-                        internal_parameters,
-                    );
-                    rd += new_off * new_off - old_off * old_off;
-                    if rd <= max_radius2 && rd * max_error2 < heap.furthest_dist2() {
-                        off[split_dim] = new_off;
-                        #[cfg_attr(rustfmt, rustfmt_skip)]
-                        let new_visits = self.recurse_knn(
-                            query,
-                            right_child, rd,
-                            heap, off,
-                            internal_parameters,
-                        );
-                        leaf_visited_count += new_visits;
-                        off[split_dim] = old_off;
-                    }
-                    leaf_visited_count
-                }
-            },
-            |heap, bucket_start_index, bucket_size| {
-                // leaf node, go through the buckets and check elements
-                let bucket_end_index = bucket_start_index + bucket_size;
-                for bucket_index in bucket_start_index..bucket_end_index {
-                    let point_index = (bucket_index * P::DIM) as usize;
-                    let point = &self.points[point_index..point_index + (P::DIM as usize)];
-                    let dist2 = point_slice_dist2::<T, P>(query, point);
-                    let epsilon = NotNan::new(T::epsilon()).unwrap();
-                    let InternalParameters {
-                        max_radius2,
-                        allow_self_match,
-                        ..
-                    } = *internal_parameters;
-                    if dist2 < max_radius2 && (allow_self_match || (dist2 > epsilon)) {
-                        heap.add(dist2, bucket_index);
-                    }
-                }
-                bucket_size
-            },
+        candidate_container: CandidateContainer,
+        parameters: &Parameters<T>,
+    ) -> (Vec<Neighbour<T, P>>, QueryStats) {
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        self.knn_coordinates_prepared(
+            k, query,
+            candidate_container,
+            &self.prepare_parameters(parameters),
         )
     }
 
-    fn build_nodes(&mut self, cloud: &[P]) {
-        // build point vector and compute bounds
-        let mut build_points: Vec<_> = (0..cloud.len()).collect();
-        enum Branch {
-            Root,
-            Left(usize),
-            Right(usize),
-        }
-        let mut ranges: Vec<_> = vec![(0..cloud.len(), Branch::Root)];
-
-        while let Some((build_points_range, parent_branch)) = ranges.pop() {
-            let count = build_points_range.len() as u32;
-            let pos = self.nodes.len();
+    /// Panics with the error of [`InsufficientPolicy::Error`] if any
+    fn knn_coordinates_prepared(
+        &self,
+        k: u32,
+        query: &[NotNan<T>],
+        candidate_container: CandidateContainer,
+        parameters: &PreparedParameters<T>,
+    ) -> (Vec<Neighbour<T, P>>, QueryStats) {
+        self.try_knn_coordinates_prepared(k, query, candidate_container, parameters)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
 
-            match parent_branch {
-                Branch::Root => (),
-                Branch::Left(parent) => debug_assert_eq!(pos, parent + 1),
-                Branch::Right(parent) => self.nodes[parent].set_child_index(pos as u32), // write the right child index of the parent node
-            }
+    fn try_knn_coordinates_prepared(
+        &self,
+        k: u32,
+        query: &[NotNan<T>],
+        candidate_container: CandidateContainer,
+        parameters: &PreparedParameters<T>,
+    ) -> Result<(Vec<Neighbour<T, P>>, QueryStats), QueryError> {
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        (match candidate_container {
+            CandidateContainer::Linear => Self::knn_generic_heap::<Vec<InternalNeighbour<T>>>,
+            CandidateContainer::BinaryHeap => Self::knn_generic_heap::<BinaryHeap<InternalNeighbour<T>>>
+        })(
+            self,
+            k, query,
+            &parameters.internal,
+            parameters.sort_results,
+        )
+    }
+
+    /// Finds the `k` nearest neighbour of `query` for approximate search with `epsilon`,
+    /// and otherwise the default parameters of [`Self::knn`].
+    ///
+    /// See [`Parameters::epsilon`], an infinite `epsilon` performing a greedy search.
+    /// Absent with the `strict-exact` feature.
+    #[cfg(not(feature = "strict-exact"))]
+    pub fn knn_eps(&self, k: u32, query: &P, epsilon: T) -> Vec<Neighbour<T, P>> {
+        let parameters = Parameters {
+            epsilon,
+            ..Parameters::default()
+        };
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        self.knn_advanced(
+            k, query,
+            Self::default_candidate_container(k),
+            &parameters,
+            None,
+        )
+    }
+
+    /// Finds the `k` nearest neighbour of `query` within `radius`,
+    /// and otherwise the default parameters of [`Self::knn`].
+    ///
+    /// Points at exactly `radius` are returned, see [`Parameters::max_radius`].
+    pub fn knn_within(&self, k: u32, query: &P, radius: Radius<T>) -> Vec<Neighbour<T, P>> {
+        self.knn_within_with(k, query, radius, &Parameters::default())
+    }
+
+    /// Finds the `k` nearest neighbour of `query` within `radius`, with user-provided parameters.
+    ///
+    /// The search is bounded by the smallest of `radius` and `parameters.max_radius` from the start,
+    /// and by the `k`-th nearest candidate once `k` are found, so that the returned vector
+    /// holds at most `k` neighbours, all within the radius.
+    /// It is empty if the nearest point is beyond the radius.
+    pub fn knn_within_with(
+        &self,
+        k: u32,
+        query: &P,
+        radius: Radius<T>,
+        parameters: &Parameters<T>,
+    ) -> Vec<Neighbour<T, P>> {
+        let parameters = Parameters {
+            max_radius: radius.get().min(parameters.max_radius),
+            ..*parameters
+        };
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        self.knn_advanced(
+            k, query,
+            Self::default_candidate_container(k),
+            &parameters,
+            None,
+        )
+    }
+
+    /// Finds the `k` nearest neighbours of the point of the tree at `external_index`, with user-provided parameters.
+    ///
+    /// The query is the point as stored in the tree.
+    /// With [`SelfMatch::ExcludeCoincident`], the point itself is excluded by its index,
+    /// as with [`SelfMatch::ExcludeByIndex`], so that, unlike with [`Self::knn_advanced`], its duplicates are returned.
+    /// The search starts by descending to the leaf holding the point, so that the bound tightens
+    /// before any sibling is considered.
+    /// Returns an error if `external_index` is not smaller than [`Self::len`].
+    pub fn knn_of(
+        &self,
+        k: u32,
+        external_index: u32,
+        parameters: &Parameters<T>,
+    ) -> Result<Vec<Neighbour<T, P>>, QueryError> {
+        if external_index as usize >= self.len() {
+            return Err(QueryError::IndexOutOfRange {
+                index: external_index,
+                len: self.len(),
+            });
+        }
+        let query = self.stored_coordinates(self.internalise_index(external_index));
+        let self_match = match parameters.self_match {
+            SelfMatch::ExcludeCoincident => SelfMatch::ExcludeByIndex(external_index),
+            self_match => self_match,
+        };
+        let internal_parameters = InternalParameters::new(
+            &Parameters {
+                self_match,
+                ..*parameters
+            },
+            self.scale,
+        );
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let (neighbours, _) = (match Self::default_candidate_container(k) {
+            CandidateContainer::Linear => Self::knn_generic_heap::<Vec<InternalNeighbour<T>>>,
+            CandidateContainer::BinaryHeap => Self::knn_generic_heap::<BinaryHeap<InternalNeighbour<T>>>
+        })(
+            self,
+            k, query,
+            &internal_parameters,
+            parameters.sort_results,
+        )?;
+        Ok(neighbours)
+    }
+
+    /// Finds the `k` nearest neighbours of `query` after the `skip` nearest ones, with user-provided parameters.
+    ///
+    /// The neighbours returned are those at positions `skip..skip + k` of the list of all neighbours
+    /// sorted by distance, as filtered by `parameters`.
+    /// The search keeps `skip + k` candidates, so that pruning bounds on the furthest of them,
+    /// and only the last `k` ones are externalised.
+    pub fn knn_skip(
+        &self,
+        skip: u32,
+        k: u32,
+        query: &P,
+        parameters: &Parameters<T>,
+    ) -> Vec<Neighbour<T, P>> {
+        let query = self.query_coordinates(query);
+        let capacity = skip.saturating_add(k).min(self.len() as u32);
+        let mut heap = BinaryHeap::<InternalNeighbour<T>>::new_with_k(capacity);
+        self.knn_internal(
+            &mut heap,
+            &query,
+            &InternalParameters::new(parameters, self.scale),
+        );
+        let skip = skip as usize;
+        let mut candidates = if parameters.sort_results {
+            let mut candidates = heap.into_vec();
+            self.sort_ties_by_external_index(&mut candidates);
+            candidates
+        } else {
+            let mut candidates = heap.into_vec();
+            if skip > 0 && skip < candidates.len() {
+                candidates.select_nth_unstable_by_key(skip - 1, |n| n.dist2);
+            }
+            candidates
+        };
+        candidates
+            .drain(skip.min(candidates.len())..)
+            .map(|n| self.externalise_neighbour(n))
+            .collect()
+    }
+
+    /// Converts `parameters` to the internal units of this tree once,
+    /// to run many queries with them through [`Self::knn_prepared`] and the other `_prepared` functions.
+    ///
+    /// All queries taking [`Parameters`] go through this conversion.
+    /// Panics if `epsilon` or `max_radius` is NaN, or if `epsilon` is not zero with the `strict-exact` feature.
+    pub fn prepare_parameters(&self, parameters: &Parameters<T>) -> PreparedParameters<T> {
+        PreparedParameters {
+            internal: InternalParameters::new(parameters, self.scale),
+            sort_results: parameters.sort_results,
+            scale: self.scale,
+        }
+    }
+
+    /// Converts `parameters` to the internal units of this tree once,
+    /// or returns an error if they are invalid.
+    ///
+    /// Same as [`Self::prepare_parameters`], but the parameters are checked with [`Parameters::validate`],
+    /// so that queries with the prepared parameters need no further check.
+    pub fn try_prepare_parameters(
+        &self,
+        parameters: &Parameters<T>,
+    ) -> Result<PreparedParameters<T>, ParameterError> {
+        parameters.validate()?;
+        Ok(self.prepare_parameters(parameters))
+    }
+
+    /// Panics if `parameters` were prepared by a tree with a different distance scale
+    fn check_prepared(&self, parameters: &PreparedParameters<T>) {
+        assert!(
+            parameters.scale == self.scale,
+            "parameters were prepared for a tree of another scale"
+        );
+    }
+
+    /// Finds the `k` nearest neighbour of `query`, with parameters prepared by [`Self::prepare_parameters`].
+    ///
+    /// Same as [`Self::knn_advanced`] with the default candidate container.
+    /// Panics if the parameters were prepared by a tree with a different distance scale,
+    /// which can happen after inserting points or modifying the tree.
+    pub fn knn_prepared(
+        &self,
+        k: u32,
+        query: &P,
+        parameters: &PreparedParameters<T>,
+    ) -> Vec<Neighbour<T, P>> {
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let (neighbours, _) = self.knn_with_stats_prepared(
+            k, query,
+            Self::default_candidate_container(k),
+            parameters,
+        );
+        neighbours
+    }
+
+    /// Finds the `k` nearest neighbour of `query`, with parameters prepared by [`Self::prepare_parameters`],
+    /// and returns counters of the work done by the search.
+    ///
+    /// Same as [`Self::knn_with_stats`], and panics like [`Self::knn_prepared`].
+    pub fn knn_with_stats_prepared<Q: QueryPoint<T> + ?Sized>(
+        &self,
+        k: u32,
+        query: &Q,
+        candidate_container: CandidateContainer,
+        parameters: &PreparedParameters<T>,
+    ) -> (Vec<Neighbour<T, P>>, QueryStats) {
+        self.check_prepared(parameters);
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        self.knn_coordinates_prepared(
+            k, &self.query_coordinates(query),
+            candidate_container,
+            parameters,
+        )
+    }
+
+    /// Finds the nearest neighbours of `query`, with parameters prepared by [`Self::prepare_parameters`],
+    /// and returns counters of the work done by the search.
+    ///
+    /// Same as [`Self::nn_raw_with_stats`], and panics like [`Self::knn_prepared`].
+    pub fn nn_raw_prepared<H: CandidateCollector<T>, Q: QueryPoint<T> + ?Sized>(
+        &self,
+        query: &Q,
+        collector: &mut H,
+        parameters: &PreparedParameters<T>,
+    ) -> QueryStats {
+        self.check_prepared(parameters);
+        self.knn_internal(
+            collector,
+            &self.query_coordinates(query),
+            &parameters.internal,
+        )
+    }
+
+    /// Finds the nearest neighbours of `query`, with user-provided parameters.
+    ///
+    /// The squared distances passed to `collector` are internal to the tree,
+    /// use [`Self::externalise_neighbour`] to get them in the original units.
+    /// Panics if `query` does not have the same number of dimensions as the tree, see [`Self::knn`].
+    pub fn nn_raw<H: CandidateCollector<T>, Q: QueryPoint<T> + ?Sized>(
+        &self,
+        query: &Q,
+        collector: &mut H,
+        parameters: &Parameters<T>,
+        touch_statistics: Option<&mut u32>,
+    ) {
+        let stats = self.nn_raw_with_stats(query, collector, parameters);
+        if let Some(touch_statistics) = touch_statistics {
+            *touch_statistics = stats.touched_points;
+        }
+    }
+
+    /// Finds the nearest neighbours of `query`, with user-provided parameters,
+    /// and returns counters of the work done by the search.
+    ///
+    /// Same as [`Self::nn_raw`], with more detailed statistics.
+    pub fn nn_raw_with_stats<H: CandidateCollector<T>, Q: QueryPoint<T> + ?Sized>(
+        &self,
+        query: &Q,
+        collector: &mut H,
+        parameters: &Parameters<T>,
+    ) -> QueryStats {
+        self.nn_raw_prepared(query, collector, &self.prepare_parameters(parameters))
+    }
+
+    /// Finds the `K` nearest neighbour of `query`, without allocating on the heap.
+    ///
+    /// Returns the neighbours sorted by increasing distance, and the number of valid neighbours,
+    /// which is smaller than `K` only if the tree or the ball defined by `parameters.max_radius`
+    /// contains less than `K` points.
+    /// The remaining slots are filled with a sentinel neighbour of index `u32::MAX`,
+    /// squared distance infinity, and point `P::default()`.
+    /// Results are always sorted, independently of `parameters.sort_results`.
+    /// For points of more than 8 dimensions, the query itself allocates.
+    pub fn knn_const<const K: usize>(
+        &self,
+        query: &P,
+        parameters: &Parameters<T>,
+    ) -> ([Neighbour<T, P>; K], usize) {
+        let mut collector = ArrayCollector::<T, K>::new();
+        let query = self.query_coordinates(query);
+        self.knn_internal(
+            &mut collector,
+            &query,
+            &InternalParameters::new(parameters, self.scale),
+        );
+        let found = collector.as_slice();
+        let neighbours = core::array::from_fn(|i| match found.get(i) {
+            Some(neighbour) => self.externalise_neighbour(*neighbour),
+            None => Neighbour {
+                point: P::default(),
+                dist2: NotNan::infinite(),
+                index: u32::MAX,
+            },
+        });
+        (neighbours, found.len())
+    }
+
+    fn knn_generic_heap<H: CandidateHeap<T> + CandidateCollector<T>>(
+        &self,
+        k: u32,
+        query: &[NotNan<T>],
+        internal_parameters: &InternalParameters<T>,
+        sort_results: bool,
+    ) -> Result<(Vec<Neighbour<T, P>>, QueryStats), QueryError> {
+        self.knn_generic_heap_map::<H, _>(k, query, internal_parameters, sort_results, |n| {
+            self.externalise_neighbour(n)
+        })
+    }
+
+    /// Finds the `k` nearest neighbour of `query` with a candidate container of type `H`,
+    /// and returns the results of `map` on them, in the order of the results,
+    /// or the error of [`InsufficientPolicy::Error`]
+    fn knn_generic_heap_map<H: CandidateHeap<T> + CandidateCollector<T>, R>(
+        &self,
+        k: u32,
+        query: &[NotNan<T>],
+        internal_parameters: &InternalParameters<T>,
+        sort_results: bool,
+        map: impl FnMut(InternalNeighbour<T>) -> R,
+    ) -> Result<(Vec<R>, QueryStats), QueryError> {
+        // there cannot be more neighbours than points, avoid allocating for them
+        let k = k.min(self.len() as u32);
+        let mut heap = H::new_with_k(k);
+        let mut stats = self.knn_internal::<H>(&mut heap, query, internal_parameters);
+        stats += self.complete_insufficient(&mut heap, k, query, internal_parameters)?;
+        let mut results = heap.into_vec();
+        if sort_results {
+            self.sort_ties_by_external_index(&mut results);
+        }
+        let results = results.into_iter().map(map).collect();
+        Ok((results, stats))
+    }
+
+    /// Validates the query and returns its coordinates
+    ///
+    /// Panics if the query does not have the dimension of the tree, or has NaN coordinates.
+    fn query_coordinates<Q: QueryPoint<T> + ?Sized>(&self, query: &Q) -> DimBuffer<T> {
+        self.try_query_coordinates(query)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Validates the query and returns its coordinates, or an error
+    fn try_query_coordinates<Q: QueryPoint<T> + ?Sized>(
+        &self,
+        query: &Q,
+    ) -> Result<DimBuffer<T>, QueryError> {
+        #[allow(clippy::let_unit_value)]
+        let () = query_point::StaticDimCheck::<T, Q, P>::OK;
+        if query.dim() != P::DIM {
+            return Err(QueryError::DimensionMismatch {
+                query_dim: query.dim(),
+                tree_dim: P::DIM,
+            });
+        }
+        let coordinates =
+            DimBuffer::try_from_fn(P::DIM as usize, |i| NotNan::new(query.coord(i as u32)))
+                .map_err(|_| QueryError::NanCoordinate)?;
+        self.rotate_query(coordinates)
+    }
+
+    fn knn_internal<H: CandidateCollector<T>>(
+        &self,
+        heap: &mut H,
+        query: &[NotNan<T>],
+        internal_parameters: &InternalParameters<T>,
+    ) -> QueryStats {
+        self.knn_internal_visited(heap, &mut (), query, internal_parameters)
+    }
+
+    /// Same as [`Self::knn_internal`], reporting every decision to descend into a node to `visitor`
+    fn knn_internal_visited<H: CandidateCollector<T>, V: NodeVisitor<T>>(
+        &self,
+        heap: &mut H,
+        visitor: &mut V,
+        query: &[NotNan<T>],
+        internal_parameters: &InternalParameters<T>,
+    ) -> QueryStats {
+        if let Some(excluded) = internal_parameters.excluded {
+            if (excluded as usize) < self.len() {
+                let mut excluding = ExcludingCollector {
+                    inner: heap,
+                    excluded: Some(self.internalise_index(excluded)),
+                };
+                let internal_parameters = internal_parameters.without_excluded();
+                return self.knn_dispatch(&mut excluding, visitor, query, &internal_parameters);
+            }
+        }
+        self.knn_dispatch(heap, visitor, query, internal_parameters)
+    }
+
+    /// Same as [`Self::knn_internal_visited`], ignoring the excluded index of `internal_parameters`
+    fn knn_dispatch<H: CandidateCollector<T>, V: NodeVisitor<T>>(
+        &self,
+        heap: &mut H,
+        visitor: &mut V,
+        query: &[NotNan<T>],
+        internal_parameters: &InternalParameters<T>,
+    ) -> QueryStats {
+        // the check is hoisted out of the traversal, so that exact searches compare bounds directly
+        if internal_parameters.is_exact() {
+            self.knn_traverse::<H, V, true>(heap, visitor, query, internal_parameters)
+        } else {
+            self.knn_traverse::<H, V, false>(heap, visitor, query, internal_parameters)
+        }
+    }
+
+    /// Same as [`Self::knn_internal_visited`], applying no error factor to the bounds if `EXACT`
+    fn knn_traverse<H: CandidateCollector<T>, V: NodeVisitor<T>, const EXACT: bool>(
+        &self,
+        heap: &mut H,
+        visitor: &mut V,
+        query: &[NotNan<T>],
+        internal_parameters: &InternalParameters<T>,
+    ) -> QueryStats {
+        let mut off = DimBuffer::new(P::DIM as usize, NotNan::<T>::zero());
+        match internal_parameters.traversal_order {
+            TraversalOrder::NearChildFirst => {
+                if V::ENABLED {
+                    visitor.visit(0, NotNan::<T>::zero(), heap.furthest_dist2(), true);
+                }
+                #[cfg_attr(rustfmt, rustfmt_skip)]
+                self.recurse_knn::<_, _, _, EXACT>(
+                    query,
+                    0, NotNan::<T>::zero(),
+                    heap, visitor, &mut off, &mut (),
+                    internal_parameters,
+                )
+            }
+            TraversalOrder::BoundOrdered => {
+                let mut cell = NodeCell::of_root(self);
+                let rd = cell.dist2_range(query, self.scale).0;
+                let descend = rd <= internal_parameters.max_radius2;
+                if V::ENABLED {
+                    visitor.visit(0, rd, heap.furthest_dist2(), descend);
+                }
+                if !descend {
+                    return QueryStats::default();
+                }
+                #[cfg_attr(rustfmt, rustfmt_skip)]
+                self.recurse_knn::<_, _, _, EXACT>(
+                    query,
+                    0, NotNan::<T>::zero(),
+                    heap, visitor, &mut off, &mut cell,
+                    internal_parameters,
+                )
+            }
+            TraversalOrder::BestFirst => {
+                self.best_first_knn::<_, _, EXACT>(heap, visitor, query, internal_parameters)
+            }
+        }
+    }
+
+    /// Searches the subtree of `node` depth-first, near child first, `rd` being the squared distance
+    /// from `query` to the split planes bounding the node, along the dimensions of `off`,
+    /// and `bounds` possibly bounding the children more tightly, applying no error factor if `EXACT`
+    #[allow(clippy::too_many_arguments)]
+    fn recurse_knn<
+        H: CandidateCollector<T>,
+        V: NodeVisitor<T>,
+        B: ChildBounds<T>,
+        const EXACT: bool,
+    >(
+        &self,
+        query: &[NotNan<T>],
+        node: usize,
+        rd: NotNan<T>,
+        heap: &mut H,
+        visitor: &mut V,
+        off: &mut [NotNan<T>],
+        bounds: &mut B,
+        internal_parameters: &InternalParameters<T>,
+    ) -> QueryStats {
+        self.nodes[node].dispatch_on_type(
+            (heap, visitor, bounds),
+            |(heap, visitor, bounds), split_dim, split_val, right_child| {
+                // split node, see whether we have to recurse
+                let split_dim = split_dim as usize;
+                let old_off = off[split_dim];
+                let new_off = (query[split_dim] - split_val) * self.scale;
+                // points that drifted across the split can be closer than the split plane
+                let far_off = if self.scaled_drift == NotNan::<T>::zero() {
+                    new_off
+                } else if new_off > NotNan::<T>::zero() {
+                    clamp_min(new_off - self.scaled_drift, NotNan::<T>::zero())
+                } else {
+                    clamp_max(new_off + self.scaled_drift, NotNan::<T>::zero())
+                };
+                let near_is_left = new_off <= NotNan::<T>::zero();
+                let (near_child, far_child) = if near_is_left {
+                    (node + 1, right_child as usize)
+                } else {
+                    (right_child as usize, node + 1)
+                };
+                let max_radius2 = internal_parameters.max_radius2;
+                let parent = bounds.save(split_dim);
+                let mut stats = QueryStats::split();
+                // the query is on the side of the near child, so its planes are those of the node
+                let near_bound =
+                    bounds.enter(self, query, split_dim, parent, split_val, near_is_left, rd);
+                let bound2 = heap.furthest_dist2();
+                let descend = !B::PRUNES_NEAR
+                    || (near_bound <= max_radius2
+                        && internal_parameters.within_error_of::<EXACT>(near_bound, bound2));
+                if V::ENABLED {
+                    visitor.visit(near_child, near_bound, bound2, descend);
+                }
+                if descend {
+                    #[cfg_attr(rustfmt, rustfmt_skip)]
+                    let new_stats = self.recurse_knn::<_, _, _, EXACT>(
+                        query,
+                        near_child, rd,
+                        heap, visitor, off, bounds,
+                        internal_parameters,
+                    );
+                    stats += new_stats;
+                }
+                let far_rd = update_rd(rd, old_off, far_off);
+                let far_bound = bounds.enter(
+                    self,
+                    query,
+                    split_dim,
+                    parent,
+                    split_val,
+                    !near_is_left,
+                    far_rd,
+                );
+                let bound2 = heap.furthest_dist2();
+                let descend = far_bound <= max_radius2
+                    && internal_parameters.within_error_of::<EXACT>(far_bound, bound2);
+                if V::ENABLED {
+                    visitor.visit(far_child, far_bound, bound2, descend);
+                }
+                if descend {
+                    off[split_dim] = far_off;
+                    #[cfg_attr(rustfmt, rustfmt_skip)]
+                    let new_stats = self.recurse_knn::<_, _, _, EXACT>(
+                        query,
+                        far_child, far_rd,
+                        heap, visitor, off, bounds,
+                        internal_parameters,
+                    );
+                    stats += new_stats;
+                    off[split_dim] = old_off;
+                }
+                bounds.restore(split_dim, parent);
+                stats
+            },
+            |(heap, _, _), bucket_start_index, bucket_size| {
+                // leaf node, go through the buckets and check elements
+                let dim = P::DIM as usize;
+                let bucket_end_index = bucket_start_index + bucket_size;
+                let bucket = &self.points
+                    [bucket_start_index as usize * dim..bucket_end_index as usize * dim];
+                #[cfg_attr(rustfmt, rustfmt_skip)]
+                leaf_scan::scan_leaf::<T, P, H>(
+                    heap, query,
+                    bucket, bucket_start_index,
+                    self.scale, internal_parameters,
+                );
+                QueryStats::leaf(bucket_size)
+            },
+        )
+    }
+
+    fn build_nodes(
+        &mut self,
+        cloud: &[P],
+        mut rng: Option<&mut SplitMix64>,
+        cancellation: Option<&CancellationToken>,
+        duplicate_leaves: bool,
+    ) -> Result<(), BuildError> {
+        // build point vector and compute bounds
+        let mut build_points: Vec<_> = (0..cloud.len()).collect();
+        enum Branch {
+            Root,
+            Left(usize),
+            Right(usize),
+        }
+        let mut ranges: Vec<_> = vec![(0..cloud.len(), Branch::Root, 0u32)];
+        // splits of too many dimensions barely prune, so all points go into the root leaf
+        let max_depth = if self.options.high_dimension.is_flat(P::DIM) {
+            0
+        } else {
+            self.options.max_depth.unwrap_or(u32::MAX)
+        };
+
+        while let Some((build_points_range, parent_branch, depth)) = ranges.pop() {
+            let count = build_points_range.len() as u32;
+            if count >= CANCELLATION_CHECK_SPAN
+                && cancellation.map_or(false, CancellationToken::is_cancelled)
+            {
+                return Err(BuildError::Cancelled);
+            }
+            let pos = self.nodes.len();
+            if pos > P::MAX_NODE_COUNT as usize || count > P::MAX_NODE_COUNT {
+                return Err(BuildError::TooManyNodes {
+                    node_count: pos + 1,
+                    max_node_count: P::MAX_NODE_COUNT,
+                });
+            }
+
+            match parent_branch {
+                Branch::Root => (),
+                Branch::Left(parent) => debug_assert_eq!(pos, parent + 1),
+                Branch::Right(parent) => self.nodes[parent].set_child_index(pos as u32), // write the right child index of the parent node
+            }
+
+            // if remaining points fit in a single bucket, add a node and this bucket
+            if count <= self.options.bucket_size || depth >= max_depth {
+                self.push_leaf(cloud, &build_points[build_points_range]);
+            } else if depth >= BuildOptions::MAX_SAFE_DEPTH {
+                // splitting would put leaves deeper than queries can recurse
+                return Err(BuildError::TooDeep {
+                    max_depth: BuildOptions::MAX_SAFE_DEPTH,
+                });
+            } else {
+                // compute bounds
+                let (min_bounds, max_bounds) =
+                    Self::get_build_points_bounds(cloud, &build_points[build_points_range.clone()]);
+                if duplicate_leaves && min_bounds == max_bounds {
+                    // all points are identical, splitting them is useless
+                    self.push_leaf(cloud, &build_points[build_points_range]);
+                    continue;
+                }
+
+                // find the largest dimension of the box
+                let mut split_dim = Self::max_delta_index(&min_bounds, &max_bounds);
+                let mut split_dim_u = split_dim as usize;
+
+                // split along this dimension, halving before adding to avoid overflows
+                let half = T::one() / (T::one() + T::one());
+                let mut split_val = max_bounds[split_dim_u] * half + min_bounds[split_dim_u] * half;
+                if let Some(rng) = rng.as_deref_mut() {
+                    if max_bounds[split_dim_u] != min_bounds[split_dim_u] {
+                        // or along a random high-variance dimension, at the mean
+                        (split_dim, split_val) = Self::random_split(
+                            cloud,
+                            &build_points[build_points_range.clone()],
+                            &min_bounds,
+                            &max_bounds,
+                            rng,
+                        );
+                        split_dim_u = split_dim as usize;
+                    }
+                }
+                if split_val < min_bounds[split_dim_u] || split_val >= max_bounds[split_dim_u] {
+                    // the mid-point was rounded out of the bounds, split the minimum off instead
+                    split_val = min_bounds[split_dim_u];
+                }
+                let (left_points, right_points) = {
+                    let mid = if max_bounds[split_dim_u] == min_bounds[split_dim_u] {
+                        // degenerate data, split in half and iterate
+                        (build_points_range.start + build_points_range.end) / 2
+                    } else {
+                        // partition data around split_val on split_dim
+                        partition::partition_index(
+                            &mut build_points[build_points_range.clone()],
+                            |index| cloud[*index].get(split_dim) <= split_val,
+                        ) + build_points_range.start
+                    };
+                    (build_points_range.start..mid, mid..build_points_range.end)
+                };
+                self.nodes.push(Node::new_split_node(split_dim, split_val));
+                // if left_points.len() == 0 || right_points.len() == 0 {
+                //     println!("l; {};\t r: {}", left_points.len(), right_points.len());
+                //     println!(
+                //         "range {:?} (l {:?}; r {:?})",
+                //         build_points_range, left_points, right_points
+                //     );
+                //     println!("{:?} : {:?}", min_bounds, max_bounds);
+                //     println!("split {}: {:?}", split_dim, split_val);
+                // }
+
+                debug_assert_ne!(left_points.len(), 0);
+                debug_assert_ne!(right_points.len(), 0);
+
+                // recurse
+                ranges.push((right_points, Branch::Right(pos), depth + 1));
+                ranges.push((left_points, Branch::Left(pos), depth + 1)); // left side should be processed first -> has to be on top of the stack
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a leaf node holding `build_points`, and their coordinates and indices
+    fn push_leaf(&mut self, cloud: &[P], build_points: &[usize]) {
+        let bucket_start_index = self.indices.len() as u32;
+        self.points.reserve(build_points.len() * P::DIM as usize);
+        self.indices.reserve(build_points.len());
+        for point_index in build_points {
+            let point_index = *point_index;
+            self.indices.push(point_index as u32);
+            for i in 0..P::DIM {
+                self.points.push(cloud[point_index].get(i));
+            }
+        }
+        self.nodes.push(Node::new_leaf_node(
+            bucket_start_index,
+            build_points.len() as u32,
+        ));
+    }
+
+    fn get_build_points_bounds(
+        cloud: &[P],
+        build_points: &[usize],
+    ) -> (Vec<NotNan<T>>, Vec<NotNan<T>>) {
+        let mut min_bounds = vec![NotNan::<T>::max_value(); P::DIM as usize];
+        let mut max_bounds = vec![NotNan::<T>::min_value(); P::DIM as usize];
+        for p_index in build_points {
+            let p = &cloud[*p_index];
+            for index in 0..P::DIM {
+                let index_u = index as usize;
+                min_bounds[index_u] = clamp_max(p.get(index), min_bounds[index_u]);
+                max_bounds[index_u] = clamp_min(p.get(index), max_bounds[index_u]);
+            }
+        }
+        (min_bounds, max_bounds)
+    }
 
-            // if remaining points fit in a single bucket, add a node and this bucket
-            if count <= self.bucket_size {
-                let bucket_start_index = self.indices.len() as u32;
-                self.points.reserve(build_points.len() * P::DIM as usize);
-                self.indices.reserve(build_points.len());
-                for point_index in &build_points[build_points_range] {
-                    let point_index = *point_index;
-                    self.indices.push(point_index as u32);
-                    for i in 0..P::DIM {
-                        self.points.push(cloud[point_index].get(i));
-                    }
+    fn max_delta_index(lower_bound: &[NotNan<T>], upper_bound: &[NotNan<T>]) -> u32 {
+        lower_bound
+            .iter()
+            .zip(upper_bound.iter())
+            .enumerate()
+            .max_by_key(|(_, (l, u))| *u - *l)
+            .map_or(0, |(index, _)| index as u32)
+    }
+
+    /// Externalize local neighbour
+    pub fn externalise_neighbour(&self, neighbour: InternalNeighbour<T>) -> Neighbour<T, P> {
+        Neighbour {
+            point: self.stored_point(neighbour.index),
+            dist2: unscale_dist2(neighbour.dist2, self.scale),
+            index: self.indices[neighbour.index as usize],
+        }
+    }
+
+    /// Externalize local neighbour-index
+    ///
+    /// External indices are always the positions of the points in the slice
+    /// from which the tree was built.
+    /// Internal indices are the positions of the points in the storage of the tree,
+    /// they are an implementation detail but are stable for a given tree.
+    pub fn externalise_index(&self, index: u32) -> u32 {
+        self.indices[index as usize]
+    }
+
+    /// Internalize external index, the inverse of [`Self::externalise_index`]
+    ///
+    /// Panics if `external` is not smaller than the number of points in the tree.
+    pub fn internalise_index(&self, external: u32) -> u32 {
+        self.internal_indices[external as usize]
+    }
+
+    /// Returns the external index of every internal index, for bulk externalisation
+    ///
+    /// This is, for internal index `i`, `externalisation_map()[i] == externalise_index(i)`.
+    pub fn externalisation_map(&self) -> &[u32] {
+        &self.indices
+    }
+
+    /// Iterate over the indices and points in this KDTree.
+    /// The order is arbitrary;
+    /// the indices are the point's location in the slice
+    /// from which the tree was built.
+    pub fn iter_idx_points(&self) -> impl Iterator<Item = (u32, P)> + '_ {
+        self.indices.iter().cloned().zip(self.iter_points())
+    }
+
+    /// Iterate over the points in this KDTree in arbitrary order.
+    pub fn iter_points(&self) -> impl Iterator<Item = P> + '_ {
+        (0..self.len() as u32).map(move |internal_index| self.stored_point(internal_index))
+    }
+
+    /// Iterate over the indices and points in this KDTree, by increasing index.
+    ///
+    /// As the tree stores the coordinates and not the points themselves,
+    /// the points are reconstructed by value, from their coordinates as given if the tree is rotated.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, P)> + '_ {
+        self.internal_indices
+            .iter()
+            .enumerate()
+            .map(move |(external, internal)| (external as u32, self.stored_point(*internal)))
+    }
+
+    /// Reconstructs the point cloud from which this KDTree was built, in its original order.
+    pub fn points(&self) -> Vec<P> {
+        self.iter().map(|(_, point)| point).collect()
+    }
+
+    /// Returns the point stored at `internal_index`, as given even if the tree is rotated
+    fn stored_point(&self, internal_index: u32) -> P {
+        P::from_slice(self.original_coordinates(internal_index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use crate::*;
+    use float_cmp::approx_eq;
+    use rand::Rng;
+    use simple_point::{random_point, random_point_cloud, P2};
+    use std::{dbg, println};
+
+    // helpers to create cloud
+    fn cloud3() -> Vec<P2> {
+        vec![P2::new2d(0., 0.), P2::new2d(-1., 3.), P2::new2d(2., -4.)]
+    }
+
+    // helper to compute the square distance between two points
+    fn point_dist2<T: Scalar, P: Point<T>>(lhs: &P, rhs: &P) -> NotNan<T> {
+        let mut dist2 = NotNan::<T>::zero();
+        for index in 0..P::DIM {
+            let diff = lhs.get(index) - rhs.get(index);
+            dist2 += diff * diff;
+        }
+        dist2
+    }
+
+    // brute force search implementations
+    fn brute_force_1nn(cloud: &[P2], query: &P2) -> Neighbour<f32, P2> {
+        let mut best_dist2 = f32::infinity();
+        let mut best_index = 0;
+        for (index, point) in cloud.iter().enumerate() {
+            let dist2 = point_dist2(point, query).into_inner();
+            if dist2 < best_dist2 {
+                best_dist2 = dist2;
+                best_index = index;
+            }
+        }
+        Neighbour {
+            point: cloud[best_index],
+            dist2: NotNan::new(best_dist2).unwrap(),
+            index: best_index as u32,
+        }
+    }
+
+    fn brute_force_knn<H: CandidateHeap<f32> + CandidateCollector<f32>>(
+        cloud: &[P2],
+        query: &P2,
+        k: u32,
+    ) -> Vec<Neighbour<f32, P2>> {
+        let mut h = H::new_with_k(k);
+        for (index, point) in cloud.iter().enumerate() {
+            let dist2 = point_dist2(point, query);
+            h.add(dist2, index as u32);
+        }
+        h.into_sorted_vec()
+            .into_iter()
+            .map(|n| {
+                let index = n.index as usize;
+                Neighbour {
+                    point: cloud[index],
+                    dist2: n.dist2,
+                    index: n.index,
+                }
+            })
+            .collect()
+    }
+
+    // tests themselves
+
+    #[test]
+    fn get_build_points_bounds() {
+        let cloud = cloud3();
+        let indices = vec![0, 1, 2];
+        let bounds = KDTree::get_build_points_bounds(&cloud, &indices);
+        assert_eq!(bounds.0, vec![-1., -4.]);
+        assert_eq!(bounds.1, vec![2., 3.]);
+    }
+
+    #[test]
+    fn max_delta_index() {
+        let b = |x: f32, y: f32| {
+            [
+                NotNan::<f32>::new(x).unwrap(),
+                NotNan::<f32>::new(y).unwrap(),
+            ]
+        };
+        assert_eq!(
+            KDTree::<f32, P2>::max_delta_index(&b(0., 0.), &b(0., 1.)),
+            1
+        );
+        assert_eq!(
+            KDTree::<f32, P2>::max_delta_index(&b(0., 0.), &b(-1., 1.)),
+            1
+        );
+        assert_eq!(
+            KDTree::<f32, P2>::max_delta_index(&b(0., 0.), &b(-1., -2.)),
+            0
+        );
+    }
+
+    #[test]
+    fn new_tree() {
+        let cloud = cloud3();
+        let tree = KDTree::new_with_bucket_size(&cloud, 2);
+        dbg!(tree);
+    }
+
+    #[test]
+    fn exact_searches_match_brute_force() {
+        use internal_parameters::InternalParameters;
+        let mut rng = SplitMix64::new(189);
+        // integer coordinates lead to many ties between distances and bounds
+        let mut cloud: Vec<P2> = (0..3000)
+            .map(|_| P2::new2d(rng.below(60) as f32, rng.below(60) as f32))
+            .collect();
+        cloud.extend(random_point_cloud::<2>(1000));
+        let mut tree = KDTree::new(&cloud);
+        for (index, point) in cloud.iter_mut().enumerate().take(100) {
+            *point = P2::new2d(rng.below(60) as f32, rng.below(60) as f32);
+            tree.update_point(index as u32, *point);
+        }
+        for traversal_order in [
+            TraversalOrder::NearChildFirst,
+            TraversalOrder::BoundOrdered,
+            TraversalOrder::BestFirst,
+        ] {
+            let parameters = Parameters {
+                traversal_order,
+                ..Parameters::default()
+            };
+            assert!(InternalParameters::new(&parameters, tree.scale).is_exact());
+            for _ in 0..200 {
+                let query = match rng.below(2) {
+                    0 => P2::new2d(rng.below(60) as f32, rng.below(60) as f32),
+                    _ => random_point(),
+                };
+                for k in [1, 7, 40] {
+                    let found = tree.knn_advanced(
+                        k,
+                        &query,
+                        CandidateContainer::BinaryHeap,
+                        &parameters,
+                        None,
+                    );
+                    let expected = brute_force_knn::<BinaryHeap<_>>(&cloud, &query, k);
+                    let dist2 = |neighbours: &[Neighbour<f32, P2>]| -> Vec<_> {
+                        neighbours.iter().map(|n| n.dist2).collect()
+                    };
+                    assert_eq!(dist2(&found), dist2(&expected));
+                }
+            }
+        }
+        #[cfg(not(feature = "strict-exact"))]
+        {
+            let approximate = Parameters {
+                epsilon: 1e-3,
+                ..Parameters::default()
+            };
+            assert!(!InternalParameters::new(&approximate, tree.scale).is_exact());
+        }
+    }
+
+    #[test]
+    fn query_1nn_allow_self() {
+        let mut touch_sum = 0;
+        const PASS_COUNT: u32 = 20;
+        const QUERY_COUNT: u32 = 100;
+        const CLOUD_SIZE: u32 = 1000;
+        const PARAMETERS: Parameters<f32> = Parameters {
+            epsilon: 0.0,
+            max_radius: f32::INFINITY,
+            boundary: Boundary::Closed,
+            self_match: SelfMatch::Allow,
+            sort_results: true,
+            traversal_order: TraversalOrder::NearChildFirst,
+            on_insufficient: InsufficientPolicy::Truncate,
+        };
+        for _ in 0..PASS_COUNT {
+            let cloud = random_point_cloud(CLOUD_SIZE);
+            let tree = KDTree::new(&cloud);
+            for _ in 0..QUERY_COUNT {
+                let query = random_point();
+                let mut touch_statistics = 0;
+
+                // linear search
+                let nns_lin = tree.knn_advanced(
+                    1,
+                    &query,
+                    CandidateContainer::Linear,
+                    &PARAMETERS,
+                    Some(&mut touch_statistics),
+                );
+                assert_eq!(nns_lin.len(), 1);
+                let nn_lin = &nns_lin[0];
+                assert_eq!(nn_lin.point, cloud[nn_lin.index as usize]);
+                touch_sum += touch_statistics;
+                // binary
+                let nns_bin =
+                    tree.knn_advanced(1, &query, CandidateContainer::BinaryHeap, &PARAMETERS, None);
+                assert_eq!(nns_bin.len(), 1);
+                let nn_bin = &nns_bin[0];
+                assert_eq!(nn_bin.point, cloud[nn_bin.index as usize]);
+                // brute force
+                let nn_bf = brute_force_1nn(&cloud, &query);
+                assert_eq!(nn_bf.point, cloud[nn_bf.index as usize]);
+                // assertion
+                assert_eq!(
+                    nn_bin.index, nn_bf.index,
+                    "KDTree binary heap: mismatch indexes\nquery: {}\npoint {}, {}\nvs bf {}, {}",
+                    query, nn_bin.dist2, nn_bin.point, nn_bf.dist2, nn_bf.point
+                );
+                assert_eq!(nn_lin.index, nn_bf.index, "\nKDTree linear heap: mismatch indexes\nquery: {}\npoint {}, {}\nvs bf {}, {}\n", query, nn_lin.dist2, nn_lin.point, nn_bf.dist2, nn_bf.point);
+                assert!(approx_eq!(f32, *nn_lin.dist2, *nn_bf.dist2, ulps = 2));
+                assert!(approx_eq!(f32, *nn_bin.dist2, *nn_bf.dist2, ulps = 2));
+            }
+        }
+        let touch_pct = (touch_sum * 100) as f32 / (PASS_COUNT * QUERY_COUNT * CLOUD_SIZE) as f32;
+        println!("Average tree point touched: {} %", touch_pct);
+    }
+
+    #[test]
+    fn query_knn_allow_self() {
+        const QUERY_COUNT: u32 = 100;
+        const CLOUD_SIZE: u32 = 1000;
+        const PARAMETERS: Parameters<f32> = Parameters {
+            epsilon: 0.0,
+            max_radius: f32::INFINITY,
+            boundary: Boundary::Closed,
+            self_match: SelfMatch::Allow,
+            sort_results: true,
+            traversal_order: TraversalOrder::NearChildFirst,
+            on_insufficient: InsufficientPolicy::Truncate,
+        };
+        let cloud = random_point_cloud(CLOUD_SIZE);
+        let tree = KDTree::new(&cloud);
+        for k in [1, 2, 3, 5, 7, 13] {
+            for _ in 0..QUERY_COUNT {
+                let query = random_point();
+                // brute force
+                let nns_bf_lin = brute_force_knn::<Vec<InternalNeighbour<f32>>>(&cloud, &query, k);
+                assert_eq!(nns_bf_lin.len(), k as usize);
+                let nns_bf_bin =
+                    brute_force_knn::<BinaryHeap<InternalNeighbour<f32>>>(&cloud, &query, k);
+                assert_eq!(nns_bf_bin.len(), k as usize);
+                // kd-tree
+                #[cfg_attr(rustfmt, rustfmt_skip)]
+                let nns_bin = tree.knn_advanced(
+                    k, &query,
+                    CandidateContainer::BinaryHeap,
+                    &PARAMETERS,
+                    None,
+                );
+                assert_eq!(nns_bin.len(), k as usize);
+                #[cfg_attr(rustfmt, rustfmt_skip)]
+                let nns_lin = tree.knn_advanced(
+                    k, &query,
+                    CandidateContainer::Linear,
+                    &PARAMETERS,
+                    None,
+                );
+                assert_eq!(nns_lin.len(), k as usize);
+                // assertion
+                for i in 0..k as usize {
+                    // get neighbour
+                    let nn_bf_lin = &nns_bf_lin[i];
+                    let nn_bf_bin = &nns_bf_bin[i];
+                    let nn_lin = &nns_lin[i];
+                    let nn_bin = &nns_bin[i];
+                    // ensure their point data are consistent with the cloud
+                    assert_eq!(nn_bf_lin.point, cloud[nn_bf_lin.index as usize]);
+                    assert_eq!(nn_bf_bin.point, cloud[nn_bf_bin.index as usize]);
+                    assert_eq!(nn_lin.point, cloud[nn_lin.index as usize]);
+                    assert_eq!(nn_bin.point, cloud[nn_bin.index as usize]);
+                    // ensure their indices are consistent
+                    assert_eq!(nn_bf_bin.index, nn_bf_lin.index, "BF binary heap: mismatch indexes at {} on {}\nquery: {}\n   bf bin {}, {}\nvs bf lin {}, {}\n", i, k, query, nn_bf_bin.dist2, nn_bf_bin.point, nn_bf_lin.dist2, nn_bf_lin.point);
+                    assert_eq!(nn_lin.index, nn_bf_lin.index, "\nKDTree linear heap: mismatch indexes at {} on {}\nquery: {}\npoint {}, {}\nvs bf {}, {}\n", i, k, query, nn_lin.dist2, nn_lin.point, nn_bf_lin.dist2, nn_bf_lin.point);
+                    assert_eq!(nn_bin.index, nn_bf_lin.index, "\nKDTree binary heap: mismatch indexes {} on {}\nquery: {}\npoint {}, {}\nvs bf {}, {}\n", i, k, query, nn_bin.dist2, nn_bin.point, nn_bf_lin.dist2, nn_bf_lin.point);
+                    // ensure their dist2 are consistent
+                    assert!(approx_eq!(
+                        f32,
+                        *nn_bf_bin.dist2,
+                        *nn_bf_lin.dist2,
+                        ulps = 2
+                    ));
+                    assert!(approx_eq!(f32, *nn_lin.dist2, *nn_bf_lin.dist2, ulps = 2));
+                    assert!(approx_eq!(f32, *nn_bin.dist2, *nn_bf_lin.dist2, ulps = 2));
                 }
-                self.nodes
-                    .push(Node::new_leaf_node(bucket_start_index, count));
-            } else {
-                // compute bounds
-                let (min_bounds, max_bounds) =
-                    Self::get_build_points_bounds(cloud, &build_points[build_points_range.clone()]);
+            }
+        }
+    }
 
-                // find the largest dimension of the box
-                let split_dim = Self::max_delta_index(&min_bounds, &max_bounds);
-                let split_dim_u = split_dim as usize;
+    #[test]
+    fn small_clouds_can_lead_to_neighbours() {
+        let cloud = vec![P2::new2d(0.0, 0.0), P2::new2d(1.0, 0.0)];
+        let tree = KDTree::new(&cloud);
+        let query = P2::new2d(0.5, 0.0);
+        for _ in [CandidateContainer::Linear, CandidateContainer::BinaryHeap] {
+            let nns = tree.knn(3, &query);
+            assert_eq!(nns.len(), 2);
+        }
+    }
 
-                // split along this dimension
-                let split_val =
-                    (max_bounds[split_dim_u] + min_bounds[split_dim_u]) * T::from(0.5).unwrap();
-                let (left_points, right_points) = {
-                    let mid = if max_bounds[split_dim_u] == min_bounds[split_dim_u] {
-                        // degenerate data, split in half and iterate
-                        (build_points_range.start + build_points_range.end) / 2
-                    } else {
-                        // partition data around split_val on split_dim
-                        partition::partition_index(
-                            &mut build_points[build_points_range.clone()],
-                            |index| cloud[*index].get(split_dim) <= split_val,
-                        ) + build_points_range.start
-                    };
-                    (build_points_range.start..mid, mid..build_points_range.end)
-                };
-                self.nodes.push(Node::new_split_node(split_dim, split_val));
-                // if left_points.len() == 0 || right_points.len() == 0 {
-                //     println!("l; {};\t r: {}", left_points.len(), right_points.len());
-                //     println!(
-                //         "range {:?} (l {:?}; r {:?})",
-                //         build_points_range, left_points, right_points
-                //     );
-                //     println!("{:?} : {:?}", min_bounds, max_bounds);
-                //     println!("split {}: {:?}", split_dim, split_val);
-                // }
+    #[test]
+    fn max_radius_can_lead_to_neighbours() {
+        let cloud = vec![P2::new2d(0.0, 0.0), P2::new2d(1.0, 0.0)];
+        let tree = KDTree::new(&cloud);
+        let query = P2::new2d(0.1, 0.0);
+        let parameters = Parameters {
+            epsilon: 0.0,
+            max_radius: 0.5,
+            boundary: Boundary::Closed,
+            self_match: SelfMatch::ExcludeCoincident,
+            sort_results: false,
+            traversal_order: TraversalOrder::NearChildFirst,
+            on_insufficient: InsufficientPolicy::Truncate,
+        };
+        for container in [CandidateContainer::Linear, CandidateContainer::BinaryHeap] {
+            let nns = tree.knn_advanced(2, &query, container, &parameters, None);
+            assert_eq!(nns.len(), 1);
+        }
+    }
 
-                debug_assert_ne!(left_points.len(), 0);
-                debug_assert_ne!(right_points.len(), 0);
+    #[test]
+    fn max_radius_is_inclusive_for_all_collectors() {
+        // self at the query, one point exactly at the radius, one just beyond
+        let cloud = vec![
+            P2::new2d(0.0, 0.0),
+            P2::new2d(2.0, 0.0),
+            P2::new2d(0.0, 2.5),
+        ];
+        let tree = KDTree::new_with_bucket_size(&cloud, 2);
+        let query = P2::new2d(0.0, 0.0);
+        let parameters = |max_radius| Parameters {
+            epsilon: 0.0,
+            max_radius,
+            boundary: Boundary::Closed,
+            self_match: SelfMatch::Allow,
+            sort_results: true,
+            traversal_order: TraversalOrder::NearChildFirst,
+            on_insufficient: InsufficientPolicy::Truncate,
+        };
+        for (max_radius, expected) in [(2.0, vec![0, 1]), (0.0, vec![0]), (1e-30, vec![0])] {
+            let parameters = parameters(max_radius);
+            for container in [CandidateContainer::Linear, CandidateContainer::BinaryHeap] {
+                let nns = tree.knn_advanced(3, &query, container, &parameters, None);
+                let indices: Vec<_> = nns.iter().map(|n| n.index).collect();
+                assert_eq!(indices, expected);
+            }
+            let mut unbounded = UnboundedCollector::with_capacity(3);
+            tree.nn_raw(&query, &mut unbounded, &parameters, None);
+            let mut indices: Vec<_> = unbounded.externalise(&tree).collect();
+            indices.sort_unstable();
+            assert_eq!(indices, expected);
+            let mut single = InternalNeighbour::default();
+            tree.nn_raw(&query, &mut single, &parameters, None);
+            assert_eq!(tree.externalise_index(single.index), 0);
+        }
+    }
 
-                // recurse
-                ranges.push((right_points, Branch::Right(pos)));
-                ranges.push((left_points, Branch::Left(pos))); // left side should be processed first -> has to be on top of the stack
+    #[test]
+    fn unbounded_collector_is_only_limited_by_max_radius() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let tree = KDTree::new(&cloud);
+        let parameters = Parameters {
+            epsilon: 0.0,
+            max_radius: 30.0,
+            boundary: Boundary::Closed,
+            self_match: SelfMatch::Allow,
+            sort_results: false,
+            traversal_order: TraversalOrder::NearChildFirst,
+            on_insufficient: InsufficientPolicy::Truncate,
+        };
+        for _ in 0..20 {
+            let query = random_point();
+            let mut collector = UnboundedCollector::with_capacity(0);
+            tree.nn_raw(&query, &mut collector, &parameters, None);
+            let mut found: Vec<_> = collector.externalise(&tree).collect();
+            found.sort_unstable();
+            let expected: Vec<_> = (0..cloud.len() as u32)
+                .filter(|i| *point_dist2(&cloud[*i as usize], &query) <= 30.0 * 30.0)
+                .collect();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn boundary_selects_open_or_closed_balls_for_all_searches() {
+        // integer coordinates put many points at exactly the radius, whose square is exact
+        let cloud: Vec<P2> = (-6..=6)
+            .flat_map(|x| (-6..=6).map(move |y| P2::new2d(x as f32, y as f32)))
+            .collect();
+        let tree = KDTree::new_with_bucket_size(&cloud, 2);
+        let query = P2::new2d(1.0, 0.0);
+        let orders = [
+            TraversalOrder::NearChildFirst,
+            TraversalOrder::BoundOrdered,
+            TraversalOrder::BestFirst,
+        ];
+        for (boundary, radius) in [
+            (Boundary::Closed, 5.0),
+            (Boundary::Open, 5.0),
+            (Boundary::Open, 0.0),
+        ] {
+            let inside = |dist2: f32| match boundary {
+                Boundary::Closed => dist2 <= radius * radius,
+                Boundary::Open => dist2 < radius * radius,
+            };
+            let mut expected: Vec<u32> = (0..cloud.len() as u32)
+                .filter(|i| inside(*point_dist2(&cloud[*i as usize], &query)))
+                .collect();
+            expected.sort_unstable();
+            for traversal_order in orders {
+                let parameters = Parameters::builder()
+                    .max_radius(radius)
+                    .boundary(boundary)
+                    .traversal_order(traversal_order)
+                    .build()
+                    .unwrap();
+                for container in [CandidateContainer::Linear, CandidateContainer::BinaryHeap] {
+                    let nns = tree.knn_advanced(200, &query, container, &parameters, None);
+                    let mut indices: Vec<_> = nns.iter().map(|n| n.index).collect();
+                    indices.sort_unstable();
+                    assert_eq!(indices, expected, "{:?} {:?}", boundary, traversal_order);
+                }
+                let mut unbounded = UnboundedCollector::with_capacity(0);
+                tree.nn_raw(&query, &mut unbounded, &parameters, None);
+                let mut indices: Vec<_> = unbounded.externalise(&tree).collect();
+                indices.sort_unstable();
+                assert_eq!(indices, expected);
             }
+            // the points at exactly the radius complete the open ball as beyond it
+            let parameters = Parameters::builder()
+                .max_radius(radius)
+                .boundary(boundary)
+                .on_insufficient(InsufficientPolicy::IgnoreRadius)
+                .build()
+                .unwrap();
+            let k = expected.len() as u32 + 1;
+            let nns = tree.knn_advanced(k, &query, CandidateContainer::Linear, &parameters, None);
+            assert!(nns[..expected.len()]
+                .iter()
+                .all(|n| expected.contains(&n.index)));
+            assert!(!inside(*nns[expected.len()].dist2));
         }
     }
 
-    fn get_build_points_bounds(
-        cloud: &[P],
-        build_points: &[usize],
-    ) -> (Vec<NotNan<T>>, Vec<NotNan<T>>) {
-        let mut min_bounds = vec![NotNan::<T>::max_value(); P::DIM as usize];
-        let mut max_bounds = vec![NotNan::<T>::min_value(); P::DIM as usize];
-        for p_index in build_points {
-            let p = &cloud[*p_index];
-            for index in 0..P::DIM {
-                let index_u = index as usize;
-                min_bounds[index_u] = clamp_max(p.get(index), min_bounds[index_u]);
-                max_bounds[index_u] = clamp_min(p.get(index), max_bounds[index_u]);
+    #[test]
+    fn extreme_magnitudes_match_f64_oracle() {
+        let dist2_f64 = |a: &P2, b: &P2| {
+            (0..2)
+                .map(|i| {
+                    let diff = *a.0[i] as f64 - *b.0[i] as f64;
+                    diff * diff
+                })
+                .sum::<f64>()
+        };
+        for magnitude in [1e-42f32, 1e-32, 1e18, 1e36] {
+            let scaled = |p: P2| P2::new2d(*p.0[0] * magnitude, *p.0[1] * magnitude);
+            let cloud: Vec<_> = random_point_cloud(500).into_iter().map(scaled).collect();
+            let tree = KDTree::new(&cloud);
+            for _ in 0..50 {
+                let query = scaled(random_point());
+                let oracle = cloud
+                    .iter()
+                    .map(|p| dist2_f64(p, &query))
+                    .fold(f64::INFINITY, f64::min);
+                for nns in [
+                    tree.knn(1, &query),
+                    tree.knn(3, &query),
+                    tree.knn(20, &query),
+                ] {
+                    let nn = &nns[0];
+                    let found = dist2_f64(&cloud[nn.index as usize], &query);
+                    assert!(
+                        (found - oracle).abs() <= oracle * 1e-5,
+                        "magnitude {magnitude}: found {found} vs oracle {oracle}"
+                    );
+                    assert!(nns.iter().all(|n| n.dist2.is_finite()));
+                }
             }
         }
-        (min_bounds, max_bounds)
     }
 
-    fn max_delta_index(lower_bound: &[NotNan<T>], upper_bound: &[NotNan<T>]) -> u32 {
-        lower_bound
-            .iter()
-            .zip(upper_bound.iter())
-            .enumerate()
-            .max_by_key(|(_, (l, u))| *u - *l)
-            .unwrap()
-            .0 as u32
+    #[test]
+    fn introspection() {
+        let cloud = cloud3();
+        let tree = KDTree::new_with_bucket_size(&cloud, 2);
+        assert_eq!(tree.len(), 3);
+        assert!(!tree.is_empty());
+        assert_eq!(tree.dim(), 2);
+        assert_eq!(
+            tree.bounds(),
+            Some((P2::new2d(-1., -4.), P2::new2d(2., 3.)))
+        );
+        let empty = KDTree::<f32, P2>::new(&[]);
+        assert_eq!(empty.len(), 0);
+        assert!(empty.is_empty());
+        assert_eq!(empty.bounds(), None);
     }
 
-    /// Externalize local neighbour
-    pub fn externalise_neighbour(&self, neighbour: InternalNeighbour<T>) -> Neighbour<T, P> {
-        let mut point = P::default();
-        let base_index = neighbour.index * P::DIM;
-        for i in 0..P::DIM {
-            point.set(i, self.points[(base_index + i) as usize]);
+    #[test]
+    fn iterate_in_original_order() {
+        let cloud: Vec<P2> = random_point_cloud(100);
+        let tree = KDTree::new(&cloud);
+        let iterated: Vec<_> = tree.iter().collect();
+        assert_eq!(iterated.len(), cloud.len());
+        for (expected_index, (index, point)) in iterated.into_iter().enumerate() {
+            assert_eq!(index, expected_index as u32);
+            assert_eq!(point, cloud[expected_index]);
         }
-        Neighbour {
-            point,
-            dist2: neighbour.dist2,
-            index: self.indices[neighbour.index as usize],
+        assert_eq!(tree.points(), cloud);
+        assert!(KDTree::<f32, P2>::new(&[]).points().is_empty());
+    }
+
+    #[test]
+    fn clone_and_compare_trees() {
+        let cloud: Vec<P2> = random_point_cloud(100);
+        let tree = KDTree::new(&cloud);
+        let cloned = tree.clone();
+        assert_eq!(tree, cloned);
+        assert_eq!(tree, KDTree::new(&cloud));
+        assert_ne!(tree, KDTree::new_with_bucket_size(&cloud, 4));
+        assert_ne!(tree, KDTree::new(&cloud[1..]));
+    }
+
+    #[test]
+    fn no_debug_or_clone_needed_on_points() {
+        #[derive(Default)]
+        struct Opaque([NotNan<f32>; 2]);
+        impl Point<f32> for Opaque {
+            fn set(&mut self, i: u32, value: NotNan<f32>) {
+                self.0[i as usize] = value;
+            }
+            fn get(&self, i: u32) -> NotNan<f32> {
+                self.0[i as usize]
+            }
+            const DIM: u32 = 2;
         }
+        let cloud = vec![Opaque::default(), Opaque::default()];
+        let tree = KDTree::new(&cloud);
+        let cloned = tree.clone();
+        assert_eq!(tree, cloned);
+        assert!(!std::format!("{:?}", tree).is_empty());
     }
 
-    /// Externalize local neighbour-index
-    pub fn externalise_index(&self, index: u32) -> u32 {
-        self.indices[index as usize]
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn public_types_are_send_and_sync() {
+        use simple_point::P3;
+        assert_send_sync::<KDTree<f32, P3>>();
+        assert_send_sync::<Parameters<f32>>();
+        assert_send_sync::<Neighbour<f32, P3>>();
+        assert_send_sync::<UnboundedCollector<f32, P3>>();
+        assert_send_sync::<BoundedCollector<f32, P3>>();
+        assert_send_sync::<InternalNeighbour<f32>>();
+        assert_send_sync::<ConcurrentKDTree<f32, P3>>();
+        #[cfg(not(feature = "strict-exact"))]
+        assert_send_sync::<QuantizedKDTree<f32, P3>>();
+        assert_send_sync::<TreeGuard<f32, P3>>();
     }
 
-    /// Iterate over the indices and points in this KDTree.
-    /// The order is arbitrary;
-    /// the indices are the point's location in the slice
-    /// from which the tree was built.
-    pub fn iter_idx_points(&self) -> impl Iterator<Item = (u32, P)> + '_ {
-        self.indices.iter().cloned().zip(self.iter_points())
+    #[test]
+    fn query_from_several_threads() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let tree = KDTree::new(&cloud);
+        let queries: Vec<P2> = random_point_cloud(100);
+        let expected: Vec<_> = queries.iter().map(|q| tree.knn(3, q)).collect();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for (query, expected) in queries.iter().zip(&expected) {
+                        assert_eq!(&tree.knn(3, query), expected);
+                    }
+                });
+            }
+        });
     }
 
-    /// Iterate over the points in this KDTree in arbitrary order.
-    pub fn iter_points(&self) -> impl Iterator<Item = P> + '_ {
-        self.points
-            .as_slice()
-            .chunks(P::DIM as usize)
-            .map(P::from_slice)
+    #[test]
+    fn query_with_other_point_types() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let tree = KDTree::new(&cloud);
+        for _ in 0..20 {
+            let query: P2 = random_point();
+            let expected = tree.knn(5, &query);
+            let raw = [*query.0[0], *query.0[1]];
+            assert_eq!(tree.knn(5, &raw[..]), expected);
+            assert_eq!(tree.knn(5, &query.0[..]), expected);
+            let wide = [raw[0] as f64, raw[1] as f64];
+            let converted = Converted::new(&wide[..]);
+            assert_eq!(tree.knn(5, &converted), expected);
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    extern crate std;
-    use crate::*;
-    use float_cmp::approx_eq;
-    use simple_point::{random_point, random_point_cloud, P2};
-    use std::{dbg, println};
+    #[test]
+    #[should_panic(expected = "Query has 3 dimensions but the tree has 2")]
+    fn query_dimension_mismatch_panics() {
+        let tree = KDTree::new(&cloud3());
+        tree.knn(1, &[0.0f32, 0.0, 0.0][..]);
+    }
 
-    // helpers to create cloud
-    fn cloud3() -> Vec<P2> {
-        vec![P2::new2d(0., 0.), P2::new2d(-1., 3.), P2::new2d(2., -4.)]
+    #[test]
+    fn queries_of_runtime_dimension_are_checked() {
+        /// A query whose dimension is only known at run time
+        struct Dynamic(Vec<f32>);
+        impl QueryPoint<f32> for Dynamic {
+            fn dim(&self) -> u32 {
+                self.0.len() as u32
+            }
+            fn coord(&self, i: u32) -> f32 {
+                self.0[i as usize]
+            }
+        }
+        assert_eq!(<P2 as QueryPoint<f32>>::STATIC_DIM, Some(2));
+        assert_eq!(<Dynamic as QueryPoint<f32>>::STATIC_DIM, None);
+        let tree = KDTree::new(&cloud3());
+        assert_eq!(
+            tree.try_knn(1, &Dynamic(vec![0.0; 4])).unwrap_err(),
+            QueryError::DimensionMismatch {
+                query_dim: 4,
+                tree_dim: 2
+            }
+        );
+        let query = Dynamic(vec![1.0, 0.0]);
+        assert_eq!(
+            tree.try_knn(2, &query).unwrap(),
+            tree.knn(2, &[1.0f32, 0.0][..])
+        );
     }
 
-    // helper to compute the square distance between two points
-    fn point_dist2<T: Scalar, P: Point<T>>(lhs: &P, rhs: &P) -> NotNan<T> {
-        let mut dist2 = NotNan::<T>::zero();
-        for index in 0..P::DIM {
-            let diff = lhs.get(index) - rhs.get(index);
-            dist2 += diff * diff;
+    #[test]
+    fn query_stats() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let tree = KDTree::new(&cloud);
+        let parameters = Parameters::default();
+        for _ in 0..20 {
+            let query: P2 = random_point();
+            let mut touch_statistics = 0;
+            #[cfg_attr(rustfmt, rustfmt_skip)]
+            let expected = tree.knn_advanced(
+                4, &query,
+                CandidateContainer::Linear,
+                &parameters,
+                Some(&mut touch_statistics),
+            );
+            let (neighbours, stats) =
+                tree.knn_with_stats(4, &query, CandidateContainer::Linear, &parameters);
+            assert_eq!(neighbours, expected);
+            assert_eq!(stats.touched_points, touch_statistics);
+            assert!(stats.visited_leaves >= 1);
+            assert!(stats.visited_nodes > stats.visited_leaves);
+            assert!((stats.visited_nodes as usize) < tree.nodes.len());
         }
-        dist2
+        // a query for all points visits the whole tree
+        let (_, stats) = tree.knn_with_stats(
+            1000,
+            &random_point::<2>(),
+            CandidateContainer::BinaryHeap,
+            &parameters,
+        );
+        assert_eq!(stats.visited_nodes as usize, tree.nodes.len());
+        assert_eq!(stats.touched_points, 1000);
+        assert_eq!(stats.visited_leaves * 2, stats.visited_nodes + 1);
     }
 
-    // brute force search implementations
-    fn brute_force_1nn(cloud: &[P2], query: &P2) -> Neighbour<f32, P2> {
-        let mut best_dist2 = f32::infinity();
-        let mut best_index = 0;
-        for (index, point) in cloud.iter().enumerate() {
-            let dist2 = point_dist2(point, query).into_inner();
-            if dist2 < best_dist2 {
-                best_dist2 = dist2;
-                best_index = index;
+    #[test]
+    fn try_new_reports_errors() {
+        let cloud = cloud3();
+        assert_eq!(
+            KDTree::try_new_with_bucket_size(&cloud, 1).unwrap_err(),
+            BuildError::BucketSizeTooSmall(1)
+        );
+        let mut with_infinity = cloud.clone();
+        with_infinity[1].set(0, NotNan::infinite());
+        assert_eq!(
+            KDTree::try_new(&with_infinity).unwrap_err(),
+            BuildError::NonFiniteCoordinate { index: 1 }
+        );
+        assert_eq!(KDTree::try_new(&cloud).unwrap(), KDTree::new(&cloud));
+    }
+
+    #[test]
+    fn try_knn_reports_errors() {
+        let tree = KDTree::new(&cloud3());
+        assert_eq!(
+            tree.try_knn(1, &[0.0f32; 3][..]).unwrap_err(),
+            QueryError::DimensionMismatch {
+                query_dim: 3,
+                tree_dim: 2
+            }
+        );
+        assert_eq!(
+            tree.try_knn(1, &[0.0, f32::NAN][..]).unwrap_err(),
+            QueryError::NanCoordinate
+        );
+        let parameters = Parameters {
+            epsilon: -1.0,
+            ..Parameters::default()
+        };
+        assert_eq!(
+            tree.try_knn_advanced(
+                1,
+                &[0.0f32; 2][..],
+                CandidateContainer::Linear,
+                &parameters,
+                None
+            )
+            .unwrap_err(),
+            Error::Parameter(ParameterError::NegativeEpsilon)
+        );
+        let query = P2::new2d(0.0, 0.0);
+        assert_eq!(tree.try_knn(2, &query).unwrap(), tree.knn(2, &query));
+    }
+
+    #[test]
+    fn knn_with_zero_or_huge_k() {
+        let cloud: Vec<P2> = random_point_cloud(100);
+        let tree = KDTree::new(&cloud);
+        let query: P2 = random_point();
+        for candidate_container in [CandidateContainer::Linear, CandidateContainer::BinaryHeap] {
+            let parameters = Parameters::default();
+            #[cfg_attr(rustfmt, rustfmt_skip)]
+            let knn = |k| tree.knn_advanced(k, &query, candidate_container, &parameters, None);
+            assert!(knn(0).is_empty());
+            assert_eq!(knn(u32::MAX), knn(100));
+        }
+    }
+
+    /// Reinterprets random bits as floats, favouring special values
+    fn arbitrary_f32(rng: &mut impl Rng) -> f32 {
+        const SPECIAL: [f32; 10] = [
+            f32::NAN,
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+            f32::MAX,
+            f32::MIN,
+            f32::MIN_POSITIVE,
+            f32::EPSILON,
+            1e-45,
+            0.0,
+            -0.0,
+        ];
+        match rng.gen_range(0..4) {
+            0 => f32::from_bits(rng.gen()),
+            1 => SPECIAL[rng.gen_range(0..SPECIAL.len())],
+            _ => rng.gen_range(-4.0..4.0),
+        }
+    }
+
+    #[test]
+    fn try_api_does_not_panic_on_arbitrary_floats() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..500 {
+            let count = rng.gen_range(0..200);
+            let cloud: Vec<P2> = (0..count)
+                .filter_map(|_| {
+                    P2::from_raw(&[arbitrary_f32(&mut rng), arbitrary_f32(&mut rng)]).ok()
+                })
+                .collect();
+            let bucket_size = [0, 1, 2, 3, 8, u32::MAX][rng.gen_range(0..6)];
+            let tree = match KDTree::try_new_with_bucket_size(&cloud, bucket_size) {
+                Ok(tree) => tree,
+                Err(_) => continue,
+            };
+            for _ in 0..10 {
+                let dim = rng.gen_range(1..4);
+                let query: Vec<f32> = (0..dim).map(|_| arbitrary_f32(&mut rng)).collect();
+                let k = [0, 1, 3, 17, u32::MAX][rng.gen_range(0..5)];
+                let _ = tree.try_knn(k, &query[..]);
+                let parameters = Parameters {
+                    epsilon: arbitrary_f32(&mut rng),
+                    max_radius: arbitrary_f32(&mut rng),
+                    boundary: Boundary::Closed,
+                    self_match: rng.gen::<bool>().into(),
+                    sort_results: rng.gen(),
+                    traversal_order: TraversalOrder::NearChildFirst,
+                    on_insufficient: InsufficientPolicy::Truncate,
+                };
+                for candidate_container in
+                    [CandidateContainer::Linear, CandidateContainer::BinaryHeap]
+                {
+                    let _ = tree.try_knn_advanced(
+                        k,
+                        &query[..],
+                        candidate_container,
+                        &parameters,
+                        None,
+                    );
+                }
             }
         }
-        Neighbour {
-            point: cloud[best_index],
-            dist2: NotNan::new(best_dist2).unwrap(),
-            index: best_index as u32,
+    }
+
+    #[test]
+    fn self_match_excludes_only_what_it_names() {
+        let mut cloud: Vec<P2> = random_point_cloud(500);
+        cloud.push(cloud[7]);
+        let tree = KDTree::new(&cloud);
+        let query = cloud[7];
+        for candidate_container in [CandidateContainer::Linear, CandidateContainer::BinaryHeap] {
+            let indices = |self_match: SelfMatch| -> Vec<u32> {
+                let parameters = Parameters {
+                    self_match,
+                    ..Parameters::default()
+                };
+                tree.knn_advanced(3, &query, candidate_container, &parameters, None)
+                    .iter()
+                    .map(|n| n.index)
+                    .collect()
+            };
+            // an external query at a stored point finds it and its duplicate
+            let found = indices(SelfMatch::NotApplicable);
+            assert_eq!(found[..2], [7, 500]);
+            assert_eq!(indices(SelfMatch::Allow), found);
+            assert_eq!(indices(SelfMatch::ExcludeByIndex(7))[..2], [500, found[2]]);
+            assert_eq!(indices(SelfMatch::ExcludeByIndex(10_000)), found);
+            assert!(indices(false.into())
+                .iter()
+                .all(|index| *index != 7 && *index != 500));
+        }
+        assert_eq!(SelfMatch::from(true), SelfMatch::Allow);
+        let of = tree
+            .knn_of(
+                2,
+                7,
+                &Parameters::builder()
+                    .allow_self_match(false)
+                    .build()
+                    .expect("valid"),
+            )
+            .expect("in range");
+        assert_eq!(of[0].index, 500);
+    }
+
+    #[test]
+    fn internalise_index_is_inverse_of_externalise_index() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let tree = KDTree::new(&cloud);
+        let map = tree.externalisation_map();
+        assert_eq!(map.len(), cloud.len());
+        for external in 0..cloud.len() as u32 {
+            let internal = tree.internalise_index(external);
+            assert_eq!(tree.externalise_index(internal), external);
+            assert_eq!(map[internal as usize], external);
         }
     }
 
-    fn brute_force_knn<H: CandidateHeap<f32> + CandidateCollector<f32>>(
-        cloud: &[P2],
-        query: &P2,
-        k: u32,
-    ) -> Vec<Neighbour<f32, P2>> {
-        let mut h = H::new_with_k(k);
-        for (index, point) in cloud.iter().enumerate() {
-            let dist2 = point_dist2(point, query);
-            h.add(dist2, index as u32);
+    #[test]
+    fn knn_const_matches_knn() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let tree = KDTree::new(&cloud);
+        let parameters = Parameters::default();
+        for _ in 0..50 {
+            let query = random_point();
+            let expected = tree.knn(8, &query);
+            let (neighbours, count) = tree.knn_const::<8>(&query, &parameters);
+            assert_eq!(count, 8);
+            assert_eq!(&neighbours[..], &expected[..]);
         }
-        h.into_sorted_vec()
-            .into_iter()
-            .map(|n| {
-                let index = n.index as usize;
-                Neighbour {
-                    point: cloud[index],
-                    dist2: n.dist2,
-                    index: n.index,
-                }
-            })
-            .collect()
     }
 
-    // tests themselves
+    #[test]
+    fn knn_const_fills_missing_with_sentinel() {
+        let tree = KDTree::new(&cloud3());
+        let (neighbours, count) = tree.knn_const::<5>(&P2::new2d(0.0, 0.0), &Parameters::default());
+        assert_eq!(count, 3);
+        assert_eq!(neighbours[0].index, 0);
+        for sentinel in &neighbours[count..] {
+            assert_eq!(sentinel.index, u32::MAX);
+            assert_eq!(sentinel.dist2, f32::INFINITY);
+            assert_eq!(sentinel.point, P2::default());
+        }
+    }
 
     #[test]
-    fn get_build_points_bounds() {
-        let cloud = cloud3();
-        let indices = vec![0, 1, 2];
-        let bounds = KDTree::get_build_points_bounds(&cloud, &indices);
-        assert_eq!(bounds.0, vec![-1., -4.]);
-        assert_eq!(bounds.1, vec![2., 3.]);
+    fn knn_const_does_not_allocate() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let tree = KDTree::new(&cloud);
+        let query = random_point();
+        let parameters = Parameters::default();
+        let allocations = allocation_counter::count(|| {
+            tree.knn_const::<4>(&query, &parameters);
+        });
+        assert_eq!(allocations, 0);
+        assert!(allocation_counter::count(|| drop(tree.knn(4, &query))) > 0);
     }
 
     #[test]
-    fn max_delta_index() {
-        let b = |x: f32, y: f32| {
-            [
-                NotNan::<f32>::new(x).unwrap(),
-                NotNan::<f32>::new(y).unwrap(),
-            ]
+    fn neighbours_order_by_distance_then_index() {
+        use std::collections::{BTreeSet, HashSet};
+        let cloud: Vec<P2> = random_point_cloud(200);
+        let tree = KDTree::new(&cloud);
+        let (a, b): (P2, P2) = (random_point(), random_point());
+        // neighbours of two queries, merged and deduplicated by index and distance
+        let mut merged: BTreeSet<Neighbour<f32, P2>> = tree.knn(10, &a).into_iter().collect();
+        merged.extend(tree.knn(10, &b));
+        merged.extend(tree.knn(10, &b));
+        let merged: Vec<_> = merged.into_iter().collect();
+        let mut expected = tree.knn(10, &a);
+        expected.extend(tree.knn(10, &b));
+        expected.sort_by(|x, y| x.dist2.cmp(&y.dist2).then(x.index.cmp(&y.index)));
+        expected.dedup();
+        assert_eq!(merged, expected);
+        let hashed: HashSet<_> = tree.knn(10, &a).into_iter().collect();
+        assert_eq!(hashed.len(), 10);
+        // the point is not compared
+        let near = Neighbour::new(3, 1.0, P2::new2d(0.0, 0.0));
+        assert_eq!(near, Neighbour::new(3, 1.0, P2::new2d(5.0, 5.0)));
+        assert!(near < Neighbour::new(2, 2.0, P2::new2d(0.0, 0.0)));
+        assert!(near < Neighbour::new(4, 1.0, P2::new2d(0.0, 0.0)));
+    }
+
+    #[test]
+    fn knn_of_excludes_the_point_by_index() {
+        let mut cloud: Vec<P2> = random_point_cloud(1000);
+        cloud.push(cloud[3]);
+        let tree = KDTree::new(&cloud);
+        let parameters = Parameters {
+            self_match: SelfMatch::ExcludeCoincident,
+            ..Parameters::default()
         };
+        for index in [0, 3, 500] {
+            let neighbours = tree.knn_of(6, index, &parameters).unwrap();
+            let expected: Vec<_> = tree
+                .knn(7, &cloud[index as usize])
+                .into_iter()
+                .filter(|n| n.index != index)
+                .take(6)
+                .collect();
+            assert_eq!(neighbours.len(), 6);
+            assert_eq!(
+                neighbours.iter().map(|n| n.dist2).collect::<Vec<_>>(),
+                expected.iter().map(|n| n.dist2).collect::<Vec<_>>()
+            );
+            assert!(neighbours.iter().all(|n| n.index != index));
+        }
+        // the duplicate of point 3 is kept, at distance zero
+        let neighbours = tree.knn_of(1, 3, &parameters).unwrap();
+        assert_eq!(neighbours[0].index, 1000);
+        assert_eq!(*neighbours[0].dist2, 0.0);
         assert_eq!(
-            KDTree::<f32, P2>::max_delta_index(&b(0., 0.), &b(0., 1.)),
-            1
-        );
-        assert_eq!(
-            KDTree::<f32, P2>::max_delta_index(&b(0., 0.), &b(-1., 1.)),
-            1
+            tree.knn_of(4, 500, &Parameters::default()).unwrap(),
+            tree.knn(4, &cloud[500])
         );
         assert_eq!(
-            KDTree::<f32, P2>::max_delta_index(&b(0., 0.), &b(-1., -2.)),
-            0
+            tree.knn_of(4, 1001, &parameters),
+            Err(QueryError::IndexOutOfRange {
+                index: 1001,
+                len: 1001
+            })
         );
     }
 
     #[test]
-    fn new_tree() {
-        let cloud = cloud3();
-        let tree = KDTree::new_with_bucket_size(&cloud, 2);
-        dbg!(tree);
-    }
-
-    #[test]
-    fn query_1nn_allow_self() {
-        let mut touch_sum = 0;
-        const PASS_COUNT: u32 = 20;
-        const QUERY_COUNT: u32 = 100;
-        const CLOUD_SIZE: u32 = 1000;
-        const PARAMETERS: Parameters<f32> = Parameters {
-            epsilon: 0.0,
-            max_radius: f32::INFINITY,
-            allow_self_match: true,
-            sort_results: true,
-        };
-        for _ in 0..PASS_COUNT {
-            let cloud = random_point_cloud(CLOUD_SIZE);
-            let tree = KDTree::new(&cloud);
-            for _ in 0..QUERY_COUNT {
-                let query = random_point();
-                let mut touch_statistics = 0;
-
-                // linear search
-                let nns_lin = tree.knn_advanced(
-                    1,
-                    &query,
-                    CandidateContainer::Linear,
-                    &PARAMETERS,
-                    Some(&mut touch_statistics),
-                );
-                assert_eq!(nns_lin.len(), 1);
-                let nn_lin = &nns_lin[0];
-                assert_eq!(nn_lin.point, cloud[nn_lin.index as usize]);
-                touch_sum += touch_statistics;
-                // binary
-                let nns_bin =
-                    tree.knn_advanced(1, &query, CandidateContainer::BinaryHeap, &PARAMETERS, None);
-                assert_eq!(nns_bin.len(), 1);
-                let nn_bin = &nns_bin[0];
-                assert_eq!(nn_bin.point, cloud[nn_bin.index as usize]);
-                // brute force
-                let nn_bf = brute_force_1nn(&cloud, &query);
-                assert_eq!(nn_bf.point, cloud[nn_bf.index as usize]);
-                // assertion
+    fn knn_skip_matches_brute_force_window() {
+        let cloud: Vec<P2> = random_point_cloud(2000);
+        let tree = KDTree::new(&cloud);
+        for _ in 0..20 {
+            let query = random_point();
+            let all = brute_force_knn::<BinaryHeap<InternalNeighbour<f32>>>(&cloud, &query, 2000);
+            for (skip, k) in [(0, 5), (3, 10), (40, 7), (1995, 10), (3000, 2)] {
+                let window = tree.knn_skip(skip, k, &query, &Parameters::default());
+                let expected: Vec<_> = all.iter().skip(skip as usize).take(k as usize).collect();
+                assert_eq!(window.len(), expected.len());
+                for (neighbour, expected) in window.iter().zip(expected) {
+                    assert_eq!(neighbour.dist2, expected.dist2);
+                }
+                let unsorted = Parameters {
+                    sort_results: false,
+                    ..Parameters::default()
+                };
+                let mut window_unsorted = tree.knn_skip(skip, k, &query, &unsorted);
+                window_unsorted.sort_by_key(|n| n.dist2);
                 assert_eq!(
-                    nn_bin.index, nn_bf.index,
-                    "KDTree binary heap: mismatch indexes\nquery: {}\npoint {}, {}\nvs bf {}, {}",
-                    query, nn_bin.dist2, nn_bin.point, nn_bf.dist2, nn_bf.point
+                    window_unsorted.iter().map(|n| n.dist2).collect::<Vec<_>>(),
+                    window.iter().map(|n| n.dist2).collect::<Vec<_>>()
                 );
-                assert_eq!(nn_lin.index, nn_bf.index, "\nKDTree linear heap: mismatch indexes\nquery: {}\npoint {}, {}\nvs bf {}, {}\n", query, nn_lin.dist2, nn_lin.point, nn_bf.dist2, nn_bf.point);
-                assert!(approx_eq!(f32, *nn_lin.dist2, *nn_bf.dist2, ulps = 2));
-                assert!(approx_eq!(f32, *nn_bin.dist2, *nn_bf.dist2, ulps = 2));
             }
         }
-        let touch_pct = (touch_sum * 100) as f32 / (PASS_COUNT * QUERY_COUNT * CLOUD_SIZE) as f32;
-        println!("Average tree point touched: {} %", touch_pct);
     }
 
     #[test]
-    fn query_knn_allow_self() {
-        const QUERY_COUNT: u32 = 100;
-        const CLOUD_SIZE: u32 = 1000;
-        const PARAMETERS: Parameters<f32> = Parameters {
-            epsilon: 0.0,
-            max_radius: f32::INFINITY,
-            allow_self_match: true,
-            sort_results: true,
-        };
-        let cloud = random_point_cloud(CLOUD_SIZE);
+    fn knn_within_with_caps_radius_searches() {
+        let cloud: Vec<P2> = random_point_cloud(2000);
         let tree = KDTree::new(&cloud);
-        for k in [1, 2, 3, 5, 7, 13] {
-            for _ in 0..QUERY_COUNT {
-                let query = random_point();
-                // brute force
-                let nns_bf_lin = brute_force_knn::<Vec<InternalNeighbour<f32>>>(&cloud, &query, k);
-                assert_eq!(nns_bf_lin.len(), k as usize);
-                let nns_bf_bin =
-                    brute_force_knn::<BinaryHeap<InternalNeighbour<f32>>>(&cloud, &query, k);
-                assert_eq!(nns_bf_bin.len(), k as usize);
-                // kd-tree
-                #[cfg_attr(rustfmt, rustfmt_skip)]
-                let nns_bin = tree.knn_advanced(
-                    k, &query,
-                    CandidateContainer::BinaryHeap,
-                    &PARAMETERS,
-                    None,
-                );
-                assert_eq!(nns_bin.len(), k as usize);
-                #[cfg_attr(rustfmt, rustfmt_skip)]
-                let nns_lin = tree.knn_advanced(
-                    k, &query,
-                    CandidateContainer::Linear,
-                    &PARAMETERS,
-                    None,
-                );
-                assert_eq!(nns_lin.len(), k as usize);
-                // assertion
-                for i in 0..k as usize {
-                    // get neighbour
-                    let nn_bf_lin = &nns_bf_lin[i];
-                    let nn_bf_bin = &nns_bf_bin[i];
-                    let nn_lin = &nns_lin[i];
-                    let nn_bin = &nns_bin[i];
-                    // ensure their point data are consistent with the cloud
-                    assert_eq!(nn_bf_lin.point, cloud[nn_bf_lin.index as usize]);
-                    assert_eq!(nn_bf_bin.point, cloud[nn_bf_bin.index as usize]);
-                    assert_eq!(nn_lin.point, cloud[nn_lin.index as usize]);
-                    assert_eq!(nn_bin.point, cloud[nn_bin.index as usize]);
-                    // ensure their indices are consistent
-                    assert_eq!(nn_bf_bin.index, nn_bf_lin.index, "BF binary heap: mismatch indexes at {} on {}\nquery: {}\n   bf bin {}, {}\nvs bf lin {}, {}\n", i, k, query, nn_bf_bin.dist2, nn_bf_bin.point, nn_bf_lin.dist2, nn_bf_lin.point);
-                    assert_eq!(nn_lin.index, nn_bf_lin.index, "\nKDTree linear heap: mismatch indexes at {} on {}\nquery: {}\npoint {}, {}\nvs bf {}, {}\n", i, k, query, nn_lin.dist2, nn_lin.point, nn_bf_lin.dist2, nn_bf_lin.point);
-                    assert_eq!(nn_bin.index, nn_bf_lin.index, "\nKDTree binary heap: mismatch indexes {} on {}\nquery: {}\npoint {}, {}\nvs bf {}, {}\n", i, k, query, nn_bin.dist2, nn_bin.point, nn_bf_lin.dist2, nn_bf_lin.point);
-                    // ensure their dist2 are consistent
-                    assert!(approx_eq!(
-                        f32,
-                        *nn_bf_bin.dist2,
-                        *nn_bf_lin.dist2,
-                        ulps = 2
-                    ));
-                    assert!(approx_eq!(f32, *nn_lin.dist2, *nn_bf_lin.dist2, ulps = 2));
-                    assert!(approx_eq!(f32, *nn_bin.dist2, *nn_bf_lin.dist2, ulps = 2));
+        for _ in 0..20 {
+            let query = random_point();
+            let all = brute_force_knn::<BinaryHeap<InternalNeighbour<f32>>>(&cloud, &query, 2000);
+            for (k, radius) in [
+                (0, 10.0),
+                (1, 0.0),
+                (5, 3.0),
+                (20, 10.0),
+                (100, 8.0),
+                (10, 1000.0),
+            ] {
+                let expected: Vec<_> = all
+                    .iter()
+                    .filter(|n| *n.dist2 <= radius * radius)
+                    .take(k as usize)
+                    .map(|n| n.dist2)
+                    .collect();
+                for parameters in [
+                    Parameters::default(),
+                    Parameters {
+                        sort_results: false,
+                        ..Parameters::default()
+                    },
+                ] {
+                    let mut found =
+                        tree.knn_within_with(k, &query, Radius::new(radius), &parameters);
+                    found.sort_by_key(|n| n.dist2);
+                    assert_eq!(found.iter().map(|n| n.dist2).collect::<Vec<_>>(), expected);
                 }
+                // the smallest of both radii applies
+                let larger_max_radius = Parameters {
+                    max_radius: 2.0 * radius,
+                    ..Parameters::default()
+                };
+                let found =
+                    tree.knn_within_with(k, &query, Radius::new(radius), &larger_max_radius);
+                assert_eq!(found.len(), expected.len());
+                let smaller_max_radius = Parameters {
+                    max_radius: radius,
+                    ..Parameters::default()
+                };
+                let found =
+                    tree.knn_within_with(k, &query, Radius::new(2.0 * radius), &smaller_max_radius);
+                assert_eq!(found.len(), expected.len());
             }
         }
+        // the nearest point beyond the radius
+        let far = P2::new2d(1e6, 1e6);
+        assert!(tree
+            .knn_within_with(5, &far, Radius::new(1.0), &Parameters::default())
+            .is_empty());
     }
 
     #[test]
-    fn small_clouds_can_lead_to_neighbours() {
-        let cloud = vec![P2::new2d(0.0, 0.0), P2::new2d(1.0, 0.0)];
+    #[cfg(not(feature = "strict-exact"))]
+    fn knn_eps_and_prepared_parameters() {
+        let cloud: Vec<P2> = random_point_cloud(2000);
         let tree = KDTree::new(&cloud);
-        let query = P2::new2d(0.5, 0.0);
-        for _ in [CandidateContainer::Linear, CandidateContainer::BinaryHeap] {
-            let nns = tree.knn(3, &query);
-            assert_eq!(nns.len(), 2);
+        let parameters = Parameters {
+            epsilon: 0.5,
+            ..Parameters::default()
+        };
+        let prepared = tree.prepare_parameters(&parameters);
+        for _ in 0..20 {
+            let query = random_point();
+            let expected =
+                tree.knn_advanced(5, &query, CandidateContainer::Linear, &parameters, None);
+            assert_eq!(tree.knn_eps(5, &query, 0.5), expected);
+            assert_eq!(tree.knn_prepared(5, &query, &prepared), expected);
+            let (found, stats) =
+                tree.knn_with_stats_prepared(5, &query, CandidateContainer::BinaryHeap, &prepared);
+            assert_eq!(found, expected);
+            let mut heap = BinaryHeap::<InternalNeighbour<f32>>::new_with_k(5);
+            assert_eq!(tree.nn_raw_prepared(&query, &mut heap, &prepared), stats);
+            assert_eq!(heap.len(), expected.len());
+        }
+        // prepared parameters are stored alongside per-thread contexts
+        fn assert_copy_send_sync<T: Copy + Send + Sync>(_: &T) {}
+        assert_copy_send_sync(&prepared);
+        assert_eq!(
+            tree.try_prepare_parameters(&Parameters {
+                epsilon: -1.0,
+                ..Parameters::default()
+            }),
+            Err(ParameterError::NegativeEpsilon)
+        );
+        assert_eq!(tree.try_prepare_parameters(&parameters), Ok(prepared));
+        // an infinite epsilon does not backtrack once enough candidates are found
+        let greedy = Parameters::builder()
+            .epsilon(f32::INFINITY)
+            .build()
+            .unwrap();
+        let exact = tree.knn(5, &P2::new2d(0.0, 0.0));
+        let (neighbours, stats) =
+            tree.knn_with_stats(5, &P2::new2d(0.0, 0.0), CandidateContainer::Linear, &greedy);
+        let (_, exact_stats) = tree.knn_with_stats(
+            5,
+            &P2::new2d(0.0, 0.0),
+            CandidateContainer::Linear,
+            &Parameters::default(),
+        );
+        assert_eq!(neighbours.len(), 5);
+        assert!(neighbours[4].dist2 >= exact[4].dist2);
+        assert!(stats.visited_nodes <= exact_stats.visited_nodes);
+        assert_eq!(
+            tree.knn_eps(3000, &P2::new2d(0.0, 0.0), f32::INFINITY)
+                .len(),
+            2000
+        );
+    }
+
+    /// Hashes `words` with FNV-1a, independently of the platform
+    fn fnv1a_hash(words: &[u32]) -> u64 {
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        for byte in words.iter().flat_map(|word| word.to_le_bytes()) {
+            hash = (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+        hash
+    }
+
+    /// Hashes the structure of `tree`
+    fn structure_hash(tree: &KDTree<f32, simple_point::P3>) -> u64 {
+        let flat = tree.export_flat();
+        let mut words = vec![flat.dim, flat.bucket_size];
+        for node in &flat.nodes {
+            words.extend([
+                node.split_dim,
+                node.first,
+                node.second,
+                node.split_val.to_bits(),
+            ]);
+        }
+        words.extend(flat.points.iter().map(|value| value.to_bits()));
+        words.extend(&flat.indices);
+        fnv1a_hash(&words)
+    }
+
+    /// A fixed cloud, with duplicates, independent of the random generator of the tests
+    fn golden_cloud() -> Vec<simple_point::P3> {
+        let mut rng = crate::rng::SplitMix64::new(42);
+        (0..3000)
+            .map(|i| {
+                let mut coordinate = || (rng.below(1 << 20) as f32 - (1 << 19) as f32) / 1024.0;
+                let point = P2::new3d(coordinate(), coordinate(), coordinate());
+                if i % 10 == 0 {
+                    P2::new3d(1.0, 2.0, 3.0)
+                } else {
+                    point
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tree_structure_is_reproducible() {
+        let cloud = golden_cloud();
+        let seeded = |seed| BuildOptions {
+            seed: Some(seed),
+            ..BuildOptions::default()
+        };
+        #[rustfmt::skip]
+        let golden = [
+            (BuildOptions::default(), 0x709064c365007435),
+            (BuildOptions { morton_presort: true, ..BuildOptions::default() }, 0x8fdbd38172cd7381),
+            (BuildOptions { duplicate_leaves: true, bucket_size: 4, ..BuildOptions::default() }, 0x6a41010272a31e55),
+            (seeded(7), 0xa767448f4a58b36c),
+            (seeded(8), 0xc73a74a5c80b3130),
+        ];
+        // a change of these hashes changes the trees built from existing data, it must be deliberate
+        for (options, hash) in golden {
+            let tree = KDTree::new_with_options(&cloud, &options);
+            assert_eq!(structure_hash(&tree), hash, "{:?}", options);
+            assert_eq!(tree.build_options(), options);
         }
+        assert_eq!(
+            structure_hash(&KDTree::new_with_options(&cloud, &seeded(7))),
+            structure_hash(&KDTree::new_with_options(&cloud, &seeded(7)))
+        );
     }
 
     #[test]
-    fn max_radius_can_lead_to_neighbours() {
-        let cloud = vec![P2::new2d(0.0, 0.0), P2::new2d(1.0, 0.0)];
+    #[cfg(not(feature = "strict-exact"))]
+    fn query_results_are_bit_identical() {
+        let cloud = golden_cloud();
         let tree = KDTree::new(&cloud);
-        let query = P2::new2d(0.1, 0.0);
-        let parameters = Parameters {
-            epsilon: 0.0,
-            max_radius: 0.5,
-            allow_self_match: false,
-            sort_results: false,
+        let mut words = Vec::new();
+        for (k, epsilon) in [(1, 0.0), (10, 0.0), (10, 0.5), (40, 0.0)] {
+            let parameters = Parameters {
+                epsilon,
+                ..Parameters::default()
+            };
+            for query in &cloud[..200] {
+                let query = P2::new3d(
+                    *query.get(0) * 0.75,
+                    *query.get(1) * 0.75,
+                    *query.get(2) * 0.75,
+                );
+                let found =
+                    tree.knn_advanced(k, &query, CandidateContainer::BinaryHeap, &parameters, None);
+                words.extend(found.iter().flat_map(|n| [n.index, n.dist2.to_bits()]));
+            }
+        }
+        let covariance_words: Vec<_> = tree
+            .knn_covariances(12, &Parameters::default())
+            .iter()
+            .flatten()
+            .map(|value| value.to_bits())
+            .collect();
+        // changing these hashes changes query results bitwise, which must be deliberate
+        assert_eq!(fnv1a_hash(&words), 0x2b7fe760075fd3e3);
+        assert_eq!(fnv1a_hash(&covariance_words), 0x93a892eaa67824d6);
+    }
+
+    #[test]
+    fn build_options_are_validated_and_recorded() {
+        let cloud: Vec<P2> = random_point_cloud(100);
+        let options = BuildOptions {
+            bucket_size: 4,
+            morton_presort: true,
+            ..BuildOptions::default()
         };
-        for container in [CandidateContainer::Linear, CandidateContainer::BinaryHeap] {
-            let nns = tree.knn_advanced(2, &query, container, &parameters, None);
-            assert_eq!(nns.len(), 1);
+        assert_eq!(options.validate(), Ok(()));
+        let tree = KDTree::new_with_options(&cloud, &options);
+        assert_eq!(tree.build_options(), options);
+        assert_eq!(KDTree::new(&cloud).build_options(), BuildOptions::default());
+        let imported: KDTree<f32, P2> = tree.export_flat().import().unwrap();
+        assert_eq!(
+            imported.build_options(),
+            BuildOptions {
+                bucket_size: 4,
+                ..BuildOptions::default()
+            }
+        );
+        let invalid = BuildOptions {
+            bucket_size: 1,
+            ..BuildOptions::default()
+        };
+        assert_eq!(invalid.validate(), Err(BuildError::BucketSizeTooSmall(1)));
+    }
+
+    #[test]
+    fn duplicate_leaves_keep_trees_of_duplicates_shallow() {
+        let positions: Vec<P2> = random_point_cloud(10);
+        let cloud: Vec<P2> = (0..1_000_000).map(|i| positions[i % 10]).collect();
+        let options = BuildOptions {
+            duplicate_leaves: true,
+            ..BuildOptions::default()
+        };
+        let tree = KDTree::new_with_options(&cloud, &options);
+        // one leaf per position
+        assert_eq!(tree.node_count(), 19);
+        assert_eq!(tree.len(), cloud.len());
+        for _ in 0..10 {
+            let query: P2 = random_point();
+            let neighbours = tree.knn(3, &query);
+            let dist2 = |p: &P2| {
+                let (dx, dy) = (*p.get(0) - *query.get(0), *p.get(1) - *query.get(1));
+                dx * dx + dy * dy
+            };
+            let nearest = positions
+                .iter()
+                .min_by(|a, b| dist2(a).partial_cmp(&dist2(b)).unwrap())
+                .unwrap();
+            assert_eq!(neighbours.len(), 3);
+            for neighbour in neighbours {
+                assert_eq!(neighbour.point, *nearest);
+                assert_eq!(cloud[neighbour.index as usize], *nearest);
+            }
+        }
+        let small: Vec<P2> = cloud[..1000].to_vec();
+        assert!(
+            KDTree::new_with_options(&small, &options).node_count()
+                < KDTree::new(&small).node_count()
+        );
+    }
+}
+
+/// Counts heap allocations performed by the current thread, for tests
+#[cfg(test)]
+mod allocation_counter {
+    extern crate std;
+    use core::cell::Cell;
+    use std::alloc::{GlobalAlloc, Layout, System};
+
+    std::thread_local! {
+        static COUNT: Cell<usize> = const { Cell::new(0) };
+    }
+
+    struct CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = COUNT.try_with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
         }
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Returns the number of allocations performed while running `f`
+    pub(crate) fn count(f: impl FnOnce()) -> usize {
+        let before = COUNT.with(|count| count.get());
+        f();
+        COUNT.with(|count| count.get()) - before
     }
 }
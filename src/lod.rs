@@ -0,0 +1,358 @@
+//! Level-of-detail nearest neighbours, where far subtrees are represented by their centroid
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+
+use num_traits::Zero;
+use ordered_float::NotNan;
+
+use crate::aggregate::subtree_sums_stride;
+use crate::cell::NodeCell;
+use crate::distance_scale::{saturate, unscale_dist2};
+use crate::internal_parameters::InternalParameters;
+use crate::leaf_scan::scan_leaf;
+use crate::{point_slice_dist2, CandidateCollector, KDTree, Neighbour, Parameters, Point, Scalar};
+
+/// When [`KDTree::knn_lod`] represents a subtree by its centroid instead of descending into it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LodPolicy<T: Scalar> {
+    /// largest ratio of the diagonal of the cell of a subtree to its distance from the query,
+    /// the opening angle of Barnes-Hut, for which the subtree is represented
+    ///
+    /// Zero descends into all subtrees, so that the results are exact.
+    pub max_extent_ratio: T,
+}
+
+impl<T: Scalar> Default for LodPolicy<T> {
+    /// Represents subtrees whose cell is seen from the query under a ratio of one half.
+    fn default() -> Self {
+        LodPolicy {
+            max_extent_ratio: T::from(0.5).expect("one half is representable"),
+        }
+    }
+}
+
+/// A result of [`KDTree::knn_lod`], either a point of the tree or the representative of a subtree
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LodNeighbour<T: Scalar, P: Point<T>> {
+    /// a point of the tree
+    Point(Neighbour<T, P>),
+    /// the centroid of the points of a subtree, standing for all of them
+    Representative {
+        /// the centroid of the points
+        centroid: P,
+        /// the squared distance to the centroid
+        dist2: NotNan<T>,
+        /// the number of points represented
+        count: u32,
+    },
+}
+
+impl<T: Scalar, P: Point<T>> LodNeighbour<T, P> {
+    /// Returns the squared distance to the point or to the centroid.
+    pub fn dist2(&self) -> NotNan<T> {
+        match self {
+            LodNeighbour::Point(neighbour) => neighbour.dist2,
+            LodNeighbour::Representative { dist2, .. } => *dist2,
+        }
+    }
+
+    /// Returns the number of points this result stands for, one for a point.
+    pub fn count(&self) -> u32 {
+        match self {
+            LodNeighbour::Point(_) => 1,
+            LodNeighbour::Representative { count, .. } => *count,
+        }
+    }
+}
+
+/// A candidate of a level-of-detail query, by internal index or node
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum LodCandidate {
+    Point(u32),
+    Subtree(u32),
+}
+
+/// Keeps the `k` nearest points and representatives
+struct LodCollector<T: Scalar> {
+    k: usize,
+    candidates: BinaryHeap<(NotNan<T>, LodCandidate)>,
+}
+
+impl<T: Scalar> LodCollector<T> {
+    fn offer(&mut self, dist2: NotNan<T>, candidate: LodCandidate) {
+        if self.candidates.len() < self.k {
+            self.candidates.push((dist2, candidate));
+        } else if let Some(mut furthest) = self.candidates.peek_mut() {
+            if dist2 < furthest.0 {
+                *furthest = (dist2, candidate);
+            }
+        }
+    }
+}
+
+impl<T: Scalar> CandidateCollector<T> for LodCollector<T> {
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        self.offer(dist2, LodCandidate::Point(index));
+    }
+
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        if self.candidates.len() < self.k {
+            None
+        } else {
+            self.candidates.peek().map(|(dist2, _)| *dist2)
+        }
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Finds the `k` nearest points of `query`, where subtrees far enough from it count as the single
+    /// centroid of their points, with user-provided parameters.
+    ///
+    /// A subtree of more than one point is represented when the diagonal of its cell is below
+    /// `lod.max_extent_ratio` times the distance from `query` to the cell,
+    /// as the Barnes-Hut approximation does for forces.
+    /// Its representative is a single result, which stands for [`LodNeighbour::count`] points,
+    /// at the distance of the centroid, so that fewer than `k` results are returned
+    /// when far subtrees are coarse enough.
+    /// Results are sorted by distance if `parameters.sort_results` is true.
+    ///
+    /// Centroids come from the sums of the subtrees, so if the tree was not built with
+    /// [`BuildOptions::subtree_sums`](crate::BuildOptions::subtree_sums), no subtree is represented
+    /// and the results are those of [`Self::knn_advanced`].
+    ///
+    /// Panics if `query` has NaN coordinates, or if `lod.max_extent_ratio` is negative or NaN.
+    pub fn knn_lod(
+        &self,
+        k: u32,
+        query: &P,
+        parameters: &Parameters<T>,
+        lod: LodPolicy<T>,
+    ) -> Vec<LodNeighbour<T, P>> {
+        let ratio = NotNan::new(lod.max_extent_ratio)
+            .ok()
+            .filter(|ratio| *ratio >= NotNan::zero())
+            .expect("max_extent_ratio must be positive or zero");
+        let query = self.query_coordinates(query);
+        let internal_parameters = InternalParameters::new(parameters, self.scale);
+        let mut collector = LodCollector {
+            k: k as usize,
+            candidates: BinaryHeap::with_capacity(k as usize),
+        };
+        if k > 0 && !self.is_empty() {
+            let mut cell = NodeCell::of_root(self);
+            let ratio2 = saturate(ratio * ratio);
+            self.recurse_lod(
+                &query,
+                0,
+                &mut cell,
+                &mut collector,
+                &internal_parameters,
+                ratio2,
+            );
+        }
+        let mut candidates = collector.candidates.into_vec();
+        if parameters.sort_results {
+            candidates.sort_unstable();
+        }
+        candidates
+            .into_iter()
+            .map(|(dist2, candidate)| {
+                let dist2 = unscale_dist2(dist2, self.scale);
+                match candidate {
+                    LodCandidate::Point(index) => LodNeighbour::Point(Neighbour {
+                        point: self.stored_point(index),
+                        dist2,
+                        index: self.externalise_index(index),
+                    }),
+                    LodCandidate::Subtree(node) => {
+                        let (count, centroid) = self.subtree_centroid(node as usize);
+                        LodNeighbour::Representative {
+                            centroid: self.point_from_tree_frame(&centroid),
+                            dist2,
+                            count,
+                        }
+                    }
+                }
+            })
+            .collect()
+    }
+
+    fn recurse_lod(
+        &self,
+        query: &[NotNan<T>],
+        node: usize,
+        cell: &mut NodeCell<T>,
+        collector: &mut LodCollector<T>,
+        internal_parameters: &InternalParameters<T>,
+        ratio2: NotNan<T>,
+    ) {
+        let (min_dist2, _) = cell.dist2_range(query, self.scale);
+        if min_dist2 > internal_parameters.max_radius2
+            || !internal_parameters.within_error(min_dist2, collector.furthest_dist2())
+        {
+            return;
+        }
+        if self.subtree_count(node) > 1 && min_dist2 > NotNan::zero() {
+            let diagonal2 = (0..P::DIM as usize).fold(NotNan::zero(), |diagonal2, dim| {
+                let (lower, upper) = cell.extent(dim);
+                let side = (upper - lower) * self.scale;
+                diagonal2 + side * side
+            });
+            if saturate(diagonal2) < ratio2 * min_dist2 {
+                let (_, centroid) = self.subtree_centroid(node);
+                let dist2 = saturate(point_slice_dist2::<T, P>(query, &centroid, self.scale));
+                if dist2 <= internal_parameters.max_radius2 {
+                    collector.offer(dist2, LodCandidate::Subtree(node as u32));
+                }
+                return;
+            }
+        }
+        self.nodes[node].dispatch_on_type(
+            (cell, collector),
+            |(cell, collector), split_dim, split_val, right_child| {
+                let dim = split_dim as usize;
+                let parent = cell.extent(dim);
+                let left_first = query[dim] < split_val;
+                for left in [left_first, !left_first] {
+                    let child = if left { node + 1 } else { right_child as usize };
+                    cell.set_child(dim, parent, split_val, self.drift, left);
+                    self.recurse_lod(query, child, cell, collector, internal_parameters, ratio2);
+                }
+                cell.set_extent(dim, parent);
+            },
+            |(_, collector), bucket_start_index, bucket_size| {
+                let dim = P::DIM as usize;
+                let start = bucket_start_index as usize * dim;
+                let bucket = &self.points[start..start + bucket_size as usize * dim];
+                scan_leaf::<T, P, _>(
+                    collector,
+                    query,
+                    bucket,
+                    bucket_start_index,
+                    self.scale,
+                    internal_parameters,
+                );
+            },
+        );
+    }
+
+    /// Returns the number of points of `node` from the subtree sums, zero without them
+    fn subtree_count(&self, node: usize) -> u32 {
+        match &self.subtree_sums {
+            Some(sums) => sums[node * subtree_sums_stride(P::DIM as usize)] as u32,
+            None => 0,
+        }
+    }
+
+    /// Returns the number of points of `node` and their centroid, from the subtree sums
+    fn subtree_centroid(&self, node: usize) -> (u32, Vec<NotNan<T>>) {
+        let sums = self
+            .subtree_sums
+            .as_ref()
+            .expect("subtrees are only represented with subtree sums");
+        let stride = subtree_sums_stride(P::DIM as usize);
+        let node_sums = &sums[node * stride..(node + 1) * stride];
+        let count = node_sums[0];
+        let centroid = node_sums[1..=P::DIM as usize]
+            .iter()
+            .map(|sum| {
+                let mean = T::from(sum / count).expect("f64 converts to any float type");
+                NotNan::new(mean).expect("the centroid of points is not NaN")
+            })
+            .collect();
+        (count as u32, centroid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn lod_tree(cloud: &[P2]) -> KDTree<f32, P2> {
+        KDTree::new_with_options(
+            cloud,
+            &BuildOptions {
+                subtree_sums: true,
+                ..BuildOptions::default()
+            },
+        )
+    }
+
+    #[test]
+    fn far_subtrees_are_represented_by_their_centroid() {
+        let cloud: Vec<P2> = random_point_cloud(5000);
+        let tree = lod_tree(&cloud);
+        let exact = KDTree::new(&cloud);
+        let policy = LodPolicy {
+            max_extent_ratio: 1.0,
+        };
+        let mut represented = 0;
+        for _ in 0..50 {
+            let query = random_point();
+            let found = tree.knn_lod(200, &query, &Parameters::default(), policy);
+            // far subtrees can be so coarse that fewer than k results remain
+            assert!(!found.is_empty() && found.len() <= 200);
+            let count: u32 = found.iter().map(LodNeighbour::count).sum();
+            assert!(count >= found.len() as u32 && count <= 5000);
+            assert!(found
+                .windows(2)
+                .all(|pair| pair[0].dist2() <= pair[1].dist2()));
+            for neighbour in &found {
+                match neighbour {
+                    LodNeighbour::Point(point) => {
+                        assert_eq!(point.point, cloud[point.index as usize]);
+                    }
+                    LodNeighbour::Representative {
+                        centroid,
+                        dist2,
+                        count,
+                    } => {
+                        represented += 1;
+                        assert!(*count > 1);
+                        let dx = centroid.get(0) - query.get(0);
+                        let dy = centroid.get(1) - query.get(1);
+                        assert!((dx * dx + dy * dy - *dist2).abs() < 1e-2);
+                    }
+                }
+            }
+            // the nearest results are real points, as their subtrees are too close to be represented
+            assert_eq!(found[0], LodNeighbour::Point(exact.knn(1, &query)[0]));
+            // a ratio of zero gives exact results
+            let zero = LodPolicy {
+                max_extent_ratio: 0.0,
+            };
+            let points: Vec<_> = tree
+                .knn_lod(50, &query, &Parameters::default(), zero)
+                .into_iter()
+                .map(|neighbour| match neighbour {
+                    LodNeighbour::Point(point) => point,
+                    LodNeighbour::Representative { .. } => panic!("no subtree is represented"),
+                })
+                .collect();
+            assert_eq!(points, exact.knn(50, &query));
+        }
+        assert!(represented > 0);
+    }
+
+    #[test]
+    fn representatives_need_subtree_sums() {
+        let cloud: Vec<P2> = random_point_cloud(2000);
+        let tree = KDTree::new(&cloud);
+        let query = P2::new2d(500.0, 500.0);
+        let found = tree.knn_lod(10, &query, &Parameters::default(), LodPolicy::default());
+        assert!(found.iter().all(|neighbour| neighbour.count() == 1));
+        // far from the cloud, the whole tree is a single representative within the radius
+        let tree = lod_tree(&cloud);
+        let found = tree.knn_lod(10, &query, &Parameters::default(), LodPolicy::default());
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].count(), 2000);
+        let parameters = Parameters {
+            max_radius: 100.0,
+            ..Parameters::default()
+        };
+        assert!(tree
+            .knn_lod(10, &query, &parameters, LodPolicy::default())
+            .is_empty());
+    }
+}
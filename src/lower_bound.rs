@@ -0,0 +1,145 @@
+//! Conservative lower bounds of the distance to the nearest point, without touching the points
+
+use alloc::vec::Vec;
+
+use num_traits::Float;
+
+use crate::{KDTree, Point, Scalar};
+
+impl<T: Scalar + Float, P: Point<T>> KDTree<T, P> {
+    /// Returns a lower bound of the distance from `query` to its nearest point in the tree,
+    /// descending at most `max_depth` levels and never reading the stored points.
+    ///
+    /// The bound is the distance to the nearest cell at depth `max_depth`, or leaf if shallower,
+    /// the cells being bounded by the bounding box of the tree and the split planes,
+    /// widened by [`Self::drift_estimate`].
+    /// It is exact if the nearest point lies on the surface of its cell, and zero if the query is inside a cell,
+    /// so deeper descents give tighter bounds at a slightly higher cost.
+    /// Returns infinity for an empty tree.
+    ///
+    /// Panics if `query` has NaN coordinates.
+    pub fn distance_lower_bound(&self, query: &P, max_depth: u32) -> T {
+        if self.is_empty() {
+            return <T as Float>::infinity();
+        }
+        let query: Vec<T> = self
+            .query_coordinates(query)
+            .iter()
+            .map(|value| value.into_inner())
+            .collect();
+        let mut lower: Vec<T> = self.min_bounds.iter().map(|v| v.into_inner()).collect();
+        let mut upper: Vec<T> = self.max_bounds.iter().map(|v| v.into_inner()).collect();
+        let mut best = <T as Float>::infinity();
+        self.recurse_lower_bound(&query, 0, max_depth, &mut lower, &mut upper, &mut best);
+        // an overflowing squared distance saturates, which keeps the bound conservative
+        Float::min(best, <T as Float>::max_value()).sqrt()
+    }
+
+    fn recurse_lower_bound(
+        &self,
+        query: &[T],
+        node: usize,
+        depth: u32,
+        lower: &mut [T],
+        upper: &mut [T],
+        best: &mut T,
+    ) {
+        let dist2 = box_dist2(query, lower, upper);
+        if dist2 >= *best {
+            return;
+        }
+        let split = self.nodes[node].dispatch_on_type(
+            (),
+            |_, split_dim, split_val, right_child| Some((split_dim, split_val, right_child)),
+            |_, _, _| None,
+        );
+        let (split_dim, split_val, right_child) = match split {
+            Some(split) if depth > 0 => split,
+            _ => {
+                *best = dist2;
+                return;
+            }
+        };
+        let dim = split_dim as usize;
+        let drift = self.drift.into_inner();
+        let split_val = split_val.into_inner();
+        let (old_lower, old_upper) = (lower[dim], upper[dim]);
+        let left = |lower: &mut [T], upper: &mut [T]| {
+            lower[dim] = old_lower;
+            upper[dim] = Float::min(old_upper, split_val + drift);
+        };
+        let right = |lower: &mut [T], upper: &mut [T]| {
+            lower[dim] = Float::max(old_lower, split_val - drift);
+            upper[dim] = old_upper;
+        };
+        // nearer child first, to prune the other one
+        if query[dim] <= split_val {
+            left(lower, upper);
+            self.recurse_lower_bound(query, node + 1, depth - 1, lower, upper, best);
+            right(lower, upper);
+            self.recurse_lower_bound(query, right_child as usize, depth - 1, lower, upper, best);
+        } else {
+            right(lower, upper);
+            self.recurse_lower_bound(query, right_child as usize, depth - 1, lower, upper, best);
+            left(lower, upper);
+            self.recurse_lower_bound(query, node + 1, depth - 1, lower, upper, best);
+        }
+        lower[dim] = old_lower;
+        upper[dim] = old_upper;
+    }
+}
+
+/// Returns the squared distance from `query` to the box between `lower` and `upper`
+fn box_dist2<T: Scalar + Float>(query: &[T], lower: &[T], upper: &[T]) -> T {
+    let mut dist2 = T::zero();
+    for ((value, low), high) in query.iter().zip(lower).zip(upper) {
+        let off = if *value < *low {
+            *low - *value
+        } else if *value > *high {
+            *value - *high
+        } else {
+            T::zero()
+        };
+        dist2 += off * off;
+    }
+    dist2
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn assert_bounds(tree: &KDTree<f32, P3>) {
+        for _ in 0..200 {
+            // also query outside of the bounding box
+            let query = random_point::<3>() * 1.5;
+            let nearest = tree.knn(1, &query)[0].dist2.sqrt();
+            let mut previous = 0.0;
+            for max_depth in [0, 1, 2, 4, 8, 16, u32::MAX] {
+                let bound = tree.distance_lower_bound(&query, max_depth);
+                assert!(bound <= nearest * (1.0 + 1e-6), "{} > {}", bound, nearest);
+                assert!(bound >= previous);
+                previous = bound;
+            }
+        }
+    }
+
+    #[test]
+    fn distance_lower_bound_never_overestimates() {
+        let cloud: Vec<P3> = random_point_cloud(2000);
+        let mut tree = KDTree::new(&cloud);
+        assert_bounds(&tree);
+        // inside the bounding box, the root cell gives no information
+        let query = P3::from([0.0, 0.0, 0.0]);
+        assert_eq!(tree.distance_lower_bound(&query, 0), 0.0);
+        // moved points widen the cells
+        for index in (0..2000).step_by(3) {
+            let moved = cloud[index] + P3::from([5.0, -3.0, 1.0]);
+            tree.update_point(index as u32, moved);
+        }
+        assert_bounds(&tree);
+        let empty = KDTree::<f32, P3>::new(&[]);
+        assert_eq!(empty.distance_lower_bound(&query, 3), f32::INFINITY);
+    }
+}
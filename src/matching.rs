@@ -0,0 +1,135 @@
+//! Greedy one-to-one matching between the points of a tree and another set of points
+
+use alloc::collections::BinaryHeap;
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+
+use ordered_float::NotNan;
+
+use crate::distance_scale::unscale_dist2;
+use crate::internal_parameters::InternalParameters;
+use crate::{BitSet, CandidateCollector, InternalNeighbour, KDTree, Parameters, Point, Scalar};
+
+/// Keeps the nearest candidate that is not matched yet
+struct UnmatchedCollector<'a, T: Scalar> {
+    nearest: InternalNeighbour<T>,
+    /// matched internal indices
+    matched: &'a BitSet,
+}
+
+impl<'a, T: Scalar> CandidateCollector<T> for UnmatchedCollector<'a, T> {
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        if !self.matched.contains(index as usize) {
+            self.nearest.add(dist2, index);
+        }
+    }
+
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        self.nearest.furthest_dist2()
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Matches the points of the tree one-to-one with `b_points`, closest pairs first.
+    ///
+    /// The closest pair of unmatched points within `max_radius`, inclusive, is matched,
+    /// and this repeats until no such pair remains.
+    /// Returns the matched pairs by increasing distance, as the index of the point of the tree,
+    /// the index in `b_points` and the squared distance.
+    /// Unmatched points of either side are absent from the result.
+    ///
+    /// Each point of `b_points` keeps its nearest unmatched point of the tree in a priority queue,
+    /// which is searched again once that point gets matched to another one.
+    ///
+    /// Panics if `max_radius` is negative or NaN, or if a point of `b_points` has NaN coordinates.
+    pub fn match_greedy(&self, b_points: &[P], max_radius: T) -> Vec<(u32, u32, T)> {
+        assert!(
+            max_radius >= T::zero(),
+            "max_radius must be positive or zero"
+        );
+        let parameters = Parameters {
+            max_radius,
+            ..Parameters::default()
+        };
+        let internal_parameters = InternalParameters::new(&parameters, self.scale);
+        let queries: Vec<_> = b_points
+            .iter()
+            .map(|point| self.query_coordinates(point))
+            .collect();
+        let mut matched = BitSet::with_len(self.len());
+        let nearest_unmatched = |b: usize, matched: &BitSet| {
+            let mut collector = UnmatchedCollector {
+                nearest: InternalNeighbour::default(),
+                matched,
+            };
+            self.knn_internal(&mut collector, &queries[b], &internal_parameters);
+            collector
+                .furthest_dist2()
+                .map(|dist2| (dist2, collector.nearest.index))
+        };
+        // candidate pairs by increasing distance, stale once their point of the tree is matched
+        let mut pairs: BinaryHeap<_> = (0..b_points.len())
+            .filter_map(|b| {
+                nearest_unmatched(b, &matched).map(|(dist2, a)| Reverse((dist2, b as u32, a)))
+            })
+            .collect();
+        let mut matches = Vec::new();
+        while let Some(Reverse((dist2, b, a))) = pairs.pop() {
+            if matched.insert(a as usize) {
+                let dist2 = unscale_dist2(dist2, self.scale).into_inner();
+                matches.push((self.externalise_index(a), b, dist2));
+            } else if let Some((dist2, a)) = nearest_unmatched(b as usize, &matched) {
+                pairs.push(Reverse((dist2, b, a)));
+            }
+        }
+        matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    /// Matches the closest pairs first, among all pairs
+    fn brute_force_match(a: &[P2], b: &[P2], max_radius: f32) -> Vec<(u32, u32, f32)> {
+        let mut pairs = Vec::new();
+        for (i, p) in a.iter().enumerate() {
+            for (j, q) in b.iter().enumerate() {
+                let dx = p.get(0) - q.get(0);
+                let dy = p.get(1) - q.get(1);
+                let dist2 = (dx * dx + dy * dy).into_inner();
+                if dist2 <= max_radius * max_radius {
+                    pairs.push((dist2, j as u32, i as u32));
+                }
+            }
+        }
+        pairs.sort_unstable_by(|lhs, rhs| lhs.partial_cmp(rhs).unwrap());
+        let mut matched_a = vec![false; a.len()];
+        let mut matched_b = vec![false; b.len()];
+        let mut matches = Vec::new();
+        for (dist2, j, i) in pairs {
+            if !matched_a[i as usize] && !matched_b[j as usize] {
+                matched_a[i as usize] = true;
+                matched_b[j as usize] = true;
+                matches.push((i, j, dist2));
+            }
+        }
+        matches
+    }
+
+    #[test]
+    fn greedy_matching_matches_brute_force() {
+        for (a_count, b_count) in [(0, 10), (10, 0), (50, 80), (200, 150)] {
+            let a: Vec<P2> = random_point_cloud(a_count);
+            let b: Vec<P2> = random_point_cloud(b_count);
+            let tree = KDTree::new(&a);
+            for max_radius in [0.0, 5.0, 20.0, 1000.0] {
+                assert_eq!(
+                    tree.match_greedy(&b, max_radius),
+                    brute_force_match(&a, &b, max_radius)
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,360 @@
+//! Merging several trees into one
+
+use alloc::vec::Vec;
+
+use crate::node::Node;
+use crate::self_check::SELF_CHECK_SAMPLE;
+use crate::{BuildError, BuildOptions, KDTree, Point, Scalar};
+
+/// A tree is kept and the other one inserted into its leaves if it has at least this many times more points
+const INSERT_RATIO: usize = 8;
+
+/// When inserting, leaves can grow up to this many times the bucket size before a full rebuild is preferred
+const MAX_LEAF_GROWTH: u32 = 2;
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Merges two trees into one, in which the indices of the points of `a` are unchanged
+    /// and the indices of the points of `b` are offset by `a.len()`.
+    ///
+    /// If one tree is much smaller than the other and both have the same rotation, see [`Self::rotation`],
+    /// its points are inserted into the leaves of the larger one, whose structure, bucket size and rotation are kept.
+    /// Otherwise, the tree is rebuilt as by [`Self::merge_all`].
+    /// If either tree has identifiers, the merged tree has those of both, see [`Self::id_of`],
+    /// and if both have timestamps, it has those of both, see [`Self::timestamp_of`].
+    /// The merged tree is one generation beyond the latest of `a` and `b`, see [`Self::generation`].
+    /// Panics if the merged tree would have more than `u32::MAX` points.
+    pub fn merge(a: KDTree<T, P>, b: KDTree<T, P>) -> KDTree<T, P> {
+        let offset = a.len();
+        let generation = a.generation.max(b.generation) + 1;
+        let ids = Self::concatenated_ids([&a, &b].into_iter());
+        let timestamps = Self::concatenated_timestamps([&a, &b].into_iter());
+        let mut merged = if a.rotation != b.rotation {
+            Self::merge_all(alloc::vec![a, b])
+        } else if a.len() >= b.len().saturating_mul(INSERT_RATIO) {
+            match a.insertion_leaves(&b) {
+                Some(leaves) => a.insert_tree(&b, &leaves, 0, offset),
+                None => Self::merge_all(alloc::vec![a, b]),
+            }
+        } else if b.len() >= a.len().saturating_mul(INSERT_RATIO) {
+            match b.insertion_leaves(&a) {
+                Some(leaves) => b.insert_tree(&a, &leaves, offset, 0),
+                None => Self::merge_all(alloc::vec![a, b]),
+            }
+        } else {
+            Self::merge_all(alloc::vec![a, b])
+        };
+        merged.set_external_ids(ids);
+        merged.set_external_timestamps(timestamps);
+        merged.generation = generation;
+        debug_assert_eq!(merged.self_check(SELF_CHECK_SAMPLE, 0), Ok(()));
+        merged
+    }
+
+    /// Merges trees into one by rebuilding it from all points, in which the indices of the points
+    /// of each tree are offset by the number of points of the trees before it.
+    ///
+    /// The storage of the largest tree is reused, and the bucket size is the one of the first tree.
+    /// The merged tree is not rotated.
+    /// If any tree has identifiers, the merged tree has those of all trees, see [`Self::id_of`],
+    /// and if all have timestamps, it has those of all trees, see [`Self::timestamp_of`].
+    /// The merged tree is one generation beyond the latest of the trees, see [`Self::generation`].
+    /// Panics if the merged tree would have more than `u32::MAX` points.
+    pub fn merge_all(trees: Vec<KDTree<T, P>>) -> KDTree<T, P> {
+        let generation = trees
+            .iter()
+            .map(|tree| tree.generation + 1)
+            .max()
+            .unwrap_or(0);
+        let count: usize = trees.iter().map(KDTree::len).sum();
+        if count > u32::MAX as usize {
+            panic!("{}", BuildError::TooManyPoints(count));
+        }
+        let bucket_size = trees
+            .first()
+            .map_or(BuildOptions::default().bucket_size, |tree| {
+                tree.options.bucket_size
+            });
+        let ids = Self::concatenated_ids(trees.iter());
+        let timestamps = Self::concatenated_timestamps(trees.iter());
+        let mut cloud = Vec::with_capacity(count);
+        for tree in &trees {
+            cloud.extend(tree.iter().map(|(_, point)| point));
+        }
+        let largest = trees
+            .into_iter()
+            .enumerate()
+            .max_by_key(|(index, tree)| (tree.len(), core::cmp::Reverse(*index)))
+            .map(|(_, tree)| tree);
+        let mut tree = match largest {
+            Some(tree) => tree,
+            None => return KDTree::new(&cloud),
+        };
+        tree.options = BuildOptions {
+            bucket_size,
+            subtree_sums: tree.subtree_sums.is_some(),
+            ..BuildOptions::default()
+        };
+        tree.nodes.clear();
+        tree.points.clear();
+        tree.indices.clear();
+        tree.drift = num_traits::Zero::zero();
+        tree.rotation = None;
+        tree.originals = None;
+        tree.build_nodes(&cloud, None, None, false)
+            .unwrap_or_else(|error| panic!("{}", error));
+        tree.update_internal_indices();
+        tree.update_bounds_and_scale();
+        tree.refresh_subtree_sums();
+        tree.mark_axis_indices_stale();
+        tree.set_external_ids(ids);
+        tree.set_external_timestamps(timestamps);
+        tree.generation = generation;
+        tree
+    }
+
+    /// Returns the leaf of `self` in which to insert each internal index of `other`, sorted by leaf,
+    /// or `None` if a leaf would grow too much
+    fn insertion_leaves(&self, other: &KDTree<T, P>) -> Option<Vec<(u32, u32)>> {
+        let dim = P::DIM as usize;
+        let mut inserted: Vec<(u32, u32)> = (0..other.len() as u32)
+            .map(|internal| {
+                let point = &other.points[internal as usize * dim..(internal as usize + 1) * dim];
+                let mut node = 0;
+                while let Some((split_dim, split_val, right_child)) = self.nodes[node]
+                    .dispatch_on_type(
+                        (),
+                        |_, split_dim, split_val, right_child| {
+                            Some((split_dim, split_val, right_child))
+                        },
+                        |_, _, _| None,
+                    )
+                {
+                    node = if point[split_dim as usize] <= split_val {
+                        node + 1
+                    } else {
+                        right_child as usize
+                    };
+                }
+                (node as u32, internal)
+            })
+            .collect();
+        inserted.sort_unstable();
+        // check the sizes of the leaves
+        let max_leaf_size = self.options.bucket_size.saturating_mul(MAX_LEAF_GROWTH);
+        let mut start = 0;
+        while start < inserted.len() {
+            let node = inserted[start].0;
+            let end = start + inserted[start..].partition_point(|(n, _)| *n == node);
+            let (_, size) = self.leaf_range(node as usize);
+            if size as usize + (end - start) > max_leaf_size as usize {
+                return None;
+            }
+            start = end;
+        }
+        Some(inserted)
+    }
+
+    /// Inserts the points of `other` into the leaves given by [`Self::insertion_leaves`],
+    /// offsetting the indices of `self` by `self_offset` and the ones of `other` by `other_offset`
+    fn insert_tree(
+        mut self,
+        other: &KDTree<T, P>,
+        inserted: &[(u32, u32)],
+        self_offset: usize,
+        other_offset: usize,
+    ) -> KDTree<T, P> {
+        let count = self.len() + other.len();
+        if count > u32::MAX as usize {
+            panic!("{}", BuildError::TooManyPoints(count));
+        }
+        let dim = P::DIM as usize;
+        // rebuild the storage, leaf by leaf
+        let mut points = Vec::with_capacity(count * dim);
+        let mut indices = Vec::with_capacity(count);
+        let mut next_inserted = 0;
+        for node in 0..self.nodes.len() {
+            if self.nodes[node].dispatch_on_type((), |_, _, _, _| true, |_, _, _| false) {
+                continue;
+            }
+            let (bucket_start_index, bucket_size) = self.leaf_range(node);
+            let new_start_index = indices.len() as u32;
+            let bucket = bucket_start_index as usize..(bucket_start_index + bucket_size) as usize;
+            points.extend_from_slice(&self.points[bucket.start * dim..bucket.end * dim]);
+            indices.extend(
+                self.indices[bucket]
+                    .iter()
+                    .map(|index| (*index as usize + self_offset) as u32),
+            );
+            while next_inserted < inserted.len() && inserted[next_inserted].0 as usize == node {
+                let internal = inserted[next_inserted].1 as usize;
+                points.extend_from_slice(&other.points[internal * dim..(internal + 1) * dim]);
+                indices.push((other.indices[internal] as usize + other_offset) as u32);
+                next_inserted += 1;
+            }
+            let new_size = indices.len() as u32 - new_start_index;
+            self.nodes[node] = Node::new_leaf_node(new_start_index, new_size);
+        }
+        if let (Some(originals), Some(other_originals)) = (&self.originals, &other.originals) {
+            let mut merged = Vec::with_capacity(count * dim);
+            let (first, second) = if self_offset == 0 {
+                (originals, other_originals)
+            } else {
+                (other_originals, originals)
+            };
+            merged.extend_from_slice(first);
+            merged.extend_from_slice(second);
+            self.originals = Some(merged);
+        }
+        self.points = points;
+        self.indices = indices;
+        self.update_internal_indices();
+        self.update_bounds_and_scale();
+        self.refresh_subtree_sums();
+        self.mark_axis_indices_stale();
+        self
+    }
+
+    /// Returns the start index and size of the bucket of the leaf `node`
+    fn leaf_range(&self, node: usize) -> (u32, u32) {
+        self.nodes[node].dispatch_on_type(
+            (),
+            |_, _, _, _| (0, 0),
+            |_, bucket_start_index, bucket_size| (bucket_start_index, bucket_size),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn assert_merged(merged: &KDTree<f32, P2>, clouds: &[&[P2]]) {
+        let cloud: Vec<P2> = clouds
+            .iter()
+            .flat_map(|cloud| cloud.iter().copied())
+            .collect();
+        // indices of each input are offset by the sizes of the ones before
+        assert_eq!(merged.points(), cloud);
+        let reference = KDTree::new(&cloud);
+        for _ in 0..50 {
+            let query: P2 = random_point();
+            assert_eq!(merged.knn(6, &query), reference.knn(6, &query));
+        }
+    }
+
+    #[test]
+    fn merge_similar_trees_rebuilds() {
+        let a: Vec<P2> = random_point_cloud(500);
+        let b: Vec<P2> = random_point_cloud(300);
+        let merged = KDTree::merge(KDTree::new(&a), KDTree::new(&b));
+        assert_merged(&merged, &[&a, &b]);
+    }
+
+    #[test]
+    fn merge_small_tree_into_large_one() {
+        let a: Vec<P2> = random_point_cloud(5000);
+        let b: Vec<P2> = random_point_cloud(20);
+        let large = KDTree::new(&a);
+        let node_count = format!("{:?}", large).matches("Node(").count();
+        let merged = KDTree::merge(large, KDTree::new(&b));
+        // the structure of the large tree is kept
+        assert_eq!(format!("{:?}", merged).matches("Node(").count(), node_count);
+        assert_merged(&merged, &[&a, &b]);
+        let merged = KDTree::merge(KDTree::new(&b), KDTree::new(&a));
+        assert_eq!(format!("{:?}", merged).matches("Node(").count(), node_count);
+        assert_merged(&merged, &[&b, &a]);
+    }
+
+    #[test]
+    fn merge_falls_back_to_rebuild_when_leaves_overflow() {
+        let a: Vec<P2> = random_point_cloud(1000);
+        // all points of `b` fall into the same leaf
+        let b: Vec<P2> = (0..50)
+            .map(|i| P2::new2d(1.0 + i as f32 * 1e-3, 1.0))
+            .collect();
+        let merged = KDTree::merge(KDTree::new(&a), KDTree::new(&b));
+        assert_merged(&merged, &[&a, &b]);
+    }
+
+    #[test]
+    fn merge_all_trees() {
+        let a: Vec<P2> = random_point_cloud(100);
+        let b: Vec<P2> = random_point_cloud(400);
+        let c: Vec<P2> = random_point_cloud(0);
+        let d: Vec<P2> = random_point_cloud(50);
+        let trees = vec![
+            KDTree::new(&a),
+            KDTree::new(&b),
+            KDTree::new(&c),
+            KDTree::new(&d),
+        ];
+        let merged = KDTree::merge_all(trees);
+        assert_merged(&merged, &[&a, &b, &c, &d]);
+        assert!(KDTree::<f32, P2>::merge_all(Vec::new()).is_empty());
+        let empty = KDTree::merge(KDTree::new(&c), KDTree::new(&c));
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn merges_mark_the_axis_indices_stale() {
+        let a: Vec<P2> = random_point_cloud(500);
+        let b: Vec<P2> = random_point_cloud(400);
+        let c: Vec<P2> = random_point_cloud(20);
+        let indexed = |cloud: &[P2]| {
+            let mut tree = KDTree::new(cloud);
+            tree.build_axis_index(0);
+            tree
+        };
+        // rebuilt, inserted into the leaves, and rebuilt from several trees
+        for (mut merged, count) in [
+            (KDTree::merge(indexed(&a), indexed(&b)), 900),
+            (KDTree::merge(indexed(&a), indexed(&c)), 520),
+            (KDTree::merge_all(vec![indexed(&a), indexed(&b)]), 900),
+        ] {
+            assert_eq!(merged.axis_range(0, -1000.0, 1000.0), None);
+            merged.refresh_axis_indices();
+            let all = merged.axis_range(0, -1000.0, 1000.0);
+            assert_eq!(all.map(<[u32]>::len), Some(count));
+        }
+    }
+
+    #[test]
+    fn merged_trees_have_the_subtree_sums_of_all_points() {
+        let a: Vec<P2> = random_point_cloud(500);
+        let b: Vec<P2> = random_point_cloud(400);
+        let c: Vec<P2> = random_point_cloud(20);
+        let options = BuildOptions {
+            subtree_sums: true,
+            ..BuildOptions::default()
+        };
+        let new = |cloud: &[P2]| KDTree::new_with_options(cloud, &options);
+        // rebuilt, then inserted into the leaves
+        let rebuilt = KDTree::merge(new(&a), new(&b));
+        let inserted = KDTree::merge(new(&a), new(&c));
+        for (merged, clouds) in [(rebuilt, [&a, &b]), (inserted, [&a, &c])] {
+            let cloud: Vec<P2> = clouds
+                .iter()
+                .flat_map(|cloud| cloud.iter().copied())
+                .collect();
+            let reference = new(&cloud);
+            assert_eq!(
+                merged
+                    .aggregate_within(&P2::new2d(0.0, 0.0), 1000.0)
+                    .count(),
+                cloud.len() as u64
+            );
+            for _ in 0..20 {
+                let query = random_point();
+                let (found, expected) = (
+                    merged.aggregate_within(&query, 30.0),
+                    reference.aggregate_within(&query, 30.0),
+                );
+                assert_eq!(found.count(), expected.count());
+                for (found, expected) in found.sums().iter().zip(expected.sums()) {
+                    assert!((found - expected).abs() <= 1e-6 * expected.abs().max(1.0));
+                }
+            }
+        }
+    }
+}
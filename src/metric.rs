@@ -0,0 +1,255 @@
+//! The distance between points as a tree measures it, shared by its searches and its public distance functions
+
+use alloc::vec::Vec;
+
+use num_traits::Zero;
+use ordered_float::NotNan;
+
+use crate::distance_scale::{saturate, unscale_dist2};
+use crate::{InternalNeighbour, KDTree, Neighbour, Point, QueryError, Scalar};
+
+/// Helper function to compute the square distance between two points given as slice
+///
+/// The differences are multiplied by `scale` before squaring, see [`KDTree::new`].
+/// An overflowing distance saturates to `T::max_value()`.
+#[inline]
+pub(crate) fn point_slice_dist2<T: Scalar, P: Point<T>>(
+    lhs: &[NotNan<T>],
+    rhs: &[NotNan<T>],
+    scale: NotNan<T>,
+) -> NotNan<T> {
+    let mut dist2 = NotNan::<T>::zero();
+    if scale == T::one() {
+        for index in 0..P::DIM {
+            let index = index as usize;
+            let diff = lhs[index] - rhs[index];
+            dist2 += diff * diff;
+        }
+    } else {
+        for index in 0..P::DIM {
+            let index = index as usize;
+            let diff = (lhs[index] - rhs[index]) * scale;
+            dist2 += diff * diff;
+        }
+    }
+    saturate(dist2)
+}
+
+/// The squared Euclidean distance of a tree, computed on differences multiplied by its distance scale
+///
+/// Searches compare internal squared distances, and return them converted by [`Self::external_dist2`],
+/// so that a distance computed by [`Self::dist2`] is bitwise the one a search returns for the same points.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct TreeMetric<T: Scalar> {
+    scale: NotNan<T>,
+}
+
+impl<T: Scalar> TreeMetric<T> {
+    /// Returns the metric of a tree of distance scale `scale`
+    pub(crate) fn new(scale: NotNan<T>) -> Self {
+        TreeMetric { scale }
+    }
+
+    /// Returns the internal squared distance between the coordinates `lhs` and `rhs`
+    #[inline]
+    pub(crate) fn internal_dist2<P: Point<T>>(
+        &self,
+        lhs: &[NotNan<T>],
+        rhs: &[NotNan<T>],
+    ) -> NotNan<T> {
+        point_slice_dist2::<T, P>(lhs, rhs, self.scale)
+    }
+
+    /// Converts an internal squared distance to the units of the points
+    #[inline]
+    pub(crate) fn external_dist2(&self, dist2: NotNan<T>) -> NotNan<T> {
+        unscale_dist2(dist2, self.scale)
+    }
+
+    /// Returns the squared distance between the coordinates `lhs` and `rhs`, in the units of the points
+    pub(crate) fn dist2<P: Point<T>>(&self, lhs: &[NotNan<T>], rhs: &[NotNan<T>]) -> NotNan<T> {
+        self.external_dist2(self.internal_dist2::<P>(lhs, rhs))
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Returns the metric of the tree
+    pub(crate) fn metric(&self) -> TreeMetric<T> {
+        TreeMetric::new(self.scale)
+    }
+
+    /// Returns the squared distance between `a` and `b` as measured by the tree.
+    ///
+    /// This is bitwise the squared distance the searches of the tree return between these points,
+    /// including the scaling of differences of the trees of points of extreme magnitudes,
+    /// and the saturation of overflowing distances, so that it can check or filter their results.
+    /// It does not allocate.
+    /// Panics if `a` or `b` has NaN coordinates.
+    pub fn distance2(&self, a: &P, b: &P) -> T {
+        let (a, b) = (self.query_coordinates(a), self.query_coordinates(b));
+        self.metric().dist2::<P>(&a, &b).into_inner()
+    }
+
+    /// Returns the squared distance between `a` and the point of the tree at `external_index`,
+    /// as stored and measured by the tree, see [`Self::distance2`].
+    ///
+    /// Panics if `a` has NaN coordinates or if `external_index` is not smaller than [`Self::len`].
+    pub fn distance2_to_stored(&self, a: &P, external_index: u32) -> T {
+        assert!(
+            (external_index as usize) < self.len(),
+            "point index {} is out of range for {} points",
+            external_index,
+            self.len()
+        );
+        let stored = self.stored_coordinates(self.internalise_index(external_index));
+        let a = self.query_coordinates(a);
+        self.metric().dist2::<P>(stored, &a).into_inner()
+    }
+
+    /// Returns the `k` nearest of the points of `candidates`, indices in the original point cloud,
+    /// to `query`, sorted by increasing distance, without traversing the tree.
+    ///
+    /// The distances are those of [`Self::distance2_to_stored`], and the neighbours are those the searches
+    /// of the tree would return if they only held the candidates, in the same order among equal distances.
+    /// Candidates appearing several times are ranked once.
+    /// This takes time `O(m log m)` for `m` candidates, and ignores the search parameters:
+    /// there is no radius, and a candidate at the position of the query is returned.
+    /// Panics if a candidate is not smaller than [`Self::len`] or if `query` has NaN coordinates,
+    /// see [`Self::try_rank_candidates`].
+    pub fn rank_candidates(&self, query: &P, candidates: &[u32], k: u32) -> Vec<Neighbour<T, P>> {
+        self.try_rank_candidates(query, candidates, k)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Same as [`Self::rank_candidates`], but returns [`QueryError::IndexOutOfRange`]
+    /// for the first candidate that is not smaller than [`Self::len`].
+    pub fn try_rank_candidates(
+        &self,
+        query: &P,
+        candidates: &[u32],
+        k: u32,
+    ) -> Result<Vec<Neighbour<T, P>>, QueryError> {
+        let metric = self.metric();
+        let query = self.query_coordinates(query);
+        let mut ranked = Vec::with_capacity(candidates.len());
+        for candidate in candidates {
+            if *candidate as usize >= self.len() {
+                return Err(QueryError::IndexOutOfRange {
+                    index: *candidate,
+                    len: self.len(),
+                });
+            }
+            let index = self.internalise_index(*candidate);
+            let dist2 = metric.internal_dist2::<P>(self.stored_coordinates(index), &query);
+            ranked.push(InternalNeighbour { index, dist2 });
+        }
+        ranked.sort_unstable_by_key(|neighbour| neighbour.index);
+        ranked.dedup_by_key(|neighbour| neighbour.index);
+        let k = (k as usize).min(ranked.len());
+        if k == 0 {
+            return Ok(Vec::new());
+        }
+        ranked.select_nth_unstable(k - 1);
+        ranked.truncate(k);
+        ranked.sort_unstable();
+        Ok(ranked
+            .into_iter()
+            .map(|neighbour| self.externalise_neighbour(neighbour))
+            .collect())
+    }
+
+    /// Returns the coordinates of the stored point of internal index `internal_index`
+    pub(crate) fn stored_coordinates(&self, internal_index: u32) -> &[NotNan<T>] {
+        let dim = P::DIM as usize;
+        let start = internal_index as usize * dim;
+        &self.points[start..start + dim]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn distances_are_bitwise_those_of_the_searches() {
+        let cloud: Vec<P3> = random_point_cloud(2000);
+        // the magnitudes of the last clouds make the tree scale the differences
+        for factor in [1.0, 1e20, 1e-30] {
+            let scaled = |p: &P3| P3::from([0, 1, 2].map(|i| p.get(i).into_inner() * factor));
+            let tree = KDTree::new(&cloud.iter().map(scaled).collect::<Vec<_>>());
+            for _ in 0..20 {
+                let query = scaled(&random_point());
+                for neighbour in tree.knn(5, &query) {
+                    let dist2 = neighbour.dist2.into_inner();
+                    assert_eq!(
+                        tree.distance2(&query, &neighbour.point).to_bits(),
+                        dist2.to_bits()
+                    );
+                    assert_eq!(
+                        tree.distance2_to_stored(&query, neighbour.index).to_bits(),
+                        dist2.to_bits()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn distances_are_symmetric_and_saturate() {
+        let cloud: Vec<P2> = random_point_cloud(100);
+        let tree = KDTree::new(&cloud);
+        assert_eq!(
+            tree.distance2(&cloud[3], &cloud[7]),
+            tree.distance2(&cloud[7], &cloud[3])
+        );
+        assert_eq!(tree.distance2_to_stored(&cloud[3], 3), 0.0);
+        assert_eq!(
+            tree.distance2(&P2::new2d(0.0, 0.0), &P2::new2d(3.0, 4.0)),
+            25.0
+        );
+        let far = P2::new2d(f32::MAX, f32::MAX);
+        assert_eq!(tree.distance2(&far, &P2::new2d(-f32::MAX, 0.0)), f32::MAX);
+    }
+
+    #[test]
+    #[should_panic(expected = "point index 100 is out of range for 100 points")]
+    fn distances_to_missing_points_panic() {
+        let cloud: Vec<P2> = random_point_cloud(100);
+        KDTree::new(&cloud).distance2_to_stored(&cloud[0], 100);
+    }
+
+    #[test]
+    fn ranked_candidates_are_the_nearest_of_the_candidates() {
+        let cloud: Vec<P3> = random_point_cloud(3000);
+        let tree = KDTree::new(&cloud);
+        let all: Vec<u32> = (0..cloud.len() as u32).collect();
+        for _ in 0..20 {
+            let query = random_point();
+            // ranking all points is the exact search
+            assert_eq!(tree.rank_candidates(&query, &all, 10), tree.knn(10, &query));
+            let candidates: Vec<u32> = tree.knn(200, &query).iter().map(|n| n.index).collect();
+            let mut shuffled: Vec<u32> = candidates.iter().rev().copied().collect();
+            shuffled.extend_from_slice(&candidates[..50]);
+            let ranked = tree.rank_candidates(&query, &shuffled, 30);
+            assert_eq!(ranked, tree.knn(30, &query));
+            for neighbour in &ranked {
+                assert_eq!(
+                    neighbour.dist2.into_inner(),
+                    tree.distance2_to_stored(&query, neighbour.index)
+                );
+            }
+        }
+        // duplicates are ranked once, and fewer candidates than k are all returned
+        let ranked = tree.rank_candidates(&cloud[5], &[5, 9, 5, 9], 10);
+        assert_eq!(ranked.iter().map(|n| n.index).collect::<Vec<_>>(), [5, 9]);
+        assert!(tree.rank_candidates(&cloud[5], &[5, 9], 0).is_empty());
+        assert_eq!(
+            tree.try_rank_candidates(&cloud[5], &[5, 3000], 1),
+            Err(QueryError::IndexOutOfRange {
+                index: 3000,
+                len: 3000
+            })
+        );
+    }
+}
@@ -0,0 +1,131 @@
+//! Morton (Z-order) codes of points, used to presort clouds before building
+
+use alloc::vec::Vec;
+
+use crate::{Point, Scalar};
+
+/// Number of bits of a Morton code
+const CODE_BITS: u32 = 64;
+
+/// Returns the indices of the points of `cloud`, ordered by their Morton code
+///
+/// The coordinates must be finite.
+/// They are mapped to an integer grid spanning the bounding box of the cloud,
+/// with `CODE_BITS / DIM` bits per dimension, and only the first `CODE_BITS` dimensions are used.
+pub(crate) fn morton_order<T: Scalar, P: Point<T>>(cloud: &[P]) -> Vec<usize> {
+    let dim = P::DIM.min(CODE_BITS);
+    let bits = CODE_BITS / dim.max(1);
+    let cells = ((1u64 << (bits - 1)) - 1) * 2 + 1;
+    // work on halved coordinates, so that extents cannot overflow
+    let half = T::one() / (T::one() + T::one());
+    let mut min = Vec::with_capacity(dim as usize);
+    let mut extent = Vec::with_capacity(dim as usize);
+    for i in 0..dim {
+        let (low, high) =
+            cloud
+                .iter()
+                .fold((T::max_value(), T::min_value()), |(low, high), point| {
+                    let value = point.get(i).into_inner() * half;
+                    (low.min(value), high.max(value))
+                });
+        min.push(low);
+        extent.push(high - low);
+    }
+    let cells_t = T::from(cells).unwrap_or_else(T::max_value);
+    let mut codes: Vec<(u64, usize)> = cloud
+        .iter()
+        .enumerate()
+        .map(|(index, point)| {
+            let mut grid = [0u64; CODE_BITS as usize];
+            for i in 0..dim {
+                let i_u = i as usize;
+                if extent[i_u] > T::zero() {
+                    let ratio = (point.get(i).into_inner() * half - min[i_u]) / extent[i_u];
+                    // the ratio is in [0, 1], but `cells_t` may round up beyond `u64::MAX`, as in 1-D with `f32`,
+                    // so the top of the range is clamped into the last cell
+                    grid[i_u] = (ratio * cells_t)
+                        .to_u64()
+                        .unwrap_or(cells - 1)
+                        .min(cells - 1);
+                }
+            }
+            (interleave(&grid[..dim as usize], bits), index)
+        })
+        .collect();
+    codes.sort_unstable();
+    codes.into_iter().map(|(_, index)| index).collect()
+}
+
+/// Interleaves the `bits` lowest bits of each value, the most significant first
+fn interleave(grid: &[u64], bits: u32) -> u64 {
+    let mut code = 0;
+    for bit in (0..bits).rev() {
+        for value in grid {
+            code = (code << 1) | ((value >> bit) & 1);
+        }
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simple_point::*;
+    use crate::{BuildOptions, KDTree};
+
+    #[test]
+    fn interleave_bits() {
+        assert_eq!(interleave(&[0b11, 0b00], 2), 0b1010);
+        assert_eq!(interleave(&[0b01, 0b10], 2), 0b0110);
+    }
+
+    #[test]
+    fn morton_order_of_a_grid() {
+        let cloud = vec![
+            P2::new2d(3e38, 3e38),
+            P2::new2d(-3e38, -3e38),
+            P2::new2d(3e38, -3e38),
+            P2::new2d(-3e38, 3e38),
+        ];
+        assert_eq!(morton_order::<f32, P2>(&cloud), vec![1, 3, 2, 0]);
+        let degenerate = vec![P2::new2d(0.0, 0.0); 3];
+        assert_eq!(morton_order::<f32, P2>(&degenerate), vec![0, 1, 2]);
+        // in 1-D, the number of cells rounds to 2^64 in `f32`, and the maximum stays last
+        let line: Vec<SimplePoint<1>> = [3.0, 1.0, 2.0, 0.0]
+            .into_iter()
+            .map(|x| SimplePoint::from([x]))
+            .collect();
+        assert_eq!(morton_order::<f32, SimplePoint<1>>(&line), vec![3, 1, 2, 0]);
+    }
+
+    #[test]
+    fn presorted_tree_returns_same_neighbours() {
+        let mut cloud: Vec<P3> = random_point_cloud(5000);
+        // duplicates must not break the grid mapping
+        cloud.extend((0..100).map(|_| P2::new3d(1.0, 2.0, 3.0)));
+        let options = BuildOptions {
+            morton_presort: true,
+            ..BuildOptions::default()
+        };
+        let presorted = KDTree::new_with_options(&cloud, &options);
+        let reference = KDTree::new(&cloud);
+        assert_eq!(presorted.points(), reference.points());
+        assert_eq!(presorted.drift_estimate(), 0.0);
+        for _ in 0..100 {
+            let query: P3 = random_point();
+            let dist2 = |tree: &KDTree<f32, P3>| -> Vec<_> {
+                tree.knn(7, &query).into_iter().map(|n| n.dist2).collect()
+            };
+            assert_eq!(dist2(&presorted), dist2(&reference));
+        }
+        for (index, point) in cloud.iter().enumerate().step_by(97) {
+            let neighbour = presorted.knn(1, point)[0];
+            assert_eq!(neighbour.dist2, 0.0);
+            assert_eq!(neighbour.point, *point);
+            assert_eq!(
+                presorted.internalise_index(presorted.externalise_index(index as u32)),
+                index as u32
+            );
+        }
+    }
+}
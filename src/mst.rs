@@ -0,0 +1,321 @@
+//! Euclidean minimum spanning tree of the points, by Borůvka steps
+
+use alloc::{vec, vec::Vec};
+
+use num_traits::{Bounded, Float};
+use ordered_float::NotNan;
+
+use crate::distance_scale::unscale_dist2;
+use crate::pair_counts::{box_pair_dist2_range, children, leaf_range, NodeBoxes};
+use crate::{point_slice_dist2, KDTree, Point, Scalar};
+
+/// Component of a node whose points are in several components
+const MIXED: u32 = u32::MAX;
+
+/// An edge between two internal indices, ordered by squared distance, then by indices,
+/// so that all edges are distinct and Borůvka steps cannot create a cycle
+type Edge<T> = (NotNan<T>, u32, u32);
+
+/// Disjoint sets of internal indices, with path halving and union by size
+struct UnionFind {
+    parents: Vec<u32>,
+    sizes: Vec<u32>,
+}
+
+impl UnionFind {
+    fn new(len: usize) -> Self {
+        UnionFind {
+            parents: (0..len as u32).collect(),
+            sizes: vec![1; len],
+        }
+    }
+
+    fn find(&mut self, mut index: u32) -> u32 {
+        while self.parents[index as usize] != index {
+            let grandparent = self.parents[self.parents[index as usize] as usize];
+            self.parents[index as usize] = grandparent;
+            index = grandparent;
+        }
+        index
+    }
+
+    /// Merges the sets of `a` and `b`, returning whether they were distinct.
+    fn union(&mut self, a: u32, b: u32) -> bool {
+        let (mut a, mut b) = (self.find(a), self.find(b));
+        if a == b {
+            return false;
+        }
+        if self.sizes[a as usize] < self.sizes[b as usize] {
+            core::mem::swap(&mut a, &mut b);
+        }
+        self.parents[b as usize] = a;
+        self.sizes[a as usize] += self.sizes[b as usize];
+        true
+    }
+}
+
+/// The state of a Borůvka step, finding for each component its shortest edge to another component
+struct BoruvkaStep<'a, T: Scalar, P: Point<T>> {
+    tree: &'a KDTree<T, P>,
+    boxes: &'a NodeBoxes<T>,
+    /// component of each point, by internal index
+    components: Vec<u32>,
+    /// component of the points of each node, [`MIXED`] if they are in several
+    node_components: Vec<u32>,
+    /// shortest edge found from each component, by the index of its root
+    shortest: Vec<Option<Edge<T>>>,
+    /// for each leaf, a squared distance not below that of the shortest edge of any of its points
+    leaf_bounds: Vec<NotNan<T>>,
+}
+
+impl<'a, T: Scalar, P: Point<T>> BoruvkaStep<'a, T, P> {
+    fn new(tree: &'a KDTree<T, P>, boxes: &'a NodeBoxes<T>, sets: &mut UnionFind) -> Self {
+        let components: Vec<u32> = (0..tree.len() as u32).map(|i| sets.find(i)).collect();
+        let mut node_components = vec![MIXED; tree.nodes.len()];
+        // children have larger ids than their parent
+        for node in (0..tree.nodes.len()).rev() {
+            node_components[node] = match children(tree, node) {
+                Some([left, right]) if boxes.counts[left] == 0 => node_components[right],
+                Some([left, right]) if boxes.counts[right] == 0 => node_components[left],
+                Some([left, right]) if node_components[left] == node_components[right] => {
+                    node_components[left]
+                }
+                Some(_) => MIXED,
+                None => {
+                    let mut range = leaf_range(tree, node);
+                    match range.next() {
+                        Some(first) => {
+                            let component = components[first];
+                            if range.all(|index| components[index] == component) {
+                                component
+                            } else {
+                                MIXED
+                            }
+                        }
+                        None => MIXED,
+                    }
+                }
+            };
+        }
+        BoruvkaStep {
+            tree,
+            boxes,
+            components,
+            node_components,
+            shortest: vec![None; tree.len()],
+            leaf_bounds: vec![NotNan::max_value(); tree.nodes.len()],
+        }
+    }
+
+    /// Returns a bound of the squared distances of the shortest edges of the points of leaf `leaf`.
+    fn bound(&self, leaf: usize) -> NotNan<T> {
+        let component = self.node_components[leaf];
+        let bound = self.leaf_bounds[leaf];
+        if component == MIXED {
+            return bound;
+        }
+        match self.shortest[component as usize] {
+            Some((dist2, _, _)) => bound.min(dist2),
+            None => bound,
+        }
+    }
+
+    /// Updates the shortest edges of the points of leaf `query` to points below `reference` of other components
+    fn traverse(&mut self, query: usize, reference: usize) {
+        let component = self.node_components[query];
+        if component != MIXED && component == self.node_components[reference] {
+            return;
+        }
+        if self.boxes.counts[reference] == 0 {
+            return;
+        }
+        let dim = P::DIM as usize;
+        let (lower, upper) = self.boxes.corners(query, dim);
+        let scale = self.tree.scale;
+        let dist2 = |node: usize| {
+            let (node_lower, node_upper) = self.boxes.corners(node, dim);
+            box_pair_dist2_range(lower, upper, node_lower, node_upper, scale).0
+        };
+        // an edge at the bound can still win on its indices
+        if dist2(reference) > self.bound(query) {
+            return;
+        }
+        match children(self.tree, reference) {
+            None => self.scan_leaves(query, reference),
+            Some([mut near, mut far]) => {
+                // visit the nearest child first, to tighten the bound early
+                if dist2(far) < dist2(near) {
+                    core::mem::swap(&mut near, &mut far);
+                }
+                self.traverse(query, near);
+                self.traverse(query, far);
+            }
+        }
+    }
+
+    /// Updates the shortest edges of the points of leaf `query` to the points of leaf `reference`
+    fn scan_leaves(&mut self, query: usize, reference: usize) {
+        let dim = P::DIM as usize;
+        let points = &self.tree.points;
+        let mut bound = NotNan::<T>::min_value();
+        for index in leaf_range(self.tree, query) {
+            let component = self.components[index];
+            let point = &points[index * dim..(index + 1) * dim];
+            let mut shortest = self.shortest[component as usize];
+            for other in leaf_range(self.tree, reference) {
+                if self.components[other] == component {
+                    continue;
+                }
+                let other_point = &points[other * dim..(other + 1) * dim];
+                let dist2 = point_slice_dist2::<T, P>(point, other_point, self.tree.scale);
+                let (a, b) = (index.min(other) as u32, index.max(other) as u32);
+                let edge = (dist2, a, b);
+                if shortest.map_or(true, |shortest| edge < shortest) {
+                    shortest = Some(edge);
+                }
+            }
+            self.shortest[component as usize] = shortest;
+            bound = bound.max(shortest.map_or(NotNan::max_value(), |edge| edge.0));
+        }
+        self.leaf_bounds[query] = self.leaf_bounds[query].min(bound);
+    }
+}
+
+impl<T: Scalar + Float, P: Point<T>> KDTree<T, P> {
+    /// Returns the edges of a Euclidean minimum spanning tree of the points,
+    /// as pairs of indices in the original point cloud, the smaller first, with their distance.
+    ///
+    /// Edges are sorted by increasing distance, then by index, which is the merge order of single-linkage clustering.
+    /// Among several minimum spanning trees, when distances are equal, the one returned only depends on the tree.
+    /// Each Borůvka step finds the shortest edge from each component to another by traversing the tree
+    /// from each of its leaves, nearest node first, pruning the nodes whose points are all in the component of the leaf,
+    /// and those farther from the leaf than the shortest edges already found from its components.
+    /// There are at most `log2(n)` steps for `n` points.
+    pub fn euclidean_mst(&self) -> Vec<(u32, u32, T)> {
+        let len = self.len();
+        if len < 2 {
+            return Vec::new();
+        }
+        let mut edges: Vec<Edge<T>> = Vec::with_capacity(len - 1);
+        let boxes = self.node_boxes();
+        let mut sets = UnionFind::new(len);
+        while edges.len() < len - 1 {
+            let mut step = BoruvkaStep::new(self, &boxes, &mut sets);
+            for leaf in 0..self.nodes.len() {
+                if boxes.counts[leaf] > 0 && children(self, leaf).is_none() {
+                    step.traverse(leaf, 0);
+                }
+            }
+            let mut shortest: Vec<Edge<T>> = step.shortest.into_iter().flatten().collect();
+            shortest.sort_unstable();
+            for (dist2, a, b) in shortest {
+                if sets.union(a, b) {
+                    edges.push((dist2, a, b));
+                }
+            }
+        }
+        let mut edges: Vec<_> = edges
+            .into_iter()
+            .map(|(dist2, a, b)| {
+                let (a, b) = (self.externalise_index(a), self.externalise_index(b));
+                (dist2, a.min(b), a.max(b))
+            })
+            .collect();
+        edges.sort_unstable();
+        edges
+            .into_iter()
+            .map(|(dist2, a, b)| {
+                let distance = Float::sqrt(unscale_dist2(dist2, self.scale).into_inner());
+                (a, b, distance)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rng::SplitMix64;
+    use crate::simple_point::*;
+    use crate::*;
+
+    /// Returns the distances of the edges of a minimum spanning tree by Prim's algorithm, sorted,
+    /// which are the same for all minimum spanning trees
+    fn prim_distances(cloud: &[P2]) -> Vec<f32> {
+        let dist2 = |a: &P2, b: &P2| {
+            let (dx, dy) = (
+                (a.get(0) - b.get(0)).into_inner(),
+                (a.get(1) - b.get(1)).into_inner(),
+            );
+            dx * dx + dy * dy
+        };
+        let mut in_tree = vec![false; cloud.len()];
+        let mut nearest = vec![f32::INFINITY; cloud.len()];
+        let mut distances = Vec::new();
+        nearest[0] = 0.0;
+        for _ in 0..cloud.len() {
+            let next = (0..cloud.len())
+                .filter(|i| !in_tree[*i])
+                .min_by(|a, b| nearest[*a].total_cmp(&nearest[*b]))
+                .unwrap();
+            in_tree[next] = true;
+            distances.push(nearest[next].sqrt());
+            for i in 0..cloud.len() {
+                nearest[i] = nearest[i].min(dist2(&cloud[next], &cloud[i]));
+            }
+        }
+        distances.remove(0);
+        distances.sort_unstable_by(f32::total_cmp);
+        distances
+    }
+
+    #[test]
+    fn mst_matches_prim() {
+        let mut rng = SplitMix64::new(191);
+        // integer coordinates lead to many equal distances, and duplicates to zero ones
+        let grid: Vec<P2> = (0..400)
+            .map(|_| P2::new2d(rng.below(30) as f32, rng.below(30) as f32))
+            .collect();
+        for cloud in [random_point_cloud::<2>(600), grid] {
+            for bucket_size in [2, 8] {
+                let tree = KDTree::new_with_bucket_size(&cloud, bucket_size);
+                let edges = tree.euclidean_mst();
+                assert_eq!(edges.len(), cloud.len() - 1);
+                // the edges connect all points, and have the distances of their points
+                let mut reached = vec![false; cloud.len()];
+                let mut adjacency = vec![Vec::new(); cloud.len()];
+                for (a, b, distance) in &edges {
+                    assert!(a < b);
+                    let (p, q) = (cloud[*a as usize], cloud[*b as usize]);
+                    let (dx, dy) = (
+                        (p.get(0) - q.get(0)).into_inner(),
+                        (p.get(1) - q.get(1)).into_inner(),
+                    );
+                    assert_eq!(*distance, (dx * dx + dy * dy).sqrt());
+                    adjacency[*a as usize].push(*b as usize);
+                    adjacency[*b as usize].push(*a as usize);
+                }
+                let mut stack = vec![0];
+                while let Some(i) = stack.pop() {
+                    if !core::mem::replace(&mut reached[i], true) {
+                        stack.extend(&adjacency[i]);
+                    }
+                }
+                assert!(reached.iter().all(|reached| *reached));
+                let distances: Vec<f32> = edges.iter().map(|edge| edge.2).collect();
+                assert!(distances.windows(2).all(|pair| pair[0] <= pair[1]));
+                assert_eq!(distances, prim_distances(&cloud));
+            }
+        }
+    }
+
+    #[test]
+    fn small_trees_have_trivial_msts() {
+        assert!(KDTree::<f32, P2>::new(&[]).euclidean_mst().is_empty());
+        let point = P2::new2d(1.0, 2.0);
+        assert!(KDTree::new(&[point]).euclidean_mst().is_empty());
+        let tree = KDTree::new(&[P2::new2d(4.0, 6.0), point, point]);
+        let edges = tree.euclidean_mst();
+        assert_eq!(edges[0], (1, 2, 0.0));
+        assert_eq!((edges[1].0, edges[1].2), (0, 5.0));
+    }
+}
@@ -0,0 +1,190 @@
+//! Counts of the neighbours of queries within several radii, in one traversal per query
+
+use alloc::{vec, vec::Vec};
+use core::ops::Range;
+
+use num_traits::Zero;
+use ordered_float::NotNan;
+
+use crate::cell::NodeCell;
+use crate::{point_slice_dist2, KDTree, Point, Radius, Scalar};
+
+/// Returns the number of squared radii of `radii2` smaller than `dist2`,
+/// which is the bin of the distance, the distances beyond all radii being in bin `radii2.len()`
+fn bin<T: Scalar>(radii2: &[NotNan<T>], dist2: NotNan<T>) -> usize {
+    radii2.partition_point(|radius2| *radius2 < dist2)
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Returns, for each radius of `radii`, the number of points within that radius of `query`, inclusive,
+    /// as the lengths of [`Self::radius_search_ordered`] for each radius, but in a single traversal.
+    ///
+    /// The traversal is bounded by the largest radius, and each distance computed is binned
+    /// into the radii by a binary search.
+    /// Subtrees whose distances from the query all fall between the same two consecutive radii,
+    /// as bounded by their cells, are added to that bin at once from the range of their points in the storage,
+    /// so only nodes straddling a radius are visited.
+    /// A point stored at the position of the query is counted.
+    ///
+    /// Panics if a radius is NaN or negative, or if the radii are not sorted in increasing order,
+    /// equal radii being allowed.
+    pub fn multi_radius_count(&self, query: &P, radii: &[T]) -> Vec<usize> {
+        let radii2 = self.internal_radii2(radii);
+        self.count_within_radii(query, &radii2)
+    }
+
+    /// Validates `radii` and converts them to squared radii in the internal units of the tree
+    fn internal_radii2(&self, radii: &[T]) -> Vec<NotNan<T>> {
+        for radius in radii {
+            let radius = NotNan::new(*radius).expect("radii must not be NaN");
+            assert!(radius >= NotNan::zero(), "radii must be positive or zero");
+        }
+        assert!(
+            radii.windows(2).all(|pair| pair[0] <= pair[1]),
+            "radii must be sorted in increasing order"
+        );
+        radii
+            .iter()
+            .map(|radius| self.internal_radius2(Radius::new(*radius)))
+            .collect()
+    }
+
+    /// Counts the points within each of the internal squared radii `radii2` of `query`
+    fn count_within_radii(&self, query: &P, radii2: &[NotNan<T>]) -> Vec<usize> {
+        // the last bin holds the points beyond all radii, which are not counted
+        let mut bins = vec![0; radii2.len() + 1];
+        if !radii2.is_empty() && !self.is_empty() {
+            let query = self.query_coordinates(query);
+            let mut cell = NodeCell::of_root(self);
+            let points = 0..self.len() as u32;
+            self.recurse_radii(&query, radii2, 0, points, &mut cell, &mut bins);
+        }
+        bins.pop();
+        let mut count = 0;
+        for bin in &mut bins {
+            count += *bin;
+            *bin = count;
+        }
+        bins
+    }
+
+    fn recurse_radii(
+        &self,
+        query: &[NotNan<T>],
+        radii2: &[NotNan<T>],
+        node: usize,
+        points: Range<u32>,
+        cell: &mut NodeCell<T>,
+        bins: &mut [usize],
+    ) {
+        if points.is_empty() {
+            return;
+        }
+        let (min_dist2, max_dist2) = cell.dist2_range(query, self.scale);
+        let nearest_bin = bin(radii2, min_dist2);
+        if nearest_bin == radii2.len() {
+            return;
+        }
+        if nearest_bin == bin(radii2, max_dist2) {
+            bins[nearest_bin] += points.len();
+            return;
+        }
+        self.nodes[node].dispatch_on_type(
+            (cell, bins, points),
+            |(cell, bins, points), split_dim, split_val, right_child| {
+                let dim = split_dim as usize;
+                let parent = cell.extent(dim);
+                let right_child = right_child as usize;
+                let middle = self.first_internal_index(right_child);
+                cell.set_child(dim, parent, split_val, self.drift, true);
+                let left_points = points.start..middle;
+                self.recurse_radii(query, radii2, node + 1, left_points, cell, bins);
+                cell.set_child(dim, parent, split_val, self.drift, false);
+                let right_points = middle..points.end;
+                self.recurse_radii(query, radii2, right_child, right_points, cell, bins);
+                cell.set_extent(dim, parent);
+            },
+            |(_, bins, points), _, _| {
+                let dim = P::DIM as usize;
+                for internal_index in points {
+                    let start = internal_index as usize * dim;
+                    let point = &self.points[start..start + dim];
+                    bins[bin(radii2, point_slice_dist2::<T, P>(point, query, self.scale))] += 1;
+                }
+            },
+        )
+    }
+}
+
+impl<T: Scalar + Send + Sync, P: Point<T> + Send + Sync> KDTree<T, P> {
+    /// Returns [`Self::multi_radius_count`] for each of `queries`, in the order of `queries`.
+    ///
+    /// The radii are validated once.
+    /// If the `rayon` feature is enabled, queries are processed in parallel.
+    ///
+    /// Panics if a radius is NaN or negative, or if the radii are not sorted in increasing order.
+    pub fn multi_radius_count_batch(&self, queries: &[P], radii: &[T]) -> Vec<Vec<usize>> {
+        let radii2 = self.internal_radii2(radii);
+        self.map_indices(queries.len(), |index| {
+            self.count_within_radii(&queries[index], &radii2)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rng::SplitMix64;
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn multi_radius_counts_match_radius_searches() {
+        let mut rng = SplitMix64::new(212);
+        // integer coordinates put many points exactly on the radii
+        let mut cloud: Vec<P2> = (0..2000)
+            .map(|_| P2::new2d(rng.below(60) as f32, rng.below(60) as f32))
+            .collect();
+        cloud.extend(random_point_cloud::<2>(1000));
+        let mut tree = KDTree::new_with_bucket_size(&cloud, 4);
+        let radii = [0.0, 1.0, 2.0, 2.0, 4.0, 8.0, 16.0, f32::INFINITY];
+        for round in 0..2 {
+            let mut queries: Vec<P2> = random_point_cloud(30);
+            queries.extend_from_slice(&cloud[..30]);
+            queries.push(P2::new2d(1e4, 1e4));
+            for query in &queries {
+                let expected: Vec<usize> = radii
+                    .iter()
+                    .map(|radius| {
+                        tree.radius_search_ordered(query, Radius::new(*radius))
+                            .len()
+                    })
+                    .collect();
+                assert_eq!(tree.multi_radius_count(query, &radii), expected);
+            }
+            assert_eq!(
+                tree.multi_radius_count_batch(&queries, &radii),
+                queries
+                    .iter()
+                    .map(|query| tree.multi_radius_count(query, &radii))
+                    .collect::<Vec<_>>()
+            );
+            if round == 0 {
+                // points moved across their splits
+                for index in (0..3000).step_by(5) {
+                    cloud[index] = cloud[index] * 0.7;
+                    tree.update_point(index as u32, cloud[index]);
+                }
+            }
+        }
+        assert!(tree.multi_radius_count(&cloud[0], &[]).is_empty());
+        let empty = KDTree::<f32, P2>::new(&[]);
+        assert_eq!(empty.multi_radius_count(&cloud[0], &[1.0, 2.0]), [0, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "radii must be sorted in increasing order")]
+    fn unsorted_radii_panic() {
+        let tree = KDTree::new(&[P2::new2d(0.0, 0.0), P2::new2d(1.0, 0.0)]);
+        tree.multi_radius_count(&P2::new2d(0.0, 0.0), &[1.0, 3.0, 2.0]);
+    }
+}
@@ -0,0 +1,173 @@
+//! Nearest-neighbour queries warm-started by a previous answer
+
+use ordered_float::NotNan;
+
+use crate::infinite::HasInfinite;
+use crate::internal_parameters::InternalParameters;
+use crate::{
+    point_slice_dist2, InternalNeighbour, KDTree, Neighbour, Parameters, Point, QueryPoint, Scalar,
+};
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Finds the nearest neighbour of `query`, with user-provided parameters,
+    /// as `knn_advanced(1, ..)` does, but without allocating.
+    ///
+    /// The search keeps a single candidate, and the neighbour is returned by value.
+    /// Returns `None` if the tree is empty or no point is within `parameters.max_radius`,
+    /// unless `parameters.on_insufficient` completes the search, see [`InsufficientPolicy`](crate::InsufficientPolicy).
+    ///
+    /// Panics in the same cases as [`Self::knn_advanced`], for example if `query` has a NaN coordinate.
+    pub fn nearest<Q: QueryPoint<T> + ?Sized>(
+        &self,
+        query: &Q,
+        parameters: &Parameters<T>,
+    ) -> Option<Neighbour<T, P>> {
+        let query = self.query_coordinates(query);
+        if self.is_empty() {
+            return None;
+        }
+        let internal_parameters = InternalParameters::new(parameters, self.scale);
+        let mut nearest = self.nearest_seeded(&query, None, &internal_parameters);
+        self.complete_insufficient_or_panic(&mut nearest, 1, &query, &internal_parameters);
+        (nearest.dist2 != NotNan::infinite()).then(|| self.externalise_neighbour(nearest))
+    }
+
+    /// Finds the nearest neighbour of `query`, starting from the point of index `hint_index`
+    /// in the original point cloud, with user-provided parameters.
+    ///
+    /// The distance to the hinted point bounds the search from the start, so if it is still the nearest
+    /// point or close to it, for example from the previous frame of a tracking, most of the tree is pruned.
+    /// The result is the same as without hint, except that among several nearest points at the same distance,
+    /// the hinted one is preferred.
+    /// A hint out of range, or excluded by `parameters`, is ignored.
+    /// Returns `None` if no point is within `parameters.max_radius`.
+    pub fn nearest_with_hint(
+        &self,
+        query: &P,
+        hint_index: u32,
+        parameters: &Parameters<T>,
+    ) -> Option<Neighbour<T, P>> {
+        let query = self.query_coordinates(query);
+        let hint = ((hint_index as usize) < self.len()).then(|| self.internalise_index(hint_index));
+        let nearest = self.nearest_seeded(
+            &query,
+            hint,
+            &InternalParameters::new(parameters, self.scale),
+        );
+        (nearest.dist2 != NotNan::infinite()).then(|| self.externalise_neighbour(nearest))
+    }
+
+    /// Returns the nearest neighbour of `query`, the search being bounded from the start
+    /// by the point of internal index `hint` if it is accepted by `internal_parameters`
+    ///
+    /// The returned squared distance is infinite if there is no neighbour.
+    pub(crate) fn nearest_seeded(
+        &self,
+        query: &[NotNan<T>],
+        hint: Option<u32>,
+        internal_parameters: &InternalParameters<T>,
+    ) -> InternalNeighbour<T> {
+        let mut nearest = InternalNeighbour::default();
+        if let Some(index) = hint {
+            let start = index as usize * P::DIM as usize;
+            let point = &self.points[start..start + P::DIM as usize];
+            let dist2 = point_slice_dist2::<T, P>(query, point, self.scale);
+            if internal_parameters.accepts(dist2, self.indices[index as usize]) {
+                nearest = InternalNeighbour { index, dist2 };
+            }
+        }
+        self.knn_internal(&mut nearest, query, internal_parameters);
+        nearest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn nearest_with_hint_matches_unhinted_search() {
+        let cloud: Vec<P2> = random_point_cloud(2000);
+        let tree = KDTree::new(&cloud);
+        let parameters = Parameters::default();
+        for hint in [0, 17, 1999, 2000, u32::MAX] {
+            for _ in 0..20 {
+                let query = random_point();
+                let expected = tree.knn(1, &query)[0];
+                let nearest = tree.nearest_with_hint(&query, hint, &parameters).unwrap();
+                assert_eq!(nearest.dist2, expected.dist2);
+                assert_eq!(nearest.point, cloud[nearest.index as usize]);
+            }
+        }
+        // the exact nearest point is kept as is
+        let query = random_point();
+        let expected = tree.knn(1, &query)[0];
+        assert_eq!(
+            tree.nearest_with_hint(&query, expected.index, &parameters),
+            Some(expected)
+        );
+        // hints excluded by the parameters are ignored
+        let no_self = Parameters {
+            self_match: SelfMatch::ExcludeCoincident,
+            max_radius: 10.0,
+            ..Parameters::default()
+        };
+        let nearest = tree.nearest_with_hint(&cloud[5], 5, &no_self);
+        assert_ne!(nearest.map(|n| n.index), Some(5));
+        assert_eq!(
+            nearest,
+            tree.knn_advanced(1, &cloud[5], CandidateContainer::Linear, &no_self, None)
+                .first()
+                .copied()
+        );
+        let far = Parameters {
+            max_radius: 0.0,
+            ..Parameters::default()
+        };
+        assert_eq!(
+            tree.nearest_with_hint(&P2::new2d(1000.0, 0.0), 3, &far),
+            None
+        );
+    }
+
+    #[test]
+    fn nearest_matches_knn_of_one() {
+        let cloud: Vec<P2> = random_point_cloud(3000);
+        let tree = KDTree::new(&cloud);
+        let mut queries: Vec<P2> = random_point_cloud(50);
+        queries.extend_from_slice(&cloud[..20]);
+        queries.push(P2::new2d(1e3, 1e3));
+        for (max_radius, allow_self_match, epsilon) in [
+            (f32::INFINITY, true, 0.0),
+            (3.0, false, 0.0),
+            (10.0, true, 0.5),
+        ]
+        .into_iter()
+        // strict exact mode rejects any non-zero epsilon
+        .filter(|(_, _, epsilon)| *epsilon == 0.0 || !cfg!(feature = "strict-exact"))
+        {
+            for on_insufficient in [
+                InsufficientPolicy::Truncate,
+                InsufficientPolicy::IgnoreRadius,
+            ] {
+                let parameters = Parameters {
+                    max_radius,
+                    self_match: allow_self_match.into(),
+                    epsilon,
+                    on_insufficient,
+                    ..Parameters::default()
+                };
+                for query in &queries {
+                    let expected = tree
+                        .knn_advanced(1, query, CandidateContainer::Linear, &parameters, None)
+                        .first()
+                        .copied();
+                    assert_eq!(tree.nearest(query, &parameters), expected);
+                }
+            }
+        }
+        let empty = KDTree::<f32, P2>::new(&[]);
+        assert_eq!(empty.nearest(&queries[0], &Parameters::default()), None);
+    }
+}
@@ -0,0 +1,260 @@
+//! Incremental nearest neighbour search, yielding neighbours by increasing distance as soon as they are final
+
+use alloc::collections::BinaryHeap;
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+
+use num_traits::{clamp_max, clamp_min, Float, Zero};
+use ordered_float::NotNan;
+
+use crate::dim_buffer::DimBuffer;
+use crate::{point_slice_dist2, update_rd, InternalNeighbour, KDTree, Neighbour, Point, Scalar};
+
+/// An iterator over the points of a tree by increasing distance to a query, see [`KDTree::nearest_iter`]
+///
+/// The search is best-first: pending nodes are kept in a priority queue by the squared distance
+/// from the query to their cell, and reached points in a priority queue by their squared distance.
+/// A point is yielded once no pending node is closer,
+/// so that no later point can be closer than it.
+pub struct NearestIter<'a, T: Scalar, P: Point<T>> {
+    tree: &'a KDTree<T, P>,
+    query: DimBuffer<T>,
+    /// the nodes to search, by increasing lower bound of the squared distance to their points,
+    /// with the slot of their offsets in `offsets`
+    pending: BinaryHeap<Reverse<(NotNan<T>, u32, u32)>>,
+    /// per slot, the offsets from the query to the cell of a pending node along each dimension
+    offsets: Vec<NotNan<T>>,
+    free_slots: Vec<u32>,
+    /// the points reached but not yet yielded, by increasing squared distance
+    candidates: BinaryHeap<Reverse<InternalNeighbour<T>>>,
+}
+
+impl<'a, T: Scalar, P: Point<T>> NearestIter<'a, T, P> {
+    fn new(tree: &'a KDTree<T, P>, query: &P) -> Self {
+        let mut pending = BinaryHeap::new();
+        pending.push(Reverse((NotNan::zero(), 0, 0)));
+        NearestIter {
+            tree,
+            query: tree.query_coordinates(query),
+            pending,
+            offsets: alloc::vec![NotNan::zero(); P::DIM as usize],
+            free_slots: Vec::new(),
+            candidates: BinaryHeap::new(),
+        }
+    }
+
+    /// Returns a slot holding a copy of the offsets of `slot`
+    fn copy_slot(&mut self, slot: u32) -> u32 {
+        let dim = P::DIM as usize;
+        let start = slot as usize * dim;
+        match self.free_slots.pop() {
+            Some(copy) => {
+                self.offsets
+                    .copy_within(start..start + dim, copy as usize * dim);
+                copy
+            }
+            None => {
+                self.offsets.extend_from_within(start..start + dim);
+                (self.offsets.len() / dim - 1) as u32
+            }
+        }
+    }
+
+    /// Descends from `node` to its nearest leaf, queuing the far children on the way,
+    /// and adds the points of the leaf to the candidates
+    fn search_node(&mut self, rd: NotNan<T>, mut node: u32, slot: u32) {
+        let tree = self.tree;
+        let dim = P::DIM as usize;
+        let zero = NotNan::<T>::zero();
+        while let Some((split_dim, split_val, right_child)) = tree.nodes[node as usize]
+            .dispatch_on_type(
+                (),
+                |_, split_dim, split_val, right_child| Some((split_dim, split_val, right_child)),
+                |_, _, _| None,
+            )
+        {
+            let split_dim = split_dim as usize;
+            let old_off = self.offsets[slot as usize * dim + split_dim];
+            let new_off = (self.query[split_dim] - split_val) * tree.scale;
+            // points that drifted across the split can be closer than the split plane
+            let far_off = if tree.scaled_drift == zero {
+                new_off
+            } else if new_off > zero {
+                clamp_min(new_off - tree.scaled_drift, zero)
+            } else {
+                clamp_max(new_off + tree.scaled_drift, zero)
+            };
+            let (near, far) = if new_off > zero {
+                (right_child, node + 1)
+            } else {
+                (node + 1, right_child)
+            };
+            let far_slot = self.copy_slot(slot);
+            self.offsets[far_slot as usize * dim + split_dim] = far_off;
+            let far_rd = update_rd(rd, old_off, far_off);
+            self.pending.push(Reverse((far_rd, far, far_slot)));
+            node = near;
+        }
+        self.free_slots.push(slot);
+        let (start, size) = tree.nodes[node as usize].dispatch_on_type(
+            (),
+            |_, _, _, _| unreachable!("the descent stops at a leaf"),
+            |_, bucket_start_index, bucket_size| (bucket_start_index, bucket_size),
+        );
+        for index in start..start + size {
+            let point_start = index as usize * dim;
+            let point = &tree.points[point_start..point_start + dim];
+            let dist2 = point_slice_dist2::<T, P>(&self.query, point, tree.scale);
+            self.candidates
+                .push(Reverse(InternalNeighbour { index, dist2 }));
+        }
+    }
+
+    /// Returns the next neighbour with its internal index and scaled squared distance
+    fn next_internal(&mut self) -> Option<InternalNeighbour<T>> {
+        loop {
+            let bound = self.pending.peek().map(|Reverse((rd, _, _))| *rd);
+            if let Some(Reverse(candidate)) = self.candidates.peek() {
+                if bound.map_or(true, |bound| candidate.dist2 <= bound) {
+                    let Reverse(candidate) = self.candidates.pop()?;
+                    return Some(candidate);
+                }
+            }
+            let Reverse((rd, node, slot)) = self.pending.pop()?;
+            self.search_node(rd, node, slot);
+        }
+    }
+}
+
+impl<'a, T: Scalar, P: Point<T>> Iterator for NearestIter<'a, T, P> {
+    type Item = Neighbour<T, P>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let candidate = self.next_internal()?;
+        Some(self.tree.externalise_neighbour(candidate))
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Iterates over all the points of the tree by increasing distance to `query`.
+    ///
+    /// Each neighbour is yielded as soon as no unvisited part of the tree may hold a closer point,
+    /// so that taking the first `k` neighbours only visits the nodes an exact `k`-nearest search would,
+    /// up to the order of the visits.
+    /// Points at equal distances are yielded in an unspecified order.
+    pub fn nearest_iter(&self, query: &P) -> NearestIter<'_, T, P> {
+        NearestIter::new(self, query)
+    }
+}
+
+impl<T: Scalar + Float, P: Point<T>> KDTree<T, P> {
+    /// Finds the nearest neighbours of `query` by increasing distance,
+    /// until the distance to the next one exceeds the distance to the previous one by more than `max_gap`.
+    ///
+    /// The nearest neighbour is always returned if the tree is not empty.
+    /// The search stops as soon as the gap is found, using [`Self::nearest_iter`].
+    ///
+    /// Panics if `max_gap` is negative or NaN.
+    pub fn knn_until_gap(&self, query: &P, max_gap: T) -> Vec<Neighbour<T, P>> {
+        assert!(max_gap >= T::zero(), "max_gap must be positive or zero");
+        // the gaps are compared in the internal units, as unscaled distances may underflow
+        let max_gap = max_gap * self.scale.into_inner();
+        let mut neighbours = Vec::new();
+        let mut iter = self.nearest_iter(query);
+        let mut previous_dist = None;
+        while let Some(neighbour) = iter.next_internal() {
+            let dist = Float::sqrt(neighbour.dist2.into_inner());
+            if previous_dist.map_or(false, |previous| dist - previous > max_gap) {
+                break;
+            }
+            previous_dist = Some(dist);
+            neighbours.push(self.externalise_neighbour(neighbour));
+        }
+        neighbours
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rng::SplitMix64;
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn sorted_brute_force_dist2s(cloud: &[P2], query: &P2) -> Vec<f32> {
+        let mut dist2s: Vec<f32> = cloud
+            .iter()
+            .map(|p| {
+                let dx = p.get(0) - query.get(0);
+                let dy = p.get(1) - query.get(1);
+                (dx * dx + dy * dy).into_inner()
+            })
+            .collect();
+        dist2s.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+        dist2s
+    }
+
+    #[test]
+    fn nearest_iter_matches_sorted_brute_force() {
+        let mut rng = SplitMix64::new(0x1234);
+        for trial in 0..30 {
+            let count = rng.below(500) as u32;
+            let bucket_size = 2 + rng.below(15) as u32;
+            let mut cloud: Vec<P2> = random_point_cloud(count);
+            let mut tree = KDTree::new_with_bucket_size(&cloud, bucket_size);
+            if trial % 3 == 0 && count > 0 {
+                // moved points make the search widen its bounds by the drift
+                for _ in 0..10 {
+                    let index = rng.below(count as u64) as u32;
+                    cloud[index as usize] = random_point();
+                    tree.update_point(index, cloud[index as usize]);
+                }
+            }
+            let query = random_point();
+            let expected = sorted_brute_force_dist2s(&cloud, &query);
+            let found: Vec<_> = tree.nearest_iter(&query).collect();
+            assert_eq!(found.len(), cloud.len());
+            let mut indices: Vec<_> = found.iter().map(|n| n.index).collect();
+            indices.sort_unstable();
+            assert_eq!(indices, (0..count).collect::<Vec<_>>());
+            for (neighbour, expected) in found.iter().zip(&expected) {
+                assert_eq!(*neighbour.dist2, *expected);
+                assert_eq!(neighbour.point, cloud[neighbour.index as usize]);
+            }
+        }
+    }
+
+    #[test]
+    fn knn_until_gap_stops_at_the_first_gap() {
+        #[rustfmt::skip]
+        let cloud = vec![
+            P2::new2d(1.0, 0.0), P2::new2d(1.5, 0.0), P2::new2d(0.0, 2.0),
+            P2::new2d(10.0, 0.0), P2::new2d(10.5, 0.0),
+        ];
+        let tree = KDTree::new(&cloud);
+        let query = P2::new2d(0.0, 0.0);
+        let indices: Vec<_> = tree
+            .knn_until_gap(&query, 1.0)
+            .iter()
+            .map(|n| n.index)
+            .collect();
+        assert_eq!(indices, vec![0, 1, 2]);
+        assert_eq!(tree.knn_until_gap(&query, 0.1).len(), 1);
+        assert_eq!(tree.knn_until_gap(&query, 100.0).len(), 5);
+        assert!(KDTree::<f32, P2>::new(&[])
+            .knn_until_gap(&query, 1.0)
+            .is_empty());
+    }
+
+    #[test]
+    fn knn_until_gap_does_not_depend_on_the_magnitude_of_coordinates() {
+        let cloud: Vec<P2> = random_point_cloud(400);
+        let query = random_point();
+        for factor in [1e-30, 1e20] {
+            let scaled: Vec<P2> = cloud.iter().map(|p| *p * factor).collect();
+            let tree = KDTree::new(&scaled);
+            let query = query * factor;
+            assert_eq!(tree.knn_until_gap(&query, 100.0 * factor).len(), 400);
+            assert_eq!(tree.knn_until_gap(&query, 0.0).len(), 1);
+        }
+    }
+}
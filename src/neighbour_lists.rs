@@ -0,0 +1,236 @@
+//! The neighbours within a radius of each point of a tree, as lists in compressed sparse row format
+
+use alloc::vec::Vec;
+
+use crate::distance_scale::unscale_dist2;
+use crate::internal_parameters::InternalParameters;
+use crate::radius_search::AllCandidates;
+use crate::{CsrGraph, KDTree, Parameters, Point, Scalar};
+
+/// Number of consecutive points whose lists are built in sequence
+const LIST_CHUNK_SIZE: usize = 1024;
+
+impl<T: Scalar + Send + Sync, P: Point<T> + Send + Sync> KDTree<T, P> {
+    /// Lists the neighbours within `radius`, inclusive, of each point of the tree.
+    ///
+    /// Nodes are the indices of the points in the original point cloud,
+    /// and weights are the squared distances between points.
+    /// A point is excluded from its own list by index, so that its duplicates are listed.
+    /// If `symmetric` is true, point `i` lists point `j` if and only if `j` lists `i`.
+    /// This holds exactly: the squared distance between two stored points is bitwise the same
+    /// in both directions, and radius searches are exact.
+    /// If `symmetric` is false, each pair is only listed by its point of smaller index.
+    ///
+    /// The lists are searched twice, once to count the neighbours and size the arrays,
+    /// and once to fill them, so that no memory is needed beyond the result.
+    /// If the `rayon` feature is enabled, both passes run in parallel,
+    /// each chunk of points writing to its own part of the arrays.
+    ///
+    /// Panics if `radius` is negative or NaN, or if there are more than `u32::MAX` entries.
+    pub fn neighbour_lists(&self, radius: T, symmetric: bool) -> CsrGraph<T> {
+        let internal_parameters = self.list_parameters(radius);
+        let chunk_count = (self.len() + LIST_CHUNK_SIZE - 1) / LIST_CHUNK_SIZE;
+        let chunk_range =
+            |chunk: usize| chunk * LIST_CHUNK_SIZE..((chunk + 1) * LIST_CHUNK_SIZE).min(self.len());
+        let init = || (AllCandidates(Vec::new()), Vec::new());
+        let counts = self.map_indices_with(chunk_count, init, |(candidates, list), chunk| {
+            chunk_range(chunk)
+                .map(|source| {
+                    let source = source as u32;
+                    self.neighbour_list(source, symmetric, &internal_parameters, candidates, list);
+                    list.len()
+                })
+                .collect::<Vec<_>>()
+        });
+        let mut offsets = Vec::with_capacity(self.len() + 1);
+        offsets.push(0u32);
+        let mut edge_count = 0usize;
+        for count in counts.into_iter().flatten() {
+            edge_count += count;
+            offsets.push(u32::try_from(edge_count).expect("there are at most u32::MAX entries"));
+        }
+        let mut targets = alloc::vec![0; edge_count];
+        let mut weights = alloc::vec![T::zero(); edge_count];
+        // split the arrays into the disjoint parts of each chunk
+        let mut parts = Vec::with_capacity(chunk_count);
+        let (mut targets_rest, mut weights_rest) = (&mut targets[..], &mut weights[..]);
+        for chunk in 0..chunk_count {
+            let range = chunk_range(chunk);
+            let size = (offsets[range.end] - offsets[range.start]) as usize;
+            let (chunk_targets, rest) = core::mem::take(&mut targets_rest).split_at_mut(size);
+            targets_rest = rest;
+            let (chunk_weights, rest) = core::mem::take(&mut weights_rest).split_at_mut(size);
+            weights_rest = rest;
+            parts.push((range, chunk_targets, chunk_weights));
+        }
+        self.for_each_with(
+            parts,
+            init,
+            |(candidates, list), (range, targets, weights)| {
+                let mut position = 0;
+                for source in range {
+                    let source = source as u32;
+                    self.neighbour_list(source, symmetric, &internal_parameters, candidates, list);
+                    for (target, dist2) in list.iter() {
+                        targets[position] = *target;
+                        weights[position] = *dist2;
+                        position += 1;
+                    }
+                }
+            },
+        );
+        CsrGraph {
+            offsets,
+            targets,
+            weights,
+        }
+    }
+
+    /// Lists the neighbours within `radius` of the points of the tree, see [`Self::neighbour_lists`],
+    /// by chunks of `chunk_size` consecutive points passed to `f` as they are built.
+    ///
+    /// `f` is called in order with the index of the first point of the chunk and its lists,
+    /// node `i` of the chunk being the point of index `first + i`,
+    /// so that only the lists of a chunk are in memory at once.
+    /// If the `rayon` feature is enabled, the lists of a chunk are searched in parallel.
+    ///
+    /// Panics if `radius` is negative or NaN, or if `chunk_size` is zero.
+    pub fn neighbour_lists_by_chunk(
+        &self,
+        radius: T,
+        symmetric: bool,
+        chunk_size: usize,
+        mut f: impl FnMut(u32, CsrGraph<T>),
+    ) {
+        assert!(chunk_size > 0, "chunk_size must be strictly positive");
+        let internal_parameters = self.list_parameters(radius);
+        for first in (0..self.len()).step_by(chunk_size) {
+            let size = chunk_size.min(self.len() - first);
+            let lists = self.map_indices_with(
+                size,
+                || (AllCandidates(Vec::new()), Vec::new()),
+                |(candidates, list), offset| {
+                    let source = (first + offset) as u32;
+                    self.neighbour_list(source, symmetric, &internal_parameters, candidates, list);
+                    list.clone()
+                },
+            );
+            let edges: Vec<_> = lists
+                .iter()
+                .enumerate()
+                .flat_map(|(node, list)| {
+                    list.iter()
+                        .map(move |(target, dist2)| (node as u32, *target, *dist2))
+                })
+                .collect();
+            f(first as u32, CsrGraph::from_sorted_edges(size, &edges));
+        }
+    }
+
+    /// Converts `radius` to the parameters of the searches of the lists
+    fn list_parameters(&self, radius: T) -> InternalParameters<T> {
+        assert!(radius >= T::zero(), "radius must be positive or zero");
+        let parameters = Parameters {
+            max_radius: radius,
+            ..Parameters::default()
+        };
+        InternalParameters::new(&parameters, self.scale)
+    }
+
+    /// Fills `list` with the neighbours of the point of external index `source`,
+    /// as their external index and squared distance sorted by index
+    fn neighbour_list(
+        &self,
+        source: u32,
+        symmetric: bool,
+        internal_parameters: &InternalParameters<T>,
+        candidates: &mut AllCandidates<T>,
+        list: &mut Vec<(u32, T)>,
+    ) {
+        let internal_source = self.internalise_index(source);
+        let dim = P::DIM as usize;
+        let start = internal_source as usize * dim;
+        candidates.0.clear();
+        self.knn_internal(
+            candidates,
+            &self.points[start..start + dim],
+            internal_parameters,
+        );
+        list.clear();
+        list.extend(
+            candidates
+                .0
+                .iter()
+                .filter(|neighbour| neighbour.index != internal_source)
+                .map(|neighbour| {
+                    let dist2 = unscale_dist2(neighbour.dist2, self.scale).into_inner();
+                    (self.externalise_index(neighbour.index), dist2)
+                })
+                .filter(|(target, _)| symmetric || *target > source),
+        );
+        list.sort_unstable_by_key(|(target, _)| *target);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn neighbour_lists_match_brute_force() {
+        let mut cloud: Vec<P2> = random_point_cloud(3000);
+        // duplicates are listed by each other
+        for i in 0..100 {
+            cloud.push(cloud[i % 10]);
+        }
+        let tree = KDTree::new(&cloud);
+        for radius in [0.0, 1.0, 4.0] {
+            let symmetric = tree.neighbour_lists(radius, true);
+            let half = tree.neighbour_lists(radius, false);
+            assert_eq!(symmetric.node_count(), cloud.len());
+            assert_eq!(symmetric.edge_count(), 2 * half.edge_count());
+            for (i, p) in cloud.iter().enumerate() {
+                let expected: Vec<_> = (0..cloud.len() as u32)
+                    .filter(|j| {
+                        let q = cloud[*j as usize];
+                        let dx = p.get(0) - q.get(0);
+                        let dy = p.get(1) - q.get(1);
+                        *j != i as u32 && (dx * dx + dy * dy).into_inner() <= radius * radius
+                    })
+                    .collect();
+                let (targets, weights) = symmetric.edges(i as u32);
+                assert_eq!(targets, &expected[..]);
+                for (j, weight) in targets.iter().zip(weights) {
+                    assert!(symmetric.contains_edge(*j, i as u32));
+                    assert!(half.contains_edge(i as u32, *j) != half.contains_edge(*j, i as u32));
+                    let (back_targets, back_weights) = symmetric.edges(*j);
+                    let back = back_targets.binary_search(&(i as u32)).unwrap();
+                    assert_eq!(back_weights[back], *weight);
+                }
+                assert!(half.edges(i as u32).0.iter().all(|j| *j > i as u32));
+            }
+        }
+    }
+
+    #[test]
+    fn neighbour_lists_by_chunk_concatenate_to_the_lists() {
+        let cloud: Vec<P2> = random_point_cloud(2500);
+        let tree = KDTree::new(&cloud);
+        let expected = tree.neighbour_lists(2.0, true);
+        for chunk_size in [1, 700, 5000] {
+            let mut next = 0;
+            tree.neighbour_lists_by_chunk(2.0, true, chunk_size, |first, lists| {
+                assert_eq!(first, next);
+                for node in 0..lists.node_count() as u32 {
+                    assert_eq!(lists.edges(node), expected.edges(first + node));
+                }
+                next += lists.node_count() as u32;
+            });
+            assert_eq!(next, cloud.len() as u32);
+        }
+        let empty = KDTree::<f32, P2>::new(&[]).neighbour_lists(1.0, true);
+        assert_eq!(empty.node_count(), 0);
+        assert_eq!(empty.edge_count(), 0);
+    }
+}
@@ -26,7 +26,8 @@ fn create_dim_child_bucket_size<T: Scalar, P: Point<T>>(
 /// If split node, it holds a split dimension and the split value along this dimension,
 /// and the index of its right child (its left child is its own index + 1).
 /// If leaf node, it holds the start index of the bucket and the number of elements in the bucket.
-#[derive(Clone)]
+/// The split dimension shares its word with the child index or bucket size, so that a node
+/// takes two words, 8 bytes for `f32` coordinates, whatever the dimension of the points.
 pub(crate) struct Node<T: Scalar, P: Point<T>> {
     /// cut dimension for split nodes (dim_bit_count lsb), index of right node or number of points in bucket (rest).
     /// Note that left index is current + 1.
@@ -79,6 +80,40 @@ impl<T: Scalar, P: Point<T>> Node<T, P> {
         }
     }
 }
+impl<T: Scalar, P: Point<T>> Clone for Node<T, P> {
+    fn clone(&self) -> Self {
+        Node {
+            dim_child_bucket_size: self.dim_child_bucket_size,
+            split_val_or_bucket_start_index: self.split_val_or_bucket_start_index,
+            phantom: PhantomData,
+        }
+    }
+}
+impl<T: Scalar, P: Point<T>> PartialEq for Node<T, P> {
+    fn eq(&self, other: &Self) -> bool {
+        // compare the union through its active field
+        self.dim_child_bucket_size == other.dim_child_bucket_size
+            && self.dispatch_on_type(
+                (),
+                |_, _, split_val, _| {
+                    other.dispatch_on_type(
+                        (),
+                        |_, _, other_split_val, _| split_val == other_split_val,
+                        |_, _, _| false,
+                    )
+                },
+                |_, bucket_start_index, _| {
+                    other.dispatch_on_type(
+                        (),
+                        |_, _, _, _| false,
+                        |_, other_bucket_start_index, _| {
+                            bucket_start_index == other_bucket_start_index
+                        },
+                    )
+                },
+            )
+    }
+}
 impl<T: Scalar, P: Point<T>> core::fmt::Debug for Node<T, P> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         self.dispatch_on_type(
@@ -103,13 +138,16 @@ impl<T: Scalar, P: Point<T>> core::fmt::Debug for Node<T, P> {
 #[cfg(test)]
 mod tests {
     extern crate std;
-    use crate::simple_point::P2;
+    use crate::simple_point::{P2d, P2, P4};
     use crate::*;
     use std::dbg;
 
     #[test]
     fn sizes() {
         dbg!(std::mem::size_of::<Node<f32, P2>>());
+        assert_eq!(std::mem::size_of::<Node<f32, P2>>(), 8);
+        assert_eq!(std::mem::size_of::<Node<f32, P4>>(), 8);
+        assert_eq!(std::mem::size_of::<Node<f64, P2d>>(), 16);
     }
 
     #[test]
@@ -0,0 +1,174 @@
+//! User data attached to the nodes of a tree, migrated across the operations renumbering the nodes
+
+use alloc::vec::Vec;
+use core::ops::{Index, IndexMut};
+
+use crate::{KDTree, NodeRef, Point, Scalar};
+
+/// A value per node of a tree, indexed by the id of the node, see [`KDTree::annotate`]
+///
+/// Node ids are the positions of the nodes in depth-first order, see [`NodeRef::id`].
+/// They are kept by queries and by the changes that move points without changing the structure,
+/// [`KDTree::update_point`] and [`KDTree::refit`].
+/// [`KDTree::optimize_for`] and [`KDTree::rebuild`] renumber the nodes and return a [`RelayoutMap`]
+/// to migrate the annotations with [`Self::remap`].
+/// The other changes of structure, such as [`KDTree::apply_batch`], renumber the nodes without a map,
+/// and the annotations must then be computed again.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NodeAnnotations<A> {
+    values: Vec<A>,
+}
+
+impl<A> NodeAnnotations<A> {
+    /// Returns the number of nodes annotated.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Returns whether no node is annotated.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Returns the annotation of each node, by node id.
+    pub fn as_slice(&self) -> &[A] {
+        &self.values
+    }
+
+    /// Returns the annotation of `node`.
+    ///
+    /// Panics if the node is not annotated, as when it is a node of another tree.
+    pub fn get<T: Scalar, P: Point<T>>(&self, node: NodeRef<'_, T, P>) -> &A {
+        &self.values[node.id()]
+    }
+}
+
+impl<A: Default + Clone> NodeAnnotations<A> {
+    /// Returns the annotations of the nodes after the relayout described by `map`:
+    /// each new node gets the annotation of its source node, and the nodes without a source the default value.
+    ///
+    /// Panics if `map` does not start from a tree of [`Self::len`] nodes.
+    pub fn remap(&self, map: &RelayoutMap) -> Self {
+        assert_eq!(
+            self.values.len(),
+            map.old_node_count,
+            "the relayout does not start from the annotated tree"
+        );
+        let values = map
+            .sources
+            .iter()
+            .map(|source| source.map_or_else(A::default, |old| self.values[old as usize].clone()))
+            .collect();
+        NodeAnnotations { values }
+    }
+}
+
+impl<A> Index<usize> for NodeAnnotations<A> {
+    type Output = A;
+
+    fn index(&self, node: usize) -> &A {
+        &self.values[node]
+    }
+}
+
+impl<A> IndexMut<usize> for NodeAnnotations<A> {
+    fn index_mut(&mut self, node: usize) -> &mut A {
+        &mut self.values[node]
+    }
+}
+
+/// The correspondence between the nodes of a tree before and after an operation renumbering them,
+/// see [`NodeAnnotations::remap`]
+///
+/// The source of a new node is the old node below which were exactly the same points, if any.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RelayoutMap {
+    /// old node id of each new node, by new node id
+    sources: Vec<Option<u32>>,
+    old_node_count: usize,
+}
+
+impl RelayoutMap {
+    /// Creates the map of a relayout of a tree of `old_node_count` nodes, `sources` holding the source of each new node
+    pub(crate) fn new(sources: Vec<Option<u32>>, old_node_count: usize) -> Self {
+        RelayoutMap {
+            sources,
+            old_node_count,
+        }
+    }
+
+    /// Returns the number of nodes before the relayout.
+    pub fn old_node_count(&self) -> usize {
+        self.old_node_count
+    }
+
+    /// Returns the number of nodes after the relayout.
+    pub fn new_node_count(&self) -> usize {
+        self.sources.len()
+    }
+
+    /// Returns the id before the relayout of the source of the node of id `new_node`, if it has one.
+    ///
+    /// Panics if `new_node` is not smaller than [`Self::new_node_count`].
+    pub fn source(&self, new_node: usize) -> Option<usize> {
+        self.sources[new_node].map(|old| old as usize)
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Returns `init` of each node of the tree, by node id, as a side table to attach data to the nodes,
+    /// for example estimates per subtree.
+    ///
+    /// The nodes are visited in depth-first order, that of their ids.
+    /// See [`NodeAnnotations`] for the operations keeping or renumbering the ids.
+    pub fn annotate<A>(&self, init: impl Fn(NodeRef<'_, T, P>) -> A) -> NodeAnnotations<A> {
+        let mut values = Vec::with_capacity(self.nodes.len());
+        let mut stack = alloc::vec![self.root()];
+        while let Some(node) = stack.pop() {
+            debug_assert_eq!(node.id(), values.len());
+            if let (Some(left), Some(right)) = (node.left(), node.right()) {
+                stack.push(right);
+                stack.push(left);
+            }
+            values.push(init(node));
+        }
+        NodeAnnotations { values }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn annotations_follow_the_relayouts() {
+        let cloud: Vec<P2> = random_point_cloud(5000);
+        let mut tree = KDTree::new(&cloud);
+        let counts = tree.annotate(|node| node.subtree_len());
+        assert_eq!(counts.len(), tree.node_count());
+        assert_eq!(counts[0], 5000);
+        assert_eq!(*counts.get(tree.root().left().unwrap()), counts[1]);
+        // queries concentrated near the origin leave most subtrees cold
+        let queries: Vec<P2> = (0..1000)
+            .map(|_| {
+                let point: P2 = random_point();
+                point * 0.05
+            })
+            .collect();
+        let heat = tree.profile(&queries, 4, &Parameters::default());
+        let map = tree.optimize_for(&heat);
+        assert_eq!(map.old_node_count(), counts.len());
+        assert_eq!(map.new_node_count(), tree.node_count());
+        assert!(map.new_node_count() < map.old_node_count());
+        // the nodes keep their points, so the counts are still right
+        let remapped = counts.remap(&map);
+        assert_eq!(remapped, tree.annotate(|node| node.subtree_len()));
+        // a rebuild only keeps the root
+        let map = tree.rebuild();
+        let remapped = remapped.remap(&map);
+        assert_eq!(remapped[0], 5000);
+        assert!(remapped.as_slice()[1..].iter().all(|count| *count == 0));
+        assert_eq!(remapped.len(), tree.node_count());
+    }
+}
@@ -0,0 +1,217 @@
+//! Read-only navigation of the structure of the tree
+
+use ordered_float::NotNan;
+
+use crate::{KDTree, Point, Scalar};
+
+/// A node of a [`KDTree`], to navigate its structure, see [`KDTree::root`]
+///
+/// A node is either a split node, which has two children, or a leaf, which holds points.
+/// Points of a split node with a coordinate along [`Self::split_dim`] smaller than or equal to
+/// [`Self::split_value`] are below its left child, the others below its right child,
+/// unless they moved across the split through [`KDTree::update_point`] or [`KDTree::refit`].
+pub struct NodeRef<'a, T: Scalar, P: Point<T>> {
+    tree: &'a KDTree<T, P>,
+    id: usize,
+    depth: u32,
+}
+
+impl<'a, T: Scalar, P: Point<T>> Clone for NodeRef<'a, T, P> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T: Scalar, P: Point<T>> Copy for NodeRef<'a, T, P> {}
+
+impl<'a, T: Scalar, P: Point<T>> NodeRef<'a, T, P> {
+    /// Returns the id of the node, as used by [`DumpOptions::highlighted`](crate::DumpOptions::highlighted),
+    /// [`QueryTrace`](crate::QueryTrace) and [`NodeAnnotations`](crate::NodeAnnotations),
+    /// which documents the changes of the tree that keep the ids.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Returns the number of ancestors of the node, 0 for the root.
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    /// Returns whether the node is a leaf.
+    pub fn is_leaf(&self) -> bool {
+        self.tree.nodes[self.id].dispatch_on_type((), |_, _, _, _| false, |_, _, _| true)
+    }
+
+    /// Returns the dimension along which the node splits, or `None` for a leaf,
+    /// a dimension of the frame of the tree if it is rotated, see [`KDTree::rotation`].
+    pub fn split_dim(&self) -> Option<u32> {
+        self.tree.nodes[self.id].dispatch_on_type(
+            (),
+            |_, split_dim, _, _| Some(split_dim),
+            |_, _, _| None,
+        )
+    }
+
+    /// Returns the value at which the node splits, or `None` for a leaf.
+    pub fn split_value(&self) -> Option<NotNan<T>> {
+        self.tree.nodes[self.id].dispatch_on_type(
+            (),
+            |_, _, split_val, _| Some(split_val),
+            |_, _, _| None,
+        )
+    }
+
+    /// Returns the left child of the node, or `None` for a leaf.
+    pub fn left(&self) -> Option<Self> {
+        (!self.is_leaf()).then_some(self.child(self.id + 1))
+    }
+
+    /// Returns the right child of the node, or `None` for a leaf.
+    pub fn right(&self) -> Option<Self> {
+        self.tree.nodes[self.id].dispatch_on_type(
+            (),
+            |_, _, _, right_child| Some(self.child(right_child as usize)),
+            |_, _, _| None,
+        )
+    }
+
+    /// Returns the number of points below the node.
+    pub fn subtree_len(&self) -> usize {
+        let (start, end) = self.point_range();
+        end - start
+    }
+
+    /// Iterates over the points below the node and their indices in the original point cloud.
+    ///
+    /// As the tree stores the coordinates and not the points themselves,
+    /// the points are reconstructed by value.
+    pub fn points(&self) -> impl ExactSizeIterator<Item = (u32, P)> + 'a {
+        let tree = self.tree;
+        let (start, end) = self.point_range();
+        (start..end).map(move |internal| {
+            (
+                tree.externalise_index(internal as u32),
+                tree.stored_point(internal as u32),
+            )
+        })
+    }
+
+    fn child(&self, id: usize) -> Self {
+        NodeRef {
+            tree: self.tree,
+            id,
+            depth: self.depth + 1,
+        }
+    }
+
+    /// Returns the range of the internal indices of the points below the node
+    fn point_range(&self) -> (usize, usize) {
+        // the leaves hold consecutive ranges in depth-first order,
+        // so the subtree spans from its leftmost leaf to its rightmost one
+        let mut first = *self;
+        while let Some(left) = first.left() {
+            first = left;
+        }
+        let mut last = *self;
+        while let Some(right) = last.right() {
+            last = right;
+        }
+        let leaf_range = |node: &Self| {
+            self.tree.nodes[node.id].dispatch_on_type(
+                (),
+                |_, _, _, _| unreachable!("the node is a leaf"),
+                |_, start, size| (start as usize, (start + size) as usize),
+            )
+        };
+        (leaf_range(&first).0, leaf_range(&last).1)
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Returns the root node, to navigate the structure of the tree.
+    ///
+    /// The tree of an empty cloud has a single leaf without points.
+    pub fn root(&self) -> NodeRef<'_, T, P> {
+        NodeRef {
+            tree: self,
+            id: 0,
+            depth: 0,
+        }
+    }
+
+    /// Returns the node of id `id`, found by descending from the root
+    ///
+    /// Panics if `id` is not the id of a node.
+    pub(crate) fn node_by_id(&self, id: usize) -> NodeRef<'_, T, P> {
+        assert!(id < self.nodes.len(), "node id out of range");
+        let mut node = self.root();
+        while node.id != id {
+            // the left subtree comes first in depth-first order, up to the right child
+            node = match node.right() {
+                Some(right) if right.id <= id => right,
+                _ => node.left().expect("the node is below a split node"),
+            };
+        }
+        node
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn check_subtree(node: NodeRef<'_, f32, P2>, lower: [f32; 2], upper: [f32; 2]) -> usize {
+        match (node.left(), node.right()) {
+            (Some(left), Some(right)) => {
+                let dim = node.split_dim().unwrap() as usize;
+                let value = *node.split_value().unwrap();
+                assert_eq!(left.depth(), node.depth() + 1);
+                assert_eq!(left.id(), node.id() + 1);
+                let mut left_upper = upper;
+                left_upper[dim] = value;
+                let mut right_lower = lower;
+                right_lower[dim] = value;
+                let count = check_subtree(left, lower, left_upper)
+                    + check_subtree(right, right_lower, upper);
+                assert_eq!(count, node.subtree_len());
+                count
+            }
+            _ => {
+                assert!(node.is_leaf());
+                assert_eq!(node.split_dim(), None);
+                assert_eq!(node.split_value(), None);
+                for (_, point) in node.points() {
+                    for dim in 0..2 {
+                        let value = *point.get(dim as u32);
+                        assert!(lower[dim] <= value && value <= upper[dim]);
+                    }
+                }
+                node.subtree_len()
+            }
+        }
+    }
+
+    #[test]
+    fn navigation_covers_the_tree() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let tree = KDTree::new(&cloud);
+        let root = tree.root();
+        assert_eq!(root.id(), 0);
+        assert_eq!(root.subtree_len(), cloud.len());
+        let count = check_subtree(root, [f32::NEG_INFINITY; 2], [f32::INFINITY; 2]);
+        assert_eq!(count, cloud.len());
+        let mut indices: Vec<u32> = root
+            .points()
+            .map(|(index, point)| {
+                assert_eq!(point, cloud[index as usize]);
+                index
+            })
+            .collect();
+        indices.sort_unstable();
+        assert_eq!(indices, (0..1000).collect::<Vec<_>>());
+        let empty = KDTree::<f32, P2>::new(&[]);
+        assert!(empty.root().is_leaf());
+        assert_eq!(empty.root().subtree_len(), 0);
+    }
+}
@@ -0,0 +1,341 @@
+//! Radius searches restricted to a cone or a slab around the query, as used with point normals
+
+use alloc::vec::Vec;
+
+use num_traits::Float;
+use ordered_float::NotNan;
+
+use crate::cell::NodeCell;
+use crate::internal_parameters::InternalParameters;
+use crate::sphere::unit;
+use crate::{
+    point_slice_dist2, InternalNeighbour, KDTree, Neighbour, Parameters, Point, QueryError, Radius,
+    Scalar,
+};
+
+/// The region around the query to which the neighbours are restricted,
+/// with the distances to the query multiplied by the scale of the tree
+enum Region<T> {
+    /// points whose offset from the query makes an angle with `axis` whose cosine is at least `cos`
+    Cone { axis: Vec<T>, cos: T, sin: T },
+    /// points whose offset from the query has a projection on `normal` smaller than `half_thickness`
+    Slab { normal: Vec<T>, half_thickness: T },
+}
+
+impl<T: Scalar + Float> Region<T> {
+    /// Whether the point at `offset` from the query, of squared norm `dist2`, is in the region
+    fn contains(&self, offset: impl Iterator<Item = T>, dist2: T) -> bool {
+        match self {
+            Region::Cone { axis, cos, .. } => {
+                let dot = offset.zip(axis).fold(T::zero(), |dot, (x, a)| dot + x * *a);
+                dot >= *cos * Float::sqrt(dist2)
+            }
+            Region::Slab {
+                normal,
+                half_thickness,
+            } => {
+                let dot = offset
+                    .zip(normal)
+                    .fold(T::zero(), |dot, (x, n)| dot + x * *n);
+                Float::abs(dot) < *half_thickness
+            }
+        }
+    }
+
+    /// Whether the box from `lower` to `upper`, offset from the query, may hold points of the region
+    fn may_intersect(&self, lower: &[T], upper: &[T]) -> bool {
+        match self {
+            Region::Cone { axis, cos, sin } => {
+                // beyond a right angle, the cone is not convex and the bounding sphere test does not hold
+                if *cos < T::zero() {
+                    return true;
+                }
+                let two = T::one() + T::one();
+                let (mut dot, mut center2, mut radius2) = (T::zero(), T::zero(), T::zero());
+                for ((lower, upper), a) in lower.iter().zip(upper).zip(axis) {
+                    let center = (*lower + *upper) / two;
+                    let half_width = (*upper - *lower) / two;
+                    dot += center * *a;
+                    center2 += center * center;
+                    radius2 += half_width * half_width;
+                }
+                if center2 <= radius2 {
+                    return true;
+                }
+                // the bounding sphere of the box is seen from the query within an angle β of its center,
+                // so it is outside the cone if its center is beyond the half-angle plus β from the axis
+                let sin_beta = Float::sqrt(radius2 / center2);
+                let cos_beta = Float::sqrt(T::one() - radius2 / center2);
+                dot >= (*cos * cos_beta - *sin * sin_beta) * Float::sqrt(center2)
+            }
+            Region::Slab {
+                normal,
+                half_thickness,
+            } => {
+                let (mut min, mut max) = (T::zero(), T::zero());
+                for ((lower, upper), n) in lower.iter().zip(upper).zip(normal) {
+                    let (a, b) = (*lower * *n, *upper * *n);
+                    min += Float::min(a, b);
+                    max += Float::max(a, b);
+                }
+                min < *half_thickness && max > -*half_thickness
+            }
+        }
+    }
+}
+
+/// The state of a radius search restricted to a region
+struct RegionSearch<'a, T: Scalar, P: Point<T>> {
+    tree: &'a KDTree<T, P>,
+    query: &'a [NotNan<T>],
+    max_radius2: NotNan<T>,
+    region: Region<T>,
+    /// lower and upper corners of the visited cell, offset from the query and scaled
+    lower: Vec<T>,
+    upper: Vec<T>,
+    found: Vec<InternalNeighbour<T>>,
+}
+
+impl<'a, T: Scalar + Float, P: Point<T>> RegionSearch<'a, T, P> {
+    fn recurse(&mut self, node: usize, cell: &mut NodeCell<T>) {
+        let scale = self.tree.scale;
+        if cell.box_dist2(self.query, self.query, scale) > self.max_radius2 {
+            return;
+        }
+        let (lower, upper) = cell.corners();
+        for i in 0..lower.len() {
+            self.lower[i] = ((lower[i] - self.query[i]) * scale).into_inner();
+            self.upper[i] = ((upper[i] - self.query[i]) * scale).into_inner();
+        }
+        if !self.region.may_intersect(&self.lower, &self.upper) {
+            return;
+        }
+        let tree = self.tree;
+        tree.nodes[node].dispatch_on_type(
+            (self, cell),
+            |(search, cell), split_dim, split_val, right_child| {
+                let dim = split_dim as usize;
+                let parent = cell.extent(dim);
+                cell.set_child(dim, parent, split_val, tree.drift, true);
+                search.recurse(node + 1, cell);
+                cell.set_child(dim, parent, split_val, tree.drift, false);
+                search.recurse(right_child as usize, cell);
+                cell.set_extent(dim, parent);
+            },
+            |(search, _), bucket_start_index, bucket_size| {
+                let dim = P::DIM as usize;
+                let start = bucket_start_index as usize;
+                for index in start..start + bucket_size as usize {
+                    let point = &tree.points[index * dim..(index + 1) * dim];
+                    let dist2 = point_slice_dist2::<T, P>(point, search.query, scale);
+                    if dist2 > search.max_radius2 {
+                        continue;
+                    }
+                    let offset = point
+                        .iter()
+                        .zip(search.query)
+                        .map(|(x, q)| ((*x - *q) * scale).into_inner());
+                    if search.region.contains(offset, dist2.into_inner()) {
+                        search.found.push(InternalNeighbour {
+                            index: index as u32,
+                            dist2,
+                        });
+                    }
+                }
+            },
+        )
+    }
+}
+
+impl<T: Scalar + Float, P: Point<T>> KDTree<T, P> {
+    /// Finds all the points within `radius` of `query`, inclusive, whose offset from `query`
+    /// makes an angle with `axis` of cosine at least `cos_half_angle`, sorted by increasing distance.
+    ///
+    /// A point at `query` itself is in the cone.
+    /// The condition is checked for each point before it is collected, and when `cos_half_angle`
+    /// is positive or zero, subtrees whose cell lies outside the cone are pruned.
+    /// Returns [`QueryError::DegenerateDirection`] if the norm of `axis` is below the machine epsilon,
+    /// or is not finite.
+    ///
+    /// Panics if `cos_half_angle` is not within `-1..=1`.
+    pub fn radius_search_in_cone(
+        &self,
+        query: &P,
+        radius: Radius<T>,
+        axis: &P,
+        cos_half_angle: T,
+    ) -> Result<Vec<Neighbour<T, P>>, QueryError> {
+        assert!(
+            cos_half_angle >= -T::one() && cos_half_angle <= T::one(),
+            "the cosine of the half-angle must be within -1..=1"
+        );
+        let mut axis = unit(axis).ok_or(QueryError::DegenerateDirection)?;
+        self.rotate_direction(&mut axis);
+        let sin = Float::sqrt(T::one() - cos_half_angle * cos_half_angle);
+        let region = Region::Cone {
+            axis,
+            cos: cos_half_angle,
+            sin,
+        };
+        Ok(self.radius_search_in_region(query, radius, region))
+    }
+
+    /// Finds all the points within `radius` of `query`, inclusive, whose distance to the plane
+    /// through `query` orthogonal to `normal` is smaller than `half_thickness`, sorted by increasing distance.
+    ///
+    /// The condition is checked for each point before it is collected,
+    /// and subtrees whose cell lies outside the slab are pruned.
+    /// Returns [`QueryError::DegenerateDirection`] if the norm of `normal` is below the machine epsilon,
+    /// or is not finite.
+    ///
+    /// Panics if `half_thickness` is negative or NaN.
+    pub fn radius_search_in_slab(
+        &self,
+        query: &P,
+        radius: Radius<T>,
+        normal: &P,
+        half_thickness: T,
+    ) -> Result<Vec<Neighbour<T, P>>, QueryError> {
+        assert!(
+            half_thickness >= T::zero(),
+            "the half-thickness must be positive or zero"
+        );
+        let mut normal = unit(normal).ok_or(QueryError::DegenerateDirection)?;
+        self.rotate_direction(&mut normal);
+        let region = Region::Slab {
+            normal,
+            half_thickness: half_thickness * self.scale.into_inner(),
+        };
+        Ok(self.radius_search_in_region(query, radius, region))
+    }
+
+    fn radius_search_in_region(
+        &self,
+        query: &P,
+        radius: Radius<T>,
+        region: Region<T>,
+    ) -> Vec<Neighbour<T, P>> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let parameters = Parameters {
+            max_radius: radius.get(),
+            ..Parameters::default()
+        };
+        let query = self.query_coordinates(query);
+        let dim = P::DIM as usize;
+        let mut search = RegionSearch {
+            tree: self,
+            query: &query,
+            max_radius2: InternalParameters::new(&parameters, self.scale).max_radius2,
+            region,
+            lower: alloc::vec![T::zero(); dim],
+            upper: alloc::vec![T::zero(); dim],
+            found: Vec::new(),
+        };
+        search.recurse(0, &mut NodeCell::of_root(self));
+        let mut found = search.found;
+        found.sort_unstable_by_key(|neighbour| (neighbour.dist2, neighbour.index));
+        found
+            .into_iter()
+            .map(|neighbour| self.externalise_neighbour(neighbour))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    /// Returns the offsets from `query` of the points within `radius`, found by an unrestricted search
+    fn offsets(tree: &KDTree<f32, P3>, query: &P3, radius: f32) -> Vec<(u32, [f32; 3])> {
+        tree.radius_search_ordered(query, Radius::new(radius))
+            .into_iter()
+            .map(|neighbour| {
+                let offset =
+                    [0, 1, 2].map(|i| (neighbour.point.get(i) - query.get(i)).into_inner());
+                (neighbour.index, offset)
+            })
+            .collect()
+    }
+
+    fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+        a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+    }
+
+    fn sorted_indices(neighbours: &[Neighbour<f32, P3>]) -> Vec<u32> {
+        assert!(neighbours
+            .windows(2)
+            .all(|pair| pair[0].dist2 <= pair[1].dist2));
+        let mut indices: Vec<u32> = neighbours.iter().map(|neighbour| neighbour.index).collect();
+        indices.sort_unstable();
+        indices
+    }
+
+    #[test]
+    fn cone_searches_match_filtered_radius_searches() {
+        let cloud: Vec<P3> = random_point_cloud(3000);
+        let tree = KDTree::new_with_bucket_size(&cloud, 4);
+        for _ in 0..20 {
+            let query: P3 = random_point();
+            let axis: P3 = random_point();
+            let a: [f32; 3] = axis.into();
+            let norm = dot(a, a).sqrt();
+            for cos_half_angle in [1.0, 0.9, 0.5, 0.0, -0.5, -1.0] {
+                let found = tree
+                    .radius_search_in_cone(&query, Radius::new(40.0), &axis, cos_half_angle)
+                    .unwrap();
+                let mut expected: Vec<u32> = offsets(&tree, &query, 40.0)
+                    .into_iter()
+                    .filter(|(_, offset)| {
+                        dot(*offset, a) / norm >= cos_half_angle * dot(*offset, *offset).sqrt()
+                    })
+                    .map(|(index, _)| index)
+                    .collect();
+                expected.sort_unstable();
+                assert_eq!(sorted_indices(&found), expected);
+            }
+        }
+        let zero = P3::default();
+        assert_eq!(
+            tree.radius_search_in_cone(&zero, Radius::new(10.0), &zero, 0.5),
+            Err(QueryError::DegenerateDirection)
+        );
+    }
+
+    #[test]
+    fn slab_searches_match_filtered_radius_searches() {
+        let cloud: Vec<P3> = random_point_cloud(3000);
+        let tree = KDTree::new_with_bucket_size(&cloud, 4);
+        for _ in 0..20 {
+            let query: P3 = random_point();
+            let normal: P3 = random_point();
+            let n: [f32; 3] = normal.into();
+            let norm = dot(n, n).sqrt();
+            for half_thickness in [0.0, 2.0, 10.0, 100.0] {
+                let found = tree
+                    .radius_search_in_slab(&query, Radius::new(40.0), &normal, half_thickness)
+                    .unwrap();
+                let mut expected: Vec<u32> = offsets(&tree, &query, 40.0)
+                    .into_iter()
+                    .filter(|(_, offset)| (dot(*offset, n) / norm).abs() < half_thickness)
+                    .map(|(index, _)| index)
+                    .collect();
+                expected.sort_unstable();
+                assert_eq!(sorted_indices(&found), expected);
+            }
+        }
+        let zero = P3::default();
+        let query = P3::from([1.0, 2.0, 3.0]);
+        assert_eq!(
+            tree.radius_search_in_slab(&query, Radius::new(10.0), &zero, 1.0),
+            Err(QueryError::DegenerateDirection)
+        );
+        let empty = KDTree::<f32, P3>::new(&[]);
+        assert_eq!(
+            empty.radius_search_in_slab(&query, Radius::new(10.0), &query, 1.0),
+            Ok(Vec::new())
+        );
+    }
+}
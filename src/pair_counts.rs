@@ -0,0 +1,375 @@
+//! Counts of the pairs of points in bins of distance, by dual-tree traversal
+
+use alloc::{vec, vec::Vec};
+use core::ops::Range;
+
+use num_traits::{Bounded, Zero};
+use ordered_float::NotNan;
+
+use crate::distance_scale::saturate;
+use crate::{point_slice_dist2, KDTree, Point, Scalar};
+
+/// The bounding boxes of the points of each node, and their numbers, computed for dual-tree traversals
+pub(crate) struct NodeBoxes<T: Scalar> {
+    /// for each node, the lower corner then the upper one
+    corners: Vec<NotNan<T>>,
+    pub(crate) counts: Vec<u64>,
+}
+
+impl<T: Scalar> NodeBoxes<T> {
+    /// Returns the lower and upper corners of the box of `node`.
+    pub(crate) fn corners(&self, node: usize, dim: usize) -> (&[NotNan<T>], &[NotNan<T>]) {
+        let start = node * 2 * dim;
+        self.corners[start..start + 2 * dim].split_at(dim)
+    }
+}
+
+/// The squared edges of the bins, in the internal units of a tree
+struct Bins<T: Scalar> {
+    edges2: Vec<NotNan<T>>,
+    scale: NotNan<T>,
+}
+
+impl<T: Scalar> Bins<T> {
+    /// Returns the number of edges not above `dist2`, so that bin `i` is slot `i + 1`
+    /// and the slots `0` and `edges2.len()` are outside the bins.
+    fn slot(&self, dist2: NotNan<T>) -> usize {
+        self.edges2.partition_point(|edge2| *edge2 <= dist2)
+    }
+
+    /// Adds `count` to the bin of `slot`, if it is one.
+    fn add_to_slot(&self, counts: &mut [u64], slot: usize, count: u64) {
+        if slot > 0 && slot < self.edges2.len() {
+            counts[slot - 1] += count;
+        }
+    }
+
+    /// Returns the slot of all distances in `[min_dist2, max_dist2]`, if they share one.
+    fn common_slot(&self, min_dist2: NotNan<T>, max_dist2: NotNan<T>) -> Option<usize> {
+        let slot = self.slot(min_dist2);
+        (slot == self.slot(max_dist2)).then_some(slot)
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Returns the number of pairs of distinct points of the tree whose distance falls in each bin,
+    /// the bin `i` holding the distances from `bins[i]` inclusive to `bins[i + 1]` exclusive.
+    ///
+    /// Each unordered pair is counted once, and distances outside the bins are not counted,
+    /// so there is one count less than there are edges.
+    /// The traversal bounds the distances between the points of two nodes by those between their bounding boxes,
+    /// and counts all their pairs at once when the bounds fall in the same bin,
+    /// so that only the pairs of nodes straddling an edge are enumerated.
+    ///
+    /// Panics if the edges are NaN, negative, or not strictly increasing.
+    pub fn pair_counts(&self, bins: &[T]) -> Vec<u64> {
+        self.count_pairs(self, true, self.internal_bins(bins))
+    }
+
+    /// Returns the number of pairs of a point of the tree and a point of `other`
+    /// whose distance falls in each bin, see [`Self::pair_counts`].
+    ///
+    /// Panics if the edges are NaN, negative, or not strictly increasing,
+    /// or if the trees do not have the same rotation, see [`Self::rotation`].
+    pub fn pair_counts_cross(&self, other: &KDTree<T, P>, bins: &[T]) -> Vec<u64> {
+        assert!(
+            self.rotation == other.rotation,
+            "Both trees must have the same rotation"
+        );
+        self.count_pairs(other, false, self.internal_bins(bins))
+    }
+
+    /// Counts the pairs of a point of the tree and a point of `other`, see [`PairCounter`]
+    fn count_pairs(&self, other: &KDTree<T, P>, self_pairs: bool, bins: Bins<T>) -> Vec<u64> {
+        let counts = vec![0; bins.edges2.len().saturating_sub(1)];
+        if counts.is_empty() || self.is_empty() || other.is_empty() {
+            return counts;
+        }
+        let boxes = self.node_boxes();
+        let other_boxes = if self_pairs {
+            None
+        } else {
+            Some(other.node_boxes())
+        };
+        let mut counter = PairCounter {
+            tree: self,
+            other,
+            self_pairs,
+            boxes: &boxes,
+            other_boxes: other_boxes.as_ref().unwrap_or(&boxes),
+            bins,
+            counts,
+        };
+        counter.count(0, 0);
+        counter.counts
+    }
+
+    /// Validates `bins` and converts them to squared edges in the internal units of the tree
+    fn internal_bins(&self, bins: &[T]) -> Bins<T> {
+        let edges2 = bins
+            .iter()
+            .map(|edge| {
+                let edge = NotNan::new(*edge).expect("bin edges must not be NaN");
+                assert!(edge >= NotNan::zero(), "bin edges must be positive or zero");
+                let edge = edge * self.scale;
+                saturate(edge * edge)
+            })
+            .collect::<Vec<_>>();
+        assert!(
+            bins.windows(2).all(|pair| pair[0] < pair[1]),
+            "bin edges must be strictly increasing"
+        );
+        Bins {
+            edges2,
+            scale: self.scale,
+        }
+    }
+
+    /// Computes the bounding boxes of the points of all nodes, and their numbers of points
+    pub(crate) fn node_boxes(&self) -> NodeBoxes<T> {
+        let dim = P::DIM as usize;
+        let mut boxes = NodeBoxes::<T> {
+            corners: vec![NotNan::zero(); self.nodes.len() * 2 * dim],
+            counts: vec![0; self.nodes.len()],
+        };
+        let mut corners = vec![NotNan::<T>::zero(); 2 * dim];
+        // children have larger ids than their parent
+        for node in (0..self.nodes.len()).rev() {
+            let (lower, upper) = corners.split_at_mut(dim);
+            // empty nodes keep an inverted box, but are skipped by the traversal
+            lower.fill(NotNan::max_value());
+            upper.fill(NotNan::min_value());
+            let mut include = |point_lower: &[NotNan<T>], point_upper: &[NotNan<T>]| {
+                for i in 0..dim {
+                    lower[i] = lower[i].min(point_lower[i]);
+                    upper[i] = upper[i].max(point_upper[i]);
+                }
+            };
+            boxes.counts[node] = self.nodes[node].dispatch_on_type(
+                &mut include,
+                |include, _, _, right_child| {
+                    let children = [node + 1, right_child as usize];
+                    for child in children {
+                        if boxes.counts[child] > 0 {
+                            let (child_lower, child_upper) = boxes.corners(child, dim);
+                            include(child_lower, child_upper);
+                        }
+                    }
+                    children.iter().map(|child| boxes.counts[*child]).sum()
+                },
+                |include, bucket_start_index, bucket_size| {
+                    let start = bucket_start_index as usize;
+                    let points = &self.points[start * dim..(start + bucket_size as usize) * dim];
+                    for point in points.chunks_exact(dim) {
+                        include(point, point);
+                    }
+                    bucket_size as u64
+                },
+            );
+            let start = node * 2 * dim;
+            boxes.corners[start..start + 2 * dim].copy_from_slice(&corners);
+        }
+        boxes
+    }
+}
+
+/// A dual-tree traversal counting the pairs of a point of `tree` and a point of `other`
+struct PairCounter<'a, T: Scalar, P: Point<T>> {
+    tree: &'a KDTree<T, P>,
+    other: &'a KDTree<T, P>,
+    /// whether `other` is `tree`, each pair of distinct points being counted once
+    self_pairs: bool,
+    boxes: &'a NodeBoxes<T>,
+    other_boxes: &'a NodeBoxes<T>,
+    bins: Bins<T>,
+    counts: Vec<u64>,
+}
+
+impl<'a, T: Scalar, P: Point<T>> PairCounter<'a, T, P> {
+    /// Counts the pairs of a point below `node` of the tree and a point below `other_node` of the other tree
+    fn count(&mut self, node: usize, other_node: usize) {
+        let count = self.boxes.counts[node];
+        let other_count = self.other_boxes.counts[other_node];
+        if count == 0 || other_count == 0 {
+            return;
+        }
+        let dim = P::DIM as usize;
+        let same = self.self_pairs && node == other_node;
+        let (lower, upper) = self.boxes.corners(node, dim);
+        let (other_lower, other_upper) = self.other_boxes.corners(other_node, dim);
+        let (min_dist2, max_dist2) =
+            box_pair_dist2_range(lower, upper, other_lower, other_upper, self.bins.scale);
+        if let Some(slot) = self.bins.common_slot(min_dist2, max_dist2) {
+            let pairs = if same {
+                count * (count - 1) / 2
+            } else {
+                count * other_count
+            };
+            self.bins.add_to_slot(&mut self.counts, slot, pairs);
+            return;
+        }
+        // split the node with more points, to balance the pairs of nodes
+        match (children(self.tree, node), children(self.other, other_node)) {
+            (None, None) => self.count_leaf_pairs(node, other_node, same),
+            (Some([left, right]), _) if same => {
+                self.count(left, left);
+                self.count(left, right);
+                self.count(right, right);
+            }
+            (Some(split), None) => split
+                .iter()
+                .for_each(|child| self.count(*child, other_node)),
+            (Some(split), Some(_)) if count >= other_count => split
+                .iter()
+                .for_each(|child| self.count(*child, other_node)),
+            (_, Some(other_split)) => other_split
+                .iter()
+                .for_each(|other_child| self.count(node, *other_child)),
+        }
+    }
+
+    /// Counts the pairs of a point of leaf `node` and a point of leaf `other_node` of the other tree,
+    /// or the pairs of distinct points of the leaf if `same`
+    fn count_leaf_pairs(&mut self, node: usize, other_node: usize, same: bool) {
+        let dim = P::DIM as usize;
+        let other_range = leaf_range(self.other, other_node);
+        for index in leaf_range(self.tree, node) {
+            let point = &self.tree.points[index * dim..(index + 1) * dim];
+            let other_start = if same { index + 1 } else { other_range.start };
+            for other_index in other_start..other_range.end {
+                let other_point = &self.other.points[other_index * dim..(other_index + 1) * dim];
+                let dist2 = point_slice_dist2::<T, P>(point, other_point, self.bins.scale);
+                let slot = self.bins.slot(saturate(dist2));
+                self.bins.add_to_slot(&mut self.counts, slot, 1);
+            }
+        }
+    }
+}
+
+/// Returns the children of `node`, if it is a split node
+pub(crate) fn children<T: Scalar, P: Point<T>>(
+    tree: &KDTree<T, P>,
+    node: usize,
+) -> Option<[usize; 2]> {
+    tree.nodes[node].dispatch_on_type(
+        (),
+        |_, _, _, right_child| Some([node + 1, right_child as usize]),
+        |_, _, _| None,
+    )
+}
+
+/// Returns the range of internal indices of the points of leaf `node`
+pub(crate) fn leaf_range<T: Scalar, P: Point<T>>(tree: &KDTree<T, P>, node: usize) -> Range<usize> {
+    tree.nodes[node].dispatch_on_type(
+        (),
+        |_, _, _, _| unreachable!("only leaves are enumerated"),
+        |_, bucket_start_index, bucket_size| {
+            bucket_start_index as usize..(bucket_start_index + bucket_size) as usize
+        },
+    )
+}
+
+/// Returns the smallest and largest squared distances between a point of the box from `lower` to `upper`
+/// and one of the box from `other_lower` to `other_upper`, with coordinate differences multiplied by `scale`
+pub(crate) fn box_pair_dist2_range<T: Scalar>(
+    lower: &[NotNan<T>],
+    upper: &[NotNan<T>],
+    other_lower: &[NotNan<T>],
+    other_upper: &[NotNan<T>],
+    scale: NotNan<T>,
+) -> (NotNan<T>, NotNan<T>) {
+    let zero = NotNan::<T>::zero();
+    let mut min_dist2 = zero;
+    let mut max_dist2 = zero;
+    for i in 0..lower.len() {
+        let near = (lower[i] - other_upper[i])
+            .max(other_lower[i] - upper[i])
+            .max(zero)
+            * scale;
+        let far = (upper[i] - other_lower[i]).max(other_upper[i] - lower[i]) * scale;
+        min_dist2 += near * near;
+        max_dist2 += far * far;
+    }
+    (saturate(min_dist2), saturate(max_dist2))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rng::SplitMix64;
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn brute_force_counts(a: &[P2], b: &[P2], bins: &[f32], self_pairs: bool) -> Vec<u64> {
+        let mut counts = vec![0; bins.len().saturating_sub(1)];
+        for (i, p) in a.iter().enumerate() {
+            let start = if self_pairs { i + 1 } else { 0 };
+            for q in &b[start..] {
+                let dx = (p.get(0) - q.get(0)).into_inner();
+                let dy = (p.get(1) - q.get(1)).into_inner();
+                let dist2 = dx * dx + dy * dy;
+                if let Some(bin) = (0..counts.len()).find(|bin| {
+                    bins[*bin] * bins[*bin] <= dist2 && dist2 < bins[bin + 1] * bins[bin + 1]
+                }) {
+                    counts[bin] += 1;
+                }
+            }
+        }
+        counts
+    }
+
+    fn grid_cloud(rng: &mut SplitMix64, count: usize) -> Vec<P2> {
+        (0..count)
+            .map(|_| P2::new2d(rng.below(50) as f32, rng.below(50) as f32))
+            .collect()
+    }
+
+    #[test]
+    fn pair_counts_match_brute_force() {
+        let mut rng = SplitMix64::new(190);
+        // integer coordinates put many distances exactly on the edges
+        let mut cloud = grid_cloud(&mut rng, 1500);
+        cloud.extend(random_point_cloud::<2>(500));
+        let other = grid_cloud(&mut rng, 700);
+        let mut tree = KDTree::new_with_bucket_size(&cloud, 4);
+        for (index, point) in cloud.iter_mut().enumerate().take(50) {
+            *point = P2::new2d(rng.below(50) as f32, rng.below(50) as f32);
+            tree.update_point(index as u32, *point);
+        }
+        let other_tree = KDTree::new(&other);
+        for bins in [
+            vec![0.0, 1.0, 2.0, 5.0, 10.0, 30.0],
+            vec![3.0, 3.5, 100.0],
+            vec![0.0, 1000.0],
+        ] {
+            assert_eq!(
+                tree.pair_counts(&bins),
+                brute_force_counts(&cloud, &cloud, &bins, true)
+            );
+            assert_eq!(
+                tree.pair_counts_cross(&other_tree, &bins),
+                brute_force_counts(&cloud, &other, &bins, false)
+            );
+        }
+        // a single bin holding all distances counts all pairs
+        let n = cloud.len() as u64;
+        assert_eq!(
+            tree.pair_counts(&[0.0, f32::INFINITY]),
+            vec![n * (n - 1) / 2]
+        );
+        assert_eq!(
+            tree.pair_counts_cross(&tree, &[0.0, f32::INFINITY]),
+            vec![n * n]
+        );
+        assert!(tree.pair_counts(&[1.0]).is_empty());
+        let empty = KDTree::<f32, P2>::new(&[]);
+        assert_eq!(empty.pair_counts(&[0.0, 1.0]), vec![0]);
+        assert_eq!(tree.pair_counts_cross(&empty, &[0.0, 1.0]), vec![0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "bin edges must be strictly increasing")]
+    fn unsorted_bins_panic() {
+        let tree = KDTree::new(&[P2::new2d(0.0, 0.0), P2::new2d(1.0, 0.0)]);
+        tree.pair_counts(&[0.0, 2.0, 2.0]);
+    }
+}
@@ -0,0 +1,400 @@
+use num_traits::Float;
+
+use crate::{ParameterError, Radius, Scalar, SquaredRadius};
+
+/// Advanced search parameters
+///
+/// Prefer [`Parameters::builder`] to construct them, as it validates the values.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Parameters<T: Scalar> {
+    /// maximal ratio of error for approximate search, 0 for exact search; has no effect if the number of neighbours found is smaller than the number requested
+    ///
+    /// With `T::infinity()`, the search greedily descends towards the query,
+    /// and only backtracks while it has found less neighbours than requested.
+    /// With the `strict-exact` feature, any other value than 0 is rejected, see [`ParameterError::ApproximateSearch`].
+    pub epsilon: T,
+    /// maximum radius in which to search, can be used to prune search, is not affected by `epsilon`
+    ///
+    /// The ball is closed by default, points at exactly `max_radius` being returned, see [`Self::boundary`].
+    /// A radius whose square underflows to zero behaves like a radius of zero,
+    /// i.e. only points coinciding with the query are returned.
+    pub max_radius: T,
+    /// whether points at exactly `max_radius` are returned
+    ///
+    /// Searches compare the squared distances to the square of the radius, as multiplied by the distance scale
+    /// of the tree, so a point is exactly at the radius if its squared distance rounds to that square.
+    pub boundary: Boundary,
+    /// which points are excluded as being the query itself, see [`SelfMatch`]
+    pub self_match: SelfMatch,
+    /// sort points by distances, when `k` > 1
+    ///
+    /// Points at the same distance are sorted by increasing index in the original point cloud,
+    /// so the results of a tree are identical on all platforms.
+    /// If false, the order of the results is unspecified, which saves the final sorting cost
+    /// when the results are only aggregated.
+    pub sort_results: bool,
+    /// the order in which the nodes of the tree are visited, which does not change the results of exact searches
+    pub traversal_order: TraversalOrder,
+    /// what to do when fewer than `k` neighbours are within `max_radius`, although the tree has `k` points
+    pub on_insufficient: InsufficientPolicy,
+}
+
+impl<T: Scalar> Default for Parameters<T> {
+    /// Exact search, no max. radius, closed ball, excluding no point as a self match, sorting results,
+    /// visiting the near child first and truncating the results to the radius.
+    fn default() -> Self {
+        Parameters {
+            epsilon: T::zero(),
+            max_radius: T::infinity(),
+            boundary: Boundary::Closed,
+            self_match: SelfMatch::NotApplicable,
+            sort_results: true,
+            traversal_order: TraversalOrder::NearChildFirst,
+            on_insufficient: InsufficientPolicy::Truncate,
+        }
+    }
+}
+
+/// Which points a search excludes as being the query itself, see [`Parameters::self_match`]
+///
+/// Only [`Self::ExcludeCoincident`] looks at distances, so the other variants never drop a stored point
+/// that coincides with an external query.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum SelfMatch {
+    /// the query is a stored point, which is returned as its own nearest neighbour
+    Allow,
+    /// the query is the stored point of this index in the original point cloud, which is excluded by its index,
+    /// its duplicates and the other points at distance zero being returned
+    ///
+    /// The searches of [`KDTree`](crate::KDTree) through its traversal honour it, as do the searches
+    /// filtering on self matches of the other structures; an index out of range excludes nothing.
+    ExcludeByIndex(u32),
+    /// the points at a squared distance up to the machine epsilon of the query are excluded,
+    /// as libnabo excludes self matches, which also excludes the duplicates of the query
+    ExcludeCoincident,
+    /// the query is not a stored point, and nothing is excluded
+    #[default]
+    NotApplicable,
+}
+
+impl SelfMatch {
+    /// Returns whether points at distance zero of the query are returned, unless excluded by index.
+    pub const fn allows_coincident(&self) -> bool {
+        !matches!(self, SelfMatch::ExcludeCoincident)
+    }
+
+    /// Returns the index of the point excluded by index, if any.
+    pub const fn excluded_index(&self) -> Option<u32> {
+        match self {
+            SelfMatch::ExcludeByIndex(index) => Some(*index),
+            _ => None,
+        }
+    }
+}
+
+impl From<bool> for SelfMatch {
+    /// Converts the former `allow_self_match` flag: `true` to [`SelfMatch::Allow`],
+    /// `false` to [`SelfMatch::ExcludeCoincident`].
+    fn from(allow_self_match: bool) -> Self {
+        if allow_self_match {
+            SelfMatch::Allow
+        } else {
+            SelfMatch::ExcludeCoincident
+        }
+    }
+}
+
+/// Whether the ball of a search includes its boundary, see [`Parameters::boundary`]
+///
+/// All searches accepting [`Parameters`] compare the distances of the candidates and the bounds of the cells
+/// they prune to the radius with the same boundary.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Boundary {
+    /// points at exactly the radius are returned, distances being compared with `<=`
+    #[default]
+    Closed,
+    /// only points strictly within the radius are returned, distances being compared with `<`
+    ///
+    /// A radius of zero then returns no point.
+    Open,
+}
+
+/// The order in which a search visits the nodes of the tree, see [`Parameters::traversal_order`]
+///
+/// Exact searches return the same neighbours in all orders, up to ties, and only the number
+/// of visited nodes changes, as reported by [`QueryStats`](crate::QueryStats).
+/// With a non-zero epsilon, approximate searches may return different neighbours.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum TraversalOrder {
+    /// depth-first, descending first into the child on the side of the split plane of the query,
+    /// and into the other one if the plane is closer than the furthest candidate
+    ///
+    /// This needs no state beyond the offsets to the split planes,
+    /// and is the fastest for queries inside the cloud, whose near child always needs a visit.
+    #[default]
+    NearChildFirst,
+    /// depth-first, bounding the distance to each child by its cell, which is the bounding box
+    /// of the tree restricted by the splits, and pruning the near child too
+    ///
+    /// Queries with a maximum radius from outside the cloud, or near its boundary, then visit fewer nodes,
+    /// at the cost of computing the distance to two cells per split node.
+    BoundOrdered,
+    /// best-first, always visiting the pending node of smallest distance to the query
+    ///
+    /// This visits the fewest leaves, which pays off for large `k` or costly collectors,
+    /// at the cost of a priority queue of pending nodes.
+    BestFirst,
+}
+
+/// What a k-nearest-neighbour search does when fewer than `k` neighbours are within `max_radius`,
+/// although the tree has at least `k` points, see [`Parameters::on_insufficient`]
+///
+/// This applies to the searches of [`KDTree`](crate::KDTree) for the `k` nearest neighbours
+/// with a candidate container, single or in batches.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum InsufficientPolicy {
+    /// returns the neighbours within the radius
+    #[default]
+    Truncate,
+    /// completes the neighbours with the nearest points beyond the radius, as if there were no radius
+    ///
+    /// The search continues from the candidates found within the radius, without the radius bound,
+    /// only offering the points beyond the radius, so that exact searches return the same neighbours
+    /// as without a radius, and searches that fill up within the radius cost nothing more.
+    IgnoreRadius,
+    /// returns [`QueryError::InsufficientNeighbours`](crate::QueryError::InsufficientNeighbours)
+    /// from the searches returning errors, such as [`KDTree::try_knn_advanced`](crate::KDTree::try_knn_advanced),
+    /// if there are points beyond the radius, and makes the other searches panic
+    Error,
+}
+
+impl<T: Scalar> Parameters<T> {
+    /// Creates a builder starting from the default parameters.
+    pub fn builder() -> ParametersBuilder<T> {
+        ParametersBuilder::from_parameters(Self::default())
+    }
+
+    /// Returns the maximum radius in which to search.
+    pub fn radius(&self) -> Radius<T> {
+        Radius::new(self.max_radius)
+    }
+
+    /// Checks that the parameters are valid.
+    pub fn validate(&self) -> Result<(), ParameterError> {
+        if self.epsilon.is_nan() {
+            return Err(ParameterError::NonFiniteEpsilon);
+        }
+        if self.epsilon < T::zero() {
+            return Err(ParameterError::NegativeEpsilon);
+        }
+        if self.max_radius.is_nan() {
+            return Err(ParameterError::NanMaxRadius);
+        }
+        if self.max_radius < T::zero() {
+            return Err(ParameterError::NegativeMaxRadius);
+        }
+        if cfg!(feature = "strict-exact") && self.epsilon != T::zero() {
+            return Err(ParameterError::ApproximateSearch);
+        }
+        Ok(())
+    }
+}
+
+/// Overrides of some [`Parameters`] for a single query of a batch, see [`KDTree::knn_batch_with`](crate::KDTree::knn_batch_with)
+///
+/// `None` keeps the value of the parameters of the batch.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QueryOverride<T: Scalar> {
+    /// overrides [`Parameters::max_radius`]
+    pub max_radius: Option<T>,
+    /// overrides [`Parameters::self_match`]
+    pub self_match: Option<SelfMatch>,
+}
+
+impl<T: Scalar> QueryOverride<T> {
+    /// Returns `parameters` with the overridden values replaced.
+    pub fn apply(&self, parameters: &Parameters<T>) -> Parameters<T> {
+        Parameters {
+            max_radius: self.max_radius.unwrap_or(parameters.max_radius),
+            self_match: self.self_match.unwrap_or(parameters.self_match),
+            ..*parameters
+        }
+    }
+}
+
+/// A builder for [`Parameters`], validating them once in [`ParametersBuilder::build`]
+///
+/// The setters are `const`, so a builder can be prepared in a `const` or `static` item:
+/// ```
+/// use nabo::{Boundary, InsufficientPolicy, Parameters, ParametersBuilder, SelfMatch, TraversalOrder};
+/// const LOCAL: ParametersBuilder<f32> = ParametersBuilder::from_parameters(Parameters {
+///     epsilon: 0.0,
+///     max_radius: f32::INFINITY,
+///     boundary: Boundary::Closed,
+///     self_match: SelfMatch::NotApplicable,
+///     sort_results: true,
+///     traversal_order: TraversalOrder::NearChildFirst,
+///     on_insufficient: InsufficientPolicy::Truncate,
+/// })
+/// .max_radius(0.1)
+/// .sort_results(false);
+/// let parameters = LOCAL.build().unwrap();
+/// assert_eq!(parameters.max_radius, 0.1);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ParametersBuilder<T: Scalar> {
+    parameters: Parameters<T>,
+}
+
+impl<T: Scalar> ParametersBuilder<T> {
+    /// Creates a builder starting from existing parameters.
+    pub const fn from_parameters(parameters: Parameters<T>) -> Self {
+        ParametersBuilder { parameters }
+    }
+
+    /// Sets the maximal ratio of error for approximate search, 0 for exact search, infinity for a greedy search.
+    pub const fn epsilon(mut self, epsilon: T) -> Self {
+        self.parameters.epsilon = epsilon;
+        self
+    }
+
+    /// Sets the maximum radius in which to search, an unsquared distance, see [`Self::radius`].
+    pub const fn max_radius(mut self, max_radius: T) -> Self {
+        self.parameters.max_radius = max_radius;
+        self
+    }
+
+    /// Sets the maximum radius in which to search.
+    pub const fn radius(mut self, radius: Radius<T>) -> Self {
+        self.parameters.max_radius = radius.get();
+        self
+    }
+
+    /// Sets whether points at exactly the maximum radius are returned.
+    pub const fn boundary(mut self, boundary: Boundary) -> Self {
+        self.parameters.boundary = boundary;
+        self
+    }
+
+    /// Sets which points are excluded as being the query itself.
+    pub const fn self_match(mut self, self_match: SelfMatch) -> Self {
+        self.parameters.self_match = self_match;
+        self
+    }
+
+    /// Sets whether the query point itself can be returned, as [`SelfMatch::from`] converts it.
+    pub const fn allow_self_match(mut self, allow_self_match: bool) -> Self {
+        self.parameters.self_match = if allow_self_match {
+            SelfMatch::Allow
+        } else {
+            SelfMatch::ExcludeCoincident
+        };
+        self
+    }
+
+    /// Sets whether the results are sorted by increasing distances, if not their order is unspecified.
+    pub const fn sort_results(mut self, sort_results: bool) -> Self {
+        self.parameters.sort_results = sort_results;
+        self
+    }
+
+    /// Sets the order in which the nodes of the tree are visited.
+    pub const fn traversal_order(mut self, traversal_order: TraversalOrder) -> Self {
+        self.parameters.traversal_order = traversal_order;
+        self
+    }
+
+    /// Sets what to do when fewer than `k` neighbours are within the maximum radius.
+    pub const fn on_insufficient(mut self, on_insufficient: InsufficientPolicy) -> Self {
+        self.parameters.on_insufficient = on_insufficient;
+        self
+    }
+
+    /// Validates and returns the parameters.
+    pub fn build(self) -> Result<Parameters<T>, ParameterError> {
+        self.parameters.validate()?;
+        Ok(self.parameters)
+    }
+}
+
+impl<T: Scalar + Float> ParametersBuilder<T> {
+    /// Sets the maximum radius in which to search from its square.
+    ///
+    /// The square root is stored, so the squared radius seen by the search may differ from `radius2` by rounding.
+    pub fn squared_radius(self, radius2: SquaredRadius<T>) -> Self {
+        self.radius(radius2.sqrt())
+    }
+}
+
+impl<T: Scalar> Default for ParametersBuilder<T> {
+    fn default() -> Self {
+        Parameters::builder()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_defaults() {
+        let parameters = Parameters::<f32>::builder().build().unwrap();
+        assert_eq!(parameters, Parameters::default());
+        assert_eq!(parameters.epsilon, 0.0);
+        assert_eq!(parameters.max_radius, f32::INFINITY);
+        assert_eq!(parameters.boundary, Boundary::Closed);
+        assert_eq!(parameters.self_match, SelfMatch::NotApplicable);
+        assert!(parameters.sort_results);
+        assert_eq!(parameters.traversal_order, TraversalOrder::NearChildFirst);
+        assert_eq!(parameters.on_insufficient, InsufficientPolicy::Truncate);
+    }
+
+    #[test]
+    fn builder_validates() {
+        let builder = Parameters::<f64>::builder();
+        assert_eq!(
+            builder.epsilon(-1.0).build(),
+            Err(ParameterError::NegativeEpsilon)
+        );
+        assert_eq!(
+            builder.epsilon(f64::NAN).build(),
+            Err(ParameterError::NonFiniteEpsilon)
+        );
+        assert_eq!(
+            builder.max_radius(-1.0).build(),
+            Err(ParameterError::NegativeMaxRadius)
+        );
+        assert_eq!(
+            builder.max_radius(f64::NAN).build(),
+            Err(ParameterError::NanMaxRadius)
+        );
+        // strict exact mode rejects any non-zero epsilon
+        let epsilon = if cfg!(feature = "strict-exact") {
+            0.0
+        } else {
+            0.5
+        };
+        let parameters = builder
+            .epsilon(epsilon)
+            .max_radius(2.0)
+            .boundary(Boundary::Open)
+            .allow_self_match(false)
+            .sort_results(false)
+            .traversal_order(TraversalOrder::BoundOrdered)
+            .on_insufficient(InsufficientPolicy::IgnoreRadius)
+            .build()
+            .unwrap();
+        assert_eq!(
+            parameters,
+            Parameters {
+                epsilon,
+                max_radius: 2.0,
+                boundary: Boundary::Open,
+                self_match: SelfMatch::ExcludeCoincident,
+                sort_results: false,
+                traversal_order: TraversalOrder::BoundOrdered,
+                on_insufficient: InsufficientPolicy::IgnoreRadius,
+            }
+        );
+    }
+}
@@ -0,0 +1,231 @@
+//! Nearest neighbours of the vertices and segments of a polyline
+
+use alloc::vec::Vec;
+
+use num_traits::Zero;
+use ordered_float::NotNan;
+
+use crate::cell::NodeCell;
+use crate::distance_scale::saturate;
+use crate::infinite::HasInfinite;
+use crate::internal_parameters::InternalParameters;
+use crate::{CandidateCollector, InternalNeighbour, KDTree, Neighbour, Parameters, Point, Scalar};
+
+/// A segment of the polyline, in tree coordinates, with its bounding box
+struct Segment<'a, T: Scalar> {
+    start: &'a [NotNan<T>],
+    end: &'a [NotNan<T>],
+    lower: Vec<NotNan<T>>,
+    upper: Vec<NotNan<T>>,
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Finds the nearest neighbour of each vertex and of each segment of `polyline`, with user-provided parameters.
+    ///
+    /// The result has `2n - 1` elements for `n` vertices: element `2i` is the nearest neighbour of vertex `i`,
+    /// and element `2i + 1` the nearest neighbour of the segment from vertex `i` to vertex `i + 1`,
+    /// its `dist2` being the squared distance from the point to the segment.
+    /// An element is `None` if no point is within `parameters.max_radius`.
+    /// `parameters.self_match` excludes points at distance zero or of a given index, as for [`Self::knn_advanced`].
+    ///
+    /// Each search is bounded from the start by the result of the previous one,
+    /// as with [`Self::nearest_with_hint`], so consecutive vertices close to each other are cheap to query.
+    ///
+    /// Panics if a vertex has NaN coordinates.
+    pub fn nearest_along_polyline(
+        &self,
+        polyline: &[P],
+        parameters: &Parameters<T>,
+    ) -> Vec<Option<Neighbour<T, P>>> {
+        let internal_parameters = InternalParameters::new(parameters, self.scale);
+        let vertices: Vec<_> = polyline.iter().map(|v| self.query_coordinates(v)).collect();
+        let mut results = Vec::with_capacity((2 * polyline.len()).saturating_sub(1));
+        let mut hint = None;
+        for (index, vertex) in vertices.iter().enumerate() {
+            if index > 0 {
+                let segment = Segment::new(&vertices[index - 1], vertex);
+                let nearest = self.nearest_to_segment(&segment, hint, &internal_parameters);
+                hint = self.push_polyline_result(&mut results, nearest).or(hint);
+            }
+            let nearest = self.nearest_seeded(vertex, hint, &internal_parameters);
+            hint = self.push_polyline_result(&mut results, nearest).or(hint);
+        }
+        results
+    }
+
+    /// Pushes `nearest` to `results`, and returns its index if it is a neighbour
+    fn push_polyline_result(
+        &self,
+        results: &mut Vec<Option<Neighbour<T, P>>>,
+        nearest: InternalNeighbour<T>,
+    ) -> Option<u32> {
+        let found = nearest.dist2 != NotNan::infinite();
+        results.push(found.then(|| self.externalise_neighbour(nearest)));
+        found.then_some(nearest.index)
+    }
+
+    /// Returns the nearest point to `segment`, the search being bounded from the start
+    /// by the point of internal index `hint` if it is accepted by `internal_parameters`
+    fn nearest_to_segment(
+        &self,
+        segment: &Segment<T>,
+        hint: Option<u32>,
+        internal_parameters: &InternalParameters<T>,
+    ) -> InternalNeighbour<T> {
+        let mut nearest = InternalNeighbour::default();
+        if let Some(index) = hint {
+            self.add_to_segment_nearest(&mut nearest, segment, index, internal_parameters);
+        }
+        if !self.is_empty() {
+            let mut cell = NodeCell::of_root(self);
+            self.recurse_segment(segment, 0, &mut nearest, &mut cell, internal_parameters);
+        }
+        nearest
+    }
+
+    fn recurse_segment(
+        &self,
+        segment: &Segment<T>,
+        node: usize,
+        nearest: &mut InternalNeighbour<T>,
+        cell: &mut NodeCell<T>,
+        internal_parameters: &InternalParameters<T>,
+    ) {
+        let rd = cell.box_dist2(&segment.lower, &segment.upper, self.scale);
+        if rd > internal_parameters.max_radius2
+            || !internal_parameters.within_error(rd, nearest.furthest_dist2())
+        {
+            return;
+        }
+        self.nodes[node].dispatch_on_type(
+            (nearest, cell),
+            |(nearest, cell), split_dim, split_val, right_child| {
+                let dim = split_dim as usize;
+                let parent = cell.extent(dim);
+                // the child holding the middle of the segment first
+                let left_first = segment.start[dim] + segment.end[dim] <= split_val + split_val;
+                for left in [left_first, !left_first] {
+                    cell.set_child(dim, parent, split_val, self.drift, left);
+                    let child = if left { node + 1 } else { right_child as usize };
+                    self.recurse_segment(segment, child, nearest, cell, internal_parameters);
+                }
+                cell.set_extent(dim, parent);
+            },
+            |(nearest, _), bucket_start_index, bucket_size| {
+                for index in bucket_start_index..bucket_start_index + bucket_size {
+                    self.add_to_segment_nearest(nearest, segment, index, internal_parameters);
+                }
+            },
+        )
+    }
+
+    fn add_to_segment_nearest(
+        &self,
+        nearest: &mut InternalNeighbour<T>,
+        segment: &Segment<T>,
+        index: u32,
+        internal_parameters: &InternalParameters<T>,
+    ) {
+        let start = index as usize * P::DIM as usize;
+        let point = &self.points[start..start + P::DIM as usize];
+        let dist2 = segment.dist2(point, self.scale);
+        if internal_parameters.accepts(dist2, self.indices[index as usize]) {
+            nearest.add(dist2, index);
+        }
+    }
+}
+
+impl<'a, T: Scalar> Segment<'a, T> {
+    fn new(start: &'a [NotNan<T>], end: &'a [NotNan<T>]) -> Self {
+        let lower = start.iter().zip(end).map(|(a, b)| *a.min(b)).collect();
+        let upper = start.iter().zip(end).map(|(a, b)| *a.max(b)).collect();
+        Segment {
+            start,
+            end,
+            lower,
+            upper,
+        }
+    }
+
+    /// Returns the squared distance from `point` to the segment, with coordinate differences multiplied by `scale`
+    fn dist2(&self, point: &[NotNan<T>], scale: NotNan<T>) -> NotNan<T> {
+        let zero = NotNan::<T>::zero();
+        let mut length2 = zero;
+        let mut projection = zero;
+        for (i, value) in point.iter().enumerate() {
+            let direction = (self.end[i] - self.start[i]) * scale;
+            length2 += direction * direction;
+            projection += direction * ((*value - self.start[i]) * scale);
+        }
+        // position of the closest point of the segment, from 0 at its start to 1 at its end
+        let t = if length2 == zero {
+            zero
+        } else {
+            (projection / length2).clamp(zero, NotNan::new(T::one()).expect("one is not NaN"))
+        };
+        let mut dist2 = zero;
+        for (i, value) in point.iter().enumerate() {
+            let closest = self.start[i] + (self.end[i] - self.start[i]) * t;
+            let diff = (*value - closest) * scale;
+            dist2 += diff * diff;
+        }
+        saturate(dist2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+    use float_cmp::approx_eq;
+
+    fn segment_dist2(a: [f32; 2], b: [f32; 2], p: [f32; 2]) -> f32 {
+        let (dx, dy) = (b[0] - a[0], b[1] - a[1]);
+        let length2 = dx * dx + dy * dy;
+        let t = if length2 == 0.0 {
+            0.0
+        } else {
+            (((p[0] - a[0]) * dx + (p[1] - a[1]) * dy) / length2).clamp(0.0, 1.0)
+        };
+        let (x, y) = (a[0] + t * dx - p[0], a[1] + t * dy - p[1]);
+        x * x + y * y
+    }
+
+    #[test]
+    fn nearest_along_polyline_matches_brute_force() {
+        let cloud: Vec<P2> = random_point_cloud(2000);
+        let tree = KDTree::new(&cloud);
+        // a random walk, with a repeated vertex
+        let mut polyline = vec![random_point::<2>()];
+        for _ in 0..40 {
+            let last = polyline[polyline.len() - 1];
+            polyline.push(last + random_point::<2>() * 0.1);
+        }
+        polyline.push(polyline[polyline.len() - 1]);
+        let results = tree.nearest_along_polyline(&polyline, &Parameters::default());
+        assert_eq!(results.len(), 2 * polyline.len() - 1);
+        let points: Vec<[f32; 2]> = cloud.iter().map(|p| (*p).into()).collect();
+        for (position, result) in results.iter().enumerate() {
+            let a: [f32; 2] = polyline[position / 2].into();
+            let b: [f32; 2] = polyline[(position + 1) / 2].into();
+            let expected = points
+                .iter()
+                .map(|p| segment_dist2(a, b, *p))
+                .fold(f32::INFINITY, f32::min);
+            let result = result.unwrap();
+            let dist2 = segment_dist2(a, b, points[result.index as usize]);
+            assert!(
+                approx_eq!(f32, dist2, expected, epsilon = 1e-3),
+                "{dist2} {expected}"
+            );
+            assert!(approx_eq!(f32, *result.dist2, expected, epsilon = 1e-3));
+        }
+        let radius = Parameters {
+            max_radius: 1e-3,
+            ..Parameters::default()
+        };
+        let far = [P2::new2d(1e4, 1e4), P2::new2d(2e4, 1e4)];
+        assert_eq!(tree.nearest_along_polyline(&far, &radius), vec![None; 3]);
+        assert!(tree.nearest_along_polyline(&[], &radius).is_empty());
+    }
+}
@@ -0,0 +1,346 @@
+//! Classification and regression by the nearest neighbours of queries
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+use num_traits::Float;
+
+use crate::distance_scale::unscale_dist2;
+use crate::internal_parameters::InternalParameters;
+use crate::{InternalNeighbour, KDTree, Parameters, Point, QueryError, Scalar, SelfMatch};
+
+/// How the neighbours are weighted by [`KDTree::knn_regress`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Weighting {
+    /// all neighbours have the same weight
+    Uniform,
+    /// neighbours have a weight of one over their distance, and neighbours at distance zero take precedence
+    InverseDistance,
+}
+
+impl<T: Scalar + Send + Sync, P: Point<T> + Send + Sync> KDTree<T, P> {
+    /// Predicts a label for each of `queries` by majority vote among its `k` nearest neighbours,
+    /// `labels[i]` being the label of the point of index `i` in the original point cloud.
+    ///
+    /// Ties are broken in favour of the label of the nearest neighbour among the tied labels.
+    /// A query without neighbour within `parameters.max_radius`, or with `k` zero, has no label.
+    /// `parameters.sort_results` is ignored.
+    /// If the `rayon` feature is enabled, queries are processed in parallel.
+    ///
+    /// Returns an error if `labels` does not have one element per point of the tree.
+    pub fn knn_classify(
+        &self,
+        queries: &[P],
+        labels: &[u32],
+        k: u32,
+        parameters: &Parameters<T>,
+    ) -> Result<Vec<Option<u32>>, QueryError> {
+        self.check_values_len(labels.len())?;
+        Ok(self.predict(Some(queries), k, parameters, |neighbours| {
+            self.vote(neighbours, labels)
+        }))
+    }
+
+    /// Predicts a label for each point of the tree from its `k` nearest other points,
+    /// for leave-one-out evaluation, in the order of the original point cloud.
+    ///
+    /// Points are excluded by index, so duplicates of a point still vote for it, see [`Self::knn_classify`].
+    pub fn knn_classify_loo(
+        &self,
+        labels: &[u32],
+        k: u32,
+        parameters: &Parameters<T>,
+    ) -> Result<Vec<Option<u32>>, QueryError> {
+        self.check_values_len(labels.len())?;
+        Ok(self.predict(None, k, parameters, |neighbours| {
+            self.vote(neighbours, labels)
+        }))
+    }
+
+    fn vote(&self, neighbours: &[InternalNeighbour<T>], labels: &[u32]) -> Option<u32> {
+        // neighbours are sorted, so the first label to reach the best count is the one of the nearest tied neighbour
+        let label = |neighbour: &InternalNeighbour<T>| {
+            labels[self.indices[neighbour.index as usize] as usize]
+        };
+        let mut best: Option<(usize, u32)> = None;
+        for (rank, neighbour) in neighbours.iter().enumerate() {
+            let candidate = label(neighbour);
+            // only count a label at its first occurrence
+            if neighbours[..rank]
+                .iter()
+                .any(|other| label(other) == candidate)
+            {
+                continue;
+            }
+            let count = neighbours[rank..]
+                .iter()
+                .filter(|other| label(other) == candidate)
+                .count();
+            if best.map_or(true, |(best_count, _)| count > best_count) {
+                best = Some((count, candidate));
+            }
+        }
+        best.map(|(_, label)| label)
+    }
+
+    /// Calls `f` with the `k` nearest neighbours of each query, sorted by increasing distance,
+    /// or of each stored point excluding itself if `queries` is `None`
+    fn predict<R: Send>(
+        &self,
+        queries: Option<&[P]>,
+        k: u32,
+        parameters: &Parameters<T>,
+        f: impl Fn(&[InternalNeighbour<T>]) -> Option<R> + Sync + Send,
+    ) -> Vec<Option<R>> {
+        let count = queries.map_or(self.len(), <[P]>::len);
+        let k = k.min(self.len() as u32) as usize;
+        let leave_one_out = queries.is_none();
+        let parameters = Parameters {
+            self_match: if leave_one_out {
+                SelfMatch::Allow
+            } else {
+                parameters.self_match
+            },
+            ..*parameters
+        };
+        let internal_parameters = InternalParameters::new(&parameters, self.scale);
+        let capacity = k + leave_one_out as usize;
+        self.map_indices_with(
+            count,
+            || {
+                (
+                    BinaryHeap::with_capacity(capacity),
+                    Vec::with_capacity(capacity),
+                )
+            },
+            |(heap, neighbours), index| {
+                if k == 0 {
+                    return None;
+                }
+                match queries {
+                    Some(queries) => {
+                        let query = self.query_coordinates(&queries[index]);
+                        self.knn_internal(heap, &query, &internal_parameters);
+                    }
+                    None => self.knn_of_stored_point(index as u32, heap, &internal_parameters),
+                }
+                neighbours.clear();
+                neighbours.extend(heap.drain());
+                neighbours.sort_unstable();
+                if leave_one_out {
+                    let source = self.internalise_index(index as u32);
+                    match neighbours.iter().position(|n| n.index == source) {
+                        Some(position) => {
+                            neighbours.remove(position);
+                        }
+                        // the point is hidden behind duplicates, drop the furthest of them
+                        None => neighbours.truncate(k),
+                    }
+                }
+                if neighbours.is_empty() {
+                    None
+                } else {
+                    f(neighbours)
+                }
+            },
+        )
+    }
+
+    fn check_values_len(&self, values: usize) -> Result<(), QueryError> {
+        if values == self.len() {
+            Ok(())
+        } else {
+            Err(QueryError::ValuesLengthMismatch {
+                points: self.len(),
+                values,
+            })
+        }
+    }
+}
+
+impl<T: Scalar + Float + Send + Sync, P: Point<T> + Send + Sync> KDTree<T, P> {
+    /// Predicts a value for each of `queries` by averaging the targets of its `k` nearest neighbours,
+    /// `targets[i]` being the target of the point of index `i` in the original point cloud.
+    ///
+    /// A query without neighbour within `parameters.max_radius`, or with `k` zero, has no value.
+    /// `parameters.sort_results` is ignored.
+    /// If the `rayon` feature is enabled, queries are processed in parallel.
+    ///
+    /// Returns an error if `targets` does not have one element per point of the tree.
+    pub fn knn_regress(
+        &self,
+        queries: &[P],
+        targets: &[T],
+        k: u32,
+        weighting: Weighting,
+        parameters: &Parameters<T>,
+    ) -> Result<Vec<Option<T>>, QueryError> {
+        self.check_values_len(targets.len())?;
+        Ok(self.predict(Some(queries), k, parameters, |neighbours| {
+            Some(self.weighted_mean(neighbours, targets, weighting))
+        }))
+    }
+
+    /// Predicts a value for each point of the tree from its `k` nearest other points,
+    /// for leave-one-out evaluation, in the order of the original point cloud.
+    ///
+    /// Points are excluded by index, see [`Self::knn_regress`] and [`Self::knn_classify_loo`].
+    pub fn knn_regress_loo(
+        &self,
+        targets: &[T],
+        k: u32,
+        weighting: Weighting,
+        parameters: &Parameters<T>,
+    ) -> Result<Vec<Option<T>>, QueryError> {
+        self.check_values_len(targets.len())?;
+        Ok(self.predict(None, k, parameters, |neighbours| {
+            Some(self.weighted_mean(neighbours, targets, weighting))
+        }))
+    }
+
+    /// Returns the mean of the targets of `neighbours`, which are sorted and not empty
+    fn weighted_mean(
+        &self,
+        neighbours: &[InternalNeighbour<T>],
+        targets: &[T],
+        weighting: Weighting,
+    ) -> T {
+        let target = |neighbour: &InternalNeighbour<T>| {
+            targets[self.indices[neighbour.index as usize] as usize]
+        };
+        let zero = T::zero();
+        let exact_hits = neighbours[0].dist2 == zero;
+        let (mut weighted_sum, mut weight_sum) = (zero, zero);
+        for neighbour in neighbours {
+            let weight = match weighting {
+                Weighting::Uniform => T::one(),
+                Weighting::InverseDistance if exact_hits => {
+                    if neighbour.dist2 == zero {
+                        T::one()
+                    } else {
+                        break;
+                    }
+                }
+                // relative to the nearest neighbour, so that weights do not overflow
+                Weighting::InverseDistance => (unscale_dist2(neighbours[0].dist2, self.scale)
+                    / unscale_dist2(neighbour.dist2, self.scale))
+                .into_inner()
+                .sqrt(),
+            };
+            weighted_sum += weight * target(neighbour);
+            weight_sum += weight;
+        }
+        weighted_sum / weight_sum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+    use float_cmp::approx_eq;
+
+    #[test]
+    fn knn_classify_votes_and_breaks_ties_by_distance() {
+        let cloud = vec![
+            P2::new2d(0.0, 0.0),
+            P2::new2d(1.0, 0.0),
+            P2::new2d(2.0, 0.0),
+            P2::new2d(3.0, 0.0),
+            P2::new2d(10.0, 0.0),
+        ];
+        let labels = [7, 5, 5, 7, 9];
+        let tree = KDTree::new(&cloud);
+        let parameters = Parameters::default();
+        let queries = [
+            P2::new2d(1.1, 0.0),
+            P2::new2d(0.4, 0.0),
+            P2::new2d(2.6, 0.0),
+            P2::new2d(10.0, 0.0),
+        ];
+        let predicted = tree.knn_classify(&queries, &labels, 3, &parameters);
+        assert_eq!(predicted, Ok(vec![Some(5), Some(5), Some(5), Some(9)]));
+        // two labels with two votes each, the nearest neighbour decides
+        let predicted = tree.knn_classify(&queries[..2], &labels, 4, &parameters);
+        assert_eq!(predicted, Ok(vec![Some(5), Some(7)]));
+        let radius = Parameters {
+            max_radius: 0.5,
+            ..Parameters::default()
+        };
+        let predicted = tree.knn_classify(&[P2::new2d(5.0, 0.0)], &labels, 3, &radius);
+        assert_eq!(predicted, Ok(vec![None]));
+        assert_eq!(
+            tree.knn_classify(&queries, &labels[..2], 3, &parameters),
+            Err(QueryError::ValuesLengthMismatch {
+                points: 5,
+                values: 2
+            })
+        );
+        // leave-one-out: each point is classified by the others,
+        // points 1 and 2 having two nearest neighbours
+        let predicted = tree.knn_classify_loo(&labels, 1, &parameters).unwrap();
+        assert_eq!(
+            [predicted[0], predicted[3], predicted[4]],
+            [Some(5), Some(5), Some(7)]
+        );
+    }
+
+    #[test]
+    fn knn_regress_matches_explicit_neighbours() {
+        let cloud: Vec<P2> = random_point_cloud(500);
+        let targets: Vec<f32> = (0..500).map(|i| (i % 13) as f32).collect();
+        let tree = KDTree::new(&cloud);
+        let parameters = Parameters::default();
+        for weighting in [Weighting::Uniform, Weighting::InverseDistance] {
+            let queries: Vec<P2> = random_point_cloud(30);
+            let predicted = tree
+                .knn_regress(&queries, &targets, 5, weighting, &parameters)
+                .unwrap();
+            let loo = tree
+                .knn_regress_loo(&targets, 5, weighting, &parameters)
+                .unwrap();
+            let cases = queries
+                .iter()
+                .zip(predicted)
+                .map(|(query, value)| (tree.knn(5, query), value))
+                .chain(
+                    cloud
+                        .iter()
+                        .enumerate()
+                        .zip(loo)
+                        .map(|((index, point), value)| {
+                            let mut neighbours = tree.knn(6, point);
+                            neighbours.retain(|n| n.index != index as u32);
+                            neighbours.truncate(5);
+                            (neighbours, value)
+                        }),
+                );
+            for (neighbours, value) in cases {
+                let weight = |n: &Neighbour<f32, P2>| match weighting {
+                    Weighting::Uniform => 1.0,
+                    Weighting::InverseDistance => 1.0 / n.dist2.sqrt(),
+                };
+                let weight_sum: f32 = neighbours.iter().map(weight).sum();
+                let expected = neighbours
+                    .iter()
+                    .map(|n| weight(n) * targets[n.index as usize])
+                    .sum::<f32>()
+                    / weight_sum;
+                let value = value.unwrap();
+                assert!(
+                    approx_eq!(f32, value, expected, epsilon = 1e-4),
+                    "{value} {expected}"
+                );
+            }
+        }
+        // an exact hit takes precedence with inverse distance weighting
+        let predicted = tree
+            .knn_regress(
+                &cloud[..1],
+                &targets,
+                5,
+                Weighting::InverseDistance,
+                &parameters,
+            )
+            .unwrap();
+        assert_eq!(predicted, vec![Some(targets[0])]);
+    }
+}
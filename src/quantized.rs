@@ -0,0 +1,451 @@
+//! A tree storing its points quantized to 16 bits per coordinate
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+
+use num_traits::{clamp_min, Zero};
+use ordered_float::NotNan;
+
+use crate::dim_buffer::DimBuffer;
+use crate::distance_scale::{saturate, unscale_dist2};
+use crate::infinite::HasInfinite;
+use crate::node::Node;
+use crate::{update_rd, CandidateCollector, InternalNeighbour, KDTree, Neighbour, Point, Scalar};
+
+/// The largest code of a quantized coordinate
+const MAX_CODE: u16 = u16::MAX;
+
+/// A [`KDTree`] storing the coordinates of its points as 16-bit codes, affine per dimension
+///
+/// Each coordinate is stored as the nearest of 65536 values evenly spaced between the smallest and the largest
+/// coordinate of the cloud along its dimension, which divides the memory of the points by 2 for `f32`
+/// and by 4 for `f64`.
+/// [`Self::knn`] searches the stored, dequantized points exactly.
+/// [`Self::knn_exact`] searches the original points exactly, bounding the quantization error
+/// and computing the exact distances of the few candidates that the error leaves undecided.
+pub struct QuantizedKDTree<T: Scalar, P: Point<T>> {
+    nodes: Vec<Node<T, P>>,
+    /// the codes of the coordinates of the points, in traversal order, `P::DIM` per point
+    codes: Vec<u16>,
+    /// index in the original point cloud of each point in traversal order
+    indices: Vec<u32>,
+    /// per dimension, the value of code 0
+    origins: Vec<T>,
+    /// per dimension, the difference between the values of consecutive codes
+    steps: Vec<T>,
+    /// per dimension, a bound on the difference between a coordinate and its dequantized value
+    errors: Vec<T>,
+    /// the largest of `errors`, by how much dequantized points can lie across a split
+    max_error: NotNan<T>,
+    /// the factor of coordinate differences in distances, that of the tree the codes were made from
+    scale: NotNan<T>,
+}
+
+impl<T: Scalar, P: Point<T>> QuantizedKDTree<T, P> {
+    /// Creates a quantized tree from `cloud`, with the default bucket size of [`KDTree::new`].
+    pub fn new(cloud: &[P]) -> Self {
+        Self::from_tree(KDTree::new(cloud))
+    }
+
+    /// Creates a quantized tree from `cloud`, with buckets of `bucket_size` points.
+    ///
+    /// Panics if `bucket_size` is smaller than 2.
+    pub fn new_with_bucket_size(cloud: &[P], bucket_size: u32) -> Self {
+        Self::from_tree(KDTree::new_with_bucket_size(cloud, bucket_size))
+    }
+
+    fn from_tree(tree: KDTree<T, P>) -> Self {
+        let dim = P::DIM as usize;
+        let mut origins = alloc::vec![T::zero(); dim];
+        let mut steps = alloc::vec![T::zero(); dim];
+        let mut errors = alloc::vec![T::zero(); dim];
+        if !tree.is_empty() {
+            let max_code = T::from(MAX_CODE).expect("the largest code is representable");
+            for i in 0..dim {
+                let min = tree.min_bounds[i].into_inner();
+                let max = tree.max_bounds[i].into_inner();
+                origins[i] = min;
+                steps[i] = (max - min) / max_code;
+                // half a step, and as much again for the rounding of the dequantization
+                errors[i] = steps[i];
+            }
+        }
+        let codes = tree
+            .points
+            .chunks_exact(dim.max(1))
+            .flat_map(|point| {
+                point.iter().enumerate().map(|(i, value)| {
+                    if steps[i] == T::zero() {
+                        0
+                    } else {
+                        let code = ((value.into_inner() - origins[i]) / steps[i]).round();
+                        code.to_u16().unwrap_or(MAX_CODE)
+                    }
+                })
+            })
+            .collect();
+        let max_error = errors.iter().fold(NotNan::<T>::zero(), |max, error| {
+            max.max(NotNan::new(*error).expect("quantization errors are not NaN"))
+        });
+        QuantizedKDTree {
+            nodes: tree.nodes,
+            codes,
+            indices: tree.indices,
+            origins,
+            steps,
+            errors,
+            max_error,
+            scale: tree.scale,
+        }
+    }
+
+    /// Returns the number of points of the tree.
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    /// Returns whether the tree has no points.
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    /// Returns the difference between the values of consecutive codes along dimension `dim`,
+    /// twice the largest quantization error.
+    pub fn step(&self, dim: u32) -> T {
+        self.steps[dim as usize]
+    }
+
+    /// Returns the stored, dequantized points, in the order of the original point cloud.
+    pub fn points(&self) -> Vec<P> {
+        let mut points = Vec::with_capacity(self.len());
+        points.resize_with(self.len(), P::default);
+        for (internal, external) in self.indices.iter().enumerate() {
+            points[*external as usize] = self.stored_point(internal);
+        }
+        points
+    }
+
+    /// Finds the `k` nearest neighbours of `query` among the stored, dequantized points,
+    /// sorted by increasing distance.
+    ///
+    /// The neighbours are the dequantized points and their distances to `query`.
+    pub fn knn(&self, k: u32, query: &P) -> Vec<Neighbour<T, P>> {
+        let mut scan = DequantizedScan {
+            heap: BinaryHeap::with_capacity(k.min(self.len() as u32) as usize),
+            query,
+        };
+        self.search(query, self.max_error * self.scale, &mut scan);
+        scan.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|n| Neighbour {
+                point: self.stored_point(n.index as usize),
+                dist2: unscale_dist2(n.dist2, self.scale),
+                index: self.indices[n.index as usize],
+            })
+            .collect()
+    }
+
+    /// Finds the `k` nearest neighbours of `query` among the original points, sorted by increasing distance.
+    ///
+    /// `cloud` must be the point cloud from which this tree was created, only the candidates
+    /// whose order the quantization leaves undecided are read from it.
+    /// The results are those of [`KDTree::knn`] on `cloud`, up to the order of points at equal distances.
+    ///
+    /// Panics if `cloud` does not have as many points as the tree.
+    pub fn knn_exact(&self, k: u32, query: &P, cloud: &[P]) -> Vec<Neighbour<T, P>> {
+        assert_eq!(
+            cloud.len(),
+            self.len(),
+            "cloud does not have the points of the tree"
+        );
+        let k = k.min(self.len() as u32) as usize;
+        let mut scan = CandidateScan {
+            upper_bounds: BinaryHeap::with_capacity(k),
+            candidates: Vec::new(),
+            query,
+        };
+        if k > 0 {
+            // original points are on the side of their splits
+            self.search(query, NotNan::zero(), &mut scan);
+        }
+        let bound2 = scan.bound2();
+        let mut heap = BinaryHeap::<InternalNeighbour<T>>::with_capacity(k);
+        for (lower_bound2, internal) in scan.candidates {
+            if lower_bound2 <= bound2 {
+                let external = self.indices[internal as usize];
+                let dist2 = saturated_dist2(query, &cloud[external as usize], self.scale);
+                heap.add(dist2, internal);
+            }
+        }
+        heap.into_sorted_vec()
+            .into_iter()
+            .map(|n| {
+                let index = self.indices[n.index as usize];
+                Neighbour {
+                    point: copy_point(&cloud[index as usize]),
+                    dist2: unscale_dist2(n.dist2, self.scale),
+                    index,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the dequantized point of internal index `internal`
+    fn stored_point(&self, internal: usize) -> P {
+        let mut point = P::default();
+        let dim = P::DIM as usize;
+        for (i, code) in self.codes[internal * dim..(internal + 1) * dim]
+            .iter()
+            .enumerate()
+        {
+            let value = self.dequantized(i, *code);
+            point.set(
+                i as u32,
+                NotNan::new(value).expect("dequantized values are not NaN"),
+            );
+        }
+        point
+    }
+
+    /// Returns the value of `code` along dimension `dim`
+    #[inline]
+    fn dequantized(&self, dim: usize, code: u16) -> T {
+        self.origins[dim] + T::from(code).expect("codes are representable") * self.steps[dim]
+    }
+
+    /// Searches the nodes that `scan` is interested in, assuming points are at most `drift`
+    /// across the splits of their nodes, `drift` and distances being scaled by the scale of the tree
+    fn search<S: LeafScan<T, P>>(&self, query: &P, drift: NotNan<T>, scan: &mut S) {
+        if self.is_empty() {
+            return;
+        }
+        let query = DimBuffer::try_from_fn(P::DIM as usize, |i| Ok::<_, ()>(query.get(i as u32)))
+            .expect("coordinates are always given");
+        let mut off = DimBuffer::new(P::DIM as usize, NotNan::<T>::zero());
+        self.recurse(&query, 0, NotNan::zero(), &mut off, drift, scan);
+    }
+
+    fn recurse<S: LeafScan<T, P>>(
+        &self,
+        query: &[NotNan<T>],
+        node: usize,
+        rd: NotNan<T>,
+        off: &mut [NotNan<T>],
+        drift: NotNan<T>,
+        scan: &mut S,
+    ) {
+        self.nodes[node].dispatch_on_type(
+            scan,
+            |scan, split_dim, split_val, right_child| {
+                let split_dim = split_dim as usize;
+                let old_off = off[split_dim];
+                let new_off = (query[split_dim] - split_val) * self.scale;
+                let far_off = if new_off > NotNan::<T>::zero() {
+                    clamp_min(new_off - drift, NotNan::<T>::zero())
+                } else {
+                    -clamp_min(-new_off - drift, NotNan::<T>::zero())
+                };
+                let (near, far) = if new_off > NotNan::<T>::zero() {
+                    (right_child as usize, node + 1)
+                } else {
+                    (node + 1, right_child as usize)
+                };
+                self.recurse(query, near, rd, off, drift, scan);
+                let rd = update_rd(rd, old_off, far_off);
+                if rd <= scan.bound2() {
+                    off[split_dim] = far_off;
+                    self.recurse(query, far, rd, off, drift, scan);
+                    off[split_dim] = old_off;
+                }
+            },
+            |scan, bucket_start_index, bucket_size| {
+                scan.scan(self, bucket_start_index as usize, bucket_size as usize);
+            },
+        );
+    }
+}
+
+/// Returns a copy of `point`, as points are not required to be `Clone`
+fn copy_point<T: Scalar, P: Point<T>>(point: &P) -> P {
+    let mut copy = P::default();
+    for i in 0..P::DIM {
+        copy.set(i, point.get(i));
+    }
+    copy
+}
+
+/// Returns the squared distance between two points with differences multiplied by `scale`,
+/// saturated to `T::max_value()`
+fn saturated_dist2<T: Scalar, P: Point<T>>(lhs: &P, rhs: &P, scale: NotNan<T>) -> NotNan<T> {
+    let mut dist2 = T::zero();
+    for i in 0..P::DIM {
+        let diff = (lhs.get(i).into_inner() - rhs.get(i).into_inner()) * scale.into_inner();
+        dist2 += diff * diff;
+    }
+    saturate(NotNan::new(dist2).unwrap_or_else(|_| NotNan::infinite()))
+}
+
+/// What a search does with the points of the leaves it reaches
+trait LeafScan<T: Scalar, P: Point<T>> {
+    /// The squared distance beyond which the search is not interested in cells
+    fn bound2(&self) -> NotNan<T>;
+    /// Scans the `size` points starting at internal index `start`
+    fn scan(&mut self, tree: &QuantizedKDTree<T, P>, start: usize, size: usize);
+}
+
+/// Keeps the nearest dequantized points, by scaled squared distances
+struct DequantizedScan<'a, T: Scalar, P> {
+    heap: BinaryHeap<InternalNeighbour<T>>,
+    query: &'a P,
+}
+
+impl<'a, T: Scalar, P: Point<T>> LeafScan<T, P> for DequantizedScan<'a, T, P> {
+    fn bound2(&self) -> NotNan<T> {
+        self.heap.furthest_dist2().unwrap_or_else(NotNan::infinite)
+    }
+
+    fn scan(&mut self, tree: &QuantizedKDTree<T, P>, start: usize, size: usize) {
+        let dim = P::DIM as usize;
+        for internal in start..start + size {
+            let mut dist2 = T::zero();
+            for (i, code) in tree.codes[internal * dim..(internal + 1) * dim]
+                .iter()
+                .enumerate()
+            {
+                let diff = self.query.get(i as u32).into_inner() - tree.dequantized(i, *code);
+                let diff = diff * tree.scale.into_inner();
+                dist2 += diff * diff;
+            }
+            let dist2 = saturate(NotNan::new(dist2).unwrap_or_else(|_| NotNan::infinite()));
+            self.heap.add(dist2, internal as u32);
+        }
+    }
+}
+
+/// Keeps the points whose original distance may be among the nearest,
+/// with lower bounds to their scaled squared distances
+struct CandidateScan<'a, T: Scalar, P> {
+    /// the smallest upper bounds of the distances of points
+    upper_bounds: BinaryHeap<NotNan<T>>,
+    candidates: Vec<(NotNan<T>, u32)>,
+    query: &'a P,
+}
+
+impl<'a, T: Scalar, P: Point<T>> LeafScan<T, P> for CandidateScan<'a, T, P> {
+    fn bound2(&self) -> NotNan<T> {
+        match self.upper_bounds.peek() {
+            Some(furthest) if self.upper_bounds.len() == self.upper_bounds.capacity() => *furthest,
+            _ => NotNan::infinite(),
+        }
+    }
+
+    fn scan(&mut self, tree: &QuantizedKDTree<T, P>, start: usize, size: usize) {
+        let dim = P::DIM as usize;
+        for internal in start..start + size {
+            let mut lower_bound2 = T::zero();
+            let mut upper_bound2 = T::zero();
+            for (i, code) in tree.codes[internal * dim..(internal + 1) * dim]
+                .iter()
+                .enumerate()
+            {
+                let scale = tree.scale.into_inner();
+                let diff = (self.query.get(i as u32).into_inner() - tree.dequantized(i, *code))
+                    .abs()
+                    * scale;
+                let error = tree.errors[i] * scale;
+                let lower = if diff > error {
+                    diff - error
+                } else {
+                    T::zero()
+                };
+                let upper = diff + error;
+                lower_bound2 += lower * lower;
+                upper_bound2 += upper * upper;
+            }
+            let lower_bound2 = NotNan::new(lower_bound2).unwrap_or_else(|_| NotNan::infinite());
+            let upper_bound2 = NotNan::new(upper_bound2).unwrap_or_else(|_| NotNan::infinite());
+            let bound2 = <Self as LeafScan<T, P>>::bound2(self);
+            if lower_bound2 <= bound2 {
+                self.candidates.push((lower_bound2, internal as u32));
+            }
+            if upper_bound2 < bound2 {
+                if self.upper_bounds.len() < self.upper_bounds.capacity() {
+                    self.upper_bounds.push(upper_bound2);
+                } else if let Some(mut furthest) = self.upper_bounds.peek_mut() {
+                    *furthest = upper_bound2;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn exact_queries_match_the_unquantized_tree() {
+        let cloud: Vec<P3> = random_point_cloud(5000);
+        let tree = KDTree::new(&cloud);
+        let quantized = QuantizedKDTree::new(&cloud);
+        assert_eq!(quantized.len(), cloud.len());
+        for _ in 0..100 {
+            let query = random_point();
+            for k in [1, 4, 20] {
+                let expected = tree.knn(k, &query);
+                let neighbours = quantized.knn_exact(k, &query, &cloud);
+                assert_eq!(neighbours, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn dequantized_queries_match_a_tree_of_the_stored_points() {
+        let cloud: Vec<P2> = random_point_cloud(5000);
+        let quantized = QuantizedKDTree::new_with_bucket_size(&cloud, 16);
+        let stored = quantized.points();
+        for (point, stored) in cloud.iter().zip(&stored) {
+            for i in 0..2 {
+                assert!((*point.get(i) - *stored.get(i)).abs() <= quantized.step(i) / 2.0 * 1.01);
+            }
+        }
+        let tree = KDTree::new(&stored);
+        for _ in 0..100 {
+            let query = random_point();
+            let neighbours = quantized.knn(10, &query);
+            let expected = tree.knn(10, &query);
+            assert_eq!(neighbours.len(), 10);
+            for (neighbour, expected) in neighbours.iter().zip(&expected) {
+                assert_eq!(neighbour.index, expected.index);
+                assert_eq!(neighbour.point, expected.point);
+            }
+        }
+        let empty = QuantizedKDTree::<f32, P2>::new(&[]);
+        assert!(empty.knn(3, &P2::new2d(0.0, 0.0)).is_empty());
+        assert!(empty.knn_exact(3, &P2::new2d(0.0, 0.0), &[]).is_empty());
+    }
+
+    #[test]
+    fn queries_do_not_depend_on_the_magnitude_of_coordinates() {
+        let cloud: Vec<P2> = random_point_cloud(400);
+        let queries: Vec<P2> = random_point_cloud(20);
+        for factor in [2f32.powi(-100), 1e20] {
+            let scaled: Vec<P2> = cloud.iter().map(|p| *p * factor).collect();
+            let tree = KDTree::new(&scaled);
+            let quantized = QuantizedKDTree::new(&scaled);
+            let stored_tree = KDTree::new(&quantized.points());
+            for query in &queries {
+                let query = *query * factor;
+                assert_eq!(quantized.knn_exact(5, &query, &scaled), tree.knn(5, &query));
+                let indices = |neighbours: Vec<Neighbour<f32, P2>>| -> Vec<u32> {
+                    neighbours.iter().map(|n| n.index).collect()
+                };
+                assert_eq!(
+                    indices(quantized.knn(5, &query)),
+                    indices(stored_tree.knn(5, &query))
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Explanations of how the parameters of a query shaped its results, to debug their interplay
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+
+use num_traits::Float;
+use ordered_float::NotNan;
+
+use crate::distance_scale::unscale_dist2;
+use crate::heap::CandidateHeap;
+use crate::internal_parameters::InternalParameters;
+use crate::query_trace::NodeVisitor;
+use crate::{
+    Boundary, CandidateCollector, InternalNeighbour, KDTree, Parameters, Point, QueryStats, Scalar,
+};
+
+/// A likely misuse of the parameters of a query, see [`KDTree::explain_query`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum QueryHint {
+    /// No point is within `max_radius`, although the tree has points
+    EmptyWithinRadius,
+    /// Fewer than `k` neighbours were found because the others are beyond `max_radius`
+    RadiusLimitsResults,
+    /// `max_radius` divided by `1 + epsilon` is smaller than the distance to the nearest point:
+    /// the approximate search guarantees nothing within the radius, so a radius of the order of the spacing
+    /// of the points needs a smaller `epsilon`
+    RadiusTooSmallForEpsilon,
+    /// `epsilon` is positive but the search visited as many nodes as an exact one, for example because
+    /// `max_radius` prunes more than the approximation: the results are approximate for no speedup
+    EpsilonIneffective,
+}
+
+/// How the parameters of a query shaped its results, see [`KDTree::explain_query`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryExplanation<T: Scalar> {
+    /// number of neighbours found by the query
+    pub found: usize,
+    /// counters of the work done by the query
+    pub stats: QueryStats,
+    /// counters of the work done by the same query with an `epsilon` of zero
+    pub exact_stats: QueryStats,
+    /// the radius within which the search visited all cells by its end: `max_radius`, or if it found
+    /// `k` neighbours and that is smaller, the distance of the furthest divided by `1 + epsilon`
+    pub effective_radius: T,
+    /// number of nodes pruned because their cell is beyond `max_radius`
+    pub nodes_pruned_by_radius: u32,
+    /// number of nodes pruned because their cell, with the `1 + epsilon` factor, is beyond the `k` neighbours
+    /// found so far
+    pub nodes_pruned_by_k: u32,
+    /// number of the `k` exact nearest neighbours, without radius, that are beyond `max_radius`
+    pub excluded_by_radius: usize,
+    /// number of points within `max_radius` that are not among the neighbours found
+    pub excluded_by_k: usize,
+    /// the likely misuses of the parameters
+    pub hints: Vec<QueryHint>,
+}
+
+/// Counts the nodes pruned by the radius and by the bound of the collector
+struct PruneCounter<T: Scalar> {
+    max_radius2: NotNan<T>,
+    by_radius: u32,
+    by_k: u32,
+}
+
+impl<T: Scalar> NodeVisitor<T> for PruneCounter<T> {
+    const ENABLED: bool = true;
+    fn visit(
+        &mut self,
+        _node: usize,
+        lower_bound2: NotNan<T>,
+        _bound2: Option<NotNan<T>>,
+        descended: bool,
+    ) {
+        match (descended, lower_bound2 > self.max_radius2) {
+            (true, _) => (),
+            (false, true) => self.by_radius += 1,
+            (false, false) => self.by_k += 1,
+        }
+    }
+}
+
+/// Counts all candidates
+struct PointCounter(usize);
+
+impl<T: Scalar> CandidateCollector<T> for PointCounter {
+    fn add(&mut self, _dist2: NotNan<T>, _index: u32) {
+        self.0 += 1;
+    }
+
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        None
+    }
+}
+
+impl<T: Scalar + Float, P: Point<T>> KDTree<T, P> {
+    /// Runs the search of the `k` nearest neighbours of `query` with `parameters` and explains how
+    /// `epsilon`, `max_radius` and `k` shaped its results, with `hints` on likely misuses.
+    ///
+    /// The search is that of [`Self::knn_advanced`] with [`InsufficientPolicy::Truncate`](crate::InsufficientPolicy::Truncate),
+    /// counting why it pruned each node it did not descend into.
+    /// This also runs an exact search without radius, an exact search with the radius and counts
+    /// the points within the radius, so it costs several queries and is meant for debugging.
+    ///
+    /// Panics if the parameters are invalid, see [`Parameters::validate`].
+    pub fn explain_query(
+        &self,
+        k: u32,
+        query: &P,
+        parameters: &Parameters<T>,
+    ) -> QueryExplanation<T> {
+        parameters
+            .validate()
+            .unwrap_or_else(|error| panic!("{}", error));
+        let coordinates = self.query_coordinates(query);
+        let internal_parameters = InternalParameters::new(parameters, self.scale);
+        let k = k.min(self.len() as u32);
+        let mut heap = BinaryHeap::<InternalNeighbour<T>>::new_with_k(k);
+        let mut counter = PruneCounter {
+            max_radius2: internal_parameters.max_radius2,
+            by_radius: 0,
+            by_k: 0,
+        };
+        let stats =
+            self.knn_internal_visited(&mut heap, &mut counter, &coordinates, &internal_parameters);
+        let found = heap.candidate_count();
+        let one = T::one();
+        let effective_radius = match heap.furthest_dist2() {
+            Some(bound2) if k > 0 => {
+                let furthest = Float::sqrt(unscale_dist2(bound2, self.scale).into_inner());
+                Float::min(parameters.max_radius, furthest / (one + parameters.epsilon))
+            }
+            _ => parameters.max_radius,
+        };
+
+        // exact nearest neighbours without radius, to tell which the radius excludes
+        let unbounded = Parameters {
+            epsilon: T::zero(),
+            max_radius: <T as Float>::infinity(),
+            ..*parameters
+        };
+        let container = Self::default_candidate_container(k);
+        let nearest = self.knn_advanced(k, query, container, &unbounded, None);
+        let radius2 = parameters.max_radius * parameters.max_radius;
+        let excluded_by_radius = nearest
+            .iter()
+            .filter(|neighbour| match parameters.boundary {
+                Boundary::Closed => *neighbour.dist2 > radius2,
+                Boundary::Open => *neighbour.dist2 >= radius2,
+            })
+            .count();
+        let mut within_radius = PointCounter(0);
+        self.knn_internal(&mut within_radius, &coordinates, &internal_parameters);
+        let exact = Parameters {
+            epsilon: T::zero(),
+            ..*parameters
+        };
+        let (_, exact_stats) = self.knn_with_stats(k, query, container, &exact);
+
+        let mut hints = Vec::new();
+        if found == 0 && !self.is_empty() && excluded_by_radius > 0 {
+            hints.push(QueryHint::EmptyWithinRadius);
+        } else if found < k as usize && excluded_by_radius > 0 {
+            hints.push(QueryHint::RadiusLimitsResults);
+        }
+        let approximate = parameters.epsilon > T::zero();
+        if let Some(nearest) = nearest.first() {
+            let guaranteed = parameters.max_radius / (one + parameters.epsilon);
+            if approximate && guaranteed * guaranteed < *nearest.dist2 {
+                hints.push(QueryHint::RadiusTooSmallForEpsilon);
+            }
+        }
+        if approximate && stats.visited_nodes >= exact_stats.visited_nodes {
+            hints.push(QueryHint::EpsilonIneffective);
+        }
+        QueryExplanation {
+            found,
+            stats,
+            exact_stats,
+            effective_radius,
+            nodes_pruned_by_radius: counter.by_radius,
+            nodes_pruned_by_k: counter.by_k,
+            excluded_by_radius,
+            excluded_by_k: within_radius.0 - found,
+            hints,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn counts_match_brute_force() {
+        let cloud: Vec<P2> = random_point_cloud(3000);
+        let tree = KDTree::new(&cloud);
+        for max_radius in [f32::INFINITY, 5.0, 0.5] {
+            let parameters = Parameters {
+                max_radius,
+                ..Parameters::default()
+            };
+            for query in (0..20).map(|_| random_point::<2>()) {
+                let explanation = tree.explain_query(10, &query, &parameters);
+                let mut dist2: Vec<f32> = cloud
+                    .iter()
+                    .map(|point| {
+                        let (dx, dy) =
+                            (*point.get(0) - *query.get(0), *point.get(1) - *query.get(1));
+                        dx * dx + dy * dy
+                    })
+                    .collect();
+                dist2.sort_unstable_by(f32::total_cmp);
+                let within = dist2
+                    .iter()
+                    .filter(|d| **d <= max_radius * max_radius)
+                    .count();
+                assert_eq!(explanation.found, within.min(10));
+                assert_eq!(explanation.found + explanation.excluded_by_radius, 10);
+                assert_eq!(explanation.excluded_by_k, within - explanation.found);
+                assert_eq!(explanation.stats, explanation.exact_stats);
+                if max_radius.is_infinite() {
+                    assert_eq!(explanation.nodes_pruned_by_radius, 0);
+                    assert!((explanation.effective_radius - dist2[9].sqrt()).abs() < 1e-3);
+                } else {
+                    assert!(explanation.effective_radius <= max_radius);
+                }
+                let (_, stats) =
+                    tree.knn_with_stats(10, &query, CandidateContainer::BinaryHeap, &parameters);
+                assert_eq!(explanation.stats, stats);
+                assert!(explanation.hints.is_empty() || within < 10);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "strict-exact"))]
+    fn hints_point_to_the_misused_parameter() {
+        // a grid of spacing 1
+        let cloud: Vec<P2> = (0..50)
+            .flat_map(|x| (0..50).map(move |y| P2::new2d(x as f32, y as f32)))
+            .collect();
+        let tree = KDTree::new(&cloud);
+        let query = P2::new2d(10.5, 10.5);
+        // a radius below the spacing
+        let tiny = Parameters {
+            epsilon: 0.3,
+            max_radius: 0.6,
+            ..Parameters::default()
+        };
+        let explanation = tree.explain_query(4, &query, &tiny);
+        assert_eq!(explanation.found, 0);
+        assert_eq!(explanation.excluded_by_radius, 4);
+        assert!(explanation.hints.contains(&QueryHint::EmptyWithinRadius));
+        assert!(explanation
+            .hints
+            .contains(&QueryHint::RadiusTooSmallForEpsilon));
+        // a radius just above the spacing, within the slack of the approximation
+        let close = Parameters {
+            max_radius: 0.8,
+            ..tiny
+        };
+        let explanation = tree.explain_query(8, &query, &close);
+        assert_eq!(explanation.found, 4);
+        assert!(explanation.hints.contains(&QueryHint::RadiusLimitsResults));
+        assert!(explanation
+            .hints
+            .contains(&QueryHint::RadiusTooSmallForEpsilon));
+        assert!(explanation.nodes_pruned_by_radius > 0);
+        // a huge epsilon that the radius makes useless
+        let dominated = Parameters {
+            epsilon: 10.0,
+            max_radius: 1.0,
+            ..Parameters::default()
+        };
+        let explanation = tree.explain_query(100, &query, &dominated);
+        assert!(explanation.hints.contains(&QueryHint::RadiusLimitsResults));
+        assert!(explanation.hints.contains(&QueryHint::EpsilonIneffective));
+        assert_eq!(explanation.stats, explanation.exact_stats);
+        // sensible parameters have no hint
+        let explanation = tree.explain_query(8, &query, &Parameters::default());
+        assert!(explanation.hints.is_empty());
+        assert_eq!(explanation.nodes_pruned_by_radius, 0);
+        assert!(explanation.nodes_pruned_by_k > 0);
+    }
+}
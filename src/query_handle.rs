@@ -0,0 +1,336 @@
+//! Resumable k-nearest-neighbour searches, run within budgets and whose `k` can grow between runs
+
+use alloc::collections::BinaryHeap;
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+
+use num_traits::{clamp_max, clamp_min, Zero};
+use ordered_float::NotNan;
+
+use crate::dim_buffer::DimBuffer;
+use crate::internal_parameters::InternalParameters;
+use crate::leaf_scan::scan_leaf;
+use crate::{
+    update_rd, BoundedCollector, CandidateCollector, InternalNeighbour, KDTree, Neighbour,
+    Parameters, Point, QueryStats, Scalar,
+};
+
+/// Offers candidates to `collector`, keeping those it does not keep in `spilled`
+struct Spilling<'c, T: Scalar, P: Point<T>> {
+    collector: &'c mut BoundedCollector<T, P>,
+    spilled: &'c mut Vec<InternalNeighbour<T>>,
+}
+
+impl<T: Scalar, P: Point<T>> CandidateCollector<T> for Spilling<'_, T, P> {
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        self.spilled
+            .extend(self.collector.add_evicting(dist2, index));
+    }
+
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        self.collector.furthest_dist2()
+    }
+}
+
+/// A search for the nearest neighbours of a query, run by steps, see [`KDTree::begin_query`]
+///
+/// The search is best-first: the nodes not visited yet are kept in a priority queue by the squared
+/// distance from the query to their cell, and the closest one is visited until no closer
+/// node may hold a candidate the collector accepts.
+/// Nodes beyond the furthest candidate are kept rather than pruned, as are the points of the visited
+/// leaves that the collector did not keep, so that growing `k` by [`Self::grow_k`] resumes
+/// the search where it stopped, with the same results as a search for the larger `k` from the start.
+pub struct QueryHandle<'a, T: Scalar, P: Point<T>> {
+    tree: &'a KDTree<T, P>,
+    query: DimBuffer<T>,
+    internal_parameters: InternalParameters<T>,
+    /// the nodes not visited yet, by increasing lower bound of the squared distance to their points,
+    /// with the slot of their offsets in `offsets`
+    pending: BinaryHeap<Reverse<(NotNan<T>, u32, u32)>>,
+    /// per slot, the offsets from the query to the cell of a pending node along each dimension
+    offsets: Vec<NotNan<T>>,
+    free_slots: Vec<u32>,
+    collector: BoundedCollector<T, P>,
+    /// the candidates of the visited leaves that the collector did not keep
+    spilled: Vec<InternalNeighbour<T>>,
+    stats: QueryStats,
+}
+
+impl<'a, T: Scalar, P: Point<T>> QueryHandle<'a, T, P> {
+    /// Visits up to `budget` leaves, closest first, and returns whether the search is complete,
+    /// see [`Self::is_complete`].
+    ///
+    /// It can be called again to continue the search, the results so far being given by [`Self::neighbours`].
+    pub fn run_until(&mut self, budget: u32) -> bool {
+        for _ in 0..budget {
+            if self.is_complete() {
+                break;
+            }
+            let Reverse((rd, node, slot)) = self.pending.pop().expect("the search is not complete");
+            self.visit(rd, node, slot);
+        }
+        self.is_complete()
+    }
+
+    /// Returns whether the candidates are the nearest neighbours, within the error of `parameters.epsilon`,
+    /// because no node left to visit may hold a candidate the collector accepts.
+    pub fn is_complete(&self) -> bool {
+        self.pending.peek().map_or(true, |Reverse((rd, _, _))| {
+            !self
+                .internal_parameters
+                .within_error(*rd, self.collector.furthest_dist2())
+        })
+    }
+
+    /// Searches for the `new_k` nearest neighbours from now on, if more than the current `k`,
+    /// and does nothing otherwise.
+    ///
+    /// The candidates found so far are kept, and the nodes left to visit are visited
+    /// by the next calls to [`Self::run_until`], until the collector is full again.
+    pub fn grow_k(&mut self, new_k: u32) {
+        // there cannot be more neighbours than points, avoid allocating for them
+        let new_k = new_k.min(self.tree.len() as u32);
+        self.collector.grow_k(new_k as usize);
+        // offer again the candidates the smaller collector did not keep
+        let spilled = core::mem::take(&mut self.spilled);
+        for candidate in spilled {
+            self.spilled.extend(
+                self.collector
+                    .add_evicting(candidate.dist2, candidate.index),
+            );
+        }
+    }
+
+    /// Returns the candidates found so far, by increasing distance,
+    /// which are the nearest neighbours once the search is complete.
+    pub fn neighbours(&self) -> Vec<Neighbour<T, P>> {
+        self.collector.clone().into_neighbours(self.tree)
+    }
+
+    /// Consumes the handle, returning the candidates found so far by increasing distance, see [`Self::neighbours`].
+    pub fn into_neighbours(self) -> Vec<Neighbour<T, P>> {
+        self.collector.into_neighbours(self.tree)
+    }
+
+    /// Returns the counters of the work done by all runs so far.
+    pub fn stats(&self) -> QueryStats {
+        self.stats
+    }
+
+    /// Returns a slot holding a copy of the offsets of `slot`
+    fn copy_slot(&mut self, slot: u32) -> u32 {
+        let dim = P::DIM as usize;
+        let start = slot as usize * dim;
+        match self.free_slots.pop() {
+            Some(copy) => {
+                self.offsets
+                    .copy_within(start..start + dim, copy as usize * dim);
+                copy
+            }
+            None => {
+                self.offsets.extend_from_within(start..start + dim);
+                (self.offsets.len() / dim - 1) as u32
+            }
+        }
+    }
+
+    /// Descends from `node` to its nearest leaf, queuing the far children within the radius on the way,
+    /// and offers the points of the leaf to the collector
+    fn visit(&mut self, rd: NotNan<T>, mut node: u32, slot: u32) {
+        let tree = self.tree;
+        let dim = P::DIM as usize;
+        let zero = NotNan::<T>::zero();
+        while let Some((split_dim, split_val, right_child)) = tree.nodes[node as usize]
+            .dispatch_on_type(
+                (),
+                |_, split_dim, split_val, right_child| Some((split_dim, split_val, right_child)),
+                |_, _, _| None,
+            )
+        {
+            self.stats += QueryStats::split();
+            let split_dim = split_dim as usize;
+            let old_off = self.offsets[slot as usize * dim + split_dim];
+            let new_off = (self.query[split_dim] - split_val) * tree.scale;
+            // points that drifted across the split can be closer than the split plane
+            let far_off = if tree.scaled_drift == zero {
+                new_off
+            } else if new_off > zero {
+                clamp_min(new_off - tree.scaled_drift, zero)
+            } else {
+                clamp_max(new_off + tree.scaled_drift, zero)
+            };
+            let (near, far) = if new_off > zero {
+                (right_child, node + 1)
+            } else {
+                (node + 1, right_child)
+            };
+            let far_rd = update_rd(rd, old_off, far_off);
+            // the radius does not change, so nodes beyond it never need a visit
+            if far_rd <= self.internal_parameters.max_radius2 {
+                let far_slot = self.copy_slot(slot);
+                self.offsets[far_slot as usize * dim + split_dim] = far_off;
+                self.pending.push(Reverse((far_rd, far, far_slot)));
+            }
+            node = near;
+        }
+        self.free_slots.push(slot);
+        let (start, size) = tree.nodes[node as usize].dispatch_on_type(
+            (),
+            |_, _, _, _| unreachable!("the descent stops at a leaf"),
+            |_, bucket_start_index, bucket_size| (bucket_start_index, bucket_size),
+        );
+        self.stats += QueryStats::leaf(size);
+        let bucket = &tree.points[start as usize * dim..(start + size) as usize * dim];
+        let mut spilling = Spilling {
+            collector: &mut self.collector,
+            spilled: &mut self.spilled,
+        };
+        scan_leaf::<T, P, _>(
+            &mut spilling,
+            &self.query,
+            bucket,
+            start,
+            tree.scale,
+            &self.internal_parameters,
+        );
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Starts a search for the `k` nearest neighbours of `query`, with user-provided parameters,
+    /// to be run by steps with [`QueryHandle::run_until`], for example by an anytime algorithm.
+    ///
+    /// No node is visited before the first run.
+    /// Once complete, the neighbours are those of [`Self::knn_advanced`], up to ties, by increasing distance.
+    /// `parameters.sort_results`, `parameters.traversal_order` and `parameters.on_insufficient` are ignored:
+    /// the neighbours are always sorted, nodes are visited closest first, and only points within
+    /// the radius are candidates.
+    ///
+    /// Panics if `query` has NaN coordinates.
+    pub fn begin_query(
+        &self,
+        query: &P,
+        k: u32,
+        parameters: &Parameters<T>,
+    ) -> QueryHandle<'_, T, P> {
+        let mut pending = BinaryHeap::new();
+        if !self.is_empty() {
+            pending.push(Reverse((NotNan::zero(), 0, 0)));
+        }
+        let k = k.min(self.len() as u32);
+        QueryHandle {
+            tree: self,
+            query: self.query_coordinates(query),
+            internal_parameters: InternalParameters::new(parameters, self.scale),
+            pending,
+            offsets: alloc::vec![NotNan::zero(); P::DIM as usize],
+            free_slots: Vec::new(),
+            collector: BoundedCollector::with_capacity(k as usize),
+            spilled: Vec::new(),
+            stats: QueryStats::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn dist2s(neighbours: &[Neighbour<f32, P2>]) -> Vec<f32> {
+        neighbours.iter().map(|n| n.dist2.into_inner()).collect()
+    }
+
+    #[test]
+    fn completed_queries_match_knn() {
+        let mut cloud: Vec<P2> = random_point_cloud(3000);
+        let mut tree = KDTree::new(&cloud);
+        for round in 0..2 {
+            for (max_radius, allow_self_match) in [(f32::INFINITY, true), (8.0, false)] {
+                let parameters = Parameters {
+                    max_radius,
+                    self_match: allow_self_match.into(),
+                    ..Parameters::default()
+                };
+                let mut queries: Vec<P2> = random_point_cloud(20);
+                queries.extend_from_slice(&cloud[..10]);
+                for query in &queries {
+                    let expected =
+                        tree.knn_advanced(8, query, CandidateContainer::Linear, &parameters, None);
+                    for budget in [1, 3, u32::MAX] {
+                        let mut handle = tree.begin_query(query, 8, &parameters);
+                        assert_eq!(handle.stats(), QueryStats::default());
+                        let mut runs = 0;
+                        while !handle.run_until(budget) {
+                            runs += 1;
+                            assert!(runs < 10_000);
+                        }
+                        assert!(handle.is_complete());
+                        assert_eq!(dist2s(&handle.neighbours()), dist2s(&expected));
+                        for neighbour in handle.into_neighbours() {
+                            assert_eq!(neighbour.point, cloud[neighbour.index as usize]);
+                        }
+                    }
+                }
+            }
+            if round == 0 {
+                // points moved across their splits
+                for index in (0..3000).step_by(4) {
+                    cloud[index] = cloud[index] * 0.8;
+                    tree.update_point(index as u32, cloud[index]);
+                }
+            }
+        }
+        let empty = KDTree::<f32, P2>::new(&[]);
+        let mut handle = empty.begin_query(&cloud[0], 4, &Parameters::default());
+        assert!(handle.run_until(1));
+        assert!(handle.neighbours().is_empty());
+    }
+
+    #[test]
+    fn growing_k_resumes_the_search() {
+        let cloud: Vec<P2> = random_point_cloud(5000);
+        let tree = KDTree::new_with_bucket_size(&cloud, 4);
+        let parameters = Parameters::default();
+        for _ in 0..30 {
+            let query = random_point();
+            let mut handle = tree.begin_query(&query, 1, &parameters);
+            assert!(handle.run_until(u32::MAX));
+            assert_eq!(dist2s(&handle.neighbours()), dist2s(&tree.knn(1, &query)));
+            let visited = handle.stats().visited_leaves;
+            // the points of the visited leaves are offered again, and the search continues from them
+            let first = handle.neighbours()[0];
+            handle.grow_k(8);
+            assert_eq!(handle.neighbours()[0], first);
+            assert!(handle.run_until(u32::MAX));
+            assert_eq!(dist2s(&handle.neighbours()), dist2s(&tree.knn(8, &query)));
+            assert!(handle.stats().visited_leaves >= visited);
+            // growing mid-search
+            let mut handle = tree.begin_query(&query, 2, &parameters);
+            handle.run_until(1);
+            handle.grow_k(30);
+            handle.grow_k(5);
+            while !handle.run_until(2) {}
+            assert_eq!(
+                dist2s(&handle.into_neighbours()),
+                dist2s(&tree.knn(30, &query))
+            );
+        }
+    }
+
+    #[test]
+    fn bounded_collectors_accept_any_candidate_until_grown_slots_are_filled() {
+        let mut collector = BoundedCollector::<f32, P2>::with_capacity(1);
+        collector.add(NotNan::new(2.0).unwrap(), 0);
+        assert_eq!(collector.furthest_dist2(), NotNan::new(2.0).ok());
+        collector.grow_k(3);
+        assert_eq!(collector.furthest_dist2(), None);
+        collector.add(NotNan::new(5.0).unwrap(), 1);
+        assert_eq!(collector.furthest_dist2(), None);
+        collector.add(NotNan::new(1.0).unwrap(), 2);
+        assert_eq!(collector.furthest_dist2(), NotNan::new(5.0).ok());
+        collector.grow_k(2);
+        collector.add(NotNan::new(3.0).unwrap(), 3);
+        assert_eq!(collector.furthest_dist2(), NotNan::new(3.0).ok());
+    }
+}
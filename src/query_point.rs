@@ -0,0 +1,101 @@
+use core::marker::PhantomData;
+
+use ordered_float::NotNan;
+
+use crate::{Point, Scalar};
+
+/// A point with which to query the tree
+///
+/// Contrary to [`Point`], it only needs to provide its coordinates,
+/// so borrowed slices or points of another scalar type can be used as queries.
+/// Every [`Point`] is a `QueryPoint` of the same scalar type.
+/// The number of dimensions of the query must match the one of the tree.
+/// Queries whose type fixes their number of dimensions, such as points,
+/// declare it in [`Self::STATIC_DIM`], so that a mismatch with the tree fails to compile:
+/// ```compile_fail
+/// use nabo::simple_point::{P2, P3};
+/// use nabo::KDTree;
+/// let tree = KDTree::new(&[P2::new2d(0.0, 0.0)]);
+/// let query: P3 = Default::default();
+/// tree.knn(1, &query);
+/// ```
+/// Other queries, such as slices, are checked when querying.
+pub trait QueryPoint<T: Scalar> {
+    /// The number of dimensions of all points of this type, if it is known at compile time.
+    const STATIC_DIM: Option<u32> = None;
+    /// The number of dimensions of this point.
+    fn dim(&self) -> u32;
+    /// Gets the value for the `i`-th component, `i` must be within `0..dim()`.
+    fn coord(&self, i: u32) -> T;
+}
+
+impl<T: Scalar, P: Point<T>> QueryPoint<T> for P {
+    const STATIC_DIM: Option<u32> = Some(P::DIM);
+    fn dim(&self) -> u32 {
+        P::DIM
+    }
+    fn coord(&self, i: u32) -> T {
+        self.get(i).into_inner()
+    }
+}
+
+impl<T: Scalar> QueryPoint<T> for [T] {
+    fn dim(&self) -> u32 {
+        self.len() as u32
+    }
+    fn coord(&self, i: u32) -> T {
+        self[i as usize]
+    }
+}
+
+impl<T: Scalar> QueryPoint<T> for [NotNan<T>] {
+    fn dim(&self) -> u32 {
+        self.len() as u32
+    }
+    fn coord(&self, i: u32) -> T {
+        self[i as usize].into_inner()
+    }
+}
+
+/// A query whose coordinates of scalar type `S` are converted on the fly
+///
+/// For example, this allows to query a `f32` tree with `f64` coordinates:
+/// ```
+/// # #[cfg(feature = "f32")] {
+/// use nabo::simple_point::P2;
+/// use nabo::{Converted, KDTree};
+/// let tree = KDTree::new(&[P2::new2d(0.0, 0.0), P2::new2d(1.0, 1.0)]);
+/// let query = [0.9f64, 0.8];
+/// let neighbours = tree.knn(1, &Converted::<f64, f32>::new(&query));
+/// assert_eq!(neighbours[0].index, 1);
+/// # }
+/// ```
+/// Coordinates that cannot be represented in `T` are rejected as invalid queries.
+pub struct Converted<'a, S: Scalar, T: Scalar>(&'a [S], PhantomData<T>);
+
+impl<'a, S: Scalar, T: Scalar> Converted<'a, S, T> {
+    /// Wraps coordinates of type `S`, to be converted to `T`.
+    pub fn new(coords: &'a [S]) -> Self {
+        Converted(coords, PhantomData)
+    }
+}
+
+impl<'a, S: Scalar, T: Scalar> QueryPoint<T> for Converted<'a, S, T> {
+    fn dim(&self) -> u32 {
+        self.0.len() as u32
+    }
+    fn coord(&self, i: u32) -> T {
+        T::from(self.0[i as usize]).unwrap_or_else(T::nan)
+    }
+}
+
+/// Compile-time check that queries of type `Q` have the dimension of the points `P` of a tree
+pub(crate) struct StaticDimCheck<T, Q: ?Sized, P>(PhantomData<(T, P)>, PhantomData<Q>);
+
+impl<T: Scalar, Q: QueryPoint<T> + ?Sized, P: Point<T>> StaticDimCheck<T, Q, P> {
+    /// Evaluated when a query function is instantiated, failing the build on a mismatch
+    pub(crate) const OK: () = match Q::STATIC_DIM {
+        Some(dim) if dim != P::DIM => panic!("the query does not have the dimension of the tree"),
+        _ => (),
+    };
+}
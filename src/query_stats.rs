@@ -0,0 +1,40 @@
+use core::ops::AddAssign;
+
+/// Counters describing the work done by a query
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueryStats {
+    /// number of nodes visited, split and leaf
+    pub visited_nodes: u32,
+    /// number of leaf nodes visited
+    pub visited_leaves: u32,
+    /// number of points whose distance to the query was computed
+    pub touched_points: u32,
+}
+
+impl QueryStats {
+    /// The counters of visiting a single leaf of `bucket_size` points
+    pub(crate) fn leaf(bucket_size: u32) -> Self {
+        QueryStats {
+            visited_nodes: 1,
+            visited_leaves: 1,
+            touched_points: bucket_size,
+        }
+    }
+
+    /// The counters of visiting a single split node
+    pub(crate) fn split() -> Self {
+        QueryStats {
+            visited_nodes: 1,
+            visited_leaves: 0,
+            touched_points: 0,
+        }
+    }
+}
+
+impl AddAssign for QueryStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.visited_nodes += rhs.visited_nodes;
+        self.visited_leaves += rhs.visited_leaves;
+        self.touched_points += rhs.touched_points;
+    }
+}
@@ -0,0 +1,212 @@
+//! Recording of the decisions taken by a query while traversing the tree
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+
+use ordered_float::NotNan;
+
+use crate::distance_scale::unscale_dist2;
+use crate::heap::CandidateHeap;
+use crate::internal_parameters::InternalParameters;
+use crate::{
+    BitSet, CandidateCollector, CandidateContainer, InternalNeighbour, KDTree, Neighbour,
+    Parameters, Point, Scalar,
+};
+
+/// Receives the decisions to descend into nodes during the traversal of the tree
+pub(crate) trait NodeVisitor<T: Scalar> {
+    /// Whether to call [`Self::visit`], false to compile the calls out of the untraced queries
+    const ENABLED: bool;
+    /// Called for each node considered, with the internal squared distances of its cell
+    /// and of the bound of the collector at decision time.
+    fn visit(
+        &mut self,
+        node: usize,
+        lower_bound2: NotNan<T>,
+        bound2: Option<NotNan<T>>,
+        descended: bool,
+    );
+}
+
+impl<T: Scalar> NodeVisitor<T> for () {
+    const ENABLED: bool = false;
+    fn visit(&mut self, _: usize, _: NotNan<T>, _: Option<NotNan<T>>, _: bool) {}
+}
+
+/// A decision on whether to descend into a node, see [`QueryTrace`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceStep<T: Scalar> {
+    /// the id of the node, see [`KDTree::node_count`]
+    pub node: usize,
+    /// the squared distance from the query to the cell of the node, as estimated by the search
+    pub lower_bound2: NotNan<T>,
+    /// the squared distance beyond which the collector was not interested in candidates, at decision time,
+    /// `None` if it accepted any candidate
+    pub bound2: Option<NotNan<T>>,
+    /// whether the search descended into the node, or pruned it
+    pub descended: bool,
+}
+
+/// The nodes considered by a query, in order, with the decisions to descend into them or prune them
+///
+/// The nearest child of a visited split node is always descended into.
+/// The other one is descended into if its `lower_bound2` is within the squared maximum radius
+/// and, multiplied by `(1 + epsilon)²`, is smaller than `bound2`, if any.
+/// The root is recorded first, with a lower bound of zero.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryTrace<T: Scalar> {
+    steps: Vec<TraceStep<T>>,
+    node_count: usize,
+}
+
+impl<T: Scalar> QueryTrace<T> {
+    /// Returns the recorded steps, in traversal order.
+    pub fn steps(&self) -> &[TraceStep<T>] {
+        &self.steps
+    }
+
+    /// Returns the set of ids of the nodes the search descended into,
+    /// for [`DumpOptions::highlighted`](crate::DumpOptions::highlighted).
+    pub fn visited_nodes(&self) -> BitSet {
+        let mut visited = BitSet::with_len(self.node_count);
+        for step in self.steps.iter().filter(|step| step.descended) {
+            visited.insert(step.node);
+        }
+        visited
+    }
+}
+
+/// Records the steps with internal distances
+struct Tracer<T: Scalar>(Vec<TraceStep<T>>);
+
+impl<T: Scalar> NodeVisitor<T> for Tracer<T> {
+    const ENABLED: bool = true;
+    fn visit(
+        &mut self,
+        node: usize,
+        lower_bound2: NotNan<T>,
+        bound2: Option<NotNan<T>>,
+        descended: bool,
+    ) {
+        self.0.push(TraceStep {
+            node,
+            lower_bound2,
+            bound2,
+            descended,
+        });
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Finds the `k` nearest neighbours of `query` as [`Self::knn_advanced`] with the default candidate container,
+    /// and records the decisions taken while traversing the tree.
+    ///
+    /// The traversal is the one of the untraced queries, so the returned neighbours are the same.
+    /// This is meant for debugging and allocates for every visited node.
+    pub fn knn_traced(
+        &self,
+        k: u32,
+        query: &P,
+        parameters: &Parameters<T>,
+    ) -> (Vec<Neighbour<T, P>>, QueryTrace<T>) {
+        (match Self::default_candidate_container(k) {
+            CandidateContainer::Linear => Self::knn_traced_heap::<Vec<InternalNeighbour<T>>>,
+            CandidateContainer::BinaryHeap => {
+                Self::knn_traced_heap::<BinaryHeap<InternalNeighbour<T>>>
+            }
+        })(self, k, query, parameters)
+    }
+
+    fn knn_traced_heap<H: CandidateHeap<T> + CandidateCollector<T>>(
+        &self,
+        k: u32,
+        query: &P,
+        parameters: &Parameters<T>,
+    ) -> (Vec<Neighbour<T, P>>, QueryTrace<T>) {
+        let query = self.query_coordinates(query);
+        let mut heap = H::new_with_k(k.min(self.len() as u32));
+        let mut tracer = Tracer(Vec::new());
+        self.knn_internal_visited(
+            &mut heap,
+            &mut tracer,
+            &query,
+            &InternalParameters::new(parameters, self.scale),
+        );
+        let neighbours = if parameters.sort_results {
+            heap.into_sorted_vec()
+        } else {
+            heap.into_vec()
+        }
+        .into_iter()
+        .map(|n| self.externalise_neighbour(n))
+        .collect();
+        let steps = tracer
+            .0
+            .into_iter()
+            .map(|step| TraceStep {
+                lower_bound2: unscale_dist2(step.lower_bound2, self.scale),
+                bound2: step.bound2.map(|bound2| unscale_dist2(bound2, self.scale)),
+                ..step
+            })
+            .collect();
+        let trace = QueryTrace {
+            steps,
+            node_count: self.nodes.len(),
+        };
+        (neighbours, trace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn trace_matches_untraced_query() {
+        let cloud: Vec<P2> = random_point_cloud(2000);
+        let tree = KDTree::new(&cloud);
+        // strict exact mode rejects any non-zero epsilon
+        let epsilons: &[f32] = if cfg!(feature = "strict-exact") {
+            &[0.0]
+        } else {
+            &[0.0, 1.0]
+        };
+        for &epsilon in epsilons {
+            let parameters = Parameters {
+                epsilon,
+                ..Parameters::default()
+            };
+            for k in [1, 10, 40] {
+                let query = random_point();
+                let (neighbours, trace) = tree.knn_traced(k, &query, &parameters);
+                let (expected, stats) = tree.knn_with_stats(
+                    k,
+                    &query,
+                    KDTree::<f32, P2>::default_candidate_container(k),
+                    &parameters,
+                );
+                assert_eq!(neighbours, expected);
+                let visited = trace.visited_nodes();
+                assert_eq!(visited.count(), stats.visited_nodes as usize);
+                let steps = trace.steps();
+                assert_eq!(steps[0].node, 0);
+                assert!(steps[0].descended);
+                let max_error2 = (1.0 + epsilon) * (1.0 + epsilon);
+                for step in steps {
+                    let bound2 = match step.bound2 {
+                        Some(bound2) => *bound2,
+                        None => {
+                            assert!(step.descended);
+                            continue;
+                        }
+                    };
+                    let expected_descend = *step.lower_bound2 * max_error2 < bound2;
+                    // rounding of the unscaled distances may blur exact ties
+                    if (*step.lower_bound2 * max_error2 - bound2).abs() > 1e-3 {
+                        assert_eq!(step.descended, expected_descend);
+                    }
+                }
+            }
+        }
+    }
+}
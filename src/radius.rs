@@ -0,0 +1,112 @@
+//! Distinct types for distances and squared distances
+
+use num_traits::Float;
+
+use crate::Scalar;
+
+/// A distance, such as a search radius, as opposed to a [`SquaredRadius`]
+///
+/// See [`ParametersBuilder::radius`](crate::ParametersBuilder::radius) and [`Neighbour::dist`](crate::Neighbour::dist).
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub struct Radius<T: Scalar>(T);
+
+impl<T: Scalar> Radius<T> {
+    /// Creates a radius from a distance, in the units of the coordinates.
+    pub const fn new(radius: T) -> Self {
+        Radius(radius)
+    }
+
+    /// Returns an infinite radius, which does not limit searches.
+    pub fn infinite() -> Self {
+        Radius(T::infinity())
+    }
+
+    /// Returns the distance.
+    pub const fn get(self) -> T {
+        self.0
+    }
+
+    /// Returns the square of this radius.
+    pub fn squared(self) -> SquaredRadius<T> {
+        SquaredRadius(self.0 * self.0)
+    }
+}
+
+/// A squared distance, as computed by the searches, as opposed to a [`Radius`]
+///
+/// See [`ParametersBuilder::squared_radius`](crate::ParametersBuilder::squared_radius) and [`Neighbour::dist2`](crate::Neighbour::dist2).
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub struct SquaredRadius<T: Scalar>(T);
+
+impl<T: Scalar> SquaredRadius<T> {
+    /// Creates a squared radius from a squared distance.
+    pub const fn new(radius2: T) -> Self {
+        SquaredRadius(radius2)
+    }
+
+    /// Returns the squared distance.
+    pub const fn get(self) -> T {
+        self.0
+    }
+}
+
+impl<T: Scalar + Float> SquaredRadius<T> {
+    /// Returns the square root of this squared radius.
+    pub fn sqrt(self) -> Radius<T> {
+        Radius(Float::sqrt(self.0))
+    }
+}
+
+impl<T: Scalar> From<Radius<T>> for SquaredRadius<T> {
+    fn from(radius: Radius<T>) -> Self {
+        radius.squared()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn radii_convert_explicitly() {
+        let radius = Radius::new(3.0f32);
+        assert_eq!(radius.get(), 3.0);
+        assert_eq!(radius.squared(), SquaredRadius::new(9.0));
+        assert_eq!(SquaredRadius::from(radius).sqrt(), radius);
+        assert!(Radius::<f64>::infinite().get().is_infinite());
+        assert!(Radius::new(1.0) < Radius::new(2.0));
+    }
+
+    #[test]
+    fn typed_radii_limit_searches_alike() {
+        let cloud: Vec<P2> = (0..10).map(|i| P2::new2d(i as f32, 0.0)).collect();
+        let tree = KDTree::new(&cloud);
+        let query = P2::new2d(0.0, 0.0);
+        let by_radius = Parameters::builder()
+            .radius(Radius::new(4.0))
+            .build()
+            .unwrap();
+        let by_squared_radius = Parameters::builder()
+            .squared_radius(SquaredRadius::new(16.0))
+            .build()
+            .unwrap();
+        assert_eq!(by_radius, by_squared_radius);
+        assert_eq!(by_radius.radius(), Radius::new(4.0));
+        let neighbours = tree.knn_within(10, &query, Radius::new(4.0));
+        assert_eq!(neighbours.len(), 5);
+        let last = neighbours[4];
+        assert_eq!(last.dist(), Radius::new(4.0));
+        assert_eq!(last.dist2(), SquaredRadius::new(16.0));
+        assert_eq!(
+            neighbours,
+            tree.knn_advanced(
+                10,
+                &query,
+                CandidateContainer::Linear,
+                &by_squared_radius,
+                None
+            )
+        );
+    }
+}
@@ -0,0 +1,219 @@
+//! Radius searches counting the neighbours first, and collecting them on demand from the leaves holding them
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use ordered_float::NotNan;
+
+use crate::cell::NodeCell;
+use crate::internal_parameters::InternalParameters;
+use crate::{
+    point_slice_dist2, InternalNeighbour, KDTree, Neighbour, Parameters, Point, Radius, Scalar,
+};
+
+/// The neighbours of a query within a radius, counted but not collected yet, see [`KDTree::radius_probe`]
+///
+/// The probe records the leaves holding at least one neighbour, so that collecting the neighbours,
+/// or counting them within a smaller radius, only scans these leaves.
+#[derive(Clone)]
+pub struct RadiusProbe<'a, T: Scalar, P: Point<T>> {
+    tree: &'a KDTree<T, P>,
+    query: Vec<NotNan<T>>,
+    radius: Radius<T>,
+    /// squared radius in the internal units of the tree
+    radius2: NotNan<T>,
+    /// first internal index and number of points of the leaves holding neighbours, in storage order
+    leaves: Vec<(u32, u32)>,
+    count: usize,
+}
+
+impl<'a, T: Scalar, P: Point<T>> RadiusProbe<'a, T, P> {
+    /// Returns the number of neighbours within the radius of the probe.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Returns the radius of the probe.
+    pub fn radius(&self) -> Radius<T> {
+        self.radius
+    }
+
+    /// Returns the number of leaves the probe recorded, which are those holding neighbours.
+    pub fn leaf_count(&self) -> usize {
+        self.leaves.len()
+    }
+
+    /// Shrinks the radius of the probe to `radius`, recounting the neighbours in the recorded leaves,
+    /// and forgetting those that do not hold any anymore.
+    ///
+    /// Panics if `radius` is larger than the radius of the probe, as the recorded leaves may miss
+    /// neighbours beyond it, or is NaN.
+    pub fn refine(&mut self, radius: Radius<T>) {
+        assert!(
+            radius <= self.radius,
+            "the radius of a probe can only shrink"
+        );
+        self.radius = radius;
+        self.radius2 = self.tree.internal_radius2(radius);
+        let (mut leaves, mut count) = (core::mem::take(&mut self.leaves), 0);
+        leaves.retain(|leaf| {
+            let found = self.scan_leaf(*leaf, |_| ());
+            count += found;
+            found > 0
+        });
+        self.leaves = leaves;
+        self.count = count;
+    }
+
+    /// Returns the neighbours within the radius of the probe, sorted by increasing internal index,
+    /// as [`KDTree::radius_search_ordered`], scanning only the recorded leaves.
+    pub fn collect(self) -> Vec<Neighbour<T, P>> {
+        let mut neighbours = Vec::with_capacity(self.count);
+        for leaf in &self.leaves {
+            self.scan_leaf(*leaf, |neighbour| {
+                neighbours.push(self.tree.externalise_neighbour(neighbour))
+            });
+        }
+        neighbours
+    }
+
+    /// Passes the points of `leaf` within the radius to `f`, returning their number
+    fn scan_leaf(
+        &self,
+        (start, size): (u32, u32),
+        mut f: impl FnMut(InternalNeighbour<T>),
+    ) -> usize {
+        let dim = P::DIM as usize;
+        let mut found = 0;
+        for index in start..start + size {
+            let start = index as usize * dim;
+            let point = &self.tree.points[start..start + dim];
+            let dist2 = point_slice_dist2::<T, P>(point, &self.query, self.tree.scale);
+            if dist2 <= self.radius2 {
+                found += 1;
+                f(InternalNeighbour { index, dist2 });
+            }
+        }
+        found
+    }
+}
+
+impl<T: Scalar, P: Point<T>> fmt::Debug for RadiusProbe<'_, T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RadiusProbe")
+            .field("radius", &self.radius)
+            .field("count", &self.count)
+            .field("leaf_count", &self.leaves.len())
+            .finish()
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Counts the points within `radius` of `query`, inclusive, returning a probe that records
+    /// the leaves holding them, to collect them later without traversing the tree again.
+    ///
+    /// This visits the same nodes as [`Self::radius_search_ordered`], and allocates
+    /// a copy of the query and one entry per leaf holding neighbours.
+    /// Adaptive algorithms can shrink the radius with [`RadiusProbe::refine`] until the count suits them,
+    /// then get the neighbours with [`RadiusProbe::collect`].
+    pub fn radius_probe(&self, query: &P, radius: Radius<T>) -> RadiusProbe<'_, T, P> {
+        let mut probe = RadiusProbe {
+            tree: self,
+            query: self.query_coordinates(query).to_vec(),
+            radius,
+            radius2: self.internal_radius2(radius),
+            leaves: Vec::new(),
+            count: 0,
+        };
+        if !self.is_empty() {
+            self.recurse_probe(0, &mut NodeCell::of_root(self), &mut probe);
+        }
+        probe
+    }
+
+    /// Returns the square of `radius` in the internal units of the tree, as searches compare it to distances
+    pub(crate) fn internal_radius2(&self, radius: Radius<T>) -> NotNan<T> {
+        let parameters = Parameters {
+            max_radius: radius.get(),
+            ..Parameters::default()
+        };
+        InternalParameters::new(&parameters, self.scale).max_radius2
+    }
+
+    fn recurse_probe(
+        &self,
+        node: usize,
+        cell: &mut NodeCell<T>,
+        probe: &mut RadiusProbe<'_, T, P>,
+    ) {
+        if cell.box_dist2(&probe.query, &probe.query, self.scale) > probe.radius2 {
+            return;
+        }
+        self.nodes[node].dispatch_on_type(
+            (cell, probe),
+            |(cell, probe), split_dim, split_val, right_child| {
+                let dim = split_dim as usize;
+                let parent = cell.extent(dim);
+                cell.set_child(dim, parent, split_val, self.drift, true);
+                self.recurse_probe(node + 1, cell, probe);
+                cell.set_child(dim, parent, split_val, self.drift, false);
+                self.recurse_probe(right_child as usize, cell, probe);
+                cell.set_extent(dim, parent);
+            },
+            |(_, probe), bucket_start_index, bucket_size| {
+                let leaf = (bucket_start_index, bucket_size);
+                let found = probe.scan_leaf(leaf, |_| ());
+                if found > 0 {
+                    probe.leaves.push(leaf);
+                    probe.count += found;
+                }
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn probes_count_and_collect_the_radius_search_results() {
+        let cloud: Vec<P2> = random_point_cloud(3000);
+        let tree = KDTree::new(&cloud);
+        for _ in 0..20 {
+            let query = random_point();
+            let mut probe = tree.radius_probe(&query, Radius::new(40.0));
+            assert_eq!(
+                probe.count(),
+                tree.radius_search_ordered(&query, Radius::new(40.0)).len()
+            );
+            let mut leaf_count = probe.leaf_count();
+            for radius in [40.0, 20.0, 5.0, 0.0] {
+                let radius = Radius::new(radius);
+                probe.refine(radius);
+                let expected = tree.radius_search_ordered(&query, radius);
+                assert_eq!(probe.count(), expected.len());
+                assert_eq!(probe.radius(), radius);
+                assert!(probe.leaf_count() <= leaf_count);
+                leaf_count = probe.leaf_count();
+                assert_eq!(probe.clone().collect(), expected);
+                // a fresh probe at the smaller radius records the same leaves
+                assert_eq!(tree.radius_probe(&query, radius).leaf_count(), leaf_count);
+            }
+        }
+        let empty = KDTree::<f32, P2>::new(&[]);
+        let probe = empty.radius_probe(&P2::new2d(0.0, 0.0), Radius::infinite());
+        assert_eq!(probe.count(), 0);
+        assert!(probe.collect().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "the radius of a probe can only shrink")]
+    fn probes_cannot_grow() {
+        let cloud: Vec<P2> = random_point_cloud(100);
+        let tree = KDTree::new(&cloud);
+        let mut probe = tree.radius_probe(&cloud[0], Radius::new(5.0));
+        probe.refine(Radius::new(6.0));
+    }
+}
@@ -0,0 +1,445 @@
+//! Radius searches returning the neighbours in storage order, for cache-friendly post-processing,
+//! or streaming them to a callback
+
+use alloc::vec::Vec;
+use core::ops::ControlFlow;
+use ordered_float::NotNan;
+
+use crate::heap::push_within_limit;
+use crate::infinite::HasInfinite;
+use crate::internal_parameters::InternalParameters;
+use crate::{
+    CandidateCollector, FixedCollector, InternalNeighbour, KDTree, Neighbour, Parameters, Point,
+    QueryError, Radius, Scalar,
+};
+
+/// Keeps all candidates with their distances, the search being only limited by the radius
+pub(crate) struct AllCandidates<T: Scalar>(pub(crate) Vec<InternalNeighbour<T>>);
+
+impl<T: Scalar> CandidateCollector<T> for AllCandidates<T> {
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        self.0.push(InternalNeighbour { index, dist2 });
+    }
+
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        None
+    }
+}
+
+/// Keeps the candidates with their distances up to a limit, then prunes the rest of the search
+struct LimitedCandidates<T: Scalar> {
+    found: Vec<InternalNeighbour<T>>,
+    limit: usize,
+    exceeded: bool,
+}
+
+impl<T: Scalar> CandidateCollector<T> for LimitedCandidates<T> {
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        if self.found.len() < self.limit {
+            push_within_limit(
+                &mut self.found,
+                InternalNeighbour { index, dist2 },
+                self.limit,
+            );
+        } else {
+            self.exceeded = true;
+        }
+    }
+
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        // once over the limit, no cell is worth visiting
+        self.exceeded.then(|| -NotNan::<T>::infinite())
+    }
+}
+
+/// Passes the candidates to a callback until it breaks, then prunes the rest of the search
+struct StreamingCollector<'a, T: Scalar, P: Point<T>, F> {
+    tree: &'a KDTree<T, P>,
+    f: F,
+    stopped: bool,
+}
+
+impl<'a, T: Scalar, P: Point<T>, F> CandidateCollector<T> for StreamingCollector<'a, T, P, F>
+where
+    F: FnMut(Neighbour<T, P>) -> ControlFlow<()>,
+{
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        if !self.stopped {
+            let neighbour = self
+                .tree
+                .externalise_neighbour(InternalNeighbour { index, dist2 });
+            self.stopped = (self.f)(neighbour).is_break();
+        }
+    }
+
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        // once stopped, no cell is worth visiting
+        self.stopped.then(|| -NotNan::<T>::infinite())
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Finds all the points within `radius` of `query`, inclusive, sorted by increasing internal index.
+    ///
+    /// Consecutive neighbours are consecutive in the storage of the tree, and those of a same leaf
+    /// are grouped together.
+    /// To read attributes of the neighbours in memory order, permute the attribute arrays once
+    /// so that the attribute of the point of external index `i` is at [`Self::internalise_index`]`(i)`,
+    /// or equivalently gather through [`Self::externalisation_map`].
+    pub fn radius_search_ordered(&self, query: &P, radius: Radius<T>) -> Vec<Neighbour<T, P>> {
+        let parameters = Parameters {
+            max_radius: radius.get(),
+            ..Parameters::default()
+        };
+        let mut collector = AllCandidates(Vec::new());
+        self.knn_internal(
+            &mut collector,
+            &self.query_coordinates(query),
+            &InternalParameters::new(&parameters, self.scale),
+        );
+        // leaves hold consecutive internal indices, so the search reports them in runs
+        collector
+            .0
+            .sort_unstable_by_key(|neighbour| neighbour.index);
+        collector
+            .0
+            .into_iter()
+            .map(|neighbour| self.externalise_neighbour(neighbour))
+            .collect()
+    }
+
+    /// Finds the points within `radius` of `query`, inclusive, in no particular order,
+    /// or returns [`QueryError::ResultLimitExceeded`] if there are more than `max_results`.
+    ///
+    /// This guards services against queries whose radius covers a large part of a huge cloud:
+    /// the search stops as soon as it reaches the point beyond the limit, only finishing the current leaf,
+    /// and never allocates memory for more than `max_results` neighbours.
+    /// `seen_at_least` in the error is then `max_results + 1`.
+    /// Without a limit, this is the same as a radius search with [`Self::nn_raw`] and an
+    /// [`UnboundedCollector`](crate::UnboundedCollector), and does not fail.
+    pub fn try_radius_search_limited(
+        &self,
+        query: &P,
+        radius: Radius<T>,
+        max_results: Option<usize>,
+    ) -> Result<Vec<Neighbour<T, P>>, QueryError> {
+        let (neighbours, truncated) = self.radius_search_limited(query, radius, max_results);
+        match max_results {
+            Some(limit) if truncated => Err(QueryError::ResultLimitExceeded {
+                limit,
+                seen_at_least: limit + 1,
+            }),
+            _ => Ok(neighbours),
+        }
+    }
+
+    /// Finds the points within `radius` of `query`, inclusive, in no particular order, at most `max_results` of them,
+    /// and returns whether the search was truncated because there were more.
+    ///
+    /// When truncated, the search stops as in [`Self::try_radius_search_limited`], and the points returned
+    /// are the first `max_results` ones reached by the search, in the order of [`Self::radius_search_streamed`],
+    /// not the nearest.
+    pub fn radius_search_limited(
+        &self,
+        query: &P,
+        radius: Radius<T>,
+        max_results: Option<usize>,
+    ) -> (Vec<Neighbour<T, P>>, bool) {
+        let parameters = Parameters {
+            max_radius: radius.get(),
+            ..Parameters::default()
+        };
+        let mut collector = LimitedCandidates {
+            found: Vec::new(),
+            limit: max_results.unwrap_or(usize::MAX),
+            exceeded: false,
+        };
+        self.knn_internal(
+            &mut collector,
+            &self.query_coordinates(query),
+            &InternalParameters::new(&parameters, self.scale),
+        );
+        let neighbours = collector
+            .found
+            .into_iter()
+            .map(|neighbour| self.externalise_neighbour(neighbour))
+            .collect();
+        (neighbours, collector.exceeded)
+    }
+
+    /// Writes the points within `radius` of `query`, inclusive, to `out` as their index in the original point cloud
+    /// and their squared distance, in no particular order, without allocating.
+    ///
+    /// Returns the number of points written and whether some were dropped because `out` was full.
+    /// On overflow, the points written are the first ones reached by the search, in the order
+    /// of [`Self::radius_search_streamed`], not the nearest.
+    /// Nothing is allocated for trees of up to eight dimensions, see [`FixedCollector`]
+    /// to pass other parameters through [`Self::nn_raw`].
+    pub fn radius_search_into(
+        &self,
+        query: &P,
+        radius: Radius<T>,
+        out: &mut [(u32, T)],
+    ) -> (usize, bool) {
+        let parameters = Parameters {
+            max_radius: radius.get(),
+            ..Parameters::default()
+        };
+        let mut collector = FixedCollector::new(out);
+        self.knn_internal(
+            &mut collector,
+            &self.query_coordinates(query),
+            &InternalParameters::new(&parameters, self.scale),
+        );
+        let overflowed = collector.overflowed();
+        (collector.into_externalised(self).len(), overflowed)
+    }
+
+    /// Passes the points within `radius` of `query`, inclusive, to `f` as the search reaches them,
+    /// in no particular order, until `f` returns [`ControlFlow::Break`].
+    ///
+    /// Nothing is collected, so that results of any size can be streamed to a channel.
+    /// Once `f` breaks, it is not called anymore and the search stops by pruning all remaining nodes,
+    /// only the current leaf being finished.
+    /// Returns [`ControlFlow::Break`] if `f` broke.
+    pub fn radius_search_streamed(
+        &self,
+        query: &P,
+        radius: Radius<T>,
+        f: impl FnMut(Neighbour<T, P>) -> ControlFlow<()>,
+    ) -> ControlFlow<()> {
+        let parameters = Parameters {
+            max_radius: radius.get(),
+            ..Parameters::default()
+        };
+        let mut collector = StreamingCollector {
+            tree: self,
+            f,
+            stopped: false,
+        };
+        self.knn_internal(
+            &mut collector,
+            &self.query_coordinates(query),
+            &InternalParameters::new(&parameters, self.scale),
+        );
+        if collector.stopped {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    }
+
+    /// Passes the points within `radius` of `query`, inclusive, to `f` by increasing distance,
+    /// until `f` returns [`ControlFlow::Break`].
+    ///
+    /// The points are found incrementally by [`Self::nearest_iter`], so that stopping early,
+    /// for example once a budget is spent, only visits the nodes needed for the points passed so far.
+    /// Returns [`ControlFlow::Break`] if `f` broke.
+    pub fn radius_search_streamed_sorted(
+        &self,
+        query: &P,
+        radius: Radius<T>,
+        mut f: impl FnMut(Neighbour<T, P>) -> ControlFlow<()>,
+    ) -> ControlFlow<()> {
+        let radius2 = radius.squared().get();
+        for neighbour in self.nearest_iter(query) {
+            if neighbour.dist2.into_inner() > radius2 {
+                break;
+            }
+            f(neighbour)?;
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::ops::ControlFlow;
+
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn radius_search_is_in_storage_order() {
+        let cloud: Vec<P2> = random_point_cloud(2000);
+        let tree = KDTree::new(&cloud);
+        for radius in [0.0, 5.0, 30.0] {
+            let query = random_point();
+            let found = tree.radius_search_ordered(&query, Radius::new(radius));
+            let internal: Vec<_> = found
+                .iter()
+                .map(|neighbour| tree.internalise_index(neighbour.index))
+                .collect();
+            assert!(internal.windows(2).all(|pair| pair[0] < pair[1]));
+            let mut indices: Vec<_> = found.iter().map(|neighbour| neighbour.index).collect();
+            indices.sort_unstable();
+            let expected: Vec<_> = (0..cloud.len() as u32)
+                .filter(|index| {
+                    let p = cloud[*index as usize];
+                    let dx = p.get(0) - query.get(0);
+                    let dy = p.get(1) - query.get(1);
+                    (dx * dx + dy * dy).into_inner() <= radius * radius
+                })
+                .collect();
+            assert_eq!(indices, expected);
+            for neighbour in &found {
+                assert_eq!(neighbour.point, cloud[neighbour.index as usize]);
+            }
+        }
+    }
+
+    #[test]
+    fn streamed_radius_searches_stop_when_asked() {
+        let cloud: Vec<P2> = random_point_cloud(3000);
+        let tree = KDTree::new(&cloud);
+        for radius in [0.0, 10.0, 50.0] {
+            let query = random_point();
+            let radius = Radius::new(radius);
+            let mut expected: Vec<_> = tree
+                .radius_search_ordered(&query, radius)
+                .iter()
+                .map(|neighbour| neighbour.index)
+                .collect();
+            expected.sort_unstable();
+            let mut streamed = Vec::new();
+            let flow = tree.radius_search_streamed(&query, radius, |neighbour| {
+                streamed.push(neighbour.index);
+                ControlFlow::Continue(())
+            });
+            assert_eq!(flow, ControlFlow::Continue(()));
+            streamed.sort_unstable();
+            assert_eq!(streamed, expected);
+            let mut sorted = Vec::new();
+            let flow = tree.radius_search_streamed_sorted(&query, radius, |neighbour| {
+                sorted.push(neighbour);
+                ControlFlow::Continue(())
+            });
+            assert_eq!(flow, ControlFlow::Continue(()));
+            assert!(sorted.windows(2).all(|pair| pair[0].dist2 <= pair[1].dist2));
+            let mut indices: Vec<_> = sorted.iter().map(|neighbour| neighbour.index).collect();
+            indices.sort_unstable();
+            assert_eq!(indices, expected);
+            // the callback is not called after breaking
+            for budget in [1, 5] {
+                let mut count = 0;
+                let mut stop_after_budget = |_| {
+                    count += 1;
+                    if count == budget {
+                        ControlFlow::Break(())
+                    } else {
+                        ControlFlow::Continue(())
+                    }
+                };
+                let flow = tree.radius_search_streamed(&query, radius, &mut stop_after_budget);
+                let broke = expected.len() >= budget;
+                assert_eq!(flow.is_break(), broke);
+                assert_eq!(count, budget.min(expected.len()));
+                let mut nearest = Vec::new();
+                let flow = tree.radius_search_streamed_sorted(&query, radius, |neighbour| {
+                    nearest.push(neighbour);
+                    if nearest.len() == budget {
+                        ControlFlow::Break(())
+                    } else {
+                        ControlFlow::Continue(())
+                    }
+                });
+                assert_eq!(flow.is_break(), broke);
+                assert_eq!(nearest[..], sorted[..budget.min(sorted.len())]);
+            }
+        }
+    }
+
+    #[test]
+    fn radius_searches_into_slices_keep_the_first_points_reached() {
+        let cloud: Vec<P2> = random_point_cloud(3000);
+        let tree = KDTree::new(&cloud);
+        let mut out = [(0, 0.0); 300];
+        for radius in [0.0, 10.0, 30.0] {
+            let query = random_point();
+            let radius = Radius::new(radius);
+            let mut streamed = Vec::new();
+            let _ = tree.radius_search_streamed(&query, radius, |neighbour| {
+                streamed.push((neighbour.index, neighbour.dist2.into_inner()));
+                ControlFlow::Continue(())
+            });
+            let (len, overflowed) = tree.radius_search_into(&query, radius, &mut out);
+            assert_eq!(len, streamed.len().min(out.len()));
+            assert_eq!(overflowed, streamed.len() > out.len());
+            assert_eq!(out[..len], streamed[..len]);
+            // the same through the collector, with a slice too small for all points
+            let mut slots = [(0, 0.0); 4];
+            let mut collector = FixedCollector::new(&mut slots);
+            tree.nn_raw(&query, &mut collector, &parameters_of(radius), None);
+            assert_eq!(collector.len(), streamed.len().min(4));
+            assert_eq!(collector.is_empty(), streamed.is_empty());
+            assert_eq!(collector.overflowed(), streamed.len() > 4);
+            assert!(collector
+                .externalise(&tree)
+                .eq(streamed.iter().copied().take(4)));
+            let kept = collector.into_externalised(&tree);
+            assert_eq!(kept[..], streamed[..kept.len()]);
+        }
+        let (len, overflowed) = tree.radius_search_into(&cloud[0], Radius::infinite(), &mut []);
+        assert_eq!((len, overflowed), (0, true));
+    }
+
+    #[test]
+    fn limited_searches_stop_at_the_limit() {
+        // a dense cloud, all within the radius
+        let cloud: Vec<P2> = random_point_cloud(20_000);
+        let tree = KDTree::new(&cloud);
+        let radius = Radius::infinite();
+        let error = tree
+            .try_radius_search_limited(&cloud[0], radius, Some(10))
+            .unwrap_err();
+        assert_eq!(
+            error,
+            QueryError::ResultLimitExceeded {
+                limit: 10,
+                seen_at_least: 11
+            }
+        );
+        let (truncated, exceeded) = tree.radius_search_limited(&cloud[0], radius, Some(10));
+        assert!(exceeded);
+        assert_eq!(truncated.len(), 10);
+        let mut streamed = Vec::new();
+        let _ = tree.radius_search_streamed(&cloud[0], radius, |neighbour| {
+            streamed.push(neighbour);
+            ControlFlow::Continue(())
+        });
+        assert_eq!(truncated[..], streamed[..10]);
+        // under the limit, nothing is dropped
+        let small = Radius::new(2.0);
+        let all = tree
+            .try_radius_search_limited(&cloud[0], small, Some(20_000))
+            .expect("fewer points than the limit");
+        assert_eq!(
+            all.len(),
+            tree.radius_search_ordered(&cloud[0], small).len()
+        );
+        assert_eq!(
+            tree.try_radius_search_limited(&cloud[0], radius, None)
+                .map(|all| all.len()),
+            Ok(cloud.len())
+        );
+        // the collector stops allocating and searching once over the limit
+        let mut collector = UnboundedCollector::<f32, P2>::with_max_results(1_000_000, 10);
+        let stats = tree.nn_raw_with_stats(&cloud[0], &mut collector, &parameters_of(radius));
+        assert!(collector.limit_exceeded());
+        assert_eq!(collector.externalise(&tree).len(), 10);
+        assert!(stats.touched_points < 10 + 2 * 8);
+        assert!(format!("{collector:?}").contains("capacity: 10,"));
+        let mut grown = UnboundedCollector::<f32, P2>::with_max_results(0, 10);
+        tree.nn_raw(&cloud[0], &mut grown, &parameters_of(radius), None);
+        assert!(format!("{grown:?}").contains("len: 10, capacity: 10,"));
+        grown.clear();
+        assert!(!grown.limit_exceeded());
+    }
+
+    fn parameters_of(radius: Radius<f32>) -> Parameters<f32> {
+        Parameters {
+            max_radius: radius.get(),
+            ..Parameters::default()
+        }
+    }
+}
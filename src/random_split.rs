@@ -0,0 +1,58 @@
+//! The randomised split rule of the trees of a [`KDForest`](crate::KDForest) and of trees built with a seed,
+//! see [`BuildOptions::seed`](crate::BuildOptions::seed)
+
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+
+use ordered_float::NotNan;
+
+use crate::rng::SplitMix64;
+use crate::{KDTree, Point, Scalar};
+
+/// Number of points, at the start of a node, on which the mean and variance of the split are estimated
+const SPLIT_SAMPLE_SIZE: usize = 100;
+
+/// Number of dimensions of largest variance among which the split dimension is drawn
+const SPLIT_CANDIDATE_DIMS: usize = 5;
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Chooses a split at the mean of a random dimension among those of largest variance, as in FLANN
+    ///
+    /// The mean and variance are estimated on the first points of `build_points`.
+    /// At least one dimension must have distinct bounds, only such dimensions are chosen.
+    pub(crate) fn random_split(
+        cloud: &[P],
+        build_points: &[usize],
+        min_bounds: &[NotNan<T>],
+        max_bounds: &[NotNan<T>],
+        rng: &mut SplitMix64,
+    ) -> (u32, NotNan<T>) {
+        let sample = &build_points[..build_points.len().min(SPLIT_SAMPLE_SIZE)];
+        // work on halved coordinates, so that differences cannot overflow
+        let half = T::one() / (T::one() + T::one());
+        let mut candidates: Vec<_> = (0..P::DIM)
+            .filter(|dim| max_bounds[*dim as usize] != min_bounds[*dim as usize])
+            .map(|dim| {
+                let mut mean = T::zero();
+                for (count, index) in sample.iter().enumerate() {
+                    let value = cloud[*index].get(dim).into_inner() * half;
+                    mean += (value - mean) / T::from(count + 1).expect("count fits a float");
+                }
+                let mut variance = T::zero();
+                for index in sample {
+                    let diff = cloud[*index].get(dim).into_inner() * half - mean;
+                    variance += diff * diff;
+                }
+                let variance = NotNan::new(variance).expect("a sum of squares is not NaN");
+                (Reverse(variance), dim, mean + mean)
+            })
+            .collect();
+        candidates.sort_unstable_by_key(|(variance, dim, _)| (*variance, *dim));
+        let count = candidates.len().min(SPLIT_CANDIDATE_DIMS);
+        let (_, dim, mean) = candidates[rng.below(count as u64) as usize];
+        (
+            dim,
+            NotNan::new(mean).expect("the mean of coordinates is not NaN"),
+        )
+    }
+}
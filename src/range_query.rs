@@ -0,0 +1,186 @@
+//! Queries of the points inside axis-aligned boxes
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use ordered_float::NotNan;
+
+use crate::cell::NodeCell;
+use crate::{KDTree, Point, Scalar};
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Returns the indices, in the original point cloud, of the points inside the axis-aligned box
+    /// from `min` to `max`, bounds included.
+    ///
+    /// The indices are in the internal order of the tree.
+    /// The box is empty if `min` is larger than `max` along a dimension.
+    /// It is in the frame of the points also if the tree is rotated, see [`Self::rotation`]:
+    /// the points are then tested by their coordinates as given, all those of the visited leaves.
+    pub fn range_query(&self, min: &P, max: &P) -> Vec<u32> {
+        let mut indices = Vec::new();
+        self.visit_box(min, max, &mut |internal_indices, point_filter| {
+            for internal_index in internal_indices {
+                if point_filter.map_or(true, |inside| inside(internal_index)) {
+                    indices.push(self.indices[internal_index as usize]);
+                }
+            }
+        });
+        indices
+    }
+
+    /// Returns the number of points inside the axis-aligned box from `min` to `max`, bounds included,
+    /// as `range_query(min, max).len()` but without listing them.
+    ///
+    /// Subtrees whose cell is inside the box are counted at once, from the range of their points in the storage,
+    /// so only nodes overlapping the border of the box are visited, unless the tree is rotated,
+    /// in which case the points of every visited subtree are tested as by [`Self::range_query`].
+    pub fn range_count(&self, min: &P, max: &P) -> usize {
+        let mut count = 0;
+        self.visit_box(min, max, &mut |internal_indices, point_filter| {
+            count += match point_filter {
+                None => internal_indices.len(),
+                Some(inside) => internal_indices.filter(|index| inside(*index)).count(),
+            };
+        });
+        count
+    }
+
+    /// Same as [`Self::visit_range`] for a box in the frame of the points
+    ///
+    /// For a rotated tree, this visits the box of the frame of the tree containing the rotated box,
+    /// filtering every point by its coordinates as given.
+    fn visit_box<F>(&self, min: &P, max: &P, visit: &mut F)
+    where
+        F: FnMut(Range<u32>, Option<&dyn Fn(u32) -> bool>),
+    {
+        if self.rotation.is_none() {
+            return self.visit_range(min, max, visit);
+        }
+        if (0..P::DIM).any(|i| min.get(i) > max.get(i)) {
+            return;
+        }
+        let (lower, upper) = self.rotated_box(min, max);
+        let inside = |internal_index: u32| {
+            let point = self.original_coordinates(internal_index);
+            (0..P::DIM).all(|i| min.get(i) <= point[i as usize] && point[i as usize] <= max.get(i))
+        };
+        self.visit_range(&lower, &upper, &mut |internal_indices, _| {
+            visit(internal_indices, Some(&inside))
+        });
+    }
+
+    /// Calls `visit` with the ranges of internal indices of the subtrees inside the box, without filter,
+    /// and of the leaves overlapping its border, with a filter telling whether a point is inside
+    pub(crate) fn visit_range<F>(&self, min: &P, max: &P, visit: &mut F)
+    where
+        F: FnMut(Range<u32>, Option<&dyn Fn(u32) -> bool>),
+    {
+        if self.is_empty() {
+            return;
+        }
+        let lower: Vec<NotNan<T>> = (0..P::DIM).map(|i| min.get(i)).collect();
+        let upper: Vec<NotNan<T>> = (0..P::DIM).map(|i| max.get(i)).collect();
+        let mut cell = NodeCell::of_root(self);
+        let points = 0..self.len() as u32;
+        self.recurse_range(&lower, &upper, 0, points, &mut cell, visit);
+    }
+
+    fn recurse_range<F>(
+        &self,
+        lower: &[NotNan<T>],
+        upper: &[NotNan<T>],
+        node: usize,
+        points: Range<u32>,
+        cell: &mut NodeCell<T>,
+        visit: &mut F,
+    ) where
+        F: FnMut(Range<u32>, Option<&dyn Fn(u32) -> bool>),
+    {
+        if points.is_empty() || !cell.intersects(lower, upper) {
+            return;
+        }
+        if cell.is_inside(lower, upper) {
+            visit(points, None);
+            return;
+        }
+        self.nodes[node].dispatch_on_type(
+            (cell, visit, points),
+            |(cell, visit, points), split_dim, split_val, right_child| {
+                let dim = split_dim as usize;
+                let parent = cell.extent(dim);
+                let right_child = right_child as usize;
+                let middle = self.first_internal_index(right_child);
+                cell.set_child(dim, parent, split_val, self.drift, true);
+                let left_points = points.start..middle;
+                self.recurse_range(lower, upper, node + 1, left_points, cell, visit);
+                cell.set_child(dim, parent, split_val, self.drift, false);
+                let right_points = middle..points.end;
+                self.recurse_range(lower, upper, right_child, right_points, cell, visit);
+                cell.set_extent(dim, parent);
+            },
+            |(_, visit, points), _, _| {
+                let dim = P::DIM as usize;
+                let inside = |internal_index: u32| {
+                    let start = internal_index as usize * dim;
+                    let point = &self.points[start..start + dim];
+                    (0..dim).all(|i| lower[i] <= point[i] && point[i] <= upper[i])
+                };
+                visit(points, Some(&inside));
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn range_count_matches_range_query_and_brute_force() {
+        let mut cloud: Vec<P2> = random_point_cloud(3000);
+        let mut tree = KDTree::new(&cloud);
+        for round in 0..2 {
+            let mut boxes: Vec<(P2, P2)> = (0..50)
+                .map(|_| {
+                    let (a, b) = (random_point::<2>(), random_point::<2>());
+                    let (a, b): ([f32; 2], [f32; 2]) = (a.into(), b.into());
+                    let min = P2::new2d(a[0].min(b[0]), a[1].min(b[1]));
+                    let max = P2::new2d(a[0].max(b[0]), a[1].max(b[1]));
+                    (min, max)
+                })
+                .collect();
+            // empty, inverted and all-containing boxes
+            boxes.push((P2::new2d(1e6, 1e6), P2::new2d(2e6, 2e6)));
+            boxes.push((P2::new2d(10.0, 10.0), P2::new2d(-10.0, -10.0)));
+            boxes.push((P2::new2d(-1e6, -1e6), P2::new2d(1e6, 1e6)));
+            // a box with a stored point on its border
+            boxes.push((cloud[3], cloud[3] + P2::new2d(20.0, 20.0)));
+            for (min, max) in boxes {
+                let brute_force = cloud
+                    .iter()
+                    .filter(|p| (0..2).all(|i| min.get(i) <= p.get(i) && p.get(i) <= max.get(i)))
+                    .count();
+                let mut indices = tree.range_query(&min, &max);
+                assert_eq!(tree.range_count(&min, &max), brute_force);
+                assert_eq!(indices.len(), brute_force);
+                indices.sort_unstable();
+                indices.dedup();
+                assert_eq!(indices.len(), brute_force);
+                assert!(indices.iter().all(|i| {
+                    let p = cloud[*i as usize];
+                    (0..2).all(|d| min.get(d) <= p.get(d) && p.get(d) <= max.get(d))
+                }));
+            }
+            if round == 0 {
+                for index in (0..3000).step_by(4) {
+                    cloud[index] = cloud[index] * 0.8;
+                    tree.update_point(index as u32, cloud[index]);
+                }
+            }
+        }
+        let all = P2::new2d(-1e6, -1e6);
+        let empty = KDTree::<f32, P2>::new(&[]);
+        assert_eq!(empty.range_count(&all, &(all * -1.0)), 0);
+    }
+}
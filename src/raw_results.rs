@@ -0,0 +1,238 @@
+//! Results of k-nearest-neighbour queries before their externalisation, for pipelined post-processing
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+use core::fmt;
+
+use ordered_float::NotNan;
+
+use crate::distance_scale::unscale_dist2;
+use crate::heap::CandidateHeap;
+use crate::{
+    CandidateContainer, InternalNeighbour, KDTree, Neighbour, Parameters, Point, QueryPoint, Scalar,
+};
+
+/// Candidate containers reused between queries of [`KDTree::knn_raw_results`]
+pub struct KnnScratch<T: Scalar> {
+    /// the number of neighbours the containers keep
+    k: u32,
+    linear: Vec<InternalNeighbour<T>>,
+    heap: BinaryHeap<InternalNeighbour<T>>,
+    candidates: Vec<InternalNeighbour<T>>,
+}
+
+impl<T: Scalar> KnnScratch<T> {
+    /// Creates empty containers, which allocate on the first query.
+    pub fn new() -> Self {
+        KnnScratch {
+            k: 0,
+            linear: Vec::new(),
+            heap: BinaryHeap::new(),
+            candidates: Vec::new(),
+        }
+    }
+
+    /// Empties the containers, for a query of `k` neighbours
+    fn reset(&mut self, k: u32) {
+        // the containers keep as many neighbours as their capacity
+        if k != self.k {
+            self.k = k;
+            self.linear = CandidateHeap::new_with_k(k);
+            self.heap = CandidateHeap::new_with_k(k);
+        }
+        CandidateHeap::clear(&mut self.linear);
+        CandidateHeap::clear(&mut self.heap);
+        self.candidates.clear();
+    }
+}
+
+impl<T: Scalar> Default for KnnScratch<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Scalar> fmt::Debug for KnnScratch<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KnnScratch")
+            .field("k", &self.k)
+            .field("len", &self.candidates.len())
+            .finish()
+    }
+}
+
+/// The neighbours found by [`KDTree::knn_raw_results`], as internal indices and squared distances
+///
+/// They are only externalised, i.e. given their index in the original point cloud and their point,
+/// on demand, with the tree that found them.
+#[derive(Clone, Copy, Debug)]
+pub struct RawResults<'a, T: Scalar> {
+    candidates: &'a [InternalNeighbour<T>],
+    scale: NotNan<T>,
+}
+
+impl<'a, T: Scalar> RawResults<'a, T> {
+    /// Returns the number of neighbours.
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    /// Returns whether no neighbour was found.
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    /// Iterates over the squared distances of the neighbours, in the order of the results.
+    pub fn dist2s(&self) -> impl ExactSizeIterator<Item = NotNan<T>> + 'a {
+        let scale = self.scale;
+        self.candidates
+            .iter()
+            .map(move |candidate| unscale_dist2(candidate.dist2, scale))
+    }
+
+    /// Returns the squared distance of the nearest neighbour, or `None` if there is none.
+    pub fn nearest_dist2(&self) -> Option<NotNan<T>> {
+        self.candidates
+            .iter()
+            .map(|candidate| candidate.dist2)
+            .min()
+            .map(|dist2| unscale_dist2(dist2, self.scale))
+    }
+
+    /// Returns neighbour `i`, which must be smaller than [`Self::len`].
+    ///
+    /// `tree` must be the tree that found the neighbours.
+    pub fn neighbour<P: Point<T>>(&self, tree: &KDTree<T, P>, i: usize) -> Neighbour<T, P> {
+        tree.externalise_neighbour(self.candidates[i])
+    }
+
+    /// Appends the neighbours to `out`, in the order of the results.
+    ///
+    /// `tree` must be the tree that found the neighbours.
+    pub fn externalise_into<P: Point<T>>(
+        &self,
+        tree: &KDTree<T, P>,
+        out: &mut Vec<Neighbour<T, P>>,
+    ) {
+        out.extend(
+            self.candidates
+                .iter()
+                .map(|candidate| tree.externalise_neighbour(*candidate)),
+        );
+    }
+
+    /// Calls `f` with each neighbour, in the order of the results, without collecting them.
+    ///
+    /// `tree` must be the tree that found the neighbours.
+    pub fn for_each<P: Point<T>>(&self, tree: &KDTree<T, P>, mut f: impl FnMut(Neighbour<T, P>)) {
+        for candidate in self.candidates {
+            f(tree.externalise_neighbour(*candidate));
+        }
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Finds the `k` nearest neighbour of `query`, with user-provided parameters,
+    /// keeping them in `scratch` without externalising them.
+    ///
+    /// The results are those of [`Self::knn_advanced`], in the same order, but the neighbours
+    /// are only given their index in the original point cloud and their point when asked
+    /// through the returned [`RawResults`], so that reading the squared distances,
+    /// or rejecting neighbours by them, costs nothing more.
+    /// With the same `scratch` for successive queries, nothing is allocated after the first one of each `k`.
+    /// Panics if `query` does not have the same number of dimensions as the tree.
+    pub fn knn_raw_results<'a, Q: QueryPoint<T> + ?Sized>(
+        &self,
+        k: u32,
+        query: &Q,
+        candidate_container: CandidateContainer,
+        parameters: &Parameters<T>,
+        scratch: &'a mut KnnScratch<T>,
+    ) -> RawResults<'a, T> {
+        let parameters = self.prepare_parameters(parameters);
+        let query = self.query_coordinates(query);
+        // there cannot be more neighbours than points, avoid allocating for them
+        let k = k.min(self.len() as u32);
+        scratch.reset(k);
+        let KnnScratch {
+            linear,
+            heap,
+            candidates,
+            ..
+        } = scratch;
+        match candidate_container {
+            CandidateContainer::Linear => {
+                self.knn_internal(linear, &query, &parameters.internal);
+                self.complete_insufficient_or_panic(linear, k, &query, &parameters.internal);
+                linear.extend_into(candidates, parameters.sort_results);
+            }
+            CandidateContainer::BinaryHeap => {
+                self.knn_internal(heap, &query, &parameters.internal);
+                self.complete_insufficient_or_panic(heap, k, &query, &parameters.internal);
+                heap.extend_into(candidates, parameters.sort_results);
+            }
+        }
+        RawResults {
+            candidates,
+            scale: self.scale,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn raw_results_externalise_to_the_knn_results() {
+        let cloud: Vec<P2> = random_point_cloud(2000);
+        let tree = KDTree::new(&cloud);
+        let mut scratch = KnnScratch::new();
+        let mut out = Vec::new();
+        for candidate_container in [CandidateContainer::Linear, CandidateContainer::BinaryHeap] {
+            for sort_results in [true, false] {
+                let parameters = Parameters {
+                    sort_results,
+                    max_radius: 10.0,
+                    ..Parameters::default()
+                };
+                for k in [1, 7, 40] {
+                    let query: P2 = random_point();
+                    let expected =
+                        tree.knn_advanced(k, &query, candidate_container, &parameters, None);
+                    let raw = tree.knn_raw_results(
+                        k,
+                        &query,
+                        candidate_container,
+                        &parameters,
+                        &mut scratch,
+                    );
+                    assert_eq!(raw.len(), expected.len());
+                    assert!(raw.dist2s().eq(expected.iter().map(|n| n.dist2)));
+                    let nearest = expected.iter().map(|n| n.dist2).min();
+                    assert_eq!(raw.nearest_dist2(), nearest);
+                    out.clear();
+                    raw.externalise_into(&tree, &mut out);
+                    assert_eq!(out, expected);
+                    let mut visited = Vec::new();
+                    raw.for_each(&tree, |neighbour| visited.push(neighbour));
+                    assert_eq!(visited, expected);
+                    if !raw.is_empty() {
+                        assert_eq!(raw.neighbour(&tree, 0), expected[0]);
+                    }
+                }
+            }
+        }
+        // the candidates of a tree with fewer than k points are all its points
+        let small = KDTree::new(&cloud[..3]);
+        let query: P2 = random_point();
+        let raw = small.knn_raw_results(
+            8,
+            &query,
+            CandidateContainer::Linear,
+            &Parameters::default(),
+            &mut scratch,
+        );
+        assert_eq!(raw.len(), 3);
+    }
+}
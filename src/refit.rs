@@ -0,0 +1,641 @@
+//! Updating the coordinates of stored points without rebuilding the tree
+
+use num_traits::{clamp_max, clamp_min, Bounded, Zero};
+use ordered_float::NotNan;
+
+use crate::dim_buffer::DimBuffer;
+use crate::node::Node;
+use crate::{BitSet, BuildError, Error, KDTree, Point, QueryError, Scalar};
+
+/// How the points of a batch of updates were placed, see [`KDTree::update_points`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UpdateSummary {
+    /// number of distinct points updated
+    pub updated: usize,
+    /// number of them that left the cell of their leaf, and were moved to the leaf of their new position
+    pub relocated: usize,
+}
+
+/// Returns [`BuildError::NonFiniteCoordinate`] with `index` if `point` has an infinite coordinate
+pub(crate) fn check_finite<T: Scalar, P: Point<T>>(
+    point: &P,
+    index: u32,
+) -> Result<(), BuildError> {
+    if (0..P::DIM).any(|i| !point.get(i).is_finite()) {
+        Err(BuildError::NonFiniteCoordinate { index })
+    } else {
+        Ok(())
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Moves the point of index `external_index` in the original point cloud to `new_pos`.
+    ///
+    /// The splits of the tree are kept, so the point might now lie on the wrong side of some of them.
+    /// Queries stay exact because their pruning is widened by [`drift_estimate`](Self::drift_estimate),
+    /// but they become slower as the drift grows, so rebuild the tree when it becomes large compared
+    /// to the distances of interest.
+    ///
+    /// This runs in time logarithmic in the number of points.
+    /// Afterwards, [`bounds`](Self::bounds) might be larger than the tight bounding box.
+    ///
+    /// Panics if `external_index` is out of range or if `new_pos` has an infinite coordinate,
+    /// see [`Self::try_update_point`].
+    pub fn update_point(&mut self, external_index: u32, new_pos: P) {
+        assert!(
+            (external_index as usize) < self.len(),
+            "Index {} is out of range for a tree of {} points",
+            external_index,
+            self.len()
+        );
+        let rotated = self.rotated_point(&new_pos);
+        check_finite(rotated.as_ref().unwrap_or(&new_pos), external_index)
+            .unwrap_or_else(|error| panic!("{}", error));
+        self.set_original(external_index, &new_pos);
+        let new_pos = rotated.unwrap_or(new_pos);
+        let internal_index = self.internalise_index(external_index);
+        let point_index = internal_index as usize * P::DIM as usize;
+        if self.subtree_sums.is_some() {
+            let new_point: Vec<_> = (0..P::DIM).map(|i| new_pos.get(i)).collect();
+            self.move_in_subtree_sums(internal_index, &new_point);
+        }
+        for i in 0..P::DIM {
+            let value = new_pos.get(i);
+            let i_u = i as usize;
+            self.points[point_index + i_u] = value;
+            self.min_bounds[i_u] = clamp_max(value, self.min_bounds[i_u]);
+            self.max_bounds[i_u] = clamp_min(value, self.max_bounds[i_u]);
+        }
+        self.drift = clamp_min(self.drift, self.point_drift(internal_index));
+        self.update_scale();
+        self.mark_axis_indices_stale();
+    }
+
+    /// Same as [`Self::update_point`], but returns [`QueryError::IndexOutOfRange`] if `external_index`
+    /// is out of range, and [`BuildError::NonFiniteCoordinate`] with `external_index` if `new_pos`
+    /// has an infinite coordinate, leaving the tree unchanged.
+    pub fn try_update_point(&mut self, external_index: u32, new_pos: P) -> Result<(), Error> {
+        self.check_update(external_index, &new_pos)?;
+        self.update_point(external_index, new_pos);
+        Ok(())
+    }
+
+    /// Moves each point of index `index` in the original point cloud to `new_pos`,
+    /// for each `(index, new_pos)` of `updates`, the last update of a point winning.
+    ///
+    /// Unlike with [`update_point`](Self::update_point), points stay on the right side of all splits,
+    /// so the drift does not grow and queries do not become slower.
+    /// A point whose new position is still within the splits of the ancestors of its leaf is moved in place,
+    /// in time logarithmic in the number of points.
+    /// The other points are relocated to the leaf their new position descends to, which rewrites the storage
+    /// of the points once for the whole batch, in time linear in the number of points but far cheaper
+    /// than a rebuild, as the nodes are kept.
+    /// Leaves receiving points can grow beyond the bucket size, which [`quality`](Self::quality) reports.
+    /// Afterwards, [`bounds`](Self::bounds) might be larger than the tight bounding box.
+    ///
+    /// Panics if an index is out of range or if a new position has an infinite coordinate,
+    /// before updating any point, see [`Self::try_update_points`].
+    pub fn update_points(&mut self, updates: &[(u32, P)]) -> UpdateSummary {
+        for (external_index, new_pos) in updates {
+            assert!(
+                (*external_index as usize) < self.len(),
+                "Index {} is out of range for a tree of {} points",
+                external_index,
+                self.len()
+            );
+            self.check_finite_in_frame(new_pos, *external_index)
+                .unwrap_or_else(|error| panic!("{}", error));
+        }
+        let dim = P::DIM as usize;
+        let mut updated = BitSet::with_len(self.len());
+        let mut relocated = BitSet::with_len(self.len());
+        let mut new_point = Vec::with_capacity(dim);
+        let mut stale_sums = false;
+        for (external_index, new_pos) in updates {
+            let internal_index = self.internalise_index(*external_index);
+            updated.insert(internal_index as usize);
+            self.set_original(*external_index, new_pos);
+            let rotated = self.rotated_point(new_pos);
+            let new_pos = rotated.as_ref().unwrap_or(new_pos);
+            new_point.clear();
+            new_point.extend((0..P::DIM).map(|i| new_pos.get(i)));
+            if self.fits_leaf(internal_index, &new_point) {
+                relocated.remove(internal_index as usize);
+                if !stale_sums {
+                    self.move_in_subtree_sums(internal_index, &new_point);
+                }
+            } else {
+                relocated.insert(internal_index as usize);
+                // the subtree sums are recomputed once the points are in their new leaves
+                stale_sums = true;
+            }
+            let start = internal_index as usize * dim;
+            self.points[start..start + dim].copy_from_slice(&new_point);
+            for (i, value) in new_point.iter().enumerate() {
+                self.min_bounds[i] = clamp_max(*value, self.min_bounds[i]);
+                self.max_bounds[i] = clamp_min(*value, self.max_bounds[i]);
+            }
+        }
+        let summary = UpdateSummary {
+            updated: updated.count(),
+            relocated: relocated.count(),
+        };
+        if summary.relocated > 0 {
+            self.relocate(&relocated);
+        }
+        if stale_sums {
+            self.refresh_subtree_sums();
+        }
+        self.update_scale();
+        self.mark_axis_indices_stale();
+        summary
+    }
+
+    /// Same as [`Self::update_points`], but returns the error of [`Self::try_update_point`]
+    /// for the first invalid update, leaving the tree unchanged.
+    pub fn try_update_points(&mut self, updates: &[(u32, P)]) -> Result<UpdateSummary, Error> {
+        for (external_index, new_pos) in updates {
+            self.check_update(*external_index, new_pos)?;
+        }
+        Ok(self.update_points(updates))
+    }
+
+    /// Replaces the coordinates of all stored points, `points` being in the order of the original point cloud.
+    ///
+    /// As with [`update_point`](Self::update_point), the splits of the tree are kept and queries stay exact,
+    /// but become slower as [`drift_estimate`](Self::drift_estimate) grows.
+    /// This recomputes the drift and the bounds exactly, in time linear in the number of points.
+    ///
+    /// Panics if `points` does not have as many points as the tree, or if a point has an infinite coordinate,
+    /// see [`Self::try_refit`].
+    pub fn refit(&mut self, points: &[P]) {
+        assert_eq!(
+            points.len(),
+            self.len(),
+            "Refitting a tree of {} points with {} points",
+            self.len(),
+            points.len()
+        );
+        for (index, point) in points.iter().enumerate() {
+            self.check_finite_in_frame(point, index as u32)
+                .unwrap_or_else(|error| panic!("{}", error));
+        }
+        if let Some(originals) = &mut self.originals {
+            originals.clear();
+            originals.extend(
+                points
+                    .iter()
+                    .flat_map(|point| (0..P::DIM).map(move |i| point.get(i))),
+            );
+        }
+        for internal_index in 0..self.len() {
+            let point = &points[self.indices[internal_index] as usize];
+            let rotated = self.rotated_point(point);
+            let point = rotated.as_ref().unwrap_or(point);
+            let point_index = internal_index * P::DIM as usize;
+            for i in 0..P::DIM {
+                self.points[point_index + i as usize] = point.get(i);
+            }
+        }
+        let mut lower = DimBuffer::new(P::DIM as usize, NotNan::<T>::min_value());
+        let mut upper = DimBuffer::new(P::DIM as usize, NotNan::<T>::max_value());
+        self.drift = self.recurse_drift(0, &mut lower, &mut upper);
+        self.update_bounds_and_scale();
+        self.refresh_subtree_sums();
+        self.mark_axis_indices_stale();
+    }
+
+    /// Same as [`Self::refit`], but returns [`BuildError::ValuesLengthMismatch`] if `points`
+    /// does not have as many points as the tree, and [`BuildError::NonFiniteCoordinate`]
+    /// if a point has an infinite coordinate, leaving the tree unchanged.
+    pub fn try_refit(&mut self, points: &[P]) -> Result<(), BuildError> {
+        if points.len() != self.len() {
+            return Err(BuildError::ValuesLengthMismatch {
+                points: self.len(),
+                values: points.len(),
+            });
+        }
+        for (index, point) in points.iter().enumerate() {
+            self.check_finite_in_frame(point, index as u32)?;
+        }
+        self.refit(points);
+        Ok(())
+    }
+
+    /// Returns the error of [`Self::try_update_point`] for moving the point of index `external_index` to `new_pos`
+    fn check_update(&self, external_index: u32, new_pos: &P) -> Result<(), Error> {
+        if external_index as usize >= self.len() {
+            return Err(QueryError::IndexOutOfRange {
+                index: external_index,
+                len: self.len(),
+            }
+            .into());
+        }
+        Ok(self.check_finite_in_frame(new_pos, external_index)?)
+    }
+
+    /// Returns [`BuildError::NonFiniteCoordinate`] with `index` if `point` has an infinite coordinate
+    /// in the frame of the tree, where finite coordinates may overflow when rotated
+    pub(crate) fn check_finite_in_frame(&self, point: &P, index: u32) -> Result<(), BuildError> {
+        match self.rotated_point(point) {
+            Some(rotated) => check_finite(&rotated, index),
+            None => check_finite(point, index),
+        }
+    }
+
+    /// Returns the maximum distance, along the split dimension, by which a point sits on the wrong side
+    /// of the split of one of its ancestor nodes.
+    ///
+    /// This is zero for a freshly built tree, exact after [`refit`](Self::refit),
+    /// and an upper bound after [`update_point`](Self::update_point).
+    pub fn drift_estimate(&self) -> NotNan<T> {
+        self.drift
+    }
+
+    /// Computes the drift of the points below `node`, given the bounds of the cell of `node` implied by its ancestors
+    pub(crate) fn recurse_drift(
+        &self,
+        node: usize,
+        lower: &mut [NotNan<T>],
+        upper: &mut [NotNan<T>],
+    ) -> NotNan<T> {
+        self.nodes[node].dispatch_on_type(
+            (lower, upper),
+            |(lower, upper), split_dim, split_val, right_child| {
+                let split_dim = split_dim as usize;
+                let old_upper = upper[split_dim];
+                upper[split_dim] = clamp_max(split_val, old_upper);
+                let left_drift = self.recurse_drift(node + 1, lower, upper);
+                upper[split_dim] = old_upper;
+                let old_lower = lower[split_dim];
+                lower[split_dim] = clamp_min(split_val, old_lower);
+                let right_drift = self.recurse_drift(right_child as usize, lower, upper);
+                lower[split_dim] = old_lower;
+                clamp_min(left_drift, right_drift)
+            },
+            |(lower, upper), bucket_start_index, bucket_size| {
+                let bucket_end_index = bucket_start_index + bucket_size;
+                let mut drift = NotNan::<T>::zero();
+                for bucket_index in bucket_start_index..bucket_end_index {
+                    let point_index = bucket_index as usize * P::DIM as usize;
+                    let point = &self.points[point_index..point_index + (P::DIM as usize)];
+                    for ((value, lower), upper) in point.iter().zip(lower.iter()).zip(upper.iter())
+                    {
+                        drift = clamp_min(drift, *lower - *value);
+                        drift = clamp_min(drift, *value - *upper);
+                    }
+                }
+                drift
+            },
+        )
+    }
+
+    /// Returns whether `point` lies within the splits of all ancestors of the leaf holding `internal_index`
+    fn fits_leaf(&self, internal_index: u32, point: &[NotNan<T>]) -> bool {
+        let mut node = 0;
+        while let Some((split_dim, split_val, right_child)) = self.nodes[node].dispatch_on_type(
+            (),
+            |_, split_dim, split_val, right_child| Some((split_dim, split_val, right_child)),
+            |_, _, _| None,
+        ) {
+            let value = point[split_dim as usize];
+            let right_child = right_child as usize;
+            if internal_index >= self.first_bucket_index(right_child) {
+                if value < split_val {
+                    return false;
+                }
+                node = right_child;
+            } else {
+                if value > split_val {
+                    return false;
+                }
+                node += 1;
+            }
+        }
+        true
+    }
+
+    /// Moves the points of `relocated`, by internal index, to the leaves their coordinates descend to,
+    /// rewriting the storage leaf by leaf
+    fn relocate(&mut self, relocated: &BitSet) {
+        let dim = P::DIM as usize;
+        let mut incoming: Vec<(usize, u32)> = (0..self.len() as u32)
+            .filter(|internal_index| relocated.contains(*internal_index as usize))
+            .map(|internal_index| {
+                let start = internal_index as usize * dim;
+                let point = &self.points[start..start + dim];
+                let mut node = 0;
+                while let Some((split_dim, split_val, right_child)) = self.nodes[node]
+                    .dispatch_on_type(
+                        (),
+                        |_, split_dim, split_val, right_child| {
+                            Some((split_dim, split_val, right_child))
+                        },
+                        |_, _, _| None,
+                    )
+                {
+                    node = if point[split_dim as usize] <= split_val {
+                        node + 1
+                    } else {
+                        right_child as usize
+                    };
+                }
+                (node, internal_index)
+            })
+            .collect();
+        incoming.sort_unstable();
+        let mut points = Vec::with_capacity(self.points.len());
+        let mut indices = Vec::with_capacity(self.len());
+        let mut ids = self.ids.as_ref().map(|ids| Vec::with_capacity(ids.len()));
+        let timestamps = self.external_timestamps();
+        let mut next_incoming = 0;
+        for node in 0..self.nodes.len() {
+            let bucket = self.nodes[node].dispatch_on_type(
+                (),
+                |_, _, _, _| None,
+                |_, bucket_start_index, bucket_size| {
+                    Some(bucket_start_index..bucket_start_index + bucket_size)
+                },
+            );
+            let bucket = match bucket {
+                Some(bucket) => bucket,
+                None => continue,
+            };
+            let new_start_index = indices.len() as u32;
+            let staying = bucket.filter(|index| !relocated.contains(*index as usize));
+            let arriving = incoming[next_incoming..]
+                .iter()
+                .take_while(|(target, _)| *target == node)
+                .map(|(_, index)| *index);
+            let mut arrived = 0;
+            for index in staying.chain(arriving.inspect(|_| arrived += 1)) {
+                let start = index as usize * dim;
+                points.extend_from_slice(&self.points[start..start + dim]);
+                indices.push(self.indices[index as usize]);
+                if let (Some(ids), Some(old_ids)) = (&mut ids, &self.ids) {
+                    ids.push(old_ids[index as usize]);
+                }
+            }
+            next_incoming += arrived;
+            self.nodes[node] =
+                Node::new_leaf_node(new_start_index, indices.len() as u32 - new_start_index);
+        }
+        self.points = points;
+        self.indices = indices;
+        self.ids = ids;
+        self.update_internal_indices();
+        self.set_external_timestamps(timestamps);
+    }
+
+    /// Computes the drift of a single point, by walking from the root to its bucket
+    fn point_drift(&self, internal_index: u32) -> NotNan<T> {
+        let point_index = internal_index as usize * P::DIM as usize;
+        let point = &self.points[point_index..point_index + (P::DIM as usize)];
+        let mut drift = NotNan::<T>::zero();
+        let mut node = 0;
+        while let Some((split_dim, split_val, right_child)) = self.nodes[node].dispatch_on_type(
+            (),
+            |_, split_dim, split_val, right_child| Some((split_dim, split_val, right_child)),
+            |_, _, _| None,
+        ) {
+            let value = point[split_dim as usize];
+            let right_child = right_child as usize;
+            if internal_index >= self.first_bucket_index(right_child) {
+                drift = clamp_min(drift, split_val - value);
+                node = right_child;
+            } else {
+                drift = clamp_min(drift, value - split_val);
+                node += 1;
+            }
+        }
+        drift
+    }
+
+    /// Returns the internal index of the first point below `node`
+    fn first_bucket_index(&self, mut node: usize) -> u32 {
+        loop {
+            let bucket_start_index = self.nodes[node].dispatch_on_type(
+                (),
+                |_, _, _, _| None,
+                |_, bucket_start_index, _| Some(bucket_start_index),
+            );
+            match bucket_start_index {
+                Some(bucket_start_index) => return bucket_start_index,
+                None => node += 1,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+    use rand::Rng;
+
+    fn jitter(cloud: &[P2], amplitude: f32) -> Vec<P2> {
+        let mut rng = rand::thread_rng();
+        cloud
+            .iter()
+            .map(|p| {
+                P2::new2d(
+                    p.get(0).into_inner() + rng.gen_range(-amplitude..amplitude),
+                    p.get(1).into_inner() + rng.gen_range(-amplitude..amplitude),
+                )
+            })
+            .collect()
+    }
+
+    fn assert_same_knn(tree: &KDTree<f32, P2>, cloud: &[P2]) {
+        let reference = KDTree::new(cloud);
+        for _ in 0..100 {
+            let query: P2 = random_point();
+            let dist2 = |neighbours: Vec<Neighbour<f32, P2>>| -> Vec<_> {
+                neighbours.into_iter().map(|n| n.dist2).collect()
+            };
+            assert_eq!(dist2(tree.knn(5, &query)), dist2(reference.knn(5, &query)));
+        }
+    }
+
+    #[test]
+    fn fresh_tree_has_no_drift() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let mut tree = KDTree::new(&cloud);
+        assert_eq!(tree.drift_estimate(), 0.0);
+        tree.refit(&cloud);
+        assert_eq!(tree.drift_estimate(), 0.0);
+    }
+
+    #[test]
+    fn refit_keeps_queries_exact() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let mut tree = KDTree::new(&cloud);
+        let moved = jitter(&cloud, 2.0);
+        tree.refit(&moved);
+        assert!(tree.drift_estimate().into_inner() > 0.0);
+        assert!(tree.drift_estimate().into_inner() < 4.0);
+        assert_eq!(tree.points(), moved);
+        assert_same_knn(&tree, &moved);
+    }
+
+    #[test]
+    fn update_point_keeps_queries_exact() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let mut tree = KDTree::new(&cloud);
+        let mut moved = cloud.clone();
+        for (index, point) in jitter(&cloud, 2.0).into_iter().enumerate().step_by(3) {
+            tree.update_point(index as u32, point);
+            moved[index] = point;
+        }
+        assert_eq!(tree.points(), moved);
+        assert_same_knn(&tree, &moved);
+        // the drift after individual updates bounds the exact one
+        let exact = {
+            let mut tree = tree.clone();
+            tree.refit(&moved);
+            tree.drift_estimate()
+        };
+        assert!(tree.drift_estimate() >= exact);
+    }
+
+    #[test]
+    fn update_point_can_move_far_away() {
+        let mut tree = KDTree::new(&random_point_cloud::<2>(100));
+        tree.update_point(7, P2::new2d(1e6, -1e6));
+        let neighbour = tree.knn(1, &P2::new2d(1e6, -1e6));
+        assert_eq!(neighbour[0].index, 7);
+        assert_eq!(neighbour[0].dist2, 0.0);
+        let (min, max) = tree.bounds().unwrap();
+        assert_eq!(min.get(1), -1e6);
+        assert_eq!(max.get(0), 1e6);
+    }
+
+    #[test]
+    #[should_panic]
+    fn update_point_out_of_range_panics() {
+        let mut tree = KDTree::new(&random_point_cloud::<2>(10));
+        tree.update_point(10, P2::default());
+    }
+
+    #[test]
+    fn batch_updates_match_a_rebuild() {
+        let mut rng = rand::thread_rng();
+        let count = 2000;
+        let mut expected: Vec<P2> = random_point_cloud(count);
+        let ids: Vec<u64> = (0..count as u64).map(|i| i * 3).collect();
+        let options = BuildOptions {
+            subtree_sums: true,
+            ..BuildOptions::default()
+        };
+        let mut tree = KDTree::try_new_with_ids(&expected, &ids, &options, true).unwrap();
+        let mut total_relocated = 0;
+        for round in 0..30 {
+            // a few points move, some by small steps that mostly stay in their leaf, some far,
+            // with repeated indices within a batch
+            let updates: Vec<(u32, P2)> = (0..rng.gen_range(1..60))
+                .map(|_| {
+                    let index = rng.gen_range(0..count / 4);
+                    let p = expected[index as usize];
+                    let new_pos = if rng.gen_bool(0.7) {
+                        P2::new2d(
+                            p.get(0).into_inner() + rng.gen_range(-0.5..0.5),
+                            p.get(1).into_inner() + rng.gen_range(-0.5..0.5),
+                        )
+                    } else {
+                        random_point()
+                    };
+                    (index, new_pos)
+                })
+                .collect();
+            let drift = tree.drift_estimate();
+            let summary = tree.update_points(&updates);
+            for (index, new_pos) in &updates {
+                expected[*index as usize] = *new_pos;
+            }
+            let mut distinct: Vec<u32> = updates.iter().map(|(index, _)| *index).collect();
+            distinct.sort_unstable();
+            distinct.dedup();
+            assert_eq!(summary.updated, distinct.len());
+            assert!(summary.relocated <= summary.updated);
+            total_relocated += summary.relocated;
+            // batches keep points on the right side of the splits
+            assert_eq!(tree.drift_estimate(), drift);
+            assert_eq!(tree.points(), expected);
+            if round % 10 == 9 {
+                assert_same_knn(&tree, &expected);
+                let query = random_point();
+                let reference = KDTree::new(&expected);
+                assert_eq!(
+                    tree.aggregate_within(&query, 30.0).count(),
+                    reference.aggregate_within(&query, 30.0).count()
+                );
+                let sums = tree.aggregate_within(&query, 300.0);
+                let reference_sums = reference.aggregate_within(&query, 300.0);
+                for (a, b) in sums.sums().iter().zip(reference_sums.sums()) {
+                    assert!((a - b).abs() < 1e-6 * count as f64);
+                }
+            }
+            // individual updates and refits interleave with batches
+            let index = rng.gen_range(0..count);
+            expected[index as usize] = random_point();
+            tree.update_point(index, expected[index as usize]);
+            if round % 5 == 4 {
+                tree.refit(&expected);
+            }
+            assert_eq!(tree.id_of(index), ids[index as usize]);
+        }
+        assert!(total_relocated > 0);
+        for index in 0..count {
+            assert_eq!(tree.id_of(index), ids[index as usize]);
+        }
+    }
+
+    #[test]
+    fn non_finite_updates_are_rejected_and_leave_the_tree_unchanged() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let mut tree = KDTree::new(&cloud);
+        let reference = tree.clone();
+        let infinite = P2::new2d(1.0, f32::INFINITY);
+        assert_eq!(
+            tree.try_update_point(3, infinite),
+            Err(Error::Build(BuildError::NonFiniteCoordinate { index: 3 }))
+        );
+        assert_eq!(
+            tree.try_update_point(1000, cloud[0]),
+            Err(Error::Query(QueryError::IndexOutOfRange {
+                index: 1000,
+                len: 1000
+            }))
+        );
+        // the valid update before the invalid one is not applied
+        assert_eq!(
+            tree.try_update_points(&[(5, cloud[6]), (8, infinite)]),
+            Err(Error::Build(BuildError::NonFiniteCoordinate { index: 8 }))
+        );
+        let mut points = cloud.clone();
+        points[11] = P2::new2d(f32::NEG_INFINITY, 0.0);
+        assert_eq!(
+            tree.try_refit(&points),
+            Err(BuildError::NonFiniteCoordinate { index: 11 })
+        );
+        assert_eq!(
+            tree.try_refit(&cloud[1..]),
+            Err(BuildError::ValuesLengthMismatch {
+                points: 1000,
+                values: 999
+            })
+        );
+        assert_eq!(tree, reference);
+        assert!(tree.find_non_finite().is_empty());
+        assert_eq!(tree.try_update_points(&[(5, cloud[6])]).map(|_| ()), Ok(()));
+        assert_eq!(tree.try_refit(&cloud), Ok(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Point 8 has an infinite coordinate")]
+    fn non_finite_updates_panic() {
+        let cloud: Vec<P2> = random_point_cloud(100);
+        let mut tree = KDTree::new(&cloud);
+        tree.update_points(&[(5, cloud[6]), (8, P2::new2d(f32::INFINITY, 0.0))]);
+    }
+}
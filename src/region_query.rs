@@ -0,0 +1,131 @@
+//! Radius queries around stored points, as used by density-based clustering
+
+use alloc::vec::Vec;
+use ordered_float::NotNan;
+
+use crate::internal_parameters::InternalParameters;
+use crate::{BitSet, CandidateCollector, KDTree, Parameters, Point, Scalar};
+
+/// Collects the external indices of the candidates that were not visited yet, and counts all of them
+struct RegionCollector<'a> {
+    indices: &'a [u32],
+    visited: &'a mut BitSet,
+    mark_visited: bool,
+    out: &'a mut Vec<u32>,
+    count: usize,
+}
+
+impl<T: Scalar> CandidateCollector<T> for RegionCollector<'_> {
+    fn add(&mut self, _dist2: NotNan<T>, index: u32) {
+        self.count += 1;
+        let external_index = self.indices[index as usize];
+        let unvisited = if self.mark_visited {
+            self.visited.insert(external_index as usize)
+        } else {
+            !self.visited.contains(external_index as usize)
+        };
+        if unvisited {
+            self.out.push(external_index);
+        }
+    }
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        // only limited by the radius
+        None
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Finds the points within distance `eps`, inclusive, of the point of index `external_index`
+    /// in the original point cloud.
+    ///
+    /// Appends to `out` the indices of these points that are not in `visited`,
+    /// including `external_index` itself if it is not,
+    /// and adds them to `visited` if `mark_visited` is true.
+    /// Returns the number of points within `eps`, visited or not.
+    ///
+    /// Panics if `external_index` is out of range, if `visited` is shorter than the tree,
+    /// or if `eps` is negative or NaN.
+    pub fn region_query(
+        &self,
+        external_index: u32,
+        eps: T,
+        visited: &mut BitSet,
+        mark_visited: bool,
+        out: &mut Vec<u32>,
+    ) -> usize {
+        assert!(
+            (external_index as usize) < self.len(),
+            "Index {} is out of range for a tree of {} points",
+            external_index,
+            self.len()
+        );
+        assert!(
+            visited.len() >= self.len(),
+            "The visited set holds {} indices but the tree has {} points",
+            visited.len(),
+            self.len()
+        );
+        assert!(eps >= T::zero(), "eps must be positive or zero");
+        let parameters = Parameters {
+            max_radius: eps,
+            ..Parameters::default()
+        };
+        let start = self.internalise_index(external_index) as usize * P::DIM as usize;
+        let mut collector = RegionCollector {
+            indices: &self.indices,
+            visited,
+            mark_visited,
+            out,
+            count: 0,
+        };
+        self.knn_internal(
+            &mut collector,
+            &self.points[start..start + P::DIM as usize],
+            &InternalParameters::new(&parameters, self.scale),
+        );
+        collector.count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn region_query_matches_brute_force() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let tree = KDTree::new(&cloud);
+        let eps = 10.0;
+        let mut visited = BitSet::with_len(cloud.len());
+        for index in [0, 17, 500, 999] {
+            let center = cloud[index as usize];
+            let mut expected: Vec<u32> = (0..cloud.len() as u32)
+                .filter(|i| {
+                    let p = cloud[*i as usize];
+                    let dx = p.get(0) - center.get(0);
+                    let dy = p.get(1) - center.get(1);
+                    (dx * dx + dy * dy).into_inner() <= eps * eps
+                })
+                .collect();
+            let mut out = Vec::new();
+            let count = tree.region_query(index, eps, &mut visited, false, &mut out);
+            out.sort_unstable();
+            assert_eq!(count, expected.len());
+            assert_eq!(out, expected);
+            assert!(out.contains(&index));
+            // skip visited points, and mark the others
+            visited.insert(index as usize);
+            out.clear();
+            let count = tree.region_query(index, eps, &mut visited, true, &mut out);
+            out.sort_unstable();
+            expected.retain(|i| *i != index);
+            assert_eq!(count, expected.len() + 1);
+            assert_eq!(out, expected);
+            out.clear();
+            tree.region_query(index, eps, &mut visited, true, &mut out);
+            assert!(out.is_empty());
+            visited.clear();
+        }
+    }
+}
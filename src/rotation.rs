@@ -0,0 +1,514 @@
+//! Random rotations of the points of a tree, see [`BuildOptions::rotation_seed`](crate::BuildOptions::rotation_seed)
+
+use alloc::{vec, vec::Vec};
+
+use num_traits::Float;
+use ordered_float::{FloatCore, NotNan};
+
+use crate::dim_buffer::DimBuffer;
+use crate::infinite::HasInfinite;
+use crate::rng::SplitMix64;
+use crate::{KDTree, Point, QueryError, Scalar};
+
+/// Returns a uniformly random rotation of `dim` dimensions drawn from `seed`, as a row-major matrix
+///
+/// The rotation is the orthonormal factor of the QR decomposition of a matrix of independent standard
+/// Gaussian entries, computed by Gram-Schmidt in `f64` so that it only depends on `seed` and `dim`.
+/// As the diagonal of the triangular factor is positive, the rotation is distributed uniformly.
+pub(crate) fn random_rotation<T: Scalar>(dim: usize, seed: u64) -> Vec<T> {
+    let mut rng = SplitMix64::new(seed);
+    // the columns of the Gaussian matrix, made orthonormal in place
+    let mut columns: Vec<f64> = vec![0.0; dim * dim];
+    for j in 0..dim {
+        loop {
+            let column = j * dim;
+            for value in &mut columns[column..column + dim] {
+                *value = gaussian(&mut rng);
+            }
+            for previous in 0..j {
+                let previous = previous * dim;
+                let dot: f64 = (0..dim)
+                    .map(|i| columns[previous + i] * columns[column + i])
+                    .sum();
+                for i in 0..dim {
+                    columns[column + i] -= dot * columns[previous + i];
+                }
+            }
+            let norm = Float::sqrt((0..dim).map(|i| columns[column + i].powi(2)).sum::<f64>());
+            // a column nearly dependent on the previous ones is drawn again, which almost never happens
+            if norm > 1e-6 {
+                for value in &mut columns[column..column + dim] {
+                    *value /= norm;
+                }
+                break;
+            }
+        }
+    }
+    // the rows of the rotation are the columns of the orthonormal factor
+    columns
+        .into_iter()
+        .map(|value| T::from(value).expect("rotation entries are representable"))
+        .collect()
+}
+
+/// Returns a standard Gaussian number, by the Box-Muller transform
+fn gaussian(rng: &mut SplitMix64) -> f64 {
+    let unit = |rng: &mut SplitMix64| (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+    // in (0, 1], so that its logarithm is finite
+    let u = 1.0 - unit(rng);
+    let angle = 2.0 * core::f64::consts::PI * unit(rng);
+    Float::sqrt(-2.0 * Float::ln(u)) * Float::cos(angle)
+}
+
+/// Returns coordinate `i` of `coordinates` multiplied by `rotation`, row-major, or by its transpose if `inverse`
+fn rotated_coordinate<T: Scalar>(
+    rotation: &[T],
+    inverse: bool,
+    coordinates: &[NotNan<T>],
+    i: usize,
+) -> T {
+    let dim = coordinates.len();
+    coordinates
+        .iter()
+        .enumerate()
+        .fold(T::zero(), |sum, (j, coordinate)| {
+            let entry = if inverse {
+                rotation[j * dim + i]
+            } else {
+                rotation[i * dim + j]
+            };
+            sum + entry * **coordinate
+        })
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Returns the rotation applied to the points and queries, as a row-major matrix of `D × D` entries,
+    /// or `None` if the tree was built without [`BuildOptions::rotation_seed`](crate::BuildOptions::rotation_seed).
+    ///
+    /// The tree stores `R p` for each point `p`, where `R` is this matrix.
+    /// It is needed to interpret what is expressed in the frame of the tree,
+    /// such as the bounds, the nodes, the dumps or the flat export.
+    pub fn rotation(&self) -> Option<&[T]> {
+        self.rotation.as_deref()
+    }
+
+    /// Returns `point` rotated into the frame of the tree, or `None` if the tree is not rotated
+    ///
+    /// Coordinates that overflow are infinite.
+    pub(crate) fn rotated_point(&self, point: &P) -> Option<P> {
+        let rotation = self.rotation.as_deref()?;
+        let coordinates =
+            DimBuffer::try_from_fn(P::DIM as usize, |i| Ok::<_, ()>(point.get(i as u32)))
+                .expect("copying coordinates does not fail");
+        let mut rotated = P::default();
+        for i in 0..P::DIM as usize {
+            let value = rotated_coordinate(rotation, false, &coordinates, i);
+            rotated.set(
+                i as u32,
+                NotNan::new(value).unwrap_or_else(|_| NotNan::infinite()),
+            );
+        }
+        Some(rotated)
+    }
+
+    /// Rotates the coordinates of a query into the frame of the tree, or returns an error if a rotated
+    /// coordinate is NaN, which happens only with infinite or overflowing coordinates
+    pub(crate) fn rotate_query(&self, query: DimBuffer<T>) -> Result<DimBuffer<T>, QueryError> {
+        match self.rotation.as_deref() {
+            None => Ok(query),
+            Some(rotation) => DimBuffer::try_from_fn(query.len(), |i| {
+                NotNan::new(rotated_coordinate(rotation, false, &query, i))
+                    .map_err(|_| QueryError::NanCoordinate)
+            }),
+        }
+    }
+
+    /// Returns the point of `coordinates`, given in the frame of the tree, rotated back into the frame of the points
+    ///
+    /// This rounds, so the stored points are rather read from [`Self::original_coordinates`].
+    pub(crate) fn point_from_tree_frame(&self, coordinates: &[NotNan<T>]) -> P {
+        match self.rotation.as_deref() {
+            None => P::from_slice(coordinates),
+            Some(rotation) => {
+                let mut point = P::default();
+                for i in 0..P::DIM as usize {
+                    let value = rotated_coordinate(rotation, true, coordinates, i);
+                    point.set(
+                        i as u32,
+                        NotNan::new(value).unwrap_or_else(|_| NotNan::infinite()),
+                    );
+                }
+                point
+            }
+        }
+    }
+
+    /// Returns the coordinates of the point of internal index `internal` in the frame of the points,
+    /// as given to the tree if it is rotated
+    pub(crate) fn original_coordinates(&self, internal: u32) -> &[NotNan<T>] {
+        match &self.originals {
+            None => self.stored_coordinates(internal),
+            Some(originals) => {
+                let dim = P::DIM as usize;
+                let start = self.indices[internal as usize] as usize * dim;
+                &originals[start..start + dim]
+            }
+        }
+    }
+
+    /// Records `point` as the coordinates as given of the point of index `external_index`
+    /// in the original point cloud, if the tree is rotated
+    pub(crate) fn set_original(&mut self, external_index: u32, point: &P) {
+        if let Some(originals) = &mut self.originals {
+            let start = external_index as usize * P::DIM as usize;
+            for i in 0..P::DIM {
+                originals[start + i as usize] = point.get(i);
+            }
+        }
+    }
+
+    /// Returns the corners of a box of the frame of the tree that contains the rotation of the box from `min` to `max`,
+    /// given in the frame of the points and not empty, the tree being rotated
+    ///
+    /// The box is widened to cover the rounding of the rotated points, and unbounded along the dimensions
+    /// whose bounds are NaN, which happens with infinite corners.
+    pub(crate) fn rotated_box(&self, min: &P, max: &P) -> (P, P) {
+        let rotation = self.rotation.as_deref().expect("the tree is rotated");
+        let dim = P::DIM as usize;
+        let two = T::one() + T::one();
+        let center: Vec<T> = (0..P::DIM)
+            .map(|i| *min.get(i) / two + *max.get(i) / two)
+            .collect();
+        let half_width: Vec<T> = (0..P::DIM)
+            .map(|i| *max.get(i) / two - *min.get(i) / two)
+            .collect();
+        // the rotated coordinates are sums of `dim` products, each rounding by a relative epsilon
+        let rounding = <T as FloatCore>::epsilon()
+            * T::from(4 * (dim + 1)).expect("small integers are representable");
+        let (mut lower, mut upper) = (P::default(), P::default());
+        for i in 0..dim {
+            let row = &rotation[i * dim..(i + 1) * dim];
+            let (mut rotated_center, mut extent, mut magnitude) = (T::zero(), T::zero(), T::zero());
+            for ((entry, center), half_width) in row.iter().zip(&center).zip(&half_width) {
+                rotated_center += *entry * *center;
+                extent += FloatCore::abs(*entry) * *half_width;
+                magnitude += FloatCore::abs(*entry) * (FloatCore::abs(*center) + *half_width);
+            }
+            let margin = magnitude * rounding + T::min_positive_value();
+            let low = NotNan::new(rotated_center - extent - margin)
+                .unwrap_or_else(|_| -NotNan::infinite());
+            let high = NotNan::new(rotated_center + extent + margin)
+                .unwrap_or_else(|_| NotNan::infinite());
+            lower.set(i as u32, low);
+            upper.set(i as u32, high);
+        }
+        (lower, upper)
+    }
+
+    /// Rotates `vector`, a direction in the frame of the points, into the frame of the tree
+    pub(crate) fn rotate_direction(&self, vector: &mut [T]) {
+        if let Some(rotation) = self.rotation.as_deref() {
+            let original: Vec<NotNan<T>> = vector
+                .iter()
+                .map(|value| NotNan::new(*value).expect("directions are not NaN"))
+                .collect();
+            for (i, value) in vector.iter_mut().enumerate() {
+                *value = rotated_coordinate(rotation, false, &original, i);
+            }
+        }
+    }
+
+    /// Returns the coordinates of the stored point of internal index `internal`, in the frame of `other`
+    ///
+    /// They are copied as they are if both trees have the same rotation, or none.
+    pub(crate) fn stored_coordinates_for(&self, internal: u32, other: &Self) -> DimBuffer<T> {
+        let stored = self.stored_coordinates(internal);
+        if self.rotation == other.rotation {
+            return DimBuffer::try_from_fn(stored.len(), |i| Ok::<_, ()>(stored[i]))
+                .expect("copying coordinates does not fail");
+        }
+        let point = P::from_slice(self.original_coordinates(internal));
+        let point = other.rotated_point(&point).unwrap_or(point);
+        DimBuffer::try_from_fn(P::DIM as usize, |i| Ok::<_, ()>(point.get(i as u32)))
+            .expect("copying coordinates does not fail")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::random_rotation;
+    use crate::rng::SplitMix64;
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn rotated(seed: u64) -> BuildOptions {
+        BuildOptions {
+            rotation_seed: Some(seed),
+            ..BuildOptions::default()
+        }
+    }
+
+    #[test]
+    fn random_rotations_are_orthonormal_and_depend_on_the_seed() {
+        for dim in [1, 2, 3, 7] {
+            let rotation: Vec<f64> = random_rotation(dim, 221);
+            for i in 0..dim {
+                for j in 0..dim {
+                    let dot: f64 = (0..dim)
+                        .map(|k| rotation[i * dim + k] * rotation[j * dim + k])
+                        .sum();
+                    let expected = if i == j { 1.0 } else { 0.0 };
+                    assert!((dot - expected).abs() < 1e-12);
+                }
+            }
+            assert_eq!(rotation, random_rotation::<f64>(dim, 221));
+        }
+        assert_ne!(random_rotation::<f64>(3, 1), random_rotation::<f64>(3, 2));
+    }
+
+    #[test]
+    fn rotated_trees_find_the_same_neighbours_as_unrotated_ones() {
+        let mut rng = SplitMix64::new(221);
+        let mut coordinate = || rng.below(200_000) as f64 / 1000.0 - 100.0;
+        let mut cloud: Vec<P3d> = (0..3000)
+            .map(|_| P3d::from([coordinate(), coordinate(), coordinate()]))
+            .collect();
+        let queries: Vec<P3d> = (0..50)
+            .map(|_| P3d::from([coordinate(), coordinate(), coordinate()]))
+            .collect();
+        let mut plain = KDTree::new(&cloud);
+        let mut tree = KDTree::new_with_options(&cloud, &rotated(7));
+        assert!(plain.rotation().is_none());
+        assert_eq!(tree.rotation().map(<[f64]>::len), Some(9));
+        let close = |a: f64, b: f64| (a - b).abs() <= 1e-9 * (1.0 + a.abs());
+        for round in 0..2 {
+            // the points are returned as given, not rotated back
+            assert_eq!(tree.points(), cloud);
+            for query in &queries {
+                let expected = plain.knn(8, query);
+                let found = tree.knn(8, query);
+                let indices = |neighbours: &[Neighbour<f64, P3d>]| {
+                    neighbours.iter().map(|n| n.index).collect::<Vec<_>>()
+                };
+                assert_eq!(indices(&found), indices(&expected));
+                for (found, expected) in found.iter().zip(&expected) {
+                    assert!(close(*found.dist2, *expected.dist2));
+                    assert_eq!(found.point, expected.point);
+                }
+                let radius = Radius::new(15.0);
+                let mut within: Vec<u32> = tree
+                    .radius_search_ordered(query, radius)
+                    .iter()
+                    .map(|n| n.index)
+                    .collect();
+                within.sort_unstable();
+                let mut expected: Vec<u32> = plain
+                    .radius_search_ordered(query, radius)
+                    .iter()
+                    .map(|n| n.index)
+                    .collect();
+                expected.sort_unstable();
+                assert_eq!(within, expected);
+            }
+            if round == 0 {
+                // moved points are rotated like the built ones
+                for index in (0..3000).step_by(4) {
+                    cloud[index] = cloud[index] * 0.8;
+                    plain.update_point(index as u32, cloud[index]);
+                    tree.update_point(index as u32, cloud[index]);
+                }
+            }
+        }
+        let flat = tree.export_flat();
+        assert_eq!(flat.rotation.as_deref(), tree.rotation());
+        let imported: KDTree<f64, P3d> = flat.import().expect("exported trees import");
+        assert_eq!(imported, tree);
+        assert_eq!(imported.knn(8, &queries[0]), tree.knn(8, &queries[0]));
+        // without the original points, they are rotated back
+        let imported: KDTree<f64, P3d> = FlatTree {
+            original_points: None,
+            ..flat
+        }
+        .import()
+        .expect("exported trees import");
+        for (index, point) in imported.iter() {
+            let original = cloud[index as usize];
+            assert!((0..3).all(|i| close(*point.get(i), *original.get(i))));
+        }
+        // rebuilding does not accumulate rounding
+        for _ in 0..3 {
+            tree.rebuild();
+        }
+        assert_eq!(tree.points(), cloud);
+    }
+
+    #[test]
+    fn rotated_trees_answer_boxes_and_slabs_in_the_frame_of_the_points() {
+        let mut cloud: Vec<P3> = random_point_cloud(3000);
+        let mut plain = KDTree::new(&cloud);
+        let mut tree = KDTree::new_with_options(&cloud, &rotated(3));
+        let sorted = |mut indices: Vec<u32>| {
+            indices.sort_unstable();
+            indices
+        };
+        for round in 0..3 {
+            let mut boxes: Vec<(P3, P3)> = (0..50)
+                .map(|_| {
+                    let (a, b) = (random_point::<3>(), random_point::<3>());
+                    let min = P3::from([0, 1, 2].map(|i| a.get(i).min(b.get(i)).into_inner()));
+                    let max = P3::from([0, 1, 2].map(|i| a.get(i).max(b.get(i)).into_inner()));
+                    (min, max)
+                })
+                .collect();
+            // stored points on the borders, inverted and unbounded boxes
+            boxes.push((cloud[3], cloud[3] + P3::from([20.0, 20.0, 20.0])));
+            boxes.push((cloud[5] + P3::from([-20.0, -20.0, -20.0]), cloud[5]));
+            boxes.push((cloud[7], cloud[7]));
+            boxes.push((P3::from([10.0; 3]), P3::from([-10.0; 3])));
+            boxes.push((P3::from([f32::NEG_INFINITY; 3]), P3::from([0.0; 3])));
+            for (min, max) in &boxes {
+                let expected = sorted(plain.range_query(min, max));
+                assert_eq!(sorted(tree.range_query(min, max)), expected);
+                assert_eq!(tree.range_count(min, max), expected.len());
+                assert_eq!(tree.extract_region(min, max).len(), expected.len());
+            }
+            for dim in 0..3 {
+                plain.build_axis_index(dim);
+                tree.build_axis_index(dim);
+                let hi = *cloud[11].get(dim);
+                assert_eq!(
+                    tree.axis_range(dim, hi - 30.0, hi),
+                    plain.axis_range(dim, hi - 30.0, hi)
+                );
+            }
+            if round == 0 {
+                let moved: Vec<(u32, P3)> = (0..3000)
+                    .step_by(5)
+                    .map(|index| (index, random_point()))
+                    .collect();
+                for (index, point) in &moved {
+                    cloud[*index as usize] = *point;
+                }
+                plain.update_points(&moved);
+                tree.update_points(&moved);
+            } else if round == 1 {
+                let inserted: Vec<P3> = random_point_cloud(40);
+                let removals: Vec<u32> = (0..3000).step_by(20).collect();
+                plain.apply_batch(&removals, &inserted);
+                tree.apply_batch(&removals, &inserted);
+                cloud = plain.points();
+            }
+            assert_eq!(tree.points(), cloud);
+        }
+        assert_eq!(tree.export_flat().import::<P3>(), Ok(tree.clone()));
+        let merged = KDTree::merge(
+            tree.clone(),
+            KDTree::new_with_options(&cloud[..10], &rotated(3)),
+        );
+        assert!(merged.rotation().is_some());
+        assert_eq!(merged.points()[..cloud.len()], cloud[..]);
+    }
+
+    #[test]
+    fn rotated_trees_differ_from_unrotated_ones_only_at_boundaries_and_ties() {
+        // the points of a grid are at exact distances, which the rotated ones round
+        let cloud: Vec<P2> = (0..256)
+            .map(|i| P2::new2d((i % 16) as f32, (i / 16) as f32))
+            .collect();
+        let plain = KDTree::new(&cloud);
+        let tree = KDTree::new_with_options(&cloud, &rotated(221));
+        let exact_dist2 = |query: &P2, index: u32| -> f32 {
+            let point = cloud[index as usize];
+            let (dx, dy) = (*point.get(0) - *query.get(0), *point.get(1) - *query.get(1));
+            dx * dx + dy * dy
+        };
+        let queries: Vec<P2> = (0..40)
+            .map(|i| {
+                P2::new2d(
+                    (i * 7 % 16) as f32,
+                    (i * 5 % 16) as f32 + (i % 2) as f32 * 0.5,
+                )
+            })
+            .collect();
+        for query in &queries {
+            for radius in [1.0, 2.0, 4.0, 5.0] {
+                for boundary in [Boundary::Closed, Boundary::Open] {
+                    let parameters = Parameters {
+                        max_radius: radius,
+                        boundary,
+                        ..Parameters::default()
+                    };
+                    let within = |tree: &KDTree<f32, P2>| -> Vec<u32> {
+                        let neighbours = tree.knn_advanced(
+                            256,
+                            query,
+                            CandidateContainer::Linear,
+                            &parameters,
+                            None,
+                        );
+                        let mut indices: Vec<u32> = neighbours.iter().map(|n| n.index).collect();
+                        indices.sort_unstable();
+                        indices
+                    };
+                    let (found, expected) = (within(&tree), within(&plain));
+                    // only the points at exactly the radius may be included or excluded differently
+                    for index in (0..256).filter(|i| found.contains(i) != expected.contains(i)) {
+                        assert_eq!(exact_dist2(query, index), radius * radius);
+                    }
+                }
+            }
+            for k in [3, 5, 9] {
+                let found = tree.knn(k, query);
+                let expected = plain.knn(k, query);
+                let dist2s = |neighbours: &[Neighbour<f32, P2>]| -> Vec<f32> {
+                    let mut dist2s: Vec<f32> = neighbours
+                        .iter()
+                        .map(|n| exact_dist2(query, n.index))
+                        .collect();
+                    dist2s.sort_unstable_by(f32::total_cmp);
+                    dist2s
+                };
+                // only the points tied at the k-th distance may be chosen differently
+                assert_eq!(dist2s(&found), dist2s(&expected));
+                let kth = dist2s(&expected)[k as usize - 1];
+                for neighbour in expected
+                    .iter()
+                    .filter(|n| exact_dist2(query, n.index) < kth)
+                {
+                    assert!(found.iter().any(|n| n.index == neighbour.index));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rotations_reduce_the_points_touched_around_axis_aligned_lines() {
+        // parallel lines of integer coordinates, far apart, queried off the lines
+        let cloud: Vec<P3d> = (0..30)
+            .flat_map(|line| {
+                let (y, z) = ((line % 6) as f64 * 50.0, (line / 6) as f64 * 50.0);
+                (0..300).map(move |x| P3d::from([x as f64, y, z]))
+            })
+            .collect();
+        let touched = |tree: &KDTree<f64, P3d>| -> u64 {
+            cloud
+                .iter()
+                .step_by(7)
+                .map(|point| {
+                    let query = *point + P3d::from([0.3, 0.2, 0.1]);
+                    let (_, stats) = tree.knn_with_stats(
+                        4,
+                        &query,
+                        CandidateContainer::BinaryHeap,
+                        &Parameters::default(),
+                    );
+                    stats.touched_points as u64
+                })
+                .sum()
+        };
+        let plain = KDTree::new(&cloud);
+        let tree = KDTree::new_with_options(&cloud, &rotated(221));
+        assert!(touched(&tree) * 4 < touched(&plain));
+        assert!(tree.stats().depth <= plain.stats().depth + 1);
+    }
+}
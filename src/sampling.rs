@@ -0,0 +1,185 @@
+//! Random sampling of the neighbours of a query, weighted by a kernel of their distance
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+use core::cmp::Reverse;
+
+use num_traits::Float;
+use ordered_float::NotNan;
+use rand::{Rng, RngCore};
+
+use crate::distance_scale::unscale_dist2;
+use crate::internal_parameters::InternalParameters;
+use crate::{CandidateCollector, KDTree, Parameters, Point, Scalar};
+
+/// Distance, in multiples of the kernel width, beyond which points are never sampled
+///
+/// At this distance, the weight `exp(-d²/σ²)` is `exp(-36)`, about `2e-16`.
+const KERNEL_CUTOFF: f64 = 6.0;
+
+/// Keeps the `m` candidates of largest key `ln(u) / w`, `u` uniform in `(0, 1]` and `w` the weight,
+/// which are a weighted sample without replacement (Efraimidis and Spirakis)
+struct ReservoirCollector<'a, T: Scalar, R: RngCore> {
+    m: usize,
+    /// `1 / σ²`
+    inverse_sigma2: f64,
+    scale: NotNan<T>,
+    rng: &'a mut R,
+    /// the reservoir, smallest key first
+    reservoir: BinaryHeap<Reverse<(NotNan<f64>, u32)>>,
+}
+
+impl<'a, T: Scalar, R: RngCore> CandidateCollector<T> for ReservoirCollector<'a, T, R> {
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        let dist2 = unscale_dist2(dist2, self.scale)
+            .to_f64()
+            .expect("scalars convert to f64");
+        let uniform = 1.0 - self.rng.gen::<f64>();
+        // ln(u) / exp(-d²/σ²), computed as a product not to divide by a weight underflowing to zero
+        let key = uniform.ln() * (dist2 * self.inverse_sigma2).exp();
+        let key = NotNan::new(key).expect("sampling keys are not NaN");
+        if self.reservoir.len() < self.m {
+            self.reservoir.push(Reverse((key, index)));
+        } else if let Some(mut smallest) = self.reservoir.peek_mut() {
+            if key > smallest.0 .0 {
+                *smallest = Reverse((key, index));
+            }
+        }
+    }
+
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        None
+    }
+}
+
+impl<T: Scalar + Float, P: Point<T>> KDTree<T, P> {
+    /// Samples `m` distinct neighbours of `query`, with a probability proportional to the kernel
+    /// `exp(-d²/σ²)` of their distance `d`, returning their indices in the original point cloud.
+    ///
+    /// The indices are in the order in which drawing one point at a time without replacement
+    /// would draw them, so the first is sampled among all neighbours with the kernel weights.
+    /// Points further than `6 σ`, whose weight is below `exp(-36)`, about `2e-16`, are never sampled,
+    /// so only the points within that radius are visited, and fewer than `m` indices are returned
+    /// if there are fewer points within it.
+    /// Each visited point draws one random number from `rng`, by reservoir sampling.
+    ///
+    /// Panics if `sigma` is not strictly positive and finite, or if `query` has NaN coordinates.
+    pub fn sample_neighbours(
+        &self,
+        query: &P,
+        m: usize,
+        sigma: T,
+        rng: &mut impl RngCore,
+    ) -> Vec<u32> {
+        assert!(
+            sigma > T::zero() && Float::is_finite(sigma),
+            "sigma must be strictly positive and finite"
+        );
+        if m == 0 {
+            return Vec::new();
+        }
+        let sigma_f64 = sigma.to_f64().expect("scalars convert to f64");
+        let cutoff = T::from(KERNEL_CUTOFF).expect("the cutoff is representable");
+        let parameters = Parameters {
+            max_radius: sigma * cutoff,
+            ..Parameters::default()
+        };
+        let mut collector = ReservoirCollector {
+            m,
+            inverse_sigma2: 1.0 / (sigma_f64 * sigma_f64),
+            scale: self.scale,
+            rng,
+            reservoir: BinaryHeap::with_capacity(m),
+        };
+        self.knn_internal(
+            &mut collector,
+            &self.query_coordinates(query),
+            &InternalParameters::new(&parameters, self.scale),
+        );
+        // the largest key is drawn first
+        collector
+            .reservoir
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((_, index))| self.externalise_index(index))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn sampling_frequencies_follow_the_kernel() {
+        let cloud: Vec<P2d> = [
+            [0.0, 0.0],
+            [0.5, 0.0],
+            [0.0, 1.0],
+            [1.0, 1.0],
+            [-1.5, 0.5],
+            [2.0, 0.0],
+            // beyond the cutoff
+            [20.0, 0.0],
+        ]
+        .into_iter()
+        .map(P2d::from)
+        .collect();
+        let tree = KDTree::new(&cloud);
+        let query = P2d::from([0.2, 0.1]);
+        let sigma = 1.0;
+        let weights: Vec<f64> = cloud
+            .iter()
+            .map(|p| {
+                let d2 = (p.get(0) - query.get(0)).powi(2) + (p.get(1) - query.get(1)).powi(2);
+                (-d2 / (sigma * sigma)).exp()
+            })
+            .collect();
+        let kept = 6;
+        let total: f64 = weights[..kept].iter().sum();
+        let mut rng = StdRng::seed_from_u64(3);
+        let draws = 20000;
+        let mut counts = vec![0u32; cloud.len()];
+        for _ in 0..draws {
+            let sample = tree.sample_neighbours(&query, 1, sigma, &mut rng);
+            assert_eq!(sample.len(), 1);
+            counts[sample[0] as usize] += 1;
+        }
+        assert_eq!(counts[kept], 0);
+        let chi2: f64 = (0..kept)
+            .map(|i| {
+                let expected = draws as f64 * weights[i] / total;
+                (counts[i] as f64 - expected).powi(2) / expected
+            })
+            .sum();
+        // the 0.1 % quantile of the chi-squared distribution of 5 degrees of freedom
+        assert!(chi2 < 20.5, "chi2 = {}", chi2);
+        // samples are distinct, and all points within the cutoff are returned when m is large
+        let mut all = tree.sample_neighbours(&query, 10, sigma, &mut rng);
+        all.sort_unstable();
+        assert_eq!(all, vec![0, 1, 2, 3, 4, 5]);
+        assert!(tree
+            .sample_neighbours(&query, 0, sigma, &mut rng)
+            .is_empty());
+    }
+
+    #[test]
+    fn large_clouds_sample_near_the_query() {
+        let cloud: Vec<P2> = random_point_cloud(5000);
+        let tree = KDTree::new(&cloud);
+        let mut rng = StdRng::seed_from_u64(5);
+        let query = P2::new2d(0.0, 0.0);
+        let sample = tree.sample_neighbours(&query, 20, 3.0, &mut rng);
+        assert_eq!(sample.len(), 20);
+        for index in &sample {
+            let p = cloud[*index as usize];
+            assert!((p.get(0) * p.get(0) + p.get(1) * p.get(1)).into_inner() <= 18.0 * 18.0);
+        }
+        let mut distinct = sample.clone();
+        distinct.sort_unstable();
+        distinct.dedup();
+        assert_eq!(distinct.len(), 20);
+    }
+}
@@ -0,0 +1,409 @@
+//! The main searches, instantiated for `f32`, `f64` and a scalar type defined outside the crate
+//!
+//! The suite only requires [`Scalar`], and expresses its values through [`NumCast`],
+//! so that the logic depending on the precision, such as the exclusion of self matches
+//! or the scaling of extreme magnitudes, runs in every width.
+
+extern crate std;
+
+use core::fmt::Debug;
+use core::marker::PhantomData;
+use core::num::FpCategory;
+use core::ops::{Add, AddAssign, Div, Mul, Neg, Rem, Sub};
+
+use num_traits::float::FloatCore;
+use num_traits::{Num, NumCast, One, ToPrimitive, Zero};
+use ordered_float::NotNan;
+
+use crate::internal_parameters::InternalParameters;
+use crate::rng::SplitMix64;
+use crate::simple_point::SimplePoint;
+use crate::{
+    CandidateContainer, KDTree, Neighbour, Parameters, Point, Radius, Scalar, SelfMatch,
+    TraversalOrder,
+};
+
+/// A marker of the unit of a [`Quantity`]
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+struct Meter;
+
+/// A length carrying its unit in its type, as a crate using `nabo` might define it
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+struct Quantity<U>(f32, PhantomData<U>);
+
+impl<U> Quantity<U> {
+    fn new(value: f32) -> Self {
+        Quantity(value, PhantomData)
+    }
+}
+
+macro_rules! quantity_operator {
+    ($trait:ident, $method:ident, $op:tt) => {
+        impl<U> $trait for Quantity<U> {
+            type Output = Self;
+            fn $method(self, rhs: Self) -> Self {
+                Quantity::new(self.0 $op rhs.0)
+            }
+        }
+    };
+}
+
+quantity_operator!(Add, add, +);
+quantity_operator!(Sub, sub, -);
+quantity_operator!(Mul, mul, *);
+quantity_operator!(Div, div, /);
+quantity_operator!(Rem, rem, %);
+
+impl<U> Neg for Quantity<U> {
+    type Output = Self;
+    fn neg(self) -> Self {
+        Quantity::new(-self.0)
+    }
+}
+
+impl<U> AddAssign for Quantity<U> {
+    fn add_assign(&mut self, rhs: Self) {
+        self.0 += rhs.0;
+    }
+}
+
+impl<U> Zero for Quantity<U> {
+    fn zero() -> Self {
+        Quantity::new(0.0)
+    }
+    fn is_zero(&self) -> bool {
+        self.0 == 0.0
+    }
+}
+
+impl<U> One for Quantity<U> {
+    fn one() -> Self {
+        Quantity::new(1.0)
+    }
+}
+
+impl<U: PartialEq> Num for Quantity<U> {
+    type FromStrRadixErr = <f32 as Num>::FromStrRadixErr;
+    fn from_str_radix(text: &str, radix: u32) -> Result<Self, Self::FromStrRadixErr> {
+        f32::from_str_radix(text, radix).map(Quantity::new)
+    }
+}
+
+impl<U> ToPrimitive for Quantity<U> {
+    fn to_i64(&self) -> Option<i64> {
+        self.0.to_i64()
+    }
+    fn to_u64(&self) -> Option<u64> {
+        self.0.to_u64()
+    }
+    fn to_f64(&self) -> Option<f64> {
+        self.0.to_f64()
+    }
+}
+
+impl<U> NumCast for Quantity<U> {
+    fn from<N: ToPrimitive>(n: N) -> Option<Self> {
+        n.to_f32().map(Quantity::new)
+    }
+}
+
+impl<U: Copy + PartialOrd> FloatCore for Quantity<U> {
+    fn infinity() -> Self {
+        Quantity::new(f32::INFINITY)
+    }
+    fn neg_infinity() -> Self {
+        Quantity::new(f32::NEG_INFINITY)
+    }
+    fn nan() -> Self {
+        Quantity::new(f32::NAN)
+    }
+    fn neg_zero() -> Self {
+        Quantity::new(-0.0)
+    }
+    fn min_value() -> Self {
+        Quantity::new(f32::MIN)
+    }
+    fn min_positive_value() -> Self {
+        Quantity::new(f32::MIN_POSITIVE)
+    }
+    fn epsilon() -> Self {
+        Quantity::new(f32::EPSILON)
+    }
+    fn max_value() -> Self {
+        Quantity::new(f32::MAX)
+    }
+    fn classify(self) -> FpCategory {
+        self.0.classify()
+    }
+    fn to_degrees(self) -> Self {
+        Quantity::new(self.0.to_degrees())
+    }
+    fn to_radians(self) -> Self {
+        Quantity::new(self.0.to_radians())
+    }
+    fn integer_decode(self) -> (u64, i16, i8) {
+        FloatCore::integer_decode(self.0)
+    }
+}
+
+/// Converts `value` to `T`, which represents it, possibly rounded
+fn scalar<T: Scalar>(value: f64) -> T {
+    T::from(value).expect("the value is representable")
+}
+
+/// Converts `value` to `f64`
+fn wide<T: Scalar>(value: T) -> f64 {
+    value.to_f64().expect("scalars convert to f64")
+}
+
+fn random_cloud<T: Scalar, const D: usize>(
+    rng: &mut SplitMix64,
+    count: usize,
+    magnitude: T,
+) -> Vec<SimplePoint<D, T>> {
+    (0..count)
+        .map(|_| {
+            // integer coordinates lead to ties, exercising their order
+            let coordinates = [(); D].map(|_| scalar::<T>(rng.below(201) as f64 - 100.0));
+            SimplePoint::from(coordinates.map(|value| value * magnitude))
+        })
+        .collect()
+}
+
+/// Returns the squared distances from `query` to its `k` nearest neighbours within the radius
+/// and beyond the self-match threshold of `parameters`, both applying to the internal distances
+/// of `tree`, which are scaled
+fn brute_force_dist2<T: Scalar, P: Point<T>>(
+    tree: &KDTree<T, P>,
+    query: &P,
+    k: usize,
+    parameters: &Parameters<T>,
+) -> Vec<T> {
+    let internal_parameters = InternalParameters::new(parameters, tree.scale);
+    let metric = tree.metric();
+    let query = tree.query_coordinates(query);
+    let dim = P::DIM as usize;
+    let mut dist2: Vec<NotNan<T>> = tree
+        .points
+        .chunks(dim)
+        .map(|point| metric.internal_dist2::<P>(point, &query))
+        .filter(|dist2| *dist2 <= internal_parameters.max_radius2)
+        .filter(|dist2| parameters.self_match.allows_coincident() || **dist2 > T::epsilon())
+        .collect();
+    dist2.sort_unstable();
+    dist2.truncate(k);
+    dist2
+        .into_iter()
+        .map(|dist2| metric.external_dist2(dist2).into_inner())
+        .collect()
+}
+
+fn dist2s<T: Scalar, P: Point<T>>(neighbours: &[Neighbour<T, P>]) -> Vec<T> {
+    neighbours
+        .iter()
+        .map(|neighbour| neighbour.dist2.into_inner())
+        .collect()
+}
+
+fn check_exact_searches<T: Scalar + Debug, const D: usize>(magnitude: T)
+where
+    SimplePoint<D, T>: Point<T>,
+{
+    let mut rng = SplitMix64::new(D as u64);
+    let cloud = random_cloud::<T, D>(&mut rng, 800, magnitude);
+    let tree = KDTree::new(&cloud);
+    let queries = random_cloud::<T, D>(&mut rng, 30, magnitude);
+    let radius = magnitude * scalar(30.0);
+    for query in queries.iter().chain(&cloud[..10]) {
+        for traversal_order in [
+            TraversalOrder::NearChildFirst,
+            TraversalOrder::BoundOrdered,
+            TraversalOrder::BestFirst,
+        ] {
+            for (max_radius, allow_self_match) in [(T::infinity(), true), (radius, false)] {
+                let parameters = Parameters {
+                    max_radius,
+                    self_match: allow_self_match.into(),
+                    traversal_order,
+                    ..Parameters::default()
+                };
+                for container in [CandidateContainer::Linear, CandidateContainer::BinaryHeap] {
+                    for k in [1, 7, 40] {
+                        let found = tree.knn_advanced(k, query, container, &parameters, None);
+                        assert_eq!(
+                            dist2s(&found),
+                            brute_force_dist2(&tree, query, k as usize, &parameters)
+                        );
+                        for neighbour in &found {
+                            assert_eq!(neighbour.point, cloud[neighbour.index as usize]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn exact_searches_match_brute_force<T: Scalar + Debug>() {
+    check_exact_searches::<T, 2>(T::one());
+    check_exact_searches::<T, 3>(T::one());
+}
+
+/// Squaring magnitudes near the limits of `T` overflows or underflows without the distance scale,
+/// the largest one being the largest whose squared distances stay finite
+fn extreme_magnitudes_match_brute_force<T: Scalar + Debug>() {
+    let (max, min) = (wide(T::max_value()), wide(T::min_positive_value()));
+    for magnitude in [
+        max.sqrt() / 400.0,
+        max.sqrt() / 1e3,
+        min * 1e6,
+        min.sqrt() / 4.0,
+    ] {
+        check_exact_searches::<T, 3>(scalar(magnitude));
+    }
+}
+
+/// Points within `T::epsilon()` in squared distance of the query are excluded as self matches,
+/// so the threshold shrinks with the precision
+fn self_matches_follow_the_precision<T: Scalar + Debug>() {
+    let offset: T = scalar(1e-5);
+    let cloud = [
+        SimplePoint::from([T::zero(), T::zero()]),
+        SimplePoint::from([offset, T::zero()]),
+        SimplePoint::from([T::one(), T::zero()]),
+    ];
+    let tree = KDTree::new(&cloud);
+    let parameters = Parameters {
+        self_match: SelfMatch::ExcludeCoincident,
+        ..Parameters::default()
+    };
+    let found = tree.knn_advanced(3, &cloud[0], CandidateContainer::Linear, &parameters, None);
+    let offset_excluded = offset * offset <= T::epsilon();
+    assert_eq!(
+        found.iter().any(|neighbour| neighbour.index == 1),
+        !offset_excluded
+    );
+    assert_eq!(found.len(), if offset_excluded { 1 } else { 2 });
+    assert_eq!(found.last().map(|neighbour| neighbour.index), Some(2));
+}
+
+fn radius_limits_are_inclusive<T: Scalar + Debug>() {
+    let cloud: Vec<SimplePoint<2, T>> = (0..5)
+        .map(|i| SimplePoint::from([scalar(i as f64), T::zero()]))
+        .collect();
+    let tree = KDTree::new(&cloud);
+    let query = &cloud[0];
+    let two: T = scalar(2.0);
+    let within: Vec<u32> = tree
+        .radius_search_ordered(query, Radius::new(two))
+        .iter()
+        .map(|neighbour| neighbour.index)
+        .collect();
+    assert_eq!(within, [0, 1, 2]);
+    let parameters = Parameters {
+        max_radius: two,
+        ..Parameters::default()
+    };
+    let found = tree.knn_advanced(5, query, CandidateContainer::Linear, &parameters, None);
+    assert_eq!(dist2s(&found), [0.0, 1.0, 4.0].map(scalar::<T>));
+    // an infinite radius does not limit the searches
+    let unbounded = tree.radius_search_ordered(query, Radius::infinite());
+    assert_eq!(unbounded.len(), 5);
+    assert_eq!(
+        tree.knn(5, query).last().map(|neighbour| neighbour.dist2),
+        Some(NotNan::new(scalar(16.0)).expect("16 is not NaN"))
+    );
+}
+
+#[cfg(not(feature = "strict-exact"))]
+fn approximate_searches_are_within_epsilon<T: Scalar + Debug>() {
+    let mut rng = SplitMix64::new(7);
+    let cloud = random_cloud::<T, 3>(&mut rng, 3000, T::one());
+    let tree = KDTree::new(&cloud);
+    let epsilon: T = scalar(0.5);
+    let factor2 = (T::one() + epsilon) * (T::one() + epsilon);
+    let parameters = Parameters {
+        epsilon,
+        ..Parameters::default()
+    };
+    for query in random_cloud::<T, 3>(&mut rng, 50, T::one()) {
+        let exact = dist2s(&tree.knn(8, &query));
+        let approximate =
+            tree.knn_advanced(8, &query, CandidateContainer::Linear, &parameters, None);
+        assert_eq!(approximate.len(), 8);
+        for (found, exact) in dist2s(&approximate).iter().zip(&exact) {
+            assert!(*found <= *exact * factor2);
+        }
+    }
+}
+
+fn flat_exports_round_trip<T: Scalar + Debug>() {
+    let mut rng = SplitMix64::new(3);
+    let cloud = random_cloud::<T, 3>(&mut rng, 500, scalar(1e-3));
+    let tree = KDTree::new(&cloud);
+    let imported = tree
+        .export_flat()
+        .import::<SimplePoint<3, T>>()
+        .expect("an exported tree imports");
+    assert!(imported == tree);
+    for query in &cloud[..20] {
+        assert_eq!(imported.knn(5, query), tree.knn(5, query));
+    }
+}
+
+macro_rules! scalar_suite {
+    ($name:ident, $scalar:ty) => {
+        mod $name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            #[test]
+            fn exact_searches_match_brute_force() {
+                super::exact_searches_match_brute_force::<$scalar>();
+            }
+
+            #[test]
+            fn extreme_magnitudes_match_brute_force() {
+                super::extreme_magnitudes_match_brute_force::<$scalar>();
+            }
+
+            #[test]
+            fn self_matches_follow_the_precision() {
+                super::self_matches_follow_the_precision::<$scalar>();
+            }
+
+            #[test]
+            fn radius_limits_are_inclusive() {
+                super::radius_limits_are_inclusive::<$scalar>();
+            }
+
+            #[test]
+            #[cfg(not(feature = "strict-exact"))]
+            fn approximate_searches_are_within_epsilon() {
+                super::approximate_searches_are_within_epsilon::<$scalar>();
+            }
+
+            #[test]
+            fn flat_exports_round_trip() {
+                super::flat_exports_round_trip::<$scalar>();
+            }
+        }
+    };
+}
+
+scalar_suite!(single, f32);
+scalar_suite!(double, f64);
+scalar_suite!(units, Quantity<Meter>);
+
+#[test]
+fn scalar_types_outside_the_crate_build_trees() {
+    // the unit stays in the types of the tree and the results
+    let cloud: Vec<SimplePoint<2, Quantity<Meter>>> = [(0.0, 0.0), (3.0, 4.0)]
+        .iter()
+        .map(|(x, y)| SimplePoint::from([Quantity::new(*x), Quantity::new(*y)]))
+        .collect();
+    let tree: KDTree<Quantity<Meter>, _> = KDTree::new(&cloud);
+    let found: Vec<Neighbour<Quantity<Meter>, _>> = tree.knn(2, &cloud[0]);
+    assert_eq!(found[1].dist2.into_inner(), Quantity::<Meter>::new(25.0));
+}
@@ -0,0 +1,248 @@
+//! End-to-end check of the query path on stored points, to detect corrupted trees
+
+use core::fmt;
+
+use crate::rng::SplitMix64;
+use crate::{KDTree, Parameters, Point, Scalar, SelfMatch};
+
+/// Number of points checked by debug builds after importing or merging trees
+pub(crate) const SELF_CHECK_SAMPLE: usize = 16;
+
+/// An inconsistency found by [`KDTree::self_check`], with indices in the original point cloud
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SelfCheckFailure<T: Scalar> {
+    /// The search from a point found no other point, although the tree has several
+    MissingNeighbour {
+        /// the point searched from
+        index: u32,
+    },
+    /// The squared distance of the nearest neighbour differs from the one computed from the coordinates
+    DistanceMismatch {
+        /// the point searched from
+        index: u32,
+        /// its nearest neighbour
+        neighbour: u32,
+        /// the squared distance returned by the search
+        dist2: T,
+        /// the squared distance computed from the stored coordinates
+        recomputed_dist2: T,
+    },
+    /// The nearest neighbour of the nearest neighbour of a point is further than the point
+    Asymmetric {
+        /// the point searched from
+        index: u32,
+        /// its nearest neighbour
+        neighbour: u32,
+        /// the squared distance between them
+        dist2: T,
+        /// the squared distance from the neighbour to its own nearest neighbour
+        neighbour_dist2: T,
+    },
+    /// A stored point has an infinite coordinate, see [`KDTree::find_non_finite`]
+    NonFiniteCoordinate {
+        /// the point
+        index: u32,
+    },
+}
+
+impl<T: Scalar> fmt::Display for SelfCheckFailure<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use SelfCheckFailure::*;
+        match self {
+            MissingNeighbour { index } => {
+                write!(f, "The search from point {} found no neighbour", index)
+            }
+            DistanceMismatch {
+                index,
+                neighbour,
+                dist2,
+                recomputed_dist2,
+            } => write!(
+                f,
+                "The search from point {} found point {} at squared distance {:?} instead of {:?}",
+                index, neighbour, dist2, recomputed_dist2
+            ),
+            Asymmetric {
+                index,
+                neighbour,
+                dist2,
+                neighbour_dist2,
+            } => write!(
+                f,
+                "Point {} is the nearest neighbour of point {} at squared distance {:?}, \
+                 but its own nearest neighbour is at squared distance {:?}",
+                neighbour, index, dist2, neighbour_dist2
+            ),
+            NonFiniteCoordinate { index } => {
+                write!(f, "Point {} has an infinite coordinate", index)
+            }
+        }
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Checks the nearest neighbours of `sample` stored points, chosen at random from `seed`,
+    /// or of all points if the tree has no more than `sample`.
+    ///
+    /// For each point, the nearest other point is searched by [`Self::knn_of`], and its squared distance
+    /// must be the one computed from the stored coordinates, bitwise, and the nearest neighbour of that point
+    /// must not be further.
+    /// Contrary to the checks of [`FlatTree::import`](crate::FlatTree::import), which only cover the structure,
+    /// this runs queries end to end, and detects splits or bounds that do not match the points.
+    /// Before that, all stored coordinates must be finite, as searches from or to infinite coordinates
+    /// would compute NaN distances.
+    /// This runs in debug builds after importing and merging trees.
+    pub fn self_check(&self, sample: usize, seed: u64) -> Result<(), SelfCheckFailure<T>> {
+        if let Some(index) = self.find_non_finite().first() {
+            return Err(SelfCheckFailure::NonFiniteCoordinate { index: *index });
+        }
+        let len = self.len();
+        if len < 2 {
+            return Ok(());
+        }
+        let parameters = Parameters {
+            self_match: SelfMatch::ExcludeCoincident,
+            ..Parameters::default()
+        };
+        let nearest = |index: u32| {
+            self.knn_of(1, index, &parameters)
+                .expect("sampled indices are in range")
+                .first()
+                .map(|neighbour| (neighbour.index, neighbour.dist2.into_inner()))
+                .ok_or(SelfCheckFailure::MissingNeighbour { index })
+        };
+        let mut rng = SplitMix64::new(seed);
+        for draw in 0..sample.min(len) {
+            let index = if sample >= len {
+                draw as u32
+            } else {
+                rng.below(len as u64) as u32
+            };
+            let (neighbour, dist2) = nearest(index)?;
+            let recomputed_dist2 = self.stored_dist2(index, neighbour);
+            if dist2.integer_decode() != recomputed_dist2.integer_decode() {
+                return Err(SelfCheckFailure::DistanceMismatch {
+                    index,
+                    neighbour,
+                    dist2,
+                    recomputed_dist2,
+                });
+            }
+            let (_, neighbour_dist2) = nearest(neighbour)?;
+            if neighbour_dist2 > dist2 {
+                return Err(SelfCheckFailure::Asymmetric {
+                    index,
+                    neighbour,
+                    dist2,
+                    neighbour_dist2,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the indices in the original point cloud of the stored points with an infinite coordinate,
+    /// in increasing order.
+    ///
+    /// The constructors, the mutations and [`FlatTree::import`](crate::FlatTree::import) reject such points,
+    /// so this is empty unless the storage was corrupted, for example by unsafe code.
+    /// It runs in time linear in the number of points.
+    pub fn find_non_finite(&self) -> Vec<u32> {
+        let dim = P::DIM as usize;
+        let mut found: Vec<u32> = self
+            .points
+            .chunks(dim)
+            .zip(&self.indices)
+            .filter(|(point, _)| point.iter().any(|value| !value.is_finite()))
+            .map(|(_, index)| *index)
+            .collect();
+        found.sort_unstable();
+        found
+    }
+
+    /// Returns the squared distance between the stored points of external indices `a` and `b`
+    fn stored_dist2(&self, a: u32, b: u32) -> T {
+        let dim = P::DIM as usize;
+        let (a, b) = (
+            self.internalise_index(a) as usize * dim,
+            self.internalise_index(b) as usize * dim,
+        );
+        self.metric()
+            .dist2::<P>(&self.points[a..a + dim], &self.points[b..b + dim])
+            .into_inner()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ordered_float::NotNan;
+
+    use super::SelfCheckFailure;
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn healthy_trees_pass_the_self_check() {
+        for dim_cloud in [random_point_cloud::<2>(3000), random_point_cloud::<2>(5)] {
+            let tree = KDTree::new(&dim_cloud);
+            assert_eq!(tree.self_check(100, 198), Ok(()));
+        }
+        let cloud: Vec<P4> = random_point_cloud(2000);
+        let mut tree = KDTree::new(&cloud);
+        for index in 0..200 {
+            tree.update_point(index, random_point());
+        }
+        assert_eq!(tree.self_check(500, 1), Ok(()));
+        // duplicates are each other's nearest neighbours at distance zero
+        let point = P2::new2d(1.0, 2.0);
+        assert_eq!(KDTree::new(&[point, point]).self_check(2, 0), Ok(()));
+        assert_eq!(KDTree::new(&[point]).self_check(10, 0), Ok(()));
+    }
+
+    #[test]
+    fn corrupted_trees_fail_the_self_check() {
+        let cloud: Vec<P2> = random_point_cloud(2000);
+        let tree = KDTree::new(&cloud);
+        // move the point farthest from the first one next to it, behind the back of the tree,
+        // so that the searches from the first point prune the leaf still holding it
+        let farthest = tree
+            .knn(cloud.len() as u32, &cloud[0])
+            .last()
+            .unwrap()
+            .index;
+        let mut corrupted = tree.clone();
+        let start = corrupted.internalise_index(farthest) as usize * 2;
+        corrupted.points[start] = cloud[0].get(0) + NotNan::new(1e-3).unwrap();
+        corrupted.points[start + 1] = cloud[0].get(1);
+        let failure = corrupted
+            .self_check(cloud.len(), 0)
+            .expect_err("the corruption is detected");
+        match failure {
+            SelfCheckFailure::Asymmetric {
+                index, neighbour, ..
+            } => assert_eq!((index, neighbour), (farthest, 0)),
+            _ => panic!("{}", failure),
+        }
+        assert!(failure.to_string().contains(&format!("point {}", farthest)));
+        // an imported copy of the intact tree is consistent
+        let imported = tree.export_flat().import::<P2>().unwrap();
+        assert_eq!(imported.self_check(200, 3), Ok(()));
+    }
+
+    #[test]
+    fn non_finite_stored_points_are_found() {
+        let cloud: Vec<P2> = random_point_cloud(500);
+        let mut tree = KDTree::new(&cloud);
+        assert!(tree.find_non_finite().is_empty());
+        for index in [42, 7] {
+            let start = tree.internalise_index(index) as usize * 2;
+            tree.points[start + 1] = NotNan::new(f32::INFINITY).unwrap();
+        }
+        assert_eq!(tree.find_non_finite(), [7, 42]);
+        let failure = tree
+            .self_check(100, 0)
+            .expect_err("the corruption is detected");
+        assert_eq!(failure, SelfCheckFailure::NonFiniteCoordinate { index: 7 });
+        assert_eq!(failure.to_string(), "Point 7 has an infinite coordinate");
+    }
+}
@@ -2,92 +2,159 @@
 
 use core::{
     fmt::Display,
-    ops::{Add, Sub},
+    ops::{Add, Mul, Sub},
 };
 use ordered_float::NotNan;
-#[cfg(any(feature = "rand", test))]
+#[cfg(any(all(feature = "rand", feature = "f32"), test))]
 use rand::Rng;
 
-use num_traits::Bounded;
+use num_traits::{Bounded, Zero};
 
-use crate::Point;
+use crate::{Point, Scalar};
 
-/// A simple `f32` `D`-dimensional point type
+/// A simple `D`-dimensional point type, with `f32` coordinates by default
 #[derive(Clone, Copy, PartialEq, Debug)]
-pub struct SimplePoint<const D: usize>(pub [NotNan<f32>; D]);
+pub struct SimplePoint<const D: usize, T = f32>(pub [NotNan<T>; D]);
+#[cfg(any(feature = "f32", test))]
 impl SimplePoint<2> {
     /// Creates a new point from (x,y).
     pub fn new2d(x: f32, y: f32) -> SimplePoint<2> {
-        SimplePoint([NotNan::new(x).unwrap(), NotNan::new(y).unwrap()])
+        SimplePoint([
+            NotNan::new(x).expect("x must not be NaN"),
+            NotNan::new(y).expect("y must not be NaN"),
+        ])
     }
     /// Creates a new point from (x,y,z).
     pub fn new3d(x: f32, y: f32, z: f32) -> SimplePoint<3> {
         SimplePoint([
-            NotNan::new(x).unwrap(),
-            NotNan::new(y).unwrap(),
-            NotNan::new(z).unwrap(),
+            NotNan::new(x).expect("x must not be NaN"),
+            NotNan::new(y).expect("y must not be NaN"),
+            NotNan::new(z).expect("z must not be NaN"),
         ])
     }
 }
-impl<const D: usize> Default for SimplePoint<D> {
-    fn default() -> SimplePoint<D> {
-        SimplePoint([NotNan::new(0.0).unwrap(); D])
+impl<const D: usize, T: Scalar> From<[T; D]> for SimplePoint<D, T> {
+    /// Panics if a coordinate is NaN.
+    fn from(coordinates: [T; D]) -> Self {
+        SimplePoint(
+            coordinates.map(|value| NotNan::new(value).expect("coordinates must not be NaN")),
+        )
     }
 }
-impl<const D: usize> Bounded for SimplePoint<D> {
-    fn min_value() -> SimplePoint<D> {
-        SimplePoint([NotNan::<f32>::min_value(); D])
+impl<const D: usize, T: Scalar> From<SimplePoint<D, T>> for [T; D] {
+    fn from(point: SimplePoint<D, T>) -> Self {
+        point.0.map(NotNan::into_inner)
     }
-    fn max_value() -> SimplePoint<D> {
-        SimplePoint([NotNan::<f32>::max_value(); D])
+}
+impl<const D: usize, T: Scalar> Default for SimplePoint<D, T> {
+    fn default() -> SimplePoint<D, T> {
+        SimplePoint([NotNan::zero(); D])
     }
 }
-impl<const D: usize> Point<f32> for SimplePoint<D> {
+impl<const D: usize, T: Scalar> Bounded for SimplePoint<D, T> {
+    fn min_value() -> SimplePoint<D, T> {
+        SimplePoint([NotNan::<T>::min_value(); D])
+    }
+    fn max_value() -> SimplePoint<D, T> {
+        SimplePoint([NotNan::<T>::max_value(); D])
+    }
+}
+impl<const D: usize, T: Scalar> Point<T> for SimplePoint<D, T> {
     const DIM: u32 = D as u32;
-    fn set(&mut self, index: u32, value: NotNan<f32>) {
+    fn set(&mut self, index: u32, value: NotNan<T>) {
         self.0[index as usize] = value;
     }
-    fn get(&self, index: u32) -> NotNan<f32> {
+    fn get(&self, index: u32) -> NotNan<T> {
         self.0[index as usize]
     }
 }
-impl<const D: usize> Add for SimplePoint<D> {
-    type Output = SimplePoint<D>;
+impl<const D: usize, T: Scalar> Add for SimplePoint<D, T> {
+    type Output = SimplePoint<D, T>;
 
-    fn add(self, rhs: SimplePoint<D>) -> Self::Output {
+    fn add(self, rhs: SimplePoint<D, T>) -> Self::Output {
         SimplePoint(core::array::from_fn(|i| self.0[i] + rhs.0[i]))
     }
 }
-impl<const D: usize> Sub for SimplePoint<D> {
-    type Output = SimplePoint<D>;
+impl<const D: usize, T: Scalar> Sub for SimplePoint<D, T> {
+    type Output = SimplePoint<D, T>;
 
-    fn sub(self, rhs: SimplePoint<D>) -> Self::Output {
+    fn sub(self, rhs: SimplePoint<D, T>) -> Self::Output {
         SimplePoint(core::array::from_fn(|i| self.0[i] - rhs.0[i]))
     }
 }
-impl<const D: usize> Display for SimplePoint<D> {
+impl<const D: usize, T: Scalar> Mul<T> for SimplePoint<D, T> {
+    type Output = SimplePoint<D, T>;
+
+    /// Panics if a product is NaN, as for infinity times zero.
+    fn mul(self, rhs: T) -> Self::Output {
+        SimplePoint(core::array::from_fn(|i| self.0[i] * rhs))
+    }
+}
+impl<const D: usize, T: Scalar + Display> Display for SimplePoint<D, T> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "[{}, {}]", self.0[0], self.0[1])
+        write!(f, "[")?;
+        for (i, value) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", value)?;
+        }
+        write!(f, "]")
     }
 }
 
 /// A simple 2-D point type
+#[cfg(any(feature = "f32", test))]
 pub type P2 = SimplePoint<2>;
 
 /// A simple 3-D point type
+#[cfg(any(feature = "f32", test))]
 pub type P3 = SimplePoint<3>;
 
+/// A simple 4-D point type
+#[cfg(any(feature = "f32", test))]
+pub type P4 = SimplePoint<4>;
+
+/// A simple 2-D point type with `f64` coordinates
+#[cfg(any(feature = "f64", test))]
+pub type P2d = SimplePoint<2, f64>;
+
+/// A simple 3-D point type with `f64` coordinates
+#[cfg(any(feature = "f64", test))]
+pub type P3d = SimplePoint<3, f64>;
+
+/// A simple 4-D point type with `f64` coordinates
+#[cfg(any(feature = "f64", test))]
+pub type P4d = SimplePoint<4, f64>;
+
 /// Creates a random point whose coordinate are in the interval [-100:100].
-#[cfg(any(feature = "rand", test))]
+#[cfg(any(all(feature = "rand", feature = "f32"), test))]
 pub fn random_point<const D: usize>() -> SimplePoint<D> {
     let mut rng = rand::thread_rng();
     SimplePoint(core::array::from_fn(|_| {
-        NotNan::new(rng.gen_range(-100.0..100.0)).unwrap()
+        NotNan::new(rng.gen_range(-100.0..100.0)).expect("random coordinates are not NaN")
     }))
 }
 
 /// Creates a random cloud of count points using [random_point()] for each.
-#[cfg(any(feature = "rand", test))]
+#[cfg(any(all(feature = "rand", feature = "f32"), test))]
 pub fn random_point_cloud<const D: usize>(count: u32) -> alloc::vec::Vec<SimplePoint<D>> {
     (0..count).map(|_| random_point()).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simple_point_conversions_and_ops() {
+        let a = P3d::from([1.0, 2.0, 3.0]);
+        let b = P3d::from([0.5, 0.5, 0.5]);
+        assert_eq!(<[f64; 3]>::from(a + b), [1.5, 2.5, 3.5]);
+        assert_eq!(<[f64; 3]>::from(a - b), [0.5, 1.5, 2.5]);
+        assert_eq!(<[f64; 3]>::from(a * 2.0), [2.0, 4.0, 6.0]);
+        assert_eq!(format!("{}", a), "[1, 2, 3]");
+        assert_eq!(format!("{}", P2::new2d(0.5, -1.0)), "[0.5, -1]");
+        assert_eq!(P4::from([1.0; 4]).get(3), 1.0);
+    }
+}
@@ -0,0 +1,449 @@
+//! Index of time-stamped points, keeping only the most recent ones
+
+use alloc::{
+    collections::{BinaryHeap, VecDeque},
+    vec::Vec,
+};
+
+use num_traits::Zero;
+use ordered_float::NotNan;
+
+use crate::dim_buffer::DimBuffer;
+use crate::distance_scale::{distance_scale, unscale_dist2};
+use crate::dynamic_tree::ComponentCollector;
+use crate::heap::{CandidateHeap, ExcludingCollector};
+use crate::internal_parameters::InternalParameters;
+use crate::{
+    copy_point, point_slice_dist2, BuildError, CandidateCollector, CandidateContainer,
+    InternalNeighbour, KDTree, Neighbour, Parameters, Point, Scalar,
+};
+
+/// The points of a span of time
+struct TimeBucket<T: Scalar, P: Point<T>> {
+    /// the bucket holds the timestamps `key * bucket_span..(key + 1) * bucket_span`
+    key: u64,
+    /// index of the first point of the bucket
+    first_index: u32,
+    /// timestamp of the last point of the bucket, which is the largest
+    last_timestamp: u64,
+    /// points of the newest bucket, until the bucket is closed
+    open: Vec<P>,
+    /// tree of the points of a closed bucket
+    tree: Option<KDTree<T, P>>,
+    /// timestamps of the points, in insertion order if open, in the internal order of the tree if closed
+    timestamps: Vec<u64>,
+    /// index of the points, in the internal order of the tree if closed, empty if open
+    indices: Vec<u32>,
+}
+
+impl<T: Scalar, P: Point<T>> TimeBucket<T, P> {
+    fn len(&self) -> usize {
+        self.timestamps.len()
+    }
+}
+
+/// An index of time-stamped points, in which old points can be expired in O(1) amortised time
+///
+/// Points are grouped in buckets by spans of `bucket_span` of their timestamps.
+/// The bucket of the newest points is searched by brute force, and a static [`KDTree`]
+/// is built for each older bucket once a point of a later span is pushed.
+/// Expiring points drops whole buckets, and a timestamp filter excludes the expired points
+/// of the oldest bucket from the results.
+/// The smaller the span, the more precise the expiry is, but the more trees are searched.
+pub struct SlidingKDTree<T: Scalar, P: Point<T>> {
+    /// span of timestamps of a bucket
+    bucket_span: u64,
+    /// the live buckets, the oldest first
+    buckets: VecDeque<TimeBucket<T, P>>,
+    /// points with a timestamp smaller than this are expired
+    cutoff: u64,
+    /// index of the next point
+    next_index: u32,
+    /// buffers of expired buckets, reused for new ones
+    spare_points: Vec<Vec<P>>,
+    spare_timestamps: Vec<Vec<u64>>,
+    spare_indices: Vec<Vec<u32>>,
+}
+
+impl<T: Scalar, P: Point<T>> SlidingKDTree<T, P> {
+    /// Creates an empty tree, grouping points in buckets of `bucket_span` of timestamps.
+    ///
+    /// Panics if `bucket_span` is zero.
+    pub fn new(bucket_span: u64) -> Self {
+        assert!(bucket_span > 0, "The bucket span must not be zero");
+        SlidingKDTree {
+            bucket_span,
+            buckets: VecDeque::new(),
+            cutoff: 0,
+            next_index: 0,
+            spare_points: Vec::new(),
+            spare_timestamps: Vec::new(),
+            spare_indices: Vec::new(),
+        }
+    }
+
+    /// Returns the number of points that are not expired.
+    pub fn len(&self) -> usize {
+        let total: usize = self.buckets.iter().map(TimeBucket::len).sum();
+        let expired = self.buckets.front().map_or(0, |bucket| {
+            bucket
+                .timestamps
+                .iter()
+                .filter(|timestamp| **timestamp < self.cutoff)
+                .count()
+        });
+        total - expired
+    }
+
+    /// Returns whether all points are expired.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of buckets holding points that are not all expired.
+    pub fn bucket_count(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// Inserts `point` with `timestamp`, and returns its index.
+    ///
+    /// The index is the number of points pushed before, wrapping around after `u32::MAX`.
+    /// Panics if `timestamp` is smaller than the one of the last pushed point,
+    /// or if `point` has an infinite coordinate.
+    pub fn push(&mut self, point: P, timestamp: u64) -> u32 {
+        let index = self.next_index;
+        if (0..P::DIM).any(|i| !point.get(i).is_finite()) {
+            panic!("{}", BuildError::NonFiniteCoordinate { index });
+        }
+        let key = timestamp / self.bucket_span;
+        match self.buckets.back() {
+            Some(bucket) if bucket.key == key => {
+                assert!(
+                    timestamp >= bucket.last_timestamp,
+                    "Timestamp {} is smaller than the last one {}",
+                    timestamp,
+                    bucket.last_timestamp
+                );
+            }
+            Some(bucket) if bucket.key > key => panic!(
+                "Timestamp {} is smaller than the last one {}",
+                timestamp, bucket.last_timestamp
+            ),
+            _ => self.open_bucket(key),
+        }
+        let bucket = self.buckets.back_mut().expect("a bucket was just opened");
+        bucket.open.push(point);
+        bucket.timestamps.push(timestamp);
+        bucket.last_timestamp = timestamp;
+        self.next_index = index.wrapping_add(1);
+        index
+    }
+
+    /// Expires all points with a timestamp smaller than `timestamp`.
+    ///
+    /// Buckets holding only expired points are dropped and their memory reused for new buckets.
+    /// Expiry is monotonic: passing a smaller timestamp than before has no effect.
+    pub fn expire_before(&mut self, timestamp: u64) {
+        self.cutoff = self.cutoff.max(timestamp);
+        while let Some(bucket) = self.buckets.front() {
+            if bucket.last_timestamp >= self.cutoff {
+                break;
+            }
+            let mut bucket = self.buckets.pop_front().expect("the front bucket exists");
+            bucket.open.clear();
+            bucket.timestamps.clear();
+            bucket.indices.clear();
+            self.spare_points.push(bucket.open);
+            self.spare_timestamps.push(bucket.timestamps);
+            self.spare_indices.push(bucket.indices);
+        }
+    }
+
+    /// Finds the `k` nearest neighbour of `query` among the points that are not expired,
+    /// using reasonable default parameters, see [`KDTree::knn`].
+    pub fn knn(&self, k: u32, query: &P) -> Vec<Neighbour<T, P>> {
+        self.knn_advanced(
+            k,
+            query,
+            KDTree::<T, P>::default_candidate_container(k),
+            &Parameters::default(),
+            None,
+        )
+    }
+
+    /// Finds the `k` nearest neighbour of `query` among the points that are not expired,
+    /// with user-provided parameters, see [`KDTree::knn_advanced`].
+    ///
+    /// The touch statistics sum the points touched in all buckets.
+    pub fn knn_advanced(
+        &self,
+        k: u32,
+        query: &P,
+        candidate_container: CandidateContainer,
+        parameters: &Parameters<T>,
+        touch_statistics: Option<&mut u32>,
+    ) -> Vec<Neighbour<T, P>> {
+        (match candidate_container {
+            CandidateContainer::Linear => Self::knn_generic_heap::<Vec<InternalNeighbour<T>>>,
+            CandidateContainer::BinaryHeap => {
+                Self::knn_generic_heap::<BinaryHeap<InternalNeighbour<T>>>
+            }
+        })(self, k, query, parameters, touch_statistics)
+    }
+
+    fn knn_generic_heap<H: CandidateHeap<T> + CandidateCollector<T>>(
+        &self,
+        k: u32,
+        query: &P,
+        parameters: &Parameters<T>,
+        touch_statistics: Option<&mut u32>,
+    ) -> Vec<Neighbour<T, P>> {
+        let point_count: usize = self.buckets.iter().map(TimeBucket::len).sum();
+        let k = k.min(point_count as u32);
+        let mut heap = H::new_with_k(k);
+        let mut query_coordinates = DimBuffer::new(P::DIM as usize, NotNan::<T>::zero());
+        for (i, value) in query_coordinates.iter_mut().enumerate() {
+            *value = query.get(i as u32);
+        }
+        // the buckets are compared at the scale of a tree of all their points
+        let max_abs = self
+            .buckets
+            .iter()
+            .map(|bucket| match &bucket.tree {
+                Some(tree) => tree.max_abs_coordinate(),
+                None => bucket
+                    .open
+                    .iter()
+                    .flat_map(|point| (0..P::DIM).map(move |i| point.get(i)))
+                    .map(|value| core::cmp::max(-value, value))
+                    .max()
+                    .unwrap_or_else(NotNan::<T>::zero),
+            })
+            .fold(NotNan::<T>::zero(), NotNan::max);
+        let scale = distance_scale(max_abs, P::DIM);
+        let mut touched_points = 0;
+        // the excluded index is global, so it is skipped after translating the indices of the buckets
+        let mut excluding = ExcludingCollector {
+            inner: &mut heap,
+            excluded: parameters.self_match.excluded_index(),
+        };
+        for bucket in self.buckets.iter().rev() {
+            // only the oldest bucket can hold expired points
+            let cutoff = self.cutoff;
+            match &bucket.tree {
+                Some(tree) => {
+                    let mut collector = ExpiryCollector {
+                        inner: ComponentCollector {
+                            heap: &mut excluding,
+                            indices: &bucket.indices,
+                            scale: tree.scale / scale,
+                        },
+                        timestamps: &bucket.timestamps,
+                        cutoff,
+                    };
+                    let stats = tree.knn_internal(
+                        &mut collector,
+                        &query_coordinates,
+                        &InternalParameters::new(parameters, tree.scale).without_excluded(),
+                    );
+                    touched_points += stats.touched_points;
+                }
+                None => {
+                    let open_parameters = InternalParameters::new(parameters, scale);
+                    let mut coordinates = DimBuffer::new(P::DIM as usize, NotNan::<T>::zero());
+                    for (local, (point, timestamp)) in
+                        bucket.open.iter().zip(&bucket.timestamps).enumerate()
+                    {
+                        if *timestamp < cutoff {
+                            continue;
+                        }
+                        for (i, value) in coordinates.iter_mut().enumerate() {
+                            *value = point.get(i as u32);
+                        }
+                        let index = bucket.first_index.wrapping_add(local as u32);
+                        let dist2 =
+                            point_slice_dist2::<T, P>(&query_coordinates, &coordinates, scale);
+                        if open_parameters.accepts(dist2, index) {
+                            excluding.add(dist2, index);
+                        }
+                    }
+                    touched_points += bucket.len() as u32;
+                }
+            }
+        }
+        if let Some(touch_statistics) = touch_statistics {
+            *touch_statistics = touched_points;
+        }
+        if parameters.sort_results {
+            heap.into_sorted_vec()
+        } else {
+            heap.into_vec()
+        }
+        .into_iter()
+        .map(|neighbour| Neighbour {
+            point: self.point(neighbour.index),
+            dist2: unscale_dist2(neighbour.dist2, scale),
+            index: neighbour.index,
+        })
+        .collect()
+    }
+
+    /// Returns the point of index `index`, which must be in a live bucket
+    fn point(&self, index: u32) -> P {
+        let bucket = self
+            .buckets
+            .iter()
+            .find(|bucket| (index.wrapping_sub(bucket.first_index) as usize) < bucket.len())
+            .expect("the point is in a live bucket");
+        let local = index.wrapping_sub(bucket.first_index);
+        match &bucket.tree {
+            Some(tree) => tree.stored_point(tree.internalise_index(local)),
+            None => copy_point(&bucket.open[local as usize]),
+        }
+    }
+
+    /// Closes the newest bucket, if any, and opens a new one for the timestamps of `key`
+    fn open_bucket(&mut self, key: u64) {
+        if let Some(bucket) = self.buckets.back_mut() {
+            let tree = KDTree::new(&bucket.open);
+            let mut timestamps = self.spare_timestamps.pop().unwrap_or_default();
+            timestamps.extend(
+                tree.externalisation_map()
+                    .iter()
+                    .map(|local| bucket.timestamps[*local as usize]),
+            );
+            let first_index = bucket.first_index;
+            bucket.indices.extend(
+                tree.externalisation_map()
+                    .iter()
+                    .map(|local| first_index.wrapping_add(*local)),
+            );
+            bucket.open.clear();
+            let mut open_timestamps = core::mem::replace(&mut bucket.timestamps, timestamps);
+            open_timestamps.clear();
+            self.spare_timestamps.push(open_timestamps);
+            self.spare_points.push(core::mem::take(&mut bucket.open));
+            bucket.tree = Some(tree);
+        }
+        self.buckets.push_back(TimeBucket {
+            key,
+            first_index: self.next_index,
+            last_timestamp: 0,
+            open: self.spare_points.pop().unwrap_or_default(),
+            tree: None,
+            timestamps: self.spare_timestamps.pop().unwrap_or_default(),
+            indices: self.spare_indices.pop().unwrap_or_default(),
+        });
+    }
+}
+
+/// Passes to a component collector only the candidates that are not expired
+struct ExpiryCollector<'a, T: Scalar, H: CandidateCollector<T>> {
+    inner: ComponentCollector<'a, T, H>,
+    /// timestamp of each internal index of the tree
+    timestamps: &'a [u64],
+    cutoff: u64,
+}
+
+impl<'a, T: Scalar, H: CandidateCollector<T>> CandidateCollector<T> for ExpiryCollector<'a, T, H> {
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        if self.timestamps[index as usize] >= self.cutoff {
+            self.inner.add(dist2, index);
+        }
+    }
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        self.inner.furthest_dist2()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn assert_same_knn(sliding: &SlidingKDTree<f32, P2>, cloud: &[P2], first_index: usize) {
+        let reference = KDTree::new(&cloud[first_index..]);
+        for _ in 0..20 {
+            let query = random_point();
+            let expected: Vec<_> = reference
+                .knn(5, &query)
+                .into_iter()
+                .map(|n| (n.index as usize + first_index, n.dist2))
+                .collect();
+            let neighbours: Vec<_> = sliding
+                .knn(5, &query)
+                .into_iter()
+                .map(|n| {
+                    assert_eq!(n.point, cloud[n.index as usize]);
+                    (n.index as usize, n.dist2)
+                })
+                .collect();
+            assert_eq!(neighbours, expected);
+        }
+    }
+
+    #[test]
+    fn sliding_tree_excludes_expired_points() {
+        let cloud: Vec<P2> = random_point_cloud(2000);
+        let mut sliding = SlidingKDTree::new(100);
+        // point i has timestamp i / 2
+        for (index, point) in cloud.iter().enumerate() {
+            assert_eq!(sliding.push(*point, index as u64 / 2), index as u32);
+            if index % 250 == 249 {
+                // keep the last 300 time units, cutting in the middle of a bucket
+                let now = index as u64 / 2;
+                sliding.expire_before(now.saturating_sub(300));
+                let first_index = (now.saturating_sub(300) * 2) as usize;
+                assert_eq!(sliding.len(), index + 1 - first_index);
+                assert_same_knn(&sliding, &cloud[..=index], first_index);
+                assert!(sliding.bucket_count() <= 5);
+            }
+        }
+        sliding.expire_before(0);
+        assert_eq!(sliding.len(), 602);
+        sliding.expire_before(10_000);
+        assert!(sliding.is_empty());
+        assert_eq!(sliding.bucket_count(), 0);
+        assert!(sliding.knn(3, &cloud[0]).is_empty());
+        sliding.push(cloud[0], 10_000);
+        assert_eq!(sliding.knn(3, &cloud[1])[0].index, 2000);
+    }
+
+    #[test]
+    fn sliding_tree_compares_buckets_of_different_scales() {
+        // the unscaled squared distances of the first points underflow, unlike those at the common scale
+        let cloud: Vec<P2> = random_point_cloud(400)
+            .into_iter()
+            .enumerate()
+            .map(|(index, point): (usize, P2)| {
+                point
+                    * if index < 200 {
+                        2f32.powi(-100)
+                    } else {
+                        2f32.powi(-90)
+                    }
+            })
+            .collect();
+        let mut sliding = SlidingKDTree::new(100);
+        for (index, point) in cloud.iter().enumerate() {
+            sliding.push(*point, index as u64);
+        }
+        assert_eq!(sliding.bucket_count(), 4);
+        let reference = KDTree::new(&cloud);
+        let queries: Vec<P2> = random_point_cloud(20);
+        for query in &queries {
+            for factor in [2f32.powi(-100), 2f32.powi(-90)] {
+                let query = *query * factor;
+                assert_eq!(sliding.knn(5, &query), reference.knn(5, &query));
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn sliding_tree_rejects_decreasing_timestamps() {
+        let mut sliding = SlidingKDTree::new(10);
+        sliding.push(P2::new2d(0.0, 0.0), 25);
+        sliding.push(P2::new2d(1.0, 0.0), 15);
+    }
+}
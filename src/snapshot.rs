@@ -0,0 +1,396 @@
+//! Cheap forks of a tree, with insertions and removals layered on top of it
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+
+use num_traits::Zero;
+use ordered_float::NotNan;
+
+use crate::dim_buffer::DimBuffer;
+use crate::distance_scale::{distance_scale, max_abs_coordinate, unscale_dist2};
+use crate::dynamic_tree::ComponentCollector;
+use crate::heap::CandidateHeap;
+use crate::internal_parameters::InternalParameters;
+use crate::{
+    point_slice_dist2, BitSet, BuildError, CandidateCollector, CandidateContainer,
+    InternalNeighbour, KDTree, Neighbour, Parameters, Point, Scalar, TreeQuality,
+};
+
+/// A fork of a [`KDTree`], in which points can be inserted and removed without copying the tree
+///
+/// The tree is shared, and the snapshot only stores the inserted points and the removed indices.
+/// Queries search the tree, skipping removed points, and the inserted points by brute force,
+/// so the snapshot is meant for a few changes, after which a new tree should be built.
+/// Queries on the tree itself are not affected by its snapshots.
+pub struct KDTreeSnapshot<'a, T: Scalar, P: Point<T>> {
+    base: &'a KDTree<T, P>,
+    /// removed points of `base`, allocated on the first removal
+    removed: Option<BitSet>,
+    removed_count: usize,
+    /// coordinates of the inserted points
+    inserted: Vec<NotNan<T>>,
+    /// whether each inserted point was removed
+    inserted_removed: Vec<bool>,
+    /// identifiers of the inserted points
+    inserted_ids: Vec<u64>,
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Creates a snapshot of this tree, in which points can be inserted and removed in O(1) time.
+    pub fn snapshot(&self) -> KDTreeSnapshot<'_, T, P> {
+        KDTreeSnapshot {
+            base: self,
+            removed: None,
+            removed_count: 0,
+            inserted: Vec::new(),
+            inserted_removed: Vec::new(),
+            inserted_ids: Vec::new(),
+        }
+    }
+}
+
+impl<'a, T: Scalar, P: Point<T>> KDTreeSnapshot<'a, T, P> {
+    /// Returns the tree of which this is a snapshot.
+    pub fn base(&self) -> &'a KDTree<T, P> {
+        self.base
+    }
+
+    /// Returns the number of points that are not removed.
+    pub fn len(&self) -> usize {
+        self.base.len() + self.inserted_removed.len() - self.removed_count
+    }
+
+    /// Returns whether all points are removed.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Inserts `point` and returns its index, following the indices of the tree and of the points inserted before.
+    ///
+    /// Its identifier is its index, see [`Self::insert_with_id`].
+    /// Panics if `point` has an infinite coordinate, or if there would be more than `u32::MAX` points.
+    pub fn insert(&mut self, point: P) -> u32 {
+        let index = self.base.len() + self.inserted_ids.len();
+        self.insert_with_id(point, index as u64)
+    }
+
+    /// Inserts `point` with identifier `id` and returns its index, see [`Self::insert`].
+    ///
+    /// The identifier needs not be unique.
+    pub fn insert_with_id(&mut self, point: P, id: u64) -> u32 {
+        let count = self.base.len() + self.inserted_removed.len();
+        if count >= u32::MAX as usize {
+            panic!("{}", BuildError::TooManyPoints(count + 1));
+        }
+        let index = count as u32;
+        if (0..P::DIM).any(|i| !point.get(i).is_finite()) {
+            panic!("{}", BuildError::NonFiniteCoordinate { index });
+        }
+        self.inserted.extend((0..P::DIM).map(|i| point.get(i)));
+        self.inserted_removed.push(false);
+        self.inserted_ids.push(id);
+        index
+    }
+
+    /// Returns the identifier of the point of index `index`, whether removed or not, see [`KDTree::id_of`].
+    ///
+    /// Panics if `index` is not the index of a point of the tree or inserted in the snapshot.
+    pub fn id_of(&self, index: u32) -> u64 {
+        let index = index as usize;
+        if index < self.base.len() {
+            self.base.id_of(index as u32)
+        } else {
+            self.inserted_ids[index - self.base.len()]
+        }
+    }
+
+    /// Removes the point of index `index`, returns whether it was present.
+    ///
+    /// Panics if `index` is not the index of a point of the tree or inserted in the snapshot.
+    pub fn remove(&mut self, index: u32) -> bool {
+        let index = index as usize;
+        let was_present = if index < self.base.len() {
+            let base_len = self.base.len();
+            self.removed
+                .get_or_insert_with(|| BitSet::with_len(base_len))
+                .insert(index)
+        } else {
+            let removed = &mut self.inserted_removed[index - self.base.len()];
+            !core::mem::replace(removed, true)
+        };
+        if was_present {
+            self.removed_count += 1;
+        }
+        was_present
+    }
+
+    /// Returns whether the point of index `index` is removed.
+    ///
+    /// Panics if `index` is not the index of a point of the tree or inserted in the snapshot.
+    pub fn is_removed(&self, index: u32) -> bool {
+        let index = index as usize;
+        if index < self.base.len() {
+            self.removed
+                .as_ref()
+                .map_or(false, |removed| removed.contains(index))
+        } else {
+            self.inserted_removed[index - self.base.len()]
+        }
+    }
+
+    /// Returns the quality of the tree, see [`KDTree::quality`], with its removed points as dead points.
+    ///
+    /// The estimated slowdown accounts for the dead points visited in vain,
+    /// but not for the inserted points, which queries scan in addition to the tree.
+    pub fn quality(&self) -> TreeQuality {
+        let mut quality = self.base.quality();
+        if !self.base.is_empty() {
+            let dead = self.removed.as_ref().map_or(0, BitSet::count);
+            quality.dead_fraction = dead as f64 / self.base.len() as f64;
+            let live = (self.base.len() - dead).max(1);
+            quality.estimated_slowdown *= self.base.len() as f64 / live as f64;
+        }
+        quality
+    }
+
+    /// Finds the `k` nearest neighbour of `query` among the points that are not removed,
+    /// using reasonable default parameters, see [`KDTree::knn`].
+    pub fn knn(&self, k: u32, query: &P) -> Vec<Neighbour<T, P>> {
+        self.knn_advanced(
+            k,
+            query,
+            KDTree::<T, P>::default_candidate_container(k),
+            &Parameters::default(),
+            None,
+        )
+    }
+
+    /// Finds the `k` nearest neighbour of `query` among the points that are not removed,
+    /// with user-provided parameters, see [`KDTree::knn_advanced`].
+    ///
+    /// The touch statistics sum the points touched in the tree and the inserted points.
+    pub fn knn_advanced(
+        &self,
+        k: u32,
+        query: &P,
+        candidate_container: CandidateContainer,
+        parameters: &Parameters<T>,
+        touch_statistics: Option<&mut u32>,
+    ) -> Vec<Neighbour<T, P>> {
+        (match candidate_container {
+            CandidateContainer::Linear => Self::knn_generic_heap::<Vec<InternalNeighbour<T>>>,
+            CandidateContainer::BinaryHeap => {
+                Self::knn_generic_heap::<BinaryHeap<InternalNeighbour<T>>>
+            }
+        })(self, k, query, parameters, touch_statistics)
+    }
+
+    fn knn_generic_heap<H: CandidateHeap<T> + CandidateCollector<T>>(
+        &self,
+        k: u32,
+        query: &P,
+        parameters: &Parameters<T>,
+        touch_statistics: Option<&mut u32>,
+    ) -> Vec<Neighbour<T, P>> {
+        let k = k.min(self.len() as u32);
+        let mut heap = H::new_with_k(k);
+        let mut query_coordinates = DimBuffer::new(P::DIM as usize, NotNan::<T>::zero());
+        for (i, value) in query_coordinates.iter_mut().enumerate() {
+            *value = query.get(i as u32);
+        }
+        // the tree and the inserted points are compared at the scale of a tree of all points
+        let max_abs = max_abs_coordinate(&self.inserted).max(self.base.max_abs_coordinate());
+        let scale = distance_scale(max_abs, P::DIM);
+        let mut collector = TombstoneCollector {
+            inner: ComponentCollector {
+                heap: &mut heap,
+                indices: self.base.externalisation_map(),
+                scale: self.base.scale / scale,
+            },
+            removed: self.removed.as_ref(),
+        };
+        // the base tree may be rotated, unlike the inserted points
+        let stats = self.base.knn_internal(
+            &mut collector,
+            &self.base.query_coordinates(query),
+            &InternalParameters::new(parameters, self.base.scale),
+        );
+        let inserted_parameters = InternalParameters::new(parameters, scale);
+        for (position, point) in self.inserted.chunks(P::DIM as usize).enumerate() {
+            let index = (self.base.len() + position) as u32;
+            if self.inserted_removed[position] {
+                continue;
+            }
+            let dist2 = point_slice_dist2::<T, P>(&query_coordinates, point, scale);
+            if inserted_parameters.accepts(dist2, index) {
+                heap.add(dist2, index);
+            }
+        }
+        if let Some(touch_statistics) = touch_statistics {
+            *touch_statistics = stats.touched_points + self.inserted_removed.len() as u32;
+        }
+        if parameters.sort_results {
+            heap.into_sorted_vec()
+        } else {
+            heap.into_vec()
+        }
+        .into_iter()
+        .map(|neighbour| Neighbour {
+            point: self.point(neighbour.index),
+            dist2: unscale_dist2(neighbour.dist2, scale),
+            index: neighbour.index,
+        })
+        .collect()
+    }
+
+    /// Returns the point of index `index`, whether removed or not
+    fn point(&self, index: u32) -> P {
+        let index = index as usize;
+        if index < self.base.len() {
+            self.base
+                .stored_point(self.base.internalise_index(index as u32))
+        } else {
+            let start = (index - self.base.len()) * P::DIM as usize;
+            P::from_slice(&self.inserted[start..start + P::DIM as usize])
+        }
+    }
+}
+
+/// Passes to a component collector only the candidates that are not removed
+struct TombstoneCollector<'a, T: Scalar, H: CandidateCollector<T>> {
+    inner: ComponentCollector<'a, T, H>,
+    /// removed external indices
+    removed: Option<&'a BitSet>,
+}
+
+impl<'a, T: Scalar, H: CandidateCollector<T>> CandidateCollector<T>
+    for TombstoneCollector<'a, T, H>
+{
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        if let Some(removed) = self.removed {
+            if removed.contains(self.inner.indices[index as usize] as usize) {
+                return;
+            }
+        }
+        self.inner.add(dist2, index);
+    }
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        self.inner.furthest_dist2()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn snapshot_layers_changes_on_the_tree() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let tree = KDTree::new(&cloud);
+        let mut snapshot = tree.snapshot();
+        assert_eq!(snapshot.len(), 1000);
+        // the fork sees its changes, the tree does not
+        let mut expected = cloud.clone();
+        let mut removed = vec![false; 1000];
+        for (index, point) in random_point_cloud::<2>(50).into_iter().enumerate() {
+            assert_eq!(snapshot.insert(point), 1000 + index as u32);
+            expected.push(point);
+            removed.push(false);
+        }
+        for index in (0..1050).step_by(7) {
+            assert!(snapshot.remove(index));
+            removed[index as usize] = true;
+        }
+        assert!(!snapshot.remove(7));
+        assert!(snapshot.is_removed(7));
+        assert!(!snapshot.is_removed(8));
+        assert_eq!(snapshot.len(), 1050 - 150);
+        let reference = KDTree::new(&cloud);
+        for _ in 0..50 {
+            let query = random_point();
+            let neighbours = snapshot.knn(8, &query);
+            let mut brute_force: Vec<_> = expected
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| !removed[*index])
+                .map(|(index, point)| {
+                    let dx = *point.get(0) - *query.get(0);
+                    let dy = *point.get(1) - *query.get(1);
+                    (dx * dx + dy * dy, index as u32)
+                })
+                .collect();
+            brute_force.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            assert_eq!(neighbours.len(), 8);
+            for (neighbour, (dist2, index)) in neighbours.iter().zip(brute_force) {
+                assert_eq!(neighbour.index, index);
+                assert_eq!(neighbour.dist2, dist2);
+                assert_eq!(neighbour.point, expected[index as usize]);
+            }
+            assert_eq!(tree.knn(8, &query), reference.knn(8, &query));
+        }
+    }
+
+    #[test]
+    fn snapshot_can_remove_everything() {
+        let cloud: Vec<P2> = random_point_cloud(20);
+        let tree = KDTree::new(&cloud);
+        let mut snapshot = tree.snapshot();
+        for index in 0..20 {
+            snapshot.remove(index);
+        }
+        assert!(snapshot.is_empty());
+        assert!(snapshot.knn(3, &cloud[0]).is_empty());
+        let index = snapshot.insert(cloud[0]);
+        assert_eq!(snapshot.knn(3, &cloud[0])[0].index, index);
+    }
+
+    #[test]
+    fn snapshot_compares_inserted_points_at_the_scale_of_the_tree() {
+        // unscaled squared distances between these points underflow to zero
+        let factor = 2f32.powi(-100);
+        let mut cloud: Vec<P2> = random_point_cloud(400)
+            .into_iter()
+            .map(|point: P2| point * factor)
+            .collect();
+        let tree = KDTree::new(&cloud);
+        let mut snapshot = tree.snapshot();
+        let inserted: P2 = random_point::<2>() * factor;
+        snapshot.insert(inserted);
+        cloud.push(inserted);
+        let reference = KDTree::new(&cloud);
+        let mut queries: Vec<P2> = random_point_cloud(20)
+            .into_iter()
+            .map(|point: P2| point * factor)
+            .collect();
+        queries.push(inserted);
+        for query in &queries {
+            assert_eq!(snapshot.knn(5, query), reference.knn(5, query));
+        }
+    }
+
+    #[test]
+    fn snapshot_of_a_rotated_tree_rotates_the_queries() {
+        let cloud: Vec<P2> = random_point_cloud(300);
+        let options = BuildOptions {
+            rotation_seed: Some(5),
+            ..BuildOptions::default()
+        };
+        let tree = KDTree::new_with_options(&cloud, &options);
+        let mut snapshot = tree.snapshot();
+        let inserted = random_point();
+        snapshot.insert(inserted);
+        let mut all = cloud.clone();
+        all.push(inserted);
+        let reference = KDTree::new(&all);
+        let queries: Vec<P2> = random_point_cloud(20);
+        for query in queries.iter().chain([&inserted]) {
+            let indices = |neighbours: Vec<Neighbour<f32, P2>>| -> Vec<u32> {
+                neighbours.iter().map(|n| n.index).collect()
+            };
+            assert_eq!(
+                indices(snapshot.knn(5, query)),
+                indices(reference.knn(5, query))
+            );
+        }
+    }
+}
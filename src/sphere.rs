@@ -0,0 +1,239 @@
+//! Nearest neighbours by direction, for points lying on or near a sphere
+
+use alloc::vec::Vec;
+
+use num_traits::Float;
+use ordered_float::NotNan;
+
+use crate::{KDTree, Neighbour, Parameters, Point, QueryError, Scalar};
+
+/// A neighbour found by [`KDTree::nearest_by_direction`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DirectionalNeighbour<T: Scalar, P: Point<T>> {
+    /// the neighbour, with its squared distance to the query point on the sphere
+    pub neighbour: Neighbour<T, P>,
+    /// the distance to the query point on the sphere
+    pub chord: T,
+    /// the angle in radians, seen from the center, between the point and the direction of the query,
+    /// `π/2` for a point at the center, which has no direction
+    pub angle: T,
+}
+
+impl<T: Scalar + Float, P: Point<T>> KDTree<T, P> {
+    /// Returns the median of the distances from `center` to the points of the tree,
+    /// an estimate of the radius of the sphere they lie near that is robust to outliers, zero if the tree is empty.
+    ///
+    /// This takes time linear in the number of points.
+    pub fn sphere_radius(&self, center: &P) -> T {
+        let mut distances: Vec<_> = self
+            .iter_points()
+            .map(|point| {
+                let dist2 = (0..P::DIM).fold(T::zero(), |dist2, i| {
+                    let diff = *point.get(i) - *center.get(i);
+                    dist2 + diff * diff
+                });
+                NotNan::new(Float::sqrt(dist2)).expect("distances between points are not NaN")
+            })
+            .collect();
+        if distances.is_empty() {
+            return T::zero();
+        }
+        let middle = distances.len() / 2;
+        distances.select_nth_unstable(middle).1.into_inner()
+    }
+
+    /// Finds the `k` nearest neighbours of the point of the sphere of `center` in `direction`,
+    /// with user-provided parameters.
+    ///
+    /// The query point is `center + radius · direction / |direction|`, with `radius` estimated
+    /// by [`Self::sphere_radius`] if `None`.
+    /// As the estimate takes time linear in the number of points, compute it once for many queries.
+    /// Neighbours are ordered by chord, which is the order of their angles for points on the sphere.
+    /// Returns [`QueryError::DegenerateDirection`] if the norm of `direction` is below the machine epsilon,
+    /// or is not finite.
+    ///
+    /// Panics if `radius` is negative or not finite.
+    pub fn nearest_by_direction(
+        &self,
+        center: &P,
+        direction: &P,
+        k: u32,
+        radius: Option<T>,
+        parameters: &Parameters<T>,
+    ) -> Result<Vec<DirectionalNeighbour<T, P>>, QueryError> {
+        let direction = unit(direction).ok_or(QueryError::DegenerateDirection)?;
+        let radius = match radius {
+            Some(radius) => {
+                assert!(
+                    radius >= T::zero() && Float::is_finite(radius),
+                    "the radius must be positive or zero and finite"
+                );
+                radius
+            }
+            None => self.sphere_radius(center),
+        };
+        let mut query = P::default();
+        for i in 0..P::DIM {
+            let value = *center.get(i) + radius * direction[i as usize];
+            query.set(
+                i,
+                NotNan::new(value).map_err(|_| QueryError::NanCoordinate)?,
+            );
+        }
+        let neighbours = self.knn_advanced(
+            k,
+            &query,
+            Self::default_candidate_container(k),
+            parameters,
+            None,
+        );
+        Ok(neighbours
+            .into_iter()
+            .map(|neighbour| {
+                let mut offset = P::default();
+                for i in 0..P::DIM {
+                    offset.set(i, neighbour.point.get(i) - *center.get(i));
+                }
+                let angle = match unit(&offset) {
+                    Some(offset) => angle_between(&offset, &direction),
+                    None => T::from(core::f64::consts::FRAC_PI_2).expect("π/2 is representable"),
+                };
+                DirectionalNeighbour {
+                    chord: Float::sqrt(*neighbour.dist2),
+                    angle,
+                    neighbour,
+                }
+            })
+            .collect())
+    }
+}
+
+/// Returns `vector` divided by its norm, if the norm is finite and not below the machine epsilon
+pub(crate) fn unit<T: Scalar + Float, P: Point<T>>(vector: &P) -> Option<Vec<T>> {
+    let norm = Float::sqrt((0..P::DIM).fold(T::zero(), |norm2, i| {
+        norm2 + *vector.get(i) * *vector.get(i)
+    }));
+    if !(Float::is_finite(norm) && norm >= Float::epsilon()) {
+        return None;
+    }
+    Some((0..P::DIM).map(|i| *vector.get(i) / norm).collect())
+}
+
+/// Returns the angle between unit vectors `a` and `b`, accurate for small and large angles
+fn angle_between<T: Float>(a: &[T], b: &[T]) -> T {
+    let (difference2, sum2) =
+        a.iter()
+            .zip(b)
+            .fold((T::zero(), T::zero()), |(difference2, sum2), (a, b)| {
+                let (difference, sum) = (*a - *b, *a + *b);
+                (difference2 + difference * difference, sum2 + sum * sum)
+            });
+    let two = T::one() + T::one();
+    two * Float::atan2(Float::sqrt(difference2), Float::sqrt(sum2))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use crate::simple_point::P3d;
+    use crate::*;
+
+    fn random_direction(rng: &mut impl Rng) -> [f64; 3] {
+        loop {
+            let v: [f64; 3] = core::array::from_fn(|_| rng.gen_range(-1.0..1.0));
+            let norm2 = v.iter().map(|x| x * x).sum::<f64>();
+            if norm2 > 0.01 && norm2 <= 1.0 {
+                let norm = norm2.sqrt();
+                return v.map(|x| x / norm);
+            }
+        }
+    }
+
+    #[test]
+    fn directions_find_the_nearest_points_of_the_sphere() {
+        let mut rng = rand::thread_rng();
+        let center = [1.0, -2.0, 3.0];
+        let radius = 5.0;
+        // points near the sphere, and a few outliers which the median ignores
+        let mut cloud: Vec<P3d> = (0..3000)
+            .map(|_| {
+                let r = radius * rng.gen_range(0.98..1.02);
+                let d = random_direction(&mut rng);
+                P3d::from(core::array::from_fn::<_, 3, _>(|i| center[i] + r * d[i]))
+            })
+            .collect();
+        cloud.extend((0..50).map(|_| P3d::from(center)));
+        cloud.extend((0..50).map(|_| P3d::from([100.0, 100.0, 100.0])));
+        let tree = KDTree::new(&cloud);
+        let center = P3d::from(center);
+        let estimate = tree.sphere_radius(&center);
+        assert!((estimate - radius).abs() < 0.05);
+        for _ in 0..50 {
+            let direction = random_direction(&mut rng);
+            // the direction does not need to be normalised
+            let scaled = P3d::from(direction.map(|x| x * 3.0));
+            let found = tree
+                .nearest_by_direction(&center, &scaled, 10, None, &Parameters::default())
+                .unwrap();
+            let query = P3d::from(core::array::from_fn::<_, 3, _>(|i| {
+                *center.get(i as u32) + estimate * direction[i]
+            }));
+            let expected = tree.knn(10, &query);
+            // the query point matches up to the rounding of the normalisation
+            assert_eq!(found.len(), expected.len());
+            for (neighbour, expected) in found.iter().zip(&expected) {
+                assert_eq!(neighbour.neighbour.index, expected.index);
+                assert!((*neighbour.neighbour.dist2 - *expected.dist2).abs() < 1e-9);
+            }
+            for neighbour in &found {
+                assert_eq!(neighbour.chord, neighbour.neighbour.dist2.sqrt());
+                let offset: Vec<f64> = (0..3)
+                    .map(|i| *neighbour.neighbour.point.get(i) - *center.get(i))
+                    .collect();
+                let norm = offset.iter().map(|x| x * x).sum::<f64>().sqrt();
+                let cos = offset
+                    .iter()
+                    .zip(direction)
+                    .map(|(a, b)| a * b)
+                    .sum::<f64>()
+                    / norm;
+                assert!((neighbour.angle - cos.clamp(-1.0, 1.0).acos()).abs() < 1e-6);
+                // the chord to a point near the sphere is close to that of the angle
+                let chord = 2.0 * norm.min(radius) * (neighbour.angle / 2.0).sin();
+                assert!(neighbour.chord <= chord + 0.02 * radius);
+            }
+        }
+        // a provided radius is used as is, and the center has no direction
+        let up = P3d::from([0.0, 0.0, 1.0]);
+        let at_center = tree
+            .nearest_by_direction(&center, &up, 1, Some(0.0), &Parameters::default())
+            .unwrap();
+        assert_eq!(at_center[0].neighbour.dist2.into_inner(), 0.0);
+        assert_eq!(at_center[0].angle, core::f64::consts::FRAC_PI_2);
+    }
+
+    #[test]
+    fn degenerate_directions_are_errors() {
+        let tree = KDTree::new(&[P3d::from([1.0, 0.0, 0.0])]);
+        let center = P3d::from([0.0, 0.0, 0.0]);
+        for direction in [
+            [0.0, 0.0, 0.0],
+            [1e-20, 0.0, 0.0],
+            [f64::INFINITY, 0.0, 0.0],
+        ] {
+            assert_eq!(
+                tree.nearest_by_direction(
+                    &center,
+                    &P3d::from(direction),
+                    1,
+                    None,
+                    &Parameters::default()
+                ),
+                Err(QueryError::DegenerateDirection)
+            );
+        }
+        assert_eq!(tree.sphere_radius(&center), 1.0);
+        assert_eq!(KDTree::<f64, P3d>::new(&[]).sphere_radius(&center), 0.0);
+    }
+}
@@ -0,0 +1,135 @@
+//! Indices stamped with the generation of the tree, to detect indices held across changes of their meaning
+
+use core::fmt;
+
+use crate::{KDTree, Neighbour, Point, Scalar};
+
+/// An index of a point in the original point cloud, with the generation of the tree it was obtained from,
+/// see [`KDTree::generation`]
+///
+/// Stamped indices are meant for results held across operations on the tree, for example from frame to frame,
+/// and are checked by [`KDTree::resolve`] before use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct StampedIndex {
+    /// the index of the point in the original point cloud
+    pub index: u32,
+    /// the generation of the tree when the index was obtained
+    pub generation: u64,
+}
+
+/// A [`StampedIndex`] does not designate a point of the tree, see [`KDTree::resolve`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct StaleIndex {
+    /// the stamped index passed
+    pub stamped: StampedIndex,
+    /// the generation of the tree
+    pub generation: u64,
+}
+
+impl fmt::Display for StaleIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Index {} of generation {} is stale in a tree of generation {}",
+            self.stamped.index, self.stamped.generation, self.generation
+        )
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Returns the generation of the tree, the number of operations that changed the meaning of its indices.
+    ///
+    /// A built tree is at generation 0.
+    /// [`Self::apply_batch`] increments it when it removes points, as removals renumber points and the indices
+    /// of the removed points are reused by the inserted ones, and [`Self::merge`] and [`Self::merge_all`] make
+    /// the merged tree one generation beyond the latest of the trees merged.
+    /// Operations that keep the indices keep the generation, including moving points, refitting and rebuilding.
+    /// Trees imported by [`FlatTree::import`](crate::FlatTree::import) are at generation 0.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns `index` stamped with the generation of the tree.
+    pub fn stamp(&self, index: u32) -> StampedIndex {
+        StampedIndex {
+            index,
+            generation: self.generation,
+        }
+    }
+
+    /// Returns the index of `stamped`, or [`StaleIndex`] if it was stamped in another generation of the tree
+    /// or is out of range.
+    pub fn resolve(&self, stamped: StampedIndex) -> Result<u32, StaleIndex> {
+        if stamped.generation == self.generation && (stamped.index as usize) < self.len() {
+            Ok(stamped.index)
+        } else {
+            Err(StaleIndex {
+                stamped,
+                generation: self.generation,
+            })
+        }
+    }
+
+    /// Marks the indices of the tree as changed, see [`Self::generation`]
+    pub(crate) fn bump_generation(&mut self) {
+        self.generation += 1;
+    }
+}
+
+impl<T: Scalar, P: Point<T>> Neighbour<T, P> {
+    /// Returns the index of this neighbour stamped with the generation of `tree`, the tree it was found in,
+    /// see [`KDTree::stamp`].
+    pub fn stamped_index(&self, tree: &KDTree<T, P>) -> StampedIndex {
+        tree.stamp(self.index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simple_point::*;
+
+    #[test]
+    fn removals_and_merges_make_stamps_stale() {
+        let cloud: Vec<P2> = random_point_cloud(500);
+        let mut tree = KDTree::new(&cloud);
+        assert_eq!(tree.generation(), 0);
+        let nearest = tree.knn(1, &cloud[42])[0];
+        let stamped = nearest.stamped_index(&tree);
+        assert_eq!(tree.resolve(stamped), Ok(42));
+        // moving points, inserting and rebuilding keep the indices
+        tree.update_point(42, cloud[42] * 0.5);
+        tree.apply_batch(&[], &[P2::new2d(1.0, 2.0)]);
+        tree.rebuild();
+        assert_eq!(tree.resolve(stamped), Ok(42));
+        // removing point 3 moves the last point to its index
+        tree.apply_batch(&[3], &[]);
+        assert_eq!(tree.generation(), 1);
+        let error = tree.resolve(stamped).unwrap_err();
+        assert_eq!(
+            error,
+            StaleIndex {
+                stamped,
+                generation: 1
+            }
+        );
+        assert_eq!(tree.resolve(tree.stamp(42)), Ok(42));
+        assert!(tree.resolve(tree.stamp(500)).is_err());
+    }
+
+    #[test]
+    fn merged_trees_are_beyond_the_generations_of_both() {
+        let cloud: Vec<P2> = random_point_cloud(200);
+        let mut a = KDTree::new(&cloud[..100]);
+        a.apply_batch(&[0, 1], &[]);
+        a.apply_batch(&[0], &[]);
+        let b = KDTree::new(&cloud[100..]);
+        let (from_a, from_b) = (a.stamp(5), b.stamp(5));
+        let merged = KDTree::merge(a, b);
+        assert_eq!(merged.generation(), 3);
+        assert!(merged.resolve(from_a).is_err());
+        assert!(merged.resolve(from_b).is_err());
+        let all = KDTree::merge_all(vec![merged.clone(), merged]);
+        assert_eq!(all.generation(), 4);
+    }
+}
@@ -0,0 +1,178 @@
+//! Tests of the `strict-exact` feature, under which no search can return approximate neighbours
+//!
+//! The feature leaves out the structures and functions that are approximate by design:
+//! [`KDForest`](crate::KDForest) and its `max_checks`, the LOD queries of [`KDTree::knn_lod`],
+//! [`QuantizedKDTree`](crate::QuantizedKDTree), [`KDTree::count_within_radius_approx`],
+//! the tuning of epsilon by [`KDTree::tune`], [`KDTree::knn_eps`], the `eval` module,
+//! the coarse answers of [`KDTree::knn_coarse_then_fine`] and the budgeted searches of [`KDTree::begin_query`].
+//! The one approximate parameter left, [`Parameters::epsilon`], must be zero:
+//! [`Parameters::validate`] rejects other values with [`ParameterError::ApproximateSearch`],
+//! and the searches that do not validate their parameters panic with that error when converting them.
+//! Run with `cargo test --features strict-exact`.
+
+use ordered_float::NotNan;
+
+use crate::internal_parameters::InternalParameters;
+use crate::simple_point::*;
+use crate::*;
+
+/// Returns the indices and squared distances of the points of `cloud` within `max_radius` of `query`,
+/// the `k` nearest by increasing distance then index
+fn brute_force(cloud: &[P3], query: &P3, k: usize, max_radius: f32) -> Vec<(u32, f32)> {
+    let mut found: Vec<(u32, f32)> = cloud
+        .iter()
+        .enumerate()
+        .map(|(index, point)| {
+            let dist2 = (0..3)
+                .map(|dim| (*point.get(dim) - *query.get(dim)).powi(2))
+                .sum();
+            (index as u32, dist2)
+        })
+        .filter(|(_, dist2)| *dist2 <= max_radius * max_radius)
+        .collect();
+    found.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+    found.truncate(k);
+    found
+}
+
+/// Returns the squared distances of `neighbours`, which are unique among ties unlike the indices
+fn dist2s(neighbours: &[Neighbour<f32, P3>]) -> Vec<f32> {
+    neighbours
+        .iter()
+        .map(|neighbour| neighbour.dist2.into_inner())
+        .collect()
+}
+
+#[test]
+fn approximate_parameters_are_rejected() {
+    let cloud: Vec<P3> = random_point_cloud(100);
+    let tree = KDTree::new(&cloud);
+    for epsilon in [0.5, f32::INFINITY] {
+        let parameters = Parameters {
+            epsilon,
+            ..Parameters::default()
+        };
+        assert_eq!(
+            parameters.validate(),
+            Err(ParameterError::ApproximateSearch)
+        );
+        assert_eq!(
+            Parameters::builder().epsilon(epsilon).build(),
+            Err(ParameterError::ApproximateSearch)
+        );
+        assert_eq!(
+            tree.try_knn_advanced(4, &cloud[0], CandidateContainer::Linear, &parameters, None),
+            Err(Error::Parameter(ParameterError::ApproximateSearch))
+        );
+        assert_eq!(
+            tree.try_prepare_parameters(&parameters),
+            Err(ParameterError::ApproximateSearch)
+        );
+        // the conversion shared by all searches reports the same error
+        assert_eq!(
+            InternalParameters::try_new(&parameters, NotNan::new(1.0).expect("one is not NaN")),
+            Err(ParameterError::ApproximateSearch)
+        );
+    }
+    // the invalid values keep their own errors
+    assert_eq!(
+        Parameters::<f32>::builder().epsilon(-1.0).build(),
+        Err(ParameterError::NegativeEpsilon)
+    );
+    assert_eq!(
+        Parameters::<f32>::builder().build(),
+        Ok(Parameters::default())
+    );
+}
+
+#[test]
+#[should_panic(expected = "epsilon must be zero in strict exact mode")]
+fn unvalidated_approximate_searches_panic() {
+    let cloud: Vec<P3> = random_point_cloud(100);
+    let parameters = Parameters {
+        epsilon: 0.5,
+        ..Parameters::default()
+    };
+    KDTree::new(&cloud).knn_advanced(4, &cloud[0], CandidateContainer::Linear, &parameters, None);
+}
+
+#[test]
+fn all_query_types_match_brute_force() {
+    let mut cloud: Vec<P3> = random_point_cloud(2000);
+    // duplicates and points on the planes of the splits
+    cloud.extend_from_within(..100);
+    cloud.extend((0..100).map(|i| P3::from([0.0, i as f32 - 50.0, 0.0])));
+    let mut queries: Vec<P3> = random_point_cloud(100);
+    queries.extend_from_slice(&cloud[..20]);
+    let seeded = BuildOptions {
+        bucket_size: 3,
+        seed: Some(247),
+        ..BuildOptions::default()
+    };
+    for tree in [
+        KDTree::new(&cloud),
+        KDTree::new_with_options(&cloud, &seeded),
+    ] {
+        for max_radius in [f32::INFINITY, 20.0] {
+            for traversal_order in [
+                TraversalOrder::NearChildFirst,
+                TraversalOrder::BoundOrdered,
+                TraversalOrder::BestFirst,
+            ] {
+                let parameters = Parameters {
+                    max_radius,
+                    traversal_order,
+                    ..Parameters::default()
+                };
+                for k in [1, 10, 50] {
+                    let expected: Vec<Vec<f32>> = queries
+                        .iter()
+                        .map(|query| {
+                            brute_force(&cloud, query, k as usize, max_radius)
+                                .iter()
+                                .map(|(_, dist2)| *dist2)
+                                .collect()
+                        })
+                        .collect();
+                    // knn, one query at a time and in batches
+                    for container in [CandidateContainer::Linear, CandidateContainer::BinaryHeap] {
+                        for (query, expected) in queries.iter().zip(&expected) {
+                            let found = tree.knn_advanced(k, query, container, &parameters, None);
+                            assert_eq!(&dist2s(&found), expected);
+                        }
+                        let batch = tree.knn_batch(k, &queries, container, &parameters);
+                        for (found, expected) in batch.iter().zip(&expected) {
+                            assert_eq!(&dist2s(found), expected);
+                        }
+                    }
+                }
+            }
+        }
+        for query in &queries {
+            // radius searches return all the points within the radius
+            let mut found: Vec<(u32, f32)> = tree
+                .radius_search_ordered(query, Radius::new(20.0))
+                .iter()
+                .map(|neighbour| (neighbour.index, neighbour.dist2.into_inner()))
+                .collect();
+            found.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+            assert_eq!(found, brute_force(&cloud, query, cloud.len(), 20.0));
+            // range queries return all the points within the box
+            let (min, max) = (
+                *query - P3::from([15.0, 10.0, 20.0]),
+                *query + P3::from([15.0, 10.0, 20.0]),
+            );
+            let mut found = tree.range_query(&min, &max);
+            found.sort_unstable();
+            let expected: Vec<u32> = (0..cloud.len() as u32)
+                .filter(|index| {
+                    let point = &cloud[*index as usize];
+                    (0..3)
+                        .all(|dim| point.get(dim) >= min.get(dim) && point.get(dim) <= max.get(dim))
+                })
+                .collect();
+            assert_eq!(found, expected);
+            assert_eq!(tree.range_count(&min, &max), expected.len());
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! Deterministic hash of the structure of a tree, for example to key caches of derived data
+
+use core::mem::size_of;
+
+use ordered_float::NotNan;
+
+use crate::{KDTree, Point, Scalar};
+
+/// A hasher consuming bytes, whose result only depends on the bytes
+trait ByteHasher {
+    fn write(&mut self, bytes: &[u8]);
+}
+
+/// The 64-bit FNV-1a hash
+struct Fnv1a64(u64);
+
+impl ByteHasher for Fnv1a64 {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
+
+/// The 128-bit FNV-1a hash
+struct Fnv1a128(u128);
+
+impl ByteHasher for Fnv1a128 {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 =
+                (self.0 ^ *byte as u128).wrapping_mul(0x0000_0000_0100_0000_0000_0000_0000_013b);
+        }
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Version of the input of [`Self::structural_hash`], incremented when it changes.
+    pub const STRUCTURAL_HASH_VERSION: u32 = 1;
+
+    /// Returns a 64-bit hash of the tree, identical on all platforms.
+    ///
+    /// The hash covers the bucket size, the nodes, the bit patterns of the stored coordinates,
+    /// the index in the original point cloud of each stored point, and the identifiers if any.
+    /// The other build options, such as the seed, are covered through the structure they lead to,
+    /// and data derived from the points, such as subtree sums, are not hashed,
+    /// so that trees exported and imported with [`Self::export_flat`] hash as the original.
+    /// Equal trees have the same hash, and a hash of the input point cloud misses
+    /// the options that change the neighbours found by approximate or bounded searches.
+    /// The input starts with [`Self::STRUCTURAL_HASH_VERSION`], which changes, along with the hashes,
+    /// only when a new version of the crate adds to or changes this input,
+    /// so hashes are stable with the crate until it does, which the changelog mentions.
+    /// The hasher, FNV-1a, is not keyed and not cryptographic.
+    pub fn structural_hash(&self) -> u64 {
+        let mut hasher = Fnv1a64(0xcbf2_9ce4_8422_2325);
+        self.hash_structure(&mut hasher);
+        hasher.0
+    }
+
+    /// Returns a 128-bit hash of the tree, see [`Self::structural_hash`], for caches with many entries.
+    pub fn structural_hash_128(&self) -> u128 {
+        let mut hasher = Fnv1a128(0x6c62_272e_07bb_0142_62b8_2175_6295_c58d);
+        self.hash_structure(&mut hasher);
+        hasher.0
+    }
+
+    /// Feeds the hashed content of the tree to `hasher`, as little-endian values
+    fn hash_structure(&self, hasher: &mut impl ByteHasher) {
+        write_u32(hasher, Self::STRUCTURAL_HASH_VERSION);
+        write_u32(hasher, P::DIM);
+        write_u32(hasher, size_of::<T>() as u32);
+        write_u32(hasher, self.options.bucket_size);
+        write_u32(hasher, self.nodes.len() as u32);
+        for node in self.nodes.iter() {
+            node.dispatch_on_type(
+                &mut *hasher,
+                |hasher, split_dim, split_val, right_child| {
+                    write_u32(hasher, split_dim);
+                    write_u32(hasher, right_child);
+                    write_scalar(hasher, split_val);
+                },
+                |hasher, bucket_start_index, bucket_size| {
+                    write_u32(hasher, u32::MAX);
+                    write_u32(hasher, bucket_start_index);
+                    write_u32(hasher, bucket_size);
+                },
+            );
+        }
+        write_u32(hasher, self.indices.len() as u32);
+        for value in &self.points {
+            write_scalar(hasher, *value);
+        }
+        for index in &self.indices {
+            write_u32(hasher, *index);
+        }
+        match &self.ids {
+            Some(ids) => {
+                hasher.write(&[1]);
+                for id in ids {
+                    hasher.write(&id.to_le_bytes());
+                }
+            }
+            None => hasher.write(&[0]),
+        }
+    }
+}
+
+fn write_u32(hasher: &mut impl ByteHasher, value: u32) {
+    hasher.write(&value.to_le_bytes());
+}
+
+/// Feeds the bit pattern of `value` to `hasher`, through its decoding so that it does not depend on `T`
+fn write_scalar<T: Scalar>(hasher: &mut impl ByteHasher, value: NotNan<T>) {
+    let (mantissa, exponent, sign) = value.into_inner().integer_decode();
+    hasher.write(&mantissa.to_le_bytes());
+    hasher.write(&exponent.to_le_bytes());
+    hasher.write(&sign.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    /// A fixed cloud, independent of the random generator of the tests
+    fn grid_cloud() -> Vec<P2> {
+        (0..400)
+            .map(|i| P2::new2d((i % 20) as f32 * 0.5, (i / 20) as f32 * 0.25 - 2.0))
+            .collect()
+    }
+
+    #[test]
+    fn structural_hashes_are_stable() {
+        let tree = KDTree::new(&grid_cloud());
+        // a change of these hashes invalidates the caches keyed by them, it must come with a new version
+        assert_eq!(KDTree::<f32, P2>::STRUCTURAL_HASH_VERSION, 1);
+        assert_eq!(tree.structural_hash(), 0xc6ec_768b_ffbf_7129);
+        assert_eq!(
+            tree.structural_hash_128(),
+            0x3ce9_a8cc_8bad_d2e4_f80f_46de_a1a7_a549
+        );
+        let empty = KDTree::<f32, P2>::new(&[]);
+        assert_ne!(empty.structural_hash(), tree.structural_hash());
+    }
+
+    #[test]
+    fn structural_hashes_survive_export_and_distinguish_trees() {
+        let cloud: Vec<P3> = random_point_cloud(2000);
+        let tree = KDTree::new(&cloud);
+        let imported = tree.export_flat().import::<P3>().unwrap();
+        assert_eq!(imported.structural_hash(), tree.structural_hash());
+        assert_eq!(imported.structural_hash_128(), tree.structural_hash_128());
+        let ids: Vec<u64> = (0..cloud.len() as u64).map(|id| id * 3).collect();
+        let with_ids = KDTree::new_with_ids(&cloud, &ids);
+        let imported = with_ids.export_flat().import::<P3>().unwrap();
+        assert_eq!(imported.structural_hash(), with_ids.structural_hash());
+        let mut hashes = vec![tree.structural_hash(), with_ids.structural_hash()];
+        for options in [
+            BuildOptions {
+                bucket_size: 4,
+                ..BuildOptions::default()
+            },
+            BuildOptions {
+                seed: Some(1),
+                ..BuildOptions::default()
+            },
+        ] {
+            hashes.push(KDTree::new_with_options(&cloud, &options).structural_hash());
+        }
+        let mut moved = tree.clone();
+        moved.update_point(0, random_point());
+        hashes.push(moved.structural_hash());
+        // options that only add derived data do not change the hash
+        let options = BuildOptions {
+            subtree_sums: true,
+            ..BuildOptions::default()
+        };
+        assert_eq!(
+            KDTree::new_with_options(&cloud, &options).structural_hash(),
+            tree.structural_hash()
+        );
+        hashes.sort_unstable();
+        hashes.dedup();
+        assert_eq!(hashes.len(), 5);
+    }
+}
@@ -0,0 +1,76 @@
+//! Radius-based thinning, keeping points at least a given distance apart
+
+use alloc::vec::Vec;
+
+use crate::{BitSet, KDTree, Point, Scalar};
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Keeps a maximal subset of points such that no two kept points are within distance `r`, inclusive,
+    /// and returns their indices in the original point cloud, in increasing order.
+    ///
+    /// Points are considered greedily by increasing index: a point is kept unless it is within `r`
+    /// of an already kept point, so of two conflicting points, the one of lower index is kept.
+    /// Duplicated points are thus reduced to their first occurrence.
+    ///
+    /// Panics if `r` is negative or NaN.
+    pub fn thin(&self, r: T) -> Vec<u32> {
+        let mut suppressed = BitSet::with_len(self.len());
+        let mut kept = Vec::new();
+        let mut neighbours = Vec::new();
+        for index in 0..self.len() as u32 {
+            if suppressed.contains(index as usize) {
+                continue;
+            }
+            kept.push(index);
+            neighbours.clear();
+            self.region_query(index, r, &mut suppressed, true, &mut neighbours);
+        }
+        kept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    fn dist2(a: &P2, b: &P2) -> f32 {
+        let dx = *a.get(0) - *b.get(0);
+        let dy = *a.get(1) - *b.get(1);
+        dx * dx + dy * dy
+    }
+
+    #[test]
+    fn thin_matches_greedy_brute_force() {
+        let cloud: Vec<P2> = random_point_cloud(2000);
+        let tree = KDTree::new(&cloud);
+        let r = 5.0;
+        let mut expected: Vec<u32> = Vec::new();
+        for (index, point) in cloud.iter().enumerate() {
+            if expected
+                .iter()
+                .all(|kept| dist2(&cloud[*kept as usize], point) > r * r)
+            {
+                expected.push(index as u32);
+            }
+        }
+        let kept = tree.thin(r);
+        assert_eq!(kept, expected);
+        assert_eq!(kept, tree.thin(r));
+    }
+
+    #[test]
+    fn thin_keeps_lower_index() {
+        let cloud = vec![
+            P2::new2d(1.0, 0.0),
+            P2::new2d(0.0, 0.0),
+            P2::new2d(1.0, 0.0),
+            P2::new2d(2.0, 0.0),
+            P2::new2d(10.0, 0.0),
+        ];
+        let tree = KDTree::new(&cloud);
+        assert_eq!(tree.thin(1.0), vec![0, 4]);
+        assert_eq!(tree.thin(0.5), vec![0, 1, 3, 4]);
+        assert_eq!(tree.thin(0.0), vec![0, 1, 3, 4]);
+    }
+}
@@ -0,0 +1,485 @@
+//! Timestamps of the points, to search the points of a time interval without a tree per interval
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+
+use num_traits::{clamp_max, clamp_min, Zero};
+use ordered_float::NotNan;
+
+use crate::heap::{CandidateHeap, ExcludingCollector};
+use crate::internal_parameters::InternalParameters;
+use crate::{
+    leaf_scan, update_rd, BuildError, CandidateCollector, InternalNeighbour, KDTree, Neighbour,
+    Parameters, Point, Scalar,
+};
+
+/// The timestamps of the points and the range of timestamps below each node
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Timestamps<T: Scalar> {
+    /// the timestamps by internal index
+    values: Vec<NotNan<T>>,
+    /// the smallest and largest timestamp of the points below each node, `None` if it has no points
+    ranges: Vec<Option<(NotNan<T>, NotNan<T>)>>,
+}
+
+impl<T: Scalar> Timestamps<T> {
+    /// Whether a point below `node` may have a timestamp within `[start, end]`
+    fn intersects(&self, node: usize, start: NotNan<T>, end: NotNan<T>) -> bool {
+        match self.ranges[node] {
+            Some((min, max)) => min <= end && max >= start,
+            None => false,
+        }
+    }
+}
+
+/// Keeps the candidates of a collector whose timestamp is within an interval
+struct InInterval<'a, T: Scalar, H> {
+    heap: &'a mut H,
+    values: &'a [NotNan<T>],
+    start: NotNan<T>,
+    end: NotNan<T>,
+}
+
+impl<'a, T: Scalar, H: CandidateCollector<T>> CandidateCollector<T> for InInterval<'a, T, H> {
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        let timestamp = self.values[index as usize];
+        if timestamp >= self.start && timestamp <= self.end {
+            self.heap.add(dist2, index);
+        }
+    }
+
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        self.heap.furthest_dist2()
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Returns the tree with `timestamps[i]` as the timestamp of the point of index `i` in the original point cloud,
+    /// for the searches of [`Self::knn_in_interval`].
+    ///
+    /// Panics if `timestamps` does not have one value per point or has a NaN, see [`Self::try_with_timestamps`].
+    pub fn with_timestamps(self, timestamps: &[T]) -> Self {
+        self.try_with_timestamps(timestamps)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Returns the tree with `timestamps[i]` as the timestamp of the point of index `i` in the original point cloud,
+    /// or [`BuildError::ValuesLengthMismatch`] if `timestamps` does not have one value per point
+    /// and [`BuildError::NanTimestamp`] if one is NaN.
+    ///
+    /// The timestamps are stored in the order of the points in the tree, with the range of timestamps
+    /// below each node, which costs two timestamps per node.
+    /// They are kept by [`Self::apply_batch_with_timestamps`], updates, rebuilds, merges of trees that all have
+    /// timestamps and extractions, but not by flat exports.
+    pub fn try_with_timestamps(mut self, timestamps: &[T]) -> Result<Self, BuildError> {
+        if timestamps.len() != self.len() {
+            return Err(BuildError::ValuesLengthMismatch {
+                points: self.len(),
+                values: timestamps.len(),
+            });
+        }
+        let timestamps = checked_timestamps(timestamps)?;
+        self.set_external_timestamps(Some(timestamps));
+        Ok(self)
+    }
+
+    /// Returns whether the tree has timestamps, see [`Self::with_timestamps`].
+    pub fn has_timestamps(&self) -> bool {
+        self.timestamps.is_some()
+    }
+
+    /// Returns the timestamp of the point of index `external_index` in the original point cloud,
+    /// or `None` if the tree has no timestamps.
+    ///
+    /// Panics if `external_index` is not smaller than [`Self::len`].
+    pub fn timestamp_of(&self, external_index: u32) -> Option<T> {
+        let internal_index = self.internalise_index(external_index) as usize;
+        self.timestamps
+            .as_ref()
+            .map(|timestamps| timestamps.values[internal_index].into_inner())
+    }
+
+    /// Returns the smallest and largest timestamps of the points, or `None` if the tree has no timestamps or no points.
+    pub fn time_range(&self) -> Option<(T, T)> {
+        let (min, max) = (*self.timestamps.as_ref()?.ranges.first()?)?;
+        Some((min.into_inner(), max.into_inner()))
+    }
+
+    /// Finds the `k` nearest neighbours of `query` among the points whose timestamp is within
+    /// the closed interval `[start, end]`, see [`Self::with_timestamps`].
+    ///
+    /// The search skips the subtrees whose range of timestamps misses the interval before computing any distance,
+    /// so a short interval costs about as much as a search in a tree of its points.
+    /// It follows the near child first, whatever `parameters.traversal_order`, and returns fewer than `k` neighbours
+    /// if fewer points are within the interval and `max_radius`, whatever `parameters.on_insufficient`.
+    /// An interval beyond the timestamps of the points or with `start` after `end` finds no neighbour.
+    ///
+    /// Panics if the tree has no timestamps, or if the parameters are invalid, see [`Parameters::validate`].
+    pub fn knn_in_interval(
+        &self,
+        k: u32,
+        query: &P,
+        start: T,
+        end: T,
+        parameters: &Parameters<T>,
+    ) -> Vec<Neighbour<T, P>> {
+        parameters
+            .validate()
+            .unwrap_or_else(|error| panic!("{}", error));
+        let timestamps = self
+            .timestamps
+            .as_ref()
+            .expect("the tree has no timestamps, see KDTree::with_timestamps");
+        let coordinates = self.query_coordinates(query);
+        let (start, end) = match (NotNan::new(start), NotNan::new(end)) {
+            (Ok(start), Ok(end)) => (start, end),
+            _ => return Vec::new(),
+        };
+        if self.nodes.is_empty() || k == 0 || !timestamps.intersects(0, start, end) {
+            return Vec::new();
+        }
+        let internal_parameters = InternalParameters::new(parameters, self.scale);
+        let mut heap = BinaryHeap::<InternalNeighbour<T>>::new_with_k(k.min(self.len() as u32));
+        let mut excluding = ExcludingCollector {
+            inner: &mut heap,
+            excluded: internal_parameters
+                .excluded
+                .filter(|index| (*index as usize) < self.len())
+                .map(|index| self.internalise_index(index)),
+        };
+        let mut filtered = InInterval {
+            heap: &mut excluding,
+            values: &timestamps.values,
+            start,
+            end,
+        };
+        let mut off = alloc::vec![NotNan::<T>::zero(); P::DIM as usize];
+        self.recurse_in_interval(
+            timestamps,
+            &coordinates,
+            0,
+            NotNan::<T>::zero(),
+            &mut filtered,
+            &mut off,
+            &internal_parameters,
+        );
+        let mut results = heap.into_vec();
+        if parameters.sort_results {
+            self.sort_ties_by_external_index(&mut results);
+        }
+        results
+            .into_iter()
+            .map(|neighbour| self.externalise_neighbour(neighbour))
+            .collect()
+    }
+
+    /// Searches below `node` as [`Self::recurse_knn`] does near child first, skipping the children
+    /// whose timestamps miss the interval of `heap`
+    #[allow(clippy::too_many_arguments)]
+    fn recurse_in_interval<H: CandidateCollector<T>>(
+        &self,
+        timestamps: &Timestamps<T>,
+        query: &[NotNan<T>],
+        node: usize,
+        rd: NotNan<T>,
+        heap: &mut InInterval<'_, T, H>,
+        off: &mut [NotNan<T>],
+        internal_parameters: &InternalParameters<T>,
+    ) {
+        self.nodes[node].dispatch_on_type(
+            (heap, off),
+            |(heap, off), split_dim, split_val, right_child| {
+                let (start, end) = (heap.start, heap.end);
+                let split_dim = split_dim as usize;
+                let old_off = off[split_dim];
+                let new_off = (query[split_dim] - split_val) * self.scale;
+                // points that drifted across the split can be closer than the split plane
+                let far_off = if self.scaled_drift == NotNan::<T>::zero() {
+                    new_off
+                } else if new_off > NotNan::<T>::zero() {
+                    clamp_min(new_off - self.scaled_drift, NotNan::<T>::zero())
+                } else {
+                    clamp_max(new_off + self.scaled_drift, NotNan::<T>::zero())
+                };
+                let (near_child, far_child) = if new_off <= NotNan::<T>::zero() {
+                    (node + 1, right_child as usize)
+                } else {
+                    (right_child as usize, node + 1)
+                };
+                if timestamps.intersects(near_child, start, end) {
+                    self.recurse_in_interval(
+                        timestamps,
+                        query,
+                        near_child,
+                        rd,
+                        heap,
+                        off,
+                        internal_parameters,
+                    );
+                }
+                if !timestamps.intersects(far_child, start, end) {
+                    return;
+                }
+                let far_rd = update_rd(rd, old_off, far_off);
+                if far_rd <= internal_parameters.max_radius2
+                    && internal_parameters.within_error(far_rd, heap.furthest_dist2())
+                {
+                    off[split_dim] = far_off;
+                    self.recurse_in_interval(
+                        timestamps,
+                        query,
+                        far_child,
+                        far_rd,
+                        heap,
+                        off,
+                        internal_parameters,
+                    );
+                    off[split_dim] = old_off;
+                }
+            },
+            |(heap, _), bucket_start_index, bucket_size| {
+                let dim = P::DIM as usize;
+                let bucket_end_index = bucket_start_index + bucket_size;
+                let bucket = &self.points
+                    [bucket_start_index as usize * dim..bucket_end_index as usize * dim];
+                leaf_scan::scan_leaf::<T, P, _>(
+                    heap,
+                    query,
+                    bucket,
+                    bucket_start_index,
+                    self.scale,
+                    internal_parameters,
+                );
+            },
+        )
+    }
+
+    /// Returns the timestamps in the order of the original point cloud, if the tree has some
+    pub(crate) fn external_timestamps(&self) -> Option<Vec<NotNan<T>>> {
+        let timestamps = self.timestamps.as_ref()?;
+        Some(
+            self.internal_indices
+                .iter()
+                .map(|internal| timestamps.values[*internal as usize])
+                .collect(),
+        )
+    }
+
+    /// Returns the timestamps of the points of `trees`, one tree after the other,
+    /// if all of them have timestamps
+    pub(crate) fn concatenated_timestamps<'a>(
+        trees: impl Iterator<Item = &'a Self> + Clone,
+    ) -> Option<Vec<NotNan<T>>>
+    where
+        T: 'a,
+        P: 'a,
+    {
+        if !trees.clone().all(KDTree::has_timestamps) {
+            return None;
+        }
+        Some(
+            trees
+                .flat_map(|tree| {
+                    tree.external_timestamps()
+                        .expect("all trees have timestamps")
+                })
+                .collect(),
+        )
+    }
+
+    /// Stores `timestamps`, given in the order of the original point cloud, in the internal order,
+    /// and computes their ranges per node
+    pub(crate) fn set_external_timestamps(&mut self, timestamps: Option<Vec<NotNan<T>>>) {
+        self.timestamps = timestamps.map(|timestamps| {
+            let values = self
+                .indices
+                .iter()
+                .map(|external| timestamps[*external as usize])
+                .collect();
+            Timestamps {
+                values,
+                ranges: Vec::new(),
+            }
+        });
+        self.refresh_time_ranges();
+    }
+
+    /// Recomputes the ranges of timestamps per node after the nodes changed, if the tree has timestamps
+    pub(crate) fn refresh_time_ranges(&mut self) {
+        let mut timestamps = match self.timestamps.take() {
+            Some(timestamps) => timestamps,
+            None => return,
+        };
+        let mut ranges = alloc::vec![None; self.nodes.len()];
+        // children follow their parent, so they are computed first in reverse order
+        for node in (0..self.nodes.len()).rev() {
+            ranges[node] = self.nodes[node].dispatch_on_type(
+                &ranges,
+                |ranges, _, _, right_child| union(ranges[node + 1], ranges[right_child as usize]),
+                |_, bucket_start_index, bucket_size| {
+                    let bucket =
+                        bucket_start_index as usize..(bucket_start_index + bucket_size) as usize;
+                    timestamps.values[bucket]
+                        .iter()
+                        .fold(None, |range, value| union(range, Some((*value, *value))))
+                },
+            );
+        }
+        timestamps.ranges = ranges;
+        self.timestamps = Some(timestamps);
+    }
+}
+
+/// Returns `timestamps` as not NaN, or the error of the first NaN
+pub(crate) fn checked_timestamps<T: Scalar>(
+    timestamps: &[T],
+) -> Result<Vec<NotNan<T>>, BuildError> {
+    timestamps
+        .iter()
+        .enumerate()
+        .map(|(index, timestamp)| {
+            NotNan::new(*timestamp).map_err(|_| BuildError::NanTimestamp {
+                index: index as u32,
+            })
+        })
+        .collect()
+}
+
+/// Returns the smallest range containing `a` and `b`
+fn union<T: Scalar>(
+    a: Option<(NotNan<T>, NotNan<T>)>,
+    b: Option<(NotNan<T>, NotNan<T>)>,
+) -> Option<(NotNan<T>, NotNan<T>)> {
+    match (a, b) {
+        (Some((a_min, a_max)), Some((b_min, b_max))) => Some((a_min.min(b_min), a_max.max(b_max))),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    /// Returns the squared distances of the `k` nearest points of `query` with timestamps in `[start, end]`
+    fn brute_force(
+        cloud: &[P2],
+        timestamps: &[f32],
+        query: &P2,
+        start: f32,
+        end: f32,
+        k: usize,
+    ) -> Vec<f32> {
+        let mut dist2: Vec<f32> = cloud
+            .iter()
+            .zip(timestamps)
+            .filter(|(_, t)| **t >= start && **t <= end)
+            .map(|(point, _)| {
+                let (dx, dy) = (*point.get(0) - *query.get(0), *point.get(1) - *query.get(1));
+                dx * dx + dy * dy
+            })
+            .collect();
+        dist2.sort_unstable_by(f32::total_cmp);
+        dist2.truncate(k);
+        dist2
+    }
+
+    fn check_intervals(tree: &KDTree<f32, P2>, cloud: &[P2], timestamps: &[f32]) {
+        for (start, end) in [(0.0, 1000.0), (100.0, 150.0), (499.5, 500.5), (-10.0, 3.0)] {
+            for query in (0..20).map(|_| random_point::<2>()) {
+                let neighbours =
+                    tree.knn_in_interval(8, &query, start, end, &Parameters::default());
+                let dist2: Vec<f32> = neighbours.iter().map(|n| *n.dist2).collect();
+                assert_eq!(dist2, brute_force(cloud, timestamps, &query, start, end, 8));
+                for neighbour in neighbours {
+                    let timestamp = timestamps[neighbour.index as usize];
+                    assert_eq!(tree.timestamp_of(neighbour.index), Some(timestamp));
+                    assert_eq!(neighbour.point, cloud[neighbour.index as usize]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn interval_searches_match_brute_force() {
+        let cloud: Vec<P2> = random_point_cloud(2000);
+        let timestamps: Vec<f32> = (0..2000).map(|i| (i / 2) as f32).collect();
+        let tree = KDTree::new(&cloud).with_timestamps(&timestamps);
+        assert!(tree.has_timestamps());
+        assert_eq!(tree.time_range(), Some((0.0, 999.0)));
+        check_intervals(&tree, &cloud, &timestamps);
+        // intervals without points find nothing
+        let query = random_point::<2>();
+        let parameters = Parameters::default();
+        assert!(tree
+            .knn_in_interval(5, &query, 2000.0, 3000.0, &parameters)
+            .is_empty());
+        assert!(tree
+            .knn_in_interval(5, &query, -5.0, -1.0, &parameters)
+            .is_empty());
+        assert!(tree
+            .knn_in_interval(5, &query, 10.0, 5.0, &parameters)
+            .is_empty());
+        assert!(tree
+            .knn_in_interval(5, &query, 10.2, 10.8, &parameters)
+            .is_empty());
+        assert!(tree
+            .knn_in_interval(5, &query, f32::NAN, 10.0, &parameters)
+            .is_empty());
+        // invalid timestamps
+        assert_eq!(
+            KDTree::new(&cloud)
+                .try_with_timestamps(&timestamps[1..])
+                .err(),
+            Some(BuildError::ValuesLengthMismatch {
+                points: 2000,
+                values: 1999
+            })
+        );
+        let mut invalid = timestamps.clone();
+        invalid[7] = f32::NAN;
+        assert_eq!(
+            KDTree::new(&cloud).try_with_timestamps(&invalid).err(),
+            Some(BuildError::NanTimestamp { index: 7 })
+        );
+        assert_eq!(KDTree::new(&cloud).time_range(), None);
+    }
+
+    #[test]
+    fn mutations_keep_the_time_ranges() {
+        let mut cloud: Vec<P2> = random_point_cloud(1000);
+        let mut timestamps: Vec<f32> = (0..1000).map(|i| i as f32 * 0.5).collect();
+        let mut tree = KDTree::new(&cloud).with_timestamps(&timestamps);
+        // inserted points take the indices of removed ones, then the following ones
+        let inserted: Vec<P2> = random_point_cloud(300);
+        let inserted_timestamps: Vec<f32> = (0..300).map(|i| 100.0 + i as f32 * 0.01).collect();
+        let indices = tree.apply_batch_with_timestamps(&[5, 9], &inserted, &inserted_timestamps);
+        cloud.extend_from_slice(&inserted[2..]);
+        timestamps.extend_from_slice(&inserted_timestamps[2..]);
+        for (position, index) in indices.inserted.iter().enumerate().take(2) {
+            cloud[*index as usize] = inserted[position];
+            timestamps[*index as usize] = inserted_timestamps[position];
+        }
+        assert_eq!(tree.points(), cloud);
+        check_intervals(&tree, &cloud, &timestamps);
+        // moving points across leaves, rebuilding and merging
+        for index in (0..cloud.len()).step_by(7) {
+            cloud[index] = random_point();
+            tree.update_point(index as u32, cloud[index]);
+        }
+        check_intervals(&tree, &cloud, &timestamps);
+        tree.rebuild();
+        check_intervals(&tree, &cloud, &timestamps);
+        let other: Vec<P2> = random_point_cloud(50);
+        let other_timestamps = vec![600.0; 50];
+        let merged = KDTree::merge(
+            tree.clone(),
+            KDTree::new(&other).with_timestamps(&other_timestamps),
+        );
+        cloud.extend_from_slice(&other);
+        timestamps.extend_from_slice(&other_timestamps);
+        assert_eq!(merged.time_range(), Some((0.0, 600.0)));
+        check_intervals(&merged, &cloud, &timestamps);
+        assert!(!KDTree::merge(tree, KDTree::new(&other)).has_timestamps());
+    }
+}
@@ -0,0 +1,297 @@
+//! The orders in which searches visit the nodes of a tree, see [`TraversalOrder`](crate::TraversalOrder)
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+use core::cmp::Reverse;
+
+use num_traits::{clamp_max, clamp_min, Zero};
+use ordered_float::NotNan;
+
+use crate::cell::NodeCell;
+use crate::internal_parameters::InternalParameters;
+use crate::leaf_scan;
+use crate::query_trace::NodeVisitor;
+use crate::{update_rd, CandidateCollector, KDTree, Point, QueryStats, Scalar};
+
+/// How a depth-first search bounds the squared distance from the query to the children of a split node
+///
+/// The bounds start from `rd`, the squared distance to the split planes above the child,
+/// and can only be tighter. Implementations are chosen statically, so that the bounds
+/// of the default order compile to nothing.
+pub(crate) trait ChildBounds<T: Scalar> {
+    /// Whether the near child can have a bound above the one of its parent, and must thus be checked
+    const PRUNES_NEAR: bool;
+    /// The state of the split dimension to restore after visiting the children
+    type Saved: Copy;
+
+    fn save(&self, dim: usize) -> Self::Saved;
+
+    /// Restricts the state to a child of a split along `dim`, `parent` being the state of the split node,
+    /// and returns the bound of the child
+    #[allow(clippy::too_many_arguments)]
+    fn enter<P: Point<T>>(
+        &mut self,
+        tree: &KDTree<T, P>,
+        query: &[NotNan<T>],
+        dim: usize,
+        parent: Self::Saved,
+        split_val: NotNan<T>,
+        left: bool,
+        rd: NotNan<T>,
+    ) -> NotNan<T>;
+
+    fn restore(&mut self, dim: usize, parent: Self::Saved);
+}
+
+/// The bounds of [`TraversalOrder::NearChildFirst`](crate::TraversalOrder::NearChildFirst), the split planes alone
+impl<T: Scalar> ChildBounds<T> for () {
+    const PRUNES_NEAR: bool = false;
+    type Saved = ();
+
+    #[inline]
+    fn save(&self, _: usize) {}
+
+    #[inline]
+    fn enter<P: Point<T>>(
+        &mut self,
+        _: &KDTree<T, P>,
+        _: &[NotNan<T>],
+        _: usize,
+        _: (),
+        _: NotNan<T>,
+        _: bool,
+        rd: NotNan<T>,
+    ) -> NotNan<T> {
+        rd
+    }
+
+    #[inline]
+    fn restore(&mut self, _: usize, _: ()) {}
+}
+
+/// The bounds of [`TraversalOrder::BoundOrdered`](crate::TraversalOrder::BoundOrdered), the cells of the nodes
+impl<T: Scalar> ChildBounds<T> for NodeCell<T> {
+    const PRUNES_NEAR: bool = true;
+    type Saved = (NotNan<T>, NotNan<T>);
+
+    #[inline]
+    fn save(&self, dim: usize) -> Self::Saved {
+        self.extent(dim)
+    }
+
+    #[inline]
+    fn enter<P: Point<T>>(
+        &mut self,
+        tree: &KDTree<T, P>,
+        query: &[NotNan<T>],
+        dim: usize,
+        parent: Self::Saved,
+        split_val: NotNan<T>,
+        left: bool,
+        rd: NotNan<T>,
+    ) -> NotNan<T> {
+        self.set_child(dim, parent, split_val, tree.drift, left);
+        rd.max(self.dist2_range(query, tree.scale).0)
+    }
+
+    #[inline]
+    fn restore(&mut self, dim: usize, parent: Self::Saved) {
+        self.set_extent(dim, parent);
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Searches the tree best-first, see [`TraversalOrder::BestFirst`](crate::TraversalOrder::BestFirst)
+    ///
+    /// Pending nodes are kept by the squared distance to their split planes, with their offsets
+    /// to these planes in slots of `offsets`. Each popped node is descended to its nearest leaf,
+    /// queuing the far children on the way, until the nearest pending node can be pruned.
+    /// No error factor is applied to the bounds if `EXACT`.
+    pub(crate) fn best_first_knn<H: CandidateCollector<T>, V: NodeVisitor<T>, const EXACT: bool>(
+        &self,
+        heap: &mut H,
+        visitor: &mut V,
+        query: &[NotNan<T>],
+        internal_parameters: &InternalParameters<T>,
+    ) -> QueryStats {
+        let dim = P::DIM as usize;
+        let zero = NotNan::<T>::zero();
+        let max_radius2 = internal_parameters.max_radius2;
+        let mut pending = BinaryHeap::new();
+        pending.push(Reverse((zero, 0u32, 0u32)));
+        let mut offsets = alloc::vec![zero; dim];
+        let mut free_slots: Vec<u32> = Vec::new();
+        let mut stats = QueryStats::default();
+        while let Some(Reverse((rd, mut node, slot))) = pending.pop() {
+            let bound2 = heap.furthest_dist2();
+            // the other pending nodes are further, so they are pruned too
+            let descend =
+                rd <= max_radius2 && internal_parameters.within_error_of::<EXACT>(rd, bound2);
+            if V::ENABLED {
+                visitor.visit(node as usize, rd, bound2, descend);
+            }
+            if !descend {
+                break;
+            }
+            while let Some((split_dim, split_val, right_child)) = self.nodes[node as usize]
+                .dispatch_on_type(
+                    (),
+                    |_, split_dim, split_val, right_child| {
+                        Some((split_dim, split_val, right_child))
+                    },
+                    |_, _, _| None,
+                )
+            {
+                stats += QueryStats::split();
+                let split_dim = split_dim as usize;
+                let old_off = offsets[slot as usize * dim + split_dim];
+                let new_off = (query[split_dim] - split_val) * self.scale;
+                // points that drifted across the split can be closer than the split plane
+                let far_off = if self.scaled_drift == zero {
+                    new_off
+                } else if new_off > zero {
+                    clamp_min(new_off - self.scaled_drift, zero)
+                } else {
+                    clamp_max(new_off + self.scaled_drift, zero)
+                };
+                let (near, far) = if new_off > zero {
+                    (right_child, node + 1)
+                } else {
+                    (node + 1, right_child)
+                };
+                let far_rd = update_rd(rd, old_off, far_off);
+                if far_rd <= max_radius2 {
+                    let start = slot as usize * dim;
+                    let far_slot = match free_slots.pop() {
+                        Some(far_slot) => {
+                            offsets.copy_within(start..start + dim, far_slot as usize * dim);
+                            far_slot
+                        }
+                        None => {
+                            offsets.extend_from_within(start..start + dim);
+                            (offsets.len() / dim - 1) as u32
+                        }
+                    };
+                    offsets[far_slot as usize * dim + split_dim] = far_off;
+                    pending.push(Reverse((far_rd, far, far_slot)));
+                } else if V::ENABLED {
+                    visitor.visit(far as usize, far_rd, heap.furthest_dist2(), false);
+                }
+                if V::ENABLED {
+                    visitor.visit(near as usize, rd, heap.furthest_dist2(), true);
+                }
+                node = near;
+            }
+            free_slots.push(slot);
+            let (bucket_start_index, bucket_size) = self.nodes[node as usize].dispatch_on_type(
+                (),
+                |_, _, _, _| unreachable!("the descent stops at a leaf"),
+                |_, bucket_start_index, bucket_size| (bucket_start_index, bucket_size),
+            );
+            let bucket_end_index = bucket_start_index + bucket_size;
+            let bucket =
+                &self.points[bucket_start_index as usize * dim..bucket_end_index as usize * dim];
+            leaf_scan::scan_leaf::<T, P, H>(
+                heap,
+                query,
+                bucket,
+                bucket_start_index,
+                self.scale,
+                internal_parameters,
+            );
+            stats += QueryStats::leaf(bucket_size);
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    const ORDERS: [TraversalOrder; 3] = [
+        TraversalOrder::NearChildFirst,
+        TraversalOrder::BoundOrdered,
+        TraversalOrder::BestFirst,
+    ];
+
+    fn knn_with_order(
+        tree: &KDTree<f32, P2>,
+        k: u32,
+        query: &P2,
+        parameters: &Parameters<f32>,
+        traversal_order: TraversalOrder,
+    ) -> (Vec<Neighbour<f32, P2>>, QueryStats) {
+        let parameters = Parameters {
+            traversal_order,
+            ..*parameters
+        };
+        tree.knn_with_stats(k, query, CandidateContainer::BinaryHeap, &parameters)
+    }
+
+    #[test]
+    fn all_orders_find_the_same_neighbours() {
+        let cloud: Vec<P2> = random_point_cloud(3000);
+        let mut drifted = KDTree::new(&cloud);
+        for index in 0..100 {
+            drifted.update_point(index, random_point());
+        }
+        for tree in [KDTree::new(&cloud), drifted, KDTree::new(&[])] {
+            for _ in 0..50 {
+                // queries inside and outside the cloud
+                let query = random_point();
+                let far_query = P2::new2d(*query.get(0) * 3.0, *query.get(1) * 3.0);
+                for (k, max_radius, allow_self_match) in [
+                    (1, f32::INFINITY, true),
+                    (8, f32::INFINITY, false),
+                    (30, 20.0, true),
+                    (0, f32::INFINITY, true),
+                ] {
+                    let parameters = Parameters {
+                        max_radius,
+                        self_match: allow_self_match.into(),
+                        ..Parameters::default()
+                    };
+                    for query in [&query, &far_query] {
+                        let (expected, _) = knn_with_order(
+                            &tree,
+                            k,
+                            query,
+                            &parameters,
+                            TraversalOrder::NearChildFirst,
+                        );
+                        for order in ORDERS {
+                            let (found, _) = knn_with_order(&tree, k, query, &parameters, order);
+                            assert_eq!(found, expected, "{:?}", order);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn orders_visit_fewer_nodes_where_expected() {
+        let cloud: Vec<P2> = random_point_cloud(10000);
+        let tree = KDTree::new(&cloud);
+        let mut visited = [[0u32; 2]; 3];
+        for _ in 0..200 {
+            let query = random_point();
+            // the cloud spans [-100, 100]², queries with a radius from outside it are often empty
+            let outside = P2::new2d(*query.get(0) + 250.0, *query.get(1));
+            let radius = Parameters {
+                max_radius: 160.0,
+                ..Parameters::default()
+            };
+            for (order, visited) in ORDERS.iter().zip(&mut visited) {
+                let (_, stats) = knn_with_order(&tree, 1, &outside, &radius, *order);
+                visited[0] += stats.visited_nodes;
+                let (_, stats) = knn_with_order(&tree, 50, &query, &Parameters::default(), *order);
+                visited[1] += stats.visited_leaves;
+            }
+        }
+        let [near_child_first, bound_ordered, best_first] = visited;
+        assert!(bound_ordered[0] < near_child_first[0]);
+        assert!(best_first[1] <= near_child_first[1]);
+    }
+}
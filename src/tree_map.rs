@@ -0,0 +1,347 @@
+//! Trees owning a payload per point
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+
+use crate::heap::CandidateHeap;
+use crate::{
+    copy_point, BatchIndices, BuildError, BuildOptions, CandidateCollector, CandidateContainer,
+    FlatTree, InternalNeighbour, KDTree, Neighbour, Parameters, Point, Scalar, UpdateSummary,
+};
+
+/// A KDTree owning a value per point, stored in the same order as the points of the tree
+///
+/// Values are accessed by the index of their point in the original point cloud,
+/// and queries return them along with the neighbours.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KDTreeMap<T: Scalar, P: Point<T>, V> {
+    tree: KDTree<T, P>,
+    /// values, in the internal order of the tree
+    values: Vec<V>,
+}
+
+impl<T: Scalar, P: Point<T>, V: Clone> KDTreeMap<T, P, V> {
+    /// Creates a new map from pairs of points and values.
+    ///
+    /// Panics in the same cases as [`KDTree::new`].
+    pub fn new(entries: &[(P, V)]) -> Self {
+        let cloud: Vec<P> = entries.iter().map(|(point, _)| copy_point(point)).collect();
+        let values = entries.iter().map(|(_, value)| value.clone()).collect();
+        Self::from_parts(&cloud, values, &BuildOptions::default())
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Removes the entries of indices `removals` in the original point cloud and inserts `insertions`,
+    /// keeping each value with its point, see [`KDTree::apply_batch`].
+    ///
+    /// The returned indices give the new index of the inserted and former entries,
+    /// as the entries of the largest indices move to the freed ones if fewer are inserted than removed.
+    /// Panics in the same cases as [`KDTree::apply_batch`].
+    pub fn apply_batch(&mut self, removals: &[u32], insertions: &[(P, V)]) -> BatchIndices {
+        let old_internal_indices = self.tree.internal_indices.clone();
+        let cloud: Vec<P> = insertions
+            .iter()
+            .map(|(point, _)| copy_point(point))
+            .collect();
+        let indices = self.tree.apply_batch(removals, &cloud);
+        let mut old_values: Vec<Option<V>> = core::mem::take(&mut self.values)
+            .into_iter()
+            .map(Some)
+            .collect();
+        // values by new index in the original point cloud
+        let mut values: Vec<Option<V>> = (0..self.tree.len()).map(|_| None).collect();
+        for (old_index, new_index) in indices.renumbered.iter().enumerate() {
+            if let Some(new_index) = new_index {
+                let old_internal = old_internal_indices[old_index] as usize;
+                values[*new_index as usize] = old_values[old_internal].take();
+            }
+        }
+        for ((_, value), new_index) in insertions.iter().zip(&indices.inserted) {
+            values[*new_index as usize] = Some(value.clone());
+        }
+        self.values = self
+            .tree
+            .externalisation_map()
+            .iter()
+            .map(|index| {
+                values[*index as usize]
+                    .take()
+                    .expect("every point has a value")
+            })
+            .collect();
+        indices
+    }
+}
+
+impl<T: Scalar, P: Point<T>, V> KDTreeMap<T, P, V> {
+    /// Creates a new map where `values[i]` is the value of `cloud[i]`, with user-provided options.
+    ///
+    /// Returns an error if `values` does not have the same length as `cloud`,
+    /// or in the same cases as [`KDTree::try_new_with_options`].
+    pub fn from_parts(
+        cloud: &[P],
+        values: Vec<V>,
+        options: &BuildOptions,
+    ) -> Result<Self, BuildError> {
+        if values.len() != cloud.len() {
+            return Err(BuildError::ValuesLengthMismatch {
+                points: cloud.len(),
+                values: values.len(),
+            });
+        }
+        let tree = KDTree::try_new_with_options(cloud, options)?;
+        let values = reorder(values, tree.externalisation_map());
+        Ok(KDTreeMap { tree, values })
+    }
+
+    /// Returns the underlying tree.
+    pub fn tree(&self) -> &KDTree<T, P> {
+        &self.tree
+    }
+
+    /// Returns the number of entries.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Returns whether the map has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Returns the value of the point of index `index` in the original point cloud.
+    ///
+    /// Panics if `index` is out of range.
+    pub fn get(&self, index: u32) -> &V {
+        &self.values[self.tree.internalise_index(index) as usize]
+    }
+
+    /// Returns the value of the point of index `index` in the original point cloud, mutably.
+    ///
+    /// Panics if `index` is out of range.
+    pub fn get_mut(&mut self, index: u32) -> &mut V {
+        &mut self.values[self.tree.internalise_index(index) as usize]
+    }
+
+    /// Moves the points of `updates`, pairs of an index in the original point cloud and a new position,
+    /// keeping each value with its point, see [`KDTree::update_points`].
+    ///
+    /// Panics in the same cases as [`KDTree::update_points`].
+    pub fn update_points(&mut self, updates: &[(u32, P)]) -> UpdateSummary {
+        let old_internal_indices = self.tree.internal_indices.clone();
+        let summary = self.tree.update_points(updates);
+        // the relocated points change their internal index
+        if summary.relocated > 0 {
+            let indices: Vec<u32> = self
+                .tree
+                .externalisation_map()
+                .iter()
+                .map(|index| old_internal_indices[*index as usize])
+                .collect();
+            self.values = reorder(core::mem::take(&mut self.values), &indices);
+        }
+        summary
+    }
+
+    /// Iterates over the values mutably, in the internal order of the tree,
+    /// with the index of their point in the original point cloud.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (u32, &mut V)> + '_ {
+        self.tree
+            .externalisation_map()
+            .iter()
+            .copied()
+            .zip(self.values.iter_mut())
+    }
+
+    /// Finds the `k` nearest neighbour of `query` and their values,
+    /// using reasonable default parameters, see [`KDTree::knn`].
+    pub fn knn(&self, k: u32, query: &P) -> Vec<(&V, Neighbour<T, P>)> {
+        self.knn_advanced(
+            k,
+            query,
+            KDTree::<T, P>::default_candidate_container(k),
+            &Parameters::default(),
+            None,
+        )
+    }
+
+    /// Finds the `k` nearest neighbour of `query` and their values,
+    /// with user-provided parameters, see [`KDTree::knn_advanced`].
+    pub fn knn_advanced(
+        &self,
+        k: u32,
+        query: &P,
+        candidate_container: CandidateContainer,
+        parameters: &Parameters<T>,
+        touch_statistics: Option<&mut u32>,
+    ) -> Vec<(&V, Neighbour<T, P>)> {
+        (match candidate_container {
+            CandidateContainer::Linear => Self::knn_generic_heap::<Vec<InternalNeighbour<T>>>,
+            CandidateContainer::BinaryHeap => {
+                Self::knn_generic_heap::<BinaryHeap<InternalNeighbour<T>>>
+            }
+        })(self, k, query, parameters, touch_statistics)
+    }
+
+    fn knn_generic_heap<H: CandidateHeap<T> + CandidateCollector<T>>(
+        &self,
+        k: u32,
+        query: &P,
+        parameters: &Parameters<T>,
+        touch_statistics: Option<&mut u32>,
+    ) -> Vec<(&V, Neighbour<T, P>)> {
+        let mut heap = H::new_with_k(k.min(self.len() as u32));
+        self.tree
+            .nn_raw(query, &mut heap, parameters, touch_statistics);
+        if parameters.sort_results {
+            heap.into_sorted_vec()
+        } else {
+            heap.into_vec()
+        }
+        .into_iter()
+        .map(|neighbour| {
+            (
+                &self.values[neighbour.index as usize],
+                self.tree.externalise_neighbour(neighbour),
+            )
+        })
+        .collect()
+    }
+
+    /// Splits the map into its tree and its values, in the order of the original point cloud.
+    pub fn into_parts(self) -> (KDTree<T, P>, Vec<V>) {
+        let values = reorder(self.values, &self.tree.internal_indices);
+        (self.tree, values)
+    }
+
+    /// Exports the tree as a [`FlatTree`], along with the values in the order of its points.
+    pub fn into_flat(self) -> (FlatTree<T>, Vec<V>) {
+        (self.tree.export_flat(), self.values)
+    }
+
+    /// Imports a map exported by [`Self::into_flat`].
+    ///
+    /// Returns an error if the flat tree is invalid, or if `values` does not have as many entries as its points.
+    pub fn from_flat(flat: &FlatTree<T>, values: Vec<V>) -> Result<Self, BuildError> {
+        let tree = flat.import::<P>()?;
+        if values.len() != tree.len() {
+            return Err(BuildError::ValuesLengthMismatch {
+                points: tree.len(),
+                values: values.len(),
+            });
+        }
+        Ok(KDTreeMap { tree, values })
+    }
+}
+
+/// Moves `values[indices[i]]` to position `i`
+fn reorder<V>(values: Vec<V>, indices: &[u32]) -> Vec<V> {
+    let mut values: Vec<Option<V>> = values.into_iter().map(Some).collect();
+    indices
+        .iter()
+        .map(|index| {
+            values[*index as usize]
+                .take()
+                .expect("indices are a permutation")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn map_returns_values_of_neighbours() {
+        let cloud: Vec<P2> = random_point_cloud(300);
+        let entries: Vec<(P2, String)> = cloud
+            .iter()
+            .enumerate()
+            .map(|(index, point)| (*point, format!("point {}", index)))
+            .collect();
+        let mut map = KDTreeMap::new(&entries);
+        assert_eq!(map.len(), 300);
+        assert_eq!(map.get(42), "point 42");
+        for _ in 0..20 {
+            let query = random_point();
+            let neighbours = map.knn(5, &query);
+            let expected = map.tree().knn(5, &query);
+            assert_eq!(neighbours.len(), 5);
+            for ((value, neighbour), expected) in neighbours.into_iter().zip(expected) {
+                assert_eq!(neighbour, expected);
+                assert_eq!(*value, format!("point {}", neighbour.index));
+            }
+        }
+        map.get_mut(7).push('!');
+        for (index, value) in map.iter_mut() {
+            if index % 2 == 1 {
+                value.push('?');
+            }
+        }
+        assert_eq!(map.get(7), "point 7!?");
+        assert_eq!(map.get(8), "point 8");
+        let (tree, values) = map.clone().into_parts();
+        assert_eq!(tree.points(), cloud);
+        assert_eq!(values[7], "point 7!?");
+        assert_eq!(values[299], "point 299?");
+        let (flat, values) = map.clone().into_flat();
+        assert_eq!(KDTreeMap::from_flat(&flat, values), Ok(map));
+    }
+
+    #[test]
+    fn map_keeps_values_with_their_points_through_changes() {
+        let cloud: Vec<P2> = random_point_cloud(500);
+        let entries: Vec<(P2, usize)> = cloud.iter().copied().zip(0..).collect();
+        let mut map = KDTreeMap::new(&entries);
+        let mut expected: Vec<usize> = (0..500).collect();
+        let check = |map: &KDTreeMap<f32, P2, usize>, expected: &[usize]| {
+            assert_eq!(map.len(), expected.len());
+            for (index, value) in expected.iter().enumerate() {
+                assert_eq!(map.get(index as u32), value);
+            }
+            for _ in 0..20 {
+                for (value, neighbour) in map.knn(4, &random_point()) {
+                    assert_eq!(*value, expected[neighbour.index as usize]);
+                }
+            }
+        };
+        // far moves relocate points to other leaves
+        let updates: Vec<(u32, P2)> = (0..500)
+            .step_by(3)
+            .map(|index| (index, random_point()))
+            .collect();
+        let summary = map.update_points(&updates);
+        assert!(summary.relocated > 0);
+        check(&map, &expected);
+        // fewer insertions than removals move the entries of the largest indices
+        let insertions: Vec<(P2, usize)> = random_point_cloud(5).into_iter().zip(1000..).collect();
+        for removals in [&[3, 40, 41, 42, 100, 250, 300, 301][..], &[0]] {
+            let indices = map.apply_batch(removals, &insertions);
+            let mut renumbered = vec![0; map.len()];
+            for (old_index, new_index) in indices.renumbered.iter().enumerate() {
+                if let Some(new_index) = new_index {
+                    renumbered[*new_index as usize] = expected[old_index];
+                }
+            }
+            for (new_index, (_, value)) in indices.inserted.iter().zip(&insertions) {
+                renumbered[*new_index as usize] = *value;
+            }
+            expected = renumbered;
+            check(&map, &expected);
+        }
+        assert_eq!(map.len(), 501);
+        assert_eq!(map.get(250), &497);
+    }
+
+    #[test]
+    fn map_needs_a_value_per_point() {
+        let cloud: Vec<P2> = random_point_cloud(3);
+        assert_eq!(
+            KDTreeMap::from_parts(&cloud, vec![1, 2], &BuildOptions::default()),
+            Err(BuildError::ValuesLengthMismatch {
+                points: 3,
+                values: 2
+            })
+        );
+    }
+}
@@ -0,0 +1,261 @@
+//! Two trees queried against each other, such as the source and target clouds of a registration
+
+use alloc::{vec, vec::Vec};
+
+use ordered_float::NotNan;
+
+use crate::distance_scale::unscale_dist2;
+use crate::infinite::HasInfinite;
+use crate::internal_parameters::InternalParameters;
+use crate::{InternalNeighbour, KDTree, Neighbour, Parameters, Point, Scalar};
+
+/// Two trees `a` and `b`, whose points are the queries of each other
+///
+/// Results share one index convention: a point of `a` is designated by its index in the cloud `a`
+/// was built from, and a point of `b` by its index in the cloud of `b`, whichever direction is queried.
+/// The results of a direction are indexed by the points it starts from, and their neighbours
+/// carry the indices of the other tree.
+/// The stored points of each tree are the queries of the other, so the original clouds are not needed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TreePair<T: Scalar, P: Point<T>> {
+    a: KDTree<T, P>,
+    b: KDTree<T, P>,
+}
+
+impl<T: Scalar, P: Point<T>> TreePair<T, P> {
+    /// Creates a pair from the trees `a` and `b`.
+    pub fn new(a: KDTree<T, P>, b: KDTree<T, P>) -> Self {
+        TreePair { a, b }
+    }
+
+    /// Returns the tree `a`.
+    pub fn a(&self) -> &KDTree<T, P> {
+        &self.a
+    }
+
+    /// Returns the tree `b`.
+    pub fn b(&self) -> &KDTree<T, P> {
+        &self.b
+    }
+
+    /// Returns the tree `a`, for example to move its points by [`KDTree::update_points`],
+    /// which keeps their indices.
+    pub fn a_mut(&mut self) -> &mut KDTree<T, P> {
+        &mut self.a
+    }
+
+    /// Returns the tree `b`, see [`Self::a_mut`].
+    pub fn b_mut(&mut self) -> &mut KDTree<T, P> {
+        &mut self.b
+    }
+
+    /// Returns the trees `a` and `b`.
+    pub fn into_trees(self) -> (KDTree<T, P>, KDTree<T, P>) {
+        (self.a, self.b)
+    }
+}
+
+impl<T: Scalar + Send + Sync, P: Point<T> + Send + Sync> TreePair<T, P> {
+    /// Returns, for each point of `a` by index, its nearest point of `b` within `parameters.max_radius`,
+    /// or `None` if there is none.
+    ///
+    /// If the `rayon` feature is enabled, points are processed in parallel.
+    pub fn a_to_b_nn(&self, parameters: &Parameters<T>) -> Vec<Option<Neighbour<T, P>>> {
+        Self::directed_nn(&self.a, &self.b, parameters)
+    }
+
+    /// Returns, for each point of `b` by index, its nearest point of `a` within `parameters.max_radius`,
+    /// or `None` if there is none, see [`Self::a_to_b_nn`].
+    pub fn b_to_a_nn(&self, parameters: &Parameters<T>) -> Vec<Option<Neighbour<T, P>>> {
+        Self::directed_nn(&self.b, &self.a, parameters)
+    }
+
+    /// Returns the pairs of points within `max_radius` of each other, inclusive, that are each other's
+    /// nearest neighbour, as the index of the point of `a`, the index of the point of `b` and
+    /// their squared distance, by increasing index in `a`.
+    ///
+    /// Each point is in at most one pair.
+    /// The nearest point of `b` is searched for every point of `a`, but the nearest point of `a`
+    /// only for the points of `b` that are the nearest of some point of `a`.
+    /// Those searches start bounded by the closest such point, so they prune most of `a`, and among
+    /// several points of `a` at the same distance, that point is preferred.
+    /// If the `rayon` feature is enabled, points are processed in parallel.
+    ///
+    /// Panics if `max_radius` is negative or NaN.
+    pub fn mutual_pairs(&self, max_radius: T) -> Vec<(u32, u32, T)> {
+        assert!(
+            max_radius >= T::zero(),
+            "max_radius must be positive or zero"
+        );
+        let parameters = Parameters {
+            max_radius,
+            ..Parameters::default()
+        };
+        let (a, b) = (&self.a, &self.b);
+        let forward = Self::internal_nn(a, b, &parameters);
+        // for each point of `b` by internal index, the closest point of `a` of which it is the nearest
+        let mut closest: Vec<Option<InternalNeighbour<T>>> = vec![None; b.len()];
+        for (a_internal, nearest) in forward.into_iter().enumerate() {
+            if nearest.dist2 == NotNan::infinite() {
+                continue;
+            }
+            let candidate = InternalNeighbour {
+                index: a_internal as u32,
+                dist2: nearest.dist2,
+            };
+            let entry = &mut closest[nearest.index as usize];
+            if entry.map_or(true, |current| candidate < current) {
+                *entry = Some(candidate);
+            }
+        }
+        let internal_parameters = InternalParameters::new(&parameters, a.scale);
+        let backward = b.map_indices(b.len(), |b_internal| {
+            closest[b_internal].map(|hint| {
+                let query = b.stored_coordinates_for(b_internal as u32, a);
+                a.nearest_seeded(&query, Some(hint.index), &internal_parameters)
+                    .index
+            })
+        });
+        let mut pairs: Vec<(u32, u32, T)> = closest
+            .iter()
+            .zip(backward)
+            .enumerate()
+            .filter_map(|(b_internal, (closest, nearest))| {
+                let closest = (*closest)?;
+                (nearest == Some(closest.index)).then(|| {
+                    (
+                        a.externalise_index(closest.index),
+                        b.externalise_index(b_internal as u32),
+                        unscale_dist2(closest.dist2, b.scale).into_inner(),
+                    )
+                })
+            })
+            .collect();
+        pairs.sort_unstable_by_key(|(a_index, _, _)| *a_index);
+        pairs
+    }
+
+    /// Returns, for each point of `from` by index, its nearest point of `to`
+    fn directed_nn(
+        from: &KDTree<T, P>,
+        to: &KDTree<T, P>,
+        parameters: &Parameters<T>,
+    ) -> Vec<Option<Neighbour<T, P>>> {
+        let mut nearest: Vec<_> = (0..from.len()).map(|_| None).collect();
+        for (internal, neighbour) in Self::internal_nn(from, to, parameters)
+            .into_iter()
+            .enumerate()
+        {
+            if neighbour.dist2 != NotNan::infinite() {
+                let external = from.externalise_index(internal as u32) as usize;
+                nearest[external] = Some(to.externalise_neighbour(neighbour));
+            }
+        }
+        nearest
+    }
+
+    /// Returns, for each point of `from` by internal index, its nearest point of `to` by internal index,
+    /// the squared distance being infinite if there is none
+    fn internal_nn(
+        from: &KDTree<T, P>,
+        to: &KDTree<T, P>,
+        parameters: &Parameters<T>,
+    ) -> Vec<InternalNeighbour<T>> {
+        let internal_parameters = InternalParameters::new(parameters, to.scale);
+        from.map_indices(from.len(), |internal| {
+            let mut nearest = InternalNeighbour::default();
+            let query = from.stored_coordinates_for(internal as u32, to);
+            to.knn_internal(&mut nearest, &query, &internal_parameters);
+            nearest
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn directed_nearest_neighbours_are_indexed_by_the_original_clouds() {
+        let a: Vec<P2> = random_point_cloud(1500);
+        let b: Vec<P2> = random_point_cloud(400);
+        let pair = TreePair::new(KDTree::new(&a), KDTree::new(&b));
+        let parameters = Parameters {
+            max_radius: 5.0,
+            ..Parameters::default()
+        };
+        for (from, to, to_cloud, nearest) in [
+            (&a, pair.b(), &b, pair.a_to_b_nn(&parameters)),
+            (&b, pair.a(), &a, pair.b_to_a_nn(&parameters)),
+        ] {
+            assert_eq!(nearest.len(), from.len());
+            for (query, nearest) in from.iter().zip(&nearest) {
+                let expected = to
+                    .knn_advanced(1, query, CandidateContainer::Linear, &parameters, None)
+                    .first()
+                    .copied();
+                assert_eq!(nearest.map(|n| n.dist2), expected.map(|n| n.dist2));
+                if let Some(nearest) = nearest {
+                    assert_eq!(to_cloud[nearest.index as usize], nearest.point);
+                }
+            }
+        }
+        let empty = TreePair::new(KDTree::new(&a), KDTree::new(&[]));
+        assert!(empty.a_to_b_nn(&parameters).iter().all(Option::is_none));
+        assert!(empty.b_to_a_nn(&parameters).is_empty());
+        assert!(empty.mutual_pairs(10.0).is_empty());
+    }
+
+    #[test]
+    fn mutual_pairs_are_each_others_nearest_neighbour() {
+        let a: Vec<P2> = random_point_cloud(1000);
+        // integer coordinates put several points of `a` at the same distance of a point of `b`
+        let mut b: Vec<P2> = a.iter().step_by(3).map(|p| *p * 1.01).collect();
+        b.extend((0..50).map(|i| P2::new2d((i % 10) as f32, (i / 10) as f32)));
+        let pair = TreePair::new(KDTree::new(&a), KDTree::new(&b));
+        for max_radius in [0.5, 2.0, f32::INFINITY] {
+            let parameters = Parameters {
+                max_radius,
+                ..Parameters::default()
+            };
+            let (forward, backward) = (pair.a_to_b_nn(&parameters), pair.b_to_a_nn(&parameters));
+            let pairs = pair.mutual_pairs(max_radius);
+            assert!(!pairs.is_empty());
+            assert!(pairs.windows(2).all(|w| w[0].0 < w[1].0));
+            for (a_index, b_index, dist2) in &pairs {
+                let to_b = forward[*a_index as usize].expect("paired points are matched");
+                let to_a = backward[*b_index as usize].expect("paired points are matched");
+                assert_eq!((to_b.index, to_b.dist2.into_inner()), (*b_index, *dist2));
+                assert_eq!(to_a.dist2.into_inner(), *dist2);
+            }
+            // every strict mutual pair is found
+            for (a_index, to_b) in forward.iter().enumerate() {
+                let to_b = match to_b {
+                    Some(to_b) => to_b,
+                    None => continue,
+                };
+                let to_a = backward[to_b.index as usize].expect("the reverse is within the radius");
+                let unique = |tree: &KDTree<f32, P2>, query: &P2| {
+                    let nearest = tree.knn(2, query);
+                    nearest[1].dist2 > nearest[0].dist2
+                };
+                if to_a.index == a_index as u32
+                    && unique(pair.b(), &a[a_index])
+                    && unique(pair.a(), &b[to_b.index as usize])
+                {
+                    assert!(pairs
+                        .iter()
+                        .any(|(pa, pb, _)| (*pa, *pb) == (a_index as u32, to_b.index)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "max_radius must be positive or zero")]
+    fn negative_radii_panic() {
+        let cloud = [P2::new2d(0.0, 0.0)];
+        TreePair::new(KDTree::new(&cloud), KDTree::new(&cloud)).mutual_pairs(-1.0);
+    }
+}
@@ -0,0 +1,278 @@
+//! How much the structure of a tree degraded since it was built, and when to rebuild it
+
+use crate::{KDTree, Point, RelayoutMap, Scalar};
+
+/// How far a tree is from a freshly built one, see [`KDTree::quality`]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TreeQuality {
+    /// largest number of split nodes on a path from the root to a leaf
+    pub depth: u32,
+    /// depth of a balanced tree holding the same number of points in full buckets
+    pub optimal_depth: u32,
+    /// mean number of points of a leaf, divided by the bucket size
+    pub leaf_occupancy: f64,
+    /// variance of the number of points of a leaf, divided by the square of the bucket size
+    pub leaf_occupancy_variance: f64,
+    /// fraction of the stored points that are removed, but still visited by queries
+    pub dead_fraction: f64,
+    /// drift of the tree, see [`KDTree::drift_estimate`], divided by the typical width of a leaf cell
+    pub drift_ratio: f64,
+    /// estimated number of points a query touches, divided by that on a freshly built tree
+    ///
+    /// The estimate multiplies the widening of the cells by the drift in each dimension,
+    /// the growth of the leaves beyond the bucket size, and the dead points visited in vain.
+    /// It is meant to be compared to thresholds, not to predict query time precisely.
+    pub estimated_slowdown: f64,
+}
+
+/// Thresholds on the [`TreeQuality`] of a tree beyond which it should be rebuilt
+///
+/// A rebuild is needed when any threshold is exceeded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AutoRebuild {
+    /// largest accepted ratio of the depth to the optimal depth
+    pub max_depth_ratio: f64,
+    /// largest accepted fraction of dead points
+    pub max_dead_fraction: f64,
+    /// largest accepted estimated slowdown
+    pub max_slowdown: f64,
+}
+
+impl Default for AutoRebuild {
+    /// Rebuilds trees whose queries are estimated to be twice slower, or with a quarter of dead points.
+    fn default() -> Self {
+        AutoRebuild {
+            max_depth_ratio: 2.0,
+            max_dead_fraction: 0.25,
+            max_slowdown: 2.0,
+        }
+    }
+}
+
+impl AutoRebuild {
+    /// Returns whether a tree of quality `quality` exceeds one of the thresholds.
+    pub fn needs_rebuild(&self, quality: &TreeQuality) -> bool {
+        quality.depth as f64 > self.max_depth_ratio * quality.optimal_depth.max(1) as f64
+            || quality.dead_fraction > self.max_dead_fraction
+            || quality.estimated_slowdown > self.max_slowdown
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Returns how far the structure of the tree is from that of a freshly built one.
+    ///
+    /// The trees of this crate are balanced when built, but [`Self::update_point`] and [`Self::refit`]
+    /// keep their splits while moving the points, and [`Self::merge`] can grow their leaves.
+    /// A tree has no dead points, only its snapshots have, see [`KDTreeSnapshot::quality`](crate::KDTreeSnapshot::quality).
+    /// This traverses all nodes, in time linear in their number.
+    pub fn quality(&self) -> TreeQuality {
+        let stats = self.stats();
+        let bucket_size = self.options.bucket_size.max(1) as f64;
+        let full_leaves = (self.len() as f64 / bucket_size).ceil().max(1.0);
+        let optimal_depth = full_leaves.log2().ceil() as u32;
+        let occupancy = self.len() as f64 / stats.leaf_count as f64;
+        let mut variance = 0.0;
+        let mut stack = alloc::vec![0usize];
+        while let Some(node) = stack.pop() {
+            self.nodes[node].dispatch_on_type(
+                &mut stack,
+                |stack, _, _, right_child| {
+                    stack.push(node + 1);
+                    stack.push(right_child as usize);
+                },
+                |_, _, bucket_size| {
+                    let deviation = bucket_size as f64 - occupancy;
+                    variance += deviation * deviation;
+                },
+            );
+        }
+        variance /= stats.leaf_count as f64;
+        // the leaf cells of a balanced tree split the extent of each non-constant dimension evenly
+        let extents: alloc::vec::Vec<f64> = self
+            .min_bounds
+            .iter()
+            .zip(&self.max_bounds)
+            .map(|(min, max)| to_f64(max.into_inner() - min.into_inner()))
+            .filter(|extent| *extent > 0.0)
+            .collect();
+        let drift = to_f64(self.drift.into_inner());
+        let drift_ratio = if extents.is_empty() || drift == 0.0 {
+            0.0
+        } else {
+            let dims = extents.len() as f64;
+            let log_extent = extents.iter().map(|extent| extent.ln()).sum::<f64>() / dims;
+            let leaf_width = log_extent.exp() / (stats.leaf_count as f64).powf(1.0 / dims);
+            drift / leaf_width
+        };
+        // a query visits the leaves its widened cell overlaps, the widening being about
+        // the drift per dimension on average, and scans more points in leaves that grew beyond the bucket size
+        let widening = (1.0 + drift_ratio).powi(extents.len() as i32);
+        TreeQuality {
+            depth: stats.depth,
+            optimal_depth,
+            leaf_occupancy: occupancy / bucket_size,
+            leaf_occupancy_variance: variance / (bucket_size * bucket_size),
+            dead_fraction: 0.0,
+            drift_ratio,
+            estimated_slowdown: widening.min(stats.leaf_count as f64)
+                * (occupancy / bucket_size).max(1.0),
+        }
+    }
+
+    /// Rebuilds the tree from its points with the options it was built with,
+    /// keeping the indices, identifiers and timestamps of the points and their auxiliary indices, and removing any drift.
+    ///
+    /// As the indices are kept, so is the generation of the tree, see [`Self::generation`].
+    /// The nodes are new, and the returned map only gives the old root as the source of the new one,
+    /// to migrate [`NodeAnnotations`](crate::NodeAnnotations).
+    pub fn rebuild(&mut self) -> RelayoutMap {
+        let old_node_count = self.nodes.len();
+        let ids = self.external_ids();
+        let timestamps = self.external_timestamps();
+        let axis_indices = core::mem::take(&mut self.axis_indices);
+        let generation = self.generation;
+        *self = KDTree::new_with_options(&self.points(), &self.options);
+        self.set_external_ids(ids);
+        self.set_external_timestamps(timestamps);
+        self.axis_indices = axis_indices;
+        self.generation = generation;
+        let mut sources = alloc::vec![None; self.nodes.len()];
+        if let Some(root) = sources.first_mut() {
+            *root = Some(0);
+        }
+        RelayoutMap::new(sources, old_node_count)
+    }
+}
+
+/// Converts `value` to `f64`, which holds all scalars up to rounding
+fn to_f64<T: Scalar>(value: T) -> f64 {
+    value.to_f64().expect("scalars convert to f64")
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use crate::simple_point::*;
+    use crate::*;
+
+    /// Mean number of points touched by the queries, the measure of query time of these tests
+    fn mean_touched(tree: &KDTree<f32, P2>, queries: &[P2]) -> f64 {
+        let total: u64 = queries
+            .iter()
+            .map(|query| {
+                let mut touched = 0;
+                tree.knn_advanced(
+                    4,
+                    query,
+                    CandidateContainer::Linear,
+                    &Parameters::default(),
+                    Some(&mut touched),
+                );
+                touched as u64
+            })
+            .sum();
+        total as f64 / queries.len() as f64
+    }
+
+    #[test]
+    fn estimated_slowdown_follows_measured_query_cost() {
+        let mut rng = rand::thread_rng();
+        let original: Vec<P2> = random_point_cloud(5000);
+        let queries: Vec<P2> = random_point_cloud(300);
+        let fresh = KDTree::new(&original);
+        let quality = fresh.quality();
+        assert_eq!(quality.estimated_slowdown, 1.0);
+        assert_eq!(quality.drift_ratio, 0.0);
+        assert_eq!(quality.dead_fraction, 0.0);
+        assert!(quality.depth >= quality.optimal_depth);
+        assert!(quality.leaf_occupancy > 0.5 && quality.leaf_occupancy <= 1.0);
+        let mut previous = (1.0, 1.0);
+        for jitter in [0.5, 2.0, 5.0, 10.0, 20.0] {
+            let moved: Vec<P2> = original
+                .iter()
+                .map(|p| {
+                    P2::new2d(
+                        *p.get(0) + rng.gen_range(-jitter..jitter),
+                        *p.get(1) + rng.gen_range(-jitter..jitter),
+                    )
+                })
+                .collect();
+            let mut tree = fresh.clone();
+            tree.refit(&moved);
+            let estimated = tree.quality().estimated_slowdown;
+            // the cost on a tree built on the moved points is the baseline
+            let mut rebuilt = tree.clone();
+            rebuilt.rebuild();
+            assert_eq!(rebuilt.quality().estimated_slowdown, 1.0);
+            let measured = mean_touched(&tree, &queries) / mean_touched(&rebuilt, &queries);
+            // the estimate grows with the measured slowdown, and stays within a small factor of it
+            assert!(estimated > previous.0 && measured > previous.1);
+            assert!(estimated < 1.5 * measured && measured < 1.5 * estimated);
+            previous = (estimated, measured);
+        }
+    }
+
+    #[test]
+    fn rebuild_keeps_indices_and_removes_drift() {
+        let cloud: Vec<P2> = random_point_cloud(1000);
+        let mut tree = KDTree::new(&cloud);
+        for index in 0..100 {
+            tree.update_point(index, random_point());
+        }
+        assert!(tree.quality().drift_ratio > 0.0);
+        let moved = tree.points();
+        tree.rebuild();
+        assert_eq!(tree.points(), moved);
+        assert_eq!(tree.drift_estimate(), 0.0);
+        let expected = KDTree::new(&moved);
+        for _ in 0..20 {
+            let query: P2 = random_point();
+            assert_eq!(tree.knn(5, &query), expected.knn(5, &query));
+        }
+        let mut empty = KDTree::<f32, P2>::new(&[]);
+        empty.rebuild();
+        assert_eq!(empty.quality().estimated_slowdown, 1.0);
+    }
+
+    #[test]
+    fn policies_rebuild_degraded_trees() {
+        let policy = AutoRebuild::default();
+        let cloud: Vec<P2> = random_point_cloud(2000);
+        let tree = KDTree::new(&cloud);
+        assert!(!policy.needs_rebuild(&tree.quality()));
+        // removed points are dead in snapshots
+        let mut snapshot = tree.snapshot();
+        for index in 0..1000 {
+            snapshot.remove(index);
+        }
+        let quality = snapshot.quality();
+        assert_eq!(quality.dead_fraction, 0.5);
+        assert_eq!(quality.estimated_slowdown, 2.0);
+        assert!(policy.needs_rebuild(&quality));
+        // moving points far degrades a concurrent tree until the callback accepts a rebuild
+        let concurrent = ConcurrentKDTree::new(tree);
+        let mut requests = Vec::new();
+        let mut accept = false;
+        for index in 0..20 {
+            concurrent.write_with_auto_rebuild(
+                |tree| tree.update_point(index, P2::new2d(1000.0, 1000.0)),
+                &policy,
+                |quality| {
+                    requests.push(quality.estimated_slowdown);
+                    accept
+                },
+            );
+            if !requests.is_empty() {
+                accept = true;
+            }
+        }
+        let current = concurrent.read();
+        assert!(requests.len() >= 2);
+        assert!(requests
+            .iter()
+            .all(|slowdown| *slowdown > policy.max_slowdown));
+        assert!(!policy.needs_rebuild(&current.quality()));
+        assert_eq!(*current.points()[0].get(0), 1000.0);
+    }
+}
@@ -0,0 +1,161 @@
+//! Figures describing the structure of a tree and the points it stores
+
+use alloc::vec::Vec;
+
+use crate::{KDTree, Point, Scalar};
+
+/// The structure of a tree, see [`KDTree::stats`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    /// number of nodes, split and leaf
+    pub node_count: usize,
+    /// number of leaves
+    pub leaf_count: usize,
+    /// number of points of the largest leaf
+    pub max_leaf_size: u32,
+    /// number of leaves holding more points than the bucket size
+    ///
+    /// Such leaves are built by [`BuildOptions::max_depth`](crate::BuildOptions::max_depth) and
+    /// [`BuildOptions::duplicate_leaves`](crate::BuildOptions::duplicate_leaves), and the queries reaching them
+    /// compute the distance to all their points.
+    pub oversized_leaf_count: usize,
+    /// largest number of split nodes on a path from the root to a leaf
+    pub depth: u32,
+    /// the dimensions along which all points have the same coordinate, by increasing dimension
+    ///
+    /// The tree never splits along these dimensions.
+    /// They are detected from the bounds of the stored points, which [`KDTree::update_point`]
+    /// only widens, so a dimension that became constant by moving points might not be reported.
+    pub constant_dims: Vec<u32>,
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Returns figures describing the structure of the tree, and the dimensions in which it is degenerate.
+    ///
+    /// This traverses all nodes, in time linear in their number.
+    pub fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats {
+            node_count: self.nodes.len(),
+            constant_dims: self.constant_dims(),
+            ..TreeStats::default()
+        };
+        let mut stack = alloc::vec![(0usize, 0u32)];
+        while let Some((node, depth)) = stack.pop() {
+            self.nodes[node].dispatch_on_type(
+                (&mut stack, &mut stats),
+                |(stack, _), _, _, right_child| {
+                    stack.push((node + 1, depth + 1));
+                    stack.push((right_child as usize, depth + 1));
+                },
+                |(_, stats), _, bucket_size| {
+                    stats.leaf_count += 1;
+                    if bucket_size > self.options.bucket_size {
+                        stats.oversized_leaf_count += 1;
+                    }
+                    stats.max_leaf_size = stats.max_leaf_size.max(bucket_size);
+                    stats.depth = stats.depth.max(depth);
+                },
+            );
+        }
+        stats
+    }
+
+    /// Returns the dimensions along which all stored points have the same coordinate,
+    /// empty for an empty tree
+    fn constant_dims(&self) -> Vec<u32> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        (0..P::DIM)
+            .filter(|dim| self.min_bounds[*dim as usize] == self.max_bounds[*dim as usize])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn stats_report_structure_and_constant_dims() {
+        // a planar scan, constant along z
+        let cloud: Vec<P3> = random_point_cloud::<2>(2000)
+            .iter()
+            .map(|p| P2::new3d(*p.get(0), *p.get(1), 1.5))
+            .collect();
+        for options in [
+            BuildOptions::default(),
+            BuildOptions {
+                seed: Some(3),
+                ..BuildOptions::default()
+            },
+        ] {
+            let tree = KDTree::new_with_options(&cloud, &options);
+            let stats = tree.stats();
+            assert_eq!(stats.constant_dims, vec![2]);
+            assert_eq!(stats.node_count, tree.node_count());
+            assert_eq!(stats.node_count, 2 * stats.leaf_count - 1);
+            assert!(stats.max_leaf_size <= options.bucket_size);
+            assert!(stats.depth > 0);
+            let mut nodes = vec![tree.root()];
+            while let Some(current) = nodes.pop() {
+                assert_ne!(current.split_dim(), Some(2));
+                nodes.extend(current.left());
+                nodes.extend(current.right());
+            }
+        }
+        assert!(KDTree::new(&random_point_cloud::<3>(100))
+            .stats()
+            .constant_dims
+            .is_empty());
+        let empty = KDTree::<f32, P3>::new(&[]).stats();
+        assert_eq!(empty.leaf_count, 1);
+        assert!(empty.constant_dims.is_empty());
+    }
+
+    #[test]
+    fn max_depth_caps_extremely_clustered_trees() {
+        type P1d = SimplePoint<1, f64>;
+        let mut rng = crate::rng::SplitMix64::new(219);
+        // a geometric progression down to the smallest subnormal peels one point per split,
+        // beside a million spread points
+        let mut cloud: Vec<P1d> = (0..=1074).map(|k| P1d::from([2f64.powi(-k)])).collect();
+        let spread = 1_000_000 - cloud.len();
+        cloud.extend(
+            (0..spread).map(|_| P1d::from([2.0 + rng.below(1 << 30) as f64 / (1 << 30) as f64])),
+        );
+        assert_eq!(
+            KDTree::try_new(&cloud).err(),
+            Some(BuildError::TooDeep {
+                max_depth: BuildOptions::MAX_SAFE_DEPTH
+            })
+        );
+        let options = BuildOptions {
+            max_depth: Some(64),
+            ..BuildOptions::default()
+        };
+        let tree = KDTree::new_with_options(&cloud, &options);
+        let stats = tree.stats();
+        assert_eq!(stats.depth, 64);
+        assert_eq!(stats.oversized_leaf_count, 1);
+        assert_eq!(stats.max_leaf_size, 1074 - 62);
+        // the oversized leaf is searched exhaustively, squared distances not underflowing around 2^-100
+        let query = P1d::from([2f64.powi(-100)]);
+        let nearest: Vec<u32> = tree
+            .knn(3, &query)
+            .iter()
+            .map(|neighbour| neighbour.index)
+            .collect();
+        assert_eq!(nearest, [100, 101, 102]);
+        let within = tree.radius_search_ordered(&query, Radius::new(2f64.powi(-100)));
+        assert_eq!(within.len(), 1074 - 99 + 1);
+        // a maximum beyond the safe depth does not prevent the error
+        let options = BuildOptions {
+            max_depth: Some(2000),
+            ..BuildOptions::default()
+        };
+        assert!(KDTree::try_new_with_options(&cloud, &options).is_err());
+        assert_eq!(KDTree::new(&cloud[..1000]).stats().oversized_leaf_count, 0);
+    }
+}
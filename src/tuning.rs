@@ -0,0 +1,232 @@
+//! Choice of the approximation of searches for a target recall, measured on sample queries
+
+use alloc::vec::Vec;
+
+use ordered_float::NotNan;
+
+use crate::infinite::HasInfinite;
+use crate::{KDTree, Neighbour, Parameters, Point, Scalar};
+
+/// The accuracy and cost of the parameters chosen by [`KDTree::tune`], measured on the sample queries
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TuningReport {
+    /// fraction of the exact neighbours retrieved with the tuned parameters, see [`KDTree::recall`]
+    pub recall: f64,
+    /// mean number of points whose distance was computed by a query with the tuned parameters
+    pub mean_touched_points: f64,
+    /// mean number of points whose distance was computed by an exact query
+    pub exact_mean_touched_points: f64,
+    /// number of settings evaluated on the sample, the exact one included
+    pub evaluations: u32,
+}
+
+/// Smallest and largest powers of two tried as epsilon before refining between them
+const MIN_EPSILON_EXPONENT: i32 = -8;
+const MAX_EPSILON_EXPONENT: i32 = 10;
+
+/// Number of bisection steps refining epsilon between a power of two reaching the target and the next one
+const REFINEMENT_STEPS: u32 = 8;
+
+/// The exact neighbours of the sample queries, against which approximate searches are measured
+struct GroundTruth<T: Scalar> {
+    /// for each query, the number of exact neighbours and the squared distance of the farthest
+    farthest: Vec<(usize, NotNan<T>)>,
+    total: usize,
+    mean_touched_points: f64,
+}
+
+impl<T: Scalar> GroundTruth<T> {
+    /// Returns the number of `found` neighbours of query `query` that are as near as its exact neighbours
+    ///
+    /// Neighbours at the distance of the farthest exact one count, so that ties do not lower the recall.
+    fn retrieved<P: Point<T>>(&self, query: usize, found: &[Neighbour<T, P>]) -> usize {
+        let (count, farthest) = self.farthest[query];
+        let retrieved = found
+            .iter()
+            .filter(|neighbour| neighbour.dist2 <= farthest)
+            .count();
+        retrieved.min(count)
+    }
+}
+
+impl<T: Scalar, P: Point<T>> KDTree<T, P> {
+    /// Returns the fraction of the exact `k` nearest neighbours of `queries` that searches
+    /// with `parameters` retrieve, one if there are no neighbours.
+    ///
+    /// A neighbour is counted as retrieved if it is not farther than the `k`-th exact one,
+    /// so that ties at that distance do not lower the recall.
+    pub fn recall(&self, queries: &[P], k: u32, parameters: &Parameters<T>) -> f64 {
+        let truth = self.ground_truth(queries, k);
+        self.measure(queries, k, parameters, &truth).0
+    }
+
+    /// Returns the cheapest parameters whose searches for the `k` nearest neighbours of `sample_queries`
+    /// reach a recall of at least `target_recall`, see [`Self::recall`], with a report of their accuracy and cost.
+    ///
+    /// The cost is the mean number of points whose distance is computed, which does not depend on the machine.
+    /// The exact neighbours are found first, then epsilon is increased by powers of two while the target is reached,
+    /// and refined by bisection beyond the last power reaching it.
+    /// Greedy searches, with an infinite epsilon, are returned if they reach the target.
+    /// The other parameters are the default ones, and the result only depends on the tree and the sample,
+    /// which should be representative of the queries to come.
+    ///
+    /// Panics if `target_recall` is not within `0..=1`.
+    pub fn tune(
+        &self,
+        sample_queries: &[P],
+        k: u32,
+        target_recall: f64,
+    ) -> (Parameters<T>, TuningReport) {
+        assert!(
+            (0.0..=1.0).contains(&target_recall),
+            "the target recall must be within 0..=1"
+        );
+        let exact = Parameters::default();
+        let truth = self.ground_truth(sample_queries, k);
+        let mut report = TuningReport {
+            recall: 1.0,
+            mean_touched_points: truth.mean_touched_points,
+            exact_mean_touched_points: truth.mean_touched_points,
+            evaluations: 1,
+        };
+        let mut best = exact;
+        let mut evaluate = |epsilon: T, report: &mut TuningReport| {
+            let parameters = Parameters { epsilon, ..exact };
+            let (recall, mean_touched_points) =
+                self.measure(sample_queries, k, &parameters, &truth);
+            report.evaluations += 1;
+            let reached = recall >= target_recall;
+            // among the settings reaching the target, keep the cheapest, the largest epsilon on ties
+            if reached && mean_touched_points <= report.mean_touched_points {
+                best = parameters;
+                report.recall = recall;
+                report.mean_touched_points = mean_touched_points;
+            }
+            reached
+        };
+        if evaluate(T::infinity(), &mut report) {
+            return (best, report);
+        }
+        let power =
+            |exponent: i32| T::from(2f64.powi(exponent)).expect("powers of two are representable");
+        let mut exponent = MIN_EPSILON_EXPONENT;
+        while exponent <= MAX_EPSILON_EXPONENT && evaluate(power(exponent), &mut report) {
+            exponent += 1;
+        }
+        if exponent == MIN_EPSILON_EXPONENT || exponent > MAX_EPSILON_EXPONENT {
+            return (best, report);
+        }
+        let (mut reached, mut missed) = (power(exponent - 1), power(exponent));
+        let two = T::one() + T::one();
+        for _ in 0..REFINEMENT_STEPS {
+            let middle = (reached + missed) / two;
+            if evaluate(middle, &mut report) {
+                reached = middle;
+            } else {
+                missed = middle;
+            }
+        }
+        (best, report)
+    }
+
+    /// Finds the exact neighbours of `queries`
+    fn ground_truth(&self, queries: &[P], k: u32) -> GroundTruth<T> {
+        let container = Self::default_candidate_container(k);
+        let mut touched = 0u64;
+        let farthest: Vec<_> = queries
+            .iter()
+            .map(|query| {
+                let (neighbours, stats) =
+                    self.knn_with_stats(k, query, container, &Parameters::default());
+                touched += stats.touched_points as u64;
+                let farthest = neighbours
+                    .last()
+                    .map_or(-NotNan::infinite(), |neighbour| neighbour.dist2);
+                (neighbours.len(), farthest)
+            })
+            .collect();
+        GroundTruth {
+            total: farthest.iter().map(|(count, _)| count).sum(),
+            farthest,
+            mean_touched_points: mean(touched, queries.len()),
+        }
+    }
+
+    /// Returns the recall of searches with `parameters` and their mean number of touched points
+    fn measure(
+        &self,
+        queries: &[P],
+        k: u32,
+        parameters: &Parameters<T>,
+        truth: &GroundTruth<T>,
+    ) -> (f64, f64) {
+        let container = Self::default_candidate_container(k);
+        let mut touched = 0u64;
+        let mut retrieved = 0;
+        for (index, query) in queries.iter().enumerate() {
+            let (neighbours, stats) = self.knn_with_stats(k, query, container, parameters);
+            touched += stats.touched_points as u64;
+            retrieved += truth.retrieved(index, &neighbours);
+        }
+        let recall = if truth.total == 0 {
+            1.0
+        } else {
+            retrieved as f64 / truth.total as f64
+        };
+        (recall, mean(touched, queries.len()))
+    }
+}
+
+fn mean(total: u64, count: usize) -> f64 {
+    if count == 0 {
+        0.0
+    } else {
+        total as f64 / count as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn tuned_parameters_reach_the_target_recall_at_lower_cost() {
+        let cloud: Vec<P3> = random_point_cloud(20000);
+        let sample: Vec<P3> = random_point_cloud(300);
+        let tree = KDTree::new(&cloud);
+        let mut previous = 0.0;
+        for target_recall in [0.99, 0.9, 0.6] {
+            let (parameters, report) = tree.tune(&sample, 10, target_recall);
+            assert!(report.recall >= target_recall);
+            assert_eq!(tree.recall(&sample, 10, &parameters), report.recall);
+            assert!(parameters.epsilon > previous);
+            assert!(report.mean_touched_points < report.exact_mean_touched_points);
+            assert!(report.evaluations > 2);
+            previous = parameters.epsilon;
+        }
+        // other queries from the same distribution reach about the same recall
+        let queries: Vec<P3> = random_point_cloud(300);
+        let (parameters, _) = tree.tune(&sample, 10, 0.9);
+        assert!(tree.recall(&queries, 10, &parameters) > 0.85);
+    }
+
+    #[test]
+    fn extreme_targets_return_exact_or_greedy_searches() {
+        let cloud: Vec<P2> = random_point_cloud(2000);
+        let sample: Vec<P2> = random_point_cloud(50);
+        let tree = KDTree::new(&cloud);
+        assert_eq!(tree.recall(&sample, 5, &Parameters::default()), 1.0);
+        let (parameters, report) = tree.tune(&sample, 5, 0.0);
+        assert_eq!(parameters.epsilon, f32::INFINITY);
+        assert_eq!(report.evaluations, 2);
+        let (parameters, report) = tree.tune(&sample, 5, 1.0);
+        assert_eq!(report.recall, 1.0);
+        assert_eq!(tree.recall(&sample, 5, &parameters), 1.0);
+        // without queries or points, any parameters retrieve all the neighbours
+        let (parameters, _) = tree.tune(&[], 5, 1.0);
+        assert_eq!(parameters.epsilon, f32::INFINITY);
+        let empty = KDTree::<f32, P2>::new(&[]);
+        assert_eq!(empty.recall(&sample, 5, &Parameters::default()), 1.0);
+    }
+}
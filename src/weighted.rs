@@ -0,0 +1,281 @@
+//! Trees of points with multiplicities, for clouds of collapsed repeated observations
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+
+use ordered_float::NotNan;
+
+use crate::{
+    BuildError, BuildOptions, CandidateCollector, InternalNeighbour, KDTree, Neighbour, Parameters,
+    Point, Scalar,
+};
+
+/// A neighbour of a [`WeightedKDTree`], with the number of the `k` slots it fills
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WeightedNeighbour<T: Scalar, P: Point<T>> {
+    /// the neighbour itself
+    pub neighbour: Neighbour<T, P>,
+    /// how many of the `k` slots this neighbour fills, at most its count
+    pub slots: u32,
+}
+
+/// A KDTree in which each point has a count, and is seen by queries as that many identical points
+///
+/// Points of count zero are never returned.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WeightedKDTree<T: Scalar, P: Point<T>> {
+    tree: KDTree<T, P>,
+    /// counts of the points, in the internal order of the tree
+    counts: Vec<u32>,
+}
+
+impl<T: Scalar, P: Point<T>> WeightedKDTree<T, P> {
+    /// Creates a new weighted tree where `counts[i]` is the count of `cloud[i]`.
+    ///
+    /// Panics in the same cases as [`Self::try_new`].
+    pub fn new(cloud: &[P], counts: &[u32]) -> Self {
+        Self::try_new(cloud, counts).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Creates a new weighted tree where `counts[i]` is the count of `cloud[i]`, with default options.
+    ///
+    /// Returns an error if `counts` does not have the same length as `cloud`,
+    /// or in the same cases as [`KDTree::try_new`].
+    pub fn try_new(cloud: &[P], counts: &[u32]) -> Result<Self, BuildError> {
+        Self::try_new_with_options(cloud, counts, &BuildOptions::default())
+    }
+
+    /// Creates a new weighted tree where `counts[i]` is the count of `cloud[i]`, with user-provided options.
+    pub fn try_new_with_options(
+        cloud: &[P],
+        counts: &[u32],
+        options: &BuildOptions,
+    ) -> Result<Self, BuildError> {
+        if counts.len() != cloud.len() {
+            return Err(BuildError::ValuesLengthMismatch {
+                points: cloud.len(),
+                values: counts.len(),
+            });
+        }
+        let tree = KDTree::try_new_with_options(cloud, options)?;
+        let counts = tree
+            .externalisation_map()
+            .iter()
+            .map(|external| counts[*external as usize])
+            .collect();
+        Ok(WeightedKDTree { tree, counts })
+    }
+
+    /// Returns the underlying tree, whose queries ignore the counts.
+    pub fn tree(&self) -> &KDTree<T, P> {
+        &self.tree
+    }
+
+    /// Returns the count of the point of index `index` in the original point cloud.
+    pub fn count(&self, index: u32) -> u32 {
+        self.counts[self.tree.internalise_index(index) as usize]
+    }
+
+    /// Returns the sum of the counts of all points.
+    pub fn total_count(&self) -> u64 {
+        self.counts.iter().map(|count| *count as u64).sum()
+    }
+
+    /// Finds the `k` nearest neighbours of `query`, where a point of count `c` fills up to `c` slots,
+    /// using reasonable default parameters.
+    pub fn knn(&self, k: u32, query: &P) -> Vec<WeightedNeighbour<T, P>> {
+        self.knn_advanced(k, query, &Parameters::default(), None)
+    }
+
+    /// Finds the `k` nearest neighbours of `query`, where a point of count `c` fills up to `c` slots,
+    /// with user-provided parameters.
+    ///
+    /// The neighbours are sorted by increasing distance, independently of `parameters.sort_results`,
+    /// and their slots sum to `k`, unless the points in reach have a smaller total count.
+    /// Only the furthest neighbour may fill less slots than its count.
+    pub fn knn_advanced(
+        &self,
+        k: u32,
+        query: &P,
+        parameters: &Parameters<T>,
+        touch_statistics: Option<&mut u32>,
+    ) -> Vec<WeightedNeighbour<T, P>> {
+        let mut collector = MultiplicityCollector {
+            heap: BinaryHeap::new(),
+            counts: &self.counts,
+            k: k as u64,
+            held: 0,
+        };
+        if k > 0 {
+            self.tree
+                .nn_raw(query, &mut collector, parameters, touch_statistics);
+        }
+        let mut remaining = collector.held.min(k as u64);
+        let mut neighbours: Vec<_> = collector
+            .heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|neighbour| {
+                let count = self.counts[neighbour.index as usize] as u64;
+                let slots = count.min(remaining);
+                remaining -= slots;
+                WeightedNeighbour {
+                    neighbour: self.tree.externalise_neighbour(neighbour),
+                    slots: slots as u32,
+                }
+            })
+            .collect();
+        neighbours.retain(|neighbour| neighbour.slots > 0);
+        neighbours
+    }
+
+    /// Returns the sum of the counts of the points within `max_radius` of `query`.
+    pub fn count_within(&self, query: &P, max_radius: T) -> u64 {
+        let mut collector = CountCollector {
+            counts: &self.counts,
+            sum: 0,
+        };
+        let parameters = Parameters {
+            max_radius,
+            ..Parameters::default()
+        };
+        self.tree.nn_raw(query, &mut collector, &parameters, None);
+        collector.sum
+    }
+}
+
+/// Keeps the nearest candidates whose counts sum to at least `k`
+///
+/// The furthest candidate is dropped as soon as the others hold at least `k` slots,
+/// so the search radius is the distance of the candidate holding the `k`-th slot.
+struct MultiplicityCollector<'a, T: Scalar> {
+    heap: BinaryHeap<InternalNeighbour<T>>,
+    /// counts by internal index
+    counts: &'a [u32],
+    k: u64,
+    /// sum of the counts of the candidates in the heap
+    held: u64,
+}
+
+impl<'a, T: Scalar> CandidateCollector<T> for MultiplicityCollector<'a, T> {
+    fn add(&mut self, dist2: NotNan<T>, index: u32) {
+        let count = self.counts[index as usize] as u64;
+        if count == 0
+            || self
+                .furthest_dist2()
+                .map_or(false, |furthest| dist2 >= furthest)
+        {
+            return;
+        }
+        self.heap.push(InternalNeighbour { index, dist2 });
+        self.held += count;
+        while let Some(furthest) = self.heap.peek() {
+            let furthest_count = self.counts[furthest.index as usize] as u64;
+            if self.held - furthest_count < self.k {
+                break;
+            }
+            self.held -= furthest_count;
+            self.heap.pop();
+        }
+    }
+
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        if self.held < self.k {
+            None
+        } else {
+            self.heap.peek().map(|n| n.dist2)
+        }
+    }
+}
+
+/// Sums the counts of all candidates
+struct CountCollector<'a> {
+    /// counts by internal index
+    counts: &'a [u32],
+    sum: u64,
+}
+
+impl<'a, T: Scalar> CandidateCollector<T> for CountCollector<'a> {
+    fn add(&mut self, _dist2: NotNan<T>, index: u32) {
+        self.sum += self.counts[index as usize] as u64;
+    }
+
+    fn furthest_dist2(&self) -> Option<NotNan<T>> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simple_point::*;
+    use crate::*;
+
+    #[test]
+    fn weighted_knn_matches_expanded_cloud() {
+        let cloud: Vec<P2> = random_point_cloud(500);
+        let counts: Vec<u32> = (0..500).map(|i| (i * 7 % 5) as u32).collect();
+        let tree = WeightedKDTree::new(&cloud, &counts);
+        assert_eq!(tree.total_count(), counts.iter().map(|c| *c as u64).sum());
+        assert_eq!(tree.count(3), counts[3]);
+        // reference: each point repeated `count` times
+        let expanded: Vec<(P2, u32)> = cloud
+            .iter()
+            .zip(&counts)
+            .enumerate()
+            .flat_map(|(index, (point, count))| {
+                core::iter::repeat((*point, index as u32)).take(*count as usize)
+            })
+            .collect();
+        for k in [1, 4, 13] {
+            for _ in 0..30 {
+                let query = random_point();
+                let neighbours = tree.knn(k, &query);
+                let mut brute_force: Vec<_> = expanded
+                    .iter()
+                    .map(|(point, index)| {
+                        let dx = *point.get(0) - *query.get(0);
+                        let dy = *point.get(1) - *query.get(1);
+                        (dx * dx + dy * dy, *index)
+                    })
+                    .collect();
+                brute_force.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                brute_force.truncate(k as usize);
+                let slots: Vec<_> = neighbours
+                    .iter()
+                    .flat_map(|n| {
+                        assert!(n.slots <= counts[n.neighbour.index as usize]);
+                        core::iter::repeat((n.neighbour.dist2.into_inner(), n.neighbour.index))
+                            .take(n.slots as usize)
+                    })
+                    .collect();
+                assert_eq!(slots, brute_force);
+            }
+        }
+    }
+
+    #[test]
+    fn weighted_knn_with_few_points_and_count_within() {
+        let cloud = vec![
+            P2::new2d(0.0, 0.0),
+            P2::new2d(1.0, 0.0),
+            P2::new2d(3.0, 0.0),
+        ];
+        let tree = WeightedKDTree::new(&cloud, &[5, 0, 2]);
+        let neighbours = tree.knn(3, &P2::new2d(0.9, 0.0));
+        assert_eq!(neighbours.len(), 1);
+        assert_eq!(neighbours[0].neighbour.index, 0);
+        assert_eq!(neighbours[0].slots, 3);
+        let neighbours = tree.knn(10, &P2::new2d(0.0, 0.0));
+        let slots: Vec<_> = neighbours.iter().map(|n| n.slots).collect();
+        assert_eq!(slots, vec![5, 2]);
+        assert!(tree.knn(0, &P2::new2d(0.0, 0.0)).is_empty());
+        assert_eq!(tree.count_within(&P2::new2d(0.0, 0.0), 1.5), 5);
+        assert_eq!(tree.count_within(&P2::new2d(0.0, 0.0), 10.0), 7);
+        assert_eq!(
+            WeightedKDTree::try_new(&cloud, &[1, 2]),
+            Err(BuildError::ValuesLengthMismatch {
+                points: 3,
+                values: 2
+            })
+        );
+    }
+}